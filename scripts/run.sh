@@ -1,13 +1,111 @@
 #!/bin/bash
+#
+# Usage: run.sh            - build and boot the OS in QEMU
+#        run.sh selftest   - build with the self-test suite, boot headless and exit
+#                            with 0 if every check passed, non-zero otherwise
+#
+# Set STACK_PROTECTOR=1 to build with stack-smashing protection (needs a nightly toolchain)
+# Set ARCH=aarch64 to build for AArch64 and boot QEMU's virt machine (needs AAVMF/QEMU_EFI.fd)
+# Set ARCH=riscv64 to build for RISC-V and boot QEMU's virt machine on OpenSBI with EDK2 on
+# top (needs RISCV_VIRT_CODE.fd and RISCV_VIRT_VARS.fd, padded to 32 MiB)
+# Set FEATURES to add build options from Cargo.toml, e.g. FEATURES=kasan,leakcheck
+# Set MINIMAL=1 to leave out the default subsystems (smp, virtio)
 
 pushd $(dirname $0)/..  # change to project root
 
-cargo build
+MODE=${1:-normal}
+ARCH=${ARCH:-x86_64}
+
+CARGO=(cargo)
+if [ -n "$STACK_PROTECTOR" ]; then
+    # RUSTFLAGS replaces the flags from .cargo/config.toml, so repeat them here
+    CARGO=(cargo +nightly)
+    export RUSTFLAGS="-C force-frame-pointers=yes -Z stack-protector=strong"
+fi
+
+TARGET=$ARCH-unknown-uefi
+if [ "$ARCH" = "riscv64" ]; then
+    # No UEFI target: build a PIE ELF with its own PE header (see .cargo/config.toml)
+    TARGET=riscv64gc-unknown-none-elf
+    if [ -n "$STACK_PROTECTOR" ]; then
+        RUSTFLAGS+=" -C relocation-model=pie -C link-arg=-Tsrc/os/arch/riscv64/image.ld"
+        RUSTFLAGS+=" -C link-arg=-pie -C link-arg=--no-dynamic-linker -C link-arg=-znotext"
+    fi
+fi
+CARGO+=(build --target $TARGET)
+
+if [ -n "$MINIMAL" ]; then
+    CARGO+=(--no-default-features)
+fi
+
+if [ "$MODE" = "selftest" ]; then
+    FEATURES=selftest${FEATURES:+,$FEATURES}
+fi
+"${CARGO[@]}" ${FEATURES:+--features "$FEATURES"} || exit 1
 
 mkdir -p esp/EFI/BOOT
-cp target/x86_64-unknown-uefi/debug/osproj.efi esp/EFI/BOOT/BOOTX64.EFI
 
-exec qemu-system-x86_64 \
-    -drive if=pflash,format=raw,readonly=on,file=OVMF_CODE.fd \
-    -drive if=pflash,format=raw,readonly=on,file=OVMF_VARS.fd \
-    -drive format=raw,file=fat:rw:esp
\ No newline at end of file
+if [ "$ARCH" = "riscv64" ]; then
+    # Flatten the ELF: file offsets equal virtual addresses, so the result is the PE image
+    OBJCOPY=$(find "$(rustc --print sysroot)" -name rust-objcopy | head -n 1)
+    "$OBJCOPY" -O binary target/$TARGET/debug/osproj esp/EFI/BOOT/BOOTRISCV64.EFI || exit 1
+
+    QEMU=qemu-system-riscv64
+    QEMU_ARGS=(
+        -machine virt
+        -bios default
+        -drive if=pflash,unit=0,format=raw,readonly=on,file=RISCV_VIRT_CODE.fd
+        -drive if=pflash,unit=1,format=raw,file=RISCV_VIRT_VARS.fd
+        -drive format=raw,file=fat:rw:esp
+    )
+
+    # The virt machine's test finisher is always there
+    EXIT_DEVICE=()
+elif [ "$ARCH" = "aarch64" ]; then
+    cp target/$TARGET/debug/osproj.efi esp/EFI/BOOT/BOOTAA64.EFI
+
+    QEMU=qemu-system-aarch64
+    QEMU_ARGS=(
+        -machine virt,gic-version=3
+        -cpu max
+        -bios QEMU_EFI.fd
+        -drive format=raw,file=fat:rw:esp
+    )
+
+    # Semihosting carries the self-test verdict
+    EXIT_DEVICE=(-semihosting)
+else
+    cp target/$TARGET/debug/osproj.efi esp/EFI/BOOT/BOOTX64.EFI
+
+    QEMU=qemu-system-x86_64
+    QEMU_ARGS=(
+        -drive if=pflash,format=raw,readonly=on,file=OVMF_CODE.fd
+        -drive if=pflash,format=raw,readonly=on,file=OVMF_VARS.fd
+        -drive format=raw,file=fat:rw:esp
+        # User-mode networking, whose DHCP server leases 10.0.2.15, on a virtio card
+        -nic user,model=virtio-net-pci
+    )
+
+    EXIT_DEVICE=(-device isa-debug-exit,iobase=0xf4,iosize=0x04)
+fi
+
+if [ "$MODE" != "selftest" ]; then
+    # The kernel log goes to the serial port
+    exec $QEMU "${QEMU_ARGS[@]}" -serial stdio
+fi
+
+# The kernel reports its verdict through isa-debug-exit (or semihosting or the RISC-V test
+# finisher, with the same encoding): QEMU exits with (code << 1) | 1, so 33 means every check passed and 35 means at
+# least one failed
+$QEMU "${QEMU_ARGS[@]}" \
+    "${EXIT_DEVICE[@]}" \
+    -serial stdio \
+    -display none
+STATUS=$?
+
+if [ $STATUS -eq 33 ]; then
+    exit 0
+fi
+
+echo "selftest failed (qemu exit status $STATUS)" >&2
+exit 1
@@ -1,6 +1,8 @@
 #![no_main]
 #![no_std]
 
+extern crate alloc;
+
 mod os;
 
 
@@ -8,22 +10,141 @@ mod os;
 use uefi::prelude::*;
 use core::fmt::Write;
 
+use os::boot::{self, BootMode};
+
 // Tell the uefi crate that this function will be our program entry-point
 #[entry]
-fn os_main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
+fn os_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
-    // To use any of the services (input, output, etc...), they need to be manually
-    // initialized by the UEFI program
-    uefi_services::init(&mut system_table).unwrap();
+    os::serial::init();
+    os::serial::init_logger(&mut system_table);
+    os::config::log();
 
     let stdout = system_table.stdout();
     _ = stdout.clear();
     _ = stdout.write_str("Booting OS\n");
 
-    loop {
-        // do something in here.
+    // Capture the command line while boot services are still available
+    boot::store_command_line(image_handle, &system_table);
+    boot::load_initrd(image_handle, &system_table);
+    #[cfg(all(target_arch = "x86_64", feature = "smp"))]
+    boot::reserve_ap_trampoline(&system_table);
+    os::serial::configure_levels();
+
+    // `ftrace` on the command line records the rest of boot into the trace buffer
+    if boot::has_flag("ftrace") {
+        os::trace::enable();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    os::cpu::init();
+    os::acpi::init(&system_table);
+    os::fdt::init(&system_table);
+    os::fdt::probe();
+    #[cfg(target_arch = "x86_64")]
+    {
+        os::pci::init();
+        os::pci::register_devices();
     }
+    #[cfg(target_arch = "x86_64")]
+    os::arch::x86_64::init();
+    #[cfg(target_arch = "aarch64")]
+    os::arch::aarch64::init();
+    #[cfg(target_arch = "riscv64")]
+    os::arch::riscv64::init(&system_table);
+    os::numa::init();
+    os::percpu::init_cpu(0);
+    os::tlb::init();
+    boot::calibrate_counter(&system_table);
+    os::clocksource::init();
+    #[cfg(target_arch = "x86_64")]
+    {
+        os::arch::x86_64::hpet::init();
+        os::kvm::init();
+    }
+    os::timekeeping::init(&system_table);
+    os::protection::init();
+    os::fpu::init();
+    os::uaccess::init();
+    os::random::init(&system_table);
+    os::stack_protector::init();
+    os::aslr::init();
+
+    // Hand off: leave boot services behind and keep the final memory map. The firmware console
+    // goes with them, so logging carries on over serial alone and printing on the framebuffer.
+    os::serial::detach_firmware_console();
+    os::console::init(image_handle, &system_table);
+    let (_runtime, memory_map) = system_table.exit_boot_services();
+    #[cfg(target_arch = "x86_64")]
+    os::arch::x86_64::init_descriptor_tables();
+    os::memory::store_memory_map(&memory_map);
+    os::frame::init();
+    os::heap::init();
+    #[cfg(target_arch = "x86_64")]
+    os::paging::init();
+
+    kernel_main()
+}
+
+// The kernel proper, entered once boot services are gone: from here on memory comes from the
+// stored memory map, and nothing may touch the boot-time system table.
+fn kernel_main() -> ! {
+    // PCI drivers (legacy virtio among them); other platforms probe their devices elsewhere
+    #[cfg(all(target_arch = "x86_64", feature = "virtio"))]
+    os::virtio::register_drivers();
+    #[cfg(target_arch = "x86_64")]
+    {
+        os::ahci::register_driver();
+        os::pci::probe_drivers();
+        os::arch::x86_64::ioapic::init();
+        os::keyboard::init();
+    }
+    os::mount::init();
+    os::vfs::init();
+    os::initrd::init();
+    os::swap::init();
+    os::pagecache::init();
+    os::sched::init();
+
+    // The boot context itself becomes PID 0, the first entry in the process table
+    let mut kernel = os::process::Process::new(0, 0, "kernel");
+    kernel.state = os::process::ProcessState::Running;
+    os::ptable::insert(kernel).expect("process table rejected the kernel process");
+    os::uring::init();
+    #[cfg(all(target_arch = "x86_64", feature = "smp"))]
+    os::arch::x86_64::smp::start_aps();
 
-    // Tell the UEFI firmware we exited without error
-    Status::SUCCESS
-}
\ No newline at end of file
+    println!("Kernel running: {} MiB of memory free", os::memory::stats().free >> 20);
+
+    if os::trace::is_enabled() {
+        os::trace::dump();
+    }
+
+    if boot::boot_mode() == BootMode::SelfTest {
+        os::selftest::run();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        os::initrd::spawn_init();
+        os::shell::spawn();
+    }
+
+    loop {
+        // Run expired timers and due writeback, then idle until the next interrupt.
+        // High-resolution timers normally run from the timer interrupt; this catches them on
+        // CPUs without a one-shot timer.
+        os::timer::run();
+        os::hrtimer::run();
+        os::exit::reap_orphans();
+        os::pagecache::run_writeback();
+
+        // Give the CPU to whatever became runnable before idling
+        os::sched::schedule();
+
+        #[cfg(target_arch = "x86_64")]
+        os::idle::enter([os::timer::next_event_ns(), os::hrtimer::next_event_ns()].into_iter().flatten().min());
+        #[cfg(not(target_arch = "x86_64"))]
+        <os::arch::Current as os::arch::Arch>::wait_for_interrupt();
+    }
+}
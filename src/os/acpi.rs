@@ -0,0 +1,561 @@
+//! ACPI table discovery.
+//!
+//! The RSDP is taken from the UEFI configuration table at boot; [`find_table`] then walks the
+//! XSDT (or the RSDT on ACPI 1.0 firmware) and returns checksum-verified tables by signature.
+//! Tables are read in place: firmware leaves them in ACPI reclaim/NVS memory, which is
+//! identity-mapped while running on the firmware's page tables.
+//!
+//! The tables the kernel relies on are parsed here for the subsystems that use them: the FADT
+//! for power management, the MADT for the processors and I/O APICs ([`madt`]), the MCFG for
+//! PCIe configuration space ([`ecam_window`]) and the HPET table for the event timer
+//! ([`hpet`]). Each parser takes the table's bytes, so it works on any copy of one.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
+use uefi::table::{Boot, SystemTable};
+
+use crate::os::percpu::MAX_CPUS;
+
+// Physical address of the RSDP (0 = not found)
+static RSDP: AtomicU64 = AtomicU64::new(0);
+
+/// The header every system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Size of [`SdtHeader`]; table-specific fields start at this offset.
+pub const HEADER_SIZE: usize = size_of::<SdtHeader>();
+
+/// Locates the RSDP in the UEFI configuration table, preferring the ACPI 2.0+ entry.
+pub fn init(system_table: &SystemTable<Boot>) {
+    let entries = system_table.config_table();
+    let entry = entries
+        .iter()
+        .find(|e| e.guid == ACPI2_GUID)
+        .or_else(|| entries.iter().find(|e| e.guid == ACPI_GUID));
+
+    match entry {
+        Some(entry) => {
+            RSDP.store(entry.address as u64, Ordering::Relaxed);
+            log::info!("acpi: RSDP at {:#x}", entry.address as u64);
+            log_tables();
+        }
+        None => log::warn!("acpi: firmware provides no RSDP"),
+    }
+}
+
+// Logs what the MADT, MCFG and HPET tables describe
+fn log_tables() {
+    if let Some(madt) = madt() {
+        let enabled = madt.cpus().iter().filter(|cpu| cpu.enabled).count();
+        log::info!("acpi: MADT lists {} processors ({} enabled), {} I/O APICs", madt.cpus().len(), enabled, madt.ioapics().len());
+    }
+    if let Some(window) = ecam_window(0) {
+        log::info!("acpi: MCFG has ECAM at {:#x} for buses {:02x}-{:02x}", window.base, window.start_bus, window.end_bus);
+    }
+    if let Some(hpet) = hpet() {
+        log::info!("acpi: HPET at {:#x} with {} comparators", hpet.address, hpet.comparators);
+    }
+}
+
+/// Returns the raw bytes of the table at physical address `addr`, if its checksum is valid.
+///
+/// # Safety
+/// `addr` must point to a mapped ACPI table.
+pub unsafe fn table_at(addr: u64) -> Option<&'static [u8]> {
+    if addr == 0 {
+        return None;
+    }
+
+    let header = unsafe { core::ptr::read_unaligned(addr as *const SdtHeader) };
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, header.length as usize) };
+
+    checksum_ok(bytes).then_some(bytes)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Finds the first table with the given signature (e.g. `b"FACP"`) and returns its bytes,
+/// header included.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp = RSDP.load(Ordering::Relaxed);
+    if rsdp == 0 {
+        return None;
+    }
+
+    // RSDP: revision at 15, RSDT address at 16, XSDT address at 24 (revision 2+)
+    let (root, entry_size) = unsafe {
+        let revision = *((rsdp + 15) as *const u8);
+        let xsdt = core::ptr::read_unaligned((rsdp + 24) as *const u64);
+
+        if revision >= 2 && xsdt != 0 {
+            (xsdt, 8)
+        } else {
+            (core::ptr::read_unaligned((rsdp + 16) as *const u32) as u64, 4)
+        }
+    };
+
+    let root = unsafe { table_at(root)? };
+
+    root[HEADER_SIZE..].chunks_exact(entry_size).find_map(|entry| {
+        let addr = if entry_size == 8 {
+            u64::from_le_bytes(entry.try_into().ok()?)
+        } else {
+            u32::from_le_bytes(entry.try_into().ok()?) as u64
+        };
+
+        let table = unsafe { table_at(addr)? };
+        (table[..4] == *signature).then_some(table)
+    })
+}
+
+pub(crate) fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+    bytes.get(offset).copied().unwrap_or(0)
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes.get(offset..offset + 2).map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes.get(offset..offset + 4).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    bytes.get(offset..offset + 8).map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap_or([0; 8])))
+}
+
+// =========================================================================
+// FADT
+// =========================================================================
+
+/// The parts of the Fixed ACPI Description Table the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Physical address of the FACS.
+    pub firmware_ctrl: u64,
+
+    /// Physical address of the DSDT.
+    pub dsdt: u64,
+
+    /// I/O port used to hand ACPI control from SMM to the OS (0 = always in ACPI mode).
+    pub smi_cmd: u32,
+
+    /// Value written to `smi_cmd` to enable ACPI mode.
+    pub acpi_enable: u8,
+
+    /// PM1a/PM1b event register blocks (status and enable halves), as I/O ports.
+    pub pm1a_evt: u32,
+    pub pm1b_evt: u32,
+
+    /// PM1a/PM1b control registers, as I/O ports.
+    pub pm1a_cnt: u32,
+    pub pm1b_cnt: u32,
+
+    /// CMOS register holding the RTC's century (0 = none).
+    pub century: u8,
+}
+
+/// Parses the FADT (signature `FACP`). The 64-bit `X_` addresses win over the legacy ones.
+pub fn fadt() -> Option<Fadt> {
+    let table = find_table(b"FACP")?;
+
+    let firmware_ctrl = match read_u64(table, 132) {
+        0 => read_u32(table, 36) as u64,
+        x => x,
+    };
+
+    let dsdt = match read_u64(table, 140) {
+        0 => read_u32(table, 40) as u64,
+        x => x,
+    };
+
+    Some(Fadt {
+        firmware_ctrl,
+        dsdt,
+        smi_cmd: read_u32(table, 48),
+        acpi_enable: read_u8(table, 52),
+        pm1a_evt: read_u32(table, 56),
+        pm1b_evt: read_u32(table, 60),
+        pm1a_cnt: read_u32(table, 64),
+        pm1b_cnt: read_u32(table, 68),
+        century: read_u8(table, 108),
+    })
+}
+
+/// Looks up the `\_Sx_` package in the DSDT and returns the `SLP_TYPa`/`SLP_TYPb` values for
+/// sleep state `state` (e.g. 3 for S3). Only the fixed package encoding firmware uses in
+/// practice is understood; there is no AML interpreter.
+pub fn sleep_type(state: u8) -> Option<(u8, u8)> {
+    let dsdt = unsafe { table_at(fadt()?.dsdt)? };
+    let name = [b'_', b'S', b'0' + state, b'_'];
+
+    let at = dsdt.windows(4).position(|w| w == name)?;
+    let mut aml = &dsdt[at + 4..];
+
+    // PackageOp, PkgLength (the top two bits of the lead byte count extra length bytes),
+    // NumElements
+    if *aml.first()? != 0x12 {
+        return None;
+    }
+    let extra = (*aml.get(1)? >> 6) as usize;
+    aml = aml.get(3 + extra..)?;
+
+    let mut next = || -> Option<u8> {
+        let (value, used) = match *aml.first()? {
+            0x0a => (*aml.get(1)?, 2), // BytePrefix
+            0x00 => (0, 1),            // ZeroOp
+            0x01 => (1, 1),            // OneOp
+            _ => return None,
+        };
+        aml = &aml[used..];
+        Some(value)
+    };
+
+    let a = next()?;
+    let b = next().unwrap_or(0);
+    Some((a, b))
+}
+
+// =========================================================================
+// MADT
+// =========================================================================
+
+/// Maximum number of I/O APICs [`madt`] records.
+pub const MAX_IOAPICS: usize = 8;
+
+/// Number of ISA IRQs the MADT can override.
+pub const ISA_IRQS: usize = 16;
+
+// MADT fields: local APIC address and flags after the header, then variable-length entries
+const MADT_LOCAL_APIC_ADDRESS: usize = HEADER_SIZE;
+const MADT_FLAGS: usize = HEADER_SIZE + 4;
+const MADT_ENTRIES: usize = HEADER_SIZE + 8;
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+// Entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+
+// Local APIC flags
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A processor's local APIC, as the MADT lists it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    /// The processor's ACPI UID.
+    pub processor_uid: u32,
+
+    /// Its local APIC ID, the destination for IPIs.
+    pub apic_id: u32,
+
+    /// Usable now. A disabled processor that is `online_capable` may be hot-added later.
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+/// An I/O APIC and the first global system interrupt (GSI) it serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+/// Where the firmware wired an ISA IRQ, when not to the GSI of the same number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaOverride {
+    pub gsi: u32,
+
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+/// The interrupt controllers the MADT (signature `APIC`) describes on x86.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    /// Physical address of every local APIC's registers.
+    pub local_apic_address: u64,
+
+    /// The legacy 8259 PICs are present and must be masked before the APICs are used.
+    pub pcat_compat: bool,
+
+    /// Indexed by ISA IRQ.
+    pub isa_overrides: [Option<IsaOverride>; ISA_IRQS],
+
+    cpus: [LocalApic; MAX_CPUS],
+    cpu_count: usize,
+    ioapics: [IoApic; MAX_IOAPICS],
+    ioapic_count: usize,
+}
+
+impl Madt {
+    /// Parses a MADT. Processors beyond [`MAX_CPUS`] and I/O APICs beyond [`MAX_IOAPICS`]
+    /// are left out; so is anything in a truncated entry.
+    pub fn parse(table: &[u8]) -> Madt {
+        let mut madt = Madt {
+            local_apic_address: read_u32(table, MADT_LOCAL_APIC_ADDRESS) as u64,
+            pcat_compat: read_u32(table, MADT_FLAGS) & MADT_PCAT_COMPAT != 0,
+            isa_overrides: [None; ISA_IRQS],
+            cpus: [LocalApic { processor_uid: 0, apic_id: 0, enabled: false, online_capable: false }; MAX_CPUS],
+            cpu_count: 0,
+            ioapics: [IoApic { id: 0, address: 0, gsi_base: 0 }; MAX_IOAPICS],
+            ioapic_count: 0,
+        };
+
+        for (kind, entry) in madt_entries(table) {
+            match kind {
+                MADT_LOCAL_APIC if entry.len() >= 8 => {
+                    let flags = read_u32(entry, 4);
+                    madt.add_cpu(read_u8(entry, 2) as u32, read_u8(entry, 3) as u32, flags);
+                }
+                MADT_LOCAL_X2APIC if entry.len() >= 16 => {
+                    madt.add_cpu(read_u32(entry, 12), read_u32(entry, 4), read_u32(entry, 8));
+                }
+                MADT_IOAPIC if entry.len() >= 12 && madt.ioapic_count < MAX_IOAPICS => {
+                    madt.ioapics[madt.ioapic_count] = IoApic { id: read_u8(entry, 2), address: read_u32(entry, 4) as u64, gsi_base: read_u32(entry, 8) };
+                    madt.ioapic_count += 1;
+                }
+                MADT_OVERRIDE if entry.len() >= 10 => {
+                    if let Some(slot) = madt.isa_overrides.get_mut(read_u8(entry, 3) as usize) {
+                        *slot = Some(IsaOverride { gsi: read_u32(entry, 4), flags: read_u16(entry, 8) });
+                    }
+                }
+                MADT_LOCAL_APIC_OVERRIDE if entry.len() >= 12 => madt.local_apic_address = read_u64(entry, 4),
+                _ => {}
+            }
+        }
+
+        madt
+    }
+
+    fn add_cpu(&mut self, processor_uid: u32, apic_id: u32, flags: u32) {
+        // Firmware marks unused slots neither enabled nor online-capable
+        if flags & (LAPIC_ENABLED | LAPIC_ONLINE_CAPABLE) == 0 || self.cpu_count == MAX_CPUS {
+            return;
+        }
+
+        let enabled = flags & LAPIC_ENABLED != 0;
+        let online_capable = flags & LAPIC_ONLINE_CAPABLE != 0;
+        self.cpus[self.cpu_count] = LocalApic { processor_uid, apic_id, enabled, online_capable };
+        self.cpu_count += 1;
+    }
+
+    /// The processors, in MADT order.
+    pub fn cpus(&self) -> &[LocalApic] {
+        &self.cpus[..self.cpu_count]
+    }
+
+    /// The I/O APICs, in MADT order.
+    pub fn ioapics(&self) -> &[IoApic] {
+        &self.ioapics[..self.ioapic_count]
+    }
+}
+
+/// The entries of MADT `table` as (type, bytes), each slice covering the whole entry. Stops at
+/// the first malformed length.
+pub fn madt_entries(table: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut offset = MADT_ENTRIES;
+    core::iter::from_fn(move || {
+        let kind = *table.get(offset)?;
+        let length = *table.get(offset + 1)? as usize;
+        let entry = table.get(offset..offset + length).filter(|_| length >= 2)?;
+        offset += length;
+        Some((kind, entry))
+    })
+}
+
+/// Parses the MADT, if the firmware has one.
+pub fn madt() -> Option<Madt> {
+    find_table(b"APIC").map(Madt::parse)
+}
+
+// =========================================================================
+// MCFG
+// =========================================================================
+
+// Allocation entries start after the header and 8 reserved bytes, 16 bytes each (base
+// address, segment group, first bus, last bus)
+const MCFG_ENTRIES: usize = HEADER_SIZE + 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// A PCIe ECAM window: configuration space for buses `start_bus..=end_bus` of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcamWindow {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Finds the window for `segment` in MCFG `table`.
+pub fn parse_mcfg(table: &[u8], segment: u16) -> Option<EcamWindow> {
+    table.get(MCFG_ENTRIES..)?.chunks_exact(MCFG_ENTRY_SIZE).find(|entry| read_u16(entry, 8) == segment).map(|entry| EcamWindow {
+        base: read_u64(entry, 0),
+        segment,
+        start_bus: read_u8(entry, 10),
+        end_bus: read_u8(entry, 11),
+    })
+}
+
+/// The ECAM window of PCI segment `segment`, from the MCFG.
+pub fn ecam_window(segment: u16) -> Option<EcamWindow> {
+    parse_mcfg(find_table(b"MCFG")?, segment)
+}
+
+// =========================================================================
+// HPET
+// =========================================================================
+
+// Event timer block ID and the generic address of the registers, which must be in memory
+const HPET_BLOCK_ID: usize = HEADER_SIZE;
+const HPET_ADDRESS_SPACE: usize = HEADER_SIZE + 4;
+const HPET_ADDRESS: usize = HEADER_SIZE + 8;
+const HPET_NUMBER: usize = HEADER_SIZE + 16;
+const HPET_MIN_TICK: usize = HEADER_SIZE + 17;
+const SYSTEM_MEMORY: u8 = 0;
+
+/// The High Precision Event Timer block the HPET table describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// Physical address of its registers.
+    pub address: u64,
+
+    /// Sequence number of the block, when there are several.
+    pub number: u8,
+
+    /// Number of comparators.
+    pub comparators: u8,
+
+    /// The main counter is 64 bits wide rather than 32.
+    pub counter_64bit: bool,
+
+    /// The smallest periodic tick that does not lose interrupts, in main counter ticks.
+    pub min_tick: u16,
+
+    /// PCI vendor ID of the block.
+    pub vendor: u16,
+}
+
+/// Parses HPET `table`; `None` if its registers are not memory-mapped.
+pub fn parse_hpet(table: &[u8]) -> Option<Hpet> {
+    if table.len() < HPET_MIN_TICK + 2 || read_u8(table, HPET_ADDRESS_SPACE) != SYSTEM_MEMORY {
+        return None;
+    }
+
+    // Block ID: comparators - 1 in bits 8-12, counter size in bit 13, vendor in bits 16-31
+    let id = read_u32(table, HPET_BLOCK_ID);
+    Some(Hpet {
+        address: read_u64(table, HPET_ADDRESS),
+        number: read_u8(table, HPET_NUMBER),
+        comparators: ((id >> 8) & 0x1f) as u8 + 1,
+        counter_64bit: id & (1 << 13) != 0,
+        min_tick: read_u16(table, HPET_MIN_TICK),
+        vendor: (id >> 16) as u16,
+    })
+}
+
+/// The HPET, if the firmware describes one.
+pub fn hpet() -> Option<Hpet> {
+    parse_hpet(find_table(b"HPET")?)
+}
+
+pub mod ktests {
+    use super::*;
+
+    // A table of `N` bytes with signature `signature` and the header's length filled in
+    fn table<const N: usize>(signature: &[u8; 4]) -> [u8; N] {
+        let mut table = [0u8; N];
+        table[..4].copy_from_slice(signature);
+        table[4..8].copy_from_slice(&(N as u32).to_le_bytes());
+        table
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn madt_entries_are_parsed() {
+            let mut madt = table::<{ HEADER_SIZE + 8 + 8 * 3 + 12 + 10 + 16 + 2 }>(b"APIC");
+            madt[MADT_LOCAL_APIC_ADDRESS..MADT_LOCAL_APIC_ADDRESS + 4].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+            madt[MADT_FLAGS] = MADT_PCAT_COMPAT as u8;
+
+            let mut at = MADT_ENTRIES;
+            let mut entry = |bytes: &[u8]| {
+                madt[at..at + bytes.len()].copy_from_slice(bytes);
+                at += bytes.len();
+            };
+
+            // Processors 0 (enabled), 1 (hot-pluggable) and an unused slot
+            entry(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+            entry(&[MADT_LOCAL_APIC, 8, 1, 2, 2, 0, 0, 0]);
+            entry(&[MADT_LOCAL_APIC, 8, 2, 4, 0, 0, 0, 0]);
+            entry(&[MADT_IOAPIC, 12, 3, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+            entry(&[MADT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+            entry(&[MADT_LOCAL_X2APIC, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+
+            // A zero-length entry ends the list
+            entry(&[MADT_LOCAL_APIC, 0]);
+
+            let parsed = Madt::parse(&madt);
+            assert_eq!(parsed.local_apic_address, 0xfee0_0000);
+            assert!(parsed.pcat_compat);
+            assert_eq!(parsed.ioapics(), [IoApic { id: 3, address: 0xfec0_0000, gsi_base: 0 }]);
+            assert_eq!(parsed.isa_overrides[0], Some(IsaOverride { gsi: 2, flags: 0 }));
+            assert_eq!(parsed.isa_overrides[1], None);
+
+            let cpus: [(u32, bool); 3] = [(0, true), (2, false), (0x100, true)];
+            let expected = &cpus[..MAX_CPUS.min(3)];
+            assert_eq!(parsed.cpus().len(), expected.len());
+            for (cpu, &(apic_id, enabled)) in parsed.cpus().iter().zip(expected) {
+                assert_eq!((cpu.apic_id, cpu.enabled), (apic_id, enabled));
+            }
+        }
+
+        fn mcfg_windows_are_found_by_segment() {
+            let mut mcfg = table::<{ HEADER_SIZE + 8 + 2 * MCFG_ENTRY_SIZE }>(b"MCFG");
+            let second = MCFG_ENTRIES + MCFG_ENTRY_SIZE;
+            mcfg[MCFG_ENTRIES..MCFG_ENTRIES + 8].copy_from_slice(&0xb000_0000u64.to_le_bytes());
+            mcfg[MCFG_ENTRIES + 11] = 0xff;
+            mcfg[second..second + 8].copy_from_slice(&0xc000_0000u64.to_le_bytes());
+            mcfg[second + 8] = 1;
+            mcfg[second + 10] = 0x10;
+            mcfg[second + 11] = 0x1f;
+
+            assert_eq!(parse_mcfg(&mcfg, 0), Some(EcamWindow { base: 0xb000_0000, segment: 0, start_bus: 0, end_bus: 0xff }));
+            assert_eq!(parse_mcfg(&mcfg, 1), Some(EcamWindow { base: 0xc000_0000, segment: 1, start_bus: 0x10, end_bus: 0x1f }));
+            assert_eq!(parse_mcfg(&mcfg, 2), None);
+            assert_eq!(parse_mcfg(&mcfg[..HEADER_SIZE], 0), None);
+        }
+
+        fn hpet_table_is_parsed() {
+            let mut hpet = table::<{ HEADER_SIZE + 20 }>(b"HPET");
+            hpet[HPET_BLOCK_ID..HPET_BLOCK_ID + 4].copy_from_slice(&0x8086_a201u32.to_le_bytes());
+            hpet[HPET_ADDRESS..HPET_ADDRESS + 8].copy_from_slice(&0xfed0_0000u64.to_le_bytes());
+            hpet[HPET_MIN_TICK] = 0x80;
+
+            let parsed = parse_hpet(&hpet).unwrap();
+            assert_eq!(parsed.address, 0xfed0_0000);
+            assert_eq!((parsed.comparators, parsed.counter_64bit, parsed.vendor, parsed.min_tick), (3, true, 0x8086, 0x80));
+
+            // Registers in I/O space cannot be used
+            hpet[HPET_ADDRESS_SPACE] = 1;
+            assert_eq!(parse_hpet(&hpet), None);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! AHCI: SATA disks behind a PCI AHCI controller, as QEMU's `-device ahci` provides.
+//!
+//! The driver binds to functions of class 01h/06h (SATA, AHCI programming interface), takes
+//! each implemented port with an ATA disk behind it and gives it one of the [`DISKS`], which
+//! are [`BlockDevice`]s. A disk uses a single command slot: a transfer builds a host-to-device
+//! register FIS in the command table, points one PRD entry at a bounce buffer, issues the slot
+//! and waits for the controller to clear it. Completion is signalled by an MSI the controller
+//! sends on a device vector, which wakes the waiting process; without MSI, and in the boot
+//! context, the wait spins on the command-issue register instead.
+//!
+//! The command list, received-FIS area, command table and bounce buffer of each disk live in
+//! statics, which are identity-mapped, so their addresses are what the controller is given.
+//! The first disk holding a FAT32 volume is attached to the [`fat32`] driver.
+
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+
+use crate::os::arch::x86_64::idt;
+use crate::os::arch::{Arch, Current};
+use crate::os::block::{BLOCK_SIZE, BlockDevice};
+use crate::os::errno::{Errno, KResult};
+use crate::os::fat32;
+use crate::os::pci::{self, Bar, Match, PciAddress};
+use crate::os::percpu;
+use crate::os::process::WaitTarget;
+use crate::os::sched;
+use crate::os::sync::Mutex;
+use crate::os::timekeeping;
+
+/// Maximum number of disks driven at once.
+pub const MAX_DISKS: usize = 4;
+
+/// Size of a sector, the unit the disk is addressed in.
+pub const SECTOR_SIZE: usize = 512;
+
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+
+// Generic host control registers
+const HBA_GHC: u64 = 0x04;
+const HBA_IS: u64 = 0x08;
+const HBA_PI: u64 = 0x0c;
+
+// Global host control bits
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, at 0x100 + port * 0x80
+const PORT_CLB: u64 = 0x00;
+const PORT_CLBU: u64 = 0x04;
+const PORT_FB: u64 = 0x08;
+const PORT_FBU: u64 = 0x0c;
+const PORT_IS: u64 = 0x10;
+const PORT_IE: u64 = 0x14;
+const PORT_CMD: u64 = 0x18;
+const PORT_TFD: u64 = 0x20;
+const PORT_SIG: u64 = 0x24;
+const PORT_SSTS: u64 = 0x28;
+const PORT_SERR: u64 = 0x30;
+const PORT_CI: u64 = 0x38;
+
+// Port command bits
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+// Port interrupt bits: a device-to-host register FIS arrived, the task file reported an error
+const IS_DHRS: u32 = 1 << 0;
+const IS_TFES: u32 = 1 << 30;
+
+// Task file status bits
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+// SStatus device detection: a device is present and communicating
+const SSTS_DET_PRESENT: u32 = 3;
+
+// Signature of an ATA disk (as opposed to ATAPI, port multipliers or bridges)
+const SIG_ATA: u32 = 0x0000_0101;
+
+// ATA commands
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY: u8 = 0xec;
+
+// Host-to-device register FIS: type, and the bit saying it carries a command
+const FIS_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 0x80;
+
+// Device register: LBA addressing
+const DEVICE_LBA: u8 = 1 << 6;
+
+// Command header bits: FIS length in doublewords, write direction
+const HEADER_FIS_LENGTH: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+
+// PRD entry bit: interrupt when the transfer is done
+const PRD_INTERRUPT: u32 = 1 << 31;
+
+// Offset of the PRD table in the command table
+const TABLE_PRDT: usize = 0x80;
+
+// IDENTIFY words giving the sector count, as byte offsets: LBA48 (4 words), LBA28 (2 words)
+const IDENTIFY_SECTORS_48: usize = 200;
+const IDENTIFY_SECTORS_28: usize = 120;
+
+// How long a command or a port may take before it counts as failed
+const TIMEOUT_NS: u64 = 5_000_000_000;
+
+// Disks wait on `IODevice(WAIT_BASE + index)`
+const WAIT_BASE: u32 = 0xa4c1_0000;
+
+/// The AHCI PCI driver.
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "ahci",
+    matches: &[Match::Class { class: 0x01, subclass: 0x06, prog_if: Some(0x01) }],
+    probe,
+};
+
+/// The disks, in the order their ports were found; unattached ones have no blocks.
+pub static DISKS: [Disk; MAX_DISKS] = [Disk::new(0, "sda"), Disk::new(1, "sdb"), Disk::new(2, "sdc"), Disk::new(3, "sdd")];
+
+// Device-visible memory of one disk. The buffer comes first for its alignment; the command
+// list needs 1 KiB alignment, the received FIS 256 bytes and the command table 128.
+#[repr(C, align(4096))]
+struct PortMemory {
+    buffer: [u8; BLOCK_SIZE],
+    list: [u8; 1024],
+    fis: [u8; 256],
+    table: [u8; 256],
+}
+
+/// A SATA disk on an AHCI port.
+pub struct Disk {
+    index: usize,
+    name: &'static str,
+
+    // Controller and port register bases (0 = no port attached), MSI vector (0 = none) and
+    // size in sectors
+    abar: AtomicU64,
+    port: AtomicU64,
+    vector: AtomicU64,
+    sectors: AtomicU64,
+
+    memory: Mutex<PortMemory>,
+}
+
+/// Registers the driver with the PCI layer. Called once at boot, before the bus is probed.
+pub fn register_driver() {
+    if let Err(errno) = pci::register_driver(&DRIVER) {
+        log::error!("ahci: cannot register driver: {:?}", errno);
+    }
+}
+
+// Enables the controller at `address` and attaches a disk to every port that has one
+fn probe(address: PciAddress) -> KResult<()> {
+    let Bar::Memory(abar) = address.bar(5) else {
+        log::warn!("ahci: {} has no register BAR", address);
+        return Err(Errno::ENODEV);
+    };
+
+    // Completion interrupts, if the function can send MSI and a vector is free
+    let vector = match idt::allocate_vector(handle_interrupt) {
+        Some(vector) if address.enable_msi(vector).is_ok() => vector,
+        _ => 0,
+    };
+
+    write(abar, HBA_GHC, read(abar, HBA_GHC) | GHC_AE);
+    let implemented = read(abar, HBA_PI);
+
+    let mut attached = 0;
+    for port in (0..32u32).filter(|port| implemented & (1 << port) != 0) {
+        let base = abar + 0x100 + port as u64 * 0x80;
+        if read(base, PORT_SSTS) & 0xf != SSTS_DET_PRESENT || read(base, PORT_SIG) != SIG_ATA {
+            continue;
+        }
+
+        let Some(disk) = DISKS.iter().find(|disk| disk.port.load(Ordering::Acquire) == 0) else {
+            log::warn!("ahci: {} port {}: no disk slot left", address, port);
+            break;
+        };
+
+        match disk.attach(abar, base, vector as u64) {
+            Ok(()) => {
+                log::info!("ahci: {} on {} port {}, {} MiB", disk.name, address, port, (disk.blocks() * BLOCK_SIZE as u64) >> 20);
+                attached += 1;
+
+                match fat32::attach(disk) {
+                    Ok(()) | Err(Errno::EBUSY) | Err(Errno::EINVAL) => {}
+                    Err(errno) => log::warn!("ahci: cannot mount {}: {:?}", disk.name, errno),
+                }
+            }
+            Err(errno) => log::warn!("ahci: {} port {}: {:?}", address, port, errno),
+        }
+    }
+
+    if vector != 0 {
+        write(abar, HBA_IS, u32::MAX);
+        write(abar, HBA_GHC, read(abar, HBA_GHC) | GHC_IE);
+    }
+
+    if attached == 0 {
+        return Err(Errno::ENODEV);
+    }
+    Ok(())
+}
+
+// Acknowledges every port that interrupted and wakes the processes waiting on it
+fn handle_interrupt() {
+    for disk in &DISKS {
+        let base = disk.port.load(Ordering::Acquire);
+        if base == 0 {
+            continue;
+        }
+
+        let status = read(base, PORT_IS);
+        if status == 0 {
+            continue;
+        }
+        write(base, PORT_IS, status);
+
+        // The port's bit in the controller's status: registers at 0x100 + port * 0x80
+        let abar = disk.abar.load(Ordering::Relaxed);
+        write(abar, HBA_IS, 1 << ((base - abar - 0x100) / 0x80));
+
+        sched::wake(WaitTarget::IODevice(WAIT_BASE + disk.index as u32));
+    }
+}
+
+fn read(base: u64, register: u64) -> u32 {
+    unsafe { ((base + register) as *const u32).read_volatile() }
+}
+
+fn write(base: u64, register: u64, value: u32) {
+    unsafe { ((base + register) as *mut u32).write_volatile(value) };
+}
+
+// Spins until `done` holds, failing with `EIO` after TIMEOUT_NS
+fn spin_until(mut done: impl FnMut() -> bool) -> KResult<()> {
+    let deadline = timekeeping::monotonic_ns().saturating_add(TIMEOUT_NS);
+    while !done() {
+        if timekeeping::monotonic_ns() > deadline {
+            return Err(Errno::EIO);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+impl Disk {
+    const fn new(index: usize, name: &'static str) -> Disk {
+        Disk {
+            index,
+            name,
+            abar: AtomicU64::new(0),
+            port: AtomicU64::new(0),
+            vector: AtomicU64::new(0),
+            sectors: AtomicU64::new(0),
+            memory: Mutex::new(PortMemory { buffer: [0; BLOCK_SIZE], list: [0; 1024], fis: [0; 256], table: [0; 256] }),
+        }
+    }
+
+    // Points the port at this disk's memory, starts it and identifies the disk behind it
+    fn attach(&self, abar: u64, base: u64, vector: u64) -> KResult<()> {
+        let mut memory = self.memory.lock();
+
+        // The port must be idle while its memory is changed
+        write(base, PORT_CMD, read(base, PORT_CMD) & !(CMD_ST | CMD_FRE));
+        spin_until(|| read(base, PORT_CMD) & (CMD_CR | CMD_FR) == 0)?;
+
+        let (list, fis) = (memory.list.as_ptr() as u64, memory.fis.as_ptr() as u64);
+        write(base, PORT_CLB, list as u32);
+        write(base, PORT_CLBU, (list >> 32) as u32);
+        write(base, PORT_FB, fis as u32);
+        write(base, PORT_FBU, (fis >> 32) as u32);
+
+        write(base, PORT_SERR, u32::MAX);
+        write(base, PORT_IS, u32::MAX);
+        write(base, PORT_IE, if vector != 0 { IS_DHRS | IS_TFES } else { 0 });
+
+        write(base, PORT_CMD, read(base, PORT_CMD) | CMD_FRE);
+        spin_until(|| read(base, PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+        write(base, PORT_CMD, read(base, PORT_CMD) | CMD_ST);
+
+        self.vector.store(vector, Ordering::Relaxed);
+        self.issue(base, &mut memory, ATA_IDENTIFY, 0, SECTOR_SIZE, false)?;
+
+        let identify = &memory.buffer[..SECTOR_SIZE];
+        let lba48 = u64::from_le_bytes(identify[IDENTIFY_SECTORS_48..IDENTIFY_SECTORS_48 + 8].try_into().unwrap_or([0; 8]));
+        let lba28 = u32::from_le_bytes(identify[IDENTIFY_SECTORS_28..IDENTIFY_SECTORS_28 + 4].try_into().unwrap_or([0; 4]));
+        let sectors = if lba48 != 0 { lba48 } else { lba28 as u64 };
+        if sectors < SECTORS_PER_BLOCK {
+            return Err(Errno::ENODEV);
+        }
+
+        self.sectors.store(sectors, Ordering::Relaxed);
+        self.abar.store(abar, Ordering::Relaxed);
+        self.port.store(base, Ordering::Release);
+        Ok(())
+    }
+
+    // Runs one command on slot 0, moving `bytes` between the disk at `lba` and the bounce
+    // buffer, and waits for it to finish
+    fn issue(&self, base: u64, memory: &mut PortMemory, command: u8, lba: u64, bytes: usize, write_to_disk: bool) -> KResult<()> {
+        spin_until(|| read(base, PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0)?;
+
+        let table = &mut memory.table;
+        table.fill(0);
+        let count = if command == ATA_IDENTIFY { 0 } else { (bytes / SECTOR_SIZE) as u16 };
+        let lba = lba.to_le_bytes();
+        table[..20].copy_from_slice(&[
+            FIS_H2D, FIS_COMMAND, command, 0,
+            lba[0], lba[1], lba[2], DEVICE_LBA,
+            lba[3], lba[4], lba[5], 0,
+            count as u8, (count >> 8) as u8, 0, 0,
+            0, 0, 0, 0,
+        ]);
+
+        let buffer = memory.buffer.as_ptr() as u64;
+        table[TABLE_PRDT..TABLE_PRDT + 8].copy_from_slice(&buffer.to_le_bytes());
+        table[TABLE_PRDT + 12..TABLE_PRDT + 16].copy_from_slice(&((bytes as u32 - 1) | PRD_INTERRUPT).to_le_bytes());
+
+        // Command header 0: FIS length, direction, one PRD entry, and the table's address
+        let direction = if write_to_disk { HEADER_WRITE } else { 0 };
+        let flags = HEADER_FIS_LENGTH | direction | 1 << 16;
+        let table_address = memory.table.as_ptr() as u64;
+        memory.list[..32].fill(0);
+        memory.list[..4].copy_from_slice(&flags.to_le_bytes());
+        memory.list[8..16].copy_from_slice(&table_address.to_le_bytes());
+
+        fence(Ordering::SeqCst);
+        write(base, PORT_IS, u32::MAX);
+        write(base, PORT_CI, 1);
+
+        let mut done = || read(base, PORT_CI) & 1 == 0 || read(base, PORT_TFD) & TFD_ERR != 0;
+        let deadline = timekeeping::monotonic_ns().saturating_add(TIMEOUT_NS);
+        let pid = percpu::current_pid();
+        while !done() {
+            if timekeeping::monotonic_ns() > deadline {
+                log::error!("ahci: {}: command {:#x} timed out", self.name, command);
+                return Err(Errno::EIO);
+            }
+
+            // With interrupts masked, as under the process table's lock, nothing could wake us
+            if sched::is_idle(pid) || self.vector.load(Ordering::Relaxed) == 0 || !Current::interrupts_enabled() {
+                core::hint::spin_loop();
+            } else {
+                sched::block_current_unless(WaitTarget::IODevice(WAIT_BASE + self.index as u32), &mut done);
+            }
+        }
+        fence(Ordering::SeqCst);
+
+        if read(base, PORT_TFD) & TFD_ERR != 0 {
+            log::error!("ahci: {}: command {:#x} at sector {} failed", self.name, command, u64::from_le_bytes(lba));
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    // The port and first sector of block `index`, which must be on the disk
+    fn locate(&self, index: u64) -> KResult<(u64, u64)> {
+        let base = self.port.load(Ordering::Acquire);
+        if base == 0 {
+            return Err(Errno::ENODEV);
+        }
+        if index >= self.blocks() {
+            return Err(Errno::EINVAL);
+        }
+        Ok((base, index * SECTORS_PER_BLOCK))
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors.load(Ordering::Relaxed) / SECTORS_PER_BLOCK
+    }
+
+    fn read_block(&self, index: u64, buf: &mut [u8; BLOCK_SIZE]) -> KResult<()> {
+        let (base, lba) = self.locate(index)?;
+        let mut memory = self.memory.lock();
+
+        self.issue(base, &mut memory, ATA_READ_DMA_EXT, lba, BLOCK_SIZE, false)?;
+        buf.copy_from_slice(&memory.buffer);
+        Ok(())
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> KResult<()> {
+        let (base, lba) = self.locate(index)?;
+        let mut memory = self.memory.lock();
+
+        memory.buffer.copy_from_slice(data);
+        self.issue(base, &mut memory, ATA_WRITE_DMA_EXT, lba, BLOCK_SIZE, true)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    crate::os::ktest::kernel_test! {
+        fn port_memory_meets_the_controller_alignment() {
+            let memory = DISKS[0].memory.lock();
+            assert_eq!(memory.buffer.as_ptr() as usize % 4096, 0);
+            assert_eq!(memory.list.as_ptr() as usize % 1024, 0);
+            assert_eq!(memory.fis.as_ptr() as usize % 256, 0);
+            assert_eq!(memory.table.as_ptr() as usize % 128, 0);
+        }
+
+        fn unattached_disks_refuse_io() {
+            let disk = DISKS.iter().find(|disk| disk.port.load(Ordering::Acquire) == 0);
+            if let Some(disk) = disk {
+                let mut buf = [0; BLOCK_SIZE];
+                assert_eq!(disk.blocks(), 0);
+                assert_eq!(disk.read_block(0, &mut buf), Err(Errno::ENODEV));
+                assert_eq!(disk.write_block(0, &buf), Err(Errno::ENODEV));
+            }
+        }
+
+        fn attached_disks_read_back_what_was_written() {
+            let Some(disk) = DISKS.iter().find(|disk| disk.port.load(Ordering::Acquire) != 0) else {
+                return;
+            };
+            assert_eq!(disk.read_block(disk.blocks(), &mut [0; BLOCK_SIZE]), Err(Errno::EINVAL));
+
+            // The last block, where a filesystem is least likely to keep anything. The buffers
+            // are on the heap, which has room for them where the kernel stack has not.
+            let last = disk.blocks() - 1;
+            let mut saved = vec![0; BLOCK_SIZE];
+            let mut pattern: Vec<u8> = (0..BLOCK_SIZE).map(|i| (i * 7) as u8).collect();
+            let mut back = vec![0; BLOCK_SIZE];
+
+            disk.read_block(last, saved.first_chunk_mut().unwrap()).unwrap();
+            disk.write_block(last, pattern.first_chunk_mut().unwrap()).unwrap();
+            disk.read_block(last, back.first_chunk_mut().unwrap()).unwrap();
+            disk.write_block(last, saved.first_chunk_mut().unwrap()).unwrap();
+            assert!(back == pattern);
+        }
+    }
+}
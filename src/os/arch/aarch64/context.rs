@@ -0,0 +1,110 @@
+//! Kernel context save and restore.
+//!
+//! A [`Context`] holds what AAPCS64 requires a callee to preserve (x19-x28, the frame pointer
+//! and the low halves of v8-v15, which the kernel leaves to the FPU code), plus the link
+//! register to resume at and the stack pointer.
+
+use super::super::{ContextEntry, GuardedEntry};
+
+/// Saved state of a kernel context that is not running.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Context {
+    x19_x28: [u64; 10],
+    fp: u64,
+    lr: u64,
+    sp: u64,
+}
+
+impl Context {
+    /// A context that calls `entry(arg)` on the stack ending at `stack_top`.
+    pub fn new(stack_top: u64, entry: ContextEntry, arg: usize) -> Self {
+        let mut context = Context {
+            lr: start_context as *const () as u64,
+            sp: stack_top & !0xf,
+            ..Context::default()
+        };
+
+        context.x19_x28[0] = entry as usize as u64;
+        context.x19_x28[1] = arg as u64;
+        context
+    }
+}
+
+// First code run by a context from Context::new: x19 holds the entry point, x20 the argument
+#[unsafe(naked)]
+unsafe extern "C" fn start_context() -> ! {
+    core::arch::naked_asm!(
+        "mov x29, xzr",
+        "mov x0, x20",
+        "blr x19",
+        "brk #0",
+    )
+}
+
+/// Saves the running context into `prev` and jumps to `next`.
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(prev: *mut Context, next: *const Context) {
+    core::arch::naked_asm!(
+        "stp x19, x20, [x0, #0]",
+        "stp x21, x22, [x0, #16]",
+        "stp x23, x24, [x0, #32]",
+        "stp x25, x26, [x0, #48]",
+        "stp x27, x28, [x0, #64]",
+        "stp x29, x30, [x0, #80]",
+        "mov x9, sp",
+        "str x9, [x0, #96]",
+        "ldp x19, x20, [x1, #0]",
+        "ldp x21, x22, [x1, #16]",
+        "ldp x23, x24, [x1, #32]",
+        "ldp x25, x26, [x1, #48]",
+        "ldp x27, x28, [x1, #64]",
+        "ldp x29, x30, [x1, #80]",
+        "ldr x9, [x1, #96]",
+        "mov sp, x9",
+        "ret",
+    )
+}
+
+/// Saves the running context into `point`, then calls `entry(arg)`. Returns 0 when `entry`
+/// returns normally, or 1 when [`resume`] is used to bail out.
+#[unsafe(naked)]
+pub unsafe extern "C" fn call_with(point: *mut Context, entry: GuardedEntry, arg: *const ()) -> u64 {
+    core::arch::naked_asm!(
+        "stp x19, x20, [x0, #0]",
+        "stp x21, x22, [x0, #16]",
+        "stp x23, x24, [x0, #32]",
+        "stp x25, x26, [x0, #48]",
+        "stp x27, x28, [x0, #64]",
+        "stp x29, x30, [x0, #80]",
+        "mov x9, sp",
+        "str x9, [x0, #96]",
+        // x19 is saved and preserved by the callee: keep `point` there to reload x30 afterwards
+        "mov x19, x0",
+        "mov x9, x1",
+        "mov x0, x2",
+        "blr x9",
+        "mov x9, x19",
+        "ldr x19, [x9, #0]",
+        "ldp x29, x30, [x9, #80]",
+        "mov x0, #0",
+        "ret",
+    )
+}
+
+/// Restores the context saved by [`call_with`] and returns 1 from it.
+#[unsafe(naked)]
+pub unsafe extern "C" fn resume(point: *const Context) -> ! {
+    core::arch::naked_asm!(
+        "ldp x19, x20, [x0, #0]",
+        "ldp x21, x22, [x0, #16]",
+        "ldp x23, x24, [x0, #32]",
+        "ldp x25, x26, [x0, #48]",
+        "ldp x27, x28, [x0, #64]",
+        "ldp x29, x30, [x0, #80]",
+        "ldr x9, [x0, #96]",
+        "mov sp, x9",
+        "mov x0, #1",
+        "ret",
+    )
+}
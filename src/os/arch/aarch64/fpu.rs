@@ -0,0 +1,169 @@
+//! FP/SIMD register state management (V0-V31, FPCR and FPSR).
+//!
+//! Each process owns an [`FpuState`] in its PCB. Unlike x86 there is nothing to size at boot:
+//! the state is always 32 128-bit registers plus two status/control registers. Switching is
+//! always eager; CPACR_EL1 lets both EL1 and EL0 use the registers without trapping.
+
+use core::arch::asm;
+
+use crate::os::process::Process;
+
+/// Size of the per-process save area.
+pub const FPU_AREA_SIZE: usize = size_of::<FpuState>();
+
+// CPACR_EL1.FPEN: no FP/SIMD traps at EL0 or EL1
+const CPACR_FPEN: u64 = 0b11 << 20;
+
+/// A saved FP/SIMD register image.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct FpuState {
+    v: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl FpuState {
+    /// The state a fresh process starts with: all registers zero, which for FPCR means round
+    /// to nearest with every exception untrapped.
+    pub const fn new() -> Self {
+        FpuState { v: [0; 32], fpcr: 0, fpsr: 0 }
+    }
+
+    /// Saves the current register contents into this area.
+    pub fn save(&mut self) {
+        let (fpcr, fpsr): (u64, u64);
+
+        unsafe {
+            asm!(
+                "stp q0, q1, [{0}, #0]",
+                "stp q2, q3, [{0}, #32]",
+                "stp q4, q5, [{0}, #64]",
+                "stp q6, q7, [{0}, #96]",
+                "stp q8, q9, [{0}, #128]",
+                "stp q10, q11, [{0}, #160]",
+                "stp q12, q13, [{0}, #192]",
+                "stp q14, q15, [{0}, #224]",
+                "stp q16, q17, [{0}, #256]",
+                "stp q18, q19, [{0}, #288]",
+                "stp q20, q21, [{0}, #320]",
+                "stp q22, q23, [{0}, #352]",
+                "stp q24, q25, [{0}, #384]",
+                "stp q26, q27, [{0}, #416]",
+                "stp q28, q29, [{0}, #448]",
+                "stp q30, q31, [{0}, #480]",
+                "mrs {1}, fpcr",
+                "mrs {2}, fpsr",
+                in(reg) self.v.as_mut_ptr(),
+                out(reg) fpcr,
+                out(reg) fpsr,
+                options(nostack, preserves_flags)
+            );
+        }
+
+        self.fpcr = fpcr;
+        self.fpsr = fpsr;
+    }
+
+    /// Loads the registers from this area.
+    pub fn restore(&self) {
+        unsafe {
+            asm!(
+                "ldp q0, q1, [{0}, #0]",
+                "ldp q2, q3, [{0}, #32]",
+                "ldp q4, q5, [{0}, #64]",
+                "ldp q6, q7, [{0}, #96]",
+                "ldp q8, q9, [{0}, #128]",
+                "ldp q10, q11, [{0}, #160]",
+                "ldp q12, q13, [{0}, #192]",
+                "ldp q14, q15, [{0}, #224]",
+                "ldp q16, q17, [{0}, #256]",
+                "ldp q18, q19, [{0}, #288]",
+                "ldp q20, q21, [{0}, #320]",
+                "ldp q22, q23, [{0}, #352]",
+                "ldp q24, q25, [{0}, #384]",
+                "ldp q26, q27, [{0}, #416]",
+                "ldp q28, q29, [{0}, #448]",
+                "ldp q30, q31, [{0}, #480]",
+                "msr fpcr, {1}",
+                "msr fpsr, {2}",
+                in(reg) self.v.as_ptr(),
+                in(reg) self.fpcr,
+                in(reg) self.fpsr,
+                // Every vector register is overwritten
+                clobber_abi("C"),
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Enables FP/SIMD at EL0 and EL1 and starts from a clean register state. Called once at boot.
+pub fn init() {
+    let cpacr = read_sysreg!("cpacr_el1");
+    unsafe {
+        write_sysreg!("cpacr_el1", cpacr | CPACR_FPEN);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+
+    // Start from a clean state so the boot context does not leak firmware values
+    FpuState::new().restore();
+
+    log::info!("fpu: {}-byte save area, eager switching", FPU_AREA_SIZE);
+}
+
+/// Bytes of each save area in use.
+pub fn area_size() -> usize {
+    FPU_AREA_SIZE
+}
+
+/// Context switch hook, called with the outgoing and incoming process.
+pub fn switch(prev: &mut Process, next: &Process) {
+    prev.fpu.save();
+    next.fpu.restore();
+}
+
+/// Teardown hook. Switching is always eager, so no CPU holds on to `pid`'s registers.
+pub fn forget(_pid: u64) {}
+
+pub mod ktests {
+    use super::*;
+
+    // FPCR.FZ: flush denormals to zero, distinguishable from the default of 0
+    const FPCR_FZ: u64 = 1 << 24;
+
+    crate::os::ktest::kernel_test! {
+        fn save_restore_roundtrip() {
+            let mut state = FpuState::new();
+            let readback: u64;
+
+            unsafe { asm!("msr fpcr, {}", in(reg) FPCR_FZ, options(nomem, nostack)) };
+            state.save();
+            FpuState::new().restore();
+            unsafe { asm!("mrs {}, fpcr", out(reg) readback, options(nomem, nostack)) };
+
+            assert_eq!(readback, 0);
+            assert_eq!(state.fpcr, FPCR_FZ);
+
+            state.restore();
+            let restored: u64;
+            unsafe { asm!("mrs {}, fpcr", out(reg) restored, options(nomem, nostack)) };
+            FpuState::new().restore();
+
+            assert_eq!(restored, FPCR_FZ);
+        }
+
+        fn initial_state_is_zero() {
+            let state = FpuState::new();
+
+            assert_eq!(state.fpcr, 0);
+            assert!(state.v.iter().all(|&v| v == 0));
+        }
+    }
+}
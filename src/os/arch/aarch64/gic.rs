@@ -0,0 +1,348 @@
+//! ARM Generic Interrupt Controller, version 2 or 3.
+//!
+//! Both have a distributor (GICD) routing shared peripheral interrupts. GICv2 adds a
+//! memory-mapped CPU interface (GICC) and addresses CPUs by an 8-bit target mask; GICv3 moves
+//! the CPU interface into the ICC_* system registers, gives every CPU a redistributor (GICR)
+//! for its SGIs and PPIs, and routes by MPIDR affinity. ID_AA64PFR0_EL1.GIC tells which
+//! interface the CPU has. Frame addresses come from the MADT, the device tree, or QEMU `virt`'s
+//! defaults when the firmware describes neither.
+
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use crate::os::acpi;
+use crate::os::device::{self, DeviceClass};
+use crate::os::percpu::PerCpu;
+
+/// Physical addresses of the GIC frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub version: u8,
+    pub distributor: u64,
+
+    /// GICv2 CPU interface (unused with GICv3).
+    pub cpu_interface: u64,
+
+    /// First GICv3 redistributor (unused with GICv2).
+    pub redistributors: u64,
+}
+
+/// QEMU `virt` machine defaults; the version is filled in from the CPU.
+pub const QEMU_VIRT: Layout =
+    Layout { version: 0, distributor: 0x0800_0000, cpu_interface: 0x0801_0000, redistributors: 0x080a_0000 };
+
+/// Interrupt IDs at and above this value are special (1023 = spurious).
+pub const SPECIAL_INTID: u32 = 1020;
+
+// Distributor registers
+const GICD_CTLR: u64 = 0x000;
+const GICD_ISENABLER: u64 = 0x100;
+const GICD_ITARGETSR: u64 = 0x800;
+const GICD_SGIR: u64 = 0xf00;
+
+// GICD_CTLR bits: group 1 forwarding, and affinity routing (GICv3)
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+
+// GICD_SGIR target list filter: every CPU but the sender
+const SGIR_ALL_BUT_SELF: u32 = 0b01 << 24;
+
+// GICv2 CPU interface registers
+const GICC_CTLR: u64 = 0x00;
+const GICC_PMR: u64 = 0x04;
+const GICC_IAR: u64 = 0x0c;
+const GICC_EOIR: u64 = 0x10;
+
+// Redistributor registers; SGI/PPI registers live in the second 64 KiB frame
+const GICR_TYPER: u64 = 0x08;
+const GICR_WAKER: u64 = 0x14;
+const GICR_SGI_FRAME: u64 = 0x1_0000;
+const GICR_IGROUPR0: u64 = GICR_SGI_FRAME + 0x080;
+const GICR_ISENABLER0: u64 = GICR_SGI_FRAME + 0x100;
+
+// GICR_TYPER bits: last redistributor in the region, and two extra frames for virtual LPIs
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+
+// GICR_WAKER bits
+const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+// ICC_SGI1R_EL1: route to every PE but the sender
+const SGI1R_IRM: u64 = 1 << 40;
+
+// MADT entry types and field offsets
+const MADT_ENTRIES: usize = acpi::HEADER_SIZE + 8;
+const MADT_GICC: u8 = 0x0b;
+const MADT_GICD: u8 = 0x0c;
+const MADT_GICR: u8 = 0x0e;
+const GICC_BASE_OFFSET: usize = 32;
+const GICD_BASE_OFFSET: usize = 8;
+const GICD_VERSION_OFFSET: usize = 20;
+const GICR_BASE_OFFSET: usize = 4;
+
+static VERSION: AtomicU8 = AtomicU8::new(0);
+static DISTRIBUTOR: AtomicU64 = AtomicU64::new(0);
+static CPU_INTERFACE: AtomicU64 = AtomicU64::new(0);
+static REDISTRIBUTORS: AtomicU64 = AtomicU64::new(0);
+
+// Raw GICC_IAR value of the interrupt being handled; GICv2 wants it back verbatim on EOI,
+// including the source CPU of an SGI
+static ACKNOWLEDGED: PerCpu<u32> = PerCpu::new(0);
+
+fn mmio_read(addr: u64) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn mmio_write(addr: u64, value: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, value) };
+}
+
+/// GIC version in use (2 or 3), 0 before [`init`].
+pub fn version() -> u8 {
+    VERSION.load(Ordering::Relaxed)
+}
+
+/// GIC architecture version the CPU's interface supports, from ID_AA64PFR0_EL1.GIC.
+fn cpu_version() -> u8 {
+    if (read_sysreg!("id_aa64pfr0_el1") >> 24) & 0xf != 0 { 3 } else { 2 }
+}
+
+/// Reads the frame addresses from the MADT, if there is one.
+pub fn layout_from_madt() -> Option<Layout> {
+    let madt = acpi::find_table(b"APIC")?;
+    let mut layout = Layout { version: 0, distributor: 0, cpu_interface: 0, redistributors: 0 };
+
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let kind = acpi::read_u8(madt, offset);
+        let length = acpi::read_u8(madt, offset + 1) as usize;
+
+        if length < 2 || offset + length > madt.len() {
+            break;
+        }
+
+        match kind {
+            // Every GICC entry carries the same GICv2 interface address; the first is enough
+            MADT_GICC if layout.cpu_interface == 0 => {
+                layout.cpu_interface = acpi::read_u64(madt, offset + GICC_BASE_OFFSET);
+            }
+            MADT_GICD => {
+                layout.distributor = acpi::read_u64(madt, offset + GICD_BASE_OFFSET);
+                layout.version = acpi::read_u8(madt, offset + GICD_VERSION_OFFSET);
+            }
+            MADT_GICR if layout.redistributors == 0 => {
+                layout.redistributors = acpi::read_u64(madt, offset + GICR_BASE_OFFSET);
+            }
+            _ => {}
+        }
+
+        offset += length;
+    }
+
+    (layout.distributor != 0).then_some(layout)
+}
+
+// Device tree `compatible` strings: GICv3, and the GICv2 implementations
+const FDT_GICV3: &str = "arm,gic-v3";
+const FDT_GICV2: &[&str] = &["arm,gic-400", "arm,cortex-a15-gic", "arm,cortex-a9-gic"];
+
+/// Reads the frame addresses from the interrupt controller the device tree described, if any.
+pub fn layout_from_fdt() -> Option<Layout> {
+    // Both bindings list the distributor first; the second region is the first redistributor
+    // range on GICv3 and the CPU interface on GICv2
+    if let Some(gic) = device::find_compatible(DeviceClass::InterruptController, &[FDT_GICV3]) {
+        let (gicd, gicr) = (gic.location.region(0)?, gic.location.region(1)?);
+        return Some(Layout { version: 3, distributor: gicd.base, cpu_interface: 0, redistributors: gicr.base });
+    }
+
+    let gic = device::find_compatible(DeviceClass::InterruptController, FDT_GICV2)?;
+    let (gicd, gicc) = (gic.location.region(0)?, gic.location.region(1)?);
+    Some(Layout { version: 2, distributor: gicd.base, cpu_interface: gicc.base, redistributors: 0 })
+}
+
+/// Enables the distributor and the boot CPU's interface. Called once at boot, after the
+/// device tree has been probed.
+pub fn init() {
+    let mut layout = layout_from_madt().or_else(layout_from_fdt).unwrap_or(QEMU_VIRT);
+
+    // Version 0 in the MADT means "look at the hardware"
+    if layout.version == 0 {
+        layout.version = cpu_version();
+    }
+
+    init_with(layout);
+}
+
+/// Like [`init`] with an explicit layout, e.g. from a device tree.
+pub fn init_with(layout: Layout) {
+    VERSION.store(layout.version, Ordering::Relaxed);
+    DISTRIBUTOR.store(layout.distributor, Ordering::Relaxed);
+    CPU_INTERFACE.store(layout.cpu_interface, Ordering::Relaxed);
+    REDISTRIBUTORS.store(layout.redistributors, Ordering::Relaxed);
+
+    let ctlr = if layout.version >= 3 { GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1 } else { 1 };
+    mmio_write(layout.distributor + GICD_CTLR, ctlr);
+
+    init_cpu();
+}
+
+/// Wakes up and enables this CPU's interface and unmasks all SGIs. Called on every CPU.
+pub fn init_cpu() {
+    if version() >= 3 {
+        let rd = this_redistributor().expect("gic: no redistributor for this CPU");
+
+        let waker = mmio_read(rd + GICR_WAKER);
+        mmio_write(rd + GICR_WAKER, waker & !WAKER_PROCESSOR_SLEEP);
+        while mmio_read(rd + GICR_WAKER) & WAKER_CHILDREN_ASLEEP != 0 {
+            core::hint::spin_loop();
+        }
+
+        // SGIs and PPIs in group 1, SGIs enabled
+        mmio_write(rd + GICR_IGROUPR0, u32::MAX);
+        mmio_write(rd + GICR_ISENABLER0, 0xffff);
+
+        // ICC_SRE_EL1.SRE: use the system register interface
+        let sre = read_sysreg!("s3_0_c12_c12_5");
+
+        unsafe {
+            write_sysreg!("s3_0_c12_c12_5", sre | 1);
+            asm!("isb", options(nomem, nostack, preserves_flags));
+
+            // ICC_PMR_EL1: let every priority through; ICC_IGRPEN1_EL1: enable group 1
+            write_sysreg!("s3_0_c4_c6_0", 0xffu64);
+            write_sysreg!("s3_0_c12_c12_7", 1u64);
+            asm!("isb", options(nomem, nostack, preserves_flags));
+        }
+    } else {
+        let gicc = CPU_INTERFACE.load(Ordering::Relaxed);
+        let gicd = DISTRIBUTOR.load(Ordering::Relaxed);
+
+        // GICD_ISENABLER0 is banked per CPU and covers SGIs and PPIs
+        mmio_write(gicd + GICD_ISENABLER, 0xffff);
+        mmio_write(gicc + GICC_PMR, 0xff);
+        mmio_write(gicc + GICC_CTLR, 1);
+    }
+}
+
+// MPIDR_EL1 affinity fields packed into 32 bits as Aff3.Aff2.Aff1.Aff0
+fn packed_affinity() -> u32 {
+    let mpidr = read_sysreg!("mpidr_el1");
+    ((((mpidr >> 32) & 0xff) << 24) | (mpidr & 0xff_ffff)) as u32
+}
+
+// Scans the redistributor region for the frame whose affinity matches this CPU
+fn this_redistributor() -> Option<u64> {
+    let affinity = packed_affinity() as u64;
+    let mut rd = REDISTRIBUTORS.load(Ordering::Relaxed);
+
+    loop {
+        let typer = unsafe { ptr::read_volatile((rd + GICR_TYPER) as *const u64) };
+
+        if typer >> 32 == affinity {
+            return Some(rd);
+        }
+
+        if typer & GICR_TYPER_LAST != 0 {
+            return None;
+        }
+
+        rd += if typer & GICR_TYPER_VLPIS != 0 { 0x4_0000 } else { 0x2_0000 };
+    }
+}
+
+/// ID other CPUs address this one by: the GICv2 CPU interface number, or the packed MPIDR
+/// affinity with GICv3.
+pub fn cpu_id() -> u32 {
+    if version() >= 3 {
+        return packed_affinity();
+    }
+
+    // GICD_ITARGETSR0 is banked and reads as this CPU's own target bit
+    let mask = mmio_read(DISTRIBUTOR.load(Ordering::Relaxed) + GICD_ITARGETSR) & 0xff;
+    mask.trailing_zeros()
+}
+
+/// Unmasks interrupt `intid` (a PPI of this CPU, or an SPI).
+pub fn enable(intid: u32) {
+    let bit = 1 << (intid % 32);
+
+    if intid < 32 && version() >= 3 {
+        if let Some(rd) = this_redistributor() {
+            mmio_write(rd + GICR_ISENABLER0, bit);
+        }
+        return;
+    }
+
+    let gicd = DISTRIBUTOR.load(Ordering::Relaxed);
+    mmio_write(gicd + GICD_ISENABLER + 4 * (intid / 32) as u64, bit);
+}
+
+/// Sends SGI `sgi` to the CPU with ID `target` (see [`cpu_id`]).
+pub fn send_sgi(target: u32, sgi: u32) {
+    // Make prior memory writes visible to the target before it takes the interrupt
+    unsafe { asm!("dsb ishst", options(nostack, preserves_flags)) };
+
+    if version() >= 3 {
+        let (aff3, aff2, aff1, aff0) = (target >> 24, (target >> 16) & 0xff, (target >> 8) & 0xff, target & 0xff);
+        let value = (aff3 as u64) << 48
+            | (aff2 as u64) << 32
+            | (sgi as u64 & 0xf) << 24
+            | (aff1 as u64) << 16
+            | 1 << (aff0 & 0xf);
+        write_sgi1r(value);
+    } else {
+        let gicd = DISTRIBUTOR.load(Ordering::Relaxed);
+        mmio_write(gicd + GICD_SGIR, 1 << (16 + (target & 7)) | (sgi & 0xf));
+    }
+}
+
+/// Sends SGI `sgi` to every CPU but the caller.
+pub fn send_sgi_all_but_self(sgi: u32) {
+    unsafe { asm!("dsb ishst", options(nostack, preserves_flags)) };
+
+    if version() >= 3 {
+        write_sgi1r(SGI1R_IRM | (sgi as u64 & 0xf) << 24);
+    } else {
+        let gicd = DISTRIBUTOR.load(Ordering::Relaxed);
+        mmio_write(gicd + GICD_SGIR, SGIR_ALL_BUT_SELF | (sgi & 0xf));
+    }
+}
+
+fn write_sgi1r(value: u64) {
+    unsafe {
+        write_sysreg!("s3_0_c12_c11_5", value);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Acknowledges the highest-priority pending interrupt, returning its ID, or `None` if nothing
+/// (or only a special ID) is pending. Every acknowledged ID must be passed to
+/// [`end_of_interrupt`].
+pub fn acknowledge() -> Option<u32> {
+    let iar = if version() >= 3 {
+        // ICC_IAR1_EL1
+        read_sysreg!("s3_0_c12_c12_0") as u32
+    } else {
+        let iar = mmio_read(CPU_INTERFACE.load(Ordering::Relaxed) + GICC_IAR);
+        ACKNOWLEDGED.with(|acknowledged| *acknowledged = iar);
+        iar
+    };
+
+    let intid = iar & 0x3ff;
+    (intid < SPECIAL_INTID).then_some(intid)
+}
+
+/// Signals that interrupt `intid` has been handled.
+pub fn end_of_interrupt(intid: u32) {
+    if version() >= 3 {
+        // ICC_EOIR1_EL1
+        unsafe { write_sysreg!("s3_0_c12_c12_1", intid) };
+        return;
+    }
+
+    let iar = ACKNOWLEDGED.with(|acknowledged| *acknowledged);
+    let value = if iar & 0x3ff == intid { iar } else { intid };
+    mmio_write(CPU_INTERFACE.load(Ordering::Relaxed) + GICC_EOIR, value);
+}
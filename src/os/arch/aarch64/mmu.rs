@@ -0,0 +1,249 @@
+//! Stage 1 EL1 translation with a 4 KiB granule: up to four levels (L0 to L3) of 512-entry
+//! tables for 48-bit virtual addresses, with 1 GiB and 2 MiB blocks at L1 and L2.
+//!
+//! UEFI enters the kernel with the MMU on and its identity map in TTBR0_EL1. [`init`] checks
+//! the geometry the firmware chose and finds the MAIR_EL1 slot holding normal write-back
+//! memory, so entries built by the kernel mean the same as the firmware's. The kernel uses no
+//! ASIDs: user mappings are non-global and dropped whenever the root changes.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Entries per table.
+pub const ENTRIES: usize = 512;
+
+/// A translation table at any level.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(4096))]
+pub struct Table {
+    pub entries: [u64; ENTRIES],
+}
+
+// Descriptor bits
+
+/// The entry is valid.
+pub const VALID: u64 = 1 << 0;
+
+/// At L0-L2 the entry points to the next table (a block otherwise); at L3 it must be set for a page.
+pub const TABLE: u64 = 1 << 1;
+
+/// Shift of the MAIR_EL1 slot index.
+pub const ATTR_INDEX_SHIFT: u64 = 2;
+
+/// EL0 may access the mapping (AP[1]).
+pub const AP_EL0: u64 = 1 << 6;
+
+/// The mapping is read-only at every level (AP[2]).
+pub const AP_READ_ONLY: u64 = 1 << 7;
+
+/// Inner shareable.
+pub const SH_INNER: u64 = 0b11 << 8;
+
+/// Access flag; without it the first access faults.
+pub const ACCESSED: u64 = 1 << 10;
+
+/// Tagged with the ASID, i.e. belongs to one address space.
+pub const NOT_GLOBAL: u64 = 1 << 11;
+
+/// EL1 may not execute from the mapping.
+pub const PXN: u64 = 1 << 53;
+
+/// EL0 may not execute from the mapping.
+pub const UXN: u64 = 1 << 54;
+
+/// Bits of a descriptor holding the output address.
+pub const ADDRESS_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// MAIR attribute encoding of normal, inner/outer write-back cacheable memory.
+pub const MAIR_NORMAL_WRITE_BACK: u8 = 0xff;
+
+// TCR_EL1 fields for TTBR0
+const TCR_T0SZ_MASK: u64 = 0x3f;
+const TCR_TG0_SHIFT: u64 = 14;
+const TCR_TG0_4K: u64 = 0b00;
+
+// Translation levels in use (4 for 48-bit addresses) and the MAIR slot of normal memory
+static LEVELS: AtomicU8 = AtomicU8::new(4);
+static NORMAL_INDEX: AtomicU8 = AtomicU8::new(0);
+
+/// Reads the firmware's translation setup. Called once at boot.
+pub fn init() {
+    let tcr = read_sysreg!("tcr_el1");
+    assert_eq!((tcr >> TCR_TG0_SHIFT) & 0b11, TCR_TG0_4K, "mmu: firmware does not use a 4 KiB granule");
+
+    // Each level resolves 9 bits above the 12-bit page offset
+    let va_bits = 64 - (tcr & TCR_T0SZ_MASK);
+    LEVELS.store(va_bits.saturating_sub(12).div_ceil(9) as u8, Ordering::Relaxed);
+
+    let mair = read_sysreg!("mair_el1").to_le_bytes();
+    match mair.iter().position(|&attr| attr == MAIR_NORMAL_WRITE_BACK) {
+        Some(index) => NORMAL_INDEX.store(index as u8, Ordering::Relaxed),
+        None => log::warn!("mmu: no write-back slot in MAIR_EL1 ({:#x}), using slot 0", u64::from_le_bytes(mair)),
+    }
+}
+
+/// Number of translation levels in use.
+pub fn levels() -> u8 {
+    LEVELS.load(Ordering::Relaxed)
+}
+
+/// Physical address of the TTBR0_EL1 table.
+pub fn root() -> u64 {
+    read_sysreg!("ttbr0_el1") & ADDRESS_MASK
+}
+
+/// Switches TTBR0_EL1 to `root` and drops the old address space's translations.
+///
+/// # Safety
+/// `root` must map the kernel, including the running code and stack.
+pub unsafe fn set_root(root: u64) {
+    unsafe {
+        write_sysreg!("ttbr0_el1", root);
+        asm!("isb", options(nostack, preserves_flags));
+    }
+
+    flush_local();
+}
+
+/// Leaf descriptor bits for normal memory with the given rights.
+///
+/// The kernel never executes user pages (PXN) and user code never executes kernel pages (UXN).
+pub fn leaf_flags(write: bool, exec: bool, user: bool) -> u64 {
+    let attr_index = NORMAL_INDEX.load(Ordering::Relaxed) as u64;
+    let mut flags = VALID | TABLE | ACCESSED | SH_INNER | attr_index << ATTR_INDEX_SHIFT;
+
+    if !write {
+        flags |= AP_READ_ONLY;
+    }
+
+    if user {
+        flags |= AP_EL0 | NOT_GLOBAL | PXN;
+        if !exec {
+            flags |= UXN;
+        }
+    } else {
+        flags |= UXN;
+        if !exec {
+            flags |= PXN;
+        }
+    }
+
+    flags
+}
+
+/// Translates `virt` through the tables at `root`, returning the physical address, or `None`
+/// if it is not mapped. The tables must be identity-mapped.
+pub fn translate(root: u64, virt: u64) -> Option<u64> {
+    let levels = levels() as u64;
+    let mut table = root;
+
+    for level in (4 - levels)..4 {
+        let shift = 12 + 9 * (3 - level);
+        let index = (virt >> shift) as usize % ENTRIES;
+        let entry = unsafe { (*(table as *const Table)).entries[index] };
+
+        if entry & VALID == 0 {
+            return None;
+        }
+
+        // A block (or the final page): the entry maps everything below this level's shift
+        if level == 3 || entry & TABLE == 0 {
+            let offset_mask = (1u64 << shift) - 1;
+            return Some((entry & ADDRESS_MASK & !offset_mask) | (virt & offset_mask));
+        }
+
+        table = entry & ADDRESS_MASK;
+    }
+
+    None
+}
+
+/// Output address of a valid page or table descriptor.
+pub fn address(entry: u64) -> u64 {
+    entry & ADDRESS_MASK
+}
+
+/// The L3 descriptor for the 4 KiB page containing `virt` in the tables at `root`, or `None`
+/// if a table on the way is missing or `virt` lies in a block. The tables must be
+/// identity-mapped.
+pub fn leaf(root: u64, virt: u64) -> Option<*mut u64> {
+    walk(root, virt, 3, None)
+}
+
+/// Like [`leaf`], but creates missing tables in frames from `alloc`, which it zeroes.
+pub fn leaf_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+    walk(root, virt, 3, Some(alloc))
+}
+
+/// The L2 descriptor for the 2 MiB region containing `virt` in the tables at `root`, or `None`
+/// if a table on the way is missing or `virt` lies in a 1 GiB block.
+pub fn block(root: u64, virt: u64) -> Option<*mut u64> {
+    walk(root, virt, 2, None)
+}
+
+/// A table descriptor pointing to the table at `phys`.
+pub fn table_descriptor(phys: u64) -> u64 {
+    phys | VALID | TABLE
+}
+
+// Walks down to the descriptor at level `last` through valid table descriptors, creating
+// missing ones with `alloc` if given
+fn walk(root: u64, virt: u64, last: u64, mut alloc: Option<&mut dyn FnMut() -> Option<u64>>) -> Option<*mut u64> {
+    let levels = levels() as u64;
+    let mut table = root;
+
+    for level in (4 - levels)..=last {
+        let shift = 12 + 9 * (3 - level);
+        let index = (virt >> shift) as usize % ENTRIES;
+        let entry = unsafe { &raw mut (*(table as *mut Table)).entries[index] };
+
+        if level == last {
+            return Some(entry);
+        }
+
+        let mut descriptor = unsafe { *entry };
+        if descriptor & VALID == 0 {
+            let new = alloc.as_mut()?()?;
+            unsafe {
+                (new as *mut Table).write_bytes(0, 1);
+                descriptor = table_descriptor(new);
+                *entry = descriptor;
+            }
+        }
+
+        if descriptor & TABLE == 0 {
+            return None;
+        }
+
+        table = descriptor & ADDRESS_MASK;
+    }
+
+    None
+}
+
+/// Invalidates this CPU's translation for the page containing `addr` in every address space.
+#[inline]
+pub fn flush_page(addr: u64) {
+    let page = (addr >> 12) & 0x0fff_ffff_ffff;
+
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1, {}",
+            "dsb nsh",
+            "isb",
+            in(reg) page,
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+/// Invalidates this CPU's non-global translations (those of ASID 0, the only one in use).
+pub fn flush_local() {
+    unsafe { asm!("dsb ishst", "tlbi aside1, xzr", "dsb nsh", "isb", options(nostack, preserves_flags)) };
+}
+
+/// Invalidates all of this CPU's EL1 translations.
+pub fn flush_all_local() {
+    unsafe { asm!("dsb ishst", "tlbi vmalle1", "dsb nsh", "isb", options(nostack, preserves_flags)) };
+}
@@ -0,0 +1,303 @@
+//! The AArch64 port: GICv2/GICv3 interrupt controllers, the ARM generic timer, and 4-level
+//! translation tables with a 4 KiB granule, as found on QEMU's `virt` machine and UEFI-booted
+//! ARM servers. The kernel runs at EL1.
+
+/// Reads a system register by name (`read_sysreg!("cntfrq_el0")`).
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { core::arch::asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }};
+}
+
+/// Writes a system register by name. Expands to an `asm!`, so the caller provides the `unsafe`.
+macro_rules! write_sysreg {
+    ($reg:literal, $value:expr) => {
+        core::arch::asm!(concat!("msr ", $reg, ", {}"), in(reg) $value as u64, options(nostack, preserves_flags))
+    };
+}
+
+pub mod context;
+pub mod fpu;
+pub mod gic;
+pub mod mmu;
+pub mod timer;
+pub mod uart;
+
+use core::arch::asm;
+
+use super::{Arch, ContextEntry, GuardedEntry};
+
+/// The AArch64 port.
+pub struct AArch64;
+
+// DAIF.I: IRQs are masked
+const DAIF_IRQ: u64 = 1 << 7;
+
+// ID_AA64ISAR0_EL1.RNDR: the RNDR/RNDRRS registers exist
+const ISAR0_RNDR_SHIFT: u64 = 60;
+
+// ID_AA64MMFR1_EL1.PAN: PSTATE.PAN exists
+const MMFR1_PAN_SHIFT: u64 = 20;
+
+// SCTLR_EL1.SPAN: when clear, taking an exception to EL1 sets PSTATE.PAN
+const SCTLR_SPAN: u64 = 1 << 23;
+
+/// Checks the translation regime the firmware left and brings up the boot CPU's interrupt
+/// controller and timer. Called once at boot after ACPI discovery and before anything sends
+/// IPIs or asks for the hardware CPU ID.
+pub fn init() {
+    mmu::init();
+    gic::init();
+    timer::init_cpu();
+
+    log::info!(
+        "aarch64: GICv{}, timer at {} Hz, {}-level translation",
+        gic::version(),
+        timer::frequency(),
+        mmu::levels()
+    );
+}
+
+/// Brings up an application processor's GIC CPU interface and timer.
+pub fn init_cpu() {
+    gic::init_cpu();
+    timer::init_cpu();
+}
+
+impl Arch for AArch64 {
+    type Context = context::Context;
+
+    const RESCHEDULE_IPI: u32 = 0;
+    const CALL_FUNCTION_IPI: u32 = 1;
+    const TIMER_IRQ: u32 = timer::VIRTUAL_TIMER_PPI;
+    const COUNTER_NAME: &'static str = "arch_sys_counter";
+    // Only TTBR0 is in use, so the window is in the lower half: at 256 GiB, within reach of the
+    // smallest (39-bit) address space firmware configures
+    const VMALLOC_START: u64 = 0x0000_0040_0000_0000;
+
+    #[inline]
+    fn interrupts_enabled() -> bool {
+        read_sysreg!("daif") & DAIF_IRQ == 0
+    }
+
+    #[inline]
+    fn enable_interrupts() {
+        unsafe { asm!("msr daifclr, #2", options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn disable_interrupts() {
+        unsafe { asm!("msr daifset, #2", options(nomem, nostack)) };
+    }
+
+    fn wait_for_interrupt() {
+        // WFI wakes on a pending interrupt even while IRQs are masked, which is then taken as
+        // soon as they are unmasked, so nothing arriving before the WFI is missed
+        unsafe { asm!("wfi", "msr daifclr, #2", options(nomem, nostack)) };
+    }
+
+    fn halt() -> ! {
+        unsafe { asm!("msr daifset, #0xf", options(nomem, nostack)) };
+
+        loop {
+            unsafe { asm!("wfi", options(nomem, nostack)) };
+        }
+    }
+
+    unsafe fn set_percpu_base(base: u64) {
+        unsafe { write_sysreg!("tpidr_el1", base) };
+    }
+
+    #[inline]
+    fn percpu_base() -> u64 {
+        read_sysreg!("tpidr_el1")
+    }
+
+    fn hardware_cpu_id() -> u32 {
+        gic::cpu_id()
+    }
+
+    fn send_ipi(hw_id: u32, ipi: u32) {
+        gic::send_sgi(hw_id, ipi);
+    }
+
+    fn send_ipi_all_but_self(ipi: u32) {
+        gic::send_sgi_all_but_self(ipi);
+    }
+
+    fn end_of_interrupt(irq: u32) {
+        gic::end_of_interrupt(irq);
+    }
+
+    fn page_table_root() -> u64 {
+        mmu::root()
+    }
+
+    unsafe fn set_page_table_root(root: u64) {
+        unsafe { mmu::set_root(root) };
+    }
+
+    #[inline]
+    fn flush_tlb_page(addr: u64) {
+        mmu::flush_page(addr);
+    }
+
+    fn flush_tlb_local() {
+        mmu::flush_local();
+    }
+
+    fn flush_tlb_all_local() {
+        mmu::flush_all_local();
+    }
+
+    fn page_flags(write: bool, exec: bool, user: bool) -> u64 {
+        mmu::leaf_flags(write, exec, user)
+    }
+
+    fn entry_address(entry: u64) -> u64 {
+        mmu::address(entry)
+    }
+
+    fn accessed_flag() -> u64 {
+        mmu::ACCESSED
+    }
+
+    fn leaf_entry(root: u64, virt: u64) -> Option<*mut u64> {
+        mmu::leaf(root, virt)
+    }
+
+    fn huge_entry(root: u64, virt: u64) -> Option<*mut u64> {
+        mmu::block(root, virt)
+    }
+
+    fn huge_flags(flags: u64) -> u64 {
+        // Blocks are told from table descriptors by bit 1 being clear
+        flags & !mmu::TABLE
+    }
+
+    fn split_flags(huge_flags: u64) -> u64 {
+        huge_flags | mmu::TABLE
+    }
+
+    fn is_huge(entry: u64) -> bool {
+        entry & (mmu::VALID | mmu::TABLE) == mmu::VALID
+    }
+
+    fn table_entry(phys: u64) -> u64 {
+        mmu::table_descriptor(phys)
+    }
+
+    fn leaf_entry_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+        mmu::leaf_or_create(root, virt, alloc)
+    }
+
+    fn init_memory_protection() {
+        // PXN/UXN are part of the base architecture and AP read-only binds EL1 too, so unlike
+        // x86 there is nothing to switch on
+    }
+
+    fn init_user_access_protection() -> (bool, bool) {
+        // Execution: user pages always carry PXN (see mmu::leaf_flags)
+        let pan = (read_sysreg!("id_aa64mmfr1_el1") >> MMFR1_PAN_SHIFT) & 0xf != 0;
+
+        if pan {
+            let sctlr = read_sysreg!("sctlr_el1");
+            unsafe {
+                write_sysreg!("sctlr_el1", sctlr & !SCTLR_SPAN);
+                asm!("isb", options(nostack, preserves_flags));
+            }
+
+            Self::user_access_end();
+        }
+
+        (true, pan)
+    }
+
+    #[inline]
+    fn user_access_begin() {
+        // msr pan, #0 (encoded by hand: the target baseline predates ARMv8.1)
+        unsafe { asm!(".inst 0xd500409f", options(nomem, nostack, preserves_flags)) };
+    }
+
+    #[inline]
+    fn user_access_end() {
+        // msr pan, #1
+        unsafe { asm!(".inst 0xd500419f", options(nomem, nostack, preserves_flags)) };
+    }
+
+    #[inline]
+    fn read_counter() -> u64 {
+        timer::read()
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        Some(timer::frequency())
+    }
+
+    fn counter_is_stable() -> bool {
+        // The system counter runs at a fixed frequency by definition
+        true
+    }
+
+    fn has_timer() -> bool {
+        true
+    }
+
+    fn arm_timer(deadline: u64) {
+        timer::arm(deadline);
+    }
+
+    fn disarm_timer() {
+        timer::disarm();
+    }
+
+    fn hardware_random() -> Option<u64> {
+        if (read_sysreg!("id_aa64isar0_el1") >> ISAR0_RNDR_SHIFT) & 0xf == 0 {
+            return None;
+        }
+
+        // RNDR sets NZCV to 0b0100 when it could not deliver; retry a few times like RDRAND
+        for _ in 0..10 {
+            let (value, ok): (u64, u64);
+            unsafe { asm!("mrs {}, s3_3_c2_c4_0", "cset {}, ne", out(reg) value, out(reg) ok, options(nomem, nostack)) };
+
+            if ok != 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    #[inline(always)]
+    fn frame_pointer() -> usize {
+        let fp: usize;
+        unsafe { asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+        fp
+    }
+
+    #[inline(always)]
+    fn stack_pointer() -> usize {
+        let sp: usize;
+        unsafe { asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+        sp
+    }
+
+    fn new_context(stack_top: u64, entry: ContextEntry, arg: usize) -> Self::Context {
+        context::Context::new(stack_top, entry, arg)
+    }
+
+    unsafe fn switch_context(prev: *mut Self::Context, next: *const Self::Context) {
+        unsafe { context::switch(prev, next) };
+    }
+
+    unsafe fn call_with_context(point: *mut Self::Context, entry: GuardedEntry, arg: *const ()) -> bool {
+        unsafe { context::call_with(point, entry, arg) != 0 }
+    }
+
+    unsafe fn resume_context(point: *const Self::Context) -> ! {
+        unsafe { context::resume(point) }
+    }
+}
@@ -0,0 +1,49 @@
+//! ARM generic timer: the system counter as seen through CNTVCT_EL0, and each CPU's EL1 virtual
+//! timer, which fires when the counter reaches the programmed compare value.
+
+use core::arch::asm;
+
+use super::gic;
+
+/// PPI of the EL1 virtual timer.
+pub const VIRTUAL_TIMER_PPI: u32 = 27;
+
+// CNTV_CTL_EL0 bits
+const CTL_ENABLE: u64 = 1 << 0;
+const CTL_IMASK: u64 = 1 << 1;
+
+/// Frequency of the system counter in Hz, as programmed by the firmware.
+pub fn frequency() -> u64 {
+    read_sysreg!("cntfrq_el0")
+}
+
+/// Reads the virtual count.
+#[inline]
+pub fn read() -> u64 {
+    // Without the barrier the read may be performed early, out of order with earlier code
+    unsafe { asm!("isb", options(nomem, nostack, preserves_flags)) };
+    read_sysreg!("cntvct_el0")
+}
+
+/// Programs this CPU's virtual timer to fire once the count reaches `deadline`.
+pub fn arm(deadline: u64) {
+    unsafe {
+        write_sysreg!("cntv_cval_el0", deadline);
+        write_sysreg!("cntv_ctl_el0", CTL_ENABLE);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Stops this CPU's virtual timer from firing.
+pub fn disarm() {
+    unsafe {
+        write_sysreg!("cntv_ctl_el0", CTL_IMASK);
+        asm!("isb", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Disarms this CPU's timer and unmasks its PPI at the GIC, so a later [`arm`] interrupts.
+pub fn init_cpu() {
+    disarm();
+    gic::enable(VIRTUAL_TIMER_PPI);
+}
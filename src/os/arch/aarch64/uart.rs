@@ -0,0 +1,55 @@
+//! PL011 UART, the early console on QEMU `virt` and most ARM boards.
+
+use core::ptr;
+
+/// MMIO base of the PL011 on QEMU `virt`.
+const PL011_BASE: u64 = 0x0900_0000;
+
+// Register offsets
+const DATA: u64 = 0x00;
+const FLAG: u64 = 0x18;
+const LINE_CONTROL: u64 = 0x2c;
+const CONTROL: u64 = 0x30;
+const INTERRUPT_MASK: u64 = 0x38;
+
+// Flag register bit set while the transmit FIFO is full
+const FLAG_TX_FULL: u32 = 1 << 5;
+
+// Line control: FIFOs on, 8 data bits
+const LINE_CONTROL_FIFO_ENABLE: u32 = 1 << 4;
+const LINE_CONTROL_WORD_LENGTH_8: u32 = 0b11 << 5;
+
+// Control: UART, transmitter and receiver enabled
+const CONTROL_ENABLE: u32 = 1 << 0;
+const CONTROL_TX_ENABLE: u32 = 1 << 8;
+const CONTROL_RX_ENABLE: u32 = 1 << 9;
+
+fn write(offset: u64, value: u32) {
+    unsafe { ptr::write_volatile((PL011_BASE + offset) as *mut u32, value) };
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { ptr::read_volatile((PL011_BASE + offset) as *const u32) }
+}
+
+/// Programs the PL011 for 8N1 with FIFOs enabled. The baud rate divisors depend on the UART's
+/// reference clock, so the firmware's setting is kept.
+pub fn init() {
+    // The line control register may only change while the UART is disabled
+    write(CONTROL, 0);
+
+    // We only ever poll
+    write(INTERRUPT_MASK, 0);
+
+    write(LINE_CONTROL, LINE_CONTROL_FIFO_ENABLE | LINE_CONTROL_WORD_LENGTH_8);
+    write(CONTROL, CONTROL_ENABLE | CONTROL_TX_ENABLE | CONTROL_RX_ENABLE);
+}
+
+/// Writes a single byte, spinning while the transmit FIFO is full.
+pub fn write_byte(byte: u8) {
+    while read(FLAG) & FLAG_TX_FULL != 0 {
+        core::hint::spin_loop();
+    }
+
+    write(DATA, byte as u32);
+}
@@ -0,0 +1,36 @@
+//! The architecture's free-running counter (the TSC on x86, the generic timer's virtual count
+//! on ARM) in calibrated units, for timestamps and short elapsed-time measurements.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Arch, Current};
+
+// Counter ticks per millisecond, from the hardware or measured once at boot (0 until known)
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the counter.
+///
+/// The counter ticks at a platform-specific rate; callers that need wall-clock units must wait
+/// until it has been calibrated (see [`ticks_per_ms`]).
+#[inline]
+pub fn read() -> u64 {
+    Current::read_counter()
+}
+
+/// Records the counter rate, as reported by the hardware or measured against a known time source.
+pub fn set_ticks_per_ms(ticks: u64) {
+    TICKS_PER_MS.store(ticks.max(1), Ordering::Relaxed);
+}
+
+/// Counter ticks per millisecond, or 0 if the counter has not been calibrated yet.
+pub fn ticks_per_ms() -> u64 {
+    TICKS_PER_MS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds elapsed since the counter value `start` (0 if not calibrated).
+pub fn millis_since(start: u64) -> u64 {
+    match ticks_per_ms() {
+        0 => 0,
+        rate => read().wrapping_sub(start) / rate,
+    }
+}
@@ -0,0 +1,64 @@
+use super::{Arch, Current};
+
+// Where a frame record keeps the caller's frame pointer and the return address, relative to
+// the frame pointer: at it on x86_64 and AArch64, just below it on RISC-V
+#[cfg(not(target_arch = "riscv64"))]
+const SAVED_FP_OFFSET: isize = 0;
+#[cfg(not(target_arch = "riscv64"))]
+const RETURN_ADDRESS_OFFSET: isize = 8;
+#[cfg(target_arch = "riscv64")]
+const SAVED_FP_OFFSET: isize = -16;
+#[cfg(target_arch = "riscv64")]
+const RETURN_ADDRESS_OFFSET: isize = -8;
+
+/// Collects up to `out.len()` return addresses by walking the frame-pointer chain, skipping the
+/// first `skip` frames above the caller. Returns how many addresses were written.
+///
+/// Relies on the kernel being built with frame pointers (see `.cargo/config.toml`); the walk
+/// stops early at a null or misaligned frame pointer. Only the position of the frame record
+/// differs between architectures, so the walk itself is shared.
+#[inline(never)]
+pub fn return_addresses(skip: usize, out: &mut [usize]) -> usize {
+    // Our own frame is the first in the chain; its return address points into our caller
+    return_addresses_from(Current::frame_pointer(), skip, out)
+}
+
+/// Like [`return_addresses`], but walks the chain starting at the frame record `fp` points to,
+/// such as the frame pointer an exception interrupted.
+pub fn return_addresses_from(mut fp: usize, skip: usize, out: &mut [usize]) -> usize {
+    let mut written = 0;
+    let mut depth = 0;
+
+    while written < out.len() {
+        if fp == 0 || !fp.is_multiple_of(8) {
+            break;
+        }
+
+        let (saved_fp, return_address) = unsafe {
+            (
+                *(fp.wrapping_add_signed(SAVED_FP_OFFSET) as *const usize),
+                *(fp.wrapping_add_signed(RETURN_ADDRESS_OFFSET) as *const usize),
+            )
+        };
+
+        if return_address == 0 {
+            break;
+        }
+
+        if depth >= skip {
+            out[written] = return_address;
+            written += 1;
+        }
+
+        depth += 1;
+
+        // The stack grows down, so each outer frame must live at a higher address
+        if saved_fp <= fp {
+            break;
+        }
+
+        fp = saved_fp;
+    }
+
+    written
+}
@@ -0,0 +1,246 @@
+//! The architecture boundary.
+//!
+//! Everything the generic kernel needs from the CPU -- masking interrupts, the per-CPU base
+//! register, IPIs, page table roots and TLB invalidation, leaf page table entry bits, the
+//! free-running counter and its timer, and saving/restoring kernel execution contexts -- goes
+//! through the [`Arch`] trait. Each port implements it on a marker type exported here as
+//! [`Current`], so generic code names `arch::Current` and never a specific port.
+//!
+//! Port modules also export a few platform pieces under the same name on every architecture
+//! ([`uart`] for the early console, `fpu` for the floating-point register state). Anything else
+//! in a port (MSRs, CPUID, port I/O, GIC registers, ...) is only for code that is itself
+//! compiled for that architecture.
+
+#[cfg(target_arch = "aarch64")]
+pub mod aarch64;
+pub mod counter;
+pub mod frame;
+#[cfg(target_arch = "riscv64")]
+pub mod riscv64;
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
+
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::{AArch64 as Current, uart};
+#[cfg(target_arch = "riscv64")]
+pub use riscv64::{Riscv64 as Current, uart};
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::{X86_64 as Current, uart};
+
+/// Entry point of a fresh kernel context (see [`Arch::new_context`]); must never return.
+pub type ContextEntry = extern "C" fn(usize) -> !;
+
+/// Function run by [`Arch::call_with_context`].
+pub type GuardedEntry = extern "C" fn(*const ());
+
+/// Operations every architecture port provides.
+///
+/// All methods act on the calling CPU unless they say otherwise.
+pub trait Arch {
+    /// Callee-saved registers, stack pointer and resume address of a kernel context that is
+    /// not running.
+    type Context: Copy + Default;
+
+    /// IPI number used for reschedule requests (an IDT vector on x86, an SGI on ARM, a
+    /// software interrupt bit on RISC-V).
+    const RESCHEDULE_IPI: u32;
+
+    /// IPI number used for cross-CPU function calls.
+    const CALL_FUNCTION_IPI: u32;
+
+    /// Interrupt number of this CPU's timer, as [`Arch::arm_timer`] programs it.
+    const TIMER_IRQ: u32;
+
+    /// Clocksource name of the free-running counter.
+    const COUNTER_NAME: &'static str;
+
+    /// Start of the 1 GiB of kernel virtual addresses `vmalloc` maps into: translated through
+    /// the root the kernel runs on, and far from anything the firmware's identity map covers.
+    const VMALLOC_START: u64;
+
+    /// Returns `true` if maskable interrupts are enabled.
+    fn interrupts_enabled() -> bool;
+
+    fn enable_interrupts();
+
+    fn disable_interrupts();
+
+    /// Enables interrupts and waits for one. Called with interrupts disabled, a wakeup that
+    /// arrives between the caller's last check and the wait is not lost.
+    fn wait_for_interrupt();
+
+    /// Disables interrupts and stops the CPU for good.
+    fn halt() -> !;
+
+    /// Points this CPU's per-CPU base register at `base`, the address of its per-CPU area.
+    ///
+    /// # Safety
+    /// `base` must point to a per-CPU area whose first word holds its own address, and the area
+    /// must live forever.
+    unsafe fn set_percpu_base(base: u64);
+
+    /// The value last passed to [`Arch::set_percpu_base`] on this CPU.
+    fn percpu_base() -> u64;
+
+    /// The ID the interrupt controller uses to address this CPU (local APIC ID on x86, GIC
+    /// target or affinity on ARM, hart ID on RISC-V).
+    fn hardware_cpu_id() -> u32;
+
+    /// Sends IPI `ipi` to the CPU with hardware ID `hw_id`.
+    fn send_ipi(hw_id: u32, ipi: u32);
+
+    /// Sends IPI `ipi` to every other CPU.
+    fn send_ipi_all_but_self(ipi: u32);
+
+    /// Signals the interrupt controller that interrupt `irq` has been handled.
+    fn end_of_interrupt(irq: u32);
+
+    /// Physical address of the page table root in use for the lower (user) half.
+    fn page_table_root() -> u64;
+
+    /// Loads a new page table root, which drops the non-global translations of the old one.
+    ///
+    /// # Safety
+    /// `root` must map the kernel, including the running code and stack.
+    unsafe fn set_page_table_root(root: u64);
+
+    /// Invalidates this CPU's translation for the page containing `addr`, global or not.
+    fn flush_tlb_page(addr: u64);
+
+    /// Invalidates all of this CPU's non-global translations.
+    fn flush_tlb_local();
+
+    /// Invalidates all of this CPU's translations, global ones included.
+    fn flush_tlb_all_local();
+
+    /// Bits of a valid leaf page table entry mapping normal memory with the given rights.
+    /// Combined with the output address by [`Arch::page_entry`]. Never called with
+    /// `write && exec`.
+    fn page_flags(write: bool, exec: bool, user: bool) -> u64;
+
+    /// A page table entry mapping physical address `phys` with `flags`.
+    fn page_entry(phys: u64, flags: u64) -> u64 {
+        phys | flags
+    }
+
+    /// Physical address a valid leaf entry maps; the inverse of [`Arch::page_entry`].
+    fn entry_address(entry: u64) -> u64;
+
+    /// Bit of a leaf entry recording that the page was accessed since the bit was last
+    /// cleared. Where the CPU does not set it itself, an access with it clear faults.
+    fn accessed_flag() -> u64;
+
+    /// The last-level entry for the 4 KiB page containing `virt` in the tables at `root`, or
+    /// `None` if a table on the way is missing or `virt` lies in a larger page. The entry
+    /// itself may be invalid. The tables must be identity-mapped.
+    fn leaf_entry(root: u64, virt: u64) -> Option<*mut u64>;
+
+    /// The entry one level up from the leaves, covering the 2 MiB region containing `virt` in
+    /// the tables at `root`, or `None` if a table on the way is missing or `virt` lies in an
+    /// even larger page. The tables must be identity-mapped.
+    fn huge_entry(root: u64, virt: u64) -> Option<*mut u64>;
+
+    /// Turns leaf bits from [`Arch::page_flags`] into those of a 2 MiB page, for
+    /// [`Arch::page_entry`] to combine with a 2 MiB-aligned address.
+    fn huge_flags(flags: u64) -> u64;
+
+    /// Turns the bits of a 2 MiB page back into those of the 4 KiB pages it splits into.
+    fn split_flags(huge_flags: u64) -> u64;
+
+    /// Whether an entry from [`Arch::huge_entry`] maps a 2 MiB page, rather than pointing to
+    /// a table or being invalid.
+    fn is_huge(entry: u64) -> bool;
+
+    /// An entry pointing to the next-level table at `phys`, leaving access control to the
+    /// entries in it.
+    fn table_entry(phys: u64) -> u64;
+
+    /// Like [`Arch::leaf_entry`], but creates the missing tables on the way in frames taken
+    /// from `alloc` (which the port zeroes). `None` if `alloc` runs out or `virt` lies in a
+    /// larger page.
+    fn leaf_entry_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64>;
+
+    /// Enables whatever the CPU needs for non-executable pages and for read-only pages to bind
+    /// the kernel too. Called once at boot before any page tables are built.
+    fn init_memory_protection();
+
+    /// Turns on the protections against the kernel executing and accessing user memory,
+    /// returning which of the two are active.
+    fn init_user_access_protection() -> (bool, bool);
+
+    /// Opens a window in which the kernel may access user memory (only called when access
+    /// protection is active).
+    fn user_access_begin();
+
+    /// Closes the window opened by [`Arch::user_access_begin`].
+    fn user_access_end();
+
+    /// Reads the free-running counter.
+    fn read_counter() -> u64;
+
+    /// Counter frequency in Hz if the hardware reports it, `None` if it must be calibrated.
+    fn counter_frequency() -> Option<u64>;
+
+    /// Returns `true` if the counter runs at a constant rate regardless of power states.
+    fn counter_is_stable() -> bool;
+
+    /// Returns `true` if this CPU has a one-shot timer [`Arch::arm_timer`] can program.
+    fn has_timer() -> bool;
+
+    /// Programs this CPU's timer to interrupt once the counter reaches `deadline`. Does nothing
+    /// without a timer.
+    fn arm_timer(deadline: u64);
+
+    /// Stops this CPU's timer from firing, and clears its interrupt if it already has.
+    fn disarm_timer();
+
+    /// A random word from the CPU's hardware generator, if it has one and it delivered.
+    fn hardware_random() -> Option<u64>;
+
+    /// Frame pointer of the calling function, the start of its frame record chain.
+    fn frame_pointer() -> usize;
+
+    /// Stack pointer of the calling function.
+    fn stack_pointer() -> usize;
+
+    /// A context that starts running `entry(arg)` on the stack ending at `stack_top`.
+    fn new_context(stack_top: u64, entry: ContextEntry, arg: usize) -> Self::Context;
+
+    /// Saves the running context into `prev` and resumes `next`. Returns once something
+    /// switches back to `prev`.
+    ///
+    /// # Safety
+    /// `next` must hold a context saved by this function or built by [`Arch::new_context`],
+    /// whose stack is still alive.
+    unsafe fn switch_context(prev: *mut Self::Context, next: *const Self::Context);
+
+    /// Saves the running context into `point` and calls `entry(arg)`. Returns `false` when
+    /// `entry` returns, or `true` when [`Arch::resume_context`] abandons it.
+    ///
+    /// # Safety
+    /// `point` must stay valid until this call returns.
+    unsafe fn call_with_context(point: *mut Self::Context, entry: GuardedEntry, arg: *const ()) -> bool;
+
+    /// Discards the current stack and returns `true` from the [`Arch::call_with_context`] call
+    /// that filled `point`.
+    ///
+    /// # Safety
+    /// Must be called (directly or indirectly) from inside that call's `entry`.
+    unsafe fn resume_context(point: *const Self::Context) -> !;
+}
+
+/// Runs `f` with interrupts masked, restoring the previous state afterwards. Nothing else can
+/// run on this CPU meanwhile, so `f` cannot be preempted or migrated.
+#[inline]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = Current::interrupts_enabled();
+    Current::disable_interrupts();
+
+    let result = f();
+
+    if were_enabled {
+        Current::enable_interrupts();
+    }
+
+    result
+}
@@ -0,0 +1,132 @@
+//! Kernel context save and restore.
+//!
+//! A [`Context`] holds what the RISC-V calling convention requires a callee to preserve (s0-s11,
+//! s0 doubling as the frame pointer, and fs0-fs11, which the kernel leaves to the FPU code),
+//! plus the return address to resume at and the stack pointer.
+
+use super::super::{ContextEntry, GuardedEntry};
+
+/// Saved state of a kernel context that is not running.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Context {
+    s: [u64; 12],
+    ra: u64,
+    sp: u64,
+}
+
+impl Context {
+    /// A context that calls `entry(arg)` on the stack ending at `stack_top`.
+    pub fn new(stack_top: u64, entry: ContextEntry, arg: usize) -> Self {
+        let mut context = Context {
+            ra: start_context as *const () as u64,
+            sp: stack_top & !0xf,
+            ..Context::default()
+        };
+
+        context.s[1] = entry as usize as u64;
+        context.s[2] = arg as u64;
+        context
+    }
+}
+
+// First code run by a context from Context::new: s1 holds the entry point, s2 the argument
+#[unsafe(naked)]
+unsafe extern "C" fn start_context() -> ! {
+    core::arch::naked_asm!(
+        "mv s0, zero",
+        "mv a0, s2",
+        "jalr s1",
+        "ebreak",
+    )
+}
+
+/// Saves the running context into `prev` and jumps to `next`.
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(prev: *mut Context, next: *const Context) {
+    core::arch::naked_asm!(
+        "sd s0, 0(a0)",
+        "sd s1, 8(a0)",
+        "sd s2, 16(a0)",
+        "sd s3, 24(a0)",
+        "sd s4, 32(a0)",
+        "sd s5, 40(a0)",
+        "sd s6, 48(a0)",
+        "sd s7, 56(a0)",
+        "sd s8, 64(a0)",
+        "sd s9, 72(a0)",
+        "sd s10, 80(a0)",
+        "sd s11, 88(a0)",
+        "sd ra, 96(a0)",
+        "sd sp, 104(a0)",
+        "ld s0, 0(a1)",
+        "ld s1, 8(a1)",
+        "ld s2, 16(a1)",
+        "ld s3, 24(a1)",
+        "ld s4, 32(a1)",
+        "ld s5, 40(a1)",
+        "ld s6, 48(a1)",
+        "ld s7, 56(a1)",
+        "ld s8, 64(a1)",
+        "ld s9, 72(a1)",
+        "ld s10, 80(a1)",
+        "ld s11, 88(a1)",
+        "ld ra, 96(a1)",
+        "ld sp, 104(a1)",
+        "ret",
+    )
+}
+
+/// Saves the running context into `point`, then calls `entry(arg)`. Returns 0 when `entry`
+/// returns normally, or 1 when [`resume`] is used to bail out.
+#[unsafe(naked)]
+pub unsafe extern "C" fn call_with(point: *mut Context, entry: GuardedEntry, arg: *const ()) -> u64 {
+    core::arch::naked_asm!(
+        "sd s0, 0(a0)",
+        "sd s1, 8(a0)",
+        "sd s2, 16(a0)",
+        "sd s3, 24(a0)",
+        "sd s4, 32(a0)",
+        "sd s5, 40(a0)",
+        "sd s6, 48(a0)",
+        "sd s7, 56(a0)",
+        "sd s8, 64(a0)",
+        "sd s9, 72(a0)",
+        "sd s10, 80(a0)",
+        "sd s11, 88(a0)",
+        "sd ra, 96(a0)",
+        "sd sp, 104(a0)",
+        // s1 is saved and preserved by the callee: keep `point` there to reload ra afterwards
+        "mv s1, a0",
+        "mv a0, a2",
+        "jalr a1",
+        "mv t0, s1",
+        "ld s1, 8(t0)",
+        "ld ra, 96(t0)",
+        "li a0, 0",
+        "ret",
+    )
+}
+
+/// Restores the context saved by [`call_with`] and returns 1 from it.
+#[unsafe(naked)]
+pub unsafe extern "C" fn resume(point: *const Context) -> ! {
+    core::arch::naked_asm!(
+        "ld s0, 0(a0)",
+        "ld s1, 8(a0)",
+        "ld s2, 16(a0)",
+        "ld s3, 24(a0)",
+        "ld s4, 32(a0)",
+        "ld s5, 40(a0)",
+        "ld s6, 48(a0)",
+        "ld s7, 56(a0)",
+        "ld s8, 64(a0)",
+        "ld s9, 72(a0)",
+        "ld s10, 80(a0)",
+        "ld s11, 88(a0)",
+        "ld ra, 96(a0)",
+        "ld sp, 104(a0)",
+        "li a0, 1",
+        "ret",
+    )
+}
@@ -0,0 +1,195 @@
+//! Floating-point register state management (f0-f31 and fcsr, with the D extension).
+//!
+//! Each process owns an [`FpuState`] in its PCB. The state is always 32 64-bit registers plus
+//! fcsr, so there is nothing to size at boot. sstatus.FS gates the registers: while it is Off
+//! every FP instruction traps, so [`init`] turns it on and switching is always eager.
+
+use core::arch::asm;
+
+use crate::os::process::Process;
+
+/// Size of the per-process save area.
+pub const FPU_AREA_SIZE: usize = size_of::<FpuState>();
+
+// sstatus.FS: Initial, the registers are usable
+const SSTATUS_FS_MASK: u64 = 0b11 << 13;
+const SSTATUS_FS_INITIAL: u64 = 0b01 << 13;
+
+/// A saved floating-point register image.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct FpuState {
+    f: [u64; 32],
+    fcsr: u64,
+}
+
+impl FpuState {
+    /// The state a fresh process starts with: all registers zero, which for fcsr means round
+    /// to nearest with no exceptions flagged.
+    pub const fn new() -> Self {
+        FpuState { f: [0; 32], fcsr: 0 }
+    }
+
+    /// Saves the current register contents into this area.
+    pub fn save(&mut self) {
+        let fcsr: u64;
+
+        unsafe {
+            asm!(
+                "fsd f0, 0({0})",
+                "fsd f1, 8({0})",
+                "fsd f2, 16({0})",
+                "fsd f3, 24({0})",
+                "fsd f4, 32({0})",
+                "fsd f5, 40({0})",
+                "fsd f6, 48({0})",
+                "fsd f7, 56({0})",
+                "fsd f8, 64({0})",
+                "fsd f9, 72({0})",
+                "fsd f10, 80({0})",
+                "fsd f11, 88({0})",
+                "fsd f12, 96({0})",
+                "fsd f13, 104({0})",
+                "fsd f14, 112({0})",
+                "fsd f15, 120({0})",
+                "fsd f16, 128({0})",
+                "fsd f17, 136({0})",
+                "fsd f18, 144({0})",
+                "fsd f19, 152({0})",
+                "fsd f20, 160({0})",
+                "fsd f21, 168({0})",
+                "fsd f22, 176({0})",
+                "fsd f23, 184({0})",
+                "fsd f24, 192({0})",
+                "fsd f25, 200({0})",
+                "fsd f26, 208({0})",
+                "fsd f27, 216({0})",
+                "fsd f28, 224({0})",
+                "fsd f29, 232({0})",
+                "fsd f30, 240({0})",
+                "fsd f31, 248({0})",
+                "frcsr {1}",
+                in(reg) self.f.as_mut_ptr(),
+                out(reg) fcsr,
+                options(nostack, preserves_flags)
+            );
+        }
+
+        self.fcsr = fcsr;
+    }
+
+    /// Loads the registers from this area.
+    pub fn restore(&self) {
+        unsafe {
+            asm!(
+                "fld f0, 0({0})",
+                "fld f1, 8({0})",
+                "fld f2, 16({0})",
+                "fld f3, 24({0})",
+                "fld f4, 32({0})",
+                "fld f5, 40({0})",
+                "fld f6, 48({0})",
+                "fld f7, 56({0})",
+                "fld f8, 64({0})",
+                "fld f9, 72({0})",
+                "fld f10, 80({0})",
+                "fld f11, 88({0})",
+                "fld f12, 96({0})",
+                "fld f13, 104({0})",
+                "fld f14, 112({0})",
+                "fld f15, 120({0})",
+                "fld f16, 128({0})",
+                "fld f17, 136({0})",
+                "fld f18, 144({0})",
+                "fld f19, 152({0})",
+                "fld f20, 160({0})",
+                "fld f21, 168({0})",
+                "fld f22, 176({0})",
+                "fld f23, 184({0})",
+                "fld f24, 192({0})",
+                "fld f25, 200({0})",
+                "fld f26, 208({0})",
+                "fld f27, 216({0})",
+                "fld f28, 224({0})",
+                "fld f29, 232({0})",
+                "fld f30, 240({0})",
+                "fld f31, 248({0})",
+                "fscsr {1}",
+                in(reg) self.f.as_ptr(),
+                in(reg) self.fcsr,
+                // Every FP register is overwritten
+                clobber_abi("C"),
+                options(nostack, readonly, preserves_flags)
+            );
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turns the FP registers on and starts from a clean register state. Called once at boot.
+pub fn init() {
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_FS_MASK, options(nomem, nostack));
+        asm!("csrs sstatus, {}", in(reg) SSTATUS_FS_INITIAL, options(nomem, nostack));
+    }
+
+    // Start from a clean state so the boot context does not leak firmware values
+    FpuState::new().restore();
+
+    log::info!("fpu: {}-byte save area, eager switching", FPU_AREA_SIZE);
+}
+
+/// Bytes of each save area in use.
+pub fn area_size() -> usize {
+    FPU_AREA_SIZE
+}
+
+/// Context switch hook, called with the outgoing and incoming process.
+pub fn switch(prev: &mut Process, next: &Process) {
+    prev.fpu.save();
+    next.fpu.restore();
+}
+
+/// Teardown hook. Switching is always eager, so no CPU holds on to `pid`'s registers.
+pub fn forget(_pid: u64) {}
+
+pub mod ktests {
+    use super::*;
+
+    // fcsr.frm = RTZ (round towards zero), distinguishable from the default of 0
+    const FCSR_RTZ: u64 = 0b001 << 5;
+
+    crate::os::ktest::kernel_test! {
+        fn save_restore_roundtrip() {
+            let mut state = FpuState::new();
+            let readback: u64;
+
+            unsafe { asm!("fscsr {}", in(reg) FCSR_RTZ, options(nomem, nostack)) };
+            state.save();
+            FpuState::new().restore();
+            unsafe { asm!("frcsr {}", out(reg) readback, options(nomem, nostack)) };
+
+            assert_eq!(readback, 0);
+            assert_eq!(state.fcsr, FCSR_RTZ);
+
+            state.restore();
+            let restored: u64;
+            unsafe { asm!("frcsr {}", out(reg) restored, options(nomem, nostack)) };
+            FpuState::new().restore();
+
+            assert_eq!(restored, FCSR_RTZ);
+        }
+
+        fn initial_state_is_zero() {
+            let state = FpuState::new();
+
+            assert_eq!(state.fcsr, 0);
+            assert!(state.f.iter().all(|&f| f == 0));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! PE/COFF image header and entry point.
+//!
+//! LLVM cannot emit COFF objects for RISC-V, so the kernel is linked as a static PIE ELF by
+//! `image.ld` and flattened with `objcopy -O binary` into a file the firmware loads as an EFI
+//! application. Like gnu-efi's `crt0-efi-riscv64.S`, the PE header below is written by hand at
+//! the start of the image and the image carries no PE base relocations: `_start` applies the
+//! ELF `R_RISCV_RELATIVE` relocations for wherever the firmware placed it, then enters
+//! `efi_main` with the image handle and system table untouched.
+//!
+//! File offsets equal virtual addresses, so every header field is a link-time constant.
+
+core::arch::global_asm!(
+    r#"
+    .section .text.head, "a"
+    .globl _pe_header
+
+    // MS-DOS stub: only the signature and the offset of the PE header matter
+    .ascii "MZ"
+    .skip 0x3a
+    .long _pe_header - ImageBase
+
+_pe_header:
+    .ascii "PE\0\0"
+
+    // COFF file header
+    .short 0x5064                               // Machine: IMAGE_FILE_MACHINE_RISCV64
+    .short 2                                    // NumberOfSections
+    .long 0                                     // TimeDateStamp
+    .long 0                                     // PointerToSymbolTable
+    .long 0                                     // NumberOfSymbols
+    .short .Lsection_table - .Loptional_header  // SizeOfOptionalHeader
+    .short 0x0206                               // Characteristics: executable, stripped
+
+    // PE32+ optional header
+.Loptional_header:
+    .short 0x020b                               // Magic: PE32+
+    .byte 0, 0                                  // Linker version
+    .long _etext - _text                        // SizeOfCode
+    .long _edata - _data                        // SizeOfInitializedData
+    .long 0                                     // SizeOfUninitializedData
+    .long _start - ImageBase                    // AddressOfEntryPoint
+    .long _text - ImageBase                     // BaseOfCode
+    .quad 0                                     // ImageBase: relocated by _start
+    .long 0x1000                                // SectionAlignment
+    .long 0x1000                                // FileAlignment
+    .short 0, 0                                 // OS version
+    .short 0, 0                                 // Image version
+    .short 0, 0                                 // Subsystem version
+    .long 0                                     // Win32VersionValue
+    .long _edata - ImageBase                    // SizeOfImage
+    .long _text - ImageBase                     // SizeOfHeaders
+    .long 0                                     // CheckSum
+    .short 10                                   // Subsystem: EFI application
+    .short 0                                    // DllCharacteristics
+    .quad 0, 0, 0, 0                            // Stack and heap reserve/commit
+    .long 0                                     // LoaderFlags
+    .long 6                                     // NumberOfRvaAndSizes
+    .quad 0, 0, 0, 0, 0, 0                      // Export..base relocation directories: none
+
+    // Section table
+.Lsection_table:
+    .ascii ".text\0\0\0"
+    .long _etext - _text                        // VirtualSize
+    .long _text - ImageBase                     // VirtualAddress
+    .long _etext - _text                        // SizeOfRawData
+    .long _text - ImageBase                     // PointerToRawData
+    .long 0, 0                                  // Relocations, line numbers
+    .short 0, 0
+    .long 0x60000020                            // Code, execute, read
+
+    .ascii ".data\0\0\0"
+    .long _edata - _data
+    .long _data - ImageBase
+    .long _edata - _data
+    .long _data - ImageBase
+    .long 0, 0
+    .short 0, 0
+    .long 0xc0000040                            // Initialized data, read, write
+
+    .section .text._start, "ax"
+    .globl _start
+_start:
+    addi sp, sp, -32
+    sd a0, 0(sp)
+    sd a1, 8(sp)
+    sd ra, 16(sp)
+
+    // Walk the dynamic section for DT_RELA (7) and DT_RELASZ (8)
+    lla t5, ImageBase
+    lla t0, _DYNAMIC
+    li t3, 0
+    li t4, 0
+.Lscan_dynamic:
+    ld t1, 0(t0)
+    beqz t1, .Lrelocate
+    ld t2, 8(t0)
+    addi t0, t0, 16
+    li t6, 7
+    bne t1, t6, 1f
+    add t3, t2, t5
+1:
+    li t6, 8
+    bne t1, t6, .Lscan_dynamic
+    mv t4, t2
+    j .Lscan_dynamic
+
+    // Apply every R_RISCV_RELATIVE (3) entry: *(base + offset) = base + addend
+.Lrelocate:
+    beqz t3, .Lenter
+    add t4, t3, t4
+.Lnext_relocation:
+    bgeu t3, t4, .Lenter
+    ld t1, 8(t3)
+    li t6, 3
+    bne t1, t6, 2f
+    ld t1, 0(t3)
+    ld t2, 16(t3)
+    add t1, t1, t5
+    add t2, t2, t5
+    sd t2, 0(t1)
+2:
+    addi t3, t3, 24
+    j .Lnext_relocation
+
+.Lenter:
+    fence.i
+    ld a0, 0(sp)
+    ld a1, 8(sp)
+    call efi_main
+    ld ra, 16(sp)
+    addi sp, sp, 32
+    ret
+"#
+);
@@ -0,0 +1,229 @@
+//! Sv39/Sv48/Sv57 paging: three to five levels of 512-entry tables with 4 KiB pages and
+//! 2 MiB/1 GiB/512 GiB superpages, selected by satp.MODE.
+//!
+//! UEFI enters the kernel either with an identity map already installed or with translation
+//! off (Bare). [`init`] keeps the firmware's mode, or picks Sv39 -- the mode every paging-capable
+//! hart implements -- for the tables the kernel builds itself. Entries hold the physical page
+//! number at bit 10, so addresses go through [`entry`] rather than being or-ed in directly. The
+//! kernel uses no ASIDs: user mappings are non-global and dropped whenever the root changes.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Entries per table.
+pub const ENTRIES: usize = 512;
+
+/// A page table at any level.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(4096))]
+pub struct Table {
+    pub entries: [u64; ENTRIES],
+}
+
+// Entry bits
+
+/// The entry is valid.
+pub const VALID: u64 = 1 << 0;
+
+/// Readable; an entry with none of R, W and X points to the next table.
+pub const READ: u64 = 1 << 1;
+
+/// Writable (only together with R).
+pub const WRITE: u64 = 1 << 2;
+
+/// Executable.
+pub const EXECUTE: u64 = 1 << 3;
+
+/// Accessible from U-mode, and never from S-mode unless sstatus.SUM is set.
+pub const USER: u64 = 1 << 4;
+
+/// Present in every address space.
+pub const GLOBAL: u64 = 1 << 5;
+
+/// Accessed; hardware without Svadu faults on the first access if clear.
+pub const ACCESSED: u64 = 1 << 6;
+
+/// Dirty; hardware without Svadu faults on the first write if clear.
+pub const DIRTY: u64 = 1 << 7;
+
+/// Shift of the physical page number in an entry.
+pub const PPN_SHIFT: u64 = 10;
+
+/// Bits of an entry holding the physical page number.
+pub const PPN_MASK: u64 = 0x003f_ffff_ffff_fc00;
+
+/// satp.MODE values.
+pub const MODE_BARE: u8 = 0;
+pub const MODE_SV39: u8 = 8;
+pub const MODE_SV48: u8 = 9;
+pub const MODE_SV57: u8 = 10;
+
+// satp fields
+const SATP_MODE_SHIFT: u64 = 60;
+const SATP_PPN_MASK: u64 = 0x0000_0fff_ffff_ffff;
+
+static MODE: AtomicU8 = AtomicU8::new(MODE_SV39);
+
+/// Reads the firmware's translation mode. Called once at boot.
+pub fn init() {
+    let mode = (read_csr!("satp") >> SATP_MODE_SHIFT) as u8;
+
+    match mode {
+        MODE_SV39 | MODE_SV48 | MODE_SV57 => MODE.store(mode, Ordering::Relaxed),
+        MODE_BARE => log::info!("mmu: firmware left translation off, using Sv39"),
+        _ => panic!("mmu: unknown satp mode {}", mode),
+    }
+}
+
+/// satp.MODE the kernel's tables use.
+pub fn mode() -> u8 {
+    MODE.load(Ordering::Relaxed)
+}
+
+/// Number of translation levels in use.
+pub fn levels() -> u8 {
+    mode() - MODE_SV39 + 3
+}
+
+/// Physical address of the root table, 0 with translation off.
+pub fn root() -> u64 {
+    (read_csr!("satp") & SATP_PPN_MASK) << 12
+}
+
+/// Switches satp to the tables at `root` and drops the old address space's translations.
+///
+/// # Safety
+/// `root` must map the kernel, including the running code and stack.
+pub unsafe fn set_root(root: u64) {
+    let satp = (mode() as u64) << SATP_MODE_SHIFT | root >> 12;
+    unsafe { write_csr!("satp", satp) };
+
+    // Writing satp does not order or invalidate anything by itself
+    flush_local();
+}
+
+/// Builds an entry mapping `phys` with the given bits.
+pub fn entry(phys: u64, flags: u64) -> u64 {
+    (phys >> 12) << PPN_SHIFT | flags
+}
+
+/// Leaf entry bits for normal memory with the given rights.
+///
+/// S-mode never executes user pages and, with SUM clear, never accesses them either.
+pub fn leaf_flags(write: bool, exec: bool, user: bool) -> u64 {
+    let mut flags = VALID | READ | ACCESSED;
+
+    if write {
+        flags |= WRITE | DIRTY;
+    }
+
+    if exec {
+        flags |= EXECUTE;
+    }
+
+    flags | if user { USER } else { GLOBAL }
+}
+
+/// Translates `virt` through the tables at `root`, returning the physical address, or `None`
+/// if it is not mapped. The tables must be identity-mapped.
+pub fn translate(root: u64, virt: u64) -> Option<u64> {
+    let levels = levels() as u64;
+    let mut table = root;
+
+    for level in (0..levels).rev() {
+        let shift = 12 + 9 * level;
+        let index = (virt >> shift) as usize % ENTRIES;
+        let entry = unsafe { (*(table as *const Table)).entries[index] };
+
+        if entry & VALID == 0 {
+            return None;
+        }
+
+        let phys = (entry & PPN_MASK) >> PPN_SHIFT << 12;
+
+        // A leaf at any level maps everything below this level's shift
+        if entry & (READ | EXECUTE) != 0 {
+            let offset_mask = (1u64 << shift) - 1;
+            return Some((phys & !offset_mask) | (virt & offset_mask));
+        }
+
+        table = phys;
+    }
+
+    None
+}
+
+/// Physical address held by a valid entry; the inverse of [`entry`].
+pub fn address(entry: u64) -> u64 {
+    (entry & PPN_MASK) >> PPN_SHIFT << 12
+}
+
+/// The level-0 entry for the 4 KiB page containing `virt` in the tables at `root`, or `None`
+/// if a table on the way is missing or `virt` lies in a superpage. The tables must be
+/// identity-mapped.
+pub fn leaf(root: u64, virt: u64) -> Option<*mut u64> {
+    walk(root, virt, 0, None)
+}
+
+/// Like [`leaf`], but creates missing tables in frames from `alloc`, which it zeroes.
+pub fn leaf_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+    walk(root, virt, 0, Some(alloc))
+}
+
+/// The level-1 entry for the 2 MiB region containing `virt` in the tables at `root`, or `None`
+/// if a table on the way is missing or `virt` lies in a larger superpage.
+pub fn megapage(root: u64, virt: u64) -> Option<*mut u64> {
+    walk(root, virt, 1, None)
+}
+
+// Walks down to the entry at `last` through valid non-leaf entries, creating missing ones with
+// `alloc` if given
+fn walk(root: u64, virt: u64, last: u64, mut alloc: Option<&mut dyn FnMut() -> Option<u64>>) -> Option<*mut u64> {
+    let levels = levels() as u64;
+    let mut table = root;
+
+    for level in (last..levels).rev() {
+        let shift = 12 + 9 * level;
+        let index = (virt >> shift) as usize % ENTRIES;
+        let entry = unsafe { &raw mut (*(table as *mut Table)).entries[index] };
+
+        if level == last {
+            return Some(entry);
+        }
+
+        let mut value = unsafe { *entry };
+        if value & VALID == 0 {
+            let new = alloc.as_mut()?()?;
+            unsafe {
+                (new as *mut Table).write_bytes(0, 1);
+                value = self::entry(new, VALID);
+                *entry = value;
+            }
+        }
+
+        if value & (READ | EXECUTE) != 0 {
+            return None;
+        }
+
+        table = address(value);
+    }
+
+    None
+}
+
+/// Invalidates this hart's translation for the page containing `addr` in every address space.
+#[inline]
+pub fn flush_page(addr: u64) {
+    unsafe { asm!("sfence.vma {}, zero", in(reg) addr, options(nostack, preserves_flags)) };
+}
+
+/// Invalidates this hart's non-global translations (those of ASID 0, the only one in use).
+pub fn flush_local() {
+    // Any rs2 but x0 selects an ASID and spares global mappings, even when the ASID is 0
+    unsafe { asm!("sfence.vma zero, {}", in(reg) 0u64, options(nostack, preserves_flags)) };
+}
+
+/// Invalidates all of this hart's translations.
+pub fn flush_all_local() {
+    unsafe { asm!("sfence.vma zero, zero", options(nostack, preserves_flags)) };
+}
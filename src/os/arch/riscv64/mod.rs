@@ -0,0 +1,366 @@
+//! The riscv64 port: the kernel runs in S-mode under SBI firmware (OpenSBI on QEMU `virt`),
+//! which owns M-mode and with it the CLINT: timer deadlines, IPIs and hart start-up go through
+//! SBI calls, external interrupts through the PLIC, and translation uses Sv39/Sv48 tables.
+//! UEFI (EDK2 or U-Boot) runs on top of the SBI firmware and loads the kernel as an EFI
+//! application; see `image.rs` for how that image is built.
+//!
+//! S-mode has no register holding the hart ID, so the firmware hands it over (through the
+//! RISC-V boot protocol for the boot hart, in a0 for harts started through SBI) and the kernel
+//! keeps it in sscratch. The per-CPU base lives in tp.
+
+/// Reads a CSR by name (`read_csr!("satp")`).
+macro_rules! read_csr {
+    ($csr:literal) => {{
+        let value: u64;
+        unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }};
+}
+
+/// Writes a CSR by name. Expands to an `asm!`, so the caller provides the `unsafe`.
+macro_rules! write_csr {
+    ($csr:literal, $value:expr) => {
+        core::arch::asm!(concat!("csrw ", $csr, ", {}"), in(reg) $value as u64, options(nostack, preserves_flags))
+    };
+}
+
+pub mod context;
+pub mod fpu;
+mod image;
+pub mod mmu;
+pub mod plic;
+pub mod sbi;
+pub mod timer;
+pub mod uart;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use uefi::prelude::*;
+use uefi::proto::unsafe_protocol;
+
+use super::{Arch, ContextEntry, GuardedEntry};
+
+/// Highest hart ID plus one the port supports; hart masks are a single word.
+pub const MAX_HARTS: usize = 64;
+
+/// The riscv64 port.
+pub struct Riscv64;
+
+// sstatus bits: S-mode interrupts enabled, S-mode may access user pages
+const SSTATUS_SIE: u64 = 1 << 1;
+const SSTATUS_SUM: u64 = 1 << 18;
+
+// sie/sip bits: supervisor software (IPI) and timer interrupts
+const SIE_SSIE: u64 = 1 << 1;
+const SIE_STIE: u64 = 1 << 5;
+
+// IPIs share the one supervisor software interrupt: the sender sets a bit in the target's
+// pending word, numbered above the PLIC's sources so end_of_interrupt can tell them apart
+const IPI_BASE: u32 = plic::SOURCES;
+
+// The supervisor timer interrupt, numbered past the IPIs; it is acknowledged by reprogramming
+// the timer, not at the PLIC
+const TIMER_IRQ_NUMBER: u32 = IPI_BASE + u32::BITS;
+
+// Bit n set: hart n has run init or init_cpu
+static ONLINE_HARTS: AtomicU64 = AtomicU64::new(0);
+
+// IPIs raised but not yet taken by each hart, bit n for IPI IPI_BASE + n
+static PENDING_IPIS: [AtomicU32; MAX_HARTS] = [const { AtomicU32::new(0) }; MAX_HARTS];
+
+/// RISCV_EFI_BOOT_PROTOCOL, through which the firmware reports the boot hart's ID.
+#[repr(C)]
+#[unsafe_protocol("ccd15fec-6f73-4eec-8395-3e69e4b940bf")]
+struct RiscvBoot {
+    revision: u64,
+    get_boot_hart_id: unsafe extern "efiapi" fn(this: *const RiscvBoot, hart_id: *mut usize) -> Status,
+}
+
+fn boot_hart_id(system_table: &SystemTable<Boot>) -> Option<usize> {
+    let bt = system_table.boot_services();
+    let handle = bt.get_handle_for_protocol::<RiscvBoot>().ok()?;
+    let protocol = bt.open_protocol_exclusive::<RiscvBoot>(handle).ok()?;
+
+    let mut hart_id = 0;
+    let status = unsafe { (protocol.get_boot_hart_id)(&*protocol, &mut hart_id) };
+    status.is_success().then_some(hart_id)
+}
+
+/// Finds the boot hart's ID and the SBI extensions, checks the translation mode the firmware
+/// left and brings up the boot hart's PLIC context and timer. Called once at boot after ACPI
+/// discovery and before anything sends IPIs or asks for the hardware CPU ID.
+pub fn init(system_table: &SystemTable<Boot>) {
+    let hart = boot_hart_id(system_table).expect("riscv64: firmware does not report the boot hart ID");
+
+    sbi::init();
+    mmu::init();
+    timer::init();
+    plic::init(hart);
+    init_hart(hart);
+
+    log::info!(
+        "riscv64: boot hart {}, Sv{} translation, timebase {}",
+        hart,
+        9 * mmu::levels() as u32 + 12,
+        match timer::frequency() {
+            Some(_) => "from firmware",
+            None => "to be calibrated",
+        }
+    );
+}
+
+/// Brings up a secondary hart's PLIC context and timer. `hart` is the ID SBI passed in a0.
+pub fn init_cpu(hart: usize) {
+    plic::init_cpu(hart);
+    init_hart(hart);
+}
+
+fn init_hart(hart: usize) {
+    assert!(hart < MAX_HARTS, "riscv64: hart ID {} exceeds MAX_HARTS", hart);
+
+    unsafe {
+        write_csr!("sscratch", hart);
+        asm!("csrs sie, {}", in(reg) SIE_SSIE, options(nomem, nostack));
+    }
+
+    timer::init_cpu();
+    ONLINE_HARTS.fetch_or(1 << hart, Ordering::Release);
+}
+
+/// Clears this hart's supervisor software interrupt and returns the IPIs raised since it was
+/// last called, for the trap handler to dispatch.
+pub fn take_ipis() -> impl Iterator<Item = u32> {
+    unsafe { asm!("csrc sip, {}", in(reg) SIE_SSIE, options(nomem, nostack)) };
+
+    let pending = PENDING_IPIS[Riscv64::hardware_cpu_id() as usize].swap(0, Ordering::Acquire);
+    (0..u32::BITS).filter(move |bit| pending & (1 << bit) != 0).map(|bit| IPI_BASE + bit)
+}
+
+impl Arch for Riscv64 {
+    type Context = context::Context;
+
+    const RESCHEDULE_IPI: u32 = IPI_BASE;
+    const CALL_FUNCTION_IPI: u32 = IPI_BASE + 1;
+    const TIMER_IRQ: u32 = TIMER_IRQ_NUMBER;
+    const COUNTER_NAME: &'static str = "riscv_timebase";
+    // The top 256 GiB are part of the upper half in Sv39, Sv48 and Sv57 alike
+    const VMALLOC_START: u64 = 0xffff_ffc0_0000_0000;
+
+    #[inline]
+    fn interrupts_enabled() -> bool {
+        read_csr!("sstatus") & SSTATUS_SIE != 0
+    }
+
+    #[inline]
+    fn enable_interrupts() {
+        unsafe { asm!("csrsi sstatus, 2", options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn disable_interrupts() {
+        unsafe { asm!("csrci sstatus, 2", options(nomem, nostack)) };
+    }
+
+    fn wait_for_interrupt() {
+        // WFI wakes on a pending interrupt even while sstatus.SIE is clear, which is then taken
+        // as soon as it is set, so nothing arriving before the WFI is missed
+        unsafe { asm!("wfi", "csrsi sstatus, 2", options(nomem, nostack)) };
+    }
+
+    fn halt() -> ! {
+        unsafe { asm!("csrci sstatus, 2", "csrw sie, zero", options(nomem, nostack)) };
+
+        loop {
+            unsafe { asm!("wfi", options(nomem, nostack)) };
+        }
+    }
+
+    unsafe fn set_percpu_base(base: u64) {
+        unsafe { asm!("mv tp, {}", in(reg) base, options(nomem, nostack, preserves_flags)) };
+    }
+
+    #[inline]
+    fn percpu_base() -> u64 {
+        let base: u64;
+        unsafe { asm!("mv {}, tp", out(reg) base, options(nomem, nostack, preserves_flags)) };
+        base
+    }
+
+    fn hardware_cpu_id() -> u32 {
+        read_csr!("sscratch") as u32
+    }
+
+    fn send_ipi(hw_id: u32, ipi: u32) {
+        PENDING_IPIS[hw_id as usize].fetch_or(1 << (ipi - IPI_BASE), Ordering::Release);
+        sbi::send_ipi(1, hw_id as usize);
+    }
+
+    fn send_ipi_all_but_self(ipi: u32) {
+        let targets = ONLINE_HARTS.load(Ordering::Acquire) & !(1 << Self::hardware_cpu_id());
+
+        for hart in (0..MAX_HARTS).filter(|hart| targets & (1 << hart) != 0) {
+            PENDING_IPIS[hart].fetch_or(1 << (ipi - IPI_BASE), Ordering::Release);
+        }
+
+        if targets != 0 {
+            sbi::send_ipi(targets as usize, 0);
+        }
+    }
+
+    fn end_of_interrupt(irq: u32) {
+        // take_ipis already acknowledged software interrupts
+        if irq < IPI_BASE {
+            plic::complete(irq, Self::hardware_cpu_id() as usize);
+        }
+    }
+
+    fn page_table_root() -> u64 {
+        mmu::root()
+    }
+
+    unsafe fn set_page_table_root(root: u64) {
+        unsafe { mmu::set_root(root) };
+    }
+
+    #[inline]
+    fn flush_tlb_page(addr: u64) {
+        mmu::flush_page(addr);
+    }
+
+    fn flush_tlb_local() {
+        mmu::flush_local();
+    }
+
+    fn flush_tlb_all_local() {
+        mmu::flush_all_local();
+    }
+
+    fn page_flags(write: bool, exec: bool, user: bool) -> u64 {
+        mmu::leaf_flags(write, exec, user)
+    }
+
+    fn page_entry(phys: u64, flags: u64) -> u64 {
+        mmu::entry(phys, flags)
+    }
+
+    fn entry_address(entry: u64) -> u64 {
+        mmu::address(entry)
+    }
+
+    fn accessed_flag() -> u64 {
+        mmu::ACCESSED
+    }
+
+    fn leaf_entry(root: u64, virt: u64) -> Option<*mut u64> {
+        mmu::leaf(root, virt)
+    }
+
+    fn huge_entry(root: u64, virt: u64) -> Option<*mut u64> {
+        mmu::megapage(root, virt)
+    }
+
+    fn huge_flags(flags: u64) -> u64 {
+        // A leaf at level 1 is a megapage; the bits are the same
+        flags
+    }
+
+    fn split_flags(huge_flags: u64) -> u64 {
+        huge_flags
+    }
+
+    fn is_huge(entry: u64) -> bool {
+        entry & mmu::VALID != 0 && entry & (mmu::READ | mmu::EXECUTE) != 0
+    }
+
+    fn table_entry(phys: u64) -> u64 {
+        mmu::entry(phys, mmu::VALID)
+    }
+
+    fn leaf_entry_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+        mmu::leaf_or_create(root, virt, alloc)
+    }
+
+    fn init_memory_protection() {
+        // Execute and write permissions are separate bits that bind S-mode too, so there is
+        // nothing to switch on
+    }
+
+    fn init_user_access_protection() -> (bool, bool) {
+        // S-mode can never execute U pages, and cannot access them while sstatus.SUM is clear
+        Self::user_access_end();
+        (true, true)
+    }
+
+    #[inline]
+    fn user_access_begin() {
+        unsafe { asm!("csrs sstatus, {}", in(reg) SSTATUS_SUM, options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn user_access_end() {
+        unsafe { asm!("csrc sstatus, {}", in(reg) SSTATUS_SUM, options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn read_counter() -> u64 {
+        timer::read()
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        timer::frequency()
+    }
+
+    fn counter_is_stable() -> bool {
+        // The timebase runs at a fixed frequency by definition
+        true
+    }
+
+    fn has_timer() -> bool {
+        true
+    }
+
+    fn arm_timer(deadline: u64) {
+        timer::arm(deadline);
+    }
+
+    fn disarm_timer() {
+        timer::disarm();
+    }
+
+    fn hardware_random() -> Option<u64> {
+        // Zkr's seed CSR traps unless M-mode granted S-mode access, and S-mode cannot find
+        // out whether it did without taking the trap
+        None
+    }
+
+    #[inline(always)]
+    fn frame_pointer() -> usize {
+        let fp: usize;
+        unsafe { asm!("mv {}, s0", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+        fp
+    }
+
+    #[inline(always)]
+    fn stack_pointer() -> usize {
+        let sp: usize;
+        unsafe { asm!("mv {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+        sp
+    }
+
+    fn new_context(stack_top: u64, entry: ContextEntry, arg: usize) -> Self::Context {
+        context::Context::new(stack_top, entry, arg)
+    }
+
+    unsafe fn switch_context(prev: *mut Self::Context, next: *const Self::Context) {
+        unsafe { context::switch(prev, next) };
+    }
+
+    unsafe fn call_with_context(point: *mut Self::Context, entry: GuardedEntry, arg: *const ()) -> bool {
+        unsafe { context::call_with(point, entry, arg) != 0 }
+    }
+
+    unsafe fn resume_context(point: *const Self::Context) -> ! {
+        unsafe { context::resume(point) }
+    }
+}
@@ -0,0 +1,160 @@
+//! Platform-Level Interrupt Controller: routes external (device) interrupts to hart contexts.
+//!
+//! Each hart has one context per privilege level it takes interrupts in; the kernel only uses
+//! the S-mode ones. A context has an enable bit per source and a priority threshold, and claims
+//! an interrupt by reading its claim register and completes it by writing the source back. The
+//! base address and the hart-to-context mapping come from the MADT's PLIC and RINTC entries.
+//! Without them the base comes from the device tree, or QEMU `virt`'s default, and hart `n`
+//! uses context `2 * n + 1` as on QEMU `virt`.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::MAX_HARTS;
+use crate::os::acpi;
+use crate::os::device::{self, DeviceClass};
+
+/// MMIO base of the PLIC on QEMU `virt`.
+pub const QEMU_VIRT_BASE: u64 = 0x0c00_0000;
+
+/// Number of interrupt sources the PLIC architecture allows; source 0 means "none".
+pub const SOURCES: u32 = 1024;
+
+// Register layout
+const PRIORITY: u64 = 0x0000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const THRESHOLD: u64 = 0x0;
+const CLAIM: u64 = 0x4;
+
+// MADT entry types and field offsets
+const MADT_ENTRIES: usize = acpi::HEADER_SIZE + 8;
+const MADT_RINTC: u8 = 0x18;
+const MADT_PLIC: u8 = 0x1b;
+const RINTC_HART_ID_OFFSET: usize = 8;
+const RINTC_EXT_INTC_ID_OFFSET: usize = 20;
+const PLIC_BASE_OFFSET: usize = 24;
+
+// Device tree `compatible` strings of the PLIC
+const FDT_COMPATIBLES: &[&str] = &["sifive,plic-1.0.0", "riscv,plic0"];
+
+// Context value of a hart the MADT said nothing about
+const UNKNOWN_CONTEXT: u32 = u32::MAX;
+
+static BASE: AtomicU64 = AtomicU64::new(QEMU_VIRT_BASE);
+static CONTEXTS: [AtomicU32; MAX_HARTS] = [const { AtomicU32::new(UNKNOWN_CONTEXT) }; MAX_HARTS];
+
+fn mmio_read(addr: u64) -> u32 {
+    unsafe { ptr::read_volatile(addr as *const u32) }
+}
+
+fn mmio_write(addr: u64, value: u32) {
+    unsafe { ptr::write_volatile(addr as *mut u32, value) };
+}
+
+/// MMIO base in use.
+pub fn base() -> u64 {
+    BASE.load(Ordering::Relaxed)
+}
+
+/// S-mode context of hart `hart`.
+pub fn context_of(hart: usize) -> u64 {
+    match CONTEXTS.get(hart).map(|context| context.load(Ordering::Relaxed)) {
+        Some(context) if context != UNKNOWN_CONTEXT => context as u64,
+        _ => 2 * hart as u64 + 1,
+    }
+}
+
+// Reads the PLIC base and each hart's context from the MADT, returning whether it had a PLIC
+fn read_madt() -> bool {
+    let Some(madt) = acpi::find_table(b"APIC") else {
+        return false;
+    };
+
+    let mut found = false;
+
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let kind = acpi::read_u8(madt, offset);
+        let length = acpi::read_u8(madt, offset + 1) as usize;
+
+        if length < 2 || offset + length > madt.len() {
+            break;
+        }
+
+        match kind {
+            MADT_PLIC => {
+                BASE.store(acpi::read_u64(madt, offset + PLIC_BASE_OFFSET), Ordering::Relaxed);
+                found = true;
+            }
+            MADT_RINTC => {
+                // External interrupt controller ID: PLIC ID in bits 31:24, context in 15:0
+                let hart = acpi::read_u64(madt, offset + RINTC_HART_ID_OFFSET) as usize;
+                let ext_intc = acpi::read_u32(madt, offset + RINTC_EXT_INTC_ID_OFFSET);
+
+                if let Some(context) = CONTEXTS.get(hart) {
+                    context.store(ext_intc & 0xffff, Ordering::Relaxed);
+                }
+            }
+            _ => {}
+        }
+
+        offset += length;
+    }
+
+    found
+}
+
+/// Locates the PLIC and opens the boot hart's context. Called once at boot.
+pub fn init(hart: usize) {
+    if !read_madt() {
+        let plic = device::find_compatible(DeviceClass::InterruptController, FDT_COMPATIBLES);
+        if let Some(region) = plic.and_then(|plic| plic.location.region(0)) {
+            BASE.store(region.base, Ordering::Relaxed);
+        }
+    }
+
+    init_cpu(hart);
+}
+
+/// Like [`init`] with an explicit base, e.g. from a device tree.
+pub fn init_with(base: u64, hart: usize) {
+    BASE.store(base, Ordering::Relaxed);
+    init_cpu(hart);
+}
+
+/// Lets every enabled source with a non-zero priority through to `hart`'s S-mode context.
+/// Called on every hart.
+pub fn init_cpu(hart: usize) {
+    mmio_write(context_register(hart, THRESHOLD), 0);
+}
+
+fn context_register(hart: usize, register: u64) -> u64 {
+    base() + CONTEXT + context_of(hart) * CONTEXT_STRIDE + register
+}
+
+/// Enables source `irq` at priority 1 for `hart`'s S-mode context.
+pub fn enable(irq: u32, hart: usize) {
+    if irq == 0 || irq >= SOURCES {
+        return;
+    }
+
+    mmio_write(base() + PRIORITY + 4 * irq as u64, 1);
+
+    let word = base() + ENABLE + context_of(hart) * ENABLE_STRIDE + 4 * (irq / 32) as u64;
+    mmio_write(word, mmio_read(word) | 1 << (irq % 32));
+}
+
+/// Claims the highest-priority pending interrupt of `hart`'s S-mode context, or `None` if
+/// nothing is pending. Every claimed source must be passed to [`complete`].
+pub fn claim(hart: usize) -> Option<u32> {
+    let irq = mmio_read(context_register(hart, CLAIM));
+    (irq != 0).then_some(irq)
+}
+
+/// Signals that source `irq`, claimed by `hart`, has been handled.
+pub fn complete(irq: u32, hart: usize) {
+    mmio_write(context_register(hart, CLAIM), irq);
+}
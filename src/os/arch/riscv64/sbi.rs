@@ -0,0 +1,182 @@
+//! RISC-V Supervisor Binary Interface: calls into the machine-mode firmware (OpenSBI on QEMU
+//! `virt`) for what S-mode cannot do itself -- programming the timer, sending IPIs, remote
+//! fences, starting harts and resetting the system.
+//!
+//! Every call is an `ecall` with the extension ID in a7, the function ID in a6 and arguments in
+//! a0-a5; the firmware answers with an error code in a0 and a value in a1. Firmware older than
+//! SBI v0.2 only has the legacy extensions, which [`init`] falls back to for the timer and IPIs.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Extension IDs
+const EXT_LEGACY_SET_TIMER: usize = 0x00;
+const EXT_LEGACY_SEND_IPI: usize = 0x04;
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4d45;
+const EXT_IPI: usize = 0x0073_5049;
+const EXT_RFENCE: usize = 0x5246_4e43;
+const EXT_HSM: usize = 0x0048_534d;
+const EXT_SRST: usize = 0x5352_5354;
+
+// Base extension functions
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_PROBE_EXTENSION: usize = 3;
+
+/// Error codes returned in a0.
+pub const SUCCESS: isize = 0;
+pub const ERR_NOT_SUPPORTED: isize = -2;
+
+/// `hart_mask_base` value meaning "every hart", ignoring the mask.
+pub const ALL_HARTS: usize = usize::MAX;
+
+/// System reset types and reasons for [`system_reset`].
+pub const RESET_SHUTDOWN: u32 = 0;
+pub const RESET_COLD_REBOOT: u32 = 1;
+pub const RESET_REASON_NONE: u32 = 0;
+pub const RESET_REASON_FAILURE: u32 = 1;
+
+// Which of the v0.2+ extensions the firmware implements
+static HAS_TIME: AtomicBool = AtomicBool::new(false);
+static HAS_IPI: AtomicBool = AtomicBool::new(false);
+static HAS_RFENCE: AtomicBool = AtomicBool::new(false);
+static HAS_HSM: AtomicBool = AtomicBool::new(false);
+static HAS_SRST: AtomicBool = AtomicBool::new(false);
+
+/// Result of an SBI call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub fn is_ok(&self) -> bool {
+        self.error == SUCCESS
+    }
+}
+
+/// Calls function `fid` of extension `eid`.
+#[inline]
+pub fn call(eid: usize, fid: usize, args: [usize; 4]) -> SbiRet {
+    let (error, value): (isize, usize);
+
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a6") fid,
+            in("a7") eid,
+            options(nostack)
+        );
+    }
+
+    SbiRet { error, value }
+}
+
+// Legacy (v0.1) calls return only an error code and take no function ID
+fn legacy_call(eid: usize, arg: usize) -> isize {
+    let error: isize;
+    unsafe { asm!("ecall", inlateout("a0") arg => error, in("a7") eid, options(nostack)) };
+    error
+}
+
+/// SBI specification version implemented by the firmware as (major, minor).
+pub fn spec_version() -> (usize, usize) {
+    let ret = call(EXT_BASE, BASE_GET_SPEC_VERSION, [0; 4]);
+
+    // v0.1 firmware has no base extension and answers with an error
+    if !ret.is_ok() {
+        return (0, 1);
+    }
+
+    ((ret.value >> 24) & 0x7f, ret.value & 0xff_ffff)
+}
+
+/// Returns `true` if the firmware implements extension `eid`.
+pub fn probe(eid: usize) -> bool {
+    let ret = call(EXT_BASE, BASE_PROBE_EXTENSION, [eid, 0, 0, 0]);
+    ret.is_ok() && ret.value != 0
+}
+
+/// Finds out which extensions the firmware implements. Called once at boot.
+pub fn init() {
+    let (major, minor) = spec_version();
+
+    if (major, minor) >= (0, 2) {
+        HAS_TIME.store(probe(EXT_TIME), Ordering::Relaxed);
+        HAS_IPI.store(probe(EXT_IPI), Ordering::Relaxed);
+        HAS_RFENCE.store(probe(EXT_RFENCE), Ordering::Relaxed);
+        HAS_HSM.store(probe(EXT_HSM), Ordering::Relaxed);
+        HAS_SRST.store(probe(EXT_SRST), Ordering::Relaxed);
+    }
+
+    log::info!(
+        "sbi: v{}.{}{}{}{}{}{}",
+        major,
+        minor,
+        if HAS_TIME.load(Ordering::Relaxed) { " TIME" } else { "" },
+        if HAS_IPI.load(Ordering::Relaxed) { " IPI" } else { "" },
+        if HAS_RFENCE.load(Ordering::Relaxed) { " RFENCE" } else { "" },
+        if HAS_HSM.load(Ordering::Relaxed) { " HSM" } else { "" },
+        if HAS_SRST.load(Ordering::Relaxed) { " SRST" } else { "" },
+    );
+}
+
+/// Programs this hart's timer to interrupt once `time` reaches `deadline`, clearing any pending
+/// timer interrupt.
+pub fn set_timer(deadline: u64) {
+    if HAS_TIME.load(Ordering::Relaxed) {
+        call(EXT_TIME, 0, [deadline as usize, 0, 0, 0]);
+    } else {
+        legacy_call(EXT_LEGACY_SET_TIMER, deadline as usize);
+    }
+}
+
+/// Raises a supervisor software interrupt on the harts in `hart_mask`, bit n standing for hart
+/// `hart_mask_base + n` (or on every hart if the base is [`ALL_HARTS`]).
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) {
+    if HAS_IPI.load(Ordering::Relaxed) {
+        call(EXT_IPI, 0, [hart_mask, hart_mask_base, 0, 0]);
+        return;
+    }
+
+    // The legacy call takes a pointer to a mask based at hart 0
+    let mask = match hart_mask_base {
+        ALL_HARTS => usize::MAX,
+        base => hart_mask.checked_shl(base as u32).unwrap_or(0),
+    };
+    legacy_call(EXT_LEGACY_SEND_IPI, &mask as *const usize as usize);
+}
+
+/// Has the harts in `hart_mask` execute `sfence.vma` for `size` bytes from `start` (all
+/// addresses if `size` is `usize::MAX`). Returns `false` without the RFENCE extension.
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize) -> bool {
+    if !HAS_RFENCE.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    call(EXT_RFENCE, 1, [hart_mask, hart_mask_base, start, size]).is_ok()
+}
+
+/// Starts hart `hart_id` in S-mode at physical address `start` with the MMU off, a0 holding
+/// its hart ID and a1 `opaque`.
+pub fn hart_start(hart_id: usize, start: usize, opaque: usize) -> SbiRet {
+    if !HAS_HSM.load(Ordering::Relaxed) {
+        return SbiRet { error: ERR_NOT_SUPPORTED, value: 0 };
+    }
+
+    call(EXT_HSM, 0, [hart_id, start, opaque, 0])
+}
+
+/// Resets or shuts down the system. Only returns if the firmware refused.
+pub fn system_reset(kind: u32, reason: u32) -> SbiRet {
+    if !HAS_SRST.load(Ordering::Relaxed) {
+        return SbiRet { error: ERR_NOT_SUPPORTED, value: 0 };
+    }
+
+    call(EXT_SRST, 0, [kind as usize, reason as usize, 0, 0])
+}
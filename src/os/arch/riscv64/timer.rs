@@ -0,0 +1,65 @@
+//! The RISC-V timebase: the `time` CSR, a constant-rate counter shared by all harts, and each
+//! hart's timer, which the SBI firmware programs on the kernel's behalf and which raises the
+//! supervisor timer interrupt once `time` reaches the deadline.
+//!
+//! Nothing in the ISA reports the timebase frequency. It comes from the ACPI RHCT or the device
+//! tree's `timebase-frequency`; without either it is calibrated at boot like the x86 TSC.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{SIE_STIE, sbi};
+use crate::os::{acpi, fdt};
+
+// RHCT: timebase frequency right after the 4-byte flags field
+const RHCT_TIMEBASE_OFFSET: usize = acpi::HEADER_SIZE + 4;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Timebase frequency in Hz, if the firmware reported it.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Records the timebase frequency, e.g. from a device tree.
+pub fn set_frequency(hz: u64) {
+    FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+/// Reads `time`.
+#[inline]
+pub fn read() -> u64 {
+    let time: u64;
+    unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack, preserves_flags)) };
+    time
+}
+
+/// Programs this hart's timer to fire once `time` reaches `deadline`.
+pub fn arm(deadline: u64) {
+    sbi::set_timer(deadline);
+}
+
+/// Stops this hart's timer from firing (by moving the deadline out of reach).
+pub fn disarm() {
+    sbi::set_timer(u64::MAX);
+}
+
+/// Reads the timebase frequency from the RHCT, or the device tree's `/cpus` node. Called once
+/// at boot.
+pub fn init() {
+    if let Some(rhct) = acpi::find_table(b"RHCT") {
+        set_frequency(acpi::read_u64(rhct, RHCT_TIMEBASE_OFFSET));
+    } else if let Some(hz) = fdt::get().and_then(|fdt| fdt.find_path("/cpus")?.u32_property("timebase-frequency")) {
+        set_frequency(hz as u64);
+    }
+}
+
+/// Disarms this hart's timer and unmasks the supervisor timer interrupt, so a later [`arm`]
+/// interrupts.
+pub fn init_cpu() {
+    disarm();
+    unsafe { asm!("csrs sie, {}", in(reg) SIE_STIE, options(nomem, nostack)) };
+}
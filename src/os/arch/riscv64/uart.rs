@@ -0,0 +1,46 @@
+//! Memory-mapped NS16550A UART, the early console on QEMU `virt` and most RISC-V boards.
+
+use core::ptr;
+
+/// MMIO base of the UART on QEMU `virt`; registers are one byte apart.
+const UART_BASE: u64 = 0x1000_0000;
+
+// Register offsets
+const DATA: u64 = 0;
+const INTERRUPT_ENABLE: u64 = 1;
+const FIFO_CONTROL: u64 = 2;
+const LINE_CONTROL: u64 = 3;
+const LINE_STATUS: u64 = 5;
+
+// Line status bit set when the transmit holding register can accept another byte
+const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+
+fn write(offset: u64, value: u8) {
+    unsafe { ptr::write_volatile((UART_BASE + offset) as *mut u8, value) };
+}
+
+fn read(offset: u64) -> u8 {
+    unsafe { ptr::read_volatile((UART_BASE + offset) as *const u8) }
+}
+
+/// Programs the UART for 8N1 with FIFOs enabled. The divisor depends on the UART's input
+/// clock, so the firmware's baud rate is kept.
+pub fn init() {
+    // We only ever poll
+    write(INTERRUPT_ENABLE, 0x00);
+
+    // 8N1, divisor latch access bit cleared
+    write(LINE_CONTROL, 0x03);
+
+    // Enable and clear the FIFOs with a 14-byte threshold
+    write(FIFO_CONTROL, 0xc7);
+}
+
+/// Writes a single byte, spinning until the transmitter is ready.
+pub fn write_byte(byte: u8) {
+    while read(LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {
+        core::hint::spin_loop();
+    }
+
+    write(DATA, byte);
+}
@@ -0,0 +1,164 @@
+//! Local APIC register access, in whichever mode (xAPIC or x2APIC) the firmware left enabled.
+//!
+//! In xAPIC mode the registers are memory-mapped at the base in `IA32_APIC_BASE`, which the
+//! firmware's identity map covers; in x2APIC mode each register is the MSR `0x800 + offset / 16`.
+
+use core::ptr;
+
+use super::msr::Msr;
+
+// IA32_APIC_BASE bits
+const BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Register offsets (xAPIC MMIO layout)
+const REG_ID: u32 = 0x20;
+const REG_EOI: u32 = 0xb0;
+const REG_SPURIOUS: u32 = 0xf0;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+// Interrupt command register fields
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// LVT timer mode field, and the mask bit
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const LVT_MASKED: u32 = 1 << 16;
+
+// Spurious interrupt vector register: the APIC is software-enabled, and spurious interrupts
+// go to vector 0xff, which has no handler to run
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const SPURIOUS_VECTOR: u32 = 0xff;
+
+// Divide configuration: the timer counts down at the bus clock divided by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Returns `true` if the local APIC is in x2APIC mode.
+pub fn x2apic_enabled() -> bool {
+    unsafe { Msr::IA32_APIC_BASE.read() & BASE_X2APIC_ENABLE != 0 }
+}
+
+fn mmio(offset: u32) -> *mut u32 {
+    let base = unsafe { Msr::IA32_APIC_BASE.read() } & BASE_ADDRESS_MASK;
+    (base + offset as u64) as *mut u32
+}
+
+fn read(offset: u32) -> u32 {
+    if x2apic_enabled() {
+        unsafe { Msr(0x800 + (offset >> 4)).read() as u32 }
+    } else {
+        unsafe { ptr::read_volatile(mmio(offset)) }
+    }
+}
+
+fn write(offset: u32, value: u32) {
+    if x2apic_enabled() {
+        unsafe { Msr(0x800 + (offset >> 4)).write(value as u64) };
+    } else {
+        unsafe { ptr::write_volatile(mmio(offset), value) };
+    }
+}
+
+/// Software-enables this CPU's APIC, in x2APIC mode if `x2apic`, as the firmware left the boot
+/// CPU's. An application processor's APIC comes out of INIT disabled.
+pub fn enable(x2apic: bool) {
+    unsafe {
+        let base = Msr::IA32_APIC_BASE.read() | BASE_GLOBAL_ENABLE;
+        Msr::IA32_APIC_BASE.write(if x2apic { base | BASE_X2APIC_ENABLE } else { base });
+    }
+    write(REG_SPURIOUS, read(REG_SPURIOUS) | SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR);
+}
+
+/// APIC ID of the calling CPU.
+pub fn id() -> u32 {
+    if x2apic_enabled() { read(REG_ID) } else { read(REG_ID) >> 24 }
+}
+
+/// Signals the end of the interrupt being serviced, unless KVM says it need not be.
+pub fn eoi() {
+    if crate::os::kvm::pv_eoi_skip() {
+        return;
+    }
+
+    write(REG_EOI, 0);
+}
+
+// Writes the ICR, which sends the IPI, and waits for an xAPIC to accept it
+fn send_icr(destination: u32, command: u32) {
+    if x2apic_enabled() {
+        // Single 64-bit register with the full 32-bit destination in the high half
+        let icr = ((destination as u64) << 32) | command as u64;
+        unsafe { Msr(0x800 + (REG_ICR_LOW >> 4)).write(icr) };
+        return;
+    }
+
+    write(REG_ICR_HIGH, destination << 24);
+    write(REG_ICR_LOW, command);
+
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Puts this CPU's APIC timer in TSC-deadline mode, interrupting with `vector` once the TSC
+/// reaches the value written to `IA32_TSC_DEADLINE`. The CPU must support the mode.
+pub fn init_deadline_timer(vector: u8) {
+    write(REG_LVT_TIMER, LVT_TIMER_TSC_DEADLINE | vector as u32);
+
+    // The mode switch must be visible before the first deadline write, or that write may be
+    // dropped
+    unsafe { core::arch::asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Counts down this CPU's APIC timer, masked, while `wait` runs and returns how far it got,
+/// for calibrating [`init_periodic_timer`] against a timer of known rate.
+pub fn measure_timer(wait: impl FnOnce()) -> u32 {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_TIMER_INITIAL, u32::MAX);
+
+    wait();
+
+    let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+    write(REG_TIMER_INITIAL, 0);
+    elapsed
+}
+
+/// Puts this CPU's APIC timer in periodic mode, interrupting with `vector` every `count`
+/// counts, at the rate [`measure_timer`] measures.
+pub fn init_periodic_timer(vector: u8, count: u32) {
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+    write(REG_TIMER_INITIAL, count);
+}
+
+/// Sends a fixed-delivery IPI with `vector` to the CPU with APIC ID `apic_id`.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    send_icr(apic_id, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends a fixed-delivery IPI with `vector` to every CPU except the caller.
+pub fn send_ipi_all_but_self(vector: u8) {
+    send_icr(0, ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+/// Sends an INIT IPI to the CPU with APIC ID `apic_id`, resetting it to wait for a startup IPI.
+pub fn send_init(apic_id: u32) {
+    send_icr(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI to the CPU with APIC ID `apic_id`, which starts it in real mode at
+/// `page << 12`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_icr(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
@@ -0,0 +1,122 @@
+//! Kernel context save and restore.
+//!
+//! A [`Context`] holds what the System V ABI requires a callee to preserve, plus the stack
+//! pointer and the address to resume at. Saving happens at a call, so the caller-saved
+//! registers are dead and need no slot.
+//!
+//! The entry functions called from here use the C ABI, which is Microsoft x64 on the UEFI
+//! target (first argument in `rcx`, 32 bytes of shadow space) and System V elsewhere (first
+//! argument in `rdi`). The trampolines load the argument into both registers and reserve the
+//! shadow space, so they work under either.
+
+use super::super::{ContextEntry, GuardedEntry};
+
+/// Saved state of a kernel context that is not running.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Context {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+impl Context {
+    /// A context that calls `entry(arg)` on the stack ending at `stack_top`.
+    pub fn new(stack_top: u64, entry: ContextEntry, arg: usize) -> Self {
+        Context {
+            r12: arg as u64,
+            r13: entry as usize as u64,
+            rsp: stack_top & !0xf,
+            rip: start_context as *const () as u64,
+            ..Context::default()
+        }
+    }
+}
+
+// First code run by a context from Context::new: r12 holds the argument, r13 the entry point.
+// rsp is 16-byte aligned here, and stays so for the call after reserving the shadow space.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn start_context() -> ! {
+    core::arch::naked_asm!(
+        "xor ebp, ebp",
+        "mov rdi, r12",
+        "mov rcx, r12",
+        "sub rsp, 32",
+        "call r13",
+        "ud2",
+    )
+}
+
+/// Saves the running context into `prev` and jumps to `next`.
+#[unsafe(naked)]
+pub unsafe extern "sysv64" fn switch(prev: *mut Context, next: *const Context) {
+    core::arch::naked_asm!(
+        "mov [rdi + 0x00], rbx",
+        "mov [rdi + 0x08], rbp",
+        "mov [rdi + 0x10], r12",
+        "mov [rdi + 0x18], r13",
+        "mov [rdi + 0x20], r14",
+        "mov [rdi + 0x28], r15",
+        // Resume at our return address with it popped, as if this call had returned
+        "mov rax, [rsp]",
+        "mov [rdi + 0x38], rax",
+        "lea rax, [rsp + 8]",
+        "mov [rdi + 0x30], rax",
+        "mov rbx, [rsi + 0x00]",
+        "mov rbp, [rsi + 0x08]",
+        "mov r12, [rsi + 0x10]",
+        "mov r13, [rsi + 0x18]",
+        "mov r14, [rsi + 0x20]",
+        "mov r15, [rsi + 0x28]",
+        "mov rsp, [rsi + 0x30]",
+        "jmp qword ptr [rsi + 0x38]",
+    )
+}
+
+/// Saves the running context into `point`, then calls `entry(arg)`. Returns 0 when `entry`
+/// returns normally, or 1 when [`resume`] is used to bail out.
+#[unsafe(naked)]
+pub unsafe extern "sysv64" fn call_with(point: *mut Context, entry: GuardedEntry, arg: *const ()) -> u64 {
+    core::arch::naked_asm!(
+        "mov [rdi + 0x00], rbx",
+        "mov [rdi + 0x08], rbp",
+        "mov [rdi + 0x10], r12",
+        "mov [rdi + 0x18], r13",
+        "mov [rdi + 0x20], r14",
+        "mov [rdi + 0x28], r15",
+        "mov rax, [rsp]",
+        "mov [rdi + 0x38], rax",
+        "lea rax, [rsp + 8]",
+        "mov [rdi + 0x30], rax",
+        "mov rax, rsi",
+        "mov rdi, rdx",
+        "mov rcx, rdx",
+        // Shadow space plus 8 bytes to keep the stack 16-byte aligned for the call
+        "sub rsp, 40",
+        "call rax",
+        "add rsp, 40",
+        "xor eax, eax",
+        "ret",
+    )
+}
+
+/// Restores the context saved by [`call_with`] and returns 1 from it.
+#[unsafe(naked)]
+pub unsafe extern "sysv64" fn resume(point: *const Context) -> ! {
+    core::arch::naked_asm!(
+        "mov rbx, [rdi + 0x00]",
+        "mov rbp, [rdi + 0x08]",
+        "mov r12, [rdi + 0x10]",
+        "mov r13, [rdi + 0x18]",
+        "mov r14, [rdi + 0x20]",
+        "mov r15, [rdi + 0x28]",
+        "mov rsp, [rdi + 0x30]",
+        "mov eax, 1",
+        "jmp qword ptr [rdi + 0x38]",
+    )
+}
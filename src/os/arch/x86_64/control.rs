@@ -0,0 +1,328 @@
+use core::arch::asm;
+
+use super::InvalidRegisterValue;
+use crate::os::cpu;
+
+// =========================================================================
+// CR0
+// =========================================================================
+
+register_flags! {
+    /// Contents of CR0.
+    pub struct Cr0Flags: u64 {
+        /// Protected mode.
+        const PE = 1 << 0;
+
+        /// Makes `wait`/`fwait` honour TS (monitor coprocessor).
+        const MP = 1 << 1;
+
+        /// Makes every x87/SSE instruction raise #UD (no FPU present).
+        const EM = 1 << 2;
+
+        /// Makes the next x87/SSE/AVX instruction raise #NM (task switched).
+        const TS = 1 << 3;
+
+        /// Native x87 error reporting.
+        const NE = 1 << 5;
+
+        /// Makes supervisor-mode writes honour read-only pages.
+        const WP = 1 << 16;
+
+        /// Alignment checking in ring 3.
+        const AM = 1 << 18;
+
+        /// Paging.
+        const PG = 1 << 31;
+    }
+}
+
+pub struct Cr0;
+
+impl Cr0 {
+    #[inline]
+    pub fn read() -> Cr0Flags {
+        let value: u64;
+        unsafe { asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr0Flags::from_bits_retain(value)
+    }
+
+    /// Writes CR0. Turning off PE or PG from long mode is refused.
+    ///
+    /// # Safety
+    /// CR0 controls paging and protection; a wrong value can crash the machine instantly.
+    #[inline]
+    pub unsafe fn write(flags: Cr0Flags) -> Result<(), InvalidRegisterValue> {
+        if !flags.contains(Cr0Flags::PE | Cr0Flags::PG) {
+            return Err(InvalidRegisterValue("CR0.PE and CR0.PG must stay set in long mode"));
+        }
+
+        unsafe { asm!("mov cr0, {}", in(reg) flags.bits(), options(nostack, preserves_flags)) };
+        Ok(())
+    }
+
+    /// Read-modify-write of CR0.
+    ///
+    /// # Safety
+    /// See [`Cr0::write`].
+    #[inline]
+    pub unsafe fn update(f: impl FnOnce(&mut Cr0Flags)) -> Result<(), InvalidRegisterValue> {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
+    }
+}
+
+/// Clears CR0.TS so FPU instructions stop raising #NM.
+#[inline]
+pub fn clts() {
+    unsafe { asm!("clts", options(nomem, nostack, preserves_flags)) };
+}
+
+// =========================================================================
+// CR2 / CR3
+// =========================================================================
+
+pub struct Cr2;
+
+impl Cr2 {
+    /// The linear address that caused the last page fault.
+    #[inline]
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }
+}
+
+pub struct Cr3;
+
+impl Cr3 {
+    /// Physical address of the active PML4 and the low 12 bits (PCID or PWT/PCD flags).
+    #[inline]
+    pub fn read() -> (u64, u16) {
+        let value: u64;
+        unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        (value & !0xfff, (value & 0xfff) as u16)
+    }
+
+    /// Switches to the page table rooted at `pml4` (which must be page-aligned).
+    ///
+    /// # Safety
+    /// The new tables must map the currently executing code and stack.
+    #[inline]
+    pub unsafe fn write(pml4: u64, low: u16) -> Result<(), InvalidRegisterValue> {
+        if pml4 & 0xfff != 0 || low > 0xfff {
+            return Err(InvalidRegisterValue("CR3 root must be page-aligned"));
+        }
+
+        unsafe { asm!("mov cr3, {}", in(reg) pml4 | low as u64, options(nostack, preserves_flags)) };
+        Ok(())
+    }
+}
+
+// =========================================================================
+// CR4
+// =========================================================================
+
+register_flags! {
+    /// Contents of CR4.
+    pub struct Cr4Flags: u64 {
+        /// Physical address extension (required in long mode).
+        const PAE = 1 << 5;
+
+        /// Global pages.
+        const PGE = 1 << 7;
+
+        /// Enables FXSAVE/FXRSTOR and SSE instructions.
+        const OSFXSR = 1 << 9;
+
+        /// Reports unmasked SIMD floating-point exceptions as #XM instead of #UD.
+        const OSXMMEXCPT = 1 << 10;
+
+        /// Enables the RDFSBASE family of instructions.
+        const FSGSBASE = 1 << 16;
+
+        /// Process-context identifiers.
+        const PCIDE = 1 << 17;
+
+        /// Enables XSAVE/XRSTOR and XCR0.
+        const OSXSAVE = 1 << 18;
+
+        /// Forbids ring 0 from executing user pages.
+        const SMEP = 1 << 20;
+
+        /// Forbids ring 0 from accessing user pages unless RFLAGS.AC is set.
+        const SMAP = 1 << 21;
+    }
+}
+
+pub struct Cr4;
+
+impl Cr4 {
+    #[inline]
+    pub fn read() -> Cr4Flags {
+        let value: u64;
+        unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        Cr4Flags::from_bits_retain(value)
+    }
+
+    /// Writes CR4, refusing bits for features the CPU lacks (they would raise #GP).
+    ///
+    /// # Safety
+    /// CR4 changes global CPU behaviour (paging features, instruction availability).
+    #[inline]
+    pub unsafe fn write(flags: Cr4Flags) -> Result<(), InvalidRegisterValue> {
+        let features = cpu::features();
+        let requirements = [
+            (Cr4Flags::PGE, features.global_pages, "CR4.PGE without global page support"),
+            (Cr4Flags::OSFXSR, features.fxsr, "CR4.OSFXSR without FXSAVE"),
+            (Cr4Flags::OSXSAVE, features.xsave, "CR4.OSXSAVE without XSAVE"),
+            (Cr4Flags::SMEP, features.smep, "CR4.SMEP without SMEP"),
+            (Cr4Flags::SMAP, features.smap, "CR4.SMAP without SMAP"),
+        ];
+
+        for (flag, supported, message) in requirements {
+            if flags.contains(flag) && !supported {
+                return Err(InvalidRegisterValue(message));
+            }
+        }
+
+        if !flags.contains(Cr4Flags::PAE) {
+            return Err(InvalidRegisterValue("CR4.PAE must stay set in long mode"));
+        }
+
+        unsafe { asm!("mov cr4, {}", in(reg) flags.bits(), options(nostack, preserves_flags)) };
+        Ok(())
+    }
+
+    /// Read-modify-write of CR4.
+    ///
+    /// # Safety
+    /// See [`Cr4::write`].
+    #[inline]
+    pub unsafe fn update(f: impl FnOnce(&mut Cr4Flags)) -> Result<(), InvalidRegisterValue> {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
+    }
+}
+
+// =========================================================================
+// CR8
+// =========================================================================
+
+pub struct Cr8;
+
+impl Cr8 {
+    /// Current task priority (0 = all interrupts allowed, 15 = all masked).
+    #[inline]
+    pub fn read() -> u8 {
+        let value: u64;
+        unsafe { asm!("mov {}, cr8", out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value as u8
+    }
+
+    /// Sets the task priority; interrupts with a priority class at or below it are held off.
+    ///
+    /// # Safety
+    /// Raising the priority can hold off interrupts other code relies on.
+    #[inline]
+    pub unsafe fn write(priority: u8) -> Result<(), InvalidRegisterValue> {
+        if priority > 15 {
+            return Err(InvalidRegisterValue("CR8 priority must be below 16"));
+        }
+
+        unsafe { asm!("mov cr8, {}", in(reg) priority as u64, options(nomem, nostack, preserves_flags)) };
+        Ok(())
+    }
+}
+
+// =========================================================================
+// XCR0
+// =========================================================================
+
+register_flags! {
+    /// Contents of XCR0: the state components XSAVE manages and whose instructions are usable.
+    pub struct Xcr0Flags: u64 {
+        /// x87 state (must always be set).
+        const X87 = 1 << 0;
+
+        /// XMM registers and MXCSR.
+        const SSE = 1 << 1;
+
+        /// Upper halves of the YMM registers.
+        const AVX = 1 << 2;
+
+        /// AVX-512 opmask registers, upper ZMM0-15 and ZMM16-31.
+        const AVX512 = 0b111 << 5;
+    }
+}
+
+pub struct Xcr0;
+
+impl Xcr0 {
+    /// Reads XCR0. Requires CR4.OSXSAVE.
+    #[inline]
+    pub fn read() -> Xcr0Flags {
+        let (low, high): (u32, u32);
+        unsafe { asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags)) };
+        Xcr0Flags::from_bits_retain(((high as u64) << 32) | low as u64)
+    }
+
+    /// Components the CPU allows in XCR0 (CPUID leaf 0xD, EDX:EAX).
+    pub fn supported() -> Xcr0Flags {
+        let leaf = core::arch::x86_64::__cpuid_count(0xd, 0);
+        Xcr0Flags::from_bits_retain(((leaf.edx as u64) << 32) | leaf.eax as u64)
+    }
+
+    /// Writes XCR0, refusing unsupported components and combinations the CPU rejects.
+    ///
+    /// # Safety
+    /// Requires CR4.OSXSAVE. Disabling a component other code is using corrupts its state.
+    #[inline]
+    pub unsafe fn write(flags: Xcr0Flags) -> Result<(), InvalidRegisterValue> {
+        if !Self::supported().contains(flags) {
+            return Err(InvalidRegisterValue("XCR0 component not supported by the CPU"));
+        }
+
+        if !flags.contains(Xcr0Flags::X87) {
+            return Err(InvalidRegisterValue("XCR0.X87 must be set"));
+        }
+
+        if flags.contains(Xcr0Flags::AVX) && !flags.contains(Xcr0Flags::SSE) {
+            return Err(InvalidRegisterValue("XCR0.AVX requires XCR0.SSE"));
+        }
+
+        if flags.intersects(Xcr0Flags::AVX512)
+            && !(flags.contains(Xcr0Flags::AVX512) && flags.contains(Xcr0Flags::AVX))
+        {
+            return Err(InvalidRegisterValue("XCR0 AVX-512 components must be enabled together with AVX"));
+        }
+
+        let (low, high) = (flags.bits() as u32, (flags.bits() >> 32) as u32);
+        unsafe { asm!("xsetbv", in("ecx") 0, in("eax") low, in("edx") high, options(nomem, nostack, preserves_flags)) };
+        Ok(())
+    }
+}
+
+// =========================================================================
+// RFLAGS.AC
+// =========================================================================
+
+/// Sets RFLAGS.AC, temporarily allowing supervisor access to user pages under SMAP.
+///
+/// # Safety
+/// Only valid when SMAP is supported (otherwise `stac` raises #UD), and must be paired with [`clac`].
+#[inline]
+pub unsafe fn stac() {
+    unsafe { asm!("stac", options(nomem, nostack)) };
+}
+
+/// Clears RFLAGS.AC, closing the user access window opened by [`stac`].
+///
+/// # Safety
+/// Only valid when SMAP is supported (otherwise `clac` raises #UD).
+#[inline]
+pub unsafe fn clac() {
+    unsafe { asm!("clac", options(nomem, nostack)) };
+}
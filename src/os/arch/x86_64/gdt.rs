@@ -0,0 +1,166 @@
+//! Global descriptor table and task state segment.
+//!
+//! Long mode ignores segment bases and limits, so the GDT only holds the flat code and data
+//! segments for ring 0 and ring 3 and each CPU's TSS. The user segments come data first, code
+//! second, the order SYSRET expects: with `STAR[63:48]` at [`KERNEL_DATA`] it loads
+//! [`USER_DATA`] into SS and [`USER_CODE`] into CS.
+//!
+//! The TSS is what long mode still needs it for: the stack the CPU switches to when an
+//! interrupt or exception arrives from ring 3 (RSP0, the running process's kernel stack) and
+//! the interrupt stack table, which gives the double fault handler a stack of its own so a
+//! kernel stack overflow is reported rather than turning into a triple fault.
+
+use core::arch::asm;
+
+use crate::os::percpu::MAX_CPUS;
+
+/// Selector of the ring 0 code segment.
+pub const KERNEL_CODE: u16 = 0x08;
+
+/// Selector of the ring 0 data segment.
+pub const KERNEL_DATA: u16 = 0x10;
+
+/// Selector of the ring 3 data segment, RPL 3.
+pub const USER_DATA: u16 = 0x18 | 3;
+
+/// Selector of the ring 3 code segment, RPL 3.
+pub const USER_CODE: u16 = 0x20 | 3;
+
+/// Selector of the TSS, which takes two entries.
+pub const TSS: u16 = 0x28;
+
+/// Interrupt stack table slot (1-based, as gates name it) of the double fault stack.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// Size of each CPU's double fault stack.
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024;
+
+// Access and flag bits of the flat segments: present, DPL, code/data, long mode or 4 GiB
+const KERNEL_CODE_ENTRY: u64 = 0x00af_9a00_0000_ffff;
+const KERNEL_DATA_ENTRY: u64 = 0x00cf_9200_0000_ffff;
+const USER_DATA_ENTRY: u64 = 0x00cf_f200_0000_ffff;
+const USER_CODE_ENTRY: u64 = 0x00af_fa00_0000_ffff;
+
+// Present, available 64-bit TSS
+const TSS_TYPE: u64 = 0x89;
+
+const ENTRIES: usize = 7;
+
+/// The 64-bit task state segment.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    reserved0: u32,
+
+    /// Stacks loaded on a switch to rings 0-2; only RSP0 is used.
+    pub rsp: [u64; 3],
+    reserved1: u64,
+
+    /// Interrupt stack table, selected by the IST field of a gate.
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+
+    /// Offset of the I/O permission bitmap; past the limit, so there is none.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        TaskStateSegment {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+/// Operand of `lgdt`/`lidt`.
+#[repr(C, packed)]
+pub struct DescriptorPointer {
+    pub limit: u16,
+    pub base: u64,
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+static mut GDTS: [[u64; ENTRIES]; MAX_CPUS] = [[0; ENTRIES]; MAX_CPUS];
+static mut TSSES: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::new() }; MAX_CPUS];
+static mut DOUBLE_FAULT_STACKS: [Stack; MAX_CPUS] = [const { Stack([0; DOUBLE_FAULT_STACK_SIZE]) }; MAX_CPUS];
+
+/// The two GDT entries describing a TSS at `base`.
+pub fn tss_descriptor(base: u64) -> [u64; 2] {
+    let limit = size_of::<TaskStateSegment>() as u64 - 1;
+
+    let low = (limit & 0xffff)
+        | (base & 0xff_ffff) << 16
+        | TSS_TYPE << 40
+        | (limit >> 16 & 0xf) << 48
+        | (base >> 24 & 0xff) << 56;
+
+    [low, base >> 32]
+}
+
+/// Builds CPU `cpu`'s GDT and TSS and loads them, reloading the segment registers. Called once
+/// per CPU, after boot services are gone: the firmware's interrupt handlers run on its own
+/// code segment, which this GDT does not have.
+pub fn init_cpu(cpu: usize) {
+    unsafe {
+        let tss = &raw mut TSSES[cpu];
+        let stack = &raw const DOUBLE_FAULT_STACKS[cpu];
+        (*tss).ist[DOUBLE_FAULT_IST as usize - 1] = stack as u64 + DOUBLE_FAULT_STACK_SIZE as u64;
+
+        let gdt = &raw mut GDTS[cpu];
+        let [tss_low, tss_high] = tss_descriptor(tss as u64);
+        *gdt = [0, KERNEL_CODE_ENTRY, KERNEL_DATA_ENTRY, USER_DATA_ENTRY, USER_CODE_ENTRY, tss_low, tss_high];
+
+        let pointer = DescriptorPointer { limit: (size_of::<[u64; ENTRIES]>() - 1) as u16, base: gdt as u64 };
+        asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+
+        // CS can only be reloaded by a far transfer; FS and GS keep their selectors, since
+        // reloading them would clear the bases the per-CPU code relies on
+        asm!(
+            "push {code}",
+            "lea {scratch}, [rip + 2f]",
+            "push {scratch}",
+            "retfq",
+            "2:",
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "mov ss, {data:x}",
+            code = in(reg) KERNEL_CODE as u64,
+            data = in(reg) KERNEL_DATA as u64,
+            scratch = lateout(reg) _,
+            options(preserves_flags),
+        );
+
+        asm!("ltr {:x}", in(reg) TSS, options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Points this CPU's RSP0 at `top`, the kernel stack interrupts from ring 3 land on.
+pub fn set_kernel_stack(top: u64) {
+    unsafe {
+        let tss = &raw mut TSSES[crate::os::percpu::cpu_id()];
+        (*tss).rsp[0] = top;
+    }
+}
+
+/// The code segment selector in use.
+pub fn code_segment() -> u16 {
+    let selector: u16;
+    unsafe { asm!("mov {:x}, cs", out(reg) selector, options(nomem, nostack, preserves_flags)) };
+    selector
+}
+
+/// The task register, the selector of the loaded TSS.
+pub fn task_register() -> u16 {
+    let selector: u16;
+    unsafe { asm!("str {:x}", out(reg) selector, options(nomem, nostack, preserves_flags)) };
+    selector
+}
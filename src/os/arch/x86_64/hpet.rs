@@ -0,0 +1,90 @@
+//! The High Precision Event Timer's main counter, as a clocksource.
+//!
+//! The ACPI HPET table locates the timer block (see [`acpi::hpet`]); its capabilities register
+//! gives the counter period in femtoseconds. Only the main counter is used, and only when it
+//! is 64 bits wide: a 32-bit one at the usual 14.3 MHz wraps every five minutes. It rates
+//! below an invariant TSC, which is cheaper to read, and above one that drifts.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::acpi;
+use crate::os::clocksource::{self, Clocksource};
+
+// Registers
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+// Capabilities: the counter is 64 bits wide; its period is in bits 32-63
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+// Configuration: the main counter runs
+const ENABLE_CNF: u64 = 1 << 0;
+
+// The specification caps the period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const RATING: u32 = 250;
+
+// MMIO base (0 = not in use), counter period, and the counter and clocksource readings when
+// it was registered, so time carries on from the source it replaces
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static START_COUNT: AtomicU64 = AtomicU64::new(0);
+static START_NS: AtomicU64 = AtomicU64::new(0);
+
+struct Hpet;
+
+static HPET: Hpet = Hpet;
+
+fn read(register: u64) -> u64 {
+    unsafe { ptr::read_volatile((BASE.load(Ordering::Relaxed) + register) as *const u64) }
+}
+
+fn write(register: u64, value: u64) {
+    unsafe { ptr::write_volatile((BASE.load(Ordering::Relaxed) + register) as *mut u64, value) }
+}
+
+/// Starts the main counter and registers it as a clocksource. Called once at boot, after the
+/// architecture counter is registered; does nothing without a usable HPET.
+pub fn init() {
+    let Some(table) = acpi::hpet() else {
+        return;
+    };
+
+    BASE.store(table.address, Ordering::Relaxed);
+    let capabilities = read(CAPABILITIES);
+    let period = capabilities >> 32;
+    if capabilities & COUNT_SIZE_CAP == 0 || period == 0 || period > MAX_PERIOD_FS {
+        log::info!("hpet: unusable counter (capabilities {:#x})", capabilities);
+        BASE.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    write(CONFIGURATION, read(CONFIGURATION) | ENABLE_CNF);
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    START_NS.store(clocksource::now_ns(), Ordering::Relaxed);
+    START_COUNT.store(read(MAIN_COUNTER), Ordering::Relaxed);
+
+    match clocksource::register(&HPET) {
+        Ok(()) => log::info!("hpet: {} MHz counter at {:#x}", 1_000_000_000 / period, table.address),
+        Err(errno) => log::warn!("hpet: cannot register: {:?}", errno),
+    }
+}
+
+impl Clocksource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        RATING
+    }
+
+    fn read_ns(&self) -> u64 {
+        let ticks = read(MAIN_COUNTER).wrapping_sub(START_COUNT.load(Ordering::Relaxed));
+        let elapsed = ticks as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000;
+        START_NS.load(Ordering::Relaxed) + elapsed as u64
+    }
+}
@@ -0,0 +1,507 @@
+//! Interrupt descriptor table and the exception and interrupt handlers.
+//!
+//! Each handled vector has a small entry stub that pushes a dummy error code where the CPU
+//! does not push one, then the vector number, and jumps to a common entry that saves the
+//! general-purpose registers. The result is a [`TrapFrame`] on the stack, which [`dispatch`]
+//! hands to the handler for the vector and which is restored on the way out, so a handler
+//! that returns resumes the interrupted code with whatever it left in the frame. The UEFI
+//! target is soft-float, so there is no vector register state to save besides.
+//!
+//! The APIC timer and the IPIs go to their handlers, after which the scheduler gets its chance
+//! to preempt the interrupted code (see [`sched::preempt`]). Device interrupts arrive on
+//! [`DEVICE_VECTORS`], each given to a driver's handler by [`allocate_vector`]; the EOI is
+//! sent for them once the handler returns.
+//!
+//! Breakpoints report the registers and carry on. A page fault on a user address first goes
+//! to [`fault::handle`], which pages in what belongs there; one in the guard below the stack
+//! kills the process. Page faults left over and general protection faults raised by user code
+//! raise `SIGSEGV`, which kills the process unless it has a handler; in the kernel they, and
+//! double faults, report the registers and panic. The double fault runs on its own stack (see
+//! [`gdt`](super::gdt)) so it can be reported even when the kernel stack is what overflowed.
+//! Anything returning to user mode delivers a pending signal on the way (see [`signal`]).
+//! Vectors without a gate raise a general protection fault naming the vector.
+
+use core::arch::{asm, naked_asm};
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::X86_64;
+use super::apic;
+use super::control::{Cr2, Cr3};
+use super::gdt::{self, DescriptorPointer};
+use super::signal;
+use crate::os::arch::Arch;
+use crate::os::cgroup;
+use crate::os::errno::Errno;
+use crate::os::fault;
+use crate::os::hrtimer;
+use crate::os::ipi;
+use crate::os::kthread;
+use crate::os::panic;
+use crate::os::percpu;
+use crate::os::ptable;
+use crate::os::sched;
+use crate::os::signal::SIGSEGV;
+use crate::os::uaccess;
+use crate::os::vma::{self, Access};
+
+/// `#BP`, raised by `int3`.
+pub const BREAKPOINT: u8 = 3;
+
+/// `#DF`, an exception while delivering another.
+pub const DOUBLE_FAULT: u8 = 8;
+
+/// `#GP`.
+pub const GENERAL_PROTECTION: u8 = 13;
+
+/// `#PF`.
+pub const PAGE_FAULT: u8 = 14;
+
+/// The APIC timer.
+pub const TIMER: u8 = super::TIMER_VECTOR as u8;
+
+/// The reschedule IPI.
+pub const RESCHEDULE: u8 = X86_64::RESCHEDULE_IPI as u8;
+
+/// The function-call IPI.
+pub const CALL_FUNCTION: u8 = X86_64::CALL_FUNCTION_IPI as u8;
+
+/// Vectors for device interrupts, well above the exceptions and below the timer and IPIs.
+pub const DEVICE_VECTORS: Range<u8> = 0x40..0x50;
+
+// Gate types: a present 64-bit interrupt gate, reachable from ring 0 only or from ring 3 too
+const INTERRUPT_GATE: u8 = 0x8e;
+const USER_INTERRUPT_GATE: u8 = 0xee;
+
+// Page fault error code bits
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_RESERVED: u64 = 1 << 3;
+const PF_FETCH: u64 = 1 << 4;
+
+// Selector error code bit: the index is an IDT vector
+const SELECTOR_IDT: u64 = 1 << 1;
+
+
+/// An IDT entry.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    flags: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl Gate {
+    const MISSING: Gate = Gate { offset_low: 0, selector: 0, ist: 0, flags: 0, offset_mid: 0, offset_high: 0, reserved: 0 };
+
+    fn new(handler: u64, ist: u8, flags: u8) -> Self {
+        Gate {
+            offset_low: handler as u16,
+            selector: gdt::KERNEL_CODE,
+            ist,
+            flags,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+static mut IDT: [Gate; 256] = [Gate::MISSING; 256];
+
+// Breakpoints taken so far
+static BREAKPOINTS: AtomicU64 = AtomicU64::new(0);
+
+// Handler of each device vector, as a `fn()` address (0 = free)
+static DEVICE_HANDLERS: [AtomicUsize; DEVICE_VECTORS.end as usize - DEVICE_VECTORS.start as usize] =
+    [const { AtomicUsize::new(0) }; DEVICE_VECTORS.end as usize - DEVICE_VECTORS.start as usize];
+
+/// The interrupted state, as the entry stubs leave it on the stack.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+
+    /// The CPU's error code, or 0 for vectors without one.
+    pub error_code: u64,
+
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rip {:#018x} cs {:#06x} rflags {:#010x}", self.rip, self.cs, self.rflags)?;
+        writeln!(f, "rsp {:#018x} ss {:#06x} error {:#x}", self.rsp, self.ss, self.error_code)?;
+        writeln!(f, "rax {:#018x} rbx {:#018x} rcx {:#018x} rdx {:#018x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "rsi {:#018x} rdi {:#018x} rbp {:#018x} r8  {:#018x}", self.rsi, self.rdi, self.rbp, self.r8)?;
+        writeln!(f, "r9  {:#018x} r10 {:#018x} r11 {:#018x} r12 {:#018x}", self.r9, self.r10, self.r11, self.r12)?;
+        writeln!(f, "r13 {:#018x} r14 {:#018x} r15 {:#018x}", self.r13, self.r14, self.r15)?;
+        write!(f, "cr2 {:#018x} cr3 {:#018x} cpu {}", Cr2::read(), Cr3::read().0, percpu::cpu_id())
+    }
+}
+
+// Entry stub for a vector the CPU pushes an error code for
+macro_rules! entry_with_error_code {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        unsafe extern "sysv64" fn $name() {
+            naked_asm!("push {vector}", "jmp {common}", vector = const $vector, common = sym common_entry)
+        }
+    };
+}
+
+// Entry stub for a vector without an error code, which pushes 0 in its place
+macro_rules! entry {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        unsafe extern "sysv64" fn $name() {
+            naked_asm!("push 0", "push {vector}", "jmp {common}", vector = const $vector, common = sym common_entry)
+        }
+    };
+}
+
+entry!(breakpoint_entry, BREAKPOINT);
+entry_with_error_code!(double_fault_entry, DOUBLE_FAULT);
+entry_with_error_code!(general_protection_entry, GENERAL_PROTECTION);
+entry_with_error_code!(page_fault_entry, PAGE_FAULT);
+entry!(timer_entry, TIMER);
+entry!(reschedule_entry, RESCHEDULE);
+entry!(call_function_entry, CALL_FUNCTION);
+entry!(device_entry_0, DEVICE_VECTORS.start);
+entry!(device_entry_1, DEVICE_VECTORS.start + 1);
+entry!(device_entry_2, DEVICE_VECTORS.start + 2);
+entry!(device_entry_3, DEVICE_VECTORS.start + 3);
+entry!(device_entry_4, DEVICE_VECTORS.start + 4);
+entry!(device_entry_5, DEVICE_VECTORS.start + 5);
+entry!(device_entry_6, DEVICE_VECTORS.start + 6);
+entry!(device_entry_7, DEVICE_VECTORS.start + 7);
+entry!(device_entry_8, DEVICE_VECTORS.start + 8);
+entry!(device_entry_9, DEVICE_VECTORS.start + 9);
+entry!(device_entry_10, DEVICE_VECTORS.start + 10);
+entry!(device_entry_11, DEVICE_VECTORS.start + 11);
+entry!(device_entry_12, DEVICE_VECTORS.start + 12);
+entry!(device_entry_13, DEVICE_VECTORS.start + 13);
+entry!(device_entry_14, DEVICE_VECTORS.start + 14);
+entry!(device_entry_15, DEVICE_VECTORS.start + 15);
+
+// The device vectors' stubs, in vector order
+const DEVICE_ENTRIES: [unsafe extern "sysv64" fn(); 16] = [
+    device_entry_0, device_entry_1, device_entry_2, device_entry_3,
+    device_entry_4, device_entry_5, device_entry_6, device_entry_7,
+    device_entry_8, device_entry_9, device_entry_10, device_entry_11,
+    device_entry_12, device_entry_13, device_entry_14, device_entry_15,
+];
+
+// Completes the TrapFrame, calls dispatch with it and returns from the interrupt. The CPU
+// aligned the stack before pushing its part, and the frame is a multiple of 16 bytes, so the
+// call is aligned too. rbp is left alone, keeping the interrupted code's frame chain. Coming
+// from ring 3 (RPL 3 in the saved CS) the GS base is the user's, so it is swapped in and out.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn common_entry() {
+    naked_asm!(
+        "test qword ptr [rsp + 24], 3",
+        "jz 2f",
+        "swapgs",
+        "2:",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "cld",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // Vector and error code
+        "add rsp, 16",
+        "test qword ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        dispatch = sym dispatch,
+    )
+}
+
+/// Hands an exception or interrupt to its handler, then, on the way back to user mode, a
+/// pending signal to its process.
+extern "sysv64" fn dispatch(frame: &mut TrapFrame) {
+    match frame.vector as u8 {
+        BREAKPOINT => breakpoint(frame),
+        PAGE_FAULT => page_fault(frame),
+        GENERAL_PROTECTION => general_protection(frame),
+        DOUBLE_FAULT => fault("double fault", frame),
+        TIMER => interrupt(hrtimer::handle_interrupt),
+        RESCHEDULE => interrupt(ipi::handle_reschedule),
+        CALL_FUNCTION => interrupt(ipi::handle_call_function),
+        vector if DEVICE_VECTORS.contains(&vector) => device_interrupt(vector),
+        vector => fault_with(frame, format_args!("exception {}", vector)),
+    }
+
+    if from_user(frame) {
+        signal::deliver_on_trap_return(frame);
+    }
+}
+
+// Runs an interrupt handler, which sends the EOI, then lets the scheduler preempt
+fn interrupt(handler: fn()) {
+    percpu::STATS.with(|stats| stats.interrupts += 1);
+    handler();
+    sched::preempt();
+}
+
+// Runs the driver's handler for a device vector, sends the EOI, then lets the scheduler
+// preempt. A vector nobody holds is acknowledged and dropped.
+fn device_interrupt(vector: u8) {
+    percpu::STATS.with(|stats| stats.interrupts += 1);
+
+    let handler = DEVICE_HANDLERS[(vector - DEVICE_VECTORS.start) as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler = unsafe { core::mem::transmute::<usize, fn()>(handler) };
+        handler();
+    }
+
+    apic::eoi();
+    sched::preempt();
+}
+
+/// Gives a free device vector to `handler`, which then runs, with interrupts masked, for
+/// every interrupt on it. Returns `None` once every vector is taken.
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    DEVICE_HANDLERS.iter().zip(DEVICE_VECTORS).find_map(|(slot, vector)| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed).ok().map(|_| vector)
+    })
+}
+
+fn breakpoint(frame: &TrapFrame) {
+    BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
+
+    // rip is past the int3
+    log::warn!("x86_64: breakpoint at {:#x}\n{}", frame.rip - 1, frame);
+}
+
+fn page_fault(frame: &TrapFrame) {
+    percpu::STATS.with(|stats| stats.page_faults += 1);
+    let (addr, cause) = (Cr2::read(), PageFaultCause(frame.error_code));
+
+    // User code, or the kernel reaching user memory for it, may have hit a page that is simply
+    // not there yet
+    if uaccess::is_user_range(addr as usize, 1) && frame.error_code & PF_RESERVED == 0 {
+        let pid = percpu::current_pid();
+        let access = match frame.error_code {
+            code if code & PF_FETCH != 0 => Access::Execute,
+            code if code & PF_WRITE != 0 => Access::Write,
+            _ => Access::Read,
+        };
+        let mut resolved = ptable::with_process(pid, |process| fault::handle(process, addr, access));
+
+        // Charges only drop page cache with the table locked; swapping or killing to make more
+        // room waits until it is not, and the fault is then tried once more
+        if resolved == Some(Err(Errno::ENOMEM))
+            && let Some(group) = ptable::with_process(pid, |process| process.cgroup)
+            && cgroup::make_room(group, 1).is_ok()
+        {
+            resolved = ptable::with_process(pid, |process| fault::handle(process, addr, access));
+        }
+
+        match resolved {
+            Some(Ok(true)) => return,
+            Some(Err(errno)) => log::debug!("x86_64: pid {}: page fault at {:#x} not resolved: {:?}", pid, addr, errno),
+            _ => {}
+        }
+
+        if from_user(frame) && ptable::with_process(pid, |process| vma::in_stack_guard(process, addr)) == Some(true) {
+            log::warn!("x86_64: pid {}: stack overflow at {:#x}, killed", pid, addr);
+            kthread::exit_current(signal::exit_status(SIGSEGV))
+        }
+    }
+
+    if from_user(frame) {
+        return user_fault(frame, format_args!("page fault at {:#x}: {}", addr, cause));
+    }
+    fault_with(frame, format_args!("page fault at {:#x}: {}", addr, cause));
+}
+
+fn general_protection(frame: &TrapFrame) {
+    if from_user(frame) {
+        return user_fault(frame, format_args!("general protection fault"));
+    }
+
+    if frame.error_code & SELECTOR_IDT != 0 {
+        fault_with(frame, format_args!("general protection fault: no handler for vector {}", frame.error_code >> 3));
+    }
+
+    fault("general protection fault", frame);
+}
+
+// Whether the exception interrupted ring 3
+fn from_user(frame: &TrapFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+// Raises SIGSEGV for an exception user code raised, or kills the process if it has no handler
+// for it that could recover
+fn user_fault(frame: &TrapFrame, what: fmt::Arguments) {
+    let pid = percpu::current_pid();
+    if ptable::with_process(pid, |process| signal::force(process, SIGSEGV)) == Some(true) {
+        return;
+    }
+
+    log::warn!("x86_64: pid {}: {} at {:#x}, killed", pid, what, frame.rip);
+    kthread::exit_current(signal::exit_status(SIGSEGV))
+}
+
+fn fault(name: &str, frame: &TrapFrame) -> ! {
+    fault_with(frame, format_args!("{}", name))
+}
+
+// Reports an exception the kernel cannot recover from and panics
+fn fault_with(frame: &TrapFrame, what: fmt::Arguments) -> ! {
+    log::error!("x86_64: {}\n{}", what, frame);
+    panic::record_fault(frame.rip as usize, frame.rsp as usize, frame.rbp as usize);
+    panic!("x86_64: {} at {:#x}", what, frame.rip);
+}
+
+/// A page fault error code, decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultCause(pub u64);
+
+impl fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+
+        let access = if code & PF_FETCH != 0 {
+            "instruction fetch"
+        } else if code & PF_WRITE != 0 {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if code & PF_USER != 0 { "user" } else { "kernel" };
+
+        if code & PF_RESERVED != 0 {
+            write!(f, "reserved bit set in a page table entry, {} from {} mode", access, mode)
+        } else if code & PF_PRESENT != 0 {
+            write!(f, "protection violation, {} from {} mode", access, mode)
+        } else {
+            write!(f, "page not present, {} from {} mode", access, mode)
+        }
+    }
+}
+
+/// Installs the exception and interrupt handlers and loads the IDT on this CPU. The gates are shared by
+/// every CPU; each must have loaded its GDT and TSS first, for the double fault stack.
+pub fn init() {
+    unsafe {
+        let idt = &raw mut IDT;
+
+        (*idt)[BREAKPOINT as usize] = Gate::new(breakpoint_entry as *const () as u64, 0, USER_INTERRUPT_GATE);
+        (*idt)[DOUBLE_FAULT as usize] =
+            Gate::new(double_fault_entry as *const () as u64, gdt::DOUBLE_FAULT_IST, INTERRUPT_GATE);
+        (*idt)[GENERAL_PROTECTION as usize] = Gate::new(general_protection_entry as *const () as u64, 0, INTERRUPT_GATE);
+        (*idt)[PAGE_FAULT as usize] = Gate::new(page_fault_entry as *const () as u64, 0, INTERRUPT_GATE);
+        (*idt)[TIMER as usize] = Gate::new(timer_entry as *const () as u64, 0, INTERRUPT_GATE);
+        (*idt)[RESCHEDULE as usize] = Gate::new(reschedule_entry as *const () as u64, 0, INTERRUPT_GATE);
+        (*idt)[CALL_FUNCTION as usize] = Gate::new(call_function_entry as *const () as u64, 0, INTERRUPT_GATE);
+        for (vector, entry) in DEVICE_VECTORS.zip(DEVICE_ENTRIES) {
+            (*idt)[vector as usize] = Gate::new(entry as *const () as u64, 0, INTERRUPT_GATE);
+        }
+    }
+
+    load();
+}
+
+/// Loads the IDT on this CPU.
+pub fn load() {
+    let pointer = DescriptorPointer { limit: (size_of::<[Gate; 256]>() - 1) as u16, base: (&raw const IDT) as u64 };
+    unsafe { asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags)) };
+}
+
+/// Number of breakpoints taken.
+pub fn breakpoints() -> u64 {
+    BREAKPOINTS.load(Ordering::Relaxed)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use alloc::format;
+
+    crate::os::ktest::kernel_test! {
+        fn descriptor_tables_are_loaded() {
+            assert_eq!(gdt::code_segment(), gdt::KERNEL_CODE);
+            assert_eq!(gdt::task_register(), gdt::TSS);
+
+            let [low, high] = gdt::tss_descriptor(0x1234_5678_9abc_def0);
+            assert_eq!((low & 0xffff, low >> 40 & 0xff), (103, 0x89));
+            assert_eq!((low >> 16 & 0xff_ffff) | (low >> 56) << 24 | high << 32, 0x1234_5678_9abc_def0);
+        }
+
+        fn breakpoints_resume() {
+            let before = breakpoints();
+            let value: u64;
+            unsafe { asm!("mov rax, 42", "int3", out("rax") value, options(nomem, nostack)) };
+            assert_eq!((breakpoints(), value), (before + 1, 42));
+        }
+
+        fn page_fault_causes_decode() {
+            let text = |code| format!("{}", PageFaultCause(code));
+
+            assert_eq!(text(0), "page not present, read from kernel mode");
+            assert_eq!(text(PF_PRESENT | PF_WRITE | PF_USER), "protection violation, write from user mode");
+            assert_eq!(text(PF_PRESENT | PF_FETCH), "protection violation, instruction fetch from kernel mode");
+            assert_eq!(text(PF_PRESENT | PF_RESERVED), "reserved bit set in a page table entry, read from kernel mode");
+        }
+    }
+}
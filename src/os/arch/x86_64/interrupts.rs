@@ -0,0 +1,24 @@
+use core::arch::asm;
+
+/// RFLAGS.IF: maskable interrupts are enabled.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Returns `true` if maskable interrupts are enabled on this CPU.
+#[inline]
+pub fn are_enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq", "pop {}", out(reg) flags, options(nomem, preserves_flags)) };
+    flags & RFLAGS_IF != 0
+}
+
+/// Masks interrupts on this CPU.
+#[inline]
+pub fn disable() {
+    unsafe { asm!("cli", options(nomem, nostack)) };
+}
+
+/// Unmasks interrupts on this CPU.
+#[inline]
+pub fn enable() {
+    unsafe { asm!("sti", options(nomem, nostack)) };
+}
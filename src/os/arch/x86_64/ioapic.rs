@@ -0,0 +1,151 @@
+//! I/O APIC: routing the legacy ISA interrupts (the keyboard's IRQ 1, say) to IDT vectors.
+//!
+//! The MADT names the I/O APIC and where the firmware wired ISA IRQs to other global system
+//! interrupts, with the polarity and trigger mode of each; an IRQ without an override is the
+//! GSI of the same number, active high and edge-triggered. Only the I/O APIC serving GSI 0 is
+//! used, which on PCs and QEMU is the one the ISA IRQs are on. The 8259 PICs are left masked,
+//! as the firmware leaves them.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::apic;
+use crate::os::acpi::{self, ISA_IRQS};
+use crate::os::errno::{Errno, KResult};
+
+// Override flags: polarity in bits 0-1, trigger mode in bits 2-3 (3 = active low / level)
+const FLAGS_ACTIVE_LOW: u32 = 0b11;
+const FLAGS_LEVEL: u32 = 0b11 << 2;
+
+// Registers, reached by writing the index to IOREGSEL and then using IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+// Redirection entry bits
+const ENTRY_ACTIVE_LOW: u32 = 1 << 13;
+const ENTRY_LEVEL: u32 = 1 << 15;
+const ENTRY_MASKED: u32 = 1 << 16;
+
+// MMIO base (0 = none found)
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+// Number of redirection entries
+static ENTRIES: AtomicU32 = AtomicU32::new(0);
+
+// GSI and flags of each ISA IRQ, as (gsi << 16 | flags); u32::MAX = no override
+static OVERRIDES: [AtomicU32; ISA_IRQS] = [const { AtomicU32::new(u32::MAX) }; ISA_IRQS];
+
+fn read(register: u32) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        ptr::write_volatile((base + IOREGSEL) as *mut u32, register);
+        ptr::read_volatile((base + IOWIN) as *const u32)
+    }
+}
+
+fn write(register: u32, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        ptr::write_volatile((base + IOREGSEL) as *mut u32, register);
+        ptr::write_volatile((base + IOWIN) as *mut u32, value);
+    }
+}
+
+/// Finds the I/O APIC and the ISA overrides in the MADT and masks every input. Called once at
+/// boot, before any driver routes an IRQ.
+pub fn init() {
+    let Some(madt) = acpi::madt() else {
+        log::warn!("ioapic: no MADT, ISA interrupts are unavailable");
+        return;
+    };
+
+    if let Some(ioapic) = madt.ioapics().iter().find(|ioapic| ioapic.gsi_base == 0) {
+        BASE.store(ioapic.address, Ordering::Relaxed);
+    }
+    for (slot, isa_override) in OVERRIDES.iter().zip(madt.isa_overrides) {
+        if let Some(isa_override) = isa_override {
+            slot.store(isa_override.gsi << 16 | isa_override.flags as u32, Ordering::Relaxed);
+        }
+    }
+
+    if BASE.load(Ordering::Relaxed) == 0 {
+        log::warn!("ioapic: the MADT lists no I/O APIC for GSI 0");
+        return;
+    }
+
+    // Maximum redirection entry index in bits 16-23
+    let entries = ((read(REG_VERSION) >> 16) & 0xff) + 1;
+    ENTRIES.store(entries, Ordering::Relaxed);
+    for gsi in 0..entries {
+        write(REG_REDIRECTION + gsi * 2, ENTRY_MASKED);
+    }
+
+    log::info!("ioapic: at {:#x}, {} inputs", BASE.load(Ordering::Relaxed), entries);
+}
+
+/// The GSI ISA IRQ `irq` arrives on, and the redirection entry bits for its polarity and
+/// trigger mode.
+pub fn isa_gsi(irq: u8) -> (u32, u32) {
+    match OVERRIDES.get(irq as usize).map(|slot| slot.load(Ordering::Relaxed)) {
+        Some(value) if value != u32::MAX => {
+            let flags = value & 0xffff;
+            let mut bits = 0;
+            if flags & FLAGS_ACTIVE_LOW == FLAGS_ACTIVE_LOW {
+                bits |= ENTRY_ACTIVE_LOW;
+            }
+            if flags & FLAGS_LEVEL == FLAGS_LEVEL {
+                bits |= ENTRY_LEVEL;
+            }
+            (value >> 16, bits)
+        }
+        _ => (irq as u32, 0),
+    }
+}
+
+/// Delivers ISA IRQ `irq` to `vector` on this CPU. Fails with `ENODEV` without an I/O APIC
+/// and `EINVAL` if the IRQ's GSI is not one of its inputs.
+pub fn route_isa(irq: u8, vector: u8) -> KResult<()> {
+    if BASE.load(Ordering::Relaxed) == 0 {
+        return Err(Errno::ENODEV);
+    }
+
+    let (gsi, bits) = isa_gsi(irq);
+    if gsi >= ENTRIES.load(Ordering::Relaxed) {
+        return Err(Errno::EINVAL);
+    }
+
+    // Destination APIC ID in bits 56-63; the low half unmasks the entry, so it goes last
+    write(REG_REDIRECTION + gsi * 2 + 1, (apic::id() & 0xff) << 24);
+    write(REG_REDIRECTION + gsi * 2, vector as u32 | bits);
+    Ok(())
+}
+
+/// Masks ISA IRQ `irq` again.
+pub fn mask_isa(irq: u8) {
+    let (gsi, bits) = isa_gsi(irq);
+    if BASE.load(Ordering::Relaxed) != 0 && gsi < ENTRIES.load(Ordering::Relaxed) {
+        write(REG_REDIRECTION + gsi * 2, ENTRY_MASKED | bits);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn isa_irqs_without_overrides_map_to_themselves() {
+            // QEMU overrides IRQ 0 (to GSI 2) and the PCI link IRQs, but never the keyboard's
+            assert_eq!(isa_gsi(1), (1, 0));
+            assert_eq!(isa_gsi(200), (200, 0));
+        }
+
+        fn the_keyboard_irq_is_an_input() {
+            if BASE.load(Ordering::Relaxed) == 0 {
+                assert_eq!(route_isa(1, 0x40), Err(Errno::ENODEV));
+                return;
+            }
+            assert!(isa_gsi(1).0 < ENTRIES.load(Ordering::Relaxed));
+        }
+    }
+}
@@ -0,0 +1,449 @@
+/// Declares a `Copy` newtype over a register value with named bit constants and the usual set
+/// operations, so register contents are passed around typed instead of as bare `u64`s.
+macro_rules! register_flags {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident: $ty:ty {
+            $(
+                $(#[$flag_meta:meta])*
+                const $flag:ident = $value:expr;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub struct $name($ty);
+
+        impl $name {
+            $(
+                $(#[$flag_meta])*
+                pub const $flag: $name = $name($value);
+            )*
+
+            pub const fn empty() -> Self {
+                $name(0)
+            }
+
+            /// Wraps a raw register value, keeping bits without a named constant.
+            pub const fn from_bits_retain(bits: $ty) -> Self {
+                $name(bits)
+            }
+
+            pub const fn bits(&self) -> $ty {
+                self.0
+            }
+
+            pub const fn contains(&self, other: $name) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            pub const fn intersects(&self, other: $name) -> bool {
+                self.0 & other.0 != 0
+            }
+
+            pub fn insert(&mut self, other: $name) {
+                self.0 |= other.0;
+            }
+
+            pub fn remove(&mut self, other: $name) {
+                self.0 &= !other.0;
+            }
+
+            pub fn set(&mut self, other: $name, enabled: bool) {
+                if enabled {
+                    self.insert(other);
+                } else {
+                    self.remove(other);
+                }
+            }
+        }
+
+        impl core::ops::BitOr for $name {
+            type Output = $name;
+
+            fn bitor(self, other: $name) -> $name {
+                $name(self.0 | other.0)
+            }
+        }
+
+        impl core::ops::BitOrAssign for $name {
+            fn bitor_assign(&mut self, other: $name) {
+                self.0 |= other.0;
+            }
+        }
+    };
+}
+
+/// A register write was refused because it would enable something the CPU does not support
+/// (which would raise #GP) or an inconsistent combination of bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRegisterValue(pub &'static str);
+
+pub mod apic;
+pub mod context;
+pub mod control;
+pub mod gdt;
+pub mod hpet;
+pub mod idt;
+pub mod interrupts;
+pub mod ioapic;
+pub mod msr;
+pub mod pit;
+pub mod port;
+pub mod pte;
+pub mod rng;
+pub mod rtc;
+pub mod signal;
+pub mod smp;
+pub mod syscall;
+pub mod uart;
+pub mod user;
+pub mod wakeup;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use self::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use self::msr::{Efer, EferFlags, Msr};
+use super::{Arch, ContextEntry, GuardedEntry};
+use crate::os::cpu;
+use crate::os::percpu;
+use crate::os::timer::TICK_NS;
+
+/// The x86_64 port: local APIC, 4-level paging with CR3, the TSC and its deadline timer.
+pub struct X86_64;
+
+// Whether EFER.NXE is on; the NX bit is a reserved bit (and faults) when it is not
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Whether the APIC timer is in TSC-deadline mode; IA32_TSC_DEADLINE faults on CPUs without it
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+// IDT vector of the APIC timer, below the IPIs
+const TIMER_VECTOR: u32 = 0xef;
+
+// How long the PIT runs to calibrate the periodic APIC timer against
+const CALIBRATION_US: u32 = 10_000;
+
+// APIC timer count of one scheduler tick when it runs periodically, 0 when it does not
+static PERIODIC_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Puts the boot CPU's APIC timer in TSC-deadline mode. Called once at boot after CPU feature
+/// detection. Without the mode there is no one-shot timer, and the APIC timer interrupts every
+/// tick instead, from [`init_descriptor_tables`] on; timers then expire on the next tick.
+pub fn init() {
+    if !cpu::features().tsc_deadline {
+        log::warn!("x86_64: no TSC-deadline timer, falling back to a periodic tick");
+        return;
+    }
+
+    init_cpu();
+    TSC_DEADLINE.store(true, Ordering::Relaxed);
+}
+
+/// Loads this CPU's GDT and TSS and the IDT with the exception handlers and enables
+/// `syscall`, then starts the periodic tick if the CPU needs one, now that its vector has a
+/// gate. Called once boot services are gone: the firmware's interrupt handlers need its own
+/// GDT, and its timer may be the APIC timer.
+pub fn init_descriptor_tables() {
+    gdt::init_cpu(percpu::cpu_id());
+    idt::init();
+    syscall::init_cpu();
+
+    if !cpu::features().tsc_deadline {
+        start_periodic_tick();
+    }
+}
+
+// Calibrates the APIC timer against the PIT and starts it interrupting every scheduler tick
+fn start_periodic_tick() {
+    let counts = apic::measure_timer(|| pit::wait_us(CALIBRATION_US)) as u64;
+    let per_tick = (counts * TICK_NS / (CALIBRATION_US as u64 * 1000)).clamp(1, u32::MAX as u64) as u32;
+
+    PERIODIC_COUNT.store(per_tick, Ordering::Relaxed);
+    apic::init_periodic_timer(TIMER_VECTOR as u8, per_tick);
+    log::info!("x86_64: periodic tick every {} APIC timer counts", per_tick);
+}
+
+/// Puts an application processor's APIC timer in TSC-deadline mode, disarmed, or starts its
+/// periodic tick.
+pub fn init_cpu() {
+    if cpu::features().tsc_deadline {
+        apic::init_deadline_timer(TIMER_VECTOR as u8);
+        unsafe { Msr::IA32_TSC_DEADLINE.write(0) };
+        return;
+    }
+
+    let count = PERIODIC_COUNT.load(Ordering::Relaxed);
+    if count != 0 {
+        apic::init_periodic_timer(TIMER_VECTOR as u8, count);
+    }
+}
+
+impl Arch for X86_64 {
+    type Context = context::Context;
+
+    const RESCHEDULE_IPI: u32 = 0xfd;
+    const CALL_FUNCTION_IPI: u32 = 0xfb;
+    const TIMER_IRQ: u32 = TIMER_VECTOR;
+    const COUNTER_NAME: &'static str = "tsc";
+    // The higher half, under a PML4 entry of its own
+    const VMALLOC_START: u64 = 0xffff_c900_0000_0000;
+
+    #[inline]
+    fn interrupts_enabled() -> bool {
+        interrupts::are_enabled()
+    }
+
+    #[inline]
+    fn enable_interrupts() {
+        interrupts::enable();
+    }
+
+    #[inline]
+    fn disable_interrupts() {
+        interrupts::disable();
+    }
+
+    fn wait_for_interrupt() {
+        // `sti; hlt` cannot miss a wakeup because sti takes effect after hlt starts
+        unsafe { asm!("sti; hlt", options(nomem, nostack)) };
+    }
+
+    fn halt() -> ! {
+        loop {
+            unsafe { asm!("cli; hlt", options(nomem, nostack)) };
+        }
+    }
+
+    unsafe fn set_percpu_base(base: u64) {
+        unsafe {
+            Msr::IA32_GS_BASE.write(base);
+
+            // Becomes the user GS base after the first swapgs on the way to ring 3
+            Msr::IA32_KERNEL_GS_BASE.write(0);
+        }
+    }
+
+    #[inline]
+    fn percpu_base() -> u64 {
+        // The area's first word is its own address
+        let base: u64;
+        unsafe { asm!("mov {}, gs:[0]", out(reg) base, options(nostack, readonly, preserves_flags)) };
+        base
+    }
+
+    fn hardware_cpu_id() -> u32 {
+        apic::id()
+    }
+
+    fn send_ipi(hw_id: u32, ipi: u32) {
+        apic::send_ipi(hw_id, ipi as u8);
+    }
+
+    fn send_ipi_all_but_self(ipi: u32) {
+        apic::send_ipi_all_but_self(ipi as u8);
+    }
+
+    fn end_of_interrupt(_irq: u32) {
+        apic::eoi();
+    }
+
+    fn page_table_root() -> u64 {
+        Cr3::read().0
+    }
+
+    unsafe fn set_page_table_root(root: u64) {
+        unsafe { Cr3::write(root, 0).expect("x86_64: invalid page table root") };
+    }
+
+    #[inline]
+    fn flush_tlb_page(addr: u64) {
+        unsafe { asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags)) };
+    }
+
+    fn flush_tlb_local() {
+        let (root, low) = Cr3::read();
+        unsafe { Cr3::write(root, low).expect("x86_64: cannot reload CR3") };
+    }
+
+    fn flush_tlb_all_local() {
+        let cr4 = Cr4::read();
+
+        if cr4.contains(Cr4Flags::PGE) {
+            // Toggling CR4.PGE drops every cached translation
+            let mut without_pge = cr4;
+            without_pge.remove(Cr4Flags::PGE);
+
+            unsafe {
+                Cr4::write(without_pge).expect("x86_64: cannot clear CR4.PGE");
+                Cr4::write(cr4).expect("x86_64: cannot restore CR4.PGE");
+            }
+        } else {
+            Self::flush_tlb_local();
+        }
+    }
+
+    fn page_flags(write: bool, exec: bool, user: bool) -> u64 {
+        let mut flags = pte::PRESENT;
+
+        if write {
+            flags |= pte::WRITABLE;
+        }
+
+        if user {
+            flags |= pte::USER;
+        }
+
+        if !exec && NX_ENABLED.load(Ordering::Relaxed) {
+            flags |= pte::NO_EXECUTE;
+        }
+
+        flags
+    }
+
+    fn entry_address(entry: u64) -> u64 {
+        entry & pte::ADDRESS_MASK
+    }
+
+    fn accessed_flag() -> u64 {
+        pte::ACCESSED
+    }
+
+    fn leaf_entry(root: u64, virt: u64) -> Option<*mut u64> {
+        pte::leaf(root, virt)
+    }
+
+    fn huge_entry(root: u64, virt: u64) -> Option<*mut u64> {
+        pte::huge(root, virt)
+    }
+
+    fn huge_flags(flags: u64) -> u64 {
+        flags | pte::HUGE_PAGE
+    }
+
+    fn split_flags(huge_flags: u64) -> u64 {
+        huge_flags & !pte::HUGE_PAGE
+    }
+
+    fn is_huge(entry: u64) -> bool {
+        entry & (pte::PRESENT | pte::HUGE_PAGE) == pte::PRESENT | pte::HUGE_PAGE
+    }
+
+    fn table_entry(phys: u64) -> u64 {
+        pte::table_entry(phys)
+    }
+
+    fn leaf_entry_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+        pte::leaf_or_create(root, virt, alloc)
+    }
+
+    fn init_memory_protection() {
+        unsafe {
+            if cpu::features().nx {
+                Efer::update(|efer| efer.insert(EferFlags::NXE)).expect("x86_64: cannot enable EFER.NXE");
+                NX_ENABLED.store(true, Ordering::Relaxed);
+            } else {
+                log::warn!("x86_64: CPU lacks NX, data pages will be executable");
+            }
+
+            Cr0::update(|cr0| cr0.insert(Cr0Flags::WP)).expect("x86_64: cannot enable CR0.WP");
+        }
+    }
+
+    fn init_user_access_protection() -> (bool, bool) {
+        let features = cpu::features();
+        let (smep, smap) = (features.smep, features.smap);
+
+        let result = unsafe {
+            Cr4::update(|cr4| {
+                if smep {
+                    cr4.insert(Cr4Flags::SMEP);
+                }
+
+                if smap {
+                    cr4.insert(Cr4Flags::SMAP);
+                }
+            })
+        };
+        result.expect("x86_64: cannot enable SMEP/SMAP");
+
+        (smep, smap)
+    }
+
+    #[inline]
+    fn user_access_begin() {
+        unsafe { control::stac() };
+    }
+
+    #[inline]
+    fn user_access_end() {
+        unsafe { control::clac() };
+    }
+
+    #[inline]
+    fn read_counter() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        None
+    }
+
+    fn counter_is_stable() -> bool {
+        cpu::features().invariant_tsc
+    }
+
+    fn has_timer() -> bool {
+        TSC_DEADLINE.load(Ordering::Relaxed)
+    }
+
+    fn arm_timer(deadline: u64) {
+        // 0 disarms the timer, so the earliest deadline that can be asked for is 1
+        if Self::has_timer() {
+            unsafe { Msr::IA32_TSC_DEADLINE.write(deadline.max(1)) };
+        }
+    }
+
+    fn disarm_timer() {
+        if Self::has_timer() {
+            unsafe { Msr::IA32_TSC_DEADLINE.write(0) };
+        }
+    }
+
+    fn hardware_random() -> Option<u64> {
+        // RDSEED is conditioned entropy, RDRAND a DRBG seeded from it
+        let features = cpu::features();
+        if features.rdseed { rng::rdseed() } else { None }.or(if features.rdrand { rng::rdrand() } else { None })
+    }
+
+    #[inline(always)]
+    fn frame_pointer() -> usize {
+        let rbp: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        rbp
+    }
+
+    #[inline(always)]
+    fn stack_pointer() -> usize {
+        let rsp: usize;
+        unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+        rsp
+    }
+
+    fn new_context(stack_top: u64, entry: ContextEntry, arg: usize) -> Self::Context {
+        context::Context::new(stack_top, entry, arg)
+    }
+
+    unsafe fn switch_context(prev: *mut Self::Context, next: *const Self::Context) {
+        unsafe { context::switch(prev, next) };
+    }
+
+    unsafe fn call_with_context(point: *mut Self::Context, entry: GuardedEntry, arg: *const ()) -> bool {
+        unsafe { context::call_with(point, entry, arg) != 0 }
+    }
+
+    unsafe fn resume_context(point: *const Self::Context) -> ! {
+        unsafe { context::resume(point) }
+    }
+}
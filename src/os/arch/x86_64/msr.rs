@@ -0,0 +1,123 @@
+use core::arch::asm;
+
+use super::InvalidRegisterValue;
+use crate::os::cpu;
+
+/// A model-specific register, identified by its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(pub u32);
+
+impl Msr {
+    /// Local APIC base address and enable bits.
+    pub const IA32_APIC_BASE: Msr = Msr(0x1b);
+
+    /// Page attribute table.
+    pub const IA32_PAT: Msr = Msr(0x277);
+
+    /// TSC value at which the local APIC timer fires in TSC-deadline mode.
+    pub const IA32_TSC_DEADLINE: Msr = Msr(0x6e0);
+
+    /// Extended Feature Enable Register; prefer the typed [`Efer`] accessor.
+    pub const IA32_EFER: Msr = Msr(0xc000_0080);
+
+    /// SYSCALL/SYSRET segment selectors.
+    pub const IA32_STAR: Msr = Msr(0xc000_0081);
+
+    /// SYSCALL entry point in long mode.
+    pub const IA32_LSTAR: Msr = Msr(0xc000_0082);
+
+    /// RFLAGS bits cleared on SYSCALL.
+    pub const IA32_FMASK: Msr = Msr(0xc000_0084);
+
+    /// FS segment base.
+    pub const IA32_FS_BASE: Msr = Msr(0xc000_0100);
+
+    /// GS segment base.
+    pub const IA32_GS_BASE: Msr = Msr(0xc000_0101);
+
+    /// GS base swapped in by `swapgs`.
+    pub const IA32_KERNEL_GS_BASE: Msr = Msr(0xc000_0102);
+
+    /// Reads the register.
+    ///
+    /// # Safety
+    /// Reading an MSR the CPU does not implement raises #GP.
+    #[inline]
+    pub unsafe fn read(self) -> u64 {
+        let (low, high): (u32, u32);
+        unsafe { asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags)) };
+        ((high as u64) << 32) | low as u64
+    }
+
+    /// Writes the register.
+    ///
+    /// # Safety
+    /// Writing MSRs changes global CPU behaviour; an unimplemented MSR or reserved bit raises #GP.
+    #[inline]
+    pub unsafe fn write(self, value: u64) {
+        let (low, high) = (value as u32, (value >> 32) as u32);
+        unsafe { asm!("wrmsr", in("ecx") self.0, in("eax") low, in("edx") high, options(nostack, preserves_flags)) };
+    }
+}
+
+register_flags! {
+    /// Contents of IA32_EFER.
+    pub struct EferFlags: u64 {
+        /// SYSCALL/SYSRET enable.
+        const SCE = 1 << 0;
+
+        /// Long mode enable.
+        const LME = 1 << 8;
+
+        /// Long mode active (read-only).
+        const LMA = 1 << 10;
+
+        /// Enables the no-execute (NX/XD) page table bit.
+        const NXE = 1 << 11;
+    }
+}
+
+pub struct Efer;
+
+impl Efer {
+    #[inline]
+    pub fn read() -> EferFlags {
+        // EFER exists on every x86_64 CPU
+        EferFlags::from_bits_retain(unsafe { Msr::IA32_EFER.read() })
+    }
+
+    /// Writes EFER, refusing to leave long mode or enable features the CPU lacks.
+    ///
+    /// # Safety
+    /// Changes how the CPU interprets page tables and system call instructions.
+    #[inline]
+    pub unsafe fn write(flags: EferFlags) -> Result<(), InvalidRegisterValue> {
+        let features = cpu::features();
+
+        if !flags.contains(EferFlags::LME) {
+            return Err(InvalidRegisterValue("EFER.LME must stay set in long mode"));
+        }
+
+        if flags.contains(EferFlags::NXE) && !features.nx {
+            return Err(InvalidRegisterValue("EFER.NXE without NX support"));
+        }
+
+        if flags.contains(EferFlags::SCE) && !features.syscall {
+            return Err(InvalidRegisterValue("EFER.SCE without SYSCALL support"));
+        }
+
+        unsafe { Msr::IA32_EFER.write(flags.bits()) };
+        Ok(())
+    }
+
+    /// Read-modify-write of EFER.
+    ///
+    /// # Safety
+    /// See [`Efer::write`].
+    #[inline]
+    pub unsafe fn update(f: impl FnOnce(&mut EferFlags)) -> Result<(), InvalidRegisterValue> {
+        let mut flags = Self::read();
+        f(&mut flags);
+        unsafe { Self::write(flags) }
+    }
+}
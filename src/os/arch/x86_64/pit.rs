@@ -0,0 +1,42 @@
+//! The 8254 programmable interval timer, as a fixed-rate reference for calibrating other
+//! timers.
+//!
+//! Only channel 2 is used: its gate and output are wired to port 0x61 rather than to an
+//! interrupt line, so it can be polled without touching the interrupt controllers.
+
+use super::port::{inb, outb};
+
+/// Input clock of the PIT, in Hz.
+pub const FREQUENCY: u64 = 1_193_182;
+
+/// Longest wait [`wait_us`] can measure, set by the 16-bit count.
+pub const MAX_WAIT_US: u32 = (0xffff * 1_000_000 / FREQUENCY) as u32;
+
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CONTROL: u16 = 0x61;
+
+// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count)
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+
+// Port 0x61 bits
+const GATE2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUT2: u8 = 1 << 5;
+
+/// Busy-waits for `us` microseconds, up to [`MAX_WAIT_US`], on channel 2.
+pub fn wait_us(us: u32) {
+    let count = (us.min(MAX_WAIT_US) as u64 * FREQUENCY / 1_000_000).max(1) as u16;
+
+    unsafe {
+        // Gate the channel on with the speaker off, then load the count, which starts it
+        outb(CONTROL, (inb(CONTROL) & !SPEAKER) | GATE2);
+        outb(COMMAND, CHANNEL2_ONE_SHOT);
+        outb(CHANNEL2, count as u8);
+        outb(CHANNEL2, (count >> 8) as u8);
+
+        while inb(CONTROL) & OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use core::arch::asm;
+
+/// Writes a single byte to the given I/O port.
+///
+/// # Safety
+/// Port I/O talks directly to hardware; the caller must know what lives at `port`.
+#[inline]
+pub unsafe fn outb(port: u16, value: u8) {
+    unsafe { asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Reads a single byte from the given I/O port.
+///
+/// # Safety
+/// Port I/O talks directly to hardware; the caller must know what lives at `port`.
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    unsafe { asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes a 16-bit word to the given I/O port.
+///
+/// # Safety
+/// Port I/O talks directly to hardware; the caller must know what lives at `port`.
+#[inline]
+pub unsafe fn outw(port: u16, value: u16) {
+    unsafe { asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Reads a 16-bit word from the given I/O port.
+///
+/// # Safety
+/// Port I/O talks directly to hardware; the caller must know what lives at `port`.
+#[inline]
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    unsafe { asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes a 32-bit doubleword to the given I/O port.
+///
+/// # Safety
+/// Port I/O talks directly to hardware; the caller must know what lives at `port`.
+#[inline]
+pub unsafe fn outl(port: u16, value: u32) {
+    unsafe { asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Reads a 32-bit doubleword from the given I/O port.
+///
+/// # Safety
+/// Port I/O talks directly to hardware; the caller must know what lives at `port`.
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    unsafe { asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags)) };
+    value
+}
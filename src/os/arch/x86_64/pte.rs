@@ -0,0 +1,99 @@
+// x86_64 page table entry flag bits shared by every paging level, and a walker for the
+// 4-level tables UEFI leaves the kernel with (CR4.LA57 is never set)
+
+/// Entries per table.
+pub const ENTRIES: usize = 512;
+
+/// The entry maps something.
+pub const PRESENT: u64 = 1 << 0;
+
+/// Writes are allowed through this entry.
+pub const WRITABLE: u64 = 1 << 1;
+
+/// Ring 3 may access the mapping.
+pub const USER: u64 = 1 << 2;
+
+/// Write-through caching.
+pub const WRITE_THROUGH: u64 = 1 << 3;
+
+/// Caching disabled (MMIO).
+pub const NO_CACHE: u64 = 1 << 4;
+
+/// Set by the CPU when the mapping is accessed.
+pub const ACCESSED: u64 = 1 << 5;
+
+/// Set by the CPU when the mapping is written.
+pub const DIRTY: u64 = 1 << 6;
+
+/// Maps a large page (2 MiB in a PD entry, 1 GiB in a PDPT entry).
+pub const HUGE_PAGE: u64 = 1 << 7;
+
+/// Not flushed from the TLB on CR3 reload.
+pub const GLOBAL: u64 = 1 << 8;
+
+/// Instruction fetches are forbidden (requires EFER.NXE).
+pub const NO_EXECUTE: u64 = 1 << 63;
+
+/// Bits of an entry holding the physical address of the frame or next-level table.
+pub const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The PT entry for the 4 KiB page containing `virt` in the tables at `root`, or `None` if a
+/// table on the way is missing or `virt` lies in a 2 MiB or 1 GiB page. The tables must be
+/// identity-mapped.
+pub fn leaf(root: u64, virt: u64) -> Option<*mut u64> {
+    walk(root, virt, 0, None)
+}
+
+/// Like [`leaf`], but creates missing tables in frames from `alloc`, which it zeroes.
+pub fn leaf_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+    walk(root, virt, 0, Some(alloc))
+}
+
+/// The PD entry for the 2 MiB region containing `virt` in the tables at `root`, or `None` if a
+/// table on the way is missing or `virt` lies in a 1 GiB page.
+pub fn huge(root: u64, virt: u64) -> Option<*mut u64> {
+    walk(root, virt, 1, None)
+}
+
+/// Like [`huge`], but creates missing tables in frames from `alloc`, which it zeroes.
+pub fn huge_or_create(root: u64, virt: u64, alloc: &mut dyn FnMut() -> Option<u64>) -> Option<*mut u64> {
+    walk(root, virt, 1, Some(alloc))
+}
+
+/// An entry pointing to the table at `phys`, leaving access control to the entries in it.
+pub fn table_entry(phys: u64) -> u64 {
+    phys | PRESENT | WRITABLE | USER
+}
+
+// Walks down to the entry at `last` (0 = PT, 1 = PD, ...) through present table entries,
+// creating missing ones with `alloc` if given
+fn walk(root: u64, virt: u64, last: u32, mut alloc: Option<&mut dyn FnMut() -> Option<u64>>) -> Option<*mut u64> {
+    let mut table = root;
+
+    for level in (last..4).rev() {
+        let index = (virt >> (12 + 9 * level)) as usize % ENTRIES;
+        let entry = unsafe { (table as *mut u64).add(index) };
+
+        if level == last {
+            return Some(entry);
+        }
+
+        let mut value = unsafe { *entry };
+        if value & PRESENT == 0 {
+            let new = alloc.as_mut()?()?;
+            unsafe {
+                (new as *mut u8).write_bytes(0, 4096);
+                value = table_entry(new);
+                *entry = value;
+            }
+        }
+
+        if value & HUGE_PAGE != 0 {
+            return None;
+        }
+
+        table = value & ADDRESS_MASK;
+    }
+
+    None
+}
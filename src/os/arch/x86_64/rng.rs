@@ -0,0 +1,30 @@
+use core::arch::asm;
+
+/// Reads RDRAND, retrying a few times on transient failure.
+pub fn rdrand() -> Option<u64> {
+    // The instruction may transiently fail; Intel recommends a small number of retries
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// Reads RDSEED, retrying a few times on transient failure.
+pub fn rdseed() -> Option<u64> {
+    for _ in 0..10 {
+        let (value, ok): (u64, u8);
+        unsafe { asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
@@ -0,0 +1,170 @@
+//! The CMOS real-time clock, as a wall clock of last resort.
+//!
+//! The RTC keeps the date and time across power-off, in registers behind an index port. It
+//! counts whole seconds in whatever format the firmware left it in (BCD or binary, 12- or
+//! 24-hour), usually in local time, which it cannot tell apart from UTC; it is taken as UTC.
+//! The registers change during the once-a-second update, so [`read`] waits for no update to
+//! be in progress and reads until two readings agree.
+
+use super::port::{inb, outb};
+use crate::os::acpi;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+// Setting the top bit of the index keeps NMIs masked while it is selected
+const NMI_DISABLE: u8 = 0x80;
+
+// Registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// Status A: an update is in progress. Status B: 24-hour clock, binary rather than BCD
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+
+// Hours register: PM in the 12-hour format
+const PM: u8 = 1 << 7;
+
+// Readings `read` takes before settling for the last
+const MAX_READS: usize = 8;
+
+/// A date and time as the RTC keeps it, to the second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// The registers as read, before decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        outb(INDEX, NMI_DISABLE | register);
+        inb(DATA)
+    }
+}
+
+// Reads the time registers once no update is in progress; `century` is the register the FADT
+// names, if any
+fn read_raw(century: Option<u8>) -> Raw {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    Raw {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century.map_or(0, read_register),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+// Decodes `raw` as status register B `status` describes it. Without a century register the
+// year is taken to be in 2000-2099. `None` if a field is out of range.
+fn decode(raw: Raw, status: u8) -> Option<RtcTime> {
+    let field = |value: u8| if status & BINARY != 0 { value } else { from_bcd(value) };
+
+    let mut hour = field(raw.hour & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 AM is midnight and 12 PM noon
+        hour %= 12;
+        if raw.hour & PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let century = match raw.century {
+        0 => 20,
+        century => field(century) as u16,
+    };
+    let time = RtcTime {
+        year: century * 100 + field(raw.year) as u16,
+        month: field(raw.month),
+        day: field(raw.day),
+        hour,
+        minute: field(raw.minute),
+        second: field(raw.second),
+    };
+
+    let valid = (1..=12).contains(&time.month) && (1..=31).contains(&time.day) && time.hour < 24 && time.minute < 60 && time.second < 60;
+    valid.then_some(time)
+}
+
+/// Reads the date and time. `None` if the RTC holds nonsense, as it does when it was never
+/// set or its battery is flat.
+pub fn read() -> Option<RtcTime> {
+    let century = acpi::fadt().map(|fadt| fadt.century).filter(|&register| register != 0);
+
+    let mut raw = read_raw(century);
+    for _ in 0..MAX_READS {
+        let again = read_raw(century);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    decode(raw, read_register(STATUS_B))
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn raw(second: u8, minute: u8, hour: u8, day: u8, month: u8, year: u8, century: u8) -> Raw {
+        Raw { second, minute, hour, day, month, year, century }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn bcd_and_binary_registers_decode_alike() {
+            let time = RtcTime { year: 2024, month: 2, day: 29, hour: 12, minute: 34, second: 56 };
+            assert_eq!(decode(raw(0x56, 0x34, 0x12, 0x29, 0x02, 0x24, 0x20), HOURS_24), Some(time));
+            assert_eq!(decode(raw(56, 34, 12, 29, 2, 24, 20), HOURS_24 | BINARY), Some(time));
+
+            // No century register: the 2000s
+            assert_eq!(decode(raw(0x56, 0x34, 0x12, 0x29, 0x02, 0x24, 0), HOURS_24).map(|time| time.year), Some(2024));
+        }
+
+        fn twelve_hour_clocks_are_converted() {
+            let hour = |register: u8| decode(raw(0, 0, register, 1, 1, 0x24, 0), 0).map(|time| time.hour);
+            assert_eq!(hour(0x12), Some(0));
+            assert_eq!(hour(0x01), Some(1));
+            assert_eq!(hour(PM | 0x12), Some(12));
+            assert_eq!(hour(PM | 0x11), Some(23));
+        }
+
+        fn nonsense_is_rejected() {
+            assert_eq!(decode(raw(0, 0, 0, 0, 1, 0x24, 0), HOURS_24), None);
+            assert_eq!(decode(raw(0, 0x60, 0, 1, 1, 0x24, 0), HOURS_24), None);
+            assert_eq!(decode(raw(0, 0, 0, 1, 13, 24, 0), HOURS_24 | BINARY), None);
+        }
+    }
+}
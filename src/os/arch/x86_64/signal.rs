@@ -0,0 +1,223 @@
+//! Running signal handlers in user mode.
+//!
+//! On the way back to user mode, from a syscall ([`deliver_on_syscall_return`]) or an interrupt
+//! or exception ([`deliver_on_trap_return`]), the next signal the process does not block is
+//! taken (see [`os::signal`](crate::os::signal)). For a handler, the interrupted registers and
+//! signal mask are saved as a [`SignalContext`] below the user stack pointer, past the 128-byte
+//! red zone, with the restorer's address pushed under it as the handler's return address; the
+//! return then goes to the handler, called as `handler(signal, 0, context)` with the stack
+//! aligned as for any call. When it returns into the restorer, that calls `rt_sigreturn`
+//! with the stack pointer at the context, and [`sigreturn`] resumes the interrupted code from
+//! it through `iretq`, every register as it was.
+//!
+//! Only the general-purpose registers are saved, so a handler that uses the FPU leaves its
+//! state behind for the code it interrupted.
+
+use core::mem::offset_of;
+
+use super::idt::TrapFrame;
+use super::syscall::{self, SyscallFrame};
+use super::user;
+use crate::os::kthread;
+use crate::os::percpu;
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::signal::{self, Action, SIGSEGV};
+use crate::os::uaccess::{self, USER_SPACE_END};
+
+// Bytes below the stack pointer the ABI lets leaf functions use without moving it
+const RED_ZONE: u64 = 128;
+
+// RFLAGS bits a context may change: CF, PF, AF, ZF, SF, TF, DF, OF and AC
+const USER_CHANGEABLE_FLAGS: u64 = 0x4_0dd5;
+
+// Trap and direction flags, which a handler starts with clear
+const TF_DF: u64 = (1 << 8) | (1 << 10);
+
+/// The state a handler interrupted, as saved on the user stack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SignalContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+
+    /// The signal mask to go back to.
+    pub mask: u64,
+}
+
+impl SignalContext {
+    pub const RAX: usize = offset_of!(SignalContext, rax);
+    pub const RBX: usize = offset_of!(SignalContext, rbx);
+    pub const RCX: usize = offset_of!(SignalContext, rcx);
+    pub const RDX: usize = offset_of!(SignalContext, rdx);
+    pub const RSI: usize = offset_of!(SignalContext, rsi);
+    pub const RDI: usize = offset_of!(SignalContext, rdi);
+    pub const RBP: usize = offset_of!(SignalContext, rbp);
+    pub const R8: usize = offset_of!(SignalContext, r8);
+    pub const R9: usize = offset_of!(SignalContext, r9);
+    pub const R10: usize = offset_of!(SignalContext, r10);
+    pub const R11: usize = offset_of!(SignalContext, r11);
+    pub const R12: usize = offset_of!(SignalContext, r12);
+    pub const R13: usize = offset_of!(SignalContext, r13);
+    pub const R14: usize = offset_of!(SignalContext, r14);
+    pub const R15: usize = offset_of!(SignalContext, r15);
+    pub const RIP: usize = offset_of!(SignalContext, rip);
+    pub const RSP: usize = offset_of!(SignalContext, rsp);
+    pub const RFLAGS: usize = offset_of!(SignalContext, rflags);
+}
+
+// Where a handler starts: the registers that change from the interrupted ones
+struct HandlerEntry {
+    rip: u64,
+    rsp: u64,
+    rdi: u64,
+    rdx: u64,
+}
+
+// Takes the running process's next signal and, for a handler, saves `context` on its user stack;
+// returns where the handler starts, or None to carry on as before. Terminates the process for a
+// signal whose action that is, and for a handler outside user memory or a stack the context
+// cannot be written to.
+fn take_signal(mut context: SignalContext) -> Option<HandlerEntry> {
+    let pid = percpu::current_pid();
+    let (signal, handler, restorer) = ptable::with_process(pid, |process: &mut Process| {
+        let (signal, action) = signal::next(process)?;
+        let Action::Handle { handler, restorer } = action else {
+            return Some((signal, 0, 0));
+        };
+
+        context.mask = process.signal_mask;
+        process.signal_mask |= 1 << signal;
+        Some((signal, handler, restorer))
+    })??;
+
+    if handler == 0 {
+        kthread::exit_current(signal::exit_status(signal));
+    }
+
+    let at = (context.rsp.wrapping_sub(RED_ZONE + size_of::<SignalContext>() as u64)) & !15;
+    let saved = uaccess::write_user(at as usize, &context).and_then(|()| uaccess::write_user(at as usize - 8, &(restorer as u64)));
+    if saved.is_err() || handler >= USER_SPACE_END {
+        log::warn!("x86_64: pid {}: cannot run the handler for signal {}, killed", pid, signal);
+        kthread::exit_current(signal::exit_status(SIGSEGV));
+    }
+
+    Some(HandlerEntry { rip: handler as u64, rsp: at - 8, rdi: signal as u64, rdx: at })
+}
+
+/// Delivers the running process's next signal as its syscall returns with `frame`.
+pub fn deliver_on_syscall_return(frame: &mut SyscallFrame) {
+    // sysret loads rcx and r11 from rip and rflags, so that is what the code sees in them
+    let context = SignalContext {
+        rax: frame.rax,
+        rbx: frame.rbx,
+        rcx: frame.rip,
+        rdx: frame.rdx,
+        rsi: frame.rsi,
+        rdi: frame.rdi,
+        rbp: frame.rbp,
+        r8: frame.r8,
+        r9: frame.r9,
+        r10: frame.r10,
+        r11: frame.rflags,
+        r12: frame.r12,
+        r13: frame.r13,
+        r14: frame.r14,
+        r15: frame.r15,
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+        mask: 0,
+    };
+
+    if let Some(entry) = take_signal(context) {
+        frame.rip = entry.rip;
+        frame.rsp = entry.rsp;
+        frame.rdi = entry.rdi;
+        frame.rsi = 0;
+        frame.rdx = entry.rdx;
+        frame.rflags &= !TF_DF;
+    }
+}
+
+/// Delivers the running process's next signal as an interrupt or exception returns to user
+/// mode with `frame`.
+pub fn deliver_on_trap_return(frame: &mut TrapFrame) {
+    let context = SignalContext {
+        rax: frame.rax,
+        rbx: frame.rbx,
+        rcx: frame.rcx,
+        rdx: frame.rdx,
+        rsi: frame.rsi,
+        rdi: frame.rdi,
+        rbp: frame.rbp,
+        r8: frame.r8,
+        r9: frame.r9,
+        r10: frame.r10,
+        r11: frame.r11,
+        r12: frame.r12,
+        r13: frame.r13,
+        r14: frame.r14,
+        r15: frame.r15,
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+        mask: 0,
+    };
+
+    if let Some(entry) = take_signal(context) {
+        frame.rip = entry.rip;
+        frame.rsp = entry.rsp;
+        frame.rdi = entry.rdi;
+        frame.rsi = 0;
+        frame.rdx = entry.rdx;
+        frame.rflags &= !TF_DF;
+    }
+}
+
+/// `rt_sigreturn()` for process `pid`, which is in the syscall: resumes the code a handler
+/// interrupted from the context at its stack pointer, restoring the signal mask saved with it.
+/// A context that is unreadable, or resumes outside user memory, kills the process.
+pub fn sigreturn(pid: u64) -> ! {
+    let at = ptable::with_process(pid, |process| unsafe { (*syscall::frame_of(process)).rsp })
+        .expect("x86_64: sigreturn without a caller");
+
+    let in_user = |context: &SignalContext| context.rip < USER_SPACE_END as u64 && context.rsp < USER_SPACE_END as u64;
+    let context = unsafe { uaccess::read_user::<SignalContext>(at as usize) }.ok().filter(in_user);
+    let Some(mut context) = context else {
+        log::warn!("x86_64: pid {}: bad signal context at {:#x}, killed", pid, at);
+        kthread::exit_current(signal::exit_status(SIGSEGV));
+    };
+
+    ptable::with_process(pid, |process| process.signal_mask = context.mask);
+    context.rflags = (context.rflags & USER_CHANGEABLE_FLAGS) | user::USER_FLAGS;
+    unsafe { user::resume(&context) }
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn contexts_are_laid_out_for_resume() {
+            assert_eq!(size_of::<SignalContext>(), 19 * 8);
+            assert_eq!((SignalContext::RAX, SignalContext::RIP, SignalContext::RFLAGS), (0, 15 * 8, 17 * 8));
+            assert_eq!(USER_CHANGEABLE_FLAGS & user::USER_FLAGS, 0);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Starting the application processors.
+//!
+//! The MADT lists the CPUs; every enabled one other than the boot CPU is started with the
+//! INIT, startup, startup IPI sequence, which sets it running in real mode at the page
+//! [`boot::reserve_ap_trampoline`] claimed. The trampoline below is copied there and, like the
+//! resume trampoline in [`wakeup`](super::wakeup), goes straight from real mode to long mode on
+//! the boot CPU's control registers and page tables, then calls [`ap_main`] on a stack of the
+//! CPU's own. There it loads the descriptor tables, enables its APIC and per-CPU area and
+//! settles into its idle loop, as the scheduler's idle process for that CPU.
+//!
+//! CPUs are started one at a time, each only once the previous one is online, since they
+//! share the trampoline's parameters. Application processors are left alone when the page
+//! tables are above 4 GiB, out of reach of the trampoline's 32-bit CR3 load, and when the FPU
+//! is switched lazily, which only works with a single CPU.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::control::{Cr0, Cr3, Cr4};
+use super::msr::Efer;
+use super::{apic, gdt, idt, pit, syscall};
+use crate::os::acpi;
+use crate::os::arch::{Arch, Current};
+use crate::os::boot;
+use crate::os::compaction;
+use crate::os::fpu;
+use crate::os::hrtimer;
+use crate::os::idle;
+use crate::os::kvm;
+use crate::os::memory::FRAME_SIZE;
+use crate::os::percpu::{self, MAX_CPUS};
+use crate::os::pid;
+use crate::os::process::{KERNEL_STACK_SIZE, Process, ProcessState};
+use crate::os::ptable;
+use crate::os::sched;
+use crate::os::timer;
+use crate::os::tlb;
+
+// The INIT IPI must be held for 10 ms before the startup IPIs, which are 200 us apart
+const INIT_DELAY_US: u32 = 10_000;
+const STARTUP_DELAY_US: u32 = 200;
+
+// How long a started CPU has to come online: polls of ONLINE_POLL_US each
+const ONLINE_POLLS: u32 = 1000;
+const ONLINE_POLL_US: u32 = 100;
+
+const STACK_FRAMES: u64 = (KERNEL_STACK_SIZE as u64).div_ceil(FRAME_SIZE);
+
+global_asm!(
+    ".section .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "jmp ap_real",
+    // Data first, so the offsets below are known when the real-mode code uses them
+    ".balign 8",
+    "ap_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    "ap_gdtr:",
+    ".word 23",
+    "ap_gdt_base:",
+    ".long 0",
+    ".balign 8",
+    "ap_cr0: .long 0",
+    "ap_cr3: .long 0",
+    "ap_cr4: .long 0",
+    ".balign 8",
+    "ap_efer: .quad 0",
+    "ap_stack: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_cpu: .quad 0",
+    ".set AP_GDTR, ap_gdtr - ap_trampoline_start",
+    ".set AP_CR0, ap_cr0 - ap_trampoline_start",
+    ".set AP_CR3, ap_cr3 - ap_trampoline_start",
+    ".set AP_CR4, ap_cr4 - ap_trampoline_start",
+    ".set AP_EFER, ap_efer - ap_trampoline_start",
+    "ap_real:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [AP_GDTR]",
+    "mov eax, dword ptr [AP_CR4]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [AP_CR3]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "mov eax, dword ptr [AP_EFER]",
+    "mov edx, dword ptr [AP_EFER + 4]",
+    "wrmsr",
+    // Setting PE and PG together enters long mode directly
+    "mov eax, dword ptr [AP_CR0]",
+    "mov cr0, eax",
+    // jmp far dword 0x08:ap_long (offset patched to the linear address)
+    ".byte 0x66, 0xea",
+    "ap_jump:",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    "ap_long:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, [rip + ap_stack]",
+    "mov rdi, [rip + ap_cpu]",
+    "mov rax, [rip + ap_entry]",
+    "and rsp, -16",
+    "call rax",
+    "ud2",
+    "ap_trampoline_end:",
+    ".text",
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_jump: u8;
+    static ap_long: u8;
+    static ap_gdt: u8;
+    static ap_gdt_base: u8;
+    static ap_cr0: u8;
+    static ap_cr3: u8;
+    static ap_cr4: u8;
+    static ap_efer: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+    static ap_cpu: u8;
+}
+
+// Whether the boot CPU's APIC is in x2APIC mode, which the others must match
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+// Idle process of the CPU being started
+static STARTING_PID: AtomicU64 = AtomicU64::new(0);
+
+// Linear address of trampoline symbol `symbol` once copied to `base`
+fn at(base: u64, symbol: *const u8) -> u64 {
+    base + (symbol as u64 - &raw const ap_trampoline_start as u64)
+}
+
+fn trampoline_len() -> usize {
+    &raw const ap_trampoline_end as usize - &raw const ap_trampoline_start as usize
+}
+
+// Copies the trampoline to `base` and patches in the boot CPU's control registers
+unsafe fn install_trampoline(base: u64) {
+    let (cr3, _) = Cr3::read();
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(&raw const ap_trampoline_start, base as *mut u8, trampoline_len());
+
+        let patch32 = |symbol: *const u8, value: u32| (at(base, symbol) as *mut u32).write_unaligned(value);
+        let patch64 = |symbol: *const u8, value: u64| (at(base, symbol) as *mut u64).write_unaligned(value);
+
+        patch32(&raw const ap_jump, at(base, &raw const ap_long) as u32);
+        patch32(&raw const ap_gdt_base, at(base, &raw const ap_gdt) as u32);
+        patch32(&raw const ap_cr0, Cr0::read().bits() as u32);
+        patch32(&raw const ap_cr3, cr3 as u32);
+        patch32(&raw const ap_cr4, Cr4::read().bits() as u32);
+
+        // EFER.LMA is set by the CPU itself once paging is on
+        patch64(&raw const ap_efer, Efer::read().bits() & !(1 << 10));
+        patch64(&raw const ap_entry, ap_main as usize as u64);
+    }
+}
+
+// Points the trampoline at CPU `cpu` and its stack
+unsafe fn set_parameters(base: u64, cpu: usize, stack_top: u64) {
+    unsafe {
+        (at(base, &raw const ap_stack) as *mut u64).write_unaligned(stack_top);
+        (at(base, &raw const ap_cpu) as *mut u64).write_unaligned(cpu as u64);
+    }
+}
+
+// Gives CPU `cpu` a stack and an idle process running on it, returning the idle PID and the
+// top of the stack
+fn create_idle(cpu: usize) -> Option<(u64, u64)> {
+    let pid = pid::alloc().ok()?;
+    let Some(stack) = compaction::alloc_contiguous(STACK_FRAMES, FRAME_SIZE) else {
+        pid::free(pid);
+        return None;
+    };
+
+    let mut process = Process::new(pid, 0, "idle");
+    process.state = ProcessState::Running;
+    process.kernel_stack = stack as usize;
+    let top = process.kernel_stack_top();
+
+    if ptable::insert(process).is_err() {
+        compaction::free_contiguous(stack, STACK_FRAMES);
+        pid::free(pid);
+        return None;
+    }
+
+    log::debug!("smp: CPU {} idles as PID {}", cpu, pid);
+    Some((pid, top))
+}
+
+// Sends INIT, startup, startup to `apic_id` and waits for CPU `cpu` to come online
+fn start(apic_id: u32, cpu: usize, page: u64) -> bool {
+    apic::send_init(apic_id);
+    pit::wait_us(INIT_DELAY_US);
+
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (page >> 12) as u8);
+        pit::wait_us(STARTUP_DELAY_US);
+    }
+
+    (0..ONLINE_POLLS).any(|_| {
+        if percpu::online_mask() & (1 << cpu) != 0 {
+            return true;
+        }
+        pit::wait_us(ONLINE_POLL_US);
+        false
+    })
+}
+
+/// Starts every enabled CPU in the MADT other than the boot CPU, up to [`MAX_CPUS`]. Called
+/// once by the boot CPU, after the kernel process is in the process table.
+pub fn start_aps() {
+    let Some(page) = boot::ap_trampoline() else {
+        return;
+    };
+    let Some(madt) = acpi::madt() else {
+        log::warn!("smp: no MADT, only the boot CPU runs");
+        return;
+    };
+    if fpu::is_lazy() {
+        log::warn!("smp: lazy FPU switching, only the boot CPU runs");
+        return;
+    }
+    if tlb::kernel_root() > u32::MAX as u64 {
+        log::warn!("smp: page tables above 4 GiB, only the boot CPU runs");
+        return;
+    }
+
+    X2APIC.store(apic::x2apic_enabled(), Ordering::Relaxed);
+    unsafe { install_trampoline(page) };
+
+    let this = apic::id();
+    let mut cpu = 1;
+    for local in madt.cpus().iter().filter(|local| local.enabled && local.apic_id != this) {
+        if cpu == MAX_CPUS {
+            log::warn!("smp: more than {} CPUs, the rest stay off", MAX_CPUS);
+            break;
+        }
+
+        let Some((pid, stack_top)) = create_idle(cpu) else {
+            log::warn!("smp: no memory to start CPU {}", cpu);
+            break;
+        };
+        STARTING_PID.store(pid, Ordering::Relaxed);
+        unsafe { set_parameters(page, cpu, stack_top) };
+
+        // One that comes up late must still find its own parameters, so stop here
+        if !start(local.apic_id, cpu, page) {
+            log::warn!("smp: APIC {} did not come online", local.apic_id);
+            break;
+        }
+        cpu += 1;
+    }
+
+    log::info!("smp: {} CPUs online", percpu::online_mask().count_ones());
+}
+
+// First code an application processor runs in long mode, on its idle process's stack
+extern "C" fn ap_main(cpu: usize) -> ! {
+    gdt::init_cpu(cpu);
+    idt::load();
+    apic::enable(X2APIC.load(Ordering::Relaxed));
+    percpu::init_cpu(cpu);
+
+    Current::init_memory_protection();
+    Current::init_user_access_protection();
+    fpu::init_cpu();
+    syscall::init_cpu();
+    super::init_cpu();
+    kvm::init_cpu();
+    tlb::note_active(Cr3::read().0);
+    sched::init_cpu(STARTING_PID.load(Ordering::Relaxed));
+
+    // Like the boot CPU's, minus the housekeeping that one does
+    loop {
+        sched::schedule();
+        idle::enter([timer::next_event_ns(), hrtimer::next_event_ns()].into_iter().flatten().min());
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn the_trampoline_fits_in_its_page() {
+            assert!(trampoline_len() <= FRAME_SIZE as usize);
+            assert!(at(0, &raw const ap_cpu) + 8 <= trampoline_len() as u64);
+        }
+
+        fn online_cpus_have_distinct_apic_ids() {
+            let online = percpu::online_mask();
+            let cpus = (0..MAX_CPUS).filter(|cpu| online & (1 << cpu) != 0);
+
+            for (index, cpu) in cpus.clone().enumerate() {
+                let id = percpu::hardware_id(cpu);
+                assert!(cpus.clone().skip(index + 1).all(|other| percpu::hardware_id(other) != id));
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! `syscall`/`sysret`: the way from user mode into the kernel for system calls.
+//!
+//! [`init_cpu`] enables the instructions and points them at [`syscall_entry`] with the GDT's
+//! selectors. User mode runs with the kernel's GS base swapped out, so the entry starts with
+//! `swapgs` to reach the per-CPU area, parks the user stack pointer there and moves to the top
+//! of the calling process's kernel stack, which the scheduler records on every switch (see
+//! [`percpu::set_kernel_stack_top`]). The user state goes into a [`SyscallFrame`] on that
+//! stack and the call to [`os::syscall::dispatch`](crate::os::syscall::dispatch), whose result
+//! returns in rax. Every other register but rcx and r11, which `syscall` clobbers, is handed
+//! back as it was, unless the call rewrote the frame ([`frame_of`]): `execve` starts the new
+//! image from it, a pending signal has it return into the handler (see
+//! [`signal`](super::signal)), and a forked child returns to user mode through a copy of it
+//! ([`return_to_user`]).
+//!
+//! The flag mask keeps interrupts off until the entry is on the kernel stack, and the exit
+//! masks them again before it leaves it; in between a call runs with them on, so it can block.
+
+use core::arch::naked_asm;
+
+use super::gdt::{KERNEL_CODE, USER_DATA};
+use super::interrupts;
+use super::signal;
+use super::msr::{Efer, EferFlags, Msr};
+use crate::os::kthread;
+use crate::os::percpu::{self, KERNEL_STACK_OFFSET, USER_RSP_OFFSET};
+use crate::os::process::Process;
+use crate::os::syscall;
+use crate::os::uaccess::USER_SPACE_END;
+
+// RFLAGS cleared on entry: TF, IF, DF, NT and AC
+const ENTRY_FLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 14) | (1 << 18);
+
+// Exit status of a process killed for returning to a non-canonical address (128 + SIGSEGV)
+const BAD_RETURN_STATUS: i32 = 128 + 11;
+
+/// The user state saved by [`syscall_entry`], lowest address first.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+
+    /// The syscall number on entry, the result on the way out.
+    pub rax: u64,
+
+    /// Where user mode resumes (`syscall` saves it in rcx).
+    pub rip: u64,
+
+    /// User RFLAGS (`syscall` saves them in r11).
+    pub rflags: u64,
+
+    pub rsp: u64,
+}
+
+/// Enables `syscall`/`sysret` on this CPU. `sysret` derives the user selectors from the base
+/// in STAR: SS from base + 8 and CS from base + 16, which is where the GDT has them.
+pub fn init_cpu() {
+    let user_base = (USER_DATA - 8) as u64;
+
+    unsafe {
+        Efer::update(|efer| efer.insert(EferFlags::SCE)).expect("x86_64: cannot enable syscall");
+        Msr::IA32_STAR.write((user_base << 48) | ((KERNEL_CODE as u64) << 32));
+        Msr::IA32_LSTAR.write(syscall_entry as *const () as u64);
+        Msr::IA32_FMASK.write(ENTRY_FLAGS_MASK);
+    }
+}
+
+/// The frame [`syscall_entry`] saves for a syscall `process` makes, at the top of its kernel
+/// stack; it holds the registers user mode gets back when the call returns.
+pub fn frame_of(process: &Process) -> *mut SyscallFrame {
+    (process.kernel_stack_top() as usize - size_of::<SyscallFrame>()) as *mut SyscallFrame
+}
+
+/// Returns to user mode with the registers in `frame`, as a syscall returns.
+///
+/// # Safety
+/// `frame` must be the running process's [`frame_of`], holding a user `rip` and `rsp`.
+#[unsafe(naked)]
+pub unsafe extern "sysv64" fn return_to_user(frame: *const SyscallFrame) -> ! {
+    naked_asm!("mov rsp, rdi", "jmp {exit}", exit = sym syscall_exit)
+}
+
+// Target of `syscall`. The frame is sixteen quadwords on a 16-byte aligned stack top, so the
+// call is aligned. rbx, rbp and r12-r15 are saved too, though dispatch preserves them, so the
+// frame holds the whole user state for `fork` to copy.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn syscall_entry() {
+    naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_rsp}]",
+        "push r11",
+        "push rcx",
+        "push rax",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r10",
+        "push r8",
+        "push r9",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "jmp {exit}",
+        user_rsp = const USER_RSP_OFFSET,
+        kernel_stack = const KERNEL_STACK_OFFSET,
+        dispatch = sym dispatch,
+        exit = sym syscall_exit,
+    )
+}
+
+// Restores the frame at the stack pointer and returns to user mode
+#[unsafe(naked)]
+unsafe extern "sysv64" fn syscall_exit() -> ! {
+    naked_asm!(
+        "cli",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rax",
+        "pop rcx",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+    )
+}
+
+// Runs the call the frame describes, with interrupts on, and stores its result in the frame,
+// then has the frame return into a handler if a signal is pending
+extern "sysv64" fn dispatch(frame: &mut SyscallFrame) {
+    interrupts::enable();
+
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = syscall::dispatch(frame.rax, args) as u64;
+    signal::deliver_on_syscall_return(frame);
+
+    // sysret to a non-canonical address faults in ring 0, on the user's stack
+    if frame.rip >= USER_SPACE_END as u64 {
+        log::warn!("x86_64: pid {} cannot return to {:#x}", percpu::current_pid(), frame.rip);
+        kthread::exit_current(BAD_RETURN_STATUS);
+    }
+}
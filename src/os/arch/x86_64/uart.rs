@@ -0,0 +1,50 @@
+//! 16550 UART on COM1, the early console on PC platforms.
+
+use super::port::{inb, outb};
+
+/// Base I/O port of the first serial port (COM1).
+const COM1: u16 = 0x3f8;
+
+// Register offsets from the UART base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+// Line status bit set when the transmit holding register can accept another byte
+const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+
+/// Programs COM1 for 38400 baud, 8 data bits, no parity, one stop bit, with FIFOs enabled.
+pub fn init() {
+    unsafe {
+        // Disable UART interrupts, we only ever poll
+        outb(COM1 + INTERRUPT_ENABLE, 0x00);
+
+        // Set the divisor latch access bit and program divisor 3 (115200 / 3 = 38400 baud)
+        outb(COM1 + LINE_CONTROL, 0x80);
+        outb(COM1 + DATA, 0x03);
+        outb(COM1 + INTERRUPT_ENABLE, 0x00);
+
+        // 8N1, divisor latch access bit cleared again
+        outb(COM1 + LINE_CONTROL, 0x03);
+
+        // Enable and clear the FIFOs with a 14-byte threshold
+        outb(COM1 + FIFO_CONTROL, 0xc7);
+
+        // Assert DTR/RTS and OUT2
+        outb(COM1 + MODEM_CONTROL, 0x0b);
+    }
+}
+
+/// Writes a single byte to COM1, spinning until the transmitter is ready.
+pub fn write_byte(byte: u8) {
+    unsafe {
+        while inb(COM1 + LINE_STATUS) & LINE_STATUS_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+
+        outb(COM1 + DATA, byte);
+    }
+}
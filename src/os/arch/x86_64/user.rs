@@ -0,0 +1,125 @@
+//! Entering user mode.
+//!
+//! A process starts on its kernel stack, in ring 0. [`enter_user`] leaves for ring 3 the way an
+//! interrupt from user mode would return: it builds the frame the CPU would have pushed, with
+//! the ring 3 selectors from the GDT, and `iretq`s through it. Every general-purpose register
+//! is cleared first so nothing of the kernel's leaks, and `swapgs` puts the per-CPU base away,
+//! which is how the syscall and interrupt entries expect to find it when user mode comes back.
+//!
+//! [`resume`] leaves the same way with every register given, to go back to code a signal
+//! handler interrupted.
+//!
+//! Once in ring 3 the process only gets back in through `syscall`, which moves to the top of
+//! its kernel stack, or an interrupt or exception, which lands there through the TSS; both
+//! are recorded on every switch (see [`percpu::set_kernel_stack_top`]).
+//!
+//! [`percpu::set_kernel_stack_top`]: crate::os::percpu::set_kernel_stack_top
+
+use core::arch::asm;
+
+use super::gdt::{USER_CODE, USER_DATA};
+use super::signal::SignalContext;
+
+/// RFLAGS a process starts with: interrupts on, and the reserved bit 1 that always reads as
+/// set.
+pub const USER_FLAGS: u64 = (1 << 9) | (1 << 1);
+
+/// Leaves the kernel for ring 3, running at `pc` on the stack at `sp` in the address space
+/// loaded on this CPU. The kernel stack is abandoned where it is.
+///
+/// # Safety
+/// `pc` and `sp` must be user addresses, and the running process's kernel stack must be the
+/// one recorded for this CPU, since that is where user mode re-enters the kernel.
+pub unsafe fn enter_user(pc: u64, sp: u64) -> ! {
+    unsafe {
+        asm!(
+            "cli",
+            "push {ss}",
+            "push rsi",
+            "push {flags}",
+            "push {cs}",
+            "push rdi",
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "swapgs",
+            "iretq",
+            ss = const USER_DATA as u64,
+            flags = const USER_FLAGS,
+            cs = const USER_CODE as u64,
+            in("rdi") pc,
+            in("rsi") sp,
+            options(noreturn),
+        )
+    }
+}
+
+/// Leaves the kernel for ring 3 with the registers in `context`, in the address space loaded
+/// on this CPU. The kernel stack is abandoned where it is.
+///
+/// # Safety
+/// As for [`enter_user`], with `context`'s `rip` and `rsp` for `pc` and `sp`; its `rflags`
+/// must have interrupts enabled and nothing set that user mode may not set itself.
+pub unsafe fn resume(context: &SignalContext) -> ! {
+    unsafe {
+        asm!(
+            "cli",
+            "push {ss}",
+            "push qword ptr [rdi + {rsp}]",
+            "push qword ptr [rdi + {rflags}]",
+            "push {cs}",
+            "push qword ptr [rdi + {rip}]",
+            "mov rax, [rdi + {rax}]",
+            "mov rbx, [rdi + {rbx}]",
+            "mov rcx, [rdi + {rcx}]",
+            "mov rdx, [rdi + {rdx}]",
+            "mov rsi, [rdi + {rsi}]",
+            "mov rbp, [rdi + {rbp}]",
+            "mov r8, [rdi + {r8}]",
+            "mov r9, [rdi + {r9}]",
+            "mov r10, [rdi + {r10}]",
+            "mov r11, [rdi + {r11}]",
+            "mov r12, [rdi + {r12}]",
+            "mov r13, [rdi + {r13}]",
+            "mov r14, [rdi + {r14}]",
+            "mov r15, [rdi + {r15}]",
+            "mov rdi, [rdi + {rdi}]",
+            "swapgs",
+            "iretq",
+            ss = const USER_DATA as u64,
+            cs = const USER_CODE as u64,
+            rax = const SignalContext::RAX,
+            rbx = const SignalContext::RBX,
+            rcx = const SignalContext::RCX,
+            rdx = const SignalContext::RDX,
+            rsi = const SignalContext::RSI,
+            rdi = const SignalContext::RDI,
+            rbp = const SignalContext::RBP,
+            r8 = const SignalContext::R8,
+            r9 = const SignalContext::R9,
+            r10 = const SignalContext::R10,
+            r11 = const SignalContext::R11,
+            r12 = const SignalContext::R12,
+            r13 = const SignalContext::R13,
+            r14 = const SignalContext::R14,
+            r15 = const SignalContext::R15,
+            rip = const SignalContext::RIP,
+            rsp = const SignalContext::RSP,
+            rflags = const SignalContext::RFLAGS,
+            in("rdi") context,
+            options(noreturn),
+        )
+    }
+}
@@ -0,0 +1,244 @@
+//! Real-mode resume trampoline and CPU context save for ACPI sleep states.
+//!
+//! On wake from S3 the firmware starts the CPU in real mode at the FACS waking vector. The
+//! trampoline below is copied to [`TRAMPOLINE_BASE`] before sleeping and goes straight from
+//! real mode to long mode: it loads a temporary GDT, restores CR4, CR3, EFER and CR0 from values
+//! patched in at suspend time, and jumps into [`save_and_sleep`], which reloads the kernel's
+//! GDT, IDT and segment registers and returns a second time, now reporting the resume.
+
+use core::arch::{global_asm, naked_asm};
+
+use super::control::{Cr0, Cr3, Cr4};
+use super::msr::Efer;
+
+/// Physical address the trampoline is copied to. It must be below 1 MiB, page-aligned and
+/// identity-mapped, and the frame allocator must never hand it out.
+pub const TRAMPOLINE_BASE: u64 = 0x8000;
+
+global_asm!(
+    ".section .text.wakeup, \"ax\"",
+    ".global wakeup_start",
+    ".global wakeup_end",
+    ".code16",
+    "wakeup_start:",
+    "jmp wakeup_real",
+    // Data first, so the offsets below are known when the real-mode code uses them
+    ".balign 8",
+    "wakeup_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    "wakeup_gdtr:",
+    ".word 23",
+    "wakeup_gdt_base:",
+    ".long 0",
+    ".balign 8",
+    "wakeup_cr0: .long 0",
+    "wakeup_cr3: .long 0",
+    "wakeup_cr4: .long 0",
+    ".balign 8",
+    "wakeup_efer: .quad 0",
+    "wakeup_context: .quad 0",
+    ".set WAKEUP_GDTR, wakeup_gdtr - wakeup_start",
+    ".set WAKEUP_CR0, wakeup_cr0 - wakeup_start",
+    ".set WAKEUP_CR3, wakeup_cr3 - wakeup_start",
+    ".set WAKEUP_CR4, wakeup_cr4 - wakeup_start",
+    ".set WAKEUP_EFER, wakeup_efer - wakeup_start",
+    "wakeup_real:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [WAKEUP_GDTR]",
+    "mov eax, dword ptr [WAKEUP_CR4]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [WAKEUP_CR3]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "mov eax, dword ptr [WAKEUP_EFER]",
+    "mov edx, dword ptr [WAKEUP_EFER + 4]",
+    "wrmsr",
+    // Setting PE and PG together enters long mode directly
+    "mov eax, dword ptr [WAKEUP_CR0]",
+    "mov cr0, eax",
+    // jmp far dword 0x08:wakeup_long (offset patched to the linear address)
+    ".byte 0x66, 0xea",
+    "wakeup_jump:",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    "wakeup_long:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    // Continue at the resume point saved by save_and_sleep, on the stack it was called on
+    "mov rax, [rip + wakeup_context]",
+    "mov rsp, [rax + 0x38]",
+    "jmp [rax + 0x30]",
+    "wakeup_end:",
+    ".text",
+);
+
+unsafe extern "C" {
+    static wakeup_start: u8;
+    static wakeup_end: u8;
+    static wakeup_jump: u8;
+    static wakeup_long: u8;
+    static wakeup_gdt: u8;
+    static wakeup_gdt_base: u8;
+    static wakeup_cr0: u8;
+    static wakeup_cr3: u8;
+    static wakeup_cr4: u8;
+    static wakeup_efer: u8;
+    static wakeup_context: u8;
+}
+
+/// Registers and descriptor tables saved by [`save_and_sleep`].
+#[repr(C)]
+struct SavedContext {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+    rsp: u64,
+    gdtr: [u8; 16],
+    idtr: [u8; 16],
+    cs: u64,
+    ss: u64,
+    ds: u64,
+}
+
+static mut CONTEXT: SavedContext = SavedContext {
+    rbx: 0,
+    rbp: 0,
+    r12: 0,
+    r13: 0,
+    r14: 0,
+    r15: 0,
+    rip: 0,
+    rsp: 0,
+    gdtr: [0; 16],
+    idtr: [0; 16],
+    cs: 0,
+    ss: 0,
+    ds: 0,
+};
+
+/// Reasons the trampoline cannot be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrampolineError {
+    /// The page tables live above 4 GiB, out of reach of the 32-bit CR3 load in real mode.
+    PageTablesTooHigh,
+}
+
+/// Copies the trampoline to [`TRAMPOLINE_BASE`] and patches in the current control registers.
+/// Returns the real-mode waking vector to store in the FACS.
+///
+/// # Safety
+/// [`TRAMPOLINE_BASE`] must be identity-mapped and not in use for anything else.
+pub unsafe fn install_trampoline() -> Result<u32, TrampolineError> {
+    let (cr3, pcid) = Cr3::read();
+    if cr3 > u32::MAX as u64 {
+        return Err(TrampolineError::PageTablesTooHigh);
+    }
+
+    unsafe {
+        let start = &raw const wakeup_start as usize;
+        let len = &raw const wakeup_end as usize - start;
+        let base = TRAMPOLINE_BASE as usize;
+
+        core::ptr::copy_nonoverlapping(start as *const u8, base as *mut u8, len);
+
+        // Linear address of a trampoline symbol once copied
+        let at = |symbol: *const u8| base + (symbol as usize - start);
+
+        let patch32 = |symbol: *const u8, value: u32| (at(symbol) as *mut u32).write_unaligned(value);
+        let patch64 = |symbol: *const u8, value: u64| (at(symbol) as *mut u64).write_unaligned(value);
+
+        patch32(&raw const wakeup_jump, at(&raw const wakeup_long) as u32);
+        patch32(&raw const wakeup_gdt_base, at(&raw const wakeup_gdt) as u32);
+        patch32(&raw const wakeup_cr0, Cr0::read().bits() as u32);
+        patch32(&raw const wakeup_cr3, (cr3 | pcid as u64) as u32);
+        patch32(&raw const wakeup_cr4, Cr4::read().bits() as u32);
+
+        // EFER.LMA is set by the CPU itself once paging is on
+        patch64(&raw const wakeup_efer, Efer::read().bits() & !(1 << 10));
+
+        patch64(&raw const wakeup_context, &raw const CONTEXT as u64);
+    }
+
+    Ok(TRAMPOLINE_BASE as u32)
+}
+
+/// Saves the callee-saved registers, descriptor tables and segment selectors, points the
+/// trampoline at the resume path and calls `sleep`. Returns `false` if `sleep` returned (the
+/// platform did not go to sleep) and `true` when execution comes back through the trampoline.
+///
+/// # Safety
+/// The trampoline must be installed. Everything not saved here (MSRs, FPU state, the local
+/// APIC, devices) is the caller's to save and restore.
+pub unsafe fn suspend(sleep: extern "sysv64" fn()) -> bool {
+    unsafe { save_and_sleep(sleep) != 0 }
+}
+
+#[unsafe(naked)]
+unsafe extern "sysv64" fn save_and_sleep(sleep: extern "sysv64" fn()) -> u64 {
+    naked_asm!(
+        "lea rax, [rip + {context}]",
+        "mov [rax + 0x00], rbx",
+        "mov [rax + 0x08], rbp",
+        "mov [rax + 0x10], r12",
+        "mov [rax + 0x18], r13",
+        "mov [rax + 0x20], r14",
+        "mov [rax + 0x28], r15",
+        "lea rcx, [rip + 2f]",
+        "mov [rax + 0x30], rcx",
+        // rsp still points at our return address here
+        "mov [rax + 0x38], rsp",
+        "sgdt [rax + 0x40]",
+        "sidt [rax + 0x50]",
+        "mov cx, cs",
+        "movzx rcx, cx",
+        "mov [rax + 0x60], rcx",
+        "mov cx, ss",
+        "movzx rcx, cx",
+        "mov [rax + 0x68], rcx",
+        "mov cx, ds",
+        "movzx rcx, cx",
+        "mov [rax + 0x70], rcx",
+        // Keep the stack 16-byte aligned for the call
+        "sub rsp, 8",
+        "call rdi",
+        "add rsp, 8",
+        "xor eax, eax",
+        "ret",
+        // Resumed from the trampoline with rsp restored and the temporary GDT loaded
+        "2:",
+        "lea rax, [rip + {context}]",
+        "lgdt [rax + 0x40]",
+        "lidt [rax + 0x50]",
+        "push qword ptr [rax + 0x60]",
+        "lea rcx, [rip + 3f]",
+        "push rcx",
+        "retfq",
+        "3:",
+        "mov rcx, [rax + 0x68]",
+        "mov ss, cx",
+        "mov rcx, [rax + 0x70]",
+        "mov ds, cx",
+        "mov es, cx",
+        "mov rbx, [rax + 0x00]",
+        "mov rbp, [rax + 0x08]",
+        "mov r12, [rax + 0x10]",
+        "mov r13, [rax + 0x18]",
+        "mov r14, [rax + 0x20]",
+        "mov r15, [rax + 0x28]",
+        "mov eax, 1",
+        "ret",
+        context = sym CONTEXT,
+    )
+}
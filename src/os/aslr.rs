@@ -0,0 +1,123 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::boot;
+use crate::os::random;
+
+/// Page granularity all randomized bases are aligned to.
+const PAGE_SIZE: u64 = 4096;
+
+/// Lowest load address for position-independent executables.
+pub const PIE_BASE: u64 = 0x0000_5555_0000_0000;
+
+/// Top of the user stack before randomization (just below the canonical hole).
+pub const STACK_TOP: u64 = 0x0000_7fff_ffff_f000;
+
+/// Highest start address of the mmap region before randomization.
+pub const MMAP_BASE: u64 = 0x0000_7f00_0000_0000;
+
+// Randomization ranges, in pages
+const PIE_RANDOM_PAGES: u64 = 1 << 28; // 1 TiB
+const STACK_RANDOM_PAGES: u64 = 1 << 22; // 16 GiB
+const MMAP_RANDOM_PAGES: u64 = 1 << 28; // 1 TiB
+const HEAP_RANDOM_PAGES: u64 = 1 << 13; // 32 MiB
+
+// Cleared by the `noaslr` boot flag
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Reads the per-boot switch: `noaslr` on the command line gives every process the same,
+/// deterministic layout, which makes debugging easier.
+pub fn init() {
+    let enabled = !boot::has_flag("noaslr");
+    ENABLED.store(enabled, Ordering::Relaxed);
+
+    if enabled && !random::is_seeded() {
+        log::warn!("aslr: CSPRNG is not seeded, layouts will be guessable");
+    }
+}
+
+/// Returns `true` if address space layouts are randomized.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Base addresses for a freshly exec'd process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Load bias added to every segment of a PIE executable (fixed-address executables ignore it).
+    pub load_base: u64,
+
+    /// Highest address of the user stack; the stack grows down from here.
+    pub stack_top: u64,
+
+    /// Top of the region `mmap` hands out addresses from (allocations grow down).
+    pub mmap_base: u64,
+
+    /// Offset added to the end of the loaded image to get the start of the heap.
+    pub heap_offset: u64,
+}
+
+/// Picks a new layout for an exec. Every exec gets an independent draw.
+pub fn randomize() -> Layout {
+    Layout {
+        load_base: PIE_BASE + random_pages(PIE_RANDOM_PAGES),
+        stack_top: STACK_TOP - random_pages(STACK_RANDOM_PAGES),
+        mmap_base: MMAP_BASE - random_pages(MMAP_RANDOM_PAGES),
+        heap_offset: random_pages(HEAP_RANDOM_PAGES),
+    }
+}
+
+/// Start of the heap for an image ending at `image_end`.
+pub fn heap_start(layout: &Layout, image_end: u64) -> u64 {
+    image_end.next_multiple_of(PAGE_SIZE) + layout.heap_offset
+}
+
+// A random page-aligned offset below `pages` pages, or 0 with ASLR off
+fn random_pages(pages: u64) -> u64 {
+    if enabled() {
+        random::below(pages) * PAGE_SIZE
+    } else {
+        0
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Runs `f` with randomization switched on or off, as the `noaslr` flag would
+    fn with_aslr<R>(enabled: bool, f: impl FnOnce() -> R) -> R {
+        let previous = ENABLED.swap(enabled, Ordering::Relaxed);
+        let result = f();
+        ENABLED.store(previous, Ordering::Relaxed);
+        result
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn offsets_are_page_aligned_and_in_range() {
+            for _ in 0..64 {
+                let layout = with_aslr(true, randomize);
+
+                for base in [layout.load_base, layout.stack_top, layout.mmap_base, layout.heap_offset] {
+                    assert!(base.is_multiple_of(PAGE_SIZE));
+                }
+                assert!((PIE_BASE..PIE_BASE + PIE_RANDOM_PAGES * PAGE_SIZE).contains(&layout.load_base));
+                assert!((STACK_TOP - (STACK_RANDOM_PAGES - 1) * PAGE_SIZE..=STACK_TOP).contains(&layout.stack_top));
+                assert!((MMAP_BASE - (MMAP_RANDOM_PAGES - 1) * PAGE_SIZE..=MMAP_BASE).contains(&layout.mmap_base));
+                assert!(layout.heap_offset < HEAP_RANDOM_PAGES * PAGE_SIZE);
+            }
+        }
+
+        fn noaslr_gives_the_fixed_bases() {
+            let fixed = Layout { load_base: PIE_BASE, stack_top: STACK_TOP, mmap_base: MMAP_BASE, heap_offset: 0 };
+            assert_eq!(with_aslr(false, randomize), fixed);
+            assert_eq!(with_aslr(false, randomize), fixed);
+        }
+
+        fn heap_starts_on_the_page_after_the_image() {
+            let layout = Layout { load_base: PIE_BASE, stack_top: STACK_TOP, mmap_base: MMAP_BASE, heap_offset: 3 * PAGE_SIZE };
+
+            assert_eq!(heap_start(&layout, 0x40_0000), 0x40_0000 + 3 * PAGE_SIZE);
+            assert_eq!(heap_start(&layout, 0x40_0001), 0x40_1000 + 3 * PAGE_SIZE);
+            assert_eq!(heap_start(&layout, 0x40_0fff), 0x40_1000 + 3 * PAGE_SIZE);
+        }
+    }
+}
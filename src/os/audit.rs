@@ -0,0 +1,311 @@
+//! Security audit log.
+//!
+//! Security-relevant events (denied permission checks, UID/GID transitions, denied syscalls,
+//! module loads) are appended to a fixed-size ring of [`AuditRecord`]s. Records are never
+//! modified once written: each carries a sequence number and a MAC over its contents and the
+//! previous record's MAC, keyed with a per-boot secret, so [`verify`] detects records that were
+//! altered, reordered or removed from the middle of the log. When the ring wraps the oldest
+//! records are overwritten; the gap shows up as a jump in sequence numbers.
+
+use crate::os::arch::counter;
+use crate::os::capability::{self, Capability};
+use crate::os::cred::Credentials;
+use crate::os::errno::KResult;
+use crate::os::random;
+use crate::os::uaccess;
+
+/// Number of records kept before the oldest are overwritten.
+pub const AUDIT_RING_SIZE: usize = 256;
+
+/// PID recorded for events raised outside any process context.
+pub const NO_PID: u64 = u64::MAX;
+
+/// Kinds of audited events. The meaning of [`AuditRecord::args`] depends on the kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum AuditKind {
+    /// A file access check failed; args: `[owner << 32 | group, mode << 16 | wanted access]`.
+    FileAccessDenied = 1,
+
+    /// A privileged operation was refused for lack of a capability; args: `[capability, 0]`.
+    CapabilityDenied = 2,
+
+    /// A signal was refused by the `kill(2)` rules; args: `[target uid, 0]`.
+    SignalDenied = 3,
+
+    /// The effective user ID changed; args: `[old euid, new euid]`.
+    SetUid = 4,
+
+    /// The effective group ID changed; args: `[old egid, new egid]`.
+    SetGid = 5,
+
+    /// The syscall filter refused a call; args: `[syscall number, 1 if the process is killed]`.
+    SyscallDenied = 6,
+
+    /// A kernel module was loaded; args: `[load address, size]`.
+    ModuleLoad = 7,
+
+    /// A security module vetoed an operation; args: `[errno, 0]`.
+    SecurityModuleDenied = 8,
+}
+
+/// One audit log entry, laid out for copying to user space as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct AuditRecord {
+    /// Position in the log, starting at 1 and never reused within a boot.
+    pub seq: u64,
+
+    /// Architecture counter value when the event was recorded.
+    pub timestamp: u64,
+
+    /// Process that caused the event, or [`NO_PID`].
+    pub pid: u64,
+
+    /// Effective user ID of that process.
+    pub uid: u32,
+
+    /// An [`AuditKind`] value.
+    pub kind: u32,
+
+    pub args: [u64; 2],
+
+    /// MAC over this record and the previous record's `mac`.
+    pub mac: u64,
+}
+
+impl AuditRecord {
+    const EMPTY: AuditRecord = AuditRecord { seq: 0, timestamp: 0, pid: 0, uid: 0, kind: 0, args: [0; 2], mac: 0 };
+}
+
+static mut RING: [AuditRecord; AUDIT_RING_SIZE] = [AuditRecord::EMPTY; AUDIT_RING_SIZE];
+
+// Sequence number of the most recent record (0 = log empty)
+static mut LAST_SEQ: u64 = 0;
+
+// MAC of the most recent record, chained into the next one
+static mut LAST_MAC: u64 = 0;
+
+// Per-boot MAC key, drawn from the CSPRNG on first use
+static mut KEY: Option<[u64; 2]> = None;
+
+fn key() -> [u64; 2] {
+    unsafe {
+        let key = &raw mut KEY;
+        *(*key).get_or_insert_with(|| [random::next_u64(), random::next_u64()])
+    }
+}
+
+/// Appends an event to the log.
+pub fn record(kind: AuditKind, pid: u64, uid: u32, args: [u64; 2]) {
+    let key = key();
+
+    unsafe {
+        let ring = &raw mut RING;
+        let last_seq = &raw mut LAST_SEQ;
+        let last_mac = &raw mut LAST_MAC;
+
+        let mut entry = AuditRecord {
+            seq: *last_seq + 1,
+            timestamp: counter::read(),
+            pid,
+            uid,
+            kind: kind as u32,
+            args,
+            mac: 0,
+        };
+        entry.mac = mac(&key, &entry, *last_mac);
+
+        (*ring)[(entry.seq as usize - 1) % AUDIT_RING_SIZE] = entry;
+        *last_seq = entry.seq;
+        *last_mac = entry.mac;
+    }
+}
+
+/// Convenience wrapper for events raised by a credential check.
+pub fn record_cred(kind: AuditKind, pid: u64, cred: &Credentials, args: [u64; 2]) {
+    record(kind, pid, cred.euid, args);
+}
+
+/// Copies records with a sequence number of at least `from` into `out`, oldest first.
+/// Returns the number copied.
+pub fn read(from: u64, out: &mut [AuditRecord]) -> usize {
+    let (first, last) = bounds();
+    let mut copied = 0;
+
+    for seq in from.max(first)..=last {
+        if copied == out.len() {
+            break;
+        }
+
+        out[copied] = unsafe {
+            let ring = &raw const RING;
+            (*ring)[(seq as usize - 1) % AUDIT_RING_SIZE]
+        };
+        copied += 1;
+    }
+
+    copied
+}
+
+// Sequence numbers of the oldest and newest records still in the ring (first > last if empty)
+fn bounds() -> (u64, u64) {
+    let last = unsafe { LAST_SEQ };
+    let first = last.saturating_sub(AUDIT_RING_SIZE as u64 - 1).max(1);
+    (first, last)
+}
+
+/// Recomputes the MAC chain over the records still in the ring. Returns the sequence number of
+/// the first record that does not check out, or `None` if the log is intact.
+pub fn verify() -> Option<u64> {
+    let key = key();
+    let (first, last) = bounds();
+
+    // The first record of the boot chains from 0; once the ring wraps the oldest surviving
+    // record's predecessor is gone and its own MAC cannot be checked
+    let mut previous = if first == 1 { Some(0) } else { None };
+
+    for seq in first..=last {
+        let entry = unsafe {
+            let ring = &raw const RING;
+            (*ring)[(seq as usize - 1) % AUDIT_RING_SIZE]
+        };
+
+        if entry.seq != seq {
+            return Some(seq);
+        }
+
+        if let Some(previous) = previous
+            && mac(&key, &entry, previous) != entry.mac
+        {
+            return Some(seq);
+        }
+
+        previous = Some(entry.mac);
+    }
+
+    None
+}
+
+/// Audit retrieval syscall: copies up to `count` records starting at sequence number `from` to
+/// `buf` in user memory and returns how many were written. Requires `CAP_AUDIT_READ`.
+pub fn sys_audit_read(cred: &Credentials, from: u64, buf: usize, count: usize) -> KResult<usize> {
+    capability::require(cred, Capability::AuditRead)?;
+
+    let mut batch = [AuditRecord::EMPTY; 16];
+    let mut written = 0;
+
+    while written < count {
+        let want = (count - written).min(batch.len());
+        let got = read(from + written as u64, &mut batch[..want]);
+
+        for (i, entry) in batch[..got].iter().enumerate() {
+            uaccess::write_user(buf + (written + i) * size_of::<AuditRecord>(), entry)?;
+        }
+
+        written += got;
+
+        if got < want {
+            break;
+        }
+    }
+
+    Ok(written)
+}
+
+// =========================================================================
+// SipHash-2-4
+// =========================================================================
+
+fn mac(key: &[u64; 2], entry: &AuditRecord, previous: u64) -> u64 {
+    let words = [
+        previous,
+        entry.seq,
+        entry.timestamp,
+        entry.pid,
+        (entry.uid as u64) << 32 | entry.kind as u64,
+        entry.args[0],
+        entry.args[1],
+    ];
+
+    siphash(key, &words)
+}
+
+fn siphash(key: &[u64; 2], words: &[u64]) -> u64 {
+    let mut v = [
+        key[0] ^ 0x736f_6d65_7073_6575,
+        key[1] ^ 0x646f_7261_6e64_6f6d,
+        key[0] ^ 0x6c79_6765_6e65_7261,
+        key[1] ^ 0x7465_6462_7974_6573,
+    ];
+
+    for &m in words {
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    // Final block: message length in bytes in the top byte
+    let last = ((words.len() * 8) as u64) << 56;
+    v[3] ^= last;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= last;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn records_chain_and_verify() {
+            let start = unsafe { LAST_SEQ };
+
+            record(AuditKind::SetUid, 1, 0, [0, 1000]);
+            record(AuditKind::SyscallDenied, 1, 1000, [59, 0]);
+
+            let mut out = [AuditRecord::EMPTY; 2];
+            assert_eq!(read(start + 1, &mut out), 2);
+            assert_eq!(out[0].seq, start + 1);
+            assert_eq!(out[1].kind, AuditKind::SyscallDenied as u32);
+            assert_eq!(verify(), None);
+        }
+
+        fn tampering_is_detected() {
+            record(AuditKind::ModuleLoad, NO_PID, 0, [0x1000, 0x2000]);
+            record(AuditKind::ModuleLoad, NO_PID, 0, [0x3000, 0x2000]);
+
+            let last = unsafe { LAST_SEQ };
+            let slot = (last as usize - 2) % AUDIT_RING_SIZE;
+
+            unsafe {
+                let ring = &raw mut RING;
+                (*ring)[slot].args[0] ^= 1;
+                assert_eq!(verify(), Some(last - 1));
+                (*ring)[slot].args[0] ^= 1;
+            }
+
+            assert_eq!(verify(), None);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Block devices: storage read and written in page-sized blocks.
+//!
+//! Drivers implement [`BlockDevice`]; users such as swap take a `&'static dyn BlockDevice`
+//! and never see which driver is behind it.
+
+use crate::os::errno::KResult;
+use crate::os::memory::FRAME_SIZE;
+
+/// Size of a block, the unit of every transfer. One page, so a block holds exactly one frame.
+pub const BLOCK_SIZE: usize = FRAME_SIZE as usize;
+
+/// A device addressed in [`BLOCK_SIZE`] blocks.
+pub trait BlockDevice: Sync {
+    /// Short name for logs (`zram0`, `vda`, ...).
+    fn name(&self) -> &str;
+
+    /// Number of blocks on the device.
+    fn blocks(&self) -> u64;
+
+    /// Reads block `index` into `buf`.
+    fn read_block(&self, index: u64, buf: &mut [u8; BLOCK_SIZE]) -> KResult<()>;
+
+    /// Writes `data` to block `index`.
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> KResult<()>;
+
+    /// Tells the device the contents of block `index` are no longer needed. Optional; the
+    /// default does nothing.
+    fn discard_block(&self, _index: u64) -> KResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use uefi::prelude::*;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+use uefi::table::boot::{AllocateType, MemoryType};
+use uefi::CStr16;
+
+use crate::os::arch::{Arch, Current, counter};
+
+/// The mode the kernel was asked to boot into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// Regular boot into the kernel proper.
+    Normal,
+
+    /// Run the in-kernel self-test suite, report the result and exit QEMU.
+    SelfTest,
+}
+
+// Maximum number of command line bytes we keep after boot (anything longer is truncated)
+const CMDLINE_MAX: usize = 256;
+
+// Static buffer holding the ASCII command line copied out of the UEFI load options
+static mut CMDLINE: [u8; CMDLINE_MAX] = [0; CMDLINE_MAX];
+
+// Number of valid bytes stored in CMDLINE
+static mut CMDLINE_LEN: usize = 0;
+
+// Physical range [start, end) the firmware loaded the kernel image into
+static mut IMAGE_EXTENT: (u64, u64) = (0, 0);
+
+// Physical range [start, start + len) the initrd was read into
+static mut INITRD_EXTENT: (u64, u64) = (0, 0);
+
+// Page below 1 MiB application processors start in (0 = none)
+static mut AP_TRAMPOLINE: u64 = 0;
+
+// Highest address a startup IPI can start a CPU at: the page number is 8 bits
+const AP_TRAMPOLINE_LIMIT: u64 = 0xf_ffff;
+
+// Where on the boot volume the initrd is looked for unless `initrd=` says otherwise
+const DEFAULT_INITRD: &str = "\\initrd.tar";
+
+// Longest initrd path kept, in UCS-2 characters
+const INITRD_PATH_MAX: usize = 64;
+
+/// Copies the image load options (the "command line" passed by the UEFI shell or boot entry)
+/// into kernel-owned storage so they stay available for the lifetime of the kernel.
+///
+/// Non-ASCII characters are dropped; missing or malformed load options leave the command line empty.
+/// The image's own load address is recorded along the way (see [`image_extent`]).
+pub fn store_command_line(image: Handle, system_table: &SystemTable<Boot>) {
+    let bt = system_table.boot_services();
+
+    // Open the LoadedImage protocol on our own image handle to get at the load options
+    let Ok(loaded_image) = bt.open_protocol_exclusive::<LoadedImage>(image) else {
+        return;
+    };
+
+    // Where the image itself sits, so its frames are never handed out as free memory
+    let (base, size) = loaded_image.info();
+    unsafe { IMAGE_EXTENT = (base as u64, base as u64 + size) };
+
+    let Ok(options) = loaded_image.load_options_as_cstr16() else {
+        return;
+    };
+
+    unsafe {
+        let mut len = 0;
+
+        for c in options.iter() {
+            let c = u16::from(*c);
+
+            // Only keep printable ASCII; UCS-2 beyond that has no meaning for our options
+            if (0x20..0x7f).contains(&c) && len < CMDLINE_MAX {
+                CMDLINE[len] = c as u8;
+                len += 1;
+            }
+        }
+
+        CMDLINE_LEN = len;
+    }
+}
+
+/// Returns the physical range `(start, end)` the kernel image was loaded into, empty if the
+/// firmware did not say.
+pub fn image_extent() -> (u64, u64) {
+    unsafe { IMAGE_EXTENT }
+}
+
+/// Reads the initrd from the volume the kernel image was loaded from into pages of its own:
+/// the file `initrd=` names, with `\` between directories, or `\initrd.tar`. The pages are
+/// loader data, which stays out of the usable memory regions, and are recorded so nothing
+/// else claims them (see [`initrd_extent`]). A missing initrd is not an error; a failed read
+/// is logged and leaves none.
+pub fn load_initrd(image: Handle, system_table: &SystemTable<Boot>) {
+    let bt = system_table.boot_services();
+
+    let mut buf = [0u16; INITRD_PATH_MAX];
+    let Ok(path) = CStr16::from_str_with_buf(option("initrd").unwrap_or(DEFAULT_INITRD), &mut buf) else {
+        log::warn!("boot: initrd path too long");
+        return;
+    };
+
+    let Ok(mut fs) = bt.get_image_file_system(image) else {
+        return;
+    };
+    let Some(mut file) = fs
+        .open_volume()
+        .and_then(|mut root| root.open(path, FileMode::Read, FileAttribute::empty()))
+        .ok()
+        .and_then(|file| file.into_regular_file())
+    else {
+        return;
+    };
+
+    let read = (|| {
+        file.set_position(RegularFile::END_OF_FILE).ok()?;
+        let size = file.get_position().ok()?;
+        file.set_position(0).ok()?;
+
+        let pages = size.div_ceil(4096) as usize;
+        let start = bt.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages.max(1)).ok()?;
+        let data = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, size as usize) };
+
+        let mut done = 0;
+        while done < data.len() {
+            match file.read(&mut data[done..]) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => done += n,
+            }
+        }
+        Some((start, size))
+    })();
+
+    match read {
+        Some(extent) => unsafe { INITRD_EXTENT = extent },
+        None => log::warn!("boot: cannot read the initrd"),
+    }
+}
+
+/// Claims a page below 1 MiB for application processors to start in: a startup IPI can only
+/// point a CPU at such a page, in real mode. Like the initrd's, the page is loader data, which
+/// stays out of the usable memory regions. Without one only the boot CPU runs.
+pub fn reserve_ap_trampoline(system_table: &SystemTable<Boot>) {
+    let bt = system_table.boot_services();
+
+    match bt.allocate_pages(AllocateType::MaxAddress(AP_TRAMPOLINE_LIMIT), MemoryType::LOADER_DATA, 1) {
+        Ok(page) => unsafe { AP_TRAMPOLINE = page },
+        Err(_) => log::warn!("boot: no page below 1 MiB for starting application processors"),
+    }
+}
+
+/// Returns the page [`reserve_ap_trampoline`] claimed, if any.
+pub fn ap_trampoline() -> Option<u64> {
+    match unsafe { AP_TRAMPOLINE } {
+        0 => None,
+        page => Some(page),
+    }
+}
+
+/// Returns the physical address and length of the initrd [`load_initrd`] read, `(0, 0)` if
+/// there is none.
+pub fn initrd_extent() -> (u64, u64) {
+    unsafe { INITRD_EXTENT }
+}
+
+/// Returns the stored kernel command line (empty if none was provided).
+pub fn command_line() -> &'static str {
+    unsafe {
+        // Only printable ASCII is ever stored, so this cannot fail
+        core::str::from_utf8(&CMDLINE[..CMDLINE_LEN]).unwrap_or("")
+    }
+}
+
+/// Returns `true` if `flag` appears as a whitespace separated word on the command line.
+pub fn has_flag(flag: &str) -> bool {
+    command_line().split_ascii_whitespace().any(|word| word == flag)
+}
+
+/// Returns the value of the first `key=value` option on the command line, if present.
+pub fn option(key: &str) -> Option<&'static str> {
+    command_line()
+        .split_ascii_whitespace()
+        .filter_map(|word| word.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// Determines the boot mode from the `selftest` Cargo feature or the `selftest` command line flag.
+pub fn boot_mode() -> BootMode {
+    if cfg!(feature = "selftest") || has_flag("selftest") {
+        BootMode::SelfTest
+    } else {
+        BootMode::Normal
+    }
+}
+
+/// Determines the architecture counter's rate: from the hardware if it reports one, otherwise
+/// by measuring it against the firmware's stall service (10 ms sample).
+pub fn calibrate_counter(system_table: &SystemTable<Boot>) {
+    crate::trace_fn!();
+
+    if let Some(hz) = Current::counter_frequency() {
+        counter::set_ticks_per_ms(hz / 1000);
+        return;
+    }
+
+    let start = counter::read();
+    system_table.boot_services().stall(10_000);
+    let ticks = counter::read() - start;
+
+    counter::set_ticks_per_ms(ticks / 10);
+
+    // A counter that follows frequency scaling (a TSC that is not invariant) drifts from the calibration
+    if !Current::counter_is_stable() {
+        log::warn!("boot: {} is not invariant, timings may drift", Current::COUNTER_NAME);
+    }
+}
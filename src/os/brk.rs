@@ -0,0 +1,169 @@
+//! The program break: `brk` and `sbrk`.
+//!
+//! A process's heap runs from `heap_base`, a little above its image, up to the program break
+//! at `heap_base + heap_size`; exec starts it empty. Raising the break only makes the pages
+//! below it legal to touch -- each is paged in on first use (see
+//! [`fault`](crate::os::fault)) -- so a heap costs nothing until it is used. Raising it is
+//! refused when the heap would run into another area of the address space (see [`vma`]) or
+//! past the address space and commit limits. Lowering it frees the pages past the new break,
+//! and the swap slots of any paged out.
+
+use crate::os::arch::{Arch, Current};
+use crate::os::cgroup::{self, PageKind};
+use crate::os::cow;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::process::Process;
+use crate::os::rlimit;
+use crate::os::swap::{self, SwapEntry};
+use crate::os::thp::{self, HUGE_PAGE_SIZE};
+use crate::os::tlb;
+use crate::os::uaccess;
+use crate::os::vma::{self, VmaKind};
+
+// Valid bit of a leaf entry, bit 0 on every port
+const VALID: u64 = 1 << 0;
+
+/// The program break of `process`.
+pub fn current(process: &Process) -> u64 {
+    (process.heap_base + process.heap_size) as u64
+}
+
+// Frees the heap pages in `[start, end)`, which are page aligned, once no CPU can reach them
+fn release_pages(process: &Process, start: u64, end: u64) -> KResult<()> {
+    let root = process.page_table_root as u64;
+    if root == 0 || start == end {
+        return Ok(());
+    }
+
+    for base in ((start & !(HUGE_PAGE_SIZE - 1))..end).step_by(HUGE_PAGE_SIZE as usize) {
+        thp::split(process, base)?;
+    }
+
+    let pages = (end - start) / FRAME_SIZE;
+    let entries = || (0..pages).map(move |i| start + i * FRAME_SIZE).filter_map(move |virt| Current::leaf_entry(root, virt));
+
+    // Clearing only the valid bit keeps the frame address for after the flush
+    let mut mapped = false;
+    for pte in entries() {
+        let entry = unsafe { *pte };
+        if entry & VALID != 0 {
+            unsafe { *pte = entry & !VALID };
+            mapped = true;
+        } else if let Some(slot) = SwapEntry::from_pte(entry) {
+            swap::free_slot(slot);
+            unsafe { *pte = 0 };
+        }
+    }
+    if mapped {
+        tlb::shootdown(Some(root), start, pages);
+    }
+
+    for pte in entries() {
+        let entry = unsafe { *pte };
+        if entry != 0 {
+            cow::release_frame(Current::entry_address(entry));
+            cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+            unsafe { *pte = 0 };
+        }
+    }
+    Ok(())
+}
+
+/// Moves `process`'s program break to `new`. Fails with `EINVAL` for a break below the heap's
+/// base or outside user space, and `ENOMEM` if the heap would overlap another area or exceed
+/// `RLIMIT_AS` or the commit limit.
+pub fn set_break(process: &mut Process, new: u64) -> KResult<()> {
+    let base = process.heap_base as u64;
+    if new < base || !uaccess::is_user_range(base as usize, (new - base) as usize) {
+        return Err(Errno::EINVAL);
+    }
+
+    let (old_end, new_end) = (current(process).next_multiple_of(FRAME_SIZE), new.next_multiple_of(FRAME_SIZE));
+    if new_end > old_end {
+        let growth = new_end - old_end;
+        if !vma::is_free(process, old_end, new_end, |area| area.kind == VmaKind::Heap) {
+            return Err(Errno::ENOMEM);
+        }
+        rlimit::check_address_space(process, growth as usize)?;
+        memory::check_commit(growth)?;
+    } else {
+        release_pages(process, new_end, old_end)?;
+    }
+
+    process.heap_size = (new - base) as usize;
+    Ok(())
+}
+
+/// `sbrk(increment)`: moves `process`'s program break by `increment` bytes and returns the
+/// old break. Fails as [`set_break`] does.
+pub fn sbrk(process: &mut Process, increment: i64) -> KResult<u64> {
+    let old = current(process);
+    let new = old.checked_add_signed(increment).ok_or(Errno::ENOMEM)?;
+    set_break(process, new)?;
+    Ok(old)
+}
+
+/// `brk(addr)`: moves `process`'s program break to `addr`, and returns the break -- the new
+/// one, or the old one if it could not be moved. `addr` 0 just asks for the break.
+pub fn sys_brk(process: &mut Process, addr: usize) -> u64 {
+    if addr != 0 {
+        let _ = set_break(process, addr as u64);
+    }
+    current(process)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::fault;
+    use crate::os::paging::{self, USER_START};
+    use crate::os::vma::Access;
+
+    crate::os::ktest::kernel_test! {
+        fn the_break_moves_within_limits() {
+            let mut process = Process::new(9360, 0, "brk");
+            process.heap_base = (USER_START + 0x1000) as usize;
+            process.stack_base = (USER_START + 0x40_0000) as usize;
+            process.stack_size = 0x10_0000;
+            let base = process.heap_base as u64;
+
+            assert_eq!(sys_brk(&mut process, 0), base);
+            assert_eq!(sbrk(&mut process, 0x2800), Ok(base));
+            assert_eq!(current(&process), base + 0x2800);
+            assert_eq!(sbrk(&mut process, -0x800), Ok(base + 0x2800));
+            assert_eq!(sys_brk(&mut process, (base + 0x1000) as usize), base + 0x1000);
+
+            // Not below the base, and not into the stack's guard; a refused move keeps the break
+            assert_eq!(set_break(&mut process, base - 1), Err(Errno::EINVAL));
+            assert_eq!(sys_brk(&mut process, (base - FRAME_SIZE) as usize), base + 0x1000);
+            let guard = process.stack_base as u64 - process.stack_size as u64 - FRAME_SIZE;
+            assert_eq!(set_break(&mut process, guard + 8), Err(Errno::ENOMEM));
+            assert_eq!(current(&process), base + 0x1000);
+        }
+
+        fn lowering_the_break_frees_pages() {
+            let free = memory::stats().free;
+            let mut process = Process::new(9361, 0, "brk");
+            assert_eq!(paging::create_address_space(&mut process), Ok(()));
+            process.heap_base = USER_START as usize;
+            let root = process.page_table_root as u64;
+
+            assert_eq!(set_break(&mut process, USER_START + 3 * FRAME_SIZE), Ok(()));
+            for page in 0..3 {
+                let virt = USER_START + page * FRAME_SIZE;
+                assert_eq!(fault::handle(&process, virt, Access::Write), Ok(true));
+            }
+
+            // The page the break now falls in stays
+            assert_eq!(set_break(&mut process, USER_START + FRAME_SIZE + 8), Ok(()));
+            assert!(paging::translate(root, USER_START + FRAME_SIZE).is_some());
+            assert_eq!(paging::translate(root, USER_START + 2 * FRAME_SIZE), None);
+
+            assert_eq!(set_break(&mut process, USER_START), Ok(()));
+            assert_eq!(paging::translate(root, USER_START), None);
+            paging::release(&mut process);
+            assert_eq!(memory::stats().free, free);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use crate::os::audit::{self, AuditKind};
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::pidns;
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::uaccess;
+
+/// A privilege that can be held independently of the others. Numbered as on Linux so
+/// userspace tooling can share the constants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Capability {
+    /// `CAP_CHOWN`: change file ownership arbitrarily.
+    Chown = 0,
+
+    /// `CAP_DAC_OVERRIDE`: bypass file read, write and execute permission checks.
+    DacOverride = 1,
+
+    /// `CAP_KILL`: send signals to any process.
+    Kill = 5,
+
+    /// `CAP_SETGID`: change group IDs and the supplementary group list.
+    SetGid = 6,
+
+    /// `CAP_SETUID`: change user IDs.
+    SetUid = 7,
+
+    /// `CAP_NET_BIND_SERVICE`: bind sockets to ports below 1024.
+    NetBindService = 10,
+
+    /// `CAP_NET_ADMIN`: configure network interfaces and routing.
+    NetAdmin = 12,
+
+    /// `CAP_SYS_MODULE`: load and unload kernel modules.
+    SysModule = 16,
+
+    /// `CAP_SYS_RAWIO`: raw port and memory I/O.
+    SysRawIo = 17,
+
+    /// `CAP_SYS_CHROOT`: change the root directory.
+    SysChroot = 18,
+
+    /// `CAP_SYS_ADMIN`: system administration (mounting, sysctl, quotas, ...).
+    SysAdmin = 21,
+
+    /// `CAP_SYS_BOOT`: reboot and power off.
+    SysBoot = 22,
+
+    /// `CAP_SYS_NICE`: raise priorities and change other processes' scheduling.
+    SysNice = 23,
+
+    /// `CAP_SYS_RESOURCE`: exceed resource limits.
+    SysResource = 24,
+
+    /// `CAP_SYS_TIME`: set the system clock.
+    SysTime = 25,
+
+    /// `CAP_AUDIT_READ`: read the security audit log.
+    AuditRead = 37,
+}
+
+/// `_LINUX_CAPABILITY_VERSION_3`, the only header version `capget` and `capset` accept.
+pub const CAPABILITY_VERSION: u32 = 0x2008_0522;
+
+/// The header `capget` and `capset` take, as on Linux: the ABI version and the target process
+/// (0 for the caller).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CapUserHeader {
+    pub version: u32,
+    pub pid: i32,
+}
+
+/// One 32-bit half of the three sets. The calls take an array of two, the low half first.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CapUserData {
+    pub effective: u32,
+    pub permitted: u32,
+    pub inheritable: u32,
+}
+
+/// A set of [`Capability`] values, stored as a bitmap indexed by capability number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilitySet(u64);
+
+impl CapabilitySet {
+    pub const EMPTY: CapabilitySet = CapabilitySet(0);
+
+    /// Every defined capability.
+    pub const FULL: CapabilitySet = CapabilitySet(
+        1 << Capability::Chown as u8
+            | 1 << Capability::DacOverride as u8
+            | 1 << Capability::Kill as u8
+            | 1 << Capability::SetGid as u8
+            | 1 << Capability::SetUid as u8
+            | 1 << Capability::NetBindService as u8
+            | 1 << Capability::NetAdmin as u8
+            | 1 << Capability::SysModule as u8
+            | 1 << Capability::SysRawIo as u8
+            | 1 << Capability::SysChroot as u8
+            | 1 << Capability::SysAdmin as u8
+            | 1 << Capability::SysBoot as u8
+            | 1 << Capability::SysNice as u8
+            | 1 << Capability::SysResource as u8
+            | 1 << Capability::SysTime as u8
+            | 1 << Capability::AuditRead as u8,
+    );
+
+    /// Builds a set from its raw bitmap, ignoring undefined bits.
+    pub const fn from_bits(bits: u64) -> Self {
+        CapabilitySet(bits & Self::FULL.0)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(&self, cap: Capability) -> bool {
+        self.0 & (1 << cap as u8) != 0
+    }
+
+    pub fn insert(&mut self, cap: Capability) {
+        self.0 |= 1 << cap as u8;
+    }
+
+    pub fn remove(&mut self, cap: Capability) {
+        self.0 &= !(1 << cap as u8);
+    }
+
+    pub const fn intersection(&self, other: CapabilitySet) -> CapabilitySet {
+        CapabilitySet(self.0 & other.0)
+    }
+
+    pub const fn difference(&self, other: CapabilitySet) -> CapabilitySet {
+        CapabilitySet(self.0 & !other.0)
+    }
+
+    /// Returns `true` if every capability in `self` is also in `other`.
+    pub const fn is_subset(&self, other: CapabilitySet) -> bool {
+        self.0 & !other.0 == 0
+    }
+}
+
+/// Returns `EPERM` unless `cred` has `cap` in its effective set. Called at every privileged
+/// operation in place of a plain "is root" test.
+pub fn require(cred: &Credentials, cap: Capability) -> KResult<()> {
+    if cred.capable(cap) {
+        Ok(())
+    } else {
+        audit::record_cred(AuditKind::CapabilityDenied, audit::NO_PID, cred, [cap as u64, 0]);
+        Err(Errno::EPERM)
+    }
+}
+
+/// Permanently removes `caps` from the process: they can be neither used nor regained, and are
+/// not passed on to children or across exec.
+pub fn drop_capabilities(process: &mut Process, caps: CapabilitySet) {
+    let cred = &mut process.cred;
+
+    cred.cap_permitted = cred.cap_permitted.difference(caps);
+    cred.cap_effective = cred.cap_effective.difference(caps);
+    cred.cap_inheritable = cred.cap_inheritable.difference(caps);
+    cred.cap_bounding = cred.cap_bounding.difference(caps);
+}
+
+/// `capset`: replaces the three sets. Nothing may be added to the permitted set, and the
+/// effective and inheritable sets must stay within it.
+pub fn capset(
+    process: &mut Process,
+    effective: CapabilitySet,
+    permitted: CapabilitySet,
+    inheritable: CapabilitySet,
+) -> KResult<()> {
+    let cred = &mut process.cred;
+
+    if !permitted.is_subset(cred.cap_permitted)
+        || !effective.is_subset(permitted)
+        || !inheritable.is_subset(permitted)
+    {
+        return Err(Errno::EPERM);
+    }
+
+    cred.cap_effective = effective;
+    cred.cap_permitted = permitted;
+    cred.cap_inheritable = inheritable;
+    Ok(())
+}
+
+/// Recomputes the sets when the process execs a new image.
+///
+/// The new image keeps only the capabilities the old one marked inheritable, except that a
+/// program running with effective UID 0 gets the full set, as root did before capabilities.
+pub fn on_exec(cred: &mut Credentials) {
+    let permitted = if cred.euid == crate::os::cred::ROOT_ID {
+        // Dropped capabilities stay dropped even for root
+        CapabilitySet::FULL.intersection(cred.cap_bounding)
+    } else {
+        cred.cap_inheritable.intersection(cred.cap_permitted)
+    };
+
+    cred.cap_permitted = permitted;
+    cred.cap_effective = permitted;
+    cred.cap_inheritable = cred.cap_inheritable.intersection(permitted);
+}
+
+/// Adjusts the sets after a UID change, as Linux does without `keepcaps`: leaving effective
+/// UID 0 clears the effective set, and once no UID is 0 the permitted set goes too.
+/// Regaining effective UID 0 through the saved UID restores the effective set.
+pub fn on_uid_change(cred: &mut Credentials) {
+    let root = crate::os::cred::ROOT_ID;
+
+    if cred.uid != root && cred.euid != root && cred.suid != root {
+        cred.cap_permitted = CapabilitySet::EMPTY;
+        cred.cap_inheritable = CapabilitySet::EMPTY;
+    }
+
+    cred.cap_effective = if cred.euid == root { cred.cap_permitted } else { CapabilitySet::EMPTY };
+}
+
+// Reads the header at `header`. A wrong version fails with `EINVAL` and is overwritten with
+// the supported one, so callers can find out what to use.
+fn read_header(header: usize) -> KResult<CapUserHeader> {
+    let mut value = unsafe { uaccess::read_user::<CapUserHeader>(header)? };
+
+    if value.version != CAPABILITY_VERSION {
+        value.version = CAPABILITY_VERSION;
+        uaccess::write_user(header, &value)?;
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(value)
+}
+
+/// `capget(header, data)`: copies the sets of the process the header names, by its PID in the
+/// namespace of the caller `caller`, to `data`. A null `data` only checks the version. Fails
+/// with `ESRCH` if the caller cannot see such a process.
+pub fn sys_capget(caller: u64, header: usize, data: usize) -> KResult<()> {
+    let header = read_header(header)?;
+
+    if header.pid < 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let ns = ptable::with_process(caller, |process| process.pid_links.namespace()).ok_or(Errno::ESRCH)?;
+    let target = match header.pid {
+        0 => caller,
+        pid => pidns::to_global(ns, pid as u64).ok_or(Errno::ESRCH)?,
+    };
+    let cred = ptable::with_process(target, |target| target.cred).ok_or(Errno::ESRCH)?;
+
+    if data == 0 {
+        return Ok(());
+    }
+
+    let half = |shift: u32| CapUserData {
+        effective: (cred.cap_effective.bits() >> shift) as u32,
+        permitted: (cred.cap_permitted.bits() >> shift) as u32,
+        inheritable: (cred.cap_inheritable.bits() >> shift) as u32,
+    };
+    uaccess::write_user(data, &[half(0), half(32)])
+}
+
+/// `capset(header, data)`: replaces the sets of the caller `caller` with those at `data`,
+/// under the rules of [`capset`]. A process can only change its own.
+pub fn sys_capset(caller: u64, header: usize, data: usize) -> KResult<()> {
+    let header = read_header(header)?;
+    let own_pid = ptable::with_process(caller, |process| process.pid_links.pid()).ok_or(Errno::ESRCH)?;
+    if header.pid != 0 && header.pid as u64 != own_pid {
+        return Err(Errno::EPERM);
+    }
+
+    let [low, high] = unsafe { uaccess::read_user::<[CapUserData; 2]>(data)? };
+    let join = |low: u32, high: u32| CapabilitySet::from_bits(low as u64 | (high as u64) << 32);
+
+    ptable::with_process(caller, |process| {
+        capset(
+            process,
+            join(low.effective, high.effective),
+            join(low.permitted, high.permitted),
+            join(low.inheritable, high.inheritable),
+        )
+    })
+    .ok_or(Errno::ESRCH)?
+}
+
+pub mod ktests {
+    use super::*;
+    use crate::os::cred::{self, ROOT_ID};
+
+    fn process(uid: u32) -> Process {
+        let mut process = Process::new(9720, 0, "caps");
+        process.cred = Credentials::new(uid, uid);
+        process
+    }
+
+    fn set(caps: &[Capability]) -> CapabilitySet {
+        let mut set = CapabilitySet::EMPTY;
+        for &cap in caps {
+            set.insert(cap);
+        }
+        set
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn dropped_capabilities_are_gone_for_good() {
+            let mut process = process(ROOT_ID);
+            drop_capabilities(&mut process, set(&[Capability::Kill, Capability::SysAdmin]));
+
+            assert!(!process.cred.capable(Capability::Kill));
+            assert_eq!(require(&process.cred, Capability::SysAdmin), Err(Errno::EPERM));
+            assert_eq!(require(&process.cred, Capability::Chown), Ok(()));
+
+            // Neither capset nor an exec as root brings them back
+            let full = CapabilitySet::FULL;
+            assert_eq!(capset(&mut process, full, full, CapabilitySet::EMPTY), Err(Errno::EPERM));
+            on_exec(&mut process.cred);
+            assert!(!process.cred.capable(Capability::Kill));
+            assert!(process.cred.capable(Capability::Chown));
+        }
+
+        fn capset_stays_within_the_permitted_set() {
+            let mut process = process(ROOT_ID);
+            let kill = set(&[Capability::Kill]);
+            let both = set(&[Capability::Kill, Capability::SysNice]);
+
+            assert_eq!(capset(&mut process, both, kill, kill), Err(Errno::EPERM));
+            assert_eq!(capset(&mut process, kill, both, both), Ok(()));
+            assert_eq!(process.cred.cap_effective, kill);
+
+            // The permitted set only shrinks
+            assert_eq!(capset(&mut process, kill, CapabilitySet::FULL, kill), Err(Errno::EPERM));
+        }
+
+        fn exec_keeps_only_the_inheritable_set() {
+            let nice = set(&[Capability::SysNice]);
+            let mut cred = Credentials::new(1000, 1000);
+            cred.cap_permitted = set(&[Capability::SysNice, Capability::Kill]);
+            cred.cap_effective = cred.cap_permitted;
+            cred.cap_inheritable = set(&[Capability::SysNice, Capability::SysTime]);
+
+            on_exec(&mut cred);
+            assert_eq!((cred.cap_permitted, cred.cap_effective, cred.cap_inheritable), (nice, nice, nice));
+
+            // Root gets everything its bounding set allows
+            let mut root = Credentials::ROOT;
+            root.cap_bounding.remove(Capability::SysBoot);
+            on_exec(&mut root);
+            assert_eq!(root.cap_permitted, CapabilitySet::FULL.difference(set(&[Capability::SysBoot])));
+        }
+
+        fn uid_changes_follow_the_root_ids() {
+            let mut cred = Credentials::ROOT;
+
+            // Leaving effective root clears only the effective set while the saved ID is root
+            cred.euid = 1000;
+            on_uid_change(&mut cred);
+            assert_eq!((cred.cap_effective, cred.cap_permitted), (CapabilitySet::EMPTY, CapabilitySet::FULL));
+
+            cred.euid = ROOT_ID;
+            on_uid_change(&mut cred);
+            assert_eq!(cred.cap_effective, CapabilitySet::FULL);
+
+            // With no root ID left the permitted set goes too
+            cred.uid = 1000;
+            cred.euid = 1000;
+            cred.suid = 1000;
+            on_uid_change(&mut cred);
+            assert_eq!((cred.cap_effective, cred.cap_permitted, cred.cap_inheritable), (CapabilitySet::EMPTY, CapabilitySet::EMPTY, CapabilitySet::EMPTY));
+
+            let mut process = process(ROOT_ID);
+            cred::setuid(&mut process, 1000).unwrap();
+            assert_eq!(require(&process.cred, Capability::SetUid), Err(Errno::EPERM));
+        }
+
+        fn sets_travel_through_user_memory() {
+            ptable::insert(process(ROOT_ID)).unwrap();
+            let mut header = CapUserHeader { version: 1, pid: 0 };
+            let mut data = [CapUserData::default(); 2];
+            let header_addr = &raw mut header as usize;
+            let data_addr = &raw mut data as usize;
+
+            // A wrong version is answered with the right one
+            assert_eq!(sys_capget(9720, header_addr, data_addr), Err(Errno::EINVAL));
+            assert_eq!(header.version, CAPABILITY_VERSION);
+
+            sys_capget(9720, header_addr, data_addr).unwrap();
+            let full = CapabilitySet::FULL.bits();
+            assert_eq!(data[0].effective as u64 | (data[1].effective as u64) << 32, full);
+
+            // AuditRead lives in the high half
+            data[0] = CapUserData::default();
+            data[1] = CapUserData { effective: 0, permitted: 1 << (Capability::AuditRead as u8 - 32), inheritable: 0 };
+            sys_capset(9720, header_addr, data_addr).unwrap();
+            let cred = ptable::with_process(9720, |p| p.cred).unwrap();
+            assert_eq!(cred.cap_permitted, set(&[Capability::AuditRead]));
+            assert_eq!(cred.cap_effective, CapabilitySet::EMPTY);
+
+            // Processes are named by their PID in the caller's namespace
+            header.pid = 9720;
+            assert_eq!(sys_capget(9720, header_addr, data_addr), Ok(()));
+            header.pid = 9721;
+            assert_eq!(sys_capget(9720, header_addr, data_addr), Err(Errno::ESRCH));
+            header.pid = 1;
+            assert_eq!(sys_capset(9720, header_addr, data_addr), Err(Errno::EPERM));
+            header.pid = -1;
+            assert_eq!(sys_capget(9720, header_addr, data_addr), Err(Errno::EINVAL));
+
+            // In a namespace of its own, the caller is PID 1 and its global PID means nothing
+            let mut parent = Process::new(9719, 0, "caps");
+            pidns::sys_unshare_pid(&mut parent).unwrap();
+            assert_eq!(ptable::with_process(9720, |p| pidns::attach(p, &parent)), Some(Ok(())));
+            header.pid = 1;
+            assert_eq!(sys_capset(9720, header_addr, data_addr), Ok(()));
+            header.pid = 9720;
+            assert_eq!(sys_capget(9720, header_addr, data_addr), Err(Errno::ESRCH));
+
+            pidns::detach(&mut ptable::remove(9720).unwrap());
+            pidns::detach(&mut parent);
+        }
+    }
+}
@@ -0,0 +1,790 @@
+//! Control groups: hierarchical CPU shares and memory ceilings.
+//!
+//! Groups form a tree under the root group, and every process belongs to exactly one group.
+//! A group's settings constrain its whole subtree:
+//!
+//! - `cpu.weight` (1-10000, default 100) scales the timeslice the scheduler hands members, as
+//!   the product of the weights relative to the default along the path from the root, so a
+//!   group at 200 gets twice the CPU of a sibling at 100 under round robin.
+//! - `memory.max` caps the pages charged to the subtree. The page fault and page cache paths
+//!   [`charge`] every frame they allocate on a group's behalf. A charge that would take the
+//!   group or an ancestor over its ceiling first reclaims clean page-cache pages from that
+//!   group's subtree, and fails with `ENOMEM` if that frees too little. Charges are made with
+//!   the process table locked, so going further is left to [`make_room`], which the page
+//!   fault handler runs once it is unlocked before retrying: it swaps the subtree's anonymous
+//!   pages out (see `swap`), and only if that frees too little does the OOM logic kill a
+//!   process, chosen from that subtree alone.
+//!
+//! The tree is configured through the `cgroup2` pseudo-filesystem: directories are groups
+//! ([`mkdir`], [`rmdir`]) and [`render`] / [`write`] serve their files. PIDs in `cgroup.procs`
+//! are root-namespace PIDs.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+use crate::os::capability::{self, Capability};
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::FRAME_SIZE;
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::sysctl;
+
+/// Index of a group in the group table.
+pub type GroupId = u16;
+
+/// The root group, which every process starts in and which has no limits.
+pub const ROOT_GROUP: GroupId = 0;
+
+/// Maximum number of groups, the root included.
+pub const MAX_GROUPS: usize = 32;
+
+/// Longest group name, in bytes.
+pub const MAX_NAME: usize = 16;
+
+/// `cpu.weight` of a new group.
+pub const DEFAULT_WEIGHT: u64 = 100;
+
+/// Valid `cpu.weight` range.
+pub const MIN_WEIGHT: u64 = 1;
+pub const MAX_WEIGHT: u64 = 10_000;
+
+/// Longest timeslice a weight can stretch a member's to, in ticks.
+pub const MAX_TIMESLICE: u64 = 1000;
+
+/// `memory.max` when unlimited.
+pub const MEMORY_UNLIMITED: u64 = u64::MAX;
+
+/// Signal the OOM logic sends its victim (`SIGKILL`).
+const SIGKILL: u32 = 9;
+
+#[derive(Debug, Clone, Copy)]
+struct Group {
+    parent: GroupId,
+    name: [u8; MAX_NAME],
+    name_len: u8,
+
+    cpu_weight: u64,
+    memory_max: u64,
+
+    // Ticks run by members of the subtree
+    cpu_usage: u64,
+
+    // Pages charged to the subtree
+    anon_pages: u64,
+    cache_pages: u64,
+
+    // Times a charge hit the ceiling, found nothing to reclaim, and killed a process
+    max_events: u64,
+    oom_events: u64,
+    oom_kills: u64,
+
+    // Processes directly in the group
+    members: u32,
+}
+
+impl Group {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or("")
+    }
+
+    fn pages(&self) -> u64 {
+        self.anon_pages + self.cache_pages
+    }
+
+    fn is_over(&self, extra: u64) -> bool {
+        self.memory_max != MEMORY_UNLIMITED && (self.pages() + extra) * FRAME_SIZE > self.memory_max
+    }
+}
+
+const ROOT: Group = Group {
+    parent: ROOT_GROUP,
+    name: [0; MAX_NAME],
+    name_len: 0,
+    cpu_weight: DEFAULT_WEIGHT,
+    memory_max: MEMORY_UNLIMITED,
+    cpu_usage: 0,
+    anon_pages: 0,
+    cache_pages: 0,
+    max_events: 0,
+    oom_events: 0,
+    oom_kills: 0,
+    members: 0,
+};
+
+static mut GROUPS: [Option<Group>; MAX_GROUPS] = {
+    let mut table = [None; MAX_GROUPS];
+    table[ROOT_GROUP as usize] = Some(ROOT);
+    table
+};
+
+fn groups() -> &'static mut [Option<Group>; MAX_GROUPS] {
+    unsafe {
+        let table = &raw mut GROUPS;
+        &mut *table
+    }
+}
+
+fn get(group: GroupId) -> Option<&'static mut Group> {
+    groups().get_mut(group as usize)?.as_mut()
+}
+
+/// Calls `f` on `group` and each of its ancestors, innermost first, stopping at the root
+/// (which is included).
+fn for_each_ancestor(group: GroupId, mut f: impl FnMut(GroupId, &mut Group)) {
+    let mut at = group;
+
+    while let Some(g) = get(at) {
+        f(at, g);
+        if at == ROOT_GROUP {
+            break;
+        }
+        at = g.parent;
+    }
+}
+
+/// Whether `group` is `ancestor` or lies below it.
+pub fn is_within(group: GroupId, ancestor: GroupId) -> bool {
+    let mut found = false;
+    for_each_ancestor(group, |at, _| found |= at == ancestor);
+    found
+}
+
+// Walks `path` (group names separated by `/`) down from the root
+fn find(path: &str) -> KResult<GroupId> {
+    path.split('/').filter(|c| !c.is_empty()).try_fold(ROOT_GROUP, |parent, name| child(parent, name).ok_or(Errno::ENOENT))
+}
+
+fn child(parent: GroupId, name: &str) -> Option<GroupId> {
+    groups()
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(_, slot)| slot.as_ref().is_some_and(|g| g.parent == parent && g.name() == name))
+        .map(|(id, _)| id as GroupId)
+}
+
+/// Number of groups, the root included.
+pub fn count() -> usize {
+    groups().iter().flatten().count()
+}
+
+// =========================================================================
+// Membership
+// =========================================================================
+
+/// Puts the new process `child` in `parent`'s group. Called when the child is created.
+pub fn inherit(child: &mut Process, parent: &Process) {
+    child.cgroup = parent.cgroup;
+    if let Some(group) = get(child.cgroup) {
+        group.members += 1;
+    }
+}
+
+/// Takes `process` out of its group. Called when the process is reaped.
+pub fn release(process: &mut Process) {
+    if let Some(group) = get(process.cgroup) {
+        group.members = group.members.saturating_sub(1);
+    }
+    process.cgroup = ROOT_GROUP;
+}
+
+/// Moves `process` into `group` and gives it that group's timeslice.
+pub fn attach(process: &mut Process, group: GroupId) -> KResult<()> {
+    get(group).ok_or(Errno::ENOENT)?.members += 1;
+    release(process);
+    process.cgroup = group;
+    process.timeslice = timeslice(process);
+
+    log::debug!("cgroup: pid {} moved to group {}", process.pid, group);
+    Ok(())
+}
+
+// =========================================================================
+// Enforcement
+// =========================================================================
+
+/// The timeslice, in ticks, the scheduler gives `process` when it is scheduled: the base
+/// `kernel.sched_timeslice` scaled by the weights of its group and every ancestor.
+pub fn timeslice(process: &Process) -> u32 {
+    let mut slice = sysctl::SCHED_TIMESLICE.load(Ordering::Relaxed);
+
+    for_each_ancestor(process.cgroup, |_, g| {
+        slice = slice.saturating_mul(g.cpu_weight) / DEFAULT_WEIGHT;
+    });
+
+    slice.clamp(1, MAX_TIMESLICE) as u32
+}
+
+/// Scheduler tick hook, called after `cpu_time` was charged. Charges the tick to every group
+/// from the process's up to the root.
+pub fn on_cpu_tick(process: &Process) {
+    for_each_ancestor(process.cgroup, |_, g| g.cpu_usage += 1);
+}
+
+// =========================================================================
+// Memory
+// =========================================================================
+
+/// What a charged page holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    /// Process memory: heap, stack, private mappings.
+    Anon,
+
+    /// File contents kept in the page cache.
+    Cache,
+}
+
+/// The page cache as seen by the memory controller.
+pub trait PageCache: Sync {
+    /// Drops up to `pages` clean pages charged to groups in `group`'s subtree, uncharging each
+    /// with [`uncharge`], and returns how many it dropped. Dirty pages stay.
+    fn reclaim_clean(&self, group: GroupId, pages: u64) -> u64;
+}
+
+static mut PAGE_CACHE: Option<&'static dyn PageCache> = None;
+
+/// Installs the page cache reclaim goes to before resorting to the OOM logic.
+pub fn set_page_cache(cache: &'static dyn PageCache) {
+    unsafe {
+        let slot = &raw mut PAGE_CACHE;
+        *slot = Some(cache);
+    }
+}
+
+fn page_cache() -> Option<&'static dyn PageCache> {
+    unsafe {
+        let slot = &raw const PAGE_CACHE;
+        *slot
+    }
+}
+
+/// Swapping of anonymous memory, as seen by the memory controller.
+pub trait AnonSwap: Sync {
+    /// Swaps out up to `pages` anonymous pages charged to groups in `group`'s subtree,
+    /// uncharging each with [`uncharge`], and returns how many it swapped.
+    fn swap_out(&self, group: GroupId, pages: u64) -> u64;
+}
+
+static mut ANON_SWAP: Option<&'static dyn AnonSwap> = None;
+
+/// Installs the swapper reclaim turns to once the page cache has nothing left to give.
+pub fn set_anon_swap(swap: &'static dyn AnonSwap) {
+    unsafe {
+        let slot = &raw mut ANON_SWAP;
+        *slot = Some(swap);
+    }
+}
+
+fn anon_swap() -> Option<&'static dyn AnonSwap> {
+    unsafe {
+        let slot = &raw const ANON_SWAP;
+        *slot
+    }
+}
+
+/// Bytes charged to `group`'s subtree.
+pub fn memory_current(group: GroupId) -> u64 {
+    get(group).map_or(0, |g| g.pages() * FRAME_SIZE)
+}
+
+// The innermost group from `group` up that `pages` more would take over its ceiling
+fn over_limit(group: GroupId, pages: u64) -> Option<GroupId> {
+    let mut over = None;
+    for_each_ancestor(group, |at, g| {
+        if over.is_none() && g.is_over(pages) {
+            over = Some(at);
+        }
+    });
+    over
+}
+
+// Brings `group` back to `pages` below its ceiling by dropping clean page cache and, if that
+// is not enough and `swap` is set, swapping anonymous pages out; true if either freed anything
+fn reclaim(group: GroupId, pages: u64, swap: bool) -> bool {
+    let Some(g) = get(group) else {
+        return false;
+    };
+
+    let before = g.pages();
+    let target = (g.memory_max / FRAME_SIZE).saturating_sub(pages);
+    let excess = || get(group).map_or(0, |g| g.pages().saturating_sub(target));
+
+    // Clean cache costs nothing to drop; a swapped page costs a compression now and a fault
+    // later
+    if let Some(cache) = page_cache() {
+        cache.reclaim_clean(group, excess());
+    }
+    if swap
+        && let Some(swapper) = anon_swap()
+        && excess() > 0
+    {
+        swapper.swap_out(group, excess());
+    }
+
+    get(group).is_some_and(|g| g.pages() < before)
+}
+
+// Kills the largest process in `group`'s subtree, unless an earlier victim is still dying
+fn oom(group: GroupId) {
+    let Some(g) = get(group) else {
+        return;
+    };
+    g.oom_events += 1;
+
+    let mut dying = false;
+    let mut victim: Option<(u64, usize)> = None;
+    ptable::for_each(|p| {
+        if p.pid == 0 || !is_within(p.cgroup, group) {
+            return;
+        }
+        dying |= p.signal_bitmap & (1 << SIGKILL) != 0;
+        if victim.is_none_or(|(_, size)| p.memory_usage() > size) {
+            victim = Some((p.pid, p.memory_usage()));
+        }
+    });
+
+    if dying {
+        return;
+    }
+    let Some((pid, size)) = victim else {
+        log::warn!("cgroup: out of memory in group {} with no process to kill", group);
+        return;
+    };
+
+    ptable::with_process(pid, |p| p.signal_bitmap |= 1 << SIGKILL);
+    g.oom_kills += 1;
+    log::warn!("cgroup: out of memory in group {}: killed pid {} ({} bytes)", group, pid, size);
+}
+
+// Reclaims, swapping only if `swap` is set, until `pages` more fit under every ceiling from
+// `group` up; returns the group it could not get under, if any
+fn reclaim_up_to(group: GroupId, pages: u64, swap: bool) -> Result<(), GroupId> {
+    while let Some(limited) = over_limit(group, pages) {
+        if let Some(g) = get(limited) {
+            g.max_events += 1;
+        }
+
+        if !reclaim(limited, pages, swap) {
+            return Err(limited);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reclaims, swapping as well as dropping clean page cache, then OOM-kills, until `pages` more
+/// fit under every ceiling from `group` up. Swapping and the OOM logic walk the process table,
+/// so this runs with it unlocked. Fails with `ENOMEM` if room cannot be made.
+pub fn make_room(group: GroupId, pages: u64) -> KResult<()> {
+    reclaim_up_to(group, pages, true).map_err(|limited| {
+        oom(limited);
+        Errno::ENOMEM
+    })
+}
+
+/// Charges `pages` pages of `kind` to `group` and its ancestors, dropping clean page cache
+/// within whichever of them is at its ceiling. Fails with `ENOMEM`, charging nothing, if that
+/// frees too little; the caller then frees the frames it allocated and, once the process table
+/// is unlocked, may [`make_room`] and try again.
+pub fn charge(group: GroupId, kind: PageKind, pages: u64) -> KResult<()> {
+    reclaim_up_to(group, pages, false).map_err(|_| Errno::ENOMEM)?;
+
+    for_each_ancestor(group, |_, g| match kind {
+        PageKind::Anon => g.anon_pages += pages,
+        PageKind::Cache => g.cache_pages += pages,
+    });
+    Ok(())
+}
+
+/// Returns `pages` pages of `kind` charged to `group` when they are freed.
+pub fn uncharge(group: GroupId, kind: PageKind, pages: u64) {
+    for_each_ancestor(group, |_, g| match kind {
+        PageKind::Anon => g.anon_pages = g.anon_pages.saturating_sub(pages),
+        PageKind::Cache => g.cache_pages = g.cache_pages.saturating_sub(pages),
+    });
+}
+
+// =========================================================================
+// cgroup2 pseudo-filesystem
+// =========================================================================
+
+/// Creates the group at `path` (relative to the mount point). Requires `CAP_SYS_ADMIN`.
+/// Names may not contain `.`, which keeps them apart from the control files.
+pub fn mkdir(path: &str, cred: &Credentials) -> KResult<()> {
+    capability::require(cred, Capability::SysAdmin)?;
+
+    let path = path.trim_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() || name.len() > MAX_NAME || name.contains('.') {
+        return Err(Errno::EINVAL);
+    }
+
+    let parent = find(parent)?;
+    if child(parent, name).is_some() {
+        return Err(Errno::EEXIST);
+    }
+
+    let slot = groups().iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOSPC)?;
+    let mut group = Group { parent, ..ROOT };
+    group.name[..name.len()].copy_from_slice(name.as_bytes());
+    group.name_len = name.len() as u8;
+    *slot = Some(group);
+
+    log::info!("cgroup: created {}", path);
+    Ok(())
+}
+
+/// Removes the empty group at `path`, first dropping whatever clean page cache is still
+/// charged to it. Fails with `EBUSY` while it has members, children or charged pages.
+pub fn rmdir(path: &str, cred: &Credentials) -> KResult<()> {
+    capability::require(cred, Capability::SysAdmin)?;
+
+    let group = find(path)?;
+    if group == ROOT_GROUP {
+        return Err(Errno::EBUSY);
+    }
+
+    let has_children = groups().iter().skip(1).flatten().any(|g| g.parent == group);
+    if has_children || get(group).is_some_and(|g| g.members > 0) {
+        return Err(Errno::EBUSY);
+    }
+
+    if let (Some(cache), Some(g)) = (page_cache(), get(group)) {
+        cache.reclaim_clean(group, g.pages());
+    }
+    if get(group).is_some_and(|g| g.pages() > 0) {
+        return Err(Errno::EBUSY);
+    }
+
+    groups()[group as usize] = None;
+    log::info!("cgroup: removed {}", path.trim_matches('/'));
+    Ok(())
+}
+
+// Splits `svc/web/cpu.weight` into the group and the file name
+fn split(path: &str) -> Option<(GroupId, &str)> {
+    let path = path.trim_matches('/');
+    let (dir, file) = path.rsplit_once('/').unwrap_or(("", path));
+    Some((find(dir).ok()?, file))
+}
+
+/// Renders the file at `path` (relative to the mount point) into `w`.
+///
+/// Every group has:
+/// - `cgroup.procs`: PIDs of the processes directly in the group, one per line
+/// - `cpu.stat`: `usage_ticks`, ticks run by the subtree
+/// - `memory.current`: bytes charged to the subtree
+/// - `memory.stat`: `anon` and `file` (page cache) bytes charged to the subtree
+/// - `memory.events`: `max` (charges that hit the ceiling), `oom` (that found nothing to
+///   reclaim) and `oom_kill` (processes killed)
+///
+/// and every group but the root:
+/// - `cpu.weight`: the group's CPU weight
+/// - `memory.max`: the subtree's ceiling in bytes, or `max`
+///
+/// Returns `None` if no such file exists.
+pub fn render(path: &str, w: &mut impl Write) -> Option<fmt::Result> {
+    let (id, file) = split(path)?;
+    let group = *get(id)?;
+
+    Some(match file {
+        "cgroup.procs" => {
+            let mut result = Ok(());
+            ptable::for_each(|p| {
+                if p.cgroup == id && result.is_ok() {
+                    result = writeln!(w, "{}", p.pid);
+                }
+            });
+            result
+        }
+        "cpu.stat" => writeln!(w, "usage_ticks {}", group.cpu_usage),
+        "memory.current" => writeln!(w, "{}", memory_current(id)),
+        "memory.stat" => writeln!(w, "anon {}\nfile {}", group.anon_pages * FRAME_SIZE, group.cache_pages * FRAME_SIZE),
+        "memory.events" => {
+            writeln!(w, "max {}\noom {}\noom_kill {}", group.max_events, group.oom_events, group.oom_kills)
+        }
+        "cpu.weight" if id != ROOT_GROUP => writeln!(w, "{}", group.cpu_weight),
+        "memory.max" if id != ROOT_GROUP => match group.memory_max {
+            MEMORY_UNLIMITED => writeln!(w, "max"),
+            max => writeln!(w, "{}", max),
+        },
+        _ => return None,
+    })
+}
+
+/// Writes `data` to the file at `path` on behalf of `cred`. `cpu.weight` and `memory.max`
+/// take a decimal number (`memory.max` also `max`); lowering `memory.max` below the current
+/// usage reclaims (and if need be OOM-kills) down to it straight away. Writing a PID to
+/// `cgroup.procs` moves that process into the group. Requires `CAP_SYS_ADMIN`.
+///
+/// Returns `None` if no such writable file exists.
+pub fn write(path: &str, data: &str, cred: &Credentials) -> Option<KResult<()>> {
+    let (id, file) = split(path)?;
+    let data = data.trim();
+
+    let apply = |f: fn(&mut Group, u64) -> KResult<()>, value: Option<u64>| -> KResult<()> {
+        capability::require(cred, Capability::SysAdmin)?;
+        let group = get(id).ok_or(Errno::ENOENT)?;
+        f(group, value.ok_or(Errno::EINVAL)?)
+    };
+
+    Some(match file {
+        "cpu.weight" if id != ROOT_GROUP => apply(
+            |g, weight| {
+                if !(MIN_WEIGHT..=MAX_WEIGHT).contains(&weight) {
+                    return Err(Errno::EINVAL);
+                }
+                g.cpu_weight = weight;
+                Ok(())
+            },
+            data.parse().ok(),
+        ),
+        "memory.max" if id != ROOT_GROUP => apply(
+            |g, max| {
+                g.memory_max = max;
+                Ok(())
+            },
+            if data == "max" { Some(MEMORY_UNLIMITED) } else { data.parse().ok() },
+        )
+        .map(|()| {
+            // As on Linux the new ceiling sticks even if the OOM killer cannot get under it
+            let _ = make_room(id, 0);
+        }),
+        "cgroup.procs" => capability::require(cred, Capability::SysAdmin).and_then(|()| {
+            let pid = data.parse().map_err(|_| Errno::EINVAL)?;
+            ptable::with_process(pid, |p| attach(p, id)).ok_or(Errno::ESRCH)?
+        }),
+        _ => return None,
+    })
+}
+
+pub mod ktests {
+    use core::sync::atomic::{AtomicU16, AtomicU64};
+
+    use super::*;
+
+    struct Output {
+        buf: [u8; 64],
+        len: usize,
+    }
+
+    impl Write for Output {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.buf.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    fn read(path: &str) -> Option<Output> {
+        let mut out = Output { buf: [0; 64], len: 0 };
+        render(path, &mut out)?.ok()?;
+        Some(out)
+    }
+
+    fn contents(out: &Output) -> &str {
+        core::str::from_utf8(&out.buf[..out.len]).unwrap()
+    }
+
+    fn admin() -> Credentials {
+        Process::new(0, 0, "cgroup").cred
+    }
+
+    // A page cache holding clean pages for one group
+    struct TestCache {
+        owner: AtomicU16,
+        pages: AtomicU64,
+    }
+
+    impl TestCache {
+        fn add(&self, owner: GroupId, pages: u64) {
+            self.owner.store(owner, Ordering::Relaxed);
+            self.pages.fetch_add(pages, Ordering::Relaxed);
+        }
+    }
+
+    impl PageCache for TestCache {
+        fn reclaim_clean(&self, group: GroupId, pages: u64) -> u64 {
+            let owner = self.owner.load(Ordering::Relaxed);
+            if !is_within(owner, group) {
+                return 0;
+            }
+
+            let dropped = pages.min(self.pages.load(Ordering::Relaxed));
+            self.pages.fetch_sub(dropped, Ordering::Relaxed);
+            uncharge(owner, PageKind::Cache, dropped);
+            dropped
+        }
+    }
+
+    static TEST_CACHE: TestCache = TestCache { owner: AtomicU16::new(ROOT_GROUP), pages: AtomicU64::new(0) };
+
+    // Swaps one group's resident anonymous pages out
+    struct TestSwap {
+        owner: AtomicU16,
+        resident: AtomicU64,
+        swapped: AtomicU64,
+    }
+
+    impl AnonSwap for TestSwap {
+        fn swap_out(&self, group: GroupId, pages: u64) -> u64 {
+            let owner = self.owner.load(Ordering::Relaxed);
+            if !is_within(owner, group) {
+                return 0;
+            }
+
+            let done = pages.min(self.resident.load(Ordering::Relaxed));
+            self.resident.fetch_sub(done, Ordering::Relaxed);
+            self.swapped.fetch_add(done, Ordering::Relaxed);
+            uncharge(owner, PageKind::Anon, done);
+            done
+        }
+    }
+
+    static TEST_SWAP: TestSwap = TestSwap {
+        owner: AtomicU16::new(ROOT_GROUP),
+        resident: AtomicU64::new(0),
+        swapped: AtomicU64::new(0),
+    };
+
+    crate::os::ktest::kernel_test! {
+        fn groups_nest_and_must_be_empty_to_remove() {
+            let groups = count();
+            let cred = admin();
+            mkdir("ktest", &cred).unwrap();
+            mkdir("/ktest/web/", &cred).unwrap();
+
+            assert_eq!(mkdir("ktest", &cred), Err(Errno::EEXIST));
+            assert_eq!(mkdir("missing/web", &cred), Err(Errno::ENOENT));
+            assert_eq!(mkdir("ktest/a.b", &cred), Err(Errno::EINVAL));
+            assert_eq!(rmdir("ktest", &cred), Err(Errno::EBUSY));
+
+            let web = find("ktest/web").unwrap();
+            assert!(is_within(web, find("ktest").unwrap()));
+            let mut process = Process::new(9200, 0, "cgroup");
+            attach(&mut process, web).unwrap();
+            assert_eq!(rmdir("ktest/web", &cred), Err(Errno::EBUSY));
+
+            release(&mut process);
+            rmdir("ktest/web", &cred).unwrap();
+            rmdir("ktest", &cred).unwrap();
+            assert_eq!(count(), groups);
+        }
+
+        fn weights_scale_timeslices() {
+            let cred = admin();
+            mkdir("ktest-cpu", &cred).unwrap();
+            mkdir("ktest-cpu/batch", &cred).unwrap();
+            write("ktest-cpu/cpu.weight", "200", &cred).unwrap().unwrap();
+            write("ktest-cpu/batch/cpu.weight", "50", &cred).unwrap().unwrap();
+
+            let base = sysctl::SCHED_TIMESLICE.load(Ordering::Relaxed) as u32;
+            let mut process = Process::new(9210, 0, "cgroup");
+            attach(&mut process, find("ktest-cpu").unwrap()).unwrap();
+            assert_eq!(process.timeslice, base * 2);
+            attach(&mut process, find("ktest-cpu/batch").unwrap()).unwrap();
+            assert_eq!(process.timeslice, base);
+
+            on_cpu_tick(&process);
+            assert_eq!(contents(&read("ktest-cpu/cpu.stat").unwrap()), "usage_ticks 1\n");
+
+            release(&mut process);
+            rmdir("ktest-cpu/batch", &cred).unwrap();
+            rmdir("ktest-cpu", &cred).unwrap();
+        }
+
+        fn reclaim_comes_before_oom() {
+            let cred = admin();
+            mkdir("ktest-mem", &cred).unwrap();
+            mkdir("ktest-mem/child", &cred).unwrap();
+            write("ktest-mem/memory.max", "65536", &cred).unwrap().unwrap();
+            let child = find("ktest-mem/child").unwrap();
+            set_page_cache(&TEST_CACHE);
+
+            // 10 clean cache pages and 4 anonymous ones, under the 16-page ceiling
+            charge(child, PageKind::Cache, 10).unwrap();
+            TEST_CACHE.add(child, 10);
+            charge(child, PageKind::Anon, 4).unwrap();
+
+            // Going 2 over drops 2 cache pages; lowering the ceiling drops another
+            charge(child, PageKind::Anon, 4).unwrap();
+            assert_eq!(memory_current(child), 16 * FRAME_SIZE);
+            write("ktest-mem/memory.max", "61440", &cred).unwrap().unwrap();
+            assert_eq!(contents(&read("ktest-mem/memory.stat").unwrap()), "anon 32768\nfile 28672\n");
+
+            // A charge drops the rest of the cache, but goes no further
+            let mut inside = Process::new(9220, 0, "cgroup");
+            attach(&mut inside, child).unwrap();
+            inside.heap_size = 32768;
+            let mut outside = Process::new(9221, 0, "cgroup");
+            outside.heap_size = 1 << 20;
+            ptable::insert(inside).unwrap();
+            ptable::insert(outside).unwrap();
+
+            assert_eq!(charge(child, PageKind::Anon, 8), Err(Errno::ENOMEM));
+            assert_eq!(contents(&read("ktest-mem/memory.events").unwrap()), "max 4\noom 0\noom_kill 0\n");
+
+            // Making room then kills the group's largest process, not the larger one outside
+            // it, and fails; a second attempt waits for that one to die
+            assert_eq!(make_room(child, 8), Err(Errno::ENOMEM));
+            assert_eq!(make_room(child, 8), Err(Errno::ENOMEM));
+            assert_eq!(memory_current(child), 8 * FRAME_SIZE);
+            assert_eq!(contents(&read("ktest-mem/memory.events").unwrap()), "max 6\noom 2\noom_kill 1\n");
+
+            let mut inside = ptable::remove(9220).unwrap();
+            let outside = ptable::remove(9221).unwrap();
+            assert_ne!(inside.signal_bitmap & (1 << SIGKILL), 0);
+            assert_eq!(outside.signal_bitmap & (1 << SIGKILL), 0);
+
+            uncharge(child, PageKind::Anon, 8);
+            release(&mut inside);
+            rmdir("ktest-mem/child", &cred).unwrap();
+            rmdir("ktest-mem", &cred).unwrap();
+        }
+
+        fn swap_comes_before_oom() {
+            let cred = admin();
+            mkdir("ktest-swap", &cred).unwrap();
+            write("ktest-swap/memory.max", "32768", &cred).unwrap().unwrap();
+            let group = find("ktest-swap").unwrap();
+
+            set_anon_swap(&TEST_SWAP);
+            TEST_SWAP.owner.store(group, Ordering::Relaxed);
+            charge(group, PageKind::Anon, 8).unwrap();
+            TEST_SWAP.resident.store(8, Ordering::Relaxed);
+
+            // Nothing in the page cache, so the charge fails, and making room swaps 3 pages out
+            // instead of a process dying
+            assert_eq!(charge(group, PageKind::Anon, 3), Err(Errno::ENOMEM));
+            assert_eq!(make_room(group, 3), Ok(()));
+            charge(group, PageKind::Anon, 3).unwrap();
+            assert_eq!(TEST_SWAP.swapped.load(Ordering::Relaxed), 3);
+            assert_eq!(memory_current(group), 8 * FRAME_SIZE);
+            assert_eq!(contents(&read("ktest-swap/memory.events").unwrap()), "max 2\noom 0\noom_kill 0\n");
+
+            TEST_SWAP.owner.store(ROOT_GROUP, Ordering::Relaxed);
+            uncharge(group, PageKind::Anon, 8);
+            rmdir("ktest-swap", &cred).unwrap();
+        }
+
+        fn files_check_input_and_privilege() {
+            let cred = admin();
+            let mut user = admin();
+            user.cap_effective.remove(Capability::SysAdmin);
+            mkdir("ktest-files", &cred).unwrap();
+
+            assert_eq!(write("ktest-files/cpu.weight", "0", &cred), Some(Err(Errno::EINVAL)));
+            assert_eq!(write("ktest-files/cpu.weight", "lots", &cred), Some(Err(Errno::EINVAL)));
+            assert_eq!(write("ktest-files/cpu.weight", "300", &user), Some(Err(Errno::EPERM)));
+            assert_eq!(write("ktest-files/cgroup.procs", "999999", &cred), Some(Err(Errno::ESRCH)));
+            assert_eq!(write("cpu.weight", "300", &cred), None);
+            assert!(read("ktest-files/nonexistent").is_none());
+            assert_eq!(mkdir("other", &user), Err(Errno::EPERM));
+
+            rmdir("ktest-files", &cred).unwrap();
+        }
+    }
+}
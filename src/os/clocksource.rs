@@ -0,0 +1,94 @@
+//! Clocksources: free-running counters that give the time since boot in nanoseconds.
+//!
+//! Each usable counter registers a [`Clocksource`] with a rating; the highest-rated one is
+//! used by [`now_ns`]. The architecture's calibrated counter (the TSC on x86) is always
+//! available, paravirtual clocks rate higher when the hypervisor provides them.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::os::arch::{Arch, Current, counter};
+use crate::os::errno::{Errno, KResult};
+
+/// A monotonic counter convertible to nanoseconds.
+pub trait Clocksource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Quality of the source; the highest-rated registered source wins. The architecture
+    /// counter rates 300 when it runs at a constant rate (an invariant TSC) and 100 otherwise.
+    fn rating(&self) -> u32;
+
+    /// Nanoseconds since an arbitrary fixed point (boot, for the built-in sources).
+    fn read_ns(&self) -> u64;
+}
+
+/// Maximum number of registered clocksources.
+const MAX_SOURCES: usize = 4;
+
+static mut SOURCES: [Option<&'static dyn Clocksource>; MAX_SOURCES] = [None; MAX_SOURCES];
+
+// Index of the selected source in SOURCES
+static CURRENT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The calibrated architecture counter.
+struct ArchCounter;
+
+impl Clocksource for ArchCounter {
+    fn name(&self) -> &'static str {
+        Current::COUNTER_NAME
+    }
+
+    fn rating(&self) -> u32 {
+        if Current::counter_is_stable() { 300 } else { 100 }
+    }
+
+    fn read_ns(&self) -> u64 {
+        match counter::ticks_per_ms() {
+            0 => 0,
+            rate => (counter::read() as u128 * 1_000_000 / rate as u128) as u64,
+        }
+    }
+}
+
+static ARCH_COUNTER: ArchCounter = ArchCounter;
+
+/// Registers the architecture counter. Called once at boot after it has been calibrated.
+pub fn init() {
+    register(&ARCH_COUNTER).expect("clocksource: no room for the architecture counter");
+}
+
+/// Registers a clocksource and switches to it if it outrates the current one.
+pub fn register(source: &'static dyn Clocksource) -> KResult<()> {
+    unsafe {
+        let sources = &raw mut SOURCES;
+
+        let index = (*sources).iter().position(|s| s.is_none()).ok_or(Errno::ENOSPC)?;
+        (*sources)[index] = Some(source);
+
+        let better = match current() {
+            Some(current) => source.rating() > current.rating(),
+            None => true,
+        };
+
+        if better {
+            CURRENT.store(index, Ordering::Release);
+            log::info!("clocksource: switched to {}", source.name());
+        }
+    }
+
+    Ok(())
+}
+
+/// The clocksource in use, if any has been registered.
+pub fn current() -> Option<&'static dyn Clocksource> {
+    let index = CURRENT.load(Ordering::Acquire);
+
+    unsafe {
+        let sources = &raw const SOURCES;
+        (*sources).get(index).copied().flatten()
+    }
+}
+
+/// Nanoseconds since boot according to the selected clocksource (0 before one is registered).
+pub fn now_ns() -> u64 {
+    current().map_or(0, |source| source.read_ns())
+}
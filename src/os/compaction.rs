@@ -0,0 +1,379 @@
+//! Page migration and memory compaction.
+//!
+//! After long uptimes free frames end up scattered between allocated ones, and requests for
+//! physically contiguous runs -- DMA buffers, huge pages -- fail although plenty of memory is
+//! free. Compaction fixes that by moving pages: a migration scanner takes the movable pages in
+//! the lower part of each usable region, a free scanner takes free frames from the top, and
+//! each page moves up into the highest free frame above it, leaving one free run at the bottom.
+//!
+//! Movable pages are the anonymous pages of user processes (data, heap and stack) that no
+//! other process shares, found by walking their page tables; anything else -- kernel memory,
+//! page tables, DMA buffers -- stays where it is. [`migrate_page`] moves one of them: the
+//! entry is replaced by a migration entry, an invalid entry naming the old frame, and shot
+//! down before the copy, so no CPU can write the old frame behind it; a fault on the entry
+//! meanwhile waits in [`handle_fault`] for the new one.
+//!
+//! [`alloc_contiguous`] is the entry point for contiguous allocations and compacts once before
+//! giving up. Compaction can also be requested by writing 1 to `vm.compact_memory`.
+
+use crate::os::arch::{Arch, Current};
+use crate::os::cow;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE, FrameAllocator};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::tlb;
+
+// Migration entries: valid bit and swap marker clear, bit 2 set, old frame from bit 12
+const VALID: u64 = 1 << 0;
+const SWAP_MARKER: u64 = 1 << 1;
+const MIGRATION_MARKER: u64 = 1 << 2;
+
+fn migration_entry(frame: u64) -> u64 {
+    frame | MIGRATION_MARKER
+}
+
+fn is_migration_entry(pte: u64) -> bool {
+    pte & (VALID | SWAP_MARKER | MIGRATION_MARKER) == MIGRATION_MARKER
+}
+
+// =========================================================================
+// Migration
+// =========================================================================
+
+// Moves the page `pte` maps to `new` and returns the frame it left, keeping every other bit of
+// the entry. `flush` runs once the migration entry is in place, before the copy.
+fn migrate(pte: &mut u64, new: u64, flush: impl FnOnce()) -> KResult<u64> {
+    let old = *pte;
+    if old & VALID == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let frame = Current::entry_address(old);
+    let flags = old & !Current::page_entry(frame, 0);
+
+    unsafe { core::ptr::write_volatile(pte, migration_entry(frame)) };
+    flush();
+
+    // Frames are identity-mapped
+    unsafe { core::ptr::copy_nonoverlapping(frame as *const u8, new as *mut u8, FRAME_SIZE as usize) };
+    unsafe { core::ptr::write_volatile(pte, Current::page_entry(new, flags)) };
+    Ok(frame)
+}
+
+/// Moves the page mapped at `virt` in `process`'s address space to the frame `new`, which the
+/// caller has allocated, and returns the frame it left for the caller to free. Fails with
+/// `EFAULT` if no page table covers `virt` and `EINVAL` if nothing is mapped there.
+pub fn migrate_page(process: &Process, virt: u64, new: u64) -> KResult<u64> {
+    let root = process.page_table_root as u64;
+    let pte = Current::leaf_entry(root, virt).ok_or(Errno::EFAULT)?;
+
+    migrate(unsafe { &mut *pte }, new, || tlb::shootdown_page(Some(root), virt))
+}
+
+/// Page fault hook: if the fault at `virt` hit a page being migrated, waits until the move is
+/// done and returns true, so the access can simply be retried.
+pub fn handle_fault(process: &Process, virt: u64) -> bool {
+    let Some(pte) = Current::leaf_entry(process.page_table_root as u64, virt) else {
+        return false;
+    };
+
+    if !is_migration_entry(unsafe { core::ptr::read_volatile(pte) }) {
+        return false;
+    }
+
+    while is_migration_entry(unsafe { core::ptr::read_volatile(pte) }) {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// =========================================================================
+// Compaction
+// =========================================================================
+
+/// Outcome of a compaction pass.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    /// Pages moved.
+    pub migrated: u64,
+
+    /// Pages that had a free frame to go to but could not be moved.
+    pub failed: u64,
+}
+
+// One region's scanners: pages below `free` move into the free frames the free scanner finds
+// walking down from it
+struct Compactor<'a> {
+    allocator: &'a dyn FrameAllocator,
+    start: u64,
+    free: u64,
+    stats: CompactStats,
+}
+
+impl<'a> Compactor<'a> {
+    fn new(allocator: &'a dyn FrameAllocator, start: u64, end: u64) -> Self {
+        Compactor { allocator, start, free: end, stats: CompactStats::default() }
+    }
+
+    // Claims the highest free frame above `frame` the free scanner has not passed yet
+    fn claim_above(&mut self, frame: u64) -> Option<u64> {
+        while self.free > frame + FRAME_SIZE {
+            self.free -= FRAME_SIZE;
+            if self.allocator.is_free(self.free) && self.allocator.alloc_frame_at(self.free) {
+                return Some(self.free);
+            }
+        }
+        None
+    }
+
+    // Moves the page `pte` maps up, if it lies in the region below the free scanner and a
+    // free frame is left above it
+    fn visit(&mut self, pte: &mut u64, flush: impl FnOnce()) {
+        if *pte & VALID == 0 {
+            return;
+        }
+
+        // Another address space still maps a shared frame where it is
+        let frame = Current::entry_address(*pte);
+        if frame < self.start || frame >= self.free || cow::is_shared(frame) {
+            return;
+        }
+
+        let Some(new) = self.claim_above(frame) else {
+            return;
+        };
+
+        match migrate(pte, new, flush) {
+            Ok(old) => {
+                self.allocator.free_frame(old);
+                self.stats.migrated += 1;
+            }
+            Err(_) => {
+                self.allocator.free_frame(new);
+                self.stats.failed += 1;
+            }
+        }
+    }
+}
+
+/// Compacts every usable memory region and returns what was moved. Does nothing before the
+/// frame allocator is set up.
+pub fn compact() -> CompactStats {
+    let mut total = CompactStats::default();
+    let Some(allocator) = memory::frame_allocator() else {
+        return total;
+    };
+
+    for region in memory::get_usable_memory_regions() {
+        let mut compactor = Compactor::new(allocator, region.start, region.start + region.size);
+
+        ptable::for_each(|process| {
+            let root = process.page_table_root as u64;
+            memory::for_each_anonymous_entry(process, |virt, pte| {
+                compactor.visit(pte, || tlb::shootdown_page(Some(root), virt));
+                true
+            });
+        });
+
+        total.migrated += compactor.stats.migrated;
+        total.failed += compactor.stats.failed;
+    }
+
+    log::info!("compaction: migrated {} pages, {} failed", total.migrated, total.failed);
+    total
+}
+
+// =========================================================================
+// Contiguous allocation
+// =========================================================================
+
+// Claims `frames` free frames starting at an `align` boundary within [start, end)
+fn claim_run(allocator: &dyn FrameAllocator, start: u64, end: u64, frames: u64, align: u64) -> Option<u64> {
+    let len = frames * FRAME_SIZE;
+    let mut base = start.next_multiple_of(align);
+
+    while base + len <= end {
+        // Skip past the last used frame in the candidate run
+        match (0..frames).rev().map(|i| base + i * FRAME_SIZE).find(|&frame| !allocator.is_free(frame)) {
+            Some(used) => base = (used + FRAME_SIZE).next_multiple_of(align),
+            None => {
+                let claimed = (0..frames).take_while(|&i| allocator.alloc_frame_at(base + i * FRAME_SIZE)).count() as u64;
+                if claimed == frames {
+                    return Some(base);
+                }
+
+                // Lost a frame to a concurrent allocation; give the rest back and move on
+                (0..claimed).for_each(|i| allocator.free_frame(base + i * FRAME_SIZE));
+                base += align;
+            }
+        }
+    }
+
+    None
+}
+
+/// Allocates `frames` physically contiguous frames starting at a multiple of `align` bytes (a
+/// power of two, at least a frame), for DMA buffers and huge pages, and returns the physical
+/// address of the first. If no free run exists, compacts memory and tries once more.
+pub fn alloc_contiguous(frames: u64, align: u64) -> Option<u64> {
+    try_alloc_contiguous(frames, align).or_else(|| {
+        compact();
+        try_alloc_contiguous(frames, align)
+    })
+}
+
+/// Like [`alloc_contiguous`], but gives up rather than compacting, which walks the process
+/// table; for callers that already hold it, such as the page fault path.
+pub fn try_alloc_contiguous(frames: u64, align: u64) -> Option<u64> {
+    let allocator = memory::frame_allocator()?;
+    let align = align.max(FRAME_SIZE);
+
+    allocator.alloc_contiguous(frames, align).or_else(|| {
+        memory::get_usable_memory_regions()
+            .iter()
+            .find_map(|region| claim_run(allocator, region.start, region.start + region.size, frames, align))
+    })
+}
+
+/// Frees a run allocated by [`alloc_contiguous`].
+pub fn free_contiguous(addr: u64, frames: u64) {
+    if let Some(allocator) = memory::frame_allocator() {
+        (0..frames).for_each(|i| allocator.free_frame(addr + i * FRAME_SIZE));
+    }
+}
+
+pub mod ktests {
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+    use crate::os::numa::Placement;
+
+    const FRAMES: usize = 8;
+
+    #[repr(C, align(32768))]
+    struct Frames([[u8; FRAME_SIZE as usize]; FRAMES]);
+
+    static mut TEST_FRAMES: Frames = Frames([[0; FRAME_SIZE as usize]; FRAMES]);
+
+    fn frames() -> &'static mut [[u8; FRAME_SIZE as usize]; FRAMES] {
+        unsafe {
+            let frames = &raw mut TEST_FRAMES;
+            &mut (*frames).0
+        }
+    }
+
+    fn base() -> u64 {
+        frames().as_ptr() as u64
+    }
+
+    fn frame(index: usize) -> u64 {
+        base() + index as u64 * FRAME_SIZE
+    }
+
+    // Hands out the eight test frames, bit i of `free` set while frame i is free
+    struct TestAllocator {
+        free: AtomicU8,
+    }
+
+    impl TestAllocator {
+        fn index(addr: u64) -> Option<u32> {
+            let index = addr.checked_sub(base())? / FRAME_SIZE;
+            (index < FRAMES as u64).then_some(index as u32)
+        }
+    }
+
+    impl FrameAllocator for TestAllocator {
+        fn alloc_frame(&self, _placement: Placement) -> Option<u64> {
+            let free = self.free.load(Ordering::Relaxed);
+            let index = free.trailing_zeros();
+            (free != 0 && self.alloc_frame_at(frame(index as usize))).then(|| frame(index as usize))
+        }
+
+        fn free_frame(&self, addr: u64) {
+            if let Some(index) = Self::index(addr) {
+                self.free.fetch_or(1 << index, Ordering::Relaxed);
+            }
+        }
+
+        fn free_frames(&self) -> usize {
+            self.free.load(Ordering::Relaxed).count_ones() as usize
+        }
+
+        fn is_free(&self, addr: u64) -> bool {
+            Self::index(addr).is_some_and(|index| self.free.load(Ordering::Relaxed) & (1 << index) != 0)
+        }
+
+        fn alloc_frame_at(&self, addr: u64) -> bool {
+            let Some(index) = Self::index(addr) else {
+                return false;
+            };
+            self.free.fetch_and(!(1 << index), Ordering::Relaxed) & (1 << index) != 0
+        }
+    }
+
+    fn entry(index: usize) -> u64 {
+        Current::page_entry(frame(index), Current::page_flags(true, false, true))
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn migration_keeps_contents_and_flags() {
+            frames()[0].fill(0x3c);
+            let mut pte = entry(0);
+            let mut flushed = false;
+
+            assert_eq!(migrate(&mut pte, frame(5), || flushed = true), Ok(frame(0)));
+            assert!(flushed);
+            assert_eq!(pte, entry(5));
+            assert!(frames()[5].iter().all(|&byte| byte == 0x3c));
+
+            let mut unmapped = 0;
+            assert_eq!(migrate(&mut unmapped, frame(6), || ()), Err(Errno::EINVAL));
+        }
+
+        fn migration_entries_are_recognised() {
+            assert!(is_migration_entry(migration_entry(frame(3))));
+            assert!(!is_migration_entry(entry(3)));
+            assert!(!is_migration_entry(0));
+            assert!(!is_migration_entry(migration_entry(frame(3)) | SWAP_MARKER));
+        }
+
+        fn compaction_leaves_a_free_run_at_the_bottom() {
+            // Frames 0, 2 and 4 hold pages, so the longest free run is 5-7
+            let allocator = TestAllocator { free: AtomicU8::new(0b1110_1010) };
+            let mut ptes = [entry(0), entry(2), entry(4)];
+            for (i, index) in [0, 2, 4].into_iter().enumerate() {
+                frames()[index].fill(i as u8 + 1);
+            }
+            assert_eq!(claim_run(&allocator, base(), frame(FRAMES), 4, FRAME_SIZE), None);
+
+            let mut compactor = Compactor::new(&allocator, base(), frame(FRAMES));
+            for pte in &mut ptes {
+                compactor.visit(pte, || ());
+            }
+
+            assert_eq!(compactor.stats, CompactStats { migrated: 3, failed: 0 });
+            assert_eq!(ptes, [entry(7), entry(6), entry(5)]);
+            for (i, index) in [7, 6, 5].into_iter().enumerate() {
+                assert!(frames()[index].iter().all(|&byte| byte == i as u8 + 1));
+            }
+
+            // Frames 0-4 are free now, and a 16 KiB-aligned run of 4 fits at the bottom
+            assert_eq!(allocator.free.load(Ordering::Relaxed), 0b0001_1111);
+            assert_eq!(claim_run(&allocator, base(), frame(FRAMES), 4, 4 * FRAME_SIZE), Some(base()));
+            assert_eq!(allocator.free_frames(), 1);
+        }
+
+        fn pages_at_the_top_stay_put() {
+            let allocator = TestAllocator { free: AtomicU8::new(0b0111_1111) };
+            let mut pte = entry(7);
+
+            let mut compactor = Compactor::new(&allocator, base(), frame(FRAMES));
+            compactor.visit(&mut pte, || ());
+
+            assert_eq!(pte, entry(7));
+            assert_eq!(compactor.stats, CompactStats::default());
+            assert_eq!(allocator.free_frames(), 7);
+        }
+    }
+}
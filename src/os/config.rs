@@ -0,0 +1,135 @@
+//! Build configuration: which optional subsystems this kernel was compiled with.
+//!
+//! Optional subsystems are Cargo features (see `Cargo.toml`; `smp` and `virtio` are on by
+//! default), so a small build such as a self-test image can leave out what it does not need
+//! with `--no-default-features`. The code of each subsystem is gated with
+//! `cfg(feature = ...)`. This module lists the same choices for whatever reports them at run
+//! time: the boot log and `/proc/config`.
+
+use core::fmt::{self, Write};
+
+/// A build option and whether this kernel has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigOption {
+    /// Cargo feature name.
+    pub name: &'static str,
+    pub enabled: bool,
+    pub help: &'static str,
+}
+
+/// Multiprocessor support (the `smp` feature); without it [`MAX_CPUS`](super::percpu::MAX_CPUS) is 1.
+pub const SMP: bool = cfg!(feature = "smp");
+
+/// virtio drivers (the `virtio` feature), which exist only on x86_64.
+pub const VIRTIO: bool = cfg!(all(feature = "virtio", target_arch = "x86_64"));
+
+/// Shadow-memory sanitizer for the kernel heap (the `kasan` feature).
+pub const KASAN: bool = cfg!(feature = "kasan");
+
+/// Leak tracking for heap and slab allocations (the `leakcheck` feature).
+pub const LEAKCHECK: bool = cfg!(feature = "leakcheck");
+
+/// Function tracing at `trace_fn!` call sites (the `ftrace` feature).
+pub const FTRACE: bool = cfg!(feature = "ftrace");
+
+/// Booting straight into the self-test suite (the `selftest` feature).
+pub const SELFTEST: bool = cfg!(feature = "selftest");
+
+/// Every build option, in the order `Cargo.toml` lists them.
+pub const OPTIONS: &[ConfigOption] = &[
+    ConfigOption { name: "smp", enabled: SMP, help: "multiprocessor support" },
+    ConfigOption { name: "virtio", enabled: VIRTIO, help: "virtio balloon and 9p drivers" },
+    ConfigOption { name: "selftest", enabled: SELFTEST, help: "boot into the self-test suite" },
+    ConfigOption { name: "kasan", enabled: KASAN, help: "kernel heap sanitizer" },
+    ConfigOption { name: "leakcheck", enabled: LEAKCHECK, help: "allocation leak tracking" },
+    ConfigOption { name: "ftrace", enabled: FTRACE, help: "function tracing" },
+];
+
+/// Whether option `name` is built in, or `None` if there is no such option.
+pub fn is_enabled(name: &str) -> Option<bool> {
+    OPTIONS.iter().find(|option| option.name == name).map(|option| option.enabled)
+}
+
+/// Writes the configuration as `CONFIG_<NAME>=y` lines, with `# CONFIG_<NAME> is not set` for
+/// options left out, the format of Linux's `/proc/config.gz`.
+pub fn write_config(w: &mut impl Write) -> fmt::Result {
+    for option in OPTIONS {
+        let name = ConfigName(option.name);
+        if option.enabled {
+            writeln!(w, "CONFIG_{}=y", name)?;
+        } else {
+            writeln!(w, "# CONFIG_{} is not set", name)?;
+        }
+    }
+    Ok(())
+}
+
+// An option name as a config symbol: upper case
+struct ConfigName(&'static str);
+
+impl fmt::Display for ConfigName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.chars().try_for_each(|c| f.write_char(c.to_ascii_uppercase()))
+    }
+}
+
+/// Logs the options this kernel was built with. Called once at boot.
+pub fn log() {
+    struct Enabled;
+
+    impl fmt::Display for Enabled {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for option in OPTIONS {
+                write!(f, " {}{}", if option.enabled { '+' } else { '-' }, option.name)?;
+            }
+            Ok(())
+        }
+    }
+
+    log::info!("config:{}", Enabled);
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Collects formatted output into a fixed buffer
+    struct Buffer {
+        bytes: [u8; 512],
+        len: usize,
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn option_names_are_unique() {
+            for (i, option) in OPTIONS.iter().enumerate() {
+                assert!(OPTIONS[i + 1..].iter().all(|other| other.name != option.name));
+            }
+        }
+
+        fn options_match_the_build() {
+            assert_eq!(is_enabled("smp"), Some(cfg!(feature = "smp")));
+            assert_eq!(is_enabled("kasan"), Some(cfg!(feature = "kasan")));
+            assert_eq!(is_enabled("selftest"), Some(cfg!(feature = "selftest")));
+            assert_eq!(is_enabled("usb"), None);
+            assert_eq!(crate::os::percpu::MAX_CPUS > 1, SMP);
+        }
+
+        fn config_lists_every_option() {
+            let mut buffer = Buffer { bytes: [0; 512], len: 0 };
+            write_config(&mut buffer).unwrap();
+            let text = core::str::from_utf8(&buffer.bytes[..buffer.len]).unwrap();
+
+            assert_eq!(text.lines().count(), OPTIONS.len());
+            assert_eq!(text.contains("CONFIG_SELFTEST=y"), SELFTEST);
+            assert_eq!(text.contains("# CONFIG_KASAN is not set"), !KASAN);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Framebuffer text console.
+//!
+//! The firmware's text output goes away with boot services, so the kernel proper draws its own
+//! text into the framebuffer the firmware's graphics output protocol (GOP) set up, which stays
+//! where it is after the handoff. [`init`] records its geometry and pixel layout while the
+//! protocol can still be asked.
+//!
+//! Text goes into a grid of cells, each a glyph of the built-in [`font`] with every row drawn
+//! twice. Lines wrap at the right edge, and once the cursor runs off the bottom the screen
+//! scrolls up by a line. [`set_color`] picks the colours of what is written next.
+//!
+//! [`print!`](crate::print) and [`println!`](crate::println) write here, or to the serial
+//! console on machines without a usable framebuffer.
+
+pub mod font;
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use uefi::Handle;
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat};
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+use uefi::table::{Boot, SystemTable};
+
+use crate::os::arch;
+use crate::os::serial;
+
+/// Width of a character cell in pixels.
+pub const CELL_WIDTH: usize = font::WIDTH;
+
+/// Height of a character cell in pixels.
+pub const CELL_HEIGHT: usize = font::HEIGHT * 2;
+
+// Tab stops are this many columns apart
+const TAB_WIDTH: usize = 8;
+
+/// A colour, 8 bits per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0x00, 0x00, 0x00);
+    pub const LIGHT_GRAY: Color = Color::rgb(0xaa, 0xaa, 0xaa);
+    pub const WHITE: Color = Color::rgb(0xff, 0xff, 0xff);
+    pub const RED: Color = Color::rgb(0xff, 0x55, 0x55);
+    pub const GREEN: Color = Color::rgb(0x55, 0xff, 0x55);
+    pub const YELLOW: Color = Color::rgb(0xff, 0xff, 0x55);
+    pub const BLUE: Color = Color::rgb(0x55, 0x55, 0xff);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+}
+
+/// How a colour is laid out in a 32-bit pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelLayout {
+    /// Red in the lowest byte, then green and blue.
+    Rgb,
+
+    /// Blue in the lowest byte, then green and red.
+    Bgr,
+
+    /// Each channel where its mask says.
+    Bitmask { red: u32, green: u32, blue: u32 },
+}
+
+impl PixelLayout {
+    /// The pixel value of `color`.
+    pub fn pixel(self, color: Color) -> u32 {
+        let Color { r, g, b } = color;
+
+        match self {
+            PixelLayout::Rgb => r as u32 | (g as u32) << 8 | (b as u32) << 16,
+            PixelLayout::Bgr => b as u32 | (g as u32) << 8 | (r as u32) << 16,
+            PixelLayout::Bitmask { red, green, blue } => channel(r, red) | channel(g, green) | channel(b, blue),
+        }
+    }
+}
+
+// `value` scaled to the width of `mask` and moved into place
+fn channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+
+    let shift = mask.trailing_zeros();
+    let bits = (mask >> shift).count_ones().min(8);
+    ((value as u32) >> (8 - bits)) << shift & mask
+}
+
+/// A linear framebuffer of 32-bit pixels.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    pub base: *mut u32,
+    pub width: usize,
+    pub height: usize,
+
+    /// Pixels from the start of one scanline to the next, at least `width`.
+    pub stride: usize,
+
+    pub layout: PixelLayout,
+}
+
+impl Framebuffer {
+    fn write(&self, x: usize, y: usize, pixel: u32) {
+        unsafe { ptr::write_volatile(self.base.add(y * self.stride + x), pixel) };
+    }
+
+    fn fill(&self, y: usize, rows: usize, pixel: u32) {
+        for y in y..y + rows {
+            (0..self.width).for_each(|x| self.write(x, y, pixel));
+        }
+    }
+}
+
+/// A text console on a framebuffer.
+pub struct Console {
+    framebuffer: Framebuffer,
+    columns: usize,
+    rows: usize,
+
+    // Cursor, the cell written next
+    column: usize,
+    row: usize,
+
+    foreground: Color,
+    background: Color,
+}
+
+impl Console {
+    /// A console covering `framebuffer`, cursor at the top left. The screen is left as it is
+    /// until [`Console::clear`].
+    pub fn new(framebuffer: Framebuffer) -> Self {
+        Console {
+            framebuffer,
+            columns: framebuffer.width / CELL_WIDTH,
+            rows: framebuffer.height / CELL_HEIGHT,
+            column: 0,
+            row: 0,
+            foreground: Color::LIGHT_GRAY,
+            background: Color::BLACK,
+        }
+    }
+
+    /// Size of the text grid, in columns and rows.
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    /// Where the next character goes, as column and row.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    /// Colours of the characters written from now on.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// Fills the screen with the background colour and moves the cursor to the top left.
+    pub fn clear(&mut self) {
+        let background = self.framebuffer.layout.pixel(self.background);
+        self.framebuffer.fill(0, self.framebuffer.height, background);
+        (self.column, self.row) = (0, 0);
+    }
+
+    /// Writes `c` at the cursor and advances it. Handles newline, carriage return, tab and
+    /// backspace; other characters without a glyph are drawn as `?`.
+    pub fn write_char(&mut self, c: char) {
+        if self.columns == 0 || self.rows == 0 {
+            return;
+        }
+
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.column = 0,
+            '\t' => {
+                let stop = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < stop.min(self.columns) {
+                    self.write_char(' ');
+                }
+            }
+            '\x08' => self.column = self.column.saturating_sub(1),
+            _ => {
+                if self.column == self.columns {
+                    self.new_line();
+                }
+                self.draw(self.column, self.row, c);
+                self.column += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    // Moves every line up by one and clears the bottom one
+    fn scroll(&mut self) {
+        let framebuffer = &self.framebuffer;
+        let line = CELL_HEIGHT * framebuffer.stride;
+        let text = self.rows * line;
+
+        unsafe { ptr::copy(framebuffer.base.add(line), framebuffer.base, text - line) };
+        framebuffer.fill((self.rows - 1) * CELL_HEIGHT, CELL_HEIGHT, framebuffer.layout.pixel(self.background));
+    }
+
+    fn draw(&self, column: usize, row: usize, c: char) {
+        let glyph = font::glyph(c);
+        let layout = self.framebuffer.layout;
+        let (foreground, background) = (layout.pixel(self.foreground), layout.pixel(self.background));
+        let (left, top) = (column * CELL_WIDTH, row * CELL_HEIGHT);
+
+        for y in 0..CELL_HEIGHT {
+            let bits = glyph[y * font::HEIGHT / CELL_HEIGHT];
+
+            for x in 0..CELL_WIDTH {
+                let pixel = if bits & (0x80 >> x) != 0 { foreground } else { background };
+                self.framebuffer.write(left + x, top + y, pixel);
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.chars().for_each(|c| self.write_char(c));
+        Ok(())
+    }
+}
+
+static mut CONSOLE: Option<Console> = None;
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the console, if there is one, holding its lock with interrupts masked
+fn locked<R>(f: impl FnOnce(&mut Console) -> R) -> Option<R> {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let console = &raw mut CONSOLE;
+            (*console).as_mut().map(f)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+/// Takes over the GOP framebuffer as the console and clears it. Must be called before boot
+/// services are exited, and after [`serial::detach_firmware_console`]: the firmware's text
+/// output draws on the same screen. Without a framebuffer to draw on (no GOP, or one that
+/// only supports `Blt`) printing goes to the serial console.
+pub fn init(image: Handle, system_table: &SystemTable<Boot>) {
+    crate::trace_fn!();
+
+    let bt = system_table.boot_services();
+
+    let Ok(handle) = bt.get_handle_for_protocol::<GraphicsOutput>() else {
+        log::warn!("console: no graphics output, printing to serial");
+        return;
+    };
+
+    // Shared, not exclusive: only the mode is needed, and the firmware console keeps working
+    // until boot services go
+    let params = OpenProtocolParams { handle, agent: image, controller: None };
+    let gop = unsafe { bt.open_protocol::<GraphicsOutput>(params, OpenProtocolAttributes::GetProtocol) };
+    let Ok(mut gop) = gop else {
+        log::warn!("console: cannot open graphics output, printing to serial");
+        return;
+    };
+
+    let info = gop.current_mode_info();
+    let layout = match (info.pixel_format(), info.pixel_bitmask()) {
+        (PixelFormat::Rgb, _) => PixelLayout::Rgb,
+        (PixelFormat::Bgr, _) => PixelLayout::Bgr,
+        (PixelFormat::Bitmask, Some(mask)) => {
+            PixelLayout::Bitmask { red: mask.red, green: mask.green, blue: mask.blue }
+        }
+        _ => {
+            log::warn!("console: framebuffer cannot be drawn on directly, printing to serial");
+            return;
+        }
+    };
+
+    let (width, height) = info.resolution();
+    let framebuffer =
+        Framebuffer { base: gop.frame_buffer().as_mut_ptr().cast(), width, height, stride: info.stride(), layout };
+
+    let mut console = Console::new(framebuffer);
+    console.clear();
+    let (columns, rows) = console.size();
+
+    unsafe {
+        let slot = &raw mut CONSOLE;
+        *slot = Some(console);
+    }
+
+    log::info!("console: {}x{} framebuffer at {:p}, {}x{} characters", width, height, framebuffer.base, columns, rows);
+}
+
+/// Returns `true` if printing goes to the framebuffer.
+pub fn is_available() -> bool {
+    locked(|_| ()).is_some()
+}
+
+/// Colours of the text printed from now on.
+pub fn set_color(foreground: Color, background: Color) {
+    locked(|console| console.set_color(foreground, background));
+}
+
+/// Clears the screen.
+pub fn clear() {
+    locked(Console::clear);
+}
+
+// Internal function for the print macros.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if locked(|console| _ = console.write_fmt(args)).is_none() {
+        serial::_print(args);
+    }
+}
+
+/// Prints a panic report to the console, taking its lock even if it is held: the panicking
+/// CPU may have been holding it, and every other CPU is stopped. Returns `false` when there
+/// is no framebuffer.
+pub fn panic_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    LOCK.store(false, Ordering::Release);
+    locked(|console| _ = console.write_fmt(args)).is_some()
+}
+
+/// Prints to the console.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::os::console::_print(core::format_args!($($arg)*)));
+}
+
+/// Prints to the console, with a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::os::console::_print(core::format_args!("{}\n", core::format_args!($($arg)*))));
+}
+
+pub mod ktests {
+    use super::*;
+
+    // 4x2 cells
+    const WIDTH: usize = 4 * CELL_WIDTH;
+    const HEIGHT: usize = 2 * CELL_HEIGHT;
+    const STRIDE: usize = WIDTH + 3;
+
+    static mut PIXELS: [u32; STRIDE * HEIGHT] = [0; STRIDE * HEIGHT];
+
+    fn test_console() -> Console {
+        unsafe {
+            let pixels = &raw mut PIXELS;
+            (*pixels).fill(0xdead_beef);
+            Console::new(Framebuffer {
+                base: (*pixels).as_mut_ptr(),
+                width: WIDTH,
+                height: HEIGHT,
+                stride: STRIDE,
+                layout: PixelLayout::Rgb,
+            })
+        }
+    }
+
+    fn pixel(x: usize, y: usize) -> u32 {
+        unsafe {
+            let pixels = &raw const PIXELS;
+            (*pixels)[y * STRIDE + x]
+        }
+    }
+
+    // Whether cell (column, row) shows `c` in `color` on black
+    fn shows(column: usize, row: usize, c: char, color: Color) -> bool {
+        let (on, off) = (PixelLayout::Rgb.pixel(color), PixelLayout::Rgb.pixel(Color::BLACK));
+
+        (0..CELL_HEIGHT).all(|y| {
+            (0..CELL_WIDTH).all(|x| {
+                let lit = font::glyph(c)[y / 2] & (0x80 >> x) != 0;
+                pixel(column * CELL_WIDTH + x, row * CELL_HEIGHT + y) == if lit { on } else { off }
+            })
+        })
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn colors_follow_the_pixel_layout() {
+            let color = Color::rgb(0x12, 0x34, 0x56);
+            assert_eq!(PixelLayout::Rgb.pixel(color), 0x56_34_12);
+            assert_eq!(PixelLayout::Bgr.pixel(color), 0x12_34_56);
+
+            // 5-6-5: channels lose their low bits
+            let rgb565 = PixelLayout::Bitmask { red: 0xf800, green: 0x07e0, blue: 0x001f };
+            assert_eq!(rgb565.pixel(Color::WHITE), 0xffff);
+            assert_eq!(rgb565.pixel(color), (0x12 >> 3) << 11 | (0x34 >> 2) << 5 | 0x56 >> 3);
+        }
+
+        fn text_wraps_and_scrolls() {
+            let mut console = test_console();
+            console.clear();
+            assert_eq!(console.size(), (4, 2));
+
+            // The padding past the visible width is left alone
+            assert_eq!(pixel(WIDTH, 0), 0xdead_beef);
+
+            console.set_color(Color::GREEN, Color::BLACK);
+            fmt::Write::write_str(&mut console, "abcde").unwrap();
+            assert_eq!(console.cursor(), (1, 1));
+            assert!(shows(0, 0, 'a', Color::GREEN) && shows(3, 0, 'd', Color::GREEN) && shows(0, 1, 'e', Color::GREEN));
+
+            // A newline on the last row scrolls the text up and clears the row
+            fmt::Write::write_str(&mut console, "\n").unwrap();
+            assert_eq!(console.cursor(), (0, 1));
+            assert!(shows(0, 0, 'e', Color::GREEN) && shows(0, 1, ' ', Color::GREEN));
+
+            // So does wrapping there; the tab pads to the end of the line
+            fmt::Write::write_str(&mut console, "f\tg").unwrap();
+            assert_eq!(console.cursor(), (1, 1));
+            assert!(shows(0, 0, 'f', Color::GREEN) && shows(3, 0, ' ', Color::GREEN) && shows(0, 1, 'g', Color::GREEN));
+        }
+    }
+}
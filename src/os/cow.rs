@@ -0,0 +1,255 @@
+//! Copy-on-write sharing of anonymous frames.
+//!
+//! `fork` gives the child the parent's image, data, heap and stack by mapping the same frames
+//! in both address spaces rather than copying them, with write access taken away on both
+//! sides. The first write to such a page faults into [`handle_fault`], which gives the writer
+//! a copy of its own -- or, if every other mapping is gone, simply gives write access back.
+//! Read-only image pages are shared the same way and never copied.
+//!
+//! A frame's mappings are counted in a hash table keyed by frame address, which only holds
+//! the frames mapped more than once; a frame it does not list has a single mapping. The table
+//! is a fixed size: when it fills up, `fork` copies pages as it used to. Code freeing an
+//! anonymous frame goes through [`release_frame`], which only frees it with its last mapping.
+//! Swap and compaction leave shared frames alone, since either would change one mapping of
+//! a frame and not the others.
+//!
+//! There is no software bit marking an entry copy-on-write: a write fault on a present,
+//! read-only page in a range the process may write -- data, heap and stack are always
+//! writable -- can only come from sharing. Each process stays charged for a shared page as if
+//! it had a copy, so taking the copy never fails on the group's limit.
+
+use crate::os::arch::{Arch, Current};
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::numa::Placement;
+use crate::os::process::Process;
+use crate::os::sync::SpinLock;
+use crate::os::tlb;
+
+/// Most frames shared at once.
+pub const MAX_SHARED_FRAMES: usize = SLOTS / 4 * 3;
+
+// Hash table slots, a power of two; kept at most three quarters full so probes stay short
+const SLOT_BITS: u32 = 14;
+const SLOTS: usize = 1 << SLOT_BITS;
+
+// Valid bit of a leaf entry, bit 0 on every port
+const VALID: u64 = 1 << 0;
+
+// Open addressing with linear probing: each used slot holds a frame (never 0, which the frame
+// allocator does not hand out) and its number of mappings, at least 2
+struct Shares {
+    slots: [(u64, u32); SLOTS],
+    used: usize,
+}
+
+static SHARES: SpinLock<Shares> = SpinLock::new(Shares { slots: [(0, 0); SLOTS], used: 0 });
+
+fn home(frame: u64) -> usize {
+    ((frame / FRAME_SIZE).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - SLOT_BITS)) as usize
+}
+
+impl Shares {
+    // The slot holding `frame`, or the free slot where it would go
+    fn find(&self, frame: u64) -> Result<usize, usize> {
+        let mut index = home(frame);
+        loop {
+            match self.slots[index].0 {
+                0 => return Err(index),
+                other if other == frame => return Ok(index),
+                _ => index = (index + 1) % SLOTS,
+            }
+        }
+    }
+
+    // Empties slot `hole`, moving later entries of the same probe run back so none is cut off
+    // from its home slot
+    fn remove(&mut self, mut hole: usize) {
+        self.slots[hole] = (0, 0);
+        self.used -= 1;
+
+        let mut index = hole;
+        loop {
+            index = (index + 1) % SLOTS;
+            let frame = self.slots[index].0;
+            if frame == 0 {
+                return;
+            }
+
+            // The entry may fill the hole if the hole lies between its home and where it is
+            let from_home = (index + SLOTS - home(frame)) % SLOTS;
+            let from_hole = (index + SLOTS - hole) % SLOTS;
+            if from_home >= from_hole {
+                self.slots[hole] = self.slots[index];
+                self.slots[index] = (0, 0);
+                hole = index;
+            }
+        }
+    }
+}
+
+/// Records one more mapping of `frame`. Returns false, recording nothing, if the frame was
+/// mapped once and the table has no room for it.
+pub fn share(frame: u64) -> bool {
+    SHARES.with(|shares| match shares.find(frame) {
+        Ok(index) => {
+            shares.slots[index].1 += 1;
+            true
+        }
+        Err(_) if shares.used == MAX_SHARED_FRAMES => false,
+        Err(index) => {
+            shares.slots[index] = (frame, 2);
+            shares.used += 1;
+            true
+        }
+    })
+}
+
+/// Whether `frame` is mapped more than once.
+pub fn is_shared(frame: u64) -> bool {
+    SHARES.with(|shares| shares.find(frame).is_ok())
+}
+
+/// Records one mapping of `frame` fewer; returns whether it was the last.
+pub fn unshare(frame: u64) -> bool {
+    SHARES.with(|shares| match shares.find(frame) {
+        Ok(index) if shares.slots[index].1 > 2 => {
+            shares.slots[index].1 -= 1;
+            false
+        }
+        Ok(index) => {
+            shares.remove(index);
+            false
+        }
+        Err(_) => true,
+    })
+}
+
+/// Number of frames currently mapped more than once.
+pub fn shared_frames() -> usize {
+    SHARES.with(|shares| shares.used)
+}
+
+/// Drops one mapping of the anonymous frame `frame`, freeing it if that was the last. The
+/// caller uncharges the page from its group, as for a frame of its own.
+pub fn release_frame(frame: u64) {
+    if unshare(frame)
+        && let Some(allocator) = memory::frame_allocator()
+    {
+        allocator.free_frame(frame);
+    }
+}
+
+// Bits telling a writable user page from a read-only one, and their values in a writable one
+// (those of a read-only one are the rest of the mask)
+fn write_bits() -> (u64, u64) {
+    let (writable, read_only) = (Current::page_flags(true, false, true), Current::page_flags(false, false, true));
+    (writable ^ read_only, writable)
+}
+
+/// Whether the valid leaf entry `entry` allows writes.
+pub fn is_writable(entry: u64) -> bool {
+    let (mask, writable) = write_bits();
+    entry & mask == writable & mask
+}
+
+/// `entry` with write access taken away, everything else kept.
+pub fn write_protect(entry: u64) -> u64 {
+    let (mask, writable) = write_bits();
+    (entry & !mask) | ((writable & mask) ^ mask)
+}
+
+/// `entry` with write access given back.
+pub fn make_writable(entry: u64) -> u64 {
+    let (mask, writable) = write_bits();
+    (entry & !mask) | (writable & mask)
+}
+
+// Whether `virt` lies in `process`'s data, heap or stack
+fn in_anonymous_range(process: &Process, virt: u64) -> bool {
+    memory::anonymous_ranges(process).iter().any(|&(start, end)| start <= virt && virt < end)
+}
+
+/// Page fault hook: resolves a write fault at `virt` on a page of `process`'s data, heap or
+/// stack that is read-only because `fork` shared it, returning whether it was one. The last
+/// mapping of a frame gets write access back; any other gets a copy, which fails with
+/// `ENOMEM` if no frame is free.
+pub fn handle_fault(process: &Process, virt: u64, write: bool) -> KResult<bool> {
+    if !write || !in_anonymous_range(process, virt) {
+        return Ok(false);
+    }
+
+    let root = process.page_table_root as u64;
+    let Some(pte) = Current::leaf_entry(root, virt) else {
+        return Ok(false);
+    };
+    let pte = unsafe { &mut *pte };
+    let entry = *pte;
+    if entry & VALID == 0 || is_writable(entry) {
+        return Ok(false);
+    }
+
+    let page = virt & !(FRAME_SIZE - 1);
+    let frame = Current::entry_address(entry);
+    if !is_shared(frame) {
+        *pte = make_writable(entry);
+        Current::flush_tlb_page(page);
+        return Ok(true);
+    }
+
+    let copy = memory::frame_allocator().and_then(|allocator| allocator.alloc_frame(Placement::Local)).ok_or(Errno::ENOMEM)?;
+
+    // Frames are identity-mapped
+    unsafe { core::ptr::copy_nonoverlapping(frame as *const u8, copy as *mut u8, FRAME_SIZE as usize) };
+    *pte = make_writable(Current::page_entry(copy, entry & !Current::page_entry(frame, 0)));
+    tlb::shootdown_page(Some(root), page);
+
+    release_frame(frame);
+    Ok(true)
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn mappings_are_counted() {
+            let frame = 0x7654_3000;
+            assert!(!is_shared(frame));
+
+            assert!(share(frame));
+            assert!(share(frame));
+            assert!(is_shared(frame));
+
+            // Three mappings: the third release is the last
+            assert!(!unshare(frame));
+            assert!(!unshare(frame));
+            assert!(!is_shared(frame));
+            assert!(unshare(frame));
+        }
+
+        fn removal_keeps_colliding_frames() {
+            let used = shared_frames();
+            let frames = (1..=256u64).map(|i| 0x4000_0000 + i * FRAME_SIZE);
+
+            frames.clone().for_each(|frame| assert!(share(frame)));
+            frames.clone().step_by(2).for_each(|frame| assert!(!unshare(frame)));
+
+            for (i, frame) in frames.clone().enumerate() {
+                assert_eq!(is_shared(frame), i % 2 == 1);
+            }
+            frames.skip(1).step_by(2).for_each(|frame| assert!(!unshare(frame)));
+            assert_eq!(shared_frames(), used);
+        }
+
+        fn write_access_comes_and_goes() {
+            let entry = Current::page_entry(0x7654_3000, Current::page_flags(true, false, true));
+            assert!(is_writable(entry));
+
+            let protected = write_protect(entry);
+            assert!(!is_writable(protected));
+            assert_eq!(Current::entry_address(protected), 0x7654_3000);
+            assert_eq!(protected, Current::page_entry(0x7654_3000, Current::page_flags(false, false, true)));
+            assert_eq!(make_writable(protected), entry);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! CPU feature detection.
+//!
+//! CPUID is parsed once at boot into a [`Features`] snapshot. Code with optional hardware
+//! paths checks `cpu::features()` instead of probing CPUID itself, so leaves the CPU does not
+//! implement are never read and older CPUs get the fallback rather than a fault.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+
+/// Optional CPU capabilities the kernel knows how to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features {
+    /// Vendor identification string, e.g. `GenuineIntel`.
+    pub vendor: [u8; 12],
+
+    /// No-execute page bit (EFER.NXE).
+    pub nx: bool,
+
+    /// 1 GiB pages in the page directory pointer table.
+    pub pages_1g: bool,
+
+    /// Global pages (CR4.PGE).
+    pub global_pages: bool,
+
+    /// Local APIC present.
+    pub apic: bool,
+
+    /// x2APIC mode (MSR-based APIC access).
+    pub x2apic: bool,
+
+    /// RDRAND instruction.
+    pub rdrand: bool,
+
+    /// RDSEED instruction.
+    pub rdseed: bool,
+
+    /// FXSAVE/FXRSTOR.
+    pub fxsr: bool,
+
+    /// XSAVE/XRSTOR and XCR0.
+    pub xsave: bool,
+
+    /// XSAVEOPT.
+    pub xsaveopt: bool,
+
+    pub sse: bool,
+    pub sse2: bool,
+    pub avx: bool,
+    pub avx2: bool,
+
+    /// Supervisor mode execution prevention.
+    pub smep: bool,
+
+    /// Supervisor mode access prevention.
+    pub smap: bool,
+
+    /// SYSCALL/SYSRET.
+    pub syscall: bool,
+
+    /// TSC runs at a constant rate in all P-, C- and T-states.
+    pub invariant_tsc: bool,
+
+    /// APIC timer TSC-deadline mode (IA32_TSC_DEADLINE).
+    pub tsc_deadline: bool,
+
+    /// MONITOR/MWAIT instructions.
+    pub mwait: bool,
+
+    /// MWAIT sub-states per C-state (CPUID leaf 5 EDX: 4 bits each, C0 in the lowest nibble);
+    /// 0 if the CPU does not enumerate them.
+    pub mwait_substates: u32,
+}
+
+// Parsed on first use; nothing runs concurrently during early boot
+static mut FEATURES: Option<Features> = None;
+
+/// The features of the boot CPU.
+pub fn features() -> Features {
+    unsafe {
+        let features = &raw mut FEATURES;
+        *(*features).get_or_insert_with(detect)
+    }
+}
+
+/// Parses CPUID and logs the result. Called once at boot before anything consults [`features`].
+pub fn init() {
+    let f = features();
+    let vendor = core::str::from_utf8(&f.vendor).unwrap_or("?");
+
+    log::info!(
+        "cpu: {} nx={} 1g={} x2apic={} rdrand={} xsave={} avx={} invariant_tsc={} tsc_deadline={}",
+        vendor, f.nx, f.pages_1g, f.x2apic, f.rdrand, f.xsave, f.avx, f.invariant_tsc, f.tsc_deadline
+    );
+}
+
+// Basic and extended leaves are only read if the CPU reports them as implemented
+fn leaf(max: u32, leaf: u32, subleaf: u32) -> CpuidResult {
+    if leaf <= max {
+        __cpuid_count(leaf, subleaf)
+    } else {
+        CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 }
+    }
+}
+
+fn bit(value: u32, bit: u32) -> bool {
+    value & (1 << bit) != 0
+}
+
+fn detect() -> Features {
+    let basic = __cpuid(0);
+    let max_basic = basic.eax;
+    let max_extended = __cpuid(0x8000_0000).eax;
+
+    let mut vendor = [0u8; 12];
+    vendor[0..4].copy_from_slice(&basic.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&basic.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&basic.ecx.to_le_bytes());
+
+    let leaf1 = leaf(max_basic, 1, 0);
+    let leaf7 = leaf(max_basic, 7, 0);
+    let leaf5 = leaf(max_basic, 5, 0);
+    let leaf_d1 = leaf(max_basic, 0xd, 1);
+    let ext1 = leaf(max_extended, 0x8000_0001, 0);
+    let ext7 = leaf(max_extended, 0x8000_0007, 0);
+
+    Features {
+        vendor,
+        nx: bit(ext1.edx, 20),
+        pages_1g: bit(ext1.edx, 26),
+        global_pages: bit(leaf1.edx, 13),
+        apic: bit(leaf1.edx, 9),
+        x2apic: bit(leaf1.ecx, 21),
+        rdrand: bit(leaf1.ecx, 30),
+        rdseed: bit(leaf7.ebx, 18),
+        fxsr: bit(leaf1.edx, 24),
+        xsave: bit(leaf1.ecx, 26),
+        xsaveopt: bit(leaf_d1.eax, 0),
+        sse: bit(leaf1.edx, 25),
+        sse2: bit(leaf1.edx, 26),
+        avx: bit(leaf1.ecx, 28),
+        avx2: bit(leaf7.ebx, 5),
+        smep: bit(leaf7.ebx, 7),
+        smap: bit(leaf7.ebx, 20),
+        syscall: bit(ext1.edx, 11),
+        invariant_tsc: bit(ext7.edx, 8),
+        tsc_deadline: bit(leaf1.ecx, 24),
+        mwait: bit(leaf1.ecx, 3),
+        // ECX bit 0: the EDX sub-state enumeration is valid
+        mwait_substates: if bit(leaf5.ecx, 0) { leaf5.edx } else { 0 },
+    }
+}
@@ -0,0 +1,335 @@
+use crate::os::audit::{self, AuditKind};
+use crate::os::capability::{self, Capability, CapabilitySet};
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::uaccess;
+
+/// The superuser's user and group ID.
+pub const ROOT_ID: u32 = 0;
+
+/// Maximum number of supplementary groups per process.
+pub const NGROUPS_MAX: usize = 16;
+
+/// User and group identity of a process, following the POSIX real/effective/saved split.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    /// Real user ID: who started the process.
+    pub uid: u32,
+
+    /// Effective user ID: used for permission checks.
+    pub euid: u32,
+
+    /// Saved set-user-ID: lets a setuid program drop and regain its privileges.
+    pub suid: u32,
+
+    /// Real group ID.
+    pub gid: u32,
+
+    /// Effective group ID.
+    pub egid: u32,
+
+    /// Saved set-group-ID.
+    pub sgid: u32,
+
+    /// Supplementary group IDs; only the first `ngroups` entries are valid.
+    pub groups: [u32; NGROUPS_MAX],
+
+    /// Number of valid entries in `groups`.
+    pub ngroups: usize,
+
+    /// Capabilities checked at privileged operations.
+    pub cap_effective: CapabilitySet,
+
+    /// Upper limit of `cap_effective`; can only shrink.
+    pub cap_permitted: CapabilitySet,
+
+    /// Capabilities passed on to the next program across exec.
+    pub cap_inheritable: CapabilitySet,
+
+    /// Capabilities the process may ever hold again, even by exec'ing as root.
+    pub cap_bounding: CapabilitySet,
+}
+
+impl Credentials {
+    /// Credentials of the superuser, given to kernel processes and the first user process.
+    pub const ROOT: Credentials = Credentials::new(ROOT_ID, ROOT_ID);
+
+    /// Credentials for `uid`/`gid` with matching effective and saved IDs and no supplementary groups.
+    /// The superuser starts with every capability, everyone else with none.
+    pub const fn new(uid: u32, gid: u32) -> Self {
+        let caps = if uid == ROOT_ID { CapabilitySet::FULL } else { CapabilitySet::EMPTY };
+
+        Credentials {
+            uid,
+            euid: uid,
+            suid: uid,
+            gid,
+            egid: gid,
+            sgid: gid,
+            groups: [0; NGROUPS_MAX],
+            ngroups: 0,
+            cap_effective: caps,
+            cap_permitted: caps,
+            cap_inheritable: CapabilitySet::EMPTY,
+            cap_bounding: CapabilitySet::FULL,
+        }
+    }
+
+    /// Returns `true` if the effective user is the superuser.
+    pub fn is_root(&self) -> bool {
+        self.euid == ROOT_ID
+    }
+
+    /// Returns `true` if `cap` is in the effective capability set.
+    pub fn capable(&self, cap: Capability) -> bool {
+        self.cap_effective.contains(cap)
+    }
+
+    /// Returns `true` if `gid` is the effective group or one of the supplementary groups.
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.egid == gid || self.groups[..self.ngroups].contains(&gid)
+    }
+
+    /// The supplementary group list.
+    pub fn groups(&self) -> &[u32] {
+        &self.groups[..self.ngroups]
+    }
+}
+
+// =========================================================================
+// setuid / setgid family
+// =========================================================================
+
+/// `setuid(2)`: a process with `CAP_SETUID` sets all three user IDs; anyone else may only switch
+/// the effective ID to their real or saved ID. Capabilities are adjusted to the new IDs.
+pub fn setuid(process: &mut Process, uid: u32) -> KResult<()> {
+    let cred = &mut process.cred;
+    let old_euid = cred.euid;
+
+    if cred.capable(Capability::SetUid) {
+        cred.uid = uid;
+        cred.euid = uid;
+        cred.suid = uid;
+    } else if uid == cred.uid || uid == cred.suid {
+        cred.euid = uid;
+    } else {
+        return Err(Errno::EPERM);
+    }
+
+    capability::on_uid_change(cred);
+
+    if cred.euid != old_euid {
+        audit::record_cred(AuditKind::SetUid, process.pid, cred, [old_euid as u64, cred.euid as u64]);
+    }
+
+    Ok(())
+}
+
+/// `setgid(2)`: same rules as [`setuid`] with `CAP_SETGID`, applied to the group IDs.
+pub fn setgid(process: &mut Process, gid: u32) -> KResult<()> {
+    let cred = &mut process.cred;
+    let old_egid = cred.egid;
+
+    if cred.capable(Capability::SetGid) {
+        cred.gid = gid;
+        cred.egid = gid;
+        cred.sgid = gid;
+    } else if gid == cred.gid || gid == cred.sgid {
+        cred.egid = gid;
+    } else {
+        return Err(Errno::EPERM);
+    }
+
+    if cred.egid != old_egid {
+        audit::record_cred(AuditKind::SetGid, process.pid, cred, [old_egid as u64, cred.egid as u64]);
+    }
+
+    Ok(())
+}
+
+/// `setgroups(2)`: replaces the supplementary group list. Requires `CAP_SETGID`.
+pub fn setgroups(process: &mut Process, groups: &[u32]) -> KResult<()> {
+    capability::require(&process.cred, Capability::SetGid)?;
+
+    if groups.len() > NGROUPS_MAX {
+        return Err(Errno::EINVAL);
+    }
+
+    process.cred.groups[..groups.len()].copy_from_slice(groups);
+    process.cred.ngroups = groups.len();
+    Ok(())
+}
+
+/// `setgroups(size, list)`: replaces the supplementary group list with the `size` group IDs
+/// at user address `list` for the caller `caller`, as [`setgroups`] does. Fails with `EINVAL`
+/// for more than [`NGROUPS_MAX`] groups.
+pub fn sys_setgroups(caller: u64, size: usize, list: usize) -> KResult<()> {
+    if size > NGROUPS_MAX {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut bytes = [0u8; NGROUPS_MAX * 4];
+    uaccess::copy_from_user(&mut bytes[..size * 4], list)?;
+
+    let mut groups = [0u32; NGROUPS_MAX];
+    for (group, bytes) in groups.iter_mut().zip(bytes.chunks_exact(4)) {
+        *group = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    ptable::with_process(caller, |process| setgroups(process, &groups[..size])).ok_or(Errno::ESRCH)?
+}
+
+// =========================================================================
+// Permission checks
+// =========================================================================
+
+/// Kinds of access requested from a file, matching the `rwx` mode bits.
+pub mod access {
+    pub const READ: u16 = 0o4;
+    pub const WRITE: u16 = 0o2;
+    pub const EXEC: u16 = 0o1;
+}
+
+/// Checks `want` (a combination of [`access`] bits) against an inode's owner, group and
+/// permission bits (`mode`, e.g. `0o644`).
+///
+/// With `CAP_DAC_OVERRIDE` a process may read and write anything, and execute anything with at
+/// least one `x` bit.
+pub fn check_file_access(cred: &Credentials, owner: u32, group: u32, mode: u16, want: u16) -> KResult<()> {
+    if cred.capable(Capability::DacOverride) {
+        let exec_denied = want & access::EXEC != 0 && mode & 0o111 == 0;
+        return if exec_denied { Err(Errno::EACCES) } else { Ok(()) };
+    }
+
+    // Only the most specific class applies: owner, then group, then other
+    let granted = if cred.euid == owner {
+        (mode >> 6) & 0o7
+    } else if cred.in_group(group) {
+        (mode >> 3) & 0o7
+    } else {
+        mode & 0o7
+    };
+
+    if granted & want == want {
+        Ok(())
+    } else {
+        let args = [(owner as u64) << 32 | group as u64, (mode as u64) << 16 | want as u64];
+        audit::record_cred(AuditKind::FileAccessDenied, audit::NO_PID, cred, args);
+        Err(Errno::EACCES)
+    }
+}
+
+/// Checks whether `sender` may send a signal to `target` (`kill(2)` rules): a process with
+/// `CAP_KILL` may signal anyone, everyone else only processes whose real or saved user ID
+/// matches their real or effective user ID.
+pub fn check_signal(sender: &Credentials, target: &Credentials) -> KResult<()> {
+    if sender.capable(Capability::Kill) {
+        return Ok(());
+    }
+
+    let sender_ids = [sender.uid, sender.euid];
+    let target_ids = [target.uid, target.suid];
+
+    if sender_ids.iter().any(|id| target_ids.contains(id)) {
+        Ok(())
+    } else {
+        audit::record_cred(AuditKind::SignalDenied, audit::NO_PID, sender, [target.uid as u64, 0]);
+        Err(Errno::EPERM)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn process(uid: u32, gid: u32) -> Process {
+        let mut process = Process::new(9700, 0, "cred");
+        process.cred = Credentials::new(uid, gid);
+        process
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn root_setuid_drops_for_good() {
+            let mut process = process(ROOT_ID, ROOT_ID);
+            setuid(&mut process, 1000).unwrap();
+
+            let cred = process.cred;
+            assert_eq!((cred.uid, cred.euid, cred.suid), (1000, 1000, 1000));
+            assert_eq!((cred.cap_effective, cred.cap_permitted), (CapabilitySet::EMPTY, CapabilitySet::EMPTY));
+            assert_eq!(setuid(&mut process, ROOT_ID), Err(Errno::EPERM));
+        }
+
+        fn users_switch_only_between_their_own_ids() {
+            let mut process = process(1000, 1000);
+            process.cred.suid = 2000;
+
+            // The effective ID moves to the saved ID and back; the real and saved IDs stay
+            setuid(&mut process, 2000).unwrap();
+            assert_eq!((process.cred.uid, process.cred.euid, process.cred.suid), (1000, 2000, 2000));
+            setuid(&mut process, 1000).unwrap();
+            assert_eq!(process.cred.euid, 1000);
+            assert_eq!(setuid(&mut process, 3000), Err(Errno::EPERM));
+
+            process.cred.sgid = 50;
+            setgid(&mut process, 50).unwrap();
+            assert_eq!((process.cred.gid, process.cred.egid), (1000, 50));
+            assert_eq!(setgid(&mut process, 60), Err(Errno::EPERM));
+        }
+
+        fn an_effective_root_comes_back_through_the_saved_id() {
+            let mut process = process(1000, 1000);
+            process.cred = Credentials::ROOT;
+            process.cred.uid = 1000;
+            process.cred.cap_effective.remove(Capability::SetUid);
+
+            // Without CAP_SETUID only the effective ID moves, so the saved root ID keeps the
+            // permitted set for when the process switches back
+            setuid(&mut process, 1000).unwrap();
+            assert_eq!((process.cred.euid, process.cred.suid), (1000, ROOT_ID));
+            assert!(!process.cred.capable(Capability::Kill));
+
+            setuid(&mut process, ROOT_ID).unwrap();
+            assert!(process.cred.capable(Capability::Kill));
+        }
+
+        fn setgroups_needs_setgid_and_a_sane_count() {
+            let mut root = process(ROOT_ID, ROOT_ID);
+            setgroups(&mut root, &[10, 20]).unwrap();
+            assert_eq!(root.cred.groups(), &[10, 20]);
+            assert!(root.cred.in_group(20) && !root.cred.in_group(30));
+            assert_eq!(setgroups(&mut root, &[0; NGROUPS_MAX + 1]), Err(Errno::EINVAL));
+            assert_eq!(sys_setgroups(root.pid, NGROUPS_MAX + 1, 0), Err(Errno::EINVAL));
+
+            let mut user = process(1000, 1000);
+            assert_eq!(setgroups(&mut user, &[10]), Err(Errno::EPERM));
+        }
+
+        fn file_access_follows_the_most_specific_class() {
+            let user = Credentials::new(1000, 100);
+
+            // Owner bits apply to the owner even when they grant less than the others get
+            assert_eq!(check_file_access(&user, 1000, 0, 0o077, access::READ), Err(Errno::EACCES));
+            assert_eq!(check_file_access(&user, 1000, 0, 0o600, access::READ | access::WRITE), Ok(()));
+            assert_eq!(check_file_access(&user, 0, 100, 0o640, access::READ), Ok(()));
+            assert_eq!(check_file_access(&user, 0, 100, 0o640, access::WRITE), Err(Errno::EACCES));
+            assert_eq!(check_file_access(&user, 0, 0, 0o604, access::READ), Ok(()));
+            assert_eq!(check_file_access(&user, 0, 0, 0o600, access::READ), Err(Errno::EACCES));
+
+            // Root reads and writes anything, but executes only what someone may execute
+            assert_eq!(check_file_access(&Credentials::ROOT, 1000, 100, 0o000, access::READ | access::WRITE), Ok(()));
+            assert_eq!(check_file_access(&Credentials::ROOT, 1000, 100, 0o644, access::EXEC), Err(Errno::EACCES));
+            assert_eq!(check_file_access(&Credentials::ROOT, 1000, 100, 0o744, access::EXEC), Ok(()));
+        }
+
+        fn signals_need_a_shared_uid_or_cap_kill() {
+            let (alice, bob) = (Credentials::new(1000, 100), Credentials::new(2000, 100));
+            assert_eq!(check_signal(&alice, &alice), Ok(()));
+            assert_eq!(check_signal(&alice, &bob), Err(Errno::EPERM));
+            assert_eq!(check_signal(&Credentials::ROOT, &bob), Ok(()));
+
+            // A target whose saved ID is the sender's, as after a setuid program dropped to it
+            let mut setuid_program = bob;
+            setuid_program.suid = 1000;
+            assert_eq!(check_signal(&alice, &setuid_program), Ok(()));
+        }
+    }
+}
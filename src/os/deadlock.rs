@@ -0,0 +1,233 @@
+use core::fmt;
+
+use crate::os::process::WaitTarget;
+use crate::os::ptable::{self, MAX_PROCESSES};
+use crate::os::sync::SpinLock;
+
+/// Maximum number of (resource, holder) pairs tracked at once.
+const MAX_HOLDINGS: usize = 128;
+
+// Who currently holds which semaphore / mutex / queue, as reported by the sync primitives
+static HOLDINGS: SpinLock<[Option<(WaitTarget, u64)>; MAX_HOLDINGS]> = SpinLock::new([None; MAX_HOLDINGS]);
+
+/// Records that `pid` now holds `resource` (a semaphore unit, a mutex, ...).
+/// Called by blocking primitives so waits on the resource can be traced back to its holders.
+pub fn note_acquired(resource: WaitTarget, pid: u64) {
+    HOLDINGS.with(|holdings| {
+        // If the table is full we lose precision, not correctness: cycles through this
+        // holding just go unreported
+        if let Some(slot) = holdings.iter_mut().find(|h| h.is_none()) {
+            *slot = Some((resource, pid));
+        }
+    });
+}
+
+/// Records that `pid` released (one unit of) `resource`.
+pub fn note_released(resource: WaitTarget, pid: u64) {
+    HOLDINGS.with(|holdings| {
+        if let Some(slot) = holdings.iter_mut().find(|h| **h == Some((resource, pid))) {
+            *slot = None;
+        }
+    });
+}
+
+/// Forgets everything `pid` holds, e.g. when the process is torn down.
+pub fn forget_process(pid: u64) {
+    HOLDINGS.with(|holdings| {
+        for slot in holdings.iter_mut() {
+            if slot.is_some_and(|(_, holder)| holder == pid) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// A cycle in the wait-for graph: each PID waits on something held by the next one, and the
+/// last waits on the first.
+#[derive(Debug, Clone, Copy)]
+pub struct Cycle {
+    pids: [u64; MAX_PROCESSES],
+    waits: [Option<WaitTarget>; MAX_PROCESSES],
+    len: usize,
+}
+
+impl Cycle {
+    /// PIDs taking part in the deadlock, in wait order.
+    pub fn pids(&self) -> &[u64] {
+        &self.pids[..self.len]
+    }
+}
+
+impl fmt::Display for Cycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in 0..self.len {
+            let next = self.pids[(i + 1) % self.len];
+            write!(f, "pid {} --[{:?}]--> pid {}", self.pids[i], self.waits[i], next)?;
+
+            if i + 1 < self.len {
+                f.write_str(", ")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the wait-for graph from the process table and the recorded holdings and returns the
+/// first cycle found, if any.
+pub fn check() -> Option<Cycle> {
+    // Work on a copy so the holdings are not locked across the process table walk
+    let holdings = HOLDINGS.with(|holdings| *holdings);
+
+    let mut nodes = [(0u64, None); MAX_PROCESSES];
+    let mut count = 0;
+
+    ptable::for_each(|p| {
+        nodes[count] = (p.pid, p.waiting_on);
+        count += 1;
+    });
+
+    find_cycle(&nodes[..count], &holdings)
+}
+
+/// Runs [`check`] and logs any deadlock found. Returns `true` if one was found.
+///
+/// Meant to be called on demand from the debug monitor, or periodically in debug builds.
+pub fn report() -> bool {
+    match check() {
+        Some(cycle) => {
+            log::error!("deadlock: {}", cycle);
+            true
+        }
+        None => false,
+    }
+}
+
+/// How often (in timer ticks) debug builds scan for deadlocks.
+const CHECK_INTERVAL_TICKS: u64 = 1000;
+
+/// Timer hook: in debug builds, scans for deadlocks every [`CHECK_INTERVAL_TICKS`] ticks.
+pub fn on_tick(tick: u64) {
+    if cfg!(debug_assertions) && tick.is_multiple_of(CHECK_INTERVAL_TICKS) {
+        report();
+    }
+}
+
+/// Cycle search over `nodes` (PID and what it waits on) using `holdings` to resolve which
+/// processes hold a resource. Kept free of global state so it can be tested directly.
+pub fn find_cycle(nodes: &[(u64, Option<WaitTarget>)], holdings: &[Option<(WaitTarget, u64)>]) -> Option<Cycle> {
+    // Depth-first search colours: 0 = unvisited, 1 = on the current path, 2 = finished
+    let mut colour = [0u8; MAX_PROCESSES];
+    let mut path = [0usize; MAX_PROCESSES];
+
+    for start in 0..nodes.len() {
+        if colour[start] == 0
+            && let Some(cycle) = visit(start, 0, nodes, holdings, &mut colour, &mut path)
+        {
+            return Some(cycle);
+        }
+    }
+
+    None
+}
+
+fn visit(
+    node: usize,
+    depth: usize,
+    nodes: &[(u64, Option<WaitTarget>)],
+    holdings: &[Option<(WaitTarget, u64)>],
+    colour: &mut [u8; MAX_PROCESSES],
+    path: &mut [usize; MAX_PROCESSES],
+) -> Option<Cycle> {
+    colour[node] = 1;
+    path[depth] = node;
+
+    let (_, waiting_on) = nodes[node];
+
+    for next in waited_on(waiting_on, nodes, holdings) {
+        match colour[next] {
+            // Back edge: the path from `next` to here closes a cycle
+            1 => {
+                let first = path[..=depth].iter().position(|n| *n == next).unwrap_or(0);
+                let mut cycle = Cycle { pids: [0; MAX_PROCESSES], waits: [None; MAX_PROCESSES], len: 0 };
+
+                for &member in &path[first..=depth] {
+                    cycle.pids[cycle.len] = nodes[member].0;
+                    cycle.waits[cycle.len] = nodes[member].1;
+                    cycle.len += 1;
+                }
+
+                return Some(cycle);
+            }
+            0 => {
+                if let Some(cycle) = visit(next, depth + 1, nodes, holdings, colour, path) {
+                    return Some(cycle);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    colour[node] = 2;
+    None
+}
+
+// Indices (into `nodes`) of every process the wait `target` depends on
+fn waited_on<'a>(
+    target: Option<WaitTarget>,
+    nodes: &'a [(u64, Option<WaitTarget>)],
+    holdings: &'a [Option<(WaitTarget, u64)>],
+) -> impl Iterator<Item = usize> + 'a {
+    let index_of = move |pid: u64| nodes.iter().position(|(p, _)| *p == pid);
+
+    // A waitpid() depends on the child itself; lock-style waits depend on the current holders
+    let direct = match target {
+        Some(WaitTarget::PID(pid)) => index_of(pid),
+        _ => None,
+    };
+
+    let held = holdings
+        .iter()
+        .flatten()
+        .filter(move |(resource, _)| {
+            matches!(target, Some(WaitTarget::Semaphore(_) | WaitTarget::Mutex(_) | WaitTarget::MessageQueue(_)))
+                && Some(*resource) == target
+        })
+        .filter_map(move |(_, holder)| index_of(*holder));
+
+    direct.into_iter().chain(held)
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn finds_mutex_cycle() {
+            let nodes = [
+                (1, Some(WaitTarget::Mutex(10))),
+                (2, Some(WaitTarget::Mutex(20))),
+                (3, None),
+            ];
+            let holdings = [Some((WaitTarget::Mutex(10), 2)), Some((WaitTarget::Mutex(20), 1))];
+
+            let cycle = find_cycle(&nodes, &holdings).expect("cycle not detected");
+            assert_eq!(cycle.pids(), &[1, 2]);
+        }
+
+        fn finds_waitpid_cycle() {
+            let nodes = [(4, Some(WaitTarget::PID(5))), (5, Some(WaitTarget::PID(4)))];
+
+            assert!(find_cycle(&nodes, &[]).is_some());
+        }
+
+        fn chains_without_cycle_are_fine() {
+            let nodes = [
+                (1, Some(WaitTarget::Semaphore(7))),
+                (2, Some(WaitTarget::Timer)),
+            ];
+            let holdings = [Some((WaitTarget::Semaphore(7), 2))];
+
+            assert!(find_cycle(&nodes, &holdings).is_none());
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! `/dev`: character devices opened by name.
+//!
+//! Drivers [`register`] a [`CharDevice`] under a name, and it appears as `/dev/<name>` on
+//! every `devtmpfs` mount; [`mount::init`] mounts one at `/dev` in the root namespace. There
+//! are no directories below the root, and devices are never removed. Reads and writes go
+//! straight to the device, which has no offsets: a device is a stream, not a file.
+//!
+//! [`mount::init`]: crate::os::mount::init
+
+use crate::os::cred::ROOT_ID;
+use crate::os::errno::{Errno, KResult};
+use crate::os::mount::Path;
+use crate::os::process::Process;
+use crate::os::sync::SpinLock;
+use crate::os::vfs::{FileSystem, Inode, InodeKind};
+
+/// Maximum number of registered devices.
+pub const MAX_DEVICES: usize = 16;
+
+// The root directory's inode; devices are numbered from 1
+const ROOT_INO: u64 = 0;
+
+/// A device read and written as a stream of bytes.
+pub trait CharDevice: Sync {
+    /// Reads what is available into `buf`, waiting for at least one byte unless the device
+    /// has reached its end.
+    fn read(&self, buf: &mut [u8]) -> KResult<usize>;
+
+    /// Writes `data`, returning how much the device took. Read-only devices, the default,
+    /// fail with `EINVAL`.
+    fn write(&self, _data: &[u8]) -> KResult<usize> {
+        Err(Errno::EINVAL)
+    }
+}
+
+static DEVICES: SpinLock<[Option<(&'static str, &'static dyn CharDevice)>; MAX_DEVICES]> = SpinLock::new([None; MAX_DEVICES]);
+
+/// `/dev` as the VFS opens it.
+pub static FS: DevFs = DevFs;
+
+pub struct DevFs;
+
+/// Makes `device` `/dev/<name>`. Fails with `EEXIST` if the name is taken and `ENOSPC` once
+/// [`MAX_DEVICES`] are registered.
+pub fn register(name: &'static str, device: &'static dyn CharDevice) -> KResult<()> {
+    DEVICES.with(|devices| {
+        if devices.iter().flatten().any(|(taken, _)| *taken == name) {
+            return Err(Errno::EEXIST);
+        }
+
+        let slot = devices.iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOSPC)?;
+        *slot = Some((name, device));
+        Ok(())
+    })
+}
+
+fn device(ino: u64) -> Option<&'static dyn CharDevice> {
+    let index = ino.checked_sub(1)? as usize;
+    DEVICES.with(|devices| devices.get(index).copied().flatten().map(|(_, device)| device))
+}
+
+impl FileSystem for DevFs {
+    fn open(&self, _process: &Process, path: &Path, _flags: u32) -> KResult<Inode> {
+        if *path == Path::ROOT {
+            return Ok(Inode { ino: ROOT_INO, kind: InodeKind::Directory, size: 0, uid: ROOT_ID, gid: ROOT_ID, mode: 0o755 });
+        }
+
+        let name = path.as_str().trim_start_matches('/');
+        let index = DEVICES.with(|devices| devices.iter().position(|slot| slot.is_some_and(|(taken, _)| taken == name)));
+        let index = index.ok_or(Errno::ENOENT)?;
+        Ok(Inode { ino: index as u64 + 1, kind: InodeKind::File, size: 0, uid: ROOT_ID, gid: ROOT_ID, mode: 0o666 })
+    }
+
+    fn read(&self, ino: u64, buf: &mut [u8], _offset: u64) -> KResult<usize> {
+        device(ino).ok_or(Errno::ENODEV)?.read(buf)
+    }
+
+    fn write(&self, ino: u64, data: &[u8], _offset: u64) -> KResult<usize> {
+        device(ino).ok_or(Errno::ENODEV)?.write(data)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::mount::{self, FsType};
+    use crate::os::vfs;
+
+    // Reads as an endless run of its byte and swallows writes
+    struct Fill(u8);
+
+    impl CharDevice for Fill {
+        fn read(&self, buf: &mut [u8]) -> KResult<usize> {
+            buf.fill(self.0);
+            Ok(buf.len())
+        }
+
+        fn write(&self, data: &[u8]) -> KResult<usize> {
+            Ok(data.len())
+        }
+    }
+
+    static FILL: Fill = Fill(b'x');
+
+    crate::os::ktest::kernel_test! {
+        fn devices_are_opened_by_name() {
+            _ = register("ktest-fill", &FILL);
+            assert_eq!(register("ktest-fill", &FILL), Err(Errno::EEXIST));
+
+            let process = Process::new(1, 0, "devfs");
+            let root = FS.open(&process, &Path::ROOT, 0).unwrap();
+            assert_eq!(root.kind, InodeKind::Directory);
+
+            let inode = FS.open(&process, &Path::new("/ktest-fill").unwrap(), 0).unwrap();
+            let mut buf = [0; 4];
+            assert_eq!(FS.read(inode.ino, &mut buf, 1000), Ok(4));
+            assert_eq!(&buf, b"xxxx");
+            assert_eq!(FS.write(inode.ino, b"ab", 0), Ok(2));
+
+            assert_eq!(FS.open(&process, &Path::new("/ktest-none").unwrap(), 0), Err(Errno::ENOENT));
+            assert_eq!(FS.open(&process, &Path::new("/ktest-fill/x").unwrap(), 0), Err(Errno::ENOENT));
+        }
+
+        fn dev_is_mounted_through_the_vfs() {
+            _ = register("ktest-fill", &FILL);
+            if mount::resolve(&Process::new(1, 0, "devfs"), "/dev").map(|resolved| resolved.fs) != Ok(FsType::Dev) {
+                return;
+            }
+
+            let file = vfs::open(&Process::new(1, 0, "devfs"), "/dev/ktest-fill", 0).unwrap();
+            let mut buf = [0; 3];
+            assert_eq!(vfs::read(&file, &mut buf), Ok(3));
+            assert_eq!(&buf, b"xxx");
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Registry of the devices discovered at boot.
+//!
+//! Bus enumeration (PCI) and firmware descriptions (the flattened device tree) record every
+//! device they find here, so a driver looks its device up the same way whichever of them
+//! described it. Entries are added during single-threaded boot and never removed, so readers
+//! need no locking.
+//!
+//! Devices are kernel objects (see [`kobject`](super::kobject)): the registry holds one
+//! reference to each, and a driver that keeps its device takes another with [`get`].
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::kobject::{KRef, Pool};
+
+/// Maximum number of registered devices.
+pub const MAX_DEVICES: usize = 64;
+
+/// Maximum number of MMIO regions kept per device.
+pub const MAX_REGIONS: usize = 4;
+
+/// What a device is, as far as the kernel cares before a driver binds to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    InterruptController,
+    Uart,
+    Virtio,
+    Other,
+}
+
+/// A physical address range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+}
+
+/// Where a device is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// A PCI function and its identity.
+    Pci { bus: u8, device: u8, function: u8, vendor_id: u16, device_id: u16 },
+
+    /// Memory-mapped registers, in firmware order; unused entries are zero-sized.
+    Mmio { regions: [Region; MAX_REGIONS] },
+}
+
+impl Location {
+    /// MMIO region `index`, if the device has one.
+    pub fn region(&self, index: usize) -> Option<Region> {
+        match self {
+            Location::Mmio { regions } => regions.get(index).copied().filter(|r| r.size != 0),
+            Location::Pci { .. } => None,
+        }
+    }
+}
+
+/// A discovered device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub class: DeviceClass,
+
+    /// The device tree's first `compatible` string, or "pci" for PCI functions.
+    pub name: &'static str,
+
+    pub location: Location,
+
+    /// Interrupt number at the platform's interrupt controller, if the device has one.
+    pub irq: Option<u32>,
+}
+
+/// Every registered device.
+pub static DEVICES: Pool<Device, MAX_DEVICES> = Pool::new("device");
+
+static mut REGISTRY: [Option<KRef<Device>>; MAX_DEVICES] = [const { None }; MAX_DEVICES];
+
+/// Records a device, returning its index in the registry.
+pub fn register(device: Device) -> KResult<usize> {
+    unsafe {
+        let registry = &raw mut REGISTRY;
+
+        let index = (*registry).iter().position(|d| d.is_none()).ok_or(Errno::ENOSPC)?;
+        (*registry)[index] = Some(DEVICES.alloc(device)?);
+        Ok(index)
+    }
+}
+
+// Registration only happens during boot, before anything reads the table concurrently
+fn devices() -> &'static [Option<KRef<Device>>; MAX_DEVICES] {
+    unsafe {
+        let registry = &raw const REGISTRY;
+        &*registry
+    }
+}
+
+/// A reference to the device at `index` in the registry.
+pub fn get(index: usize) -> Option<KRef<Device>> {
+    devices().get(index).cloned().flatten()
+}
+
+/// Calls `f` for every registered device, in registration order.
+pub fn for_each(mut f: impl FnMut(&Device)) {
+    devices().iter().flatten().for_each(|device| f(device));
+}
+
+/// The first registered device matching `predicate`.
+pub fn find(predicate: impl Fn(&Device) -> bool) -> Option<Device> {
+    devices().iter().flatten().find(|device| predicate(device)).map(|device| **device)
+}
+
+/// The first registered device of class `class` whose name is one of `names`.
+pub fn find_compatible(class: DeviceClass, names: &[&str]) -> Option<Device> {
+    find(|device| device.class == class && names.contains(&device.name))
+}
+
+/// Number of registered devices.
+pub fn count() -> usize {
+    devices().iter().flatten().count()
+}
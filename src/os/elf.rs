@@ -0,0 +1,706 @@
+//! ELF64 executables: loading a program into a fresh address space.
+//!
+//! [`load`] checks the headers of an x86_64 executable in memory and copies each `PT_LOAD`
+//! segment into frames of its own, mapped at the segment's address with the rights its flags
+//! ask for: code readable and executable, data writable, the rest read-only, never writable
+//! and executable at once. Position-independent executables are placed at the ASLR load base;
+//! fixed-address ones must lie in the user range. There is no dynamic linker, so executables
+//! with an interpreter are refused, and a static PIE relocates itself. At the top of the stack
+//! go the argument strings and the vectors the SysV ABI puts there: `argc`, `argv`, an empty
+//! environment and an auxiliary vector describing the image. Only the pages they take are
+//! mapped; the rest of the stack is paged in as it grows (see [`fault`](crate::os::fault)).
+//!
+//! [`exec`] loads an image in place of a process's own, behind `execveat`.
+//!
+//! [`spawn`] wraps a loaded image in a new process and hands it to the scheduler, which
+//! starts it at its entry point in ring 3 (see [`user::enter_user`]). Segment and stack pages
+//! are charged to the process's group as anonymous memory, and [`release`] frees them when
+//! the address space goes.
+
+use alloc::format;
+use alloc::vec::Vec;
+
+use crate::os::arch::x86_64::syscall::{self, SyscallFrame};
+use crate::os::arch::x86_64::user;
+use crate::os::arch::{Arch, Current};
+use crate::os::aslr::{self, Layout};
+use crate::os::cgroup::{self, PageKind};
+use crate::os::compaction;
+use crate::os::cow;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file;
+use crate::os::fpu;
+use crate::os::jobctl::SIG_IGN;
+use crate::os::kthread;
+use crate::os::lsm;
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::mmap;
+use crate::os::numa::Placement;
+use crate::os::paging::{self, USER_END, USER_START};
+use crate::os::percpu;
+use crate::os::pid;
+use crate::os::process::{KERNEL_STACK_SIZE, Process};
+use crate::os::protection::Protection;
+use crate::os::ptable;
+use crate::os::sched;
+use crate::os::swap;
+use crate::os::thp;
+use crate::os::tlb;
+use crate::os::uaccess;
+
+/// How far a new process's stack may grow; its pages are mapped as it gets there.
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+
+/// Most bytes the argument strings and their pointers may take on the stack.
+pub const MAX_ARG_BYTES: usize = USER_STACK_SIZE / 4;
+
+/// Most program headers an executable may have.
+pub const MAX_PROGRAM_HEADERS: usize = 16;
+
+/// Most arguments `execveat` passes on.
+pub const MAX_ARGS: usize = 32;
+
+/// Largest executable `execveat` reads.
+pub const MAX_IMAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// `execveat` flag: run the file open as the descriptor itself.
+pub const AT_EMPTY_PATH: u32 = 0x1000;
+
+// Identification and file header
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const CURRENT_VERSION: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+// Program header types and flags
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+// Auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+// Valid bit of a leaf entry, bit 0 on every port
+const VALID: u64 = 1 << 0;
+
+// Frames of a process's kernel stack
+const KERNEL_STACK_FRAMES: u64 = (KERNEL_STACK_SIZE as u64).div_ceil(FRAME_SIZE);
+
+/// The parts of the file header the loader uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    position_independent: bool,
+    entry: u64,
+    phoff: u64,
+    phnum: usize,
+}
+
+/// A program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProgramHeader {
+    ty: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+impl ProgramHeader {
+    fn protection(&self) -> Protection {
+        if self.flags & PF_X != 0 {
+            Protection::code(true)
+        } else if self.flags & PF_W != 0 {
+            Protection::data(true)
+        } else {
+            Protection::read_only(true)
+        }
+    }
+}
+
+fn read_u16(image: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(image.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(image: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(image.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(image: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(image.get(at..at + 8)?.try_into().ok()?))
+}
+
+// The file header of an x86_64 executable, or `None` for anything else
+fn header(image: &[u8]) -> Option<Header> {
+    let ident = image.get(..HEADER_SIZE)?;
+    if &ident[..4] != MAGIC || ident[4] != CLASS_64 || ident[5] != DATA_LITTLE_ENDIAN || ident[6] != CURRENT_VERSION {
+        return None;
+    }
+
+    let ty = read_u16(image, 16)?;
+    if !matches!(ty, ET_EXEC | ET_DYN) || read_u16(image, 18)? != EM_X86_64 {
+        return None;
+    }
+
+    let phoff = read_u64(image, 32)?;
+    let phnum = read_u16(image, 56)? as usize;
+    let table_end = phoff.checked_add((phnum * PROGRAM_HEADER_SIZE) as u64)?;
+    if read_u16(image, 54)? as usize != PROGRAM_HEADER_SIZE || phnum > MAX_PROGRAM_HEADERS || table_end > image.len() as u64 {
+        return None;
+    }
+
+    Some(Header { position_independent: ty == ET_DYN, entry: read_u64(image, 24)?, phoff, phnum })
+}
+
+// Program header `index`, which the header says exists
+fn program_header(image: &[u8], header: &Header, index: usize) -> Option<ProgramHeader> {
+    let at = header.phoff as usize + index * PROGRAM_HEADER_SIZE;
+
+    Some(ProgramHeader {
+        ty: read_u32(image, at)?,
+        flags: read_u32(image, at + 4)?,
+        offset: read_u64(image, at + 8)?,
+        vaddr: read_u64(image, at + 16)?,
+        filesz: read_u64(image, at + 32)?,
+        memsz: read_u64(image, at + 40)?,
+    })
+}
+
+// The `PT_LOAD` segments of `image`, checked against the file and the user range once moved
+// by `bias`. Fails with `ENOEXEC` for a segment the image does not hold or that lands outside
+// the user range, or an interpreter, and `EACCES` for a writable and executable segment.
+fn segments(image: &[u8], header: &Header, bias: u64) -> KResult<([Option<ProgramHeader>; MAX_PROGRAM_HEADERS], usize)> {
+    let mut found = [None; MAX_PROGRAM_HEADERS];
+    let mut count = 0;
+
+    for index in 0..header.phnum {
+        let segment = program_header(image, header, index).ok_or(Errno::ENOEXEC)?;
+        match segment.ty {
+            PT_INTERP => return Err(Errno::ENOEXEC),
+            PT_LOAD => {}
+            _ => continue,
+        }
+
+        let in_file = segment.offset.checked_add(segment.filesz).is_some_and(|end| end <= image.len() as u64);
+        let start = bias.checked_add(segment.vaddr).ok_or(Errno::ENOEXEC)?;
+        let in_range = start >= USER_START && start.checked_add(segment.memsz).is_some_and(|end| end <= USER_END);
+        if !in_file || !in_range || segment.filesz > segment.memsz {
+            return Err(Errno::ENOEXEC);
+        }
+
+        if segment.protection().violates_wx() {
+            return Err(Errno::EACCES);
+        }
+
+        found[count] = Some(ProgramHeader { vaddr: start, ..segment });
+        count += 1;
+    }
+
+    if count == 0 {
+        return Err(Errno::ENOEXEC);
+    }
+    Ok((found, count))
+}
+
+// Backs the page at `virt` with a new frame charged to `process`, filled by `fill`, and maps it
+fn map_new_page(process: &Process, virt: u64, protection: Protection, fill: impl FnOnce(&mut [u8])) -> KResult<()> {
+    let allocator = memory::frame_allocator().ok_or(Errno::ENOMEM)?;
+    cgroup::charge(process.cgroup, PageKind::Anon, 1)?;
+    let Some(frame) = allocator.alloc_frame(Placement::Local) else {
+        cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+        return Err(Errno::ENOMEM);
+    };
+
+    // Frames are identity-mapped
+    let page = unsafe { core::slice::from_raw_parts_mut(frame as *mut u8, FRAME_SIZE as usize) };
+    page.fill(0);
+    fill(page);
+
+    paging::map_page(process.page_table_root as u64, virt, frame, protection).inspect_err(|_| {
+        allocator.free_frame(frame);
+        cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+    })
+}
+
+// Copies `segment` of `image` into new pages of `process`. Segments sharing a page fail with
+// `ENOEXEC`.
+fn map_segment(process: &Process, image: &[u8], segment: &ProgramHeader) -> KResult<()> {
+    let first = segment.vaddr & !(FRAME_SIZE - 1);
+    let end = (segment.vaddr + segment.memsz).next_multiple_of(FRAME_SIZE);
+    let file = &image[segment.offset as usize..(segment.offset + segment.filesz) as usize];
+
+    for page in (first..end).step_by(FRAME_SIZE as usize) {
+        map_new_page(process, page, segment.protection(), |frame| {
+            // The part of the file contents that lands on this page
+            let from = page.max(segment.vaddr);
+            let to = (page + FRAME_SIZE).min(segment.vaddr + segment.filesz);
+            if from < to {
+                let source = &file[(from - segment.vaddr) as usize..(to - segment.vaddr) as usize];
+                frame[(from - page) as usize..(to - page) as usize].copy_from_slice(source);
+            }
+        })
+        .map_err(|err| if err == Errno::EEXIST { Errno::ENOEXEC } else { err })?;
+    }
+    Ok(())
+}
+
+// Writes `bytes` to `virt` in `process`'s address space, which maps it
+fn write_to(process: &Process, virt: u64, bytes: &[u8]) {
+    let root = process.page_table_root as u64;
+    let mut done = 0;
+
+    while done < bytes.len() {
+        let at = virt + done as u64;
+        let n = (bytes.len() - done).min((FRAME_SIZE - at % FRAME_SIZE) as usize);
+        let phys = paging::translate(root, at).expect("elf: writing to an unmapped stack page");
+
+        unsafe { core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), phys as *mut u8, n) };
+        done += n;
+    }
+}
+
+// Places the stack below `layout.stack_top`, maps the pages at its top that `argv` and the
+// auxiliary vector take, and lays them out there as the SysV ABI expects; returns the initial
+// stack pointer, which points at `argc`. The rest of the stack is paged in on demand.
+fn build_stack(process: &mut Process, layout: &Layout, argv: &[&str], auxv: &[(u64, u64)]) -> KResult<u64> {
+    let strings: usize = argv.iter().map(|arg| arg.len() + 1).sum();
+    // argc, argv and its terminator, the empty environment, the auxiliary pairs and AT_NULL
+    let words = 1 + argv.len() + 1 + 1 + 2 * (auxv.len() + 1);
+    if strings + words * 8 > MAX_ARG_BYTES {
+        return Err(Errno::E2BIG);
+    }
+
+    let top = layout.stack_top;
+    process.stack_base = top as usize;
+    process.stack_size = USER_STACK_SIZE;
+
+    let mut string = top - strings as u64;
+    let sp = (string - (words * 8) as u64) & !15;
+    for page in ((sp & !(FRAME_SIZE - 1))..top).step_by(FRAME_SIZE as usize) {
+        map_new_page(process, page, Protection::data(true), |_| {})?;
+    }
+
+    let mut word = sp;
+    let mut push = |value: u64| {
+        write_to(process, word, &value.to_le_bytes());
+        word += 8;
+    };
+
+    push(argv.len() as u64);
+    for arg in argv {
+        push(string);
+        write_to(process, string, arg.as_bytes());
+        write_to(process, string + arg.len() as u64, &[0]);
+        string += arg.len() as u64 + 1;
+    }
+    push(0);
+    push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        push(key);
+        push(value);
+    }
+
+    Ok(sp)
+}
+
+/// Loads the executable `image` into `process`, which must have an empty address space of its
+/// own, with arguments `argv`: maps its segments and a stack holding the arguments, fills in
+/// the segment fields and points `pc` at the entry point and `sp` at `argc`. Fails with
+/// `ENOEXEC` if the image is not an x86_64 executable this loader can place, `EACCES` for a
+/// writable and executable segment, `E2BIG` if the arguments do not fit [`MAX_ARG_BYTES`] and
+/// `ENOMEM` when memory runs out; whatever was mapped by then stays, for [`release`] to free.
+pub fn load(process: &mut Process, image: &[u8], argv: &[&str]) -> KResult<()> {
+    let header = header(image).ok_or(Errno::ENOEXEC)?;
+    let layout = aslr::randomize();
+    let bias = if header.position_independent { layout.load_base } else { 0 };
+
+    let (segments, count) = segments(image, &header, bias)?;
+    let segments = &segments[..count];
+    let entry = bias.checked_add(header.entry).filter(|entry| (USER_START..USER_END).contains(entry)).ok_or(Errno::ENOEXEC)?;
+
+    let span = |writable: bool| {
+        let mut matching = segments.iter().flatten().filter(|s| (s.flags & PF_W != 0) == writable);
+        let first = matching.clone().map(|s| s.vaddr).min()?;
+        Some((first, matching.map(|s| s.vaddr + s.memsz).max()? - first))
+    };
+    let image_end = segments.iter().flatten().map(|s| s.vaddr + s.memsz).max().unwrap_or(0);
+    let (code_base, code_size) = span(false).unwrap_or((image_end, 0));
+    let (data_base, data_size) = span(true).unwrap_or((image_end, 0));
+
+    let pages: u64 = segments.iter().flatten().map(|s| (s.vaddr % FRAME_SIZE + s.memsz).div_ceil(FRAME_SIZE)).sum();
+    memory::check_commit(pages * FRAME_SIZE + USER_STACK_SIZE as u64)?;
+
+    process.code_base = code_base as usize;
+    process.code_size = code_size as usize;
+    process.data_base = data_base as usize;
+    process.data_size = data_size as usize;
+    process.heap_base = aslr::heap_start(&layout, image_end) as usize;
+    process.heap_size = 0;
+    process.mmap_base = layout.mmap_base as usize;
+
+    for segment in segments.iter().flatten() {
+        map_segment(process, image, segment)?;
+    }
+
+    // The program headers, where a segment maps them
+    let phdr = segments
+        .iter()
+        .flatten()
+        .find(|s| s.offset <= header.phoff && header.phoff < s.offset + s.filesz)
+        .map(|s| s.vaddr + (header.phoff - s.offset));
+
+    let mut auxv = [(AT_PHENT, PROGRAM_HEADER_SIZE as u64), (AT_PHNUM, header.phnum as u64), (AT_PAGESZ, FRAME_SIZE), (AT_ENTRY, entry), (AT_PHDR, 0)];
+    let auxv = match phdr {
+        Some(phdr) => {
+            auxv[4].1 = phdr;
+            &auxv[..]
+        }
+        None => &auxv[..4],
+    };
+
+    process.sp = build_stack(process, &layout, argv, auxv)? as usize;
+    process.pc = entry as usize;
+    Ok(())
+}
+
+/// Frees the pages mapped in `process`'s image and in its data, heap and stack -- those it
+/// still shares with another process only once the last one lets go -- uncharging them. Called when its address space is torn down, after swap and huge pages are released.
+pub fn release(process: &mut Process) {
+    let root = process.page_table_root as u64;
+    if root == 0 {
+        return;
+    }
+
+    let code = (process.code_base as u64, (process.code_base + process.code_size) as u64);
+    for (start, end) in [code].into_iter().chain(memory::anonymous_ranges(process)) {
+        for virt in ((start & !(FRAME_SIZE - 1))..end).step_by(FRAME_SIZE as usize) {
+            let Some(pte) = Current::leaf_entry(root, virt) else {
+                continue;
+            };
+            let entry = unsafe { *pte };
+            if entry & VALID == 0 {
+                continue;
+            }
+
+            unsafe { *pte = 0 };
+            cow::release_frame(Current::entry_address(entry));
+            cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+        }
+    }
+}
+
+/// Starts a process running the executable `image` with arguments `argv`, the first of which
+/// names it, as a child of `ppid`, and returns its PID. Fails as [`load`] does, and with
+/// `EAGAIN` when no PID or process table slot is free.
+pub fn spawn(image: &[u8], argv: &[&str], ppid: u64) -> KResult<u64> {
+    let pid = pid::alloc()?;
+    let mut process = Process::new(pid, ppid, "");
+    process.on_exec(argv.first().copied().unwrap_or(""));
+    fpu::reset(process);
+
+    let Some(stack) = compaction::alloc_contiguous(KERNEL_STACK_FRAMES, FRAME_SIZE) else {
+        pid::free(pid);
+        return Err(Errno::ENOMEM);
+    };
+    process.kernel_stack = stack as usize;
+    sched::init_context(&mut process, start_user, 0);
+
+    // In the table before anything is mapped, so a failed load gets the whole PCB back
+    if ptable::insert(process).is_err() {
+        compaction::free_contiguous(stack, KERNEL_STACK_FRAMES);
+        pid::free(pid);
+        return Err(Errno::EAGAIN);
+    }
+
+    let loaded = ptable::with_process(pid, |process| {
+        paging::create_address_space(process)?;
+        load(process, image, argv)
+    });
+    if let Some(Err(err)) = loaded {
+        if let Some(mut process) = ptable::remove(pid) {
+            release(&mut process);
+            paging::release(&mut process);
+            kthread::release(&mut process);
+        }
+        pid::free(pid);
+        return Err(err);
+    }
+
+    sched::admit(pid)?;
+    Ok(pid)
+}
+
+/// Replaces the image of process `pid`, which is in a syscall, with the executable `image` run
+/// with arguments `argv`, the first of which renames it: the new image is loaded into an
+/// address space of its own, and only once that worked is the old one torn down and the
+/// syscall pointed at the new entry point. Caught signals go back to their default action,
+/// ignored ones stay ignored; open files, limits and credentials stay as they are. Fails as
+/// [`load`] does, with the old image untouched, and with `EINVAL` for a process without an
+/// address space, such as a kernel thread.
+pub fn exec(pid: u64, image: &[u8], argv: &[&str]) -> KResult<()> {
+    let copied = ptable::with_process(pid, |p| (p.ppid, p.cgroup, p.page_table_root != 0 && p.kernel_stack != 0));
+    let (ppid, cgroup, usable) = copied.ok_or(Errno::ESRCH)?;
+    if !usable {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut new = Process::new(pid, ppid, "");
+    new.cgroup = cgroup;
+    if let Err(err) = paging::create_address_space(&mut new).and_then(|()| load(&mut new, image, argv)) {
+        release(&mut new);
+        paging::release(&mut new);
+        return Err(err);
+    }
+
+    ptable::with_process(pid, |process| replace_image(process, &new, argv)).ok_or(Errno::ESRCH)
+}
+
+// Tears down the old image of `process` and switches it over to the loaded image `new`
+fn replace_image(process: &mut Process, new: &Process, argv: &[&str]) {
+    swap::release(process);
+    thp::release(process);
+    mmap::release(process);
+    release(process);
+    paging::release(process);
+
+    process.code_base = new.code_base;
+    process.code_size = new.code_size;
+    process.data_base = new.data_base;
+    process.data_size = new.data_size;
+    process.heap_base = new.heap_base;
+    process.heap_size = new.heap_size;
+    process.stack_base = new.stack_base;
+    process.stack_size = new.stack_size;
+    process.mmap_base = new.mmap_base;
+    process.page_table_root = new.page_table_root;
+    process.pc = new.pc;
+    process.sp = new.sp;
+
+    process.signal_handlers.iter_mut().filter(|handler| **handler != SIG_IGN).for_each(|handler| *handler = 0);
+    process.on_exec(argv.first().copied().unwrap_or(""));
+    fpu::reset(process);
+
+    // The syscall returns into the new image, with nothing of the old one in its registers
+    unsafe {
+        tlb::switch_to(process.page_table_root as u64);
+        syscall::frame_of(process).write(SyscallFrame {
+            rip: process.pc as u64,
+            rsp: process.sp as u64,
+            rflags: user::USER_FLAGS,
+            ..SyscallFrame::default()
+        });
+    }
+}
+
+// Reads the NUL-terminated string at the user address `addr` onto the end of `buf`, which
+// may not grow past `limit` bytes
+fn read_user_string(buf: &mut Vec<u8>, addr: usize, limit: usize) -> KResult<()> {
+    for at in addr.. {
+        let mut byte = [0u8];
+        uaccess::copy_from_user(&mut byte, at)?;
+        if byte[0] == 0 {
+            return Ok(());
+        }
+        if buf.len() == limit {
+            return Err(Errno::E2BIG);
+        }
+        buf.push(byte[0]);
+    }
+    Err(Errno::EFAULT)
+}
+
+// Reads the whole of the open file `file`, up to MAX_IMAGE_SIZE bytes
+fn read_image(file: u32) -> KResult<Vec<u8>> {
+    let ops = file::ops().ok_or(Errno::EBADF)?;
+    let mut image = Vec::new();
+    let mut chunk = [0u8; FRAME_SIZE as usize];
+
+    loop {
+        let n = (ops.read)(file, &mut chunk, image.len() as u64)?;
+        if n == 0 {
+            return Ok(image);
+        }
+        if image.len() + n > MAX_IMAGE_SIZE {
+            return Err(Errno::ENOMEM);
+        }
+        image.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// `execveat(fd, path, argv, envp, flags)`: replaces the caller's image with the executable
+/// open as `fd`, run with the NUL-terminated argument vector at `argv` (see [`exec`]); on
+/// success the call returns into the new image. Files are not looked up by path, so `flags`
+/// must hold `AT_EMPTY_PATH` and `path` be empty; the environment is not passed on. Fails with
+/// `EINVAL` for other flags, `ENOENT` for a path, `EBADF` if `fd` is not open, `EFAULT` for
+/// arguments outside user memory, `E2BIG` for more than [`MAX_ARGS`] arguments or
+/// [`MAX_ARG_BYTES`] bytes of them, `EINVAL` if one is not UTF-8, as a security module's
+/// [`exec`](lsm::SecurityModule::exec) hook vetoes it and as [`exec`] does.
+pub fn sys_execveat(caller: u64, fd: usize, path: usize, argv: usize, flags: u32) -> KResult<()> {
+    if flags != AT_EMPTY_PATH {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut first = [0u8];
+    uaccess::copy_from_user(&mut first, path)?;
+    if first[0] != 0 {
+        return Err(Errno::ENOENT);
+    }
+
+    let (file, runner) = ptable::with_process(caller, |process| Ok((process.file(fd)?.id, process.stand_in())))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    // Named by descriptor, as Linux names a program run with `AT_EMPTY_PATH`
+    lsm::check_exec(&runner, &format!("/dev/fd/{}", fd))?;
+
+    // The strings back to back, and where each ends
+    let mut strings = Vec::new();
+    let mut ends = Vec::new();
+    for index in 0.. {
+        let arg: u64 = unsafe { uaccess::read_user(argv + index * 8)? };
+        if arg == 0 {
+            break;
+        }
+        if index == MAX_ARGS {
+            return Err(Errno::E2BIG);
+        }
+
+        read_user_string(&mut strings, arg as usize, MAX_ARG_BYTES)?;
+        ends.push(strings.len());
+    }
+
+    let strings = core::str::from_utf8(&strings).map_err(|_| Errno::EINVAL)?;
+    let mut start = 0;
+    let mut args = Vec::with_capacity(ends.len());
+    for end in ends {
+        args.push(strings.get(start..end).ok_or(Errno::EINVAL)?);
+        start = end;
+    }
+
+    let image = read_image(file)?;
+    exec(caller, &image, &args)
+}
+
+// First code a loaded process runs, on its kernel stack and in its own address space, which
+// the scheduler loaded: it leaves for the entry point in ring 3.
+extern "C" fn start_user(_: usize) -> ! {
+    kthread::reclaim_stack();
+
+    let pid = percpu::current_pid();
+    let (pc, sp) = ptable::with_process(pid, |process| (process.pc as u64, process.sp as u64))
+        .expect("elf: starting a process that is not in the table");
+    unsafe { user::enter_user(pc, sp) }
+}
+
+pub mod ktests {
+    use super::*;
+
+    // An executable with a code segment at 0x40_0000 holding the headers and a data segment
+    // two pages above it, `bss` bytes longer than its contents
+    fn executable(ty: u16, flags: [u32; 2], bss: u64) -> [u8; 0x200] {
+        let mut image = [0u8; 0x200];
+        image[..4].copy_from_slice(MAGIC);
+        image[4..7].copy_from_slice(&[CLASS_64, DATA_LITTLE_ENDIAN, CURRENT_VERSION]);
+        image[16..18].copy_from_slice(&ty.to_le_bytes());
+        image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        image[24..32].copy_from_slice(&0x40_0100u64.to_le_bytes());
+        image[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+        image[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&2u16.to_le_bytes());
+
+        let segments = [(flags[0], 0u64, 0x40_0000u64, 0x180u64, 0x180u64), (flags[1], 0x180, 0x40_2000, 0x80, 0x80 + bss)];
+        for (i, (flags, offset, vaddr, filesz, memsz)) in segments.into_iter().enumerate() {
+            let at = HEADER_SIZE + i * PROGRAM_HEADER_SIZE;
+            image[at..at + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            image[at + 4..at + 8].copy_from_slice(&flags.to_le_bytes());
+            image[at + 8..at + 16].copy_from_slice(&offset.to_le_bytes());
+            image[at + 16..at + 24].copy_from_slice(&vaddr.to_le_bytes());
+            image[at + 32..at + 40].copy_from_slice(&filesz.to_le_bytes());
+            image[at + 40..at + 48].copy_from_slice(&memsz.to_le_bytes());
+        }
+        image[0x180..0x200].fill(0xab);
+        image
+    }
+
+    fn read_u64_at(process: &Process, virt: u64) -> u64 {
+        let phys = paging::translate(process.page_table_root as u64, virt).unwrap();
+        unsafe { *(phys as *const u64) }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn only_x86_64_executables_are_accepted() {
+            let image = executable(ET_EXEC, [PF_X, PF_W], 0);
+            assert_eq!(header(&image).map(|h| (h.position_independent, h.phnum)), Some((false, 2)));
+            assert_eq!(header(&executable(ET_DYN, [PF_X, PF_W], 0)).map(|h| h.position_independent), Some(true));
+            assert_eq!(header(&image[..HEADER_SIZE - 1]), None);
+            assert_eq!(header(b"\x7fELF but not really"), None);
+
+            let mut other = image;
+            other[18] = 0xb7;
+            assert_eq!(header(&other), None);
+
+            let mut process = Process::new(9980, 0, "elf");
+            let wx = executable(ET_EXEC, [PF_X, PF_W | PF_X], 0);
+            assert_eq!(load(&mut process, &wx, &["wx"]), Err(Errno::EACCES));
+
+            let mut kernel_entry = image;
+            kernel_entry[24..32].copy_from_slice(&0xffff_8000_0000_0000u64.to_le_bytes());
+            assert_eq!(load(&mut process, &kernel_entry, &["kernel"]), Err(Errno::ENOEXEC));
+        }
+
+        fn loading_maps_segments_and_the_stack() {
+            let free = memory::stats().free;
+            let mut process = Process::new(9981, 0, "elf");
+            assert_eq!(paging::create_address_space(&mut process), Ok(()));
+
+            let image = executable(ET_EXEC, [PF_X, PF_W], 0x1800);
+            assert_eq!(load(&mut process, &image, &["init", "-v"]), Ok(()));
+            assert_eq!((process.pc, process.code_base, process.data_base), (0x40_0100, 0x40_0000, 0x40_2000));
+            assert_eq!(process.data_size, 0x1880);
+            assert!(process.heap_base >= 0x40_4000);
+
+            // File contents are copied, the rest of the segment is zero
+            let root = process.page_table_root as u64;
+            let data = paging::translate(root, 0x40_2000).unwrap();
+            assert_eq!(unsafe { *(data as *const u8) }, 0xab);
+            assert_eq!(read_u64_at(&process, 0x40_2080), 0);
+            assert!(paging::translate(root, 0x40_3000).is_some());
+
+            // argc, then argv, then an empty environment
+            let sp = process.sp as u64;
+            assert_eq!(sp % 16, 0);
+            assert_eq!(read_u64_at(&process, sp), 2);
+            let arg = paging::translate(root, read_u64_at(&process, sp + 16)).unwrap();
+            assert_eq!(unsafe { core::slice::from_raw_parts(arg as *const u8, 3) }, b"-v\0");
+            assert_eq!((read_u64_at(&process, sp + 24), read_u64_at(&process, sp + 32)), (0, 0));
+            assert_eq!(read_u64_at(&process, sp + 40), AT_PHENT);
+
+            // Only the top of the stack is mapped so far
+            let bottom = (process.stack_base - process.stack_size) as u64;
+            assert!(paging::translate(root, process.stack_base as u64 - 8).is_some());
+            assert_eq!(paging::translate(root, bottom), None);
+
+            release(&mut process);
+            paging::release(&mut process);
+            assert_eq!(memory::stats().free, free);
+        }
+
+        fn only_processes_with_an_address_space_exec() {
+            let image = executable(ET_EXEC, [PF_X, PF_W], 0);
+            ptable::insert(Process::new(9982, 0, "kthread")).unwrap();
+            assert_eq!(exec(9982, &image, &["init"]), Err(Errno::EINVAL));
+            assert_eq!(exec(u64::MAX, &image, &["init"]), Err(Errno::ESRCH));
+            assert_eq!(sys_execveat(9982, 0, 0, 0, 0), Err(Errno::EINVAL));
+            ptable::remove(9982);
+        }
+    }
+}
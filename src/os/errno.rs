@@ -0,0 +1,143 @@
+/// POSIX error numbers returned by kernel services and, negated, by system calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    /// Operation not permitted.
+    EPERM = 1,
+
+    /// No such file or directory.
+    ENOENT = 2,
+
+    /// No such process.
+    ESRCH = 3,
+
+    /// Interrupted system call.
+    EINTR = 4,
+
+    /// I/O error.
+    EIO = 5,
+
+    /// Argument list too long.
+    E2BIG = 7,
+
+    /// Exec format error.
+    ENOEXEC = 8,
+
+    /// Bad file descriptor.
+    EBADF = 9,
+
+    /// No child processes.
+    ECHILD = 10,
+
+    /// Resource temporarily unavailable.
+    EAGAIN = 11,
+
+    /// Out of memory.
+    ENOMEM = 12,
+
+    /// Permission denied.
+    EACCES = 13,
+
+    /// Bad address.
+    EFAULT = 14,
+
+    /// Device or resource busy.
+    EBUSY = 16,
+
+    /// File exists.
+    EEXIST = 17,
+
+    /// No such device.
+    ENODEV = 19,
+
+    /// Not a directory.
+    ENOTDIR = 20,
+
+    /// Is a directory.
+    EISDIR = 21,
+
+    /// Invalid argument.
+    EINVAL = 22,
+
+    /// Too many open files.
+    EMFILE = 24,
+
+    /// Inappropriate ioctl for device.
+    ENOTTY = 25,
+
+    /// File too large.
+    EFBIG = 27,
+
+    /// No space left on device.
+    ENOSPC = 28,
+
+    /// Illegal seek.
+    ESPIPE = 29,
+
+    /// Read-only file system.
+    EROFS = 30,
+
+    /// Result out of range.
+    ERANGE = 34,
+
+    /// File name too long.
+    ENAMETOOLONG = 36,
+
+    /// Function not implemented.
+    ENOSYS = 38,
+
+    /// Identifier removed.
+    EIDRM = 43,
+
+    /// Socket operation on non-socket.
+    ENOTSOCK = 88,
+
+    /// Destination address required.
+    EDESTADDRREQ = 89,
+
+    /// Message too long.
+    EMSGSIZE = 90,
+
+    /// Protocol not supported.
+    EPROTONOSUPPORT = 93,
+
+    /// Address family not supported by protocol.
+    EAFNOSUPPORT = 97,
+
+    /// Address already in use.
+    EADDRINUSE = 98,
+
+    /// Network is down.
+    ENETDOWN = 100,
+
+    /// No route to host.
+    EHOSTUNREACH = 113,
+}
+
+impl Errno {
+    /// The value a system call returns to user space for this error (`-errno`).
+    pub fn as_syscall_return(self) -> i64 {
+        -(self as i64)
+    }
+
+    /// Converts a raw Linux error number (e.g. from a 9P server) to an `Errno`, mapping codes
+    /// the kernel has no variant for to `EIO`.
+    pub fn from_raw(code: u32) -> Errno {
+        const KNOWN: &[Errno] = &[
+            Errno::EPERM, Errno::ENOENT, Errno::ESRCH, Errno::EINTR, Errno::EIO, Errno::E2BIG,
+            Errno::ENOEXEC, Errno::EBADF, Errno::ECHILD, Errno::EAGAIN, Errno::ENOMEM,
+            Errno::EACCES, Errno::EFAULT, Errno::EBUSY, Errno::EEXIST, Errno::ENODEV,
+            Errno::ENOTDIR, Errno::EISDIR, Errno::EINVAL, Errno::EMFILE, Errno::ENOTTY,
+            Errno::EFBIG, Errno::ENOSPC, Errno::ESPIPE, Errno::EROFS, Errno::ERANGE, Errno::ENAMETOOLONG,
+            Errno::ENOSYS, Errno::EIDRM, Errno::ENOTSOCK, Errno::EDESTADDRREQ, Errno::EMSGSIZE,
+            Errno::EPROTONOSUPPORT, Errno::EAFNOSUPPORT, Errno::EADDRINUSE, Errno::ENETDOWN,
+            Errno::EHOSTUNREACH,
+        ];
+
+        KNOWN.iter().copied().find(|errno| *errno as u32 == code).unwrap_or(Errno::EIO)
+    }
+}
+
+/// Result type used by kernel services that can fail with an [`Errno`].
+pub type KResult<T> = Result<T, Errno>;
@@ -0,0 +1,466 @@
+//! Process exit, reparenting and reaping.
+//!
+//! An exiting process gives up its timers, message queues and address space at once, but its
+//! PCB stays in the process table as a zombie, holding the exit code until the parent collects
+//! it with `wait4`; only then is it reaped: removed from the table, its namespace, mount and cgroup
+//! references dropped, its kernel stack and its PID freed.
+//!
+//! Children outliving their parent are adopted by a reaper: the init (PID 1) of the innermost
+//! namespace of theirs that still has one, or the kernel (PID 0) when none does. Nobody waits
+//! for adopted processes, so the kernel reaps them itself as soon as they exit, as it does for
+//! zombies whose parent is gone or is the kernel. [`reap_orphans`] sweeps up any such zombie
+//! the exit path missed, so no PCB outlives both its process and its parent.
+
+use crate::os::cgroup;
+use crate::os::deadlock;
+#[cfg(target_arch = "x86_64")]
+use crate::os::elf;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file;
+use crate::os::hrtimer;
+use crate::os::ipc;
+use crate::os::itimer;
+use crate::os::jobctl::{self, JobEvent};
+use crate::os::kthread;
+use crate::os::mmap;
+use crate::os::mount;
+#[cfg(target_arch = "x86_64")]
+use crate::os::paging;
+use crate::os::pid;
+use crate::os::pidns::{self, INIT_PID, PidLinks, ROOT_NS};
+use crate::os::process::{Process, ProcessState, WaitTarget};
+use crate::os::ptable::{self, MAX_PROCESSES};
+use crate::os::swap;
+use crate::os::thp;
+use crate::os::uaccess;
+
+/// `wait4` option: return at once instead of blocking when no child has exited yet.
+pub const WNOHANG: u32 = 1;
+
+/// `wait4` option: also report children stopped by a signal.
+pub const WUNTRACED: u32 = 2;
+
+/// `wait4` option: also report stopped children continued by `SIGCONT`.
+pub const WCONTINUED: u32 = 8;
+
+/// What [`wait`] found happened to a child.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildStatus {
+    /// Exited with this code; the child has been reaped.
+    Exited(i32),
+
+    /// Stopped by this signal.
+    Stopped(u32),
+
+    /// Continued after a stop.
+    Continued,
+}
+
+impl ChildStatus {
+    /// The status as `wait4` stores it, for the `WIFEXITED` family of macros.
+    pub fn wstatus(self) -> i32 {
+        match self {
+            ChildStatus::Exited(code) => (code & 0xff) << 8,
+            ChildStatus::Stopped(signal) => ((signal as i32 & 0xff) << 8) | 0x7f,
+            ChildStatus::Continued => 0xffff,
+        }
+    }
+}
+
+const SIGCHLD: u32 = 17;
+
+// Adopts processes when no namespace init is left to
+const KERNEL_PID: u64 = 0;
+
+// The reaper for `child`, whose parent `exiting` is going away: the init of its innermost
+// namespace that has a live one other than the two of them, or else the kernel
+fn find_reaper(links: &PidLinks, child: u64, exiting: u64) -> u64 {
+    for level in (0..=links.level as usize).rev() {
+        let Some(init) = pidns::to_global(links.ns[level], INIT_PID) else {
+            continue;
+        };
+        if init == exiting || init == child {
+            continue;
+        }
+
+        let alive = ptable::with_process(init, |p| p.state != ProcessState::Terminated);
+        if alive == Some(true) {
+            return init;
+        }
+    }
+
+    KERNEL_PID
+}
+
+// Removes the zombie `pid` from the process table and drops what it still holds, returning
+// its exit code
+fn reap(pid: u64) -> Option<i32> {
+    let mut process = ptable::remove(pid)?;
+
+    pidns::detach(&mut process);
+    mount::release(&mut process);
+    cgroup::release(&mut process);
+    kthread::release(&mut process);
+    pid::free(pid);
+
+    Some(process.exit_code.unwrap_or(0))
+}
+
+// Whether a zombie with parent `ppid` is left for the kernel to reap
+fn unwaited(adopted: bool, ppid: u64) -> bool {
+    adopted
+        || ppid == KERNEL_PID
+        || ptable::with_process(ppid, |p| p.state == ProcessState::Terminated) != Some(false)
+}
+
+/// Terminates process `pid` with exit code `code`: closes its files, releases its timers,
+/// message queues and address space, hangs up the terminal of the session it leads, hands its children to a
+/// reaper and leaves it a zombie for its parent, which is sent `SIGCHLD` and woken if it is
+/// waiting. Processes nobody will wait for are reaped at once.
+/// Fails with `EPERM` for the kernel and `ESRCH` if there is no such live process.
+pub fn exit(pid: u64, code: i32) -> KResult<()> {
+    if pid == KERNEL_PID {
+        return Err(Errno::EPERM);
+    }
+
+    let (files, ppid, adopted, sid) = ptable::with_process(pid, |process| {
+        if process.state == ProcessState::Terminated {
+            return Err(Errno::ESRCH);
+        }
+
+        let files = file::release(process);
+        itimer::release(process);
+        hrtimer::release(process);
+        swap::release(process);
+        thp::release(process);
+        mmap::release(process);
+        #[cfg(target_arch = "x86_64")]
+        elf::release(process);
+        #[cfg(target_arch = "x86_64")]
+        paging::release(process);
+
+        process.state = ProcessState::Terminated;
+        process.exit_code = Some(code);
+        process.waiting_on = None;
+        process.wakeup_time = None;
+        Ok((files, process.ppid, process.adopted, process.sid))
+    })
+    .ok_or(Errno::ESRCH)??;
+    drop(files);
+
+    ipc::release(pid);
+    deadlock::forget_process(pid);
+    if sid == pid {
+        jobctl::end_session(sid);
+    }
+
+    let mut children = [0u64; MAX_PROCESSES];
+    let mut count = 0;
+    ptable::for_each(|p| {
+        if p.ppid == pid && p.pid != pid {
+            children[count] = p.pid;
+            count += 1;
+        }
+    });
+
+    for &child in &children[..count] {
+        let Some(links) = ptable::with_process(child, |p| p.pid_links) else {
+            continue;
+        };
+        let reaper = find_reaper(&links, child, pid);
+
+        let zombie = ptable::with_process(child, |p| {
+            p.ppid = reaper;
+            p.adopted = true;
+            p.state == ProcessState::Terminated
+        });
+        if zombie == Some(true) {
+            reap(child);
+        }
+    }
+
+    if unwaited(adopted, ppid) {
+        reap(pid);
+        return Ok(());
+    }
+
+    notify_parent(pid, ppid);
+    Ok(())
+}
+
+/// Tells `ppid` that its child `child` exited, stopped or continued: raises `SIGCHLD` and
+/// wakes the parent if it is waiting for that child.
+pub fn notify_parent(child: u64, ppid: u64) {
+    ptable::with_process(ppid, |parent| {
+        parent.signal_bitmap |= 1 << SIGCHLD;
+
+        if parent.waiting_on == Some(WaitTarget::AnyChild) || parent.waiting_on == Some(WaitTarget::PID(child)) {
+            parent.wake();
+        }
+    });
+}
+
+// The children a `wait` call is after
+#[derive(Clone, Copy)]
+enum Children {
+    Any,
+    Pid(u64),
+    Group(u64),
+}
+
+/// Collects an exited child of the caller `caller`: `pid` is a PID in the caller's namespace,
+/// -1 for any child, 0 for any child in the caller's process group or `-pgid` for any child in
+/// group `pgid`. Returns the child's PID as the caller sees it and its exit code,
+/// having reaped it; with [`WUNTRACED`] and [`WCONTINUED`] a stop or continue not yet reported
+/// is returned instead, the child left as it is. With nothing to report, returns `None` and,
+/// unless `options` has [`WNOHANG`], blocks the caller until there is; the call is restarted
+/// once it is runnable again. Fails with `ECHILD` if there is no such child and `EINVAL` for
+/// unknown options.
+pub fn wait(caller: u64, pid: i64, options: u32) -> KResult<Option<(u64, ChildStatus)>> {
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let (ns, pgid) = ptable::with_process(caller, |process| (process.pid_links.namespace(), process.pgid))
+        .ok_or(Errno::ESRCH)?;
+
+    // The root namespace numbers groups as the kernel does, so one whose leader is gone can
+    // still be named there
+    let group = |id: u64| match ns {
+        ROOT_NS => Some(id),
+        ns => pidns::to_global(ns, id),
+    };
+    let target = match pid {
+        -1 => Children::Any,
+        0 => Children::Group(pgid),
+        pid if pid > 0 => Children::Pid(pidns::to_global(ns, pid as u64).ok_or(Errno::ECHILD)?),
+        pid => Children::Group(group(pid.unsigned_abs()).ok_or(Errno::ECHILD)?),
+    };
+
+    let wanted = |event: JobEvent| match event {
+        JobEvent::Stopped(_) => options & WUNTRACED != 0,
+        JobEvent::Continued => options & WCONTINUED != 0,
+    };
+
+    // Blocked before looking, so a child exiting during the scan wakes the caller instead of
+    // finding it not yet waiting
+    let block = options & WNOHANG == 0;
+    if block {
+        ptable::with_process(caller, |process| {
+            process.waiting_on = Some(match target {
+                Children::Pid(pid) => WaitTarget::PID(pid),
+                Children::Any | Children::Group(_) => WaitTarget::AnyChild,
+            });
+            process.state = ProcessState::Blocked;
+        });
+    }
+
+    let mut found = false;
+    let mut ready = None;
+    ptable::for_each(|p| {
+        let wanted_child = match target {
+            Children::Any => true,
+            Children::Pid(pid) => p.pid == pid,
+            Children::Group(pgid) => p.pgid == pgid,
+        };
+        if p.ppid != caller || p.pid == caller || !wanted_child {
+            return;
+        }
+
+        found = true;
+        if ready.is_some() {
+            return;
+        }
+        if p.state == ProcessState::Terminated {
+            ready = Some((p.pid, None));
+        } else if let Some(event) = p.job_event.filter(|event| wanted(*event)) {
+            p.job_event = None;
+            ready = Some((p.pid, Some(event)));
+        }
+    });
+
+    if block && (!found || ready.is_some()) {
+        ptable::with_process(caller, |process| process.wake());
+    }
+    if !found {
+        return Err(Errno::ECHILD);
+    }
+
+    let Some((child, event)) = ready else {
+        return Ok(None);
+    };
+    let visible = pidns::from_global(ns, child).unwrap_or(0);
+    let status = match event {
+        Some(JobEvent::Stopped(signal)) => ChildStatus::Stopped(signal),
+        Some(JobEvent::Continued) => ChildStatus::Continued,
+        None => ChildStatus::Exited(reap(child).unwrap_or(0)),
+    };
+    Ok(Some((visible, status)))
+}
+
+/// `wait4(pid, status, options, rusage)` without `rusage`: as [`wait`], writing the child's
+/// wait status to `status` unless it is null. Returns the child's PID, or 0 if none has
+/// exited yet.
+pub fn sys_wait4(caller: u64, pid: i64, status: usize, options: u32) -> KResult<u64> {
+    let Some((child, child_status)) = wait(caller, pid, options)? else {
+        return Ok(0);
+    };
+
+    if status != 0 {
+        uaccess::copy_to_user(status, &child_status.wstatus().to_ne_bytes())?;
+    }
+    Ok(child)
+}
+
+/// Reaps zombies nobody will wait for: adopted ones and those whose parent is the kernel or
+/// gone. Returns how many it reaped. Called from the main loop, behind [`exit`], which
+/// normally reaps them itself.
+pub fn reap_orphans() -> usize {
+    let mut zombies = [(0u64, 0u64, false); MAX_PROCESSES];
+    let mut count = 0;
+    ptable::for_each(|p| {
+        if p.state == ProcessState::Terminated && p.pid != KERNEL_PID {
+            zombies[count] = (p.pid, p.ppid, p.adopted);
+            count += 1;
+        }
+    });
+
+    let mut reaped = 0;
+    for &(pid, ppid, adopted) in &zombies[..count] {
+        if unwaited(adopted, ppid) && reap(pid).is_some() {
+            reaped += 1;
+        }
+    }
+    reaped
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn spawn(pid: u64, ppid: u64) {
+        let mut process = Process::new(pid, ppid, "exit");
+        process.state = ProcessState::Ready;
+        ptable::insert(process).unwrap();
+    }
+
+    fn exists(pid: u64) -> bool {
+        ptable::with_process(pid, |_| ()).is_some()
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn deep_orphan_chains_leak_nothing() {
+            let baseline = ptable::count();
+
+            // 9900 -> 9901 -> ... -> 9915, each the parent of the next
+            for pid in 9900..9916 {
+                spawn(pid, if pid == 9900 { KERNEL_PID } else { pid - 1 });
+            }
+
+            // Exiting every other link from the top orphans the rest, while the exited ones
+            // stay zombies for their still-live parents
+            for pid in (9900..9916).step_by(2) {
+                assert_eq!(exit(pid, 0), Ok(()));
+            }
+            for pid in (9901..9916).step_by(2) {
+                let adopted = ptable::with_process(pid, |p| (p.adopted, p.ppid));
+                assert_eq!(adopted, Some((true, find_reaper(&PidLinks::root(pid), pid, 0))));
+            }
+
+            for pid in (9901..9916).rev().step_by(2) {
+                assert_eq!(exit(pid, 0), Ok(()));
+            }
+            assert_eq!(reap_orphans(), 0);
+            assert_eq!(ptable::count(), baseline);
+        }
+
+        fn zombies_wait_for_their_parent() {
+            spawn(9920, KERNEL_PID);
+            spawn(9921, 9920);
+
+            assert_eq!(exit(9921, 3), Ok(()));
+            assert!(exists(9921));
+            assert_eq!(reap_orphans(), 0);
+
+            let signalled = ptable::with_process(9920, |parent| parent.signal_bitmap & (1 << SIGCHLD) != 0);
+            assert_eq!(signalled, Some(true));
+            assert_eq!(wait(9920, 9921, 0), Ok(Some((9921, ChildStatus::Exited(3)))));
+            assert_eq!(ptable::with_process(9920, |parent| (parent.state, parent.waiting_on)), Some((ProcessState::Ready, None)));
+            assert!(!exists(9921));
+
+            assert_eq!(exit(9920, 0), Ok(()));
+            assert!(!exists(9920));
+        }
+
+        fn orphaned_zombies_are_reaped() {
+            spawn(9930, KERNEL_PID);
+            spawn(9931, 9930);
+            spawn(9932, 9931);
+
+            // A zombie whose parent exits goes with it
+            assert_eq!(exit(9932, 0), Ok(()));
+            assert!(exists(9932));
+            assert_eq!(exit(9931, 0), Ok(()));
+            assert!(exists(9931) && !exists(9932));
+
+            // One whose parent vanishes without exiting is reaped on exit, or by the sweep if
+            // it exited before
+            spawn(9933, 9930);
+            assert!(ptable::remove(9930).is_some());
+            assert_eq!(exit(9933, 0), Ok(()));
+            assert!(!exists(9933));
+            assert_eq!(reap_orphans(), 1);
+            assert!(!exists(9931));
+
+            assert_eq!(exit(KERNEL_PID, 0), Err(Errno::EPERM));
+            assert_eq!(exit(9933, 0), Err(Errno::ESRCH));
+        }
+
+        fn wait_without_children_or_exits() {
+            spawn(9940, KERNEL_PID);
+            assert_eq!(wait(9940, -1, 0), Err(Errno::ECHILD));
+            assert_eq!(ptable::with_process(9940, |parent| parent.state), Some(ProcessState::Ready));
+            assert_eq!(wait(9940, 0, 0), Err(Errno::ECHILD));
+            assert_eq!(wait(9940, -1, 0x100), Err(Errno::EINVAL));
+
+            spawn(9941, 9940);
+            assert_eq!(wait(9940, -1, WNOHANG), Ok(None));
+            assert_eq!(ptable::with_process(9940, |parent| parent.waiting_on), Some(None));
+            assert_eq!(sys_wait4(9940, 9941, 0, WNOHANG), Ok(0));
+
+            // Group waits match the child's process group, not its parent's
+            assert_eq!(wait(9940, 0, WNOHANG), Err(Errno::ECHILD));
+            assert_eq!(wait(9940, -9941, WNOHANG), Ok(None));
+            assert_eq!(wait(9940, -9949, WNOHANG), Err(Errno::ECHILD));
+            ptable::with_process(9941, |child| child.pgid = 9940);
+            assert_eq!(wait(9940, 0, WNOHANG), Ok(None));
+            assert_eq!(wait(9940, -9940, WNOHANG), Ok(None));
+
+            // With 9940 gone from the table, the kernel reaps its child
+            ptable::remove(9940);
+            assert_eq!(wait(9940, -1, 0), Err(Errno::ESRCH));
+            assert_eq!(exit(9941, 0), Ok(()));
+            assert!(!exists(9941));
+        }
+
+        fn exit_wakes_a_waiting_parent() {
+            spawn(9950, KERNEL_PID);
+            spawn(9951, 9950);
+            spawn(9952, 9950);
+
+            assert_eq!(wait(9950, -1, 0), Ok(None));
+            let blocked = ptable::with_process(9950, |p| (p.state, p.waiting_on));
+            assert_eq!(blocked, Some((ProcessState::Blocked, Some(WaitTarget::AnyChild))));
+
+            assert_eq!(exit(9952, 7), Ok(()));
+            let woken = ptable::with_process(9950, |p| (p.state, p.waiting_on));
+            assert_eq!(woken, Some((ProcessState::Ready, None)));
+
+            // The parent exiting takes its zombie with it and hands the live child on
+            assert_eq!(exit(9950, 0), Ok(()));
+            assert!(!exists(9950) && !exists(9952));
+            assert_eq!(ptable::with_process(9951, |p| p.adopted), Some(true));
+            assert_eq!(exit(9951, 0), Ok(()));
+            assert!(!exists(9951));
+        }
+    }
+}
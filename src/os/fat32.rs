@@ -0,0 +1,872 @@
+//! FAT32, the filesystem of the EFI system partition.
+//!
+//! [`attach`] reads the boot sector of a FAT32 volume on a [`BlockDevice`] and mounts it at
+//! `/boot` in the root namespace; mounting `vfat` anywhere else shows the same volume. Paths
+//! are looked up a directory at a time, each component matched against long (VFAT) and 8.3
+//! short names alike, ignoring ASCII case. Files can be read and written, growing by whole
+//! clusters as needed, and are truncated when opened with `O_TRUNC`. Nothing can be created,
+//! renamed or removed yet, and timestamps are left as they are.
+//!
+//! The device is addressed in page-sized blocks while the volume lays itself out in sectors,
+//! so all access goes byte-wise through a one-block buffer that every change writes through.
+//! Every copy of the FAT is updated together. One [`Mutex`] serialises access to the volume,
+//! so a process reading a file waits for the one writing it.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::os::block::{BLOCK_SIZE, BlockDevice};
+use crate::os::cred::ROOT_ID;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file::{MAX_FILES, O_ACCMODE, O_RDONLY, O_TRUNC};
+use crate::os::mount::{self, FsType, Path};
+use crate::os::process::Process;
+use crate::os::sync::{Mutex, SpinLock};
+use crate::os::vfs::{self, FileSystem, Inode, InodeKind};
+
+/// Where [`attach`] mounts the volume.
+pub const MOUNT_POINT: &str = "/boot";
+
+// Boot sector fields, by byte offset
+const BPB_BYTES_PER_SECTOR: u64 = 11;
+const BPB_SECTORS_PER_CLUSTER: u64 = 13;
+const BPB_RESERVED_SECTORS: u64 = 14;
+const BPB_FATS: u64 = 16;
+const BPB_ROOT_ENTRIES: u64 = 17;
+const BPB_TOTAL_SECTORS_16: u64 = 19;
+const BPB_FAT_SIZE_16: u64 = 22;
+const BPB_TOTAL_SECTORS_32: u64 = 32;
+const BPB_FAT_SIZE_32: u64 = 36;
+const BPB_ROOT_CLUSTER: u64 = 44;
+const BOOT_SIGNATURE: u64 = 510;
+
+// Directory entry fields, by byte offset
+const DIR_ATTR: u64 = 11;
+const DIR_CLUSTER_HIGH: u64 = 20;
+const DIR_CLUSTER_LOW: u64 = 26;
+const DIR_SIZE: u64 = 28;
+const DIR_ENTRY_SIZE: u64 = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+// First name byte of a deleted entry, and what stands for a real 0xe5 there
+const DELETED: u8 = 0xe5;
+const KANJI_E5: u8 = 0x05;
+
+// Long name entries: the flag on the last (first stored) one, and characters per entry
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+const MAX_LONG_ENTRIES: usize = 20;
+
+// FAT entries hold 28 bits; values from END_OF_CHAIN up end a chain
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+
+/// A FAT32 volume.
+pub struct Volume {
+    device: &'static dyn BlockDevice,
+
+    // Byte offsets and sizes on the device
+    fat_start: u64,
+    fat_size: u64,
+    fats: u32,
+    data_start: u64,
+    cluster_size: u64,
+
+    // Clusters are numbered from 2
+    clusters: u32,
+    root_cluster: u32,
+
+    // The block last read, if still valid
+    block: Vec<u8>,
+    cached: Option<u64>,
+}
+
+// A file or directory found by a lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+
+    // Where its directory entry lies on the device; 0 for the root, which has none
+    at: u64,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+static VOLUME: Mutex<Option<Volume>> = Mutex::new(None);
+
+// Open files, by inode number
+static OPEN: SpinLock<[Option<Entry>; MAX_FILES]> = SpinLock::new([None; MAX_FILES]);
+
+/// The attached volume as the VFS opens it.
+pub static FS: Fat32 = Fat32;
+
+pub struct Fat32;
+
+/// Reads the FAT32 volume on `device` and mounts it at [`MOUNT_POINT`]. Fails with `EINVAL` if
+/// `device` holds no FAT32 volume and `EBUSY` if one is attached already.
+pub fn attach(device: &'static dyn BlockDevice) -> KResult<()> {
+    let volume = Volume::new(device)?;
+    let (clusters, cluster_size) = (volume.clusters, volume.cluster_size);
+
+    {
+        let mut attached = VOLUME.lock();
+        if attached.is_some() {
+            return Err(Errno::EBUSY);
+        }
+        *attached = Some(volume);
+    }
+    mount::mount_in_root(FsType::Fat32, MOUNT_POINT)?;
+
+    log::info!("fat32: {} on {}, {} clusters of {} bytes", device.name(), MOUNT_POINT, clusters, cluster_size);
+    Ok(())
+}
+
+fn with_volume<R>(f: impl FnOnce(&mut Volume) -> KResult<R>) -> KResult<R> {
+    VOLUME.lock().as_mut().map_or(Err(Errno::ENODEV), f)
+}
+
+impl Volume {
+    /// Reads the boot sector of the volume on `device`. Fails with `EINVAL` if it does not
+    /// describe a FAT32 volume that fits on the device.
+    pub fn new(device: &'static dyn BlockDevice) -> KResult<Volume> {
+        let mut volume = Volume {
+            device,
+            fat_start: 0,
+            fat_size: 0,
+            fats: 0,
+            data_start: 0,
+            cluster_size: 0,
+            clusters: 0,
+            root_cluster: 0,
+            block: vec![0; BLOCK_SIZE],
+            cached: None,
+        };
+
+        let bytes_per_sector = volume.read_u16(BPB_BYTES_PER_SECTOR)? as u64;
+        let sectors_per_cluster = volume.read_u8(BPB_SECTORS_PER_CLUSTER)? as u64;
+        let reserved = volume.read_u16(BPB_RESERVED_SECTORS)? as u64;
+        let fats = volume.read_u8(BPB_FATS)? as u32;
+        let total = match volume.read_u16(BPB_TOTAL_SECTORS_16)? {
+            0 => volume.read_u32(BPB_TOTAL_SECTORS_32)? as u64,
+            total => total as u64,
+        };
+        let fat_sectors = volume.read_u32(BPB_FAT_SIZE_32)? as u64;
+
+        // FAT12 and FAT16 have a fixed root directory and a 16-bit FAT size instead
+        let fat32 = volume.read_u16(BPB_ROOT_ENTRIES)? == 0 && volume.read_u16(BPB_FAT_SIZE_16)? == 0;
+        let valid = volume.read_u16(BOOT_SIGNATURE)? == 0xaa55
+            && fat32
+            && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && sectors_per_cluster.is_power_of_two()
+            && fats > 0
+            && fat_sectors > 0;
+        let data_sectors = total.checked_sub(reserved + fats as u64 * fat_sectors).filter(|_| valid);
+        let Some(data_sectors) = data_sectors else {
+            return Err(Errno::EINVAL);
+        };
+
+        volume.fat_start = reserved * bytes_per_sector;
+        volume.fat_size = fat_sectors * bytes_per_sector;
+        volume.fats = fats;
+        volume.data_start = volume.fat_start + fats as u64 * volume.fat_size;
+        volume.cluster_size = sectors_per_cluster * bytes_per_sector;
+
+        // As many clusters as both the data area and one FAT have room for
+        let clusters = (data_sectors / sectors_per_cluster).min(volume.fat_size / 4 - 2);
+        volume.clusters = u32::try_from(clusters).map_err(|_| Errno::EINVAL)?;
+        volume.root_cluster = volume.read_u32(BPB_ROOT_CLUSTER)?;
+
+        if total * bytes_per_sector > device.blocks() * BLOCK_SIZE as u64 || volume.check(volume.root_cluster).is_err() {
+            return Err(Errno::EINVAL);
+        }
+        Ok(volume)
+    }
+
+    fn buffer(&mut self) -> &mut [u8; BLOCK_SIZE] {
+        self.block.first_chunk_mut().expect("fat32: short block buffer")
+    }
+
+    fn load(&mut self, index: u64) -> KResult<()> {
+        if self.cached != Some(index) {
+            self.cached = None;
+            let device = self.device;
+            device.read_block(index, self.buffer())?;
+            self.cached = Some(index);
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, mut at: u64, buf: &mut [u8]) -> KResult<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let start = (at % BLOCK_SIZE as u64) as usize;
+            let n = (buf.len() - done).min(BLOCK_SIZE - start);
+
+            self.load(at / BLOCK_SIZE as u64)?;
+            buf[done..done + n].copy_from_slice(&self.block[start..start + n]);
+            done += n;
+            at += n as u64;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, mut at: u64, data: &[u8]) -> KResult<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let index = at / BLOCK_SIZE as u64;
+            let start = (at % BLOCK_SIZE as u64) as usize;
+            let n = (data.len() - done).min(BLOCK_SIZE - start);
+
+            self.load(index)?;
+            self.block[start..start + n].copy_from_slice(&data[done..done + n]);
+            let device = self.device;
+            if let Err(errno) = device.write_block(index, self.buffer()) {
+                self.cached = None;
+                return Err(errno);
+            }
+            done += n;
+            at += n as u64;
+        }
+        Ok(())
+    }
+
+    fn read_u8(&mut self, at: u64) -> KResult<u8> {
+        let mut bytes = [0; 1];
+        self.read_at(at, &mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn read_u16(&mut self, at: u64) -> KResult<u16> {
+        let mut bytes = [0; 2];
+        self.read_at(at, &mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn read_u32(&mut self, at: u64) -> KResult<u32> {
+        let mut bytes = [0; 4];
+        self.read_at(at, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    // Fails with EIO for a cluster outside the data area: a FAT or directory pointing there is
+    // corrupt
+    fn check(&self, cluster: u32) -> KResult<()> {
+        if cluster < 2 || cluster - 2 >= self.clusters {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    fn cluster_at(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size
+    }
+
+    fn fat(&mut self, cluster: u32) -> KResult<u32> {
+        self.check(cluster)?;
+        Ok(self.read_u32(self.fat_start + cluster as u64 * 4)? & CLUSTER_MASK)
+    }
+
+    // Sets `cluster`'s entry in every FAT, keeping the reserved top bits
+    fn set_fat(&mut self, cluster: u32, value: u32) -> KResult<()> {
+        self.check(cluster)?;
+        for copy in 0..self.fats as u64 {
+            let at = self.fat_start + copy * self.fat_size + cluster as u64 * 4;
+            let old = self.read_u32(at)?;
+            self.write_at(at, &((old & !CLUSTER_MASK) | value).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // The cluster after `cluster` in its chain, or None at the end
+    fn next(&mut self, cluster: u32) -> KResult<Option<u32>> {
+        match self.fat(cluster)? {
+            next if next >= END_OF_CHAIN => Ok(None),
+            next => self.check(next).map(|()| Some(next)),
+        }
+    }
+
+    // Takes a free cluster, zeroed, and chains it after `prev`
+    fn alloc(&mut self, prev: Option<u32>) -> KResult<u32> {
+        let mut free = None;
+        for cluster in 2..self.clusters + 2 {
+            if self.fat(cluster)? == 0 {
+                free = Some(cluster);
+                break;
+            }
+        }
+        let cluster = free.ok_or(Errno::ENOSPC)?;
+
+        let zeros = [0u8; 512];
+        let start = self.cluster_at(cluster);
+        for at in (0..self.cluster_size).step_by(zeros.len()) {
+            self.write_at(start + at, &zeros)?;
+        }
+
+        self.set_fat(cluster, CLUSTER_MASK)?;
+        if let Some(prev) = prev {
+            self.set_fat(prev, cluster)?;
+        }
+        Ok(cluster)
+    }
+
+    fn free_chain(&mut self, first: u32) -> KResult<()> {
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            cluster = self.next(current)?;
+            self.set_fat(current, 0)?;
+        }
+        Ok(())
+    }
+
+    // Free clusters left
+    fn free_clusters(&mut self) -> KResult<u32> {
+        let mut free = 0;
+        for cluster in 2..self.clusters + 2 {
+            if self.fat(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    // Calls `f` on each entry of the directory whose chain starts at `first`, with where it
+    // lies, until `f` returns something or the entries run out
+    fn scan_dir<R>(&mut self, first: u32, mut f: impl FnMut(&[u8; 32], u64) -> Option<R>) -> KResult<Option<R>> {
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            let start = self.cluster_at(current);
+            for at in (start..start + self.cluster_size).step_by(DIR_ENTRY_SIZE as usize) {
+                let mut raw = [0u8; DIR_ENTRY_SIZE as usize];
+                self.read_at(at, &mut raw)?;
+
+                // A free entry with nothing in use after it
+                if raw[0] == 0 {
+                    return Ok(None);
+                }
+                if let Some(found) = f(&raw, at) {
+                    return Ok(Some(found));
+                }
+            }
+            cluster = self.next(current)?;
+        }
+        Ok(None)
+    }
+
+    // Looks `name` up in the directory whose chain starts at `first`
+    fn find(&mut self, first: u32, name: &str) -> KResult<Option<Entry>> {
+        let mut long = LongName::new();
+
+        self.scan_dir(first, |raw, at| {
+            if raw[0] == DELETED {
+                long.reset();
+                return None;
+            }
+            if raw[DIR_ATTR as usize] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                long.add(raw);
+                return None;
+            }
+
+            let matches = long.name(raw).is_some_and(|units| long_name_matches(units, name)) || short_name_matches(raw, name);
+            long.reset();
+            if !matches || raw[DIR_ATTR as usize] & ATTR_VOLUME_ID != 0 {
+                return None;
+            }
+
+            let field = |offset: u64| u16::from_le_bytes([raw[offset as usize], raw[offset as usize + 1]]) as u32;
+            let size = u32::from_le_bytes(raw[DIR_SIZE as usize..DIR_SIZE as usize + 4].try_into().unwrap());
+            Some(Entry {
+                attr: raw[DIR_ATTR as usize],
+                first_cluster: (field(DIR_CLUSTER_HIGH) << 16) | field(DIR_CLUSTER_LOW),
+                size,
+                at,
+            })
+        })
+    }
+
+    // Finds what `path`, from the volume's root, names
+    fn lookup(&mut self, path: &Path) -> KResult<Entry> {
+        let mut entry = Entry { attr: ATTR_DIRECTORY, first_cluster: self.root_cluster, size: 0, at: 0 };
+
+        for component in path.components() {
+            if !entry.is_dir() {
+                return Err(Errno::ENOTDIR);
+            }
+            // A `..` entry pointing at the root says cluster 0
+            let dir = if entry.first_cluster == 0 { self.root_cluster } else { entry.first_cluster };
+            entry = self.find(dir, component)?.ok_or(Errno::ENOENT)?;
+        }
+        Ok(entry)
+    }
+
+    // The `index`th cluster of the chain starting at `first`
+    fn seek(&mut self, first: u32, index: u64) -> KResult<u32> {
+        let mut cluster = first;
+        for _ in 0..index {
+            cluster = self.next(cluster)?.ok_or(Errno::EIO)?;
+        }
+        Ok(cluster)
+    }
+
+    fn read_file(&mut self, file: &Entry, offset: u64, buf: &mut [u8]) -> KResult<usize> {
+        let size = file.size as u64;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min((size - offset) as usize);
+        let mut cluster = self.seek(file.first_cluster, offset / self.cluster_size)?;
+        let mut at = offset;
+        let mut done = 0;
+
+        loop {
+            let within = at % self.cluster_size;
+            let n = (len - done).min((self.cluster_size - within) as usize);
+            self.read_at(self.cluster_at(cluster) + within, &mut buf[done..done + n])?;
+            done += n;
+            at += n as u64;
+
+            if done == len {
+                return Ok(done);
+            }
+            cluster = self.next(cluster)?.ok_or(Errno::EIO)?;
+        }
+    }
+
+    // Writes `data` at `offset` in `file`, growing its chain and size as needed. Returns how
+    // much was written, which is short only if the volume filled up part way.
+    fn write_file(&mut self, file: &mut Entry, offset: u64, data: &[u8]) -> KResult<usize> {
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > u32::MAX as u64) {
+            return Err(Errno::EFBIG);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let mut done = 0;
+        let written = self.write_chain(file, offset, data, &mut done);
+        if done > 0 && offset + done as u64 > file.size as u64 {
+            file.size = (offset + done as u64) as u32;
+            self.write_at(file.at + DIR_SIZE, &file.size.to_le_bytes())?;
+        }
+
+        match written {
+            Ok(()) => Ok(done),
+            Err(errno) if done == 0 => Err(errno),
+            Err(_) => Ok(done),
+        }
+    }
+
+    fn write_chain(&mut self, file: &mut Entry, offset: u64, data: &[u8], done: &mut usize) -> KResult<()> {
+        if file.first_cluster == 0 {
+            let first = self.alloc(None)?;
+            self.set_first_cluster(file, first)?;
+        }
+
+        // Walk to the cluster holding `offset`, extending the chain to reach it
+        let mut cluster = file.first_cluster;
+        for _ in 0..offset / self.cluster_size {
+            cluster = match self.next(cluster)? {
+                Some(next) => next,
+                None => self.alloc(Some(cluster))?,
+            };
+        }
+
+        let mut at = offset;
+        loop {
+            let within = at % self.cluster_size;
+            let n = (data.len() - *done).min((self.cluster_size - within) as usize);
+            self.write_at(self.cluster_at(cluster) + within, &data[*done..*done + n])?;
+            *done += n;
+            at += n as u64;
+
+            if *done == data.len() {
+                return Ok(());
+            }
+            cluster = match self.next(cluster)? {
+                Some(next) => next,
+                None => self.alloc(Some(cluster))?,
+            };
+        }
+    }
+
+    fn set_first_cluster(&mut self, file: &mut Entry, cluster: u32) -> KResult<()> {
+        self.write_at(file.at + DIR_CLUSTER_HIGH, &((cluster >> 16) as u16).to_le_bytes())?;
+        self.write_at(file.at + DIR_CLUSTER_LOW, &(cluster as u16).to_le_bytes())?;
+        file.first_cluster = cluster;
+        Ok(())
+    }
+
+    // Empties `file`, freeing its clusters
+    fn truncate(&mut self, file: &mut Entry) -> KResult<()> {
+        if file.first_cluster != 0 {
+            let first = file.first_cluster;
+            self.set_first_cluster(file, 0)?;
+            self.free_chain(first)?;
+        }
+        file.size = 0;
+        self.write_at(file.at + DIR_SIZE, &0u32.to_le_bytes())
+    }
+}
+
+// A long name being put together from the entries before a short one
+struct LongName {
+    units: [u16; MAX_LONG_ENTRIES * LONG_NAME_CHARS],
+    entries: usize,
+
+    // The entry expected next, counting down to 1; 0 once all were seen
+    expected: u8,
+    checksum: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn new() -> Self {
+        LongName { units: [0; MAX_LONG_ENTRIES * LONG_NAME_CHARS], entries: 0, expected: 0, checksum: 0, valid: false }
+    }
+
+    fn reset(&mut self) {
+        self.valid = false;
+    }
+
+    fn add(&mut self, raw: &[u8; 32]) {
+        let sequence = raw[0] & !LAST_LONG_ENTRY;
+        if raw[0] & LAST_LONG_ENTRY != 0 {
+            self.entries = sequence as usize;
+            self.expected = sequence;
+            self.checksum = raw[13];
+            self.valid = (1..=MAX_LONG_ENTRIES).contains(&self.entries);
+        }
+        if !self.valid || sequence != self.expected || raw[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+
+        // Characters 1-5, 6-11 and 12-13 of the entry's part of the name
+        let start = (sequence as usize - 1) * LONG_NAME_CHARS;
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (i, offset) in offsets.enumerate() {
+            self.units[start + i] = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        }
+        self.expected -= 1;
+    }
+
+    // The long name belonging to the short entry `raw`, if one was put together for it
+    fn name(&self, raw: &[u8; 32]) -> Option<&[u16]> {
+        if !self.valid || self.expected != 0 || short_name_checksum(raw) != self.checksum {
+            return None;
+        }
+
+        let units = &self.units[..self.entries * LONG_NAME_CHARS];
+        let len = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+        Some(&units[..len])
+    }
+}
+
+fn short_name_checksum(raw: &[u8; 32]) -> u8 {
+    raw[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn long_name_matches(units: &[u16], name: &str) -> bool {
+    let mut wanted = name.chars();
+    let same = char::decode_utf16(units.iter().copied())
+        .all(|unit| unit.is_ok_and(|c| wanted.next().is_some_and(|w| c.eq_ignore_ascii_case(&w))));
+    same && wanted.next().is_none()
+}
+
+// Matches `name` against the 8.3 name of `raw`, `NAME    EXT` standing for `name.ext`
+fn short_name_matches(raw: &[u8; 32], name: &str) -> bool {
+    let mut short = [0u8; 12];
+    let base = raw[..8].iter().rposition(|&b| b != b' ').map_or(0, |last| last + 1);
+    let ext = raw[8..11].iter().rposition(|&b| b != b' ').map_or(0, |last| last + 1);
+
+    short[..base].copy_from_slice(&raw[..base]);
+    if short[0] == KANJI_E5 {
+        short[0] = DELETED;
+    }
+    let mut len = base;
+    if ext > 0 {
+        short[len] = b'.';
+        short[len + 1..len + 1 + ext].copy_from_slice(&raw[8..8 + ext]);
+        len += 1 + ext;
+    }
+
+    short[..len].eq_ignore_ascii_case(name.as_bytes())
+}
+
+fn open_file(ino: u64) -> KResult<Entry> {
+    OPEN.with(|open| open.get(ino as usize).copied().flatten()).ok_or(Errno::EBADF)
+}
+
+impl FileSystem for Fat32 {
+    fn open(&self, process: &Process, path: &Path, flags: u32) -> KResult<Inode> {
+        with_volume(|volume| {
+            let mut entry = volume.lookup(path)?;
+            let writable = flags & O_ACCMODE != O_RDONLY;
+            if writable && entry.attr & ATTR_READ_ONLY != 0 {
+                return Err(Errno::EACCES);
+            }
+
+            // FAT has no owners: everything is root's, writable by root unless marked read-only
+            let kind = if entry.is_dir() { InodeKind::Directory } else { InodeKind::File };
+            let mode = match kind {
+                InodeKind::Directory => 0o755,
+                InodeKind::File => 0o644,
+            };
+            let mode = if entry.attr & ATTR_READ_ONLY != 0 { mode & !0o222 } else { mode };
+            let mut inode = Inode { ino: 0, kind, size: entry.size as u64, uid: ROOT_ID, gid: ROOT_ID, mode };
+            vfs::check_access(process, &inode, flags)?;
+
+            if writable && flags & O_TRUNC != 0 && !entry.is_dir() {
+                volume.truncate(&mut entry)?;
+            }
+            let ino = OPEN.with(|open| {
+                let ino = open.iter().position(Option::is_none)?;
+                open[ino] = Some(entry);
+                Some(ino)
+            });
+            inode.ino = ino.ok_or(Errno::ENOSPC)? as u64;
+            inode.size = entry.size as u64;
+            Ok(inode)
+        })
+    }
+
+    fn read(&self, ino: u64, buf: &mut [u8], offset: u64) -> KResult<usize> {
+        with_volume(|volume| volume.read_file(&open_file(ino)?, offset, buf))
+    }
+
+    fn write(&self, ino: u64, data: &[u8], offset: u64) -> KResult<usize> {
+        with_volume(|volume| {
+            let mut file = open_file(ino)?;
+            let written = volume.write_file(&mut file, offset, data);
+            OPEN.with(|open| open[ino as usize] = Some(file));
+            written
+        })
+    }
+
+    fn close(&self, ino: u64) {
+        OPEN.with(|open| open[ino as usize] = None);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::file::{O_RDWR, O_WRONLY};
+    use crate::os::vfs;
+
+    const DISK_BLOCKS: usize = 16;
+    const SECTOR: u64 = 512;
+
+    // 128 sectors: 8 reserved, two one-sector FATs, then one-sector clusters from 2 up
+    const TOTAL_SECTORS: u64 = 128;
+    const DATA_START: u64 = 10 * SECTOR;
+    const CLUSTERS: u32 = 118;
+
+    const HELLO: &[u8] = b"hello world";
+
+    // A small RAM disk for a volume of our own
+    struct TestDisk;
+
+    static mut TEST_BLOCKS: [[u8; BLOCK_SIZE]; DISK_BLOCKS] = [[0; BLOCK_SIZE]; DISK_BLOCKS];
+
+    static TEST_DISK: TestDisk = TestDisk;
+
+    fn blocks() -> &'static mut [[u8; BLOCK_SIZE]; DISK_BLOCKS] {
+        unsafe {
+            let blocks = &raw mut TEST_BLOCKS;
+            &mut *blocks
+        }
+    }
+
+    impl BlockDevice for TestDisk {
+        fn name(&self) -> &str {
+            "ktest"
+        }
+
+        fn blocks(&self) -> u64 {
+            DISK_BLOCKS as u64
+        }
+
+        fn read_block(&self, index: u64, buf: &mut [u8; BLOCK_SIZE]) -> KResult<()> {
+            buf.copy_from_slice(blocks().get(index as usize).ok_or(Errno::EIO)?);
+            Ok(())
+        }
+
+        fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> KResult<()> {
+            blocks().get_mut(index as usize).ok_or(Errno::EIO)?.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn put(at: u64, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            let at = at as usize + i;
+            blocks()[at / BLOCK_SIZE][at % BLOCK_SIZE] = byte;
+        }
+    }
+
+    fn short_entry(at: u64, name: &[u8; 11], attr: u8, cluster: u32, size: u32) {
+        put(at, name);
+        put(at + DIR_ATTR, &[attr]);
+        put(at + DIR_CLUSTER_HIGH, &((cluster >> 16) as u16).to_le_bytes());
+        put(at + DIR_CLUSTER_LOW, &(cluster as u16).to_le_bytes());
+        put(at + DIR_SIZE, &size.to_le_bytes());
+    }
+
+    fn long_entry(at: u64, order: u8, part: &str, checksum: u8) {
+        let mut units = [0xffffu16; LONG_NAME_CHARS];
+        part.encode_utf16().chain(Some(0)).take(LONG_NAME_CHARS).enumerate().for_each(|(i, unit)| units[i] = unit);
+
+        put(at, &[order]);
+        put(at + DIR_ATTR, &[ATTR_LONG_NAME]);
+        put(at + 13, &[checksum]);
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (unit, offset) in units.iter().zip(offsets) {
+            put(at + offset as u64, &unit.to_le_bytes());
+        }
+    }
+
+    fn cluster(n: u32) -> u64 {
+        DATA_START + (n as u64 - 2) * SECTOR
+    }
+
+    // Formats the test disk: `/Hello World.txt` (short name HELLOW~1.TXT) in cluster 3, and
+    // `/EFI/BOOTX64.EFI` (read-only) in cluster 5 under `/EFI` in cluster 4
+    fn format() {
+        blocks().iter_mut().for_each(|block| block.fill(0));
+
+        put(BPB_BYTES_PER_SECTOR, &(SECTOR as u16).to_le_bytes());
+        put(BPB_SECTORS_PER_CLUSTER, &[1]);
+        put(BPB_RESERVED_SECTORS, &8u16.to_le_bytes());
+        put(BPB_FATS, &[2]);
+        put(BPB_TOTAL_SECTORS_32, &(TOTAL_SECTORS as u32).to_le_bytes());
+        put(BPB_FAT_SIZE_32, &1u32.to_le_bytes());
+        put(BPB_ROOT_CLUSTER, &2u32.to_le_bytes());
+        put(BOOT_SIGNATURE, &0xaa55u16.to_le_bytes());
+
+        for fat in [8 * SECTOR, 9 * SECTOR] {
+            for (n, value) in [0x0fff_fff8u32, CLUSTER_MASK, CLUSTER_MASK, CLUSTER_MASK, CLUSTER_MASK, CLUSTER_MASK].iter().enumerate() {
+                put(fat + n as u64 * 4, &value.to_le_bytes());
+            }
+        }
+
+        let short = *b"HELLOW~1TXT";
+        let mut raw = [0u8; 32];
+        raw[..11].copy_from_slice(&short);
+        let checksum = short_name_checksum(&raw);
+        long_entry(cluster(2), LAST_LONG_ENTRY | 2, "xt", checksum);
+        long_entry(cluster(2) + 32, 1, "Hello World.t", checksum);
+        short_entry(cluster(2) + 64, &short, 0, 3, HELLO.len() as u32);
+        short_entry(cluster(2) + 96, b"EFI        ", ATTR_DIRECTORY, 4, 0);
+        put(cluster(3), HELLO);
+
+        short_entry(cluster(4), b"BOOTX64 EFI", ATTR_READ_ONLY, 5, 4);
+        put(cluster(5), b"MZ\x90\x00");
+    }
+
+    fn volume() -> Volume {
+        format();
+        Volume::new(&TEST_DISK).unwrap()
+    }
+
+    fn path(path: &str) -> Path {
+        Path::new(path).unwrap()
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn boot_sectors_are_checked() {
+            blocks().iter_mut().for_each(|block| block.fill(0));
+            assert_eq!(Volume::new(&TEST_DISK).map(|_| ()).err(), Some(Errno::EINVAL));
+
+            format();
+            put(BPB_FAT_SIZE_16, &1u16.to_le_bytes());
+            assert_eq!(Volume::new(&TEST_DISK).map(|_| ()).err(), Some(Errno::EINVAL));
+
+            let volume = volume();
+            assert_eq!((volume.clusters, volume.cluster_size, volume.data_start), (CLUSTERS, SECTOR, DATA_START));
+        }
+
+        fn paths_resolve_by_long_and_short_names() {
+            let mut volume = volume();
+
+            let hello = volume.lookup(&path("/hello WORLD.txt")).unwrap();
+            assert_eq!(volume.lookup(&path("/HELLOW~1.TXT")), Ok(hello));
+            assert_eq!((hello.first_cluster, hello.size), (3, HELLO.len() as u32));
+
+            let boot = volume.lookup(&path("/efi/bootx64.efi")).unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(volume.read_file(&boot, 0, &mut buf), Ok(4));
+            assert_eq!(&buf[..2], b"MZ");
+            assert_eq!(volume.read_file(&hello, 6, &mut buf), Ok(5));
+            assert_eq!(&buf[..5], b"world");
+
+            assert_eq!(volume.lookup(&path("/efi/missing")), Err(Errno::ENOENT));
+            assert_eq!(volume.lookup(&path("/hello world.txt/x")), Err(Errno::ENOTDIR));
+            assert_eq!(volume.lookup(&path("/hello world.t")), Err(Errno::ENOENT));
+        }
+
+        fn writes_grow_files_across_clusters() {
+            let mut volume = volume();
+            let mut hello = volume.lookup(&path("/hello world.txt")).unwrap();
+            let free = volume.free_clusters().unwrap();
+
+            // Two clusters further, the first one partly
+            let data = [0x5au8; 1200];
+            assert_eq!(volume.write_file(&mut hello, 5, &data), Ok(1200));
+            assert_eq!(hello.size, 1205);
+            assert_eq!(volume.free_clusters(), Ok(free - 2));
+
+            // What was written is on the disk, not only in the buffer
+            let mut reread = Volume::new(&TEST_DISK).unwrap();
+            let found = reread.lookup(&path("/hello world.txt")).unwrap();
+            assert_eq!(found, hello);
+            let mut buf = [0u8; 8];
+            assert_eq!(reread.read_file(&found, 1198, &mut buf), Ok(7));
+            assert_eq!(&buf[..7], &[0x5a; 7]);
+            assert_eq!(reread.read_file(&found, 0, &mut buf[..5]), Ok(5));
+            assert_eq!(&buf[..5], b"hello");
+
+            volume.truncate(&mut hello).unwrap();
+            assert_eq!(volume.free_clusters(), Ok(free + 1));
+            assert_eq!(volume.lookup(&path("/hello world.txt")).map(|e| (e.first_cluster, e.size)), Ok((0, 0)));
+
+            // Every FAT copy says the same
+            let fat = |copy: usize| &blocks()[0][(8 + copy) * SECTOR as usize..(9 + copy) * SECTOR as usize];
+            assert_eq!(fat(0), fat(1));
+        }
+
+        fn files_open_through_a_vfat_mount() {
+            let mut process = Process::new(9940, 0, "fat32");
+            mount::sys_unshare_mount(&mut process).unwrap();
+            mount::sys_mount(&process, "vfat", "/boot").unwrap();
+            let attached = VOLUME.lock().replace(volume());
+
+            let file = vfs::open(&process, "/boot/Hello World.txt", O_RDWR).unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(vfs::read(&file, &mut buf), Ok(HELLO.len()));
+            assert_eq!(vfs::write(&file, b"!"), Ok(1));
+            drop(file);
+
+            let file = vfs::open(&process, "/boot/HELLOW~1.TXT", O_RDONLY).unwrap();
+            assert_eq!(vfs::read(&file, &mut buf), Ok(HELLO.len() + 1));
+            assert_eq!(&buf[..HELLO.len() + 1], b"hello world!");
+            drop(file);
+
+            assert_eq!(vfs::open(&process, "/boot/efi/bootx64.efi", O_WRONLY).map(|_| ()), Err(Errno::EACCES));
+            assert_eq!(vfs::open(&process, "/boot/efi", O_WRONLY).map(|_| ()), Err(Errno::EISDIR));
+
+            *VOLUME.lock() = attached;
+            mount::release(&mut process);
+        }
+    }
+}
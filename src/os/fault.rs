@@ -0,0 +1,123 @@
+//! Page faults on user addresses.
+//!
+//! The page fault handler hands [`handle`] every fault on a user address in the current
+//! process's address space, whether user code took it or the kernel copying to or from user
+//! memory. An access outside every area of the address space (see [`vma`]), or one its area
+//! does not allow, is never resolved. Otherwise the hooks that may own the fault get their
+//! turn in order: a page being migrated ([`compaction::handle_fault`]), a write to a page
+//! `fork` shared ([`cow::handle_fault`]), a page paged out ([`swap::handle_fault`]), a page of
+//! a mapping ([`mmap::handle_fault`]).
+//!
+//! Failing those, heap and stack are paged in on demand: a page of either gets a zeroed
+//! frame on first touch -- or the whole 2 MiB around it, when [`thp::handle_fault`] takes
+//! it. So a stack costs only the pages its deepest call touched, and a heap only those of
+//! its break that were used. A fault in the guard below the stack is a stack overflow, which
+//! [`vma::in_stack_guard`] tells the handler so it can kill the process outright: a `SIGSEGV`
+//! handler would only fault again on the same stack.
+
+use crate::os::arch::{Arch, Current};
+use crate::os::cgroup::{self, PageKind};
+use crate::os::compaction;
+use crate::os::cow;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::mmap;
+use crate::os::numa::Placement;
+use crate::os::process::Process;
+use crate::os::swap;
+use crate::os::thp;
+use crate::os::vma::{self, Access, VmaKind};
+
+/// Resolves a fault at `virt` in `process`'s address space on an `access` of it; returns
+/// whether it did, in which case the access can be retried. Fails with `EFAULT` for an access
+/// the area forbids, and otherwise as the hook that owns the fault does: with `ENOMEM` when no
+/// frame can be had, say.
+pub fn handle(process: &Process, virt: u64, access: Access) -> KResult<bool> {
+    if process.page_table_root == 0 {
+        return Ok(false);
+    }
+
+    let Some(area) = vma::find(process, virt) else {
+        return Ok(false);
+    };
+    if !area.permits(access) {
+        return Err(Errno::EFAULT);
+    }
+
+    let write = access == Access::Write;
+    if compaction::handle_fault(process, virt) || cow::handle_fault(process, virt, write)? || swap::handle_fault(process, virt)? || mmap::handle_fault(process, virt, write)? {
+        return Ok(true);
+    }
+
+    match area.kind {
+        VmaKind::Heap | VmaKind::Stack => demand_zero(process, virt),
+        _ => Ok(false),
+    }
+}
+
+// Backs the untouched heap or stack page at `virt` with zeroes, charged to the process's
+// group
+fn demand_zero(process: &Process, virt: u64) -> KResult<bool> {
+    if thp::handle_fault(process, virt) {
+        return Ok(true);
+    }
+
+    let allocator = memory::frame_allocator().ok_or(Errno::ENOMEM)?;
+    let page = virt & !(FRAME_SIZE - 1);
+    let pte = Current::leaf_entry_or_create(process.page_table_root as u64, page, &mut || allocator.alloc_frame(Placement::Local)).ok_or(Errno::ENOMEM)?;
+    let pte = unsafe { &mut *pte };
+    if *pte != 0 {
+        return Ok(false);
+    }
+
+    cgroup::charge(process.cgroup, PageKind::Anon, 1)?;
+    let Some(frame) = allocator.alloc_frame(Placement::Local) else {
+        cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+        return Err(Errno::ENOMEM);
+    };
+
+    // Frames are identity-mapped
+    unsafe { core::ptr::write_bytes(frame as *mut u8, 0, FRAME_SIZE as usize) };
+    *pte = Current::page_entry(frame, Current::page_flags(true, false, true));
+    Current::flush_tlb_page(page);
+    Ok(true)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::elf;
+    use crate::os::paging::{self, USER_START};
+
+    crate::os::ktest::kernel_test! {
+        fn stack_pages_come_on_first_touch() {
+            let free = memory::stats().free;
+            let mut process = Process::new(9380, 0, "fault");
+            assert_eq!(paging::create_address_space(&mut process), Ok(()));
+            process.stack_base = (USER_START + 32 * FRAME_SIZE) as usize;
+            process.stack_size = 4 * FRAME_SIZE as usize;
+            let root = process.page_table_root as u64;
+
+            let top = process.stack_base as u64 - 8;
+            assert_eq!(paging::translate(root, top), None);
+            assert_eq!(handle(&process, top, Access::Write), Ok(true));
+            let frame = paging::translate(root, top).unwrap();
+            assert_eq!(unsafe { *(frame as *const u64) }, 0);
+
+            // Past the stack's reach lies the guard, and past that nothing of the stack's
+            let below = USER_START + 28 * FRAME_SIZE - 8;
+            assert_eq!(handle(&process, below, Access::Write), Ok(false));
+            assert!(vma::in_stack_guard(&process, below));
+            assert!(!vma::in_stack_guard(&process, top));
+            assert!(!vma::in_stack_guard(&process, below - vma::STACK_GUARD_PAGES * FRAME_SIZE));
+
+            // An empty heap has no pages to give
+            process.heap_base = USER_START as usize;
+            assert_eq!(handle(&process, USER_START, Access::Read), Ok(false));
+
+            elf::release(&mut process);
+            paging::release(&mut process);
+            assert_eq!(memory::stats().free, free);
+        }
+    }
+}
@@ -0,0 +1,703 @@
+//! Flattened device tree (devicetree blob) parsing.
+//!
+//! Firmware on ARM and RISC-V boards (and some embedded x86 ones) describes the machine with a
+//! device tree instead of, or next to, ACPI. UEFI hands it over through the configuration
+//! table; [`init`] validates the blob and [`probe`] registers the devices the kernel knows
+//! about -- interrupt controllers, UARTs and virtio-mmio transports -- in the device registry
+//! PCI enumeration feeds. The blob is read in place and never copied.
+//!
+//! The structure block is a stream of big-endian tokens: each node opens with `BEGIN_NODE` and
+//! its name, lists its properties (name as an offset into the strings block, then the value),
+//! nests its children and closes with `END_NODE`. A node's `reg` is decoded with its parent's
+//! `#address-cells` and `#size-cells`; its interrupts with the `#interrupt-cells` of the
+//! controller its (possibly inherited) `interrupt-parent` names.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use uefi::table::{Boot, SystemTable};
+use uefi::{Guid, guid};
+
+use crate::os::device::{self, Device, DeviceClass, Location, MAX_REGIONS, Region};
+
+/// Configuration table GUID of the device tree.
+pub const DEVICE_TREE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+
+const MAGIC: u32 = 0xd00d_feed;
+
+// Newest `last_comp_version` understood: blobs of version 17 and later that still read as 17
+const LAST_COMPATIBLE_VERSION: u32 = 17;
+
+// Header size and field offsets
+const HEADER_SIZE: usize = 40;
+const TOTAL_SIZE_OFFSET: usize = 4;
+const STRUCT_OFFSET: usize = 8;
+const STRINGS_OFFSET: usize = 12;
+const VERSION_OFFSET: usize = 20;
+const LAST_COMP_VERSION_OFFSET: usize = 24;
+const STRINGS_SIZE_OFFSET: usize = 32;
+const STRUCT_SIZE_OFFSET: usize = 36;
+
+// Structure block tokens
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+/// Deepest node nesting followed; deeper subtrees are skipped.
+const MAX_DEPTH: usize = 16;
+
+// Cell counts in effect where a node does not say
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// `compatible` strings of the UARTs the kernel has drivers for.
+pub const UART_COMPATIBLES: &[&str] = &["ns16550a", "ns16550", "arm,pl011", "snps,dw-apb-uart"];
+
+/// `compatible` string of virtio-mmio transports.
+pub const VIRTIO_MMIO_COMPATIBLE: &str = "virtio,mmio";
+
+// virtio-mmio registers read to skip empty transport slots
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+
+// ARM GIC interrupt specifier types: shared and private peripheral interrupts
+const GIC_SPI: u32 = 0;
+const GIC_PPI: u32 = 1;
+
+// Address of the blob (0 = none)
+static BLOB: AtomicU64 = AtomicU64::new(0);
+
+/// A validated device tree blob.
+#[derive(Debug, Clone, Copy)]
+pub struct Fdt {
+    blob: &'static [u8],
+    structs: &'static [u8],
+    strings: &'static [u8],
+}
+
+// One token of the structure block
+enum Token {
+    BeginNode(&'static str),
+    EndNode,
+    Prop(&'static str, &'static [u8]),
+    Nop,
+    End,
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// Reads a value of `cells` 32-bit cells (at most two are meaningful)
+fn cells(bytes: &[u8], offset: usize, cells: u32) -> Option<u64> {
+    (0..cells as usize).try_fold(0u64, |value, i| Some(value << 32 | be32(bytes, offset + 4 * i)? as u64))
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+// A NUL-terminated string starting at `offset`
+fn c_str(bytes: &'static [u8], offset: usize) -> Option<&'static str> {
+    let tail = bytes.get(offset..)?;
+    let len = tail.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&tail[..len]).ok()
+}
+
+impl Fdt {
+    /// Validates the header of `blob`, which may extend beyond the tree's total size.
+    pub fn new(blob: &'static [u8]) -> Option<Fdt> {
+        if be32(blob, 0)? != MAGIC || be32(blob, LAST_COMP_VERSION_OFFSET)? > LAST_COMPATIBLE_VERSION {
+            return None;
+        }
+
+        let total = be32(blob, TOTAL_SIZE_OFFSET)? as usize;
+        let blob = blob.get(..total)?;
+
+        // Version 16 has no strings/structure sizes; both blocks then run to the end
+        let structs_at = be32(blob, STRUCT_OFFSET)? as usize;
+        let strings_at = be32(blob, STRINGS_OFFSET)? as usize;
+        let (structs_len, strings_len) = if be32(blob, VERSION_OFFSET)? >= 17 {
+            (be32(blob, STRUCT_SIZE_OFFSET)? as usize, be32(blob, STRINGS_SIZE_OFFSET)? as usize)
+        } else {
+            (total.checked_sub(structs_at)?, total.checked_sub(strings_at)?)
+        };
+
+        if structs_at < HEADER_SIZE || strings_at < HEADER_SIZE {
+            return None;
+        }
+
+        Some(Fdt {
+            blob,
+            structs: blob.get(structs_at..structs_at.checked_add(structs_len)?)?,
+            strings: blob.get(strings_at..strings_at.checked_add(strings_len)?)?,
+        })
+    }
+
+    /// Validates the blob at physical address `addr`.
+    ///
+    /// # Safety
+    /// `addr` must point to mapped memory holding at least the header, and the whole tree if
+    /// the header is valid.
+    pub unsafe fn at(addr: u64) -> Option<Fdt> {
+        let header = unsafe { core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+        if be32(header, 0)? != MAGIC {
+            return None;
+        }
+
+        let total = be32(header, TOTAL_SIZE_OFFSET)? as usize;
+        Fdt::new(unsafe { core::slice::from_raw_parts(addr as *const u8, total.max(HEADER_SIZE)) })
+    }
+
+    /// Size of the blob in bytes.
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    fn token(&self, offset: usize) -> Option<(Token, usize)> {
+        let next = offset + 4;
+
+        let token = match be32(self.structs, offset)? {
+            BEGIN_NODE => {
+                let name = c_str(self.structs, next)?;
+                return Some((Token::BeginNode(name), align4(next + name.len() + 1)));
+            }
+            END_NODE => Token::EndNode,
+            PROP => {
+                let len = be32(self.structs, next)? as usize;
+                let name = c_str(self.strings, be32(self.structs, next + 4)? as usize)?;
+                let value = self.structs.get(next + 8..next + 8 + len)?;
+                return Some((Token::Prop(name, value), align4(next + 8 + len)));
+            }
+            NOP => Token::Nop,
+            END => Token::End,
+            _ => return None,
+        };
+
+        Some((token, next))
+    }
+
+    /// Calls `f` for every node in document order, the root (named "") first. Stops at the
+    /// first malformed token.
+    pub fn for_each_node(&self, mut f: impl FnMut(&Node)) {
+        // Per depth: the cell counts and interrupt parent a node passes on to its children
+        let mut inherited = [(DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS, 0u32); MAX_DEPTH + 1];
+        let mut depth = 0;
+        let mut skipping = 0;
+        let mut offset = 0;
+
+        while let Some((token, next)) = self.token(offset) {
+            offset = next;
+
+            match token {
+                Token::BeginNode(_) if depth >= MAX_DEPTH => skipping += 1,
+                Token::BeginNode(name) => {
+                    let (address_cells, size_cells, parent_irq) = inherited[depth];
+                    let mut node =
+                        Node { fdt: *self, name, depth, offset, address_cells, size_cells, interrupt_parent: parent_irq };
+
+                    if let Some(phandle) = node.u32_property("interrupt-parent") {
+                        node.interrupt_parent = phandle;
+                    }
+
+                    f(&node);
+
+                    depth += 1;
+                    inherited[depth] = (
+                        node.u32_property("#address-cells").unwrap_or(DEFAULT_ADDRESS_CELLS),
+                        node.u32_property("#size-cells").unwrap_or(DEFAULT_SIZE_CELLS),
+                        node.interrupt_parent,
+                    );
+                }
+                Token::EndNode if skipping > 0 => skipping -= 1,
+                Token::EndNode => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                Token::Prop(..) | Token::Nop => {}
+                Token::End => return,
+            }
+        }
+    }
+
+    /// The first node matching `predicate`.
+    pub fn find(&self, predicate: impl Fn(&Node) -> bool) -> Option<Node> {
+        let mut found = None;
+
+        self.for_each_node(|node| {
+            if found.is_none() && predicate(node) {
+                found = Some(*node);
+            }
+        });
+
+        found
+    }
+
+    /// The node at `path` (e.g. "/cpus"). Components may leave out the unit address.
+    pub fn find_path(&self, path: &str) -> Option<Node> {
+        let mut components = [""; MAX_DEPTH];
+        let mut len = 0;
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            *components.get_mut(len)? = component;
+            len += 1;
+        }
+
+        // Walk in document order, tracking how many leading components the current branch matches
+        let mut matched = 0;
+        let mut found = None;
+
+        self.for_each_node(|node| {
+            if found.is_some() || node.depth == 0 || node.depth > matched + 1 {
+                if node.depth == 0 && len == 0 {
+                    found = Some(*node);
+                }
+                return;
+            }
+
+            matched = node.depth - 1;
+            if node.name_matches(components[matched]) {
+                matched += 1;
+                if matched == len {
+                    found = Some(*node);
+                }
+            }
+        });
+
+        found
+    }
+
+    /// The node whose `phandle` is `phandle`.
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node> {
+        self.find(|node| node.u32_property("phandle").or_else(|| node.u32_property("linux,phandle")) == Some(phandle))
+    }
+}
+
+/// A node of the tree, with the context needed to decode its properties.
+#[derive(Debug, Clone, Copy)]
+pub struct Node {
+    fdt: Fdt,
+
+    /// Node name including the unit address ("uart@9000000").
+    pub name: &'static str,
+
+    /// Nesting depth, 0 for the root.
+    pub depth: usize,
+
+    // Offset of the first token after the name, where the properties start
+    offset: usize,
+
+    /// `#address-cells` and `#size-cells` of the parent, which `reg` is encoded with.
+    pub address_cells: u32,
+    pub size_cells: u32,
+
+    /// Phandle of the interrupt controller this node's interrupts go to (0 = none).
+    pub interrupt_parent: u32,
+}
+
+impl Node {
+    /// Raw value of property `name`.
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        let mut offset = self.offset;
+
+        // Properties come before the first child
+        while let Some((token, next)) = self.fdt.token(offset) {
+            match token {
+                Token::Prop(prop, value) if prop == name => return Some(value),
+                Token::Prop(..) | Token::Nop => offset = next,
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    /// Returns `true` if the node has property `name`, whatever its value.
+    pub fn has_property(&self, name: &str) -> bool {
+        self.property(name).is_some()
+    }
+
+    /// Property `name` as a single 32-bit cell.
+    pub fn u32_property(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0)
+    }
+
+    /// Property `name` as a string (the first of a string list).
+    pub fn str_property(&self, name: &str) -> Option<&'static str> {
+        c_str(self.property(name)?, 0)
+    }
+
+    /// The strings of a string-list property such as `compatible`.
+    pub fn strings(&self, name: &str) -> impl Iterator<Item = &'static str> {
+        self.property(name)
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    /// Returns `true` if any of the node's `compatible` strings is `compatible`.
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.strings("compatible").any(|c| c == compatible)
+    }
+
+    /// The node's name without the unit address.
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    fn name_matches(&self, component: &str) -> bool {
+        self.name == component || (!component.contains('@') && self.base_name() == component)
+    }
+
+    /// Returns `false` for nodes whose `status` marks them absent or unusable.
+    pub fn is_enabled(&self) -> bool {
+        matches!(self.str_property("status"), None | Some("okay") | Some("ok"))
+    }
+
+    /// Entry `index` of `reg`.
+    pub fn reg(&self, index: usize) -> Option<Region> {
+        let reg = self.property("reg")?;
+        let stride = 4 * (self.address_cells + self.size_cells) as usize;
+        let at = index.checked_mul(stride)?;
+
+        if stride == 0 || at + stride > reg.len() {
+            return None;
+        }
+
+        Some(Region {
+            base: cells(reg, at, self.address_cells)?,
+            size: cells(reg, at + 4 * self.address_cells as usize, self.size_cells)?,
+        })
+    }
+
+    /// The node's first interrupt, as the number its interrupt controller knows it by.
+    pub fn irq(&self) -> Option<u32> {
+        let interrupts = self.property("interrupts")?;
+        let controller = self.fdt.find_phandle(self.interrupt_parent)?;
+        let interrupt_cells = controller.u32_property("#interrupt-cells").unwrap_or(1);
+
+        // The GIC's three cells are type, number within the type, and trigger flags
+        if interrupt_cells == 3 && controller.strings("compatible").any(|c| c.starts_with("arm,")) {
+            let number = be32(interrupts, 4)?;
+            return match be32(interrupts, 0)? {
+                GIC_SPI => Some(32 + number),
+                GIC_PPI => Some(16 + number),
+                _ => None,
+            };
+        }
+
+        be32(interrupts, 0)
+    }
+}
+
+/// Locates the device tree in the UEFI configuration table.
+pub fn init(system_table: &SystemTable<Boot>) {
+    let Some(entry) = system_table.config_table().iter().find(|e| e.guid == DEVICE_TREE_GUID) else {
+        return;
+    };
+
+    match unsafe { Fdt::at(entry.address as u64) } {
+        Some(fdt) => {
+            BLOB.store(entry.address as u64, Ordering::Relaxed);
+            log::info!("fdt: device tree at {:#x}, {} bytes", entry.address as u64, fdt.total_size());
+        }
+        None => log::warn!("fdt: invalid device tree at {:#x}", entry.address as u64),
+    }
+}
+
+/// The firmware's device tree, if it provided one.
+pub fn get() -> Option<Fdt> {
+    match BLOB.load(Ordering::Relaxed) {
+        0 => None,
+        addr => unsafe { Fdt::at(addr) },
+    }
+}
+
+/// Calls `f` for every RAM range the memory nodes describe.
+pub fn for_each_memory_region(fdt: &Fdt, mut f: impl FnMut(Region)) {
+    fdt.for_each_node(|node| {
+        if node.str_property("device_type") != Some("memory") || !node.is_enabled() {
+            return;
+        }
+
+        (0..).map_while(|i| node.reg(i)).filter(|r| r.size != 0).for_each(&mut f);
+    });
+}
+
+// Returns `true` if the virtio-mmio transport at `base` has a device behind it
+fn virtio_mmio_present(base: u64) -> bool {
+    unsafe {
+        core::ptr::read_volatile(base as *const u32) == VIRTIO_MMIO_MAGIC
+            && core::ptr::read_volatile((base + VIRTIO_MMIO_DEVICE_ID) as *const u32) != 0
+    }
+}
+
+// The class of a node the kernel has a use for
+fn classify(node: &Node) -> Option<DeviceClass> {
+    // CPU-local controllers (RISC-V harts' own interrupt controllers) have no registers
+    if node.has_property("interrupt-controller") && node.has_property("reg") {
+        return Some(DeviceClass::InterruptController);
+    }
+
+    if node.strings("compatible").any(|c| UART_COMPATIBLES.contains(&c)) {
+        return Some(DeviceClass::Uart);
+    }
+
+    if node.is_compatible(VIRTIO_MMIO_COMPATIBLE) {
+        return node.reg(0).is_some_and(|r| virtio_mmio_present(r.base)).then_some(DeviceClass::Virtio);
+    }
+
+    None
+}
+
+/// Registers the tree's interrupt controllers, UARTs and populated virtio-mmio transports with
+/// the device registry. Returns how many devices were registered.
+pub fn register_devices(fdt: &Fdt) -> usize {
+    let mut registered = 0;
+
+    fdt.for_each_node(|node| {
+        if !node.is_enabled() {
+            return;
+        }
+
+        let Some(class) = classify(node) else {
+            return;
+        };
+
+        let mut regions = [Region::default(); MAX_REGIONS];
+        for (i, region) in regions.iter_mut().enumerate() {
+            *region = node.reg(i).unwrap_or_default();
+        }
+
+        let device = Device {
+            class,
+            name: node.strings("compatible").next().unwrap_or(node.base_name()),
+            location: Location::Mmio { regions },
+            irq: node.irq(),
+        };
+
+        match device::register(device) {
+            Ok(_) => registered += 1,
+            Err(_) => log::warn!("fdt: device registry full, dropping {}", node.name),
+        }
+    });
+
+    registered
+}
+
+/// Registers the devices of the firmware's tree and logs the memory it describes. Called
+/// once at boot, before the architecture code looks for its interrupt controller.
+pub fn probe() {
+    let Some(fdt) = get() else {
+        return;
+    };
+
+    let mut memory = 0;
+    for_each_memory_region(&fdt, |region| memory += region.size);
+
+    let devices = register_devices(&fdt);
+    log::info!("fdt: {} MiB of memory, {} devices registered", memory >> 20, devices);
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Builds a blob: a root with #address-cells = <2>, #size-cells = <2>, a memory node, a
+    // PLIC-like controller and a UART wired to it, and a disabled UART
+    struct Builder {
+        buf: [u8; 1024],
+        structs: usize,
+        strings: [u8; 256],
+        strings_len: usize,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder { buf: [0; 1024], structs: HEADER_SIZE, strings: [0; 256], strings_len: 0 }
+        }
+
+        fn word(&mut self, value: u32) {
+            self.buf[self.structs..self.structs + 4].copy_from_slice(&value.to_be_bytes());
+            self.structs += 4;
+        }
+
+        fn begin(&mut self, name: &str) {
+            self.word(BEGIN_NODE);
+            self.buf[self.structs..self.structs + name.len()].copy_from_slice(name.as_bytes());
+            self.structs = align4(self.structs + name.len() + 1);
+        }
+
+        fn end(&mut self) {
+            self.word(END_NODE);
+        }
+
+        // Adds `name` to the strings block unless it is already there
+        fn string(&mut self, name: &str) -> usize {
+            let mut offset = 0;
+            while offset < self.strings_len {
+                let len = self.strings[offset..].iter().position(|&b| b == 0).unwrap_or(0);
+                if &self.strings[offset..offset + len] == name.as_bytes() {
+                    return offset;
+                }
+                offset += len + 1;
+            }
+
+            self.strings[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+            self.strings_len += name.len() + 1;
+            offset
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) {
+            let name_offset = self.string(name);
+
+            self.word(PROP);
+            self.word(value.len() as u32);
+            self.word(name_offset as u32);
+            self.buf[self.structs..self.structs + value.len()].copy_from_slice(value);
+            self.structs = align4(self.structs + value.len());
+        }
+
+        fn prop_u32(&mut self, name: &str, value: u32) {
+            self.prop(name, &value.to_be_bytes());
+        }
+
+        fn prop_reg(&mut self, base: u64, size: u64) {
+            let mut reg = [0u8; 16];
+            reg[..8].copy_from_slice(&base.to_be_bytes());
+            reg[8..].copy_from_slice(&size.to_be_bytes());
+            self.prop("reg", &reg);
+        }
+
+        fn finish(mut self) -> [u8; 1024] {
+            self.word(END);
+
+            let strings_at = self.structs;
+            let total = strings_at + self.strings_len;
+            self.buf[strings_at..total].copy_from_slice(&self.strings[..self.strings_len]);
+
+            for (offset, value) in [
+                (0, MAGIC),
+                (TOTAL_SIZE_OFFSET, total as u32),
+                (STRUCT_OFFSET, HEADER_SIZE as u32),
+                (STRINGS_OFFSET, strings_at as u32),
+                (VERSION_OFFSET, 17),
+                (LAST_COMP_VERSION_OFFSET, 16),
+                (STRINGS_SIZE_OFFSET, self.strings_len as u32),
+                (STRUCT_SIZE_OFFSET, (strings_at - HEADER_SIZE) as u32),
+            ] {
+                self.buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+            }
+
+            self.buf
+        }
+    }
+
+    static mut SAMPLE: [u8; 1024] = [0; 1024];
+
+    fn sample() -> Fdt {
+        let mut b = Builder::new();
+
+        b.begin("");
+        b.prop_u32("#address-cells", 2);
+        b.prop_u32("#size-cells", 2);
+        b.prop_u32("interrupt-parent", 1);
+
+        b.begin("memory@80000000");
+        b.prop("device_type", b"memory\0");
+        b.prop_reg(0x8000_0000, 0x4000_0000);
+        b.end();
+
+        b.begin("soc");
+        b.prop_u32("#address-cells", 2);
+        b.prop_u32("#size-cells", 2);
+
+        b.begin("plic@c000000");
+        b.prop("compatible", b"sifive,plic-1.0.0\0riscv,plic0\0");
+        b.prop("interrupt-controller", &[]);
+        b.prop_u32("#interrupt-cells", 1);
+        b.prop_u32("phandle", 1);
+        b.prop_reg(0x0c00_0000, 0x60_0000);
+        b.end();
+
+        b.begin("serial@10000000");
+        b.prop("compatible", b"ns16550a\0");
+        b.prop_u32("interrupts", 10);
+        b.prop_reg(0x1000_0000, 0x100);
+        b.end();
+
+        b.begin("serial@10001000");
+        b.prop("compatible", b"ns16550a\0");
+        b.prop("status", b"disabled\0");
+        b.prop_reg(0x1000_1000, 0x100);
+        b.end();
+
+        b.end();
+        b.end();
+
+        unsafe {
+            let blob = &raw mut SAMPLE;
+            *blob = b.finish();
+            Fdt::new(&*blob).expect("sample blob is valid")
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn rejects_bad_magic() {
+            static GARBAGE: [u8; 64] = [0xff; 64];
+            assert!(Fdt::new(&GARBAGE).is_none());
+        }
+
+        fn walks_nodes_in_order() {
+            let fdt = sample();
+            let mut names = [""; 8];
+            let mut count = 0;
+
+            fdt.for_each_node(|node| {
+                names[count] = node.name;
+                count += 1;
+            });
+
+            assert_eq!(count, 6);
+            assert_eq!(names[..3], ["", "memory@80000000", "soc"]);
+            assert_eq!(names[4], "serial@10000000");
+        }
+
+        fn decodes_reg_and_memory() {
+            let fdt = sample();
+            let mut total = 0;
+
+            for_each_memory_region(&fdt, |region| {
+                assert_eq!(region.base, 0x8000_0000);
+                total += region.size;
+            });
+
+            assert_eq!(total, 0x4000_0000);
+        }
+
+        fn finds_paths_and_interrupts() {
+            let fdt = sample();
+
+            let uart = fdt.find_path("/soc/serial@10000000").expect("uart by full name");
+            assert_eq!(uart.reg(0), Some(Region { base: 0x1000_0000, size: 0x100 }));
+            assert_eq!(uart.irq(), Some(10));
+            assert!(uart.is_compatible("ns16550a"));
+
+            assert_eq!(fdt.find_path("/soc").map(|n| n.depth), Some(1));
+            assert!(fdt.find_path("/soc/missing").is_none());
+            assert_eq!(fdt.find_path("/").map(|n| n.depth), Some(0));
+        }
+
+        fn classifies_enabled_devices() {
+            let fdt = sample();
+            let plic = fdt.find_phandle(1).expect("plic by phandle");
+            let disabled = fdt.find_path("/soc/serial@10001000").expect("disabled uart");
+
+            assert_eq!(classify(&plic), Some(DeviceClass::InterruptController));
+            assert!(!disabled.is_enabled());
+        }
+    }
+}
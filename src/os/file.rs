@@ -0,0 +1,189 @@
+//! Open files: what file descriptors refer to.
+//!
+//! Opening a file creates a [`File`] kernel object (see [`kobject`](super::kobject)), and each
+//! descriptor referring to it holds a reference, so `dup` shares the file between descriptors.
+//! The file is closed when its last reference
+//! goes -- a ring's or a socket's when no descriptor refers to it any more, a backing file's
+//! through its [`FileOps`] -- not when some descriptor for it is.
+//!
+//! Files are named by ids the backing store hands out, which the page cache and mappings key
+//! on; ids from [`RING_FILE_BASE`](super::uring::RING_FILE_BASE) up are syscall rings, and
+//! those from [`SOCKET_FILE_BASE`](super::net::udp::SOCKET_FILE_BASE) below them sockets.
+//! Each file also keeps the flags it was opened with and the offset `read` and `write` carry
+//! on from, so descriptors sharing a file share its offset.
+
+use core::sync::atomic::AtomicU64;
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::kobject::{KRef, Pool};
+use crate::os::net::udp;
+use crate::os::process::Process;
+use crate::os::rlimit;
+use crate::os::uring;
+
+/// Maximum number of files open at once, system-wide.
+pub const MAX_FILES: usize = 256;
+
+/// `open` flags: the access mode, in the low two bits, truncation and appending.
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+/// Operations on open files, provided by whatever backs process file descriptors.
+pub struct FileOps {
+    pub read: fn(file: u32, buf: &mut [u8], offset: u64) -> KResult<usize>,
+    pub write: fn(file: u32, data: &[u8], offset: u64) -> KResult<usize>,
+
+    /// Waits for a connection on a listening socket and returns the new file.
+    pub accept: fn(file: u32) -> KResult<u32>,
+    pub close: fn(file: u32),
+}
+
+static mut FILE_OPS: Option<&'static FileOps> = None;
+
+/// Installs the operations files are read, written, accepted on and closed with.
+pub fn set_file_ops(ops: &'static FileOps) {
+    unsafe {
+        let slot = &raw mut FILE_OPS;
+        *slot = Some(ops);
+    }
+}
+
+/// The installed file operations, if any.
+pub fn ops() -> Option<&'static FileOps> {
+    unsafe {
+        let slot = &raw const FILE_OPS;
+        *slot
+    }
+}
+
+/// An open file.
+#[derive(Debug)]
+pub struct File {
+    /// The backing store's id for the file.
+    pub id: u32,
+
+    /// The `O_*` flags it was opened with.
+    pub flags: u32,
+
+    /// Where the next `read` or `write` starts.
+    pub offset: AtomicU64,
+}
+
+impl File {
+    /// Whether the access mode lets the file be read.
+    pub fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    /// Whether the access mode lets the file be written.
+    pub fn writable(&self) -> bool {
+        matches!(self.flags & O_ACCMODE, O_WRONLY | O_RDWR)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Some(ring) = uring::ring_index(self.id) {
+            uring::close_ring(ring);
+        } else if let Some(socket) = udp::socket_index(self.id) {
+            udp::close(socket);
+        } else if let Some(ops) = ops() {
+            (ops.close)(self.id);
+        }
+    }
+}
+
+/// Every open file.
+pub static FILES: Pool<File, MAX_FILES> = Pool::new("file");
+
+/// Opens the file with id `id` for reading and writing, taking over closing it once nothing
+/// refers to it. Fails with `ENOSPC` when [`MAX_FILES`] files are open; the file is then left
+/// open.
+pub fn open(id: u32) -> KResult<KRef<File>> {
+    open_with(id, O_RDWR)
+}
+
+/// Like [`open`], with the `O_*` flags `flags`.
+pub fn open_with(id: u32, flags: u32) -> KResult<KRef<File>> {
+    FILES.try_alloc(File { id, flags, offset: AtomicU64::new(0) }).map_err(|file| {
+        // Not ours to close yet
+        core::mem::forget(file);
+        Errno::ENOSPC
+    })
+}
+
+/// `close(fd)`: takes descriptor `fd` from `process` and returns its file, closed when the
+/// caller drops it if it was the last reference. Closing a socket wakes its waiters, so the
+/// file is dropped once the process table is unlocked. Fails with `EBADF` if `fd` is not open.
+pub fn sys_close(process: &mut Process, fd: usize) -> KResult<KRef<File>> {
+    process.file_descriptors.get_mut(fd).and_then(Option::take).ok_or(Errno::EBADF)
+}
+
+/// `dup(fd)`: a new descriptor, the lowest free one, for the file open as `fd`. Fails with
+/// `EBADF` if `fd` is not open and `EMFILE` when no descriptor is free.
+pub fn sys_dup(process: &mut Process, fd: usize) -> KResult<usize> {
+    let file = process.file(fd)?.clone();
+    process.alloc_fd(file)
+}
+
+/// `dup2(old, new)`: makes descriptor `new` refer to the file open as `old`, returning what
+/// `new` referred to for the caller to drop, as for [`sys_close`]. Fails with `EBADF` if `old`
+/// is not open or `new` is out of range.
+pub fn sys_dup2(process: &mut Process, old: usize, new: usize) -> KResult<Option<KRef<File>>> {
+    let file = process.file(old)?.clone();
+    if new >= process.file_descriptors.len() || rlimit::check_fd(process, new).is_err() {
+        return Err(Errno::EBADF);
+    }
+
+    Ok(process.file_descriptors[new].replace(file))
+}
+
+/// Takes all of `process`'s descriptors, to be dropped as for [`sys_close`]. Called when it
+/// exits.
+pub fn release(process: &mut Process) -> [Option<KRef<File>>; 64] {
+    core::mem::replace(&mut process.file_descriptors, [const { None }; 64])
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::kobject::PoolStats;
+
+    crate::os::ktest::kernel_test! {
+        fn descriptors_share_files() {
+            let live = FILES.live();
+            let mut process = Process::new(9990, 0, "file");
+
+            let fd = process.alloc_fd(open(5).unwrap()).unwrap();
+            assert_eq!(sys_dup(&mut process, fd), Ok(fd + 1));
+            assert!(sys_dup2(&mut process, fd, 10).is_ok_and(|old| old.is_none()));
+            assert!(KRef::ptr_eq(process.file(fd).unwrap(), process.file(10).unwrap()));
+            assert_eq!(KRef::count(process.file(fd).unwrap()), 3);
+
+            // The file stays open until its last descriptor is closed
+            assert!(sys_close(&mut process, fd).is_ok());
+            assert_eq!(sys_close(&mut process, fd).map(drop), Err(Errno::EBADF));
+            assert!(sys_close(&mut process, fd + 1).is_ok());
+            assert_eq!(FILES.live(), live + 1);
+            assert_eq!(process.file(10).map(|file| file.id), Ok(5));
+
+            release(&mut process);
+            assert_eq!(FILES.live(), live);
+        }
+
+        fn bad_descriptors_are_ebadf() {
+            let mut process = Process::new(9991, 0, "file");
+            assert_eq!(sys_dup(&mut process, 0).map(|_| ()), Err(Errno::EBADF));
+            assert_eq!(sys_dup(&mut process, 1000).map(|_| ()), Err(Errno::EBADF));
+
+            process.alloc_fd(open(6).unwrap()).unwrap();
+            assert_eq!(sys_dup2(&mut process, 0, 64).map(drop), Err(Errno::EBADF));
+            assert!(sys_dup2(&mut process, 0, 0).is_ok_and(|old| old.is_some()));
+            assert_eq!(KRef::count(process.file(0).unwrap()), 1);
+        }
+    }
+}
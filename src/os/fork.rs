@@ -0,0 +1,345 @@
+//! `fork`: creating a process as a copy of the caller.
+//!
+//! The child gets a PID of its own, in the parent's namespace for children, and a copy of the
+//! rest: the address space, the descriptor table -- each descriptor shares the parent's open
+//! file, as after `dup` --, signal dispositions and mask, limits, credentials, syscall filter,
+//! control group, mount namespace and job. Pending signals, timers and CPU time start afresh.
+//!
+//! The image, data, heap and stack are shared copy-on-write (see [`cow`]): the child maps the
+//! parent's frames, both lose write access to them, and whichever writes first takes a copy.
+//! Each shared page is charged to the child's group as though it were copied. Pages of
+//! mappings are copied eagerly: anonymous pages, and private file pages the parent has
+//! written to, into frames charged to the child's group, while pages that map the page cache
+//! are mapped in the child too, holding the cached page once more. Swapped out pages are read
+//! back and huge pages split before they are shared or copied.
+//!
+//! The child resumes where the parent returns from the syscall, with the registers the syscall
+//! entry saved for it (see [`SyscallFrame`]) except rax, which is 0 in the child and the
+//! child's PID in the parent.
+
+use crate::os::arch::x86_64::pte::ADDRESS_MASK;
+use crate::os::arch::x86_64::syscall::{self as entry, SyscallFrame};
+use crate::os::arch::{Arch, Current};
+use crate::os::cgroup::{self, PageKind};
+use crate::os::compaction;
+use crate::os::cow;
+use crate::os::elf;
+use crate::os::errno::{Errno, KResult};
+use crate::os::kthread;
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::mmap;
+use crate::os::mount;
+use crate::os::numa::Placement;
+use crate::os::pagecache;
+use crate::os::paging;
+use crate::os::pid;
+use crate::os::pidns;
+use crate::os::process::{KERNEL_STACK_SIZE, Process};
+use crate::os::ptable;
+use crate::os::sched;
+use crate::os::swap;
+use crate::os::thp;
+use crate::os::tlb;
+
+// Frames of a process's kernel stack
+const KERNEL_STACK_FRAMES: u64 = (KERNEL_STACK_SIZE as u64).div_ceil(FRAME_SIZE);
+
+// Valid bit of a leaf entry, bit 0 on every port
+const VALID: u64 = 1 << 0;
+
+// Accessed and dirty bits, which start clear in the child's copies
+const ACCESSED_DIRTY: u64 = (1 << 5) | (1 << 6);
+
+// A PCB for the child `pid` of `parent`, with everything that is simply copied
+fn child_of(parent: &Process, pid: u64) -> Process {
+    let mut child = Process::new(pid, parent.pid, parent.comm());
+
+    child.pgid = parent.pgid;
+    child.sid = parent.sid;
+    child.priority = parent.priority;
+    child.timeslice = parent.timeslice;
+
+    child.code_base = parent.code_base;
+    child.code_size = parent.code_size;
+    child.data_base = parent.data_base;
+    child.data_size = parent.data_size;
+    child.heap_base = parent.heap_base;
+    child.heap_size = parent.heap_size;
+    child.stack_base = parent.stack_base;
+    child.stack_size = parent.stack_size;
+    child.mmap_base = parent.mmap_base;
+    child.mappings = parent.mappings;
+
+    child.pc = parent.pc;
+    child.sp = parent.sp;
+    child.flags = parent.flags;
+    child.fpu = parent.fpu;
+
+    child.file_descriptors = parent.file_descriptors.clone();
+    child.signal_handlers = parent.signal_handlers;
+    child.signal_restorers = parent.signal_restorers;
+    child.signal_mask = parent.signal_mask;
+    child.rlimits = parent.rlimits;
+    child.cred = parent.cred;
+    child.syscall_filter = parent.syscall_filter;
+    child
+}
+
+// Maps a copy of the page at `virt` in `parent` into `child`, if the parent has one there.
+// `cached` is the page cache page the parent's mapping shows at `virt`, if any; a page still
+// mapping it is shared rather than copied.
+fn copy_page(parent: &Process, child: &Process, virt: u64, cached: Option<(u32, u64)>) -> KResult<()> {
+    let root = parent.page_table_root as u64;
+    if Current::leaf_entry(root, virt).is_none() {
+        thp::split(parent, virt)?;
+    }
+    let Some(pte) = Current::leaf_entry(root, virt) else {
+        return Ok(());
+    };
+
+    if unsafe { *pte } & VALID == 0 && cached.is_none() {
+        swap::handle_fault(parent, virt)?;
+    }
+    let entry = unsafe { *pte };
+    if entry & VALID == 0 {
+        return Ok(());
+    }
+
+    let allocator = memory::frame_allocator().ok_or(Errno::ENOMEM)?;
+    let frame = Current::entry_address(entry);
+    let shared = cached.filter(|&(file, index)| pagecache::lookup(file, index) == Some(frame));
+
+    let copy = match shared {
+        Some((file, index)) => pagecache::get(file, index, child.cgroup)?,
+        None => {
+            cgroup::charge(child.cgroup, PageKind::Anon, 1)?;
+            let Some(copy) = allocator.alloc_frame(Placement::Local) else {
+                cgroup::uncharge(child.cgroup, PageKind::Anon, 1);
+                return Err(Errno::ENOMEM);
+            };
+
+            // Frames are identity-mapped
+            unsafe { core::ptr::copy_nonoverlapping(frame as *const u8, copy as *mut u8, FRAME_SIZE as usize) };
+            copy
+        }
+    };
+
+    let Some(slot) = Current::leaf_entry_or_create(child.page_table_root as u64, virt, &mut || allocator.alloc_frame(Placement::Local)) else {
+        match shared {
+            Some((file, index)) => pagecache::put(file, index),
+            None => {
+                allocator.free_frame(copy);
+                cgroup::uncharge(child.cgroup, PageKind::Anon, 1);
+            }
+        }
+        return Err(Errno::ENOMEM);
+    };
+
+    unsafe { *slot = (entry & !(ADDRESS_MASK | ACCESSED_DIRTY)) | copy };
+    Ok(())
+}
+
+// Maps the page at `virt` in `parent` into `child` too, if the parent has one there, taking
+// write access away from both. Copies it instead when no more frames can be shared.
+fn share_page(parent: &Process, child: &Process, virt: u64) -> KResult<()> {
+    let root = parent.page_table_root as u64;
+    if Current::leaf_entry(root, virt).is_none() {
+        thp::split(parent, virt)?;
+    }
+    let Some(pte) = Current::leaf_entry(root, virt) else {
+        return Ok(());
+    };
+
+    if unsafe { *pte } & VALID == 0 {
+        swap::handle_fault(parent, virt)?;
+    }
+    let entry = unsafe { *pte };
+    if entry & VALID == 0 {
+        return Ok(());
+    }
+
+    let allocator = memory::frame_allocator().ok_or(Errno::ENOMEM)?;
+    let slot = Current::leaf_entry_or_create(child.page_table_root as u64, virt, &mut || allocator.alloc_frame(Placement::Local)).ok_or(Errno::ENOMEM)?;
+
+    cgroup::charge(child.cgroup, PageKind::Anon, 1)?;
+    if !cow::share(Current::entry_address(entry)) {
+        cgroup::uncharge(child.cgroup, PageKind::Anon, 1);
+        return copy_page(parent, child, virt, None);
+    }
+
+    let protected = cow::write_protect(entry);
+    if protected != entry {
+        unsafe { *pte = protected };
+        tlb::shootdown_page(Some(root), virt);
+    }
+    unsafe { *slot = protected & !ACCESSED_DIRTY };
+    Ok(())
+}
+
+// Shares every page of `parent`'s image, data, heap and stack with `child`, which has an empty
+// address space of its own, and copies the pages of its mappings
+fn copy_address_space(parent: &Process, child: &Process) -> KResult<()> {
+    let code = (parent.code_base as u64, (parent.code_base + parent.code_size) as u64);
+    for (start, end) in [code].into_iter().chain(memory::anonymous_ranges(parent)) {
+        for virt in ((start & !(FRAME_SIZE - 1))..end).step_by(FRAME_SIZE as usize) {
+            share_page(parent, child, virt)?;
+        }
+    }
+
+    for mapping in parent.mappings.iter().flatten() {
+        for virt in (mapping.start..mapping.start + mapping.len).step_by(FRAME_SIZE as usize) {
+            let cached = mapping.file.map(|file| (file, (mapping.offset + (virt - mapping.start)) / FRAME_SIZE));
+            copy_page(parent, child, virt, cached)?;
+        }
+    }
+    Ok(())
+}
+
+// Undoes a fork that failed once the child had joined the parent's namespace and group
+fn discard(pid: u64) {
+    if let Some(mut child) = ptable::remove(pid) {
+        mmap::release(&mut child);
+        elf::release(&mut child);
+        paging::release(&mut child);
+
+        pidns::detach(&mut child);
+        mount::release(&mut child);
+        cgroup::release(&mut child);
+        kthread::release(&mut child);
+    }
+    pid::free(pid);
+}
+
+/// Creates a child of process `parent`, which is in a syscall, and admits it to the scheduler;
+/// returns its PID. Fails with `EINVAL` for a process without an address space of its own,
+/// such as a kernel thread, `EAGAIN` when no PID or process table slot is free and `ENOMEM`
+/// when memory runs out.
+pub fn fork(parent: u64) -> KResult<u64> {
+    let forkable = ptable::with_process(parent, |p| p.page_table_root != 0 && p.kernel_stack != 0).ok_or(Errno::ESRCH)?;
+    if !forkable {
+        return Err(Errno::EINVAL);
+    }
+
+    let pid = pid::alloc()?;
+    let Some(stack) = compaction::alloc_contiguous(KERNEL_STACK_FRAMES, FRAME_SIZE) else {
+        pid::free(pid);
+        return Err(Errno::ENOMEM);
+    };
+
+    // The child's kernel stack starts with the parent's frame, as though it made the call
+    let child = ptable::with_process(parent, |parent| {
+        let mut child = child_of(parent, pid);
+        child.kernel_stack = stack as usize;
+        unsafe { entry::frame_of(&child).write(SyscallFrame { rax: 0, ..*entry::frame_of(parent) }) };
+        child
+    });
+    let Some(mut child) = child else {
+        compaction::free_contiguous(stack, KERNEL_STACK_FRAMES);
+        pid::free(pid);
+        return Err(Errno::ESRCH);
+    };
+    let frame = entry::frame_of(&child);
+    sched::init_context(&mut child, start_child, frame as usize);
+
+    // In the table before anything is shared with the parent, so a failure gets it all back
+    if ptable::insert(child).is_err() {
+        compaction::free_contiguous(stack, KERNEL_STACK_FRAMES);
+        pid::free(pid);
+        return Err(Errno::EAGAIN);
+    }
+
+    let joined = ptable::with_pair(pid, parent, |child, parent| -> KResult<()> {
+        pidns::attach(child, parent)?;
+        cgroup::inherit(child, parent);
+        mount::inherit(child, parent);
+        Ok(())
+    });
+    if let Err(err) = joined.unwrap_or(Err(Errno::ESRCH)) {
+        if let Some(mut child) = ptable::remove(pid) {
+            kthread::release(&mut child);
+        }
+        pid::free(pid);
+        return Err(err);
+    }
+
+    let copied = ptable::with_pair(pid, parent, |child, parent| {
+        paging::create_address_space(child)?;
+        copy_address_space(parent, child)
+    });
+    if let Err(err) = copied.unwrap_or(Err(Errno::ESRCH)) {
+        discard(pid);
+        return Err(err);
+    }
+
+    sched::admit(pid)?;
+    Ok(pid)
+}
+
+// First code a forked child runs, on its kernel stack and in its own address space: it
+// returns from the parent's syscall
+extern "C" fn start_child(frame: usize) -> ! {
+    kthread::reclaim_stack();
+    unsafe { entry::return_to_user(frame as *const SyscallFrame) }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::arch::x86_64::pte;
+    use crate::os::protection::Protection;
+
+    crate::os::ktest::kernel_test! {
+        fn only_processes_with_an_address_space_fork() {
+            ptable::insert(Process::new(9992, 0, "kthread")).unwrap();
+            assert_eq!(fork(9992), Err(Errno::EINVAL));
+            assert_eq!(fork(u64::MAX), Err(Errno::ESRCH));
+            ptable::remove(9992);
+        }
+
+        fn address_spaces_are_shared_until_written() {
+            let free = memory::stats().free;
+            let mut parent = Process::new(9990, 0, "parent");
+            let mut child = Process::new(9991, 9990, "child");
+            assert_eq!(paging::create_address_space(&mut parent), Ok(()));
+            assert_eq!(paging::create_address_space(&mut child), Ok(()));
+
+            let allocator = memory::frame_allocator().unwrap();
+            let frame = allocator.alloc_frame(Placement::Local).unwrap();
+            unsafe { (frame as *mut u64).write(0x1234) };
+            parent.data_base = paging::USER_START as usize;
+            parent.data_size = 2 * FRAME_SIZE as usize;
+            assert_eq!(paging::map_page(parent.page_table_root as u64, paging::USER_START, frame, Protection::data(true)), Ok(()));
+
+            assert_eq!(copy_address_space(&parent, &child), Ok(()));
+            child.data_base = parent.data_base;
+            child.data_size = parent.data_size;
+
+            // The same frame, read-only on both sides; the unmapped page stays so
+            let (root, parent_root) = (child.page_table_root as u64, parent.page_table_root as u64);
+            assert_eq!(paging::translate(root, paging::USER_START), Some(frame));
+            assert!(cow::is_shared(frame));
+            assert_eq!(unsafe { *pte::leaf(root, paging::USER_START).unwrap() } & pte::WRITABLE, 0);
+            assert_eq!(unsafe { *pte::leaf(parent_root, paging::USER_START).unwrap() } & pte::WRITABLE, 0);
+            assert_eq!(paging::translate(root, paging::USER_START + FRAME_SIZE), None);
+
+            // The child's write takes a copy; the parent's then finds the frame its own
+            assert_eq!(cow::handle_fault(&child, paging::USER_START, true), Ok(true));
+            let copy = paging::translate(root, paging::USER_START).unwrap();
+            assert_ne!(copy, frame);
+            assert_eq!(unsafe { *(copy as *const u64) }, 0x1234);
+            assert_eq!(unsafe { *pte::leaf(root, paging::USER_START).unwrap() } & pte::WRITABLE, pte::WRITABLE);
+
+            assert!(!cow::is_shared(frame));
+            assert_eq!(cow::handle_fault(&parent, paging::USER_START, true), Ok(true));
+            assert_eq!(paging::translate(parent_root, paging::USER_START), Some(frame));
+            assert_eq!(unsafe { *pte::leaf(parent_root, paging::USER_START).unwrap() } & pte::WRITABLE, pte::WRITABLE);
+
+            elf::release(&mut child);
+            paging::release(&mut child);
+            assert_eq!(paging::unmap_page(parent_root, paging::USER_START), Ok(frame));
+            allocator.free_frame(frame);
+            paging::release(&mut parent);
+            assert_eq!(memory::stats().free, free);
+        }
+    }
+}
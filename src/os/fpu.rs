@@ -0,0 +1,301 @@
+//! x87/SSE/AVX register state management.
+//!
+//! Each process owns an [`FpuState`] save area in its PCB. The size of the area actually used
+//! comes from CPUID leaf 0xD for the state components enabled in XCR0; CPUs without XSAVE fall
+//! back to the 512-byte FXSAVE format.
+//!
+//! Switching is eager by default: the outgoing process's registers are saved and the incoming
+//! one's restored on every context switch, which leaves no other process's state in the
+//! registers to leak speculatively. Booting with `fpu=lazy` instead sets CR0.TS on a switch
+//! and swaps state only when the new process first touches the FPU and takes #NM.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::os::arch::x86_64::control::{self, Cr0, Cr0Flags, Cr4, Cr4Flags, Xcr0, Xcr0Flags};
+use crate::os::boot;
+use crate::os::cpu;
+use crate::os::process::Process;
+use crate::os::ptable;
+
+/// Size of the per-process save area. Enough for x87, SSE, AVX and AVX-512 state; components
+/// whose XSAVE layout would not fit are left disabled.
+pub const FPU_AREA_SIZE: usize = 4096;
+
+/// Size of the legacy FXSAVE image.
+const FXSAVE_SIZE: usize = 512;
+
+// Offsets into the legacy region of the save area
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+// Reset values of the x87 control word and MXCSR: all exceptions masked, round to nearest
+const FCW_DEFAULT: u16 = 0x037f;
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/// A saved x87/SSE/AVX register image in XSAVE (or FXSAVE) format.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(64))]
+pub struct FpuState {
+    area: [u8; FPU_AREA_SIZE],
+}
+
+impl FpuState {
+    /// The state a fresh process starts with: default control words, all registers zero.
+    /// An all-zero XSAVE header marks every extended component as being in its initial state.
+    pub const fn new() -> Self {
+        let mut area = [0u8; FPU_AREA_SIZE];
+
+        let fcw = FCW_DEFAULT.to_le_bytes();
+        area[FCW_OFFSET] = fcw[0];
+        area[FCW_OFFSET + 1] = fcw[1];
+
+        let mxcsr = MXCSR_DEFAULT.to_le_bytes();
+        let mut i = 0;
+        while i < mxcsr.len() {
+            area[MXCSR_OFFSET + i] = mxcsr[i];
+            i += 1;
+        }
+
+        FpuState { area }
+    }
+
+    /// Saves the current register contents into this area.
+    pub fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        let (low, high) = (u32::MAX, u32::MAX);
+
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                if cpu::features().xsaveopt {
+                    asm!("xsaveopt64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+                } else {
+                    asm!("xsave64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+                }
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Loads the registers from this area.
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        let (low, high) = (u32::MAX, u32::MAX);
+
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Whether XSAVE/XRSTOR (rather than FXSAVE/FXRSTOR) is in use
+static XSAVE: AtomicBool = AtomicBool::new(false);
+
+// Bytes of the save area the CPU actually uses with the enabled components
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+// Whether state is switched lazily through #NM
+static LAZY: AtomicBool = AtomicBool::new(false);
+
+// PID whose state is currently loaded in the registers (lazy mode), or NO_OWNER
+static OWNER: AtomicU64 = AtomicU64::new(NO_OWNER);
+const NO_OWNER: u64 = u64::MAX;
+
+// XCR0 as the boot CPU was configured, for the others to copy (XSAVE only)
+static XCR0: AtomicU64 = AtomicU64::new(0);
+
+/// Enables the FPU, SSE and (if present) XSAVE with every supported component that fits in
+/// [`FPU_AREA_SIZE`], and picks the switching mode. Called once at boot.
+pub fn init() {
+    let features = cpu::features();
+
+    unsafe {
+        enable(features.xsave);
+
+        if features.xsave {
+            let supported = Xcr0::supported();
+
+            let mut xcr0 = Xcr0Flags::X87 | Xcr0Flags::SSE;
+            for component in [Xcr0Flags::AVX, Xcr0Flags::AVX512] {
+                if supported.contains(component) {
+                    xcr0.insert(component);
+                }
+            }
+
+            Xcr0::write(xcr0).expect("fpu: cannot configure XCR0");
+
+            // CPUID.(EAX=0xD,ECX=0).EBX: save area size needed for the components now enabled
+            if __cpuid_count(0xd, 0).ebx as usize > FPU_AREA_SIZE {
+                xcr0.remove(Xcr0Flags::AVX512);
+                Xcr0::write(xcr0).expect("fpu: cannot configure XCR0");
+            }
+
+            XCR0.store(xcr0.bits(), Ordering::Relaxed);
+            AREA_SIZE.store(__cpuid_count(0xd, 0).ebx as usize, Ordering::Relaxed);
+            XSAVE.store(true, Ordering::Relaxed);
+        }
+
+        // Start from a clean state so the boot context does not leak firmware values
+        FpuState::new().restore();
+    }
+
+    LAZY.store(boot::option("fpu") == Some("lazy"), Ordering::Relaxed);
+
+    log::info!(
+        "fpu: {} with a {}-byte save area, {} switching",
+        if XSAVE.load(Ordering::Relaxed) { "xsave" } else { "fxsave" },
+        AREA_SIZE.load(Ordering::Relaxed),
+        if LAZY.load(Ordering::Relaxed) { "lazy" } else { "eager" }
+    );
+}
+
+/// Configures an application processor's FPU the way [`init`] configured the boot CPU's, so
+/// every save area has the same layout wherever it is saved.
+pub fn init_cpu() {
+    let xsave = XSAVE.load(Ordering::Relaxed);
+
+    unsafe {
+        enable(xsave);
+        if xsave {
+            Xcr0::write(Xcr0Flags::from_bits_retain(XCR0.load(Ordering::Relaxed))).expect("fpu: cannot configure XCR0");
+        }
+        FpuState::new().restore();
+    }
+}
+
+// Turns on the FPU and SSE, and XSAVE if `xsave`, on this CPU
+unsafe fn enable(xsave: bool) {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.insert(Cr0Flags::MP);
+            cr0.remove(Cr0Flags::EM | Cr0Flags::TS);
+        })
+        .expect("fpu: cannot configure CR0");
+
+        Cr4::update(|cr4| {
+            cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT);
+            cr4.set(Cr4Flags::OSXSAVE, xsave);
+        })
+        .expect("fpu: cannot configure CR4");
+    }
+}
+
+/// Whether state is switched lazily. The registers then have a single owner system-wide, so
+/// the kernel keeps to the boot CPU.
+pub fn is_lazy() -> bool {
+    LAZY.load(Ordering::Relaxed)
+}
+
+/// Bytes of each save area in use with the components the CPU was configured for.
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/// Context switch hook, called with the outgoing and incoming process.
+pub fn switch(prev: &mut Process, next: &Process) {
+    if LAZY.load(Ordering::Relaxed) {
+        // The registers still belong to their owner; the next FPU instruction traps to
+        // handle_device_not_available, unless `next` is the owner already
+        let owner = OWNER.load(Ordering::Relaxed);
+        let result = unsafe { Cr0::update(|cr0| cr0.set(Cr0Flags::TS, owner != next.pid)) };
+        result.expect("fpu: cannot update CR0.TS");
+        return;
+    }
+
+    prev.fpu.save();
+    next.fpu.restore();
+}
+
+/// `#NM` (device not available) handler for lazy switching: saves the registers into their
+/// previous owner's area and loads `current`'s state.
+pub fn handle_device_not_available(current: &mut Process) {
+    control::clts();
+
+    let owner = OWNER.swap(current.pid, Ordering::Relaxed);
+    if owner == current.pid {
+        return;
+    }
+
+    if owner != NO_OWNER {
+        let mut saved = false;
+        ptable::with_process(owner, |p| {
+            p.fpu.save();
+            saved = true;
+        });
+
+        // The owner exited without forget() being called; its state can simply be dropped
+        if !saved {
+            log::debug!("fpu: previous owner {} is gone", owner);
+        }
+    }
+
+    current.fpu.restore();
+}
+
+/// Exec hook: gives `current`, the running process, a fresh state, loaded now or, when
+/// switching lazily, on its next FPU instruction.
+pub fn reset(current: &mut Process) {
+    current.fpu = FpuState::new();
+    if LAZY.load(Ordering::Relaxed) {
+        forget(current.pid);
+        let result = unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::TS)) };
+        result.expect("fpu: cannot update CR0.TS");
+        return;
+    }
+
+    current.fpu.restore();
+}
+
+/// Teardown hook: the registers no longer belong to `pid`.
+pub fn forget(pid: u64) {
+    _ = OWNER.compare_exchange(pid, NO_OWNER, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn save_restore_roundtrip() {
+            // MXCSR is never touched by compiled code, so it survives between the asm blocks;
+            // flush-to-zero (bit 15) makes the value distinguishable from the default
+            let custom: u32 = MXCSR_DEFAULT | 1 << 15;
+            let mut state = FpuState::new();
+            let mut readback: u32 = 0;
+
+            unsafe {
+                asm!("ldmxcsr [{}]", in(reg) &custom, options(nostack, readonly));
+                state.save();
+                FpuState::new().restore();
+                asm!("stmxcsr [{}]", in(reg) &mut readback, options(nostack));
+            }
+
+            assert_eq!(readback, MXCSR_DEFAULT);
+            assert_eq!(state.area[MXCSR_OFFSET..MXCSR_OFFSET + 4], custom.to_le_bytes());
+
+            state.restore();
+            unsafe { asm!("stmxcsr [{}]", in(reg) &mut readback, options(nostack)) };
+            FpuState::new().restore();
+
+            assert_eq!(readback, custom);
+        }
+
+        fn initial_state_has_default_control_words() {
+            let state = FpuState::new();
+
+            assert_eq!(u16::from_le_bytes([state.area[0], state.area[1]]), FCW_DEFAULT);
+            assert_eq!(state.area[MXCSR_OFFSET..MXCSR_OFFSET + 4], MXCSR_DEFAULT.to_le_bytes());
+        }
+    }
+}
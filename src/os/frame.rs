@@ -0,0 +1,368 @@
+//! The physical frame allocator.
+//!
+//! Each usable memory region becomes a zone with a bitmap of its frames, a set bit marking a
+//! frame in use. The bitmap is carved from the first frames of the zone itself, which are
+//! identity-mapped like the rest of memory, so tracking costs one frame per 128 MiB and no
+//! allocation. The frames of the kernel image, of the buffer the firmware left the memory map
+//! in, and frame 0 (whose address reads as "none" to page table code) are never handed out.
+//!
+//! Single frames come from the zones of the nodes [`numa::fallback_order`] lists, each zone
+//! searched from just past its last allocation; runs of contiguous frames, for DMA buffers and
+//! huge pages, from the first zone with a free run at the requested alignment.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch;
+use crate::os::boot;
+use crate::os::memory::{self, FRAME_SIZE, FrameAllocator, MemoryRegion};
+use crate::os::numa::{self, Placement};
+
+/// Maximum number of zones, one per usable memory region.
+const MAX_ZONES: usize = 32;
+
+// Frames one bitmap word covers
+const WORD_FRAMES: u64 = 64;
+
+#[derive(Clone, Copy)]
+struct Zone {
+    start: u64,
+    frames: u64,
+    node: u8,
+
+    // One bit per frame, padding bits past `frames` set
+    bitmap: *mut u64,
+    free: u64,
+
+    // Word to start the next single-frame search at
+    hint: u64,
+}
+
+impl Zone {
+    const EMPTY: Zone = Zone { start: 0, frames: 0, node: 0, bitmap: core::ptr::null_mut(), free: 0, hint: 0 };
+
+    fn words(&self) -> u64 {
+        self.frames.div_ceil(WORD_FRAMES)
+    }
+
+    fn word(&self, index: u64) -> u64 {
+        unsafe { *self.bitmap.add(index as usize) }
+    }
+
+    fn set_word(&mut self, index: u64, value: u64) {
+        unsafe { *self.bitmap.add(index as usize) = value };
+    }
+
+    fn index_of(&self, addr: u64) -> Option<u64> {
+        let index = addr.checked_sub(self.start)? / FRAME_SIZE;
+        (index < self.frames).then_some(index)
+    }
+
+    fn address(&self, index: u64) -> u64 {
+        self.start + index * FRAME_SIZE
+    }
+
+    fn is_used(&self, index: u64) -> bool {
+        self.word(index / WORD_FRAMES) & (1 << (index % WORD_FRAMES)) != 0
+    }
+
+    // Marks a frame used, returning whether it was free
+    fn take(&mut self, index: u64) -> bool {
+        let (word, bit) = (self.word(index / WORD_FRAMES), 1 << (index % WORD_FRAMES));
+        let was_free = word & bit == 0;
+        self.set_word(index / WORD_FRAMES, word | bit);
+        self.free -= was_free as u64;
+        was_free
+    }
+
+    // Marks a frame free, returning whether it was in use
+    fn release(&mut self, index: u64) -> bool {
+        let (word, bit) = (self.word(index / WORD_FRAMES), 1 << (index % WORD_FRAMES));
+        let was_used = word & bit != 0;
+        self.set_word(index / WORD_FRAMES, word & !bit);
+        self.free += was_used as u64;
+        was_used
+    }
+
+    fn alloc_one(&mut self) -> Option<u64> {
+        if self.free == 0 {
+            return None;
+        }
+
+        let words = self.words();
+        let found = (0..words).map(|i| (self.hint + i) % words).find(|&w| self.word(w) != u64::MAX)?;
+        let index = found * WORD_FRAMES + self.word(found).trailing_ones() as u64;
+
+        self.take(index);
+        self.hint = found;
+        Some(self.address(index))
+    }
+
+    fn alloc_run(&mut self, frames: u64, align: u64) -> Option<u64> {
+        let end = self.start + self.frames * FRAME_SIZE;
+        let mut base = self.start.next_multiple_of(align);
+
+        if frames > self.free {
+            return None;
+        }
+
+        while base + frames * FRAME_SIZE <= end {
+            let first = (base - self.start) / FRAME_SIZE;
+
+            // Skip past the last used frame in the candidate run
+            match (first..first + frames).rev().find(|&i| self.is_used(i)) {
+                Some(used) => base = self.address(used + 1).next_multiple_of(align),
+                None => {
+                    (first..first + frames).for_each(|i| _ = self.take(i));
+                    return Some(base);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// A bitmap allocator over a set of memory regions.
+pub struct BitmapAllocator {
+    lock: AtomicBool,
+    zones: UnsafeCell<[Zone; MAX_ZONES]>,
+}
+
+// Zones are only reached under the lock
+unsafe impl Sync for BitmapAllocator {}
+
+impl BitmapAllocator {
+    /// An allocator with no memory, to be given some with [`init`](Self::init).
+    pub const fn new() -> Self {
+        BitmapAllocator { lock: AtomicBool::new(false), zones: UnsafeCell::new([Zone::EMPTY; MAX_ZONES]) }
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut [Zone]) -> R) -> R {
+        arch::without_interrupts(|| {
+            while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                core::hint::spin_loop();
+            }
+
+            let result = unsafe { f(&mut *self.zones.get()) };
+            self.lock.store(false, Ordering::Release);
+            result
+        })
+    }
+
+    /// Takes over the frames of `regions`, except those overlapping any `[start, end)` range
+    /// in `reserved`. Each region's bitmap is written into its first frames, so the regions
+    /// must be memory nothing else uses.
+    pub fn init(&self, regions: &[MemoryRegion], reserved: &[(u64, u64)]) {
+        self.locked(|zones| {
+            zones.fill(Zone::EMPTY);
+
+            let mut count = 0;
+            for region in regions {
+                let frames = region.size / FRAME_SIZE;
+                let bitmap_frames = (frames.div_ceil(WORD_FRAMES) * 8).div_ceil(FRAME_SIZE);
+                if frames <= bitmap_frames {
+                    continue;
+                }
+                if count == MAX_ZONES {
+                    log::warn!("frame: out of zones, leaving {:#x}-{:#x} unused", region.start, region.end());
+                    continue;
+                }
+
+                let mut zone = Zone { start: region.start, frames, node: region.node, bitmap: region.start as *mut u64, free: frames, hint: 0 };
+                unsafe { core::ptr::write_bytes(zone.bitmap, 0, zone.words() as usize) };
+
+                // Padding past the last frame reads as used, so searches never stop there
+                let tail = frames % WORD_FRAMES;
+                if tail != 0 {
+                    zone.set_word(zone.words() - 1, !0 << tail);
+                }
+
+                for index in 0..bitmap_frames {
+                    zone.take(index);
+                }
+                for &(start, end) in reserved {
+                    let first = start.max(zone.start);
+                    let last = end.min(zone.address(frames));
+                    let mut frame = first & !(FRAME_SIZE - 1);
+                    while frame < last {
+                        zone.take((frame - zone.start) / FRAME_SIZE);
+                        frame += FRAME_SIZE;
+                    }
+                }
+
+                zones[count] = zone;
+                count += 1;
+            }
+        });
+    }
+
+    /// Allocates `frames` physically contiguous frames starting at a multiple of `align` bytes
+    /// and returns the address of the first.
+    pub fn alloc_contiguous(&self, frames: u64, align: u64) -> Option<u64> {
+        let align = align.max(FRAME_SIZE);
+        self.locked(|zones| zones.iter_mut().find_map(|zone| zone.alloc_run(frames, align)))
+    }
+
+    // The zone holding `addr` and the frame's index in it
+    fn with_frame<R>(&self, addr: u64, f: impl FnOnce(&mut Zone, u64) -> R) -> Option<R> {
+        self.locked(|zones| {
+            zones.iter_mut().find_map(|zone| zone.index_of(addr).map(|index| (zone, index))).map(|(zone, index)| f(zone, index))
+        })
+    }
+}
+
+impl FrameAllocator for BitmapAllocator {
+    fn alloc_frame(&self, placement: Placement) -> Option<u64> {
+        let order = numa::fallback_order(placement);
+        self.locked(|zones| {
+            order.as_slice().iter().find_map(|&node| zones.iter_mut().filter(|zone| zone.node == node).find_map(Zone::alloc_one))
+        })
+    }
+
+    fn free_frame(&self, addr: u64) {
+        if self.with_frame(addr, |zone, index| zone.release(index)) != Some(true) {
+            log::warn!("frame: freeing {:#x}, which is not an allocated frame", addr);
+        }
+    }
+
+    fn free_frames(&self) -> usize {
+        self.locked(|zones| zones.iter().map(|zone| zone.free).sum::<u64>() as usize)
+    }
+
+    fn is_free(&self, addr: u64) -> bool {
+        self.with_frame(addr, |zone, index| !zone.is_used(index)) == Some(true)
+    }
+
+    fn alloc_frame_at(&self, addr: u64) -> bool {
+        self.with_frame(addr, |zone, index| zone.take(index)) == Some(true)
+    }
+
+    fn alloc_contiguous(&self, frames: u64, align: u64) -> Option<u64> {
+        BitmapAllocator::alloc_contiguous(self, frames, align)
+    }
+}
+
+/// The kernel's frame allocator.
+pub static FRAMES: BitmapAllocator = BitmapAllocator::new();
+
+/// Hands the usable memory regions to [`FRAMES`] and installs it as the frame allocator.
+/// Called once the final memory map has been stored.
+pub fn init() {
+    crate::trace_fn!();
+
+    let (image_start, image_end) = boot::image_extent();
+    let (map_start, map_end) = memory::memory_map_buffer();
+    let (initrd_start, initrd_len) = boot::initrd_extent();
+    let reserved = [(0, FRAME_SIZE), (image_start, image_end), (map_start, map_end), (initrd_start, initrd_start + initrd_len)];
+
+    FRAMES.init(memory::get_usable_memory_regions(), &reserved);
+    memory::set_frame_allocator(&FRAMES);
+
+    log::info!("frame: {} of {} frames free", FRAMES.free_frames(), memory::stats().total / FRAME_SIZE);
+}
+
+/// Allocates one frame on the calling CPU's node, or the nearest one with memory free.
+pub fn alloc_frame() -> Option<u64> {
+    FRAMES.alloc_frame(Placement::Local)
+}
+
+/// Allocates `frames` physically contiguous frames and returns the address of the first.
+pub fn alloc_contiguous(frames: u64) -> Option<u64> {
+    FRAMES.alloc_contiguous(frames, FRAME_SIZE)
+}
+
+/// Frees a frame from [`alloc_frame`], or one frame of a run from [`alloc_contiguous`].
+pub fn free_frame(addr: u64) {
+    FRAMES.free_frame(addr);
+}
+
+pub mod ktests {
+    use super::*;
+    use crate::os::memory::RegionKind;
+
+    const TEST_FRAMES: usize = 32;
+
+    #[repr(C, align(32768))]
+    struct Frames([[u8; FRAME_SIZE as usize]; TEST_FRAMES]);
+
+    static mut MEMORY: Frames = Frames([[0; FRAME_SIZE as usize]; TEST_FRAMES]);
+
+    fn base() -> u64 {
+        (&raw const MEMORY) as u64
+    }
+
+    fn frame(index: u64) -> u64 {
+        base() + index * FRAME_SIZE
+    }
+
+    // An 8-frame region and a 24-frame one, with frames 10-11 of the second reserved
+    fn allocator() -> &'static BitmapAllocator {
+        static TEST: BitmapAllocator = BitmapAllocator::new();
+
+        let regions = [
+            MemoryRegion { start: frame(0), size: 8 * FRAME_SIZE, node: 0, kind: RegionKind::Usable },
+            MemoryRegion { start: frame(8), size: 24 * FRAME_SIZE, node: 0, kind: RegionKind::Usable },
+        ];
+        TEST.init(&regions, &[(frame(10), frame(12))]);
+        &TEST
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn bitmaps_and_reserved_frames_are_never_handed_out() {
+            let allocator = allocator();
+
+            // Each region gives up its first frame to its bitmap
+            assert_eq!(allocator.free_frames(), 7 + 21);
+            assert!(!allocator.is_free(frame(0)) && !allocator.is_free(frame(8)));
+            assert!(!allocator.is_free(frame(10)) && !allocator.is_free(frame(11)));
+            assert!(allocator.is_free(frame(9)) && allocator.is_free(frame(12)));
+
+            let mut seen = 0u64;
+            while let Some(addr) = allocator.alloc_frame(Placement::Local) {
+                let index = (addr - base()) / FRAME_SIZE;
+                assert!(![0, 8, 10, 11].contains(&index), "handed out frame {}", index);
+                assert!(seen & (1 << index) == 0, "frame {} handed out twice", index);
+                seen |= 1 << index;
+            }
+            assert_eq!((seen.count_ones(), allocator.free_frames()), (28, 0));
+        }
+
+        fn freed_frames_are_reused() {
+            let allocator = allocator();
+            let free = allocator.free_frames();
+
+            let addr = allocator.alloc_frame(Placement::Local).unwrap();
+            assert_eq!(addr, frame(1));
+            assert!(!allocator.alloc_frame_at(addr));
+
+            allocator.free_frame(addr);
+            assert!(allocator.is_free(addr));
+            assert_eq!(allocator.alloc_frame(Placement::Local), Some(addr));
+
+            // A frame freed twice is only counted once
+            allocator.free_frame(addr);
+            allocator.free_frame(addr);
+            assert_eq!(allocator.free_frames(), free);
+
+            assert!(allocator.alloc_frame_at(frame(12)));
+            assert!(!allocator.alloc_frame_at(frame(10)));
+            assert!(!allocator.alloc_frame_at(frame(TEST_FRAMES as u64)));
+        }
+
+        fn contiguous_runs_skip_used_frames() {
+            let allocator = allocator();
+
+            assert_eq!(allocator.alloc_contiguous(4, FRAME_SIZE), Some(frame(1)));
+
+            // Too little is left of the first region, and the reserved frames split the second
+            let run = allocator.alloc_contiguous(8, 8 * FRAME_SIZE);
+            assert_eq!(run, Some(frame(16)));
+            assert!((16..24).all(|i| !allocator.is_free(frame(i))));
+
+            assert_eq!(allocator.alloc_contiguous(14, FRAME_SIZE), None);
+            (16..24).for_each(|i| allocator.free_frame(frame(i)));
+            assert_eq!(allocator.alloc_contiguous(12, FRAME_SIZE), Some(frame(12)));
+        }
+    }
+}
@@ -0,0 +1,405 @@
+//! The kernel heap, behind `#[global_allocator]`, so `alloc`'s `Box`, `Vec` and friends work
+//! anywhere in the kernel.
+//!
+//! Free memory is kept as a list of holes sorted by address, each hole's header written into the
+//! hole itself. Allocations take the first hole that fits, splitting off what is left on either
+//! side; frees put the block back in order and merge it with the holes next to it. Every block
+//! is a multiple of [`ALIGN`] bytes at an [`ALIGN`]-aligned address, so any leftover is big
+//! enough to hold a header.
+//!
+//! The heap starts out with [`INITIAL_FRAMES`] frames from the frame allocator and grows by at
+//! least [`GROW_FRAMES`] whenever no hole fits, compacting memory and deflating the balloon if
+//! that is what it takes. An allocation that still fails is logged and reported to `alloc`'s
+//! error handler, which panics.
+//!
+//! With the `kasan` feature each block carries red zones on either side, and the initial
+//! region is shadowed; memory the heap grows by later is not.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch;
+use crate::os::compaction;
+use crate::os::kasan;
+use crate::os::leak;
+use crate::os::memory::FRAME_SIZE;
+
+/// Alignment and size granule of every block.
+pub const ALIGN: usize = 16;
+
+/// Frames the heap starts out with. Fully shadowed with the `kasan` feature.
+pub const INITIAL_FRAMES: u64 = 256;
+
+/// Fewest frames the heap grows by at a time.
+pub const GROW_FRAMES: u64 = 64;
+
+// Bytes in front of the pointer handed out: the leading red zone with the `kasan` feature
+const PREFIX: usize = if cfg!(feature = "kasan") { kasan::REDZONE } else { 0 };
+
+// Header of a free block, at its start
+#[repr(C)]
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+const _: () = assert!(size_of::<Hole>() <= ALIGN);
+
+struct Holes {
+    // Lowest-addressed hole
+    head: *mut Hole,
+    total: usize,
+    used: usize,
+}
+
+/// Heap usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes the heap has taken from the frame allocator.
+    pub total: usize,
+
+    /// Bytes in allocated blocks, red zones and rounding included.
+    pub used: usize,
+}
+
+/// A first-fit heap over memory handed to it with [`add_region`](Self::add_region).
+pub struct Heap {
+    lock: AtomicBool,
+    holes: UnsafeCell<Holes>,
+}
+
+// The hole list is only touched under the lock
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Heap {
+            lock: AtomicBool::new(false),
+            holes: UnsafeCell::new(Holes { head: core::ptr::null_mut(), total: 0, used: 0 }),
+        }
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut Holes) -> R) -> R {
+        arch::without_interrupts(|| {
+            while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                core::hint::spin_loop();
+            }
+
+            let result = unsafe { f(&mut *self.holes.get()) };
+            self.lock.store(false, Ordering::Release);
+            result
+        })
+    }
+
+    /// Hands `[base, base + size)` to the heap, trimmed to [`ALIGN`]. The memory must be
+    /// writable and used by nothing else from now on.
+    pub fn add_region(&self, base: usize, size: usize) {
+        let start = base.next_multiple_of(ALIGN);
+        let end = (base + size) & !(ALIGN - 1);
+        if end <= start {
+            return;
+        }
+
+        self.locked(|holes| {
+            holes.total += end - start;
+            unsafe { holes.insert(start, end - start) };
+        });
+    }
+
+    /// Takes a block of `size` bytes (a multiple of [`ALIGN`]) such that `block + prefix` is a
+    /// multiple of `align`, and returns its address.
+    pub fn allocate(&self, size: usize, align: usize, prefix: usize) -> Option<usize> {
+        debug_assert!(size.is_multiple_of(ALIGN) && prefix.is_multiple_of(ALIGN) && align.is_power_of_two());
+        let align = align.max(ALIGN);
+
+        self.locked(|holes| unsafe {
+            let block = holes.take(size, align, prefix)?;
+            holes.used += size;
+            Some(block)
+        })
+    }
+
+    /// Gives back a block from [`allocate`](Self::allocate), with the size it was taken with.
+    pub fn free(&self, block: usize, size: usize) {
+        self.locked(|holes| {
+            holes.used -= size;
+            unsafe { holes.insert(block, size) };
+        });
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.locked(|holes| HeapStats { total: holes.total, used: holes.used })
+    }
+
+    /// Number of holes and the size of the largest one.
+    pub fn fragmentation(&self) -> (usize, usize) {
+        self.locked(|holes| unsafe {
+            let mut count = 0;
+            let mut largest = 0;
+            let mut hole = holes.head;
+            while !hole.is_null() {
+                count += 1;
+                largest = largest.max((*hole).size);
+                hole = (*hole).next;
+            }
+            (count, largest)
+        })
+    }
+}
+
+impl Holes {
+    // First fit: carves the block out of the first hole with room for it, leaving any space in
+    // front of and behind it as holes
+    unsafe fn take(&mut self, size: usize, align: usize, prefix: usize) -> Option<usize> {
+        let mut link: *mut *mut Hole = &mut self.head;
+
+        unsafe {
+            while !(*link).is_null() {
+                let hole = *link;
+                let start = hole as usize;
+                let end = start + (*hole).size;
+
+                let block = (start + prefix).next_multiple_of(align) - prefix;
+                if block + size > end {
+                    link = &mut (*hole).next;
+                    continue;
+                }
+
+                let next = (*hole).next;
+                let rest = end - (block + size);
+
+                // What is left behind the block takes the hole's place in the list
+                let after = if rest > 0 {
+                    let after = (block + size) as *mut Hole;
+                    after.write(Hole { size: rest, next });
+                    after
+                } else {
+                    next
+                };
+
+                // What is left in front keeps the hole's header
+                if block > start {
+                    (*hole).size = block - start;
+                    (*hole).next = after;
+                } else {
+                    *link = after;
+                }
+
+                return Some(block);
+            }
+        }
+
+        None
+    }
+
+    // Puts `[block, block + size)` back in address order, merging it with adjacent holes
+    unsafe fn insert(&mut self, block: usize, size: usize) {
+        let mut prev: *mut Hole = core::ptr::null_mut();
+        let mut next = self.head;
+
+        unsafe {
+            while !next.is_null() && (next as usize) < block {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let mut hole = block as *mut Hole;
+            hole.write(Hole { size, next });
+
+            if !next.is_null() && block + size == next as usize {
+                (*hole).size += (*next).size;
+                (*hole).next = (*next).next;
+            }
+
+            if prev.is_null() {
+                self.head = hole;
+            } else if prev as usize + (*prev).size == block {
+                (*prev).size += (*hole).size;
+                (*prev).next = (*hole).next;
+                hole = prev;
+            } else {
+                (*prev).next = hole;
+            }
+
+            debug_assert!((*hole).next.is_null() || hole as usize + (*hole).size <= (*hole).next as usize);
+        }
+    }
+}
+
+/// The kernel heap.
+pub static HEAP: Heap = Heap::new();
+
+struct KernelAllocator;
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+// Size of the block backing an allocation of `layout`
+fn block_size(layout: Layout) -> usize {
+    kasan::block_size(layout.size()).next_multiple_of(ALIGN).max(ALIGN)
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = block_size(layout);
+        let allocate = || HEAP.allocate(size, layout.align(), PREFIX);
+
+        let Some(block) = allocate().or_else(|| grow(size + layout.align()).then(allocate).flatten()) else {
+            log::error!("heap: out of memory allocating {} bytes ({:?})", layout.size(), HEAP.stats());
+            return core::ptr::null_mut();
+        };
+
+        let user = kasan::on_alloc(block, layout.size());
+        leak::record_alloc(user, layout.size(), 2);
+        user as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        leak::record_free(ptr as usize);
+        let block = kasan::on_free(ptr as usize, layout.size());
+        HEAP.free(block, block_size(layout));
+    }
+}
+
+// Adds at least `bytes` to the heap: frames from the frame allocator, compacting memory if
+// no run is free, then from the balloon. Returns whether the heap grew.
+fn grow(bytes: usize) -> bool {
+    let frames = (bytes as u64).div_ceil(FRAME_SIZE).max(GROW_FRAMES);
+
+    let run = compaction::alloc_contiguous(frames, FRAME_SIZE)
+        .or_else(|| deflate_balloon(frames).then(|| compaction::alloc_contiguous(frames, FRAME_SIZE)).flatten());
+
+    let Some(addr) = run else {
+        return false;
+    };
+
+    HEAP.add_region(addr as usize, (frames * FRAME_SIZE) as usize);
+    log::debug!("heap: grew by {} KiB at {:#x}", frames * FRAME_SIZE / 1024, addr);
+    true
+}
+
+// Asks the balloon for `frames` frames back, if there is one. Returns whether it gave any.
+fn deflate_balloon(frames: u64) -> bool {
+    #[cfg(all(target_arch = "x86_64", feature = "virtio"))]
+    {
+        crate::os::virtio::balloon::deflate_on_oom(frames as usize) > 0
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", feature = "virtio")))]
+    {
+        _ = frames;
+        false
+    }
+}
+
+/// Gives the heap its first [`INITIAL_FRAMES`] frames. Called once the frame allocator is up;
+/// allocating before then fails.
+pub fn init() {
+    crate::trace_fn!();
+
+    let Some(addr) = compaction::alloc_contiguous(INITIAL_FRAMES, FRAME_SIZE) else {
+        log::error!("heap: no memory for the initial heap");
+        return;
+    };
+
+    let size = (INITIAL_FRAMES * FRAME_SIZE) as usize;
+    #[cfg(feature = "kasan")]
+    kasan::init_heap(addr as usize, size);
+    HEAP.add_region(addr as usize, size);
+
+    log::info!("heap: {} KiB at {:#x}", size / 1024, addr);
+}
+
+/// Heap usage of the kernel heap.
+pub fn stats() -> HeapStats {
+    HEAP.stats()
+}
+
+pub mod ktests {
+    use super::*;
+
+    use alloc::boxed::Box;
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+
+    const TEST_BYTES: usize = 4096;
+
+    #[repr(C, align(4096))]
+    struct Memory([u8; TEST_BYTES]);
+
+    static mut MEMORY: Memory = Memory([0; TEST_BYTES]);
+
+    fn base() -> usize {
+        (&raw const MEMORY) as usize
+    }
+
+    fn heap() -> &'static Heap {
+        static TEST: Heap = Heap::new();
+
+        // Everything handed out by the previous test comes back as one region
+        unsafe { *TEST.holes.get() = Holes { head: core::ptr::null_mut(), total: 0, used: 0 } };
+        TEST.add_region(base(), TEST_BYTES);
+        &TEST
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn blocks_are_aligned_and_disjoint() {
+            let heap = heap();
+
+            let a = heap.allocate(48, ALIGN, 0).unwrap();
+            let b = heap.allocate(32, 256, 0).unwrap();
+            let c = heap.allocate(16, 64, 16).unwrap();
+            assert_eq!(a, base());
+            assert!(b.is_multiple_of(256) && (c + 16).is_multiple_of(64));
+
+            let mut blocks = [(a, 48), (b, 32), (c, 16)];
+            blocks.sort();
+            assert!(blocks.windows(2).all(|w| w[0].0 + w[0].1 <= w[1].0));
+            assert_eq!(heap.stats(), HeapStats { total: TEST_BYTES, used: 96 });
+        }
+
+        fn freed_blocks_merge_back() {
+            let heap = heap();
+
+            let blocks: [usize; 4] = core::array::from_fn(|_| heap.allocate(1024, ALIGN, 0).unwrap());
+            assert_eq!(heap.allocate(ALIGN, ALIGN, 0), None);
+
+            // Freed out of order, the holes still end up as one
+            for index in [1, 3, 0, 2] {
+                heap.free(blocks[index], 1024);
+            }
+            assert_eq!(heap.fragmentation(), (1, TEST_BYTES));
+            assert_eq!(heap.stats().used, 0);
+
+            // Aligning leaves a hole in front of the block as well as behind it
+            let small = heap.allocate(ALIGN, ALIGN, 0).unwrap();
+            let aligned = heap.allocate(512, 2048, 0).unwrap();
+            assert_eq!(aligned, base() + 2048);
+            assert_eq!(heap.fragmentation(), (2, 2048 - ALIGN));
+
+            heap.free(small, ALIGN);
+            heap.free(aligned, 512);
+            assert_eq!(heap.allocate(TEST_BYTES, ALIGN, 0), Some(base()));
+        }
+
+        fn collections_use_the_kernel_heap() {
+            let used = stats().used;
+
+            let boxed = Box::new([7u64; 32]);
+            let mut numbers: Vec<u32> = (0..1000).collect();
+            numbers.retain(|n| n % 3 == 0);
+            let mut map = BTreeMap::new();
+            for n in &numbers {
+                map.insert(*n, n * 2);
+            }
+
+            assert!(stats().used > used);
+            assert_eq!((boxed[31], numbers.len(), map.get(&999)), (7, 334, Some(&1998)));
+            assert_eq!(&*boxed as *const _ as usize % align_of::<u64>(), 0);
+
+            drop((boxed, numbers, map));
+            assert_eq!(stats().used, used);
+        }
+    }
+}
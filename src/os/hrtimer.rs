@@ -0,0 +1,437 @@
+//! High-resolution timers: callbacks with nanosecond deadlines, fired from the CPU's one-shot
+//! timer interrupt instead of waiting for the [`timer`](super::timer) wheel's millisecond tick.
+//!
+//! Pending timers sit in a small table. Whenever it changes, the earliest deadline is converted
+//! to a counter value and programmed with [`Arch::arm_timer`]: the TSC-deadline timer on x86,
+//! the generic timer on ARM, the SBI timer on RISC-V. [`handle_interrupt`] runs the expired
+//! callbacks, outside the table's lock so they may add and cancel timers themselves, and
+//! programs the next deadline. The wheel stays the cheaper choice for timeouts that only need
+//! tick accuracy.
+//!
+//! Deadlines are in `CLOCK_MONOTONIC` time. The counter deadline comes from the calibrated
+//! counter rate, which the NTP frequency correction does not follow, so the interrupt may
+//! arrive slightly early; a timer that is not yet due is then simply programmed again.
+//!
+//! Without a one-shot timer (x86 CPUs lacking TSC-deadline mode) timers still expire, from
+//! [`run`] in the idle loop, which sleeps no longer than [`next_event_ns`] says.
+//!
+//! `nanosleep` and `clock_nanosleep` block the caller on one of these timers.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch::{self, Arch, Current, counter};
+use crate::os::errno::{Errno, KResult};
+use crate::os::itimer::TIMER_ABSTIME;
+use crate::os::process::{Process, ProcessState, WaitTarget};
+use crate::os::ptable;
+use crate::os::timekeeping::{self, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, Timespec};
+use crate::os::uaccess;
+
+/// Maximum number of high-resolution timers pending at once.
+pub const MAX_HRTIMERS: usize = 64;
+
+/// Called when a timer expires, with its ID, the data it was added with and the number of
+/// expirations since the last call (more than 1 when a periodic timer's callback ran late).
+pub type Callback = fn(id: HrTimerId, data: usize, expirations: u64);
+
+/// A pending high-resolution timer. IDs are not reused while the timer is pending, and stale
+/// IDs of expired or cancelled timers refer to nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HrTimerId {
+    slot: u16,
+    generation: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HrTimer {
+    // Monotonic time the timer expires at and, for periodic timers, the time between expirations
+    expires: u64,
+    interval: u64,
+
+    callback: Callback,
+    data: usize,
+}
+
+// An expiration handed to `run`
+#[derive(Debug, Clone, Copy)]
+struct Expired {
+    id: HrTimerId,
+    callback: Callback,
+    data: usize,
+    expirations: u64,
+}
+
+struct Queue {
+    timers: [Option<HrTimer>; MAX_HRTIMERS],
+    generations: [u16; MAX_HRTIMERS],
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Queue { timers: [None; MAX_HRTIMERS], generations: [0; MAX_HRTIMERS] }
+    }
+
+    fn get(&self, id: HrTimerId) -> Option<&HrTimer> {
+        let slot = id.slot as usize;
+        if self.generations[slot] != id.generation {
+            return None;
+        }
+        self.timers[slot].as_ref()
+    }
+
+    fn insert(&mut self, expires: u64, interval: u64, callback: Callback, data: usize) -> KResult<HrTimerId> {
+        let slot = self.timers.iter().position(Option::is_none).ok_or(Errno::EAGAIN)?;
+
+        self.timers[slot] = Some(HrTimer { expires, interval, callback, data });
+        Ok(HrTimerId { slot: slot as u16, generation: self.generations[slot] })
+    }
+
+    fn remove(&mut self, id: HrTimerId) -> Option<HrTimer> {
+        self.get(id)?;
+
+        self.generations[id.slot as usize] = self.generations[id.slot as usize].wrapping_add(1);
+        self.timers[id.slot as usize].take()
+    }
+
+    // Takes the earliest timer expired by `now`. Periodic timers are moved to their next
+    // expiration; one-shot timers are removed.
+    fn pop_expired(&mut self, now: u64) -> Option<Expired> {
+        let (slot, timer) = self
+            .timers
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, timer)| Some((slot, (*timer)?)))
+            .filter(|(_, timer)| timer.expires <= now)
+            .min_by_key(|(_, timer)| timer.expires)?;
+
+        let id = HrTimerId { slot: slot as u16, generation: self.generations[slot] };
+        let mut expired = Expired { id, callback: timer.callback, data: timer.data, expirations: 1 };
+
+        // One-shot timers have no interval to divide by
+        if let Some(missed) = (now - timer.expires).checked_div(timer.interval) {
+            expired.expirations += missed;
+            if let Some(timer) = self.timers[slot].as_mut() {
+                timer.expires += expired.expirations * timer.interval;
+            }
+        } else {
+            self.remove(id);
+        }
+        Some(expired)
+    }
+
+    fn next_expiry(&self) -> Option<u64> {
+        self.timers.iter().flatten().map(|timer| timer.expires).min()
+    }
+}
+
+static mut QUEUE: Queue = Queue::new();
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the queue holding its lock, with interrupts masked so the timer interrupt cannot
+// spin on a lock its own CPU holds
+fn locked<R>(f: impl FnOnce(&mut Queue) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let queue = &raw mut QUEUE;
+            f(&mut *queue)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+// Counter value at monotonic time `expires`, rounded up so the interrupt is not early by more
+// than the frequency correction. `None` until the counter has been calibrated.
+fn counter_deadline(expires: u64, now: u64, start: u64, ticks_per_ms: u64) -> Option<u64> {
+    if ticks_per_ms == 0 {
+        return None;
+    }
+
+    let ticks = (expires.saturating_sub(now) as u128 * ticks_per_ms as u128).div_ceil(1_000_000);
+    Some(start.saturating_add(ticks.min(u64::MAX as u128) as u64))
+}
+
+// Programs this CPU's timer for the deadline `next`, or disarms it with nothing pending
+fn program(next: Option<u64>) {
+    if !Current::has_timer() {
+        return;
+    }
+
+    let deadline = next.and_then(|expires| {
+        counter_deadline(expires, timekeeping::monotonic_ns(), counter::read(), counter::ticks_per_ms())
+    });
+    match deadline {
+        Some(deadline) => Current::arm_timer(deadline),
+        None => Current::disarm_timer(),
+    }
+}
+
+/// Adds a timer expiring when `CLOCK_MONOTONIC` reaches `expires_ns` and then, if
+/// `interval_ns` is not 0, every `interval_ns`. A deadline already past expires on the next
+/// interrupt. Fails with `EAGAIN` when [`MAX_HRTIMERS`] timers are pending.
+pub fn add_at(expires_ns: u64, interval_ns: u64, callback: Callback, data: usize) -> KResult<HrTimerId> {
+    locked(|queue| {
+        let id = queue.insert(expires_ns, interval_ns, callback, data)?;
+        program(queue.next_expiry());
+        Ok(id)
+    })
+}
+
+/// Adds a timer expiring `delay_ns` from now, as [`add_at`] does.
+pub fn add(delay_ns: u64, interval_ns: u64, callback: Callback, data: usize) -> KResult<HrTimerId> {
+    add_at(timekeeping::monotonic_ns().saturating_add(delay_ns), interval_ns, callback, data)
+}
+
+/// Cancels a pending timer, returning the time that was left until it expired, or `None` if
+/// it had already expired or been cancelled.
+pub fn cancel(id: HrTimerId) -> Option<u64> {
+    let timer = locked(|queue| {
+        let timer = queue.remove(id)?;
+        program(queue.next_expiry());
+        Some(timer)
+    })?;
+    Some(timer.expires.saturating_sub(timekeeping::monotonic_ns()))
+}
+
+/// The time left until a pending timer expires and its interval, both in nanoseconds, or
+/// `None` if the timer is no longer pending.
+pub fn remaining(id: HrTimerId) -> Option<(u64, u64)> {
+    let timer = locked(|queue| queue.get(id).copied())?;
+    Some((timer.expires.saturating_sub(timekeeping::monotonic_ns()), timer.interval))
+}
+
+/// Time until the next timer expires, for the idle governor. `None` with no timer pending.
+pub fn next_event_ns() -> Option<u64> {
+    let expires = locked(|queue| queue.next_expiry())?;
+    Some(expires.saturating_sub(timekeeping::monotonic_ns()))
+}
+
+/// Runs the callbacks of all timers that have expired and programs the timer for the next.
+/// Called from the timer interrupt and the idle loop.
+pub fn run() {
+    let now = timekeeping::monotonic_ns();
+
+    while let Some(expired) = locked(|queue| queue.pop_expired(now)) {
+        (expired.callback)(expired.id, expired.data, expired.expirations);
+    }
+    locked(|queue| program(queue.next_expiry()));
+}
+
+/// Handler for the timer interrupt ([`Arch::TIMER_IRQ`]).
+pub fn handle_interrupt() {
+    // Reprogramming (or disarming) the timer clears a level-triggered interrupt before the EOI
+    run();
+    Current::end_of_interrupt(Current::TIMER_IRQ);
+}
+
+// =========================================================================
+// nanosleep / clock_nanosleep
+// =========================================================================
+
+/// A sleep in progress: the timer that ends it and where to report the time left if a signal
+/// cuts it short (0 for nowhere).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sleep {
+    pub timer: HrTimerId,
+    pub rem: usize,
+}
+
+fn finish_sleep(process: &mut Process) {
+    process.sleep = None;
+    process.wakeup_time = None;
+    process.wake();
+}
+
+// Timer callback: makes the sleeping process runnable again
+fn wake(id: HrTimerId, pid: usize, _expirations: u64) {
+    ptable::with_process(pid as u64, |process| {
+        if process.sleep.is_some_and(|sleep| sleep.timer == id) {
+            finish_sleep(process);
+        }
+    });
+}
+
+/// Blocks `process` until `CLOCK_MONOTONIC` reaches `deadline_ns`. If [`interrupt_sleep`]
+/// ends the sleep early the time left is copied to `rem`, unless it is 0. A deadline already
+/// past returns without blocking. Fails with `EAGAIN` when no timer is free.
+pub fn sleep_until(process: &mut Process, deadline_ns: u64, rem: usize) -> KResult<()> {
+    if deadline_ns <= timekeeping::monotonic_ns() {
+        return Ok(());
+    }
+
+    let timer = add_at(deadline_ns, 0, wake, process.pid as usize)?;
+    process.sleep = Some(Sleep { timer, rem });
+    process.wakeup_time = Some(deadline_ns);
+    process.waiting_on = Some(WaitTarget::Timer);
+    process.state = ProcessState::Blocked;
+    Ok(())
+}
+
+/// Ends `process`'s sleep early, for a signal: cancels its timer, makes it runnable and copies
+/// the time left to the `rem` the sleep started with. Returns the time left, or `None` if the
+/// process was not sleeping. Called on the way back to user space in the sleeping process,
+/// whose address space `rem` is in; fails with `EFAULT` if it cannot be written.
+pub fn interrupt_sleep(process: &mut Process) -> KResult<Option<u64>> {
+    let Some(sleep) = process.sleep else {
+        return Ok(None);
+    };
+
+    let left = cancel(sleep.timer).unwrap_or(0);
+    finish_sleep(process);
+    if sleep.rem != 0 {
+        uaccess::write_user(sleep.rem, &Timespec::from_ns(left))?;
+    }
+    Ok(Some(left))
+}
+
+/// `clock_nanosleep(clock, flags, req, rem)`: blocks the caller `caller` until the `struct
+/// timespec` at `req` has passed -- relative to now, or with `TIMER_ABSTIME` an absolute time on `clock`.
+/// Only relative sleeps report the time left to `rem` when interrupted. Fails with `EINVAL` for
+/// an unknown clock or flag or a malformed time, and `EAGAIN` when no timer is free.
+pub fn sys_clock_nanosleep(caller: u64, clock: u32, flags: u32, req: usize, rem: usize) -> KResult<()> {
+    if flags & !TIMER_ABSTIME != 0 || !matches!(clock, CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME) {
+        return Err(Errno::EINVAL);
+    }
+
+    let req = unsafe { uaccess::read_user::<Timespec>(req)? }.to_ns()?;
+    let now = timekeeping::monotonic_ns();
+
+    // An absolute time on another clock is converted to monotonic time once, now
+    let (deadline, rem) = match flags & TIMER_ABSTIME {
+        0 => (now.saturating_add(req), rem),
+        _ => (now.saturating_add(req.saturating_sub(timekeeping::now(clock)?)), 0),
+    };
+    ptable::with_process(caller, |process| sleep_until(process, deadline, rem)).ok_or(Errno::ESRCH)?
+}
+
+/// `nanosleep(req, rem)`: a relative `clock_nanosleep` on `CLOCK_MONOTONIC`.
+pub fn sys_nanosleep(caller: u64, req: usize, rem: usize) -> KResult<()> {
+    sys_clock_nanosleep(caller, CLOCK_MONOTONIC, 0, req, rem)
+}
+
+/// Cancels `process`'s sleep, if it is sleeping. Called when it exits.
+pub fn release(process: &mut Process) {
+    if let Some(sleep) = process.sleep.take() {
+        cancel(sleep.timer);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn ignore(_id: HrTimerId, _data: usize, _expirations: u64) {}
+
+    static mut TEST_QUEUE: Queue = Queue::new();
+
+    fn test_queue() -> &'static mut Queue {
+        unsafe {
+            let queue = &raw mut TEST_QUEUE;
+            *queue = Queue::new();
+            &mut *queue
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn timers_expire_in_deadline_order() {
+            let queue = test_queue();
+            let late = queue.insert(30_500, 0, ignore, 2).unwrap();
+            let early = queue.insert(10_250, 0, ignore, 1).unwrap();
+
+            assert!(queue.pop_expired(10_249).is_none());
+            assert_eq!(queue.next_expiry(), Some(10_250));
+
+            // Both due: the earlier deadline runs first, whatever order they were added in
+            assert_eq!(queue.pop_expired(40_000).map(|e| (e.id, e.data)), Some((early, 1)));
+            assert_eq!(queue.pop_expired(40_000).map(|e| (e.id, e.data)), Some((late, 2)));
+            assert!(queue.pop_expired(40_000).is_none());
+
+            assert!(queue.get(early).is_none());
+            assert!(queue.remove(late).is_none());
+            assert_eq!(queue.next_expiry(), None);
+        }
+
+        fn periodic_timers_count_missed_expirations() {
+            let queue = test_queue();
+            let id = queue.insert(1_000, 300, ignore, 0).unwrap();
+
+            assert_eq!(queue.pop_expired(1_000).map(|e| e.expirations), Some(1));
+            assert_eq!(queue.get(id).map(|t| t.expires), Some(1_300));
+
+            // 1,300, 1,600 and 1,900 have all passed by 2,000
+            assert_eq!(queue.pop_expired(2_000).map(|e| e.expirations), Some(3));
+            assert_eq!(queue.get(id).map(|t| t.expires), Some(2_200));
+
+            assert!(queue.remove(id).is_some());
+            assert!(queue.pop_expired(u64::MAX).is_none());
+        }
+
+        fn queue_full_is_eagain() {
+            let queue = test_queue();
+            for _ in 0..MAX_HRTIMERS {
+                queue.insert(1, 0, ignore, 0).unwrap();
+            }
+            assert_eq!(queue.insert(1, 0, ignore, 0), Err(Errno::EAGAIN));
+        }
+
+        fn deadlines_convert_to_counter_values() {
+            // 3 GHz counter: 3,000,000 ticks per millisecond, 3 per nanosecond
+            assert_eq!(counter_deadline(1_500, 1_000, 7_000, 3_000_000), Some(8_500));
+            assert_eq!(counter_deadline(500, 1_000, 7_000, 3_000_000), Some(7_000));
+
+            // Sub-nanosecond remainders round up: 1 ns at 1 MHz is one tick away
+            assert_eq!(counter_deadline(1_001, 1_000, 7_000, 1_000), Some(7_001));
+            assert_eq!(counter_deadline(u64::MAX, 0, 7_000, 3_000_000), Some(u64::MAX));
+            assert_eq!(counter_deadline(1_500, 1_000, 7_000, 0), None);
+        }
+
+        fn sleeps_block_until_woken() {
+            let mut process = Process::new(9600, 0, "hrtimer");
+            process.state = ProcessState::Running;
+
+            // A deadline already past does not block
+            assert_eq!(sleep_until(&mut process, 0, 0), Ok(()));
+            assert_eq!(process.state, ProcessState::Running);
+
+            let deadline = timekeeping::monotonic_ns() + 60 * timekeeping::NS_PER_SEC;
+            assert_eq!(sleep_until(&mut process, deadline, 0), Ok(()));
+            assert_eq!(process.state, ProcessState::Blocked);
+            assert_eq!(process.wakeup_time, Some(deadline));
+            let timer = process.sleep.unwrap().timer;
+
+            ptable::insert(process).unwrap();
+            wake(timer, 9600, 1);
+            let process = ptable::remove(9600).unwrap();
+
+            assert_eq!(process.state, ProcessState::Ready);
+            assert_eq!(process.sleep, None);
+            assert_eq!(process.waiting_on, None);
+            cancel(timer);
+        }
+
+        fn interrupted_sleeps_report_the_time_left() {
+            let mut process = Process::new(9601, 0, "hrtimer");
+            assert_eq!(interrupt_sleep(&mut process), Ok(None));
+
+            let deadline = timekeeping::monotonic_ns() + 60 * timekeeping::NS_PER_SEC;
+            sleep_until(&mut process, deadline, 0).unwrap();
+            let timer = process.sleep.unwrap().timer;
+
+            let left = interrupt_sleep(&mut process).unwrap().unwrap();
+            assert!(left > 0 && left <= 60 * timekeeping::NS_PER_SEC);
+            assert_eq!(process.state, ProcessState::Ready);
+            assert!(remaining(timer).is_none());
+        }
+
+        fn clock_nanosleep_checks_clock_and_flags() {
+            ptable::insert(Process::new(9602, 0, "hrtimer")).unwrap();
+            assert_eq!(sys_clock_nanosleep(9602, 3, 0, 0, 0), Err(Errno::EINVAL));
+            assert_eq!(sys_clock_nanosleep(9602, CLOCK_MONOTONIC, 2, 0, 0), Err(Errno::EINVAL));
+            ptable::remove(9602);
+        }
+    }
+}
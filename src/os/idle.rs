@@ -0,0 +1,136 @@
+//! CPU idle states.
+//!
+//! When there is nothing to run the CPU enters an idle state until the next interrupt. Deeper
+//! MWAIT C-states save more power but take longer to leave and only pay off if the CPU stays
+//! idle long enough. The governor picks the deepest state whose target residency fits the
+//! predicted idle time, where the prediction is the time until the next timer event scaled
+//! by how accurate past predictions turned out to be (wakeups from other interrupts make
+//! actual idle periods shorter than the timer suggests). Without MWAIT it falls back to `hlt`.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::arch::counter;
+use crate::os::cpu;
+
+/// One selectable idle state.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleState {
+    pub name: &'static str,
+
+    /// MWAIT hint (`EAX`): bits 7:4 are the target C-state minus one, bits 3:0 the sub-state.
+    pub hint: u32,
+
+    /// Worst-case time to resume execution, in nanoseconds.
+    pub exit_latency_ns: u64,
+
+    /// Minimum idle time for the state to save energy overall, in nanoseconds.
+    pub target_residency_ns: u64,
+}
+
+/// Candidate MWAIT states, shallowest first. Only those the CPU enumerates are used.
+const MWAIT_STATES: [IdleState; 4] = [
+    IdleState { name: "C1", hint: 0x00, exit_latency_ns: 2_000, target_residency_ns: 2_000 },
+    IdleState { name: "C1E", hint: 0x01, exit_latency_ns: 10_000, target_residency_ns: 20_000 },
+    IdleState { name: "C3", hint: 0x10, exit_latency_ns: 80_000, target_residency_ns: 200_000 },
+    IdleState { name: "C6", hint: 0x20, exit_latency_ns: 130_000, target_residency_ns: 600_000 },
+];
+
+/// Exit latency the governor will never exceed, in nanoseconds.
+pub const LATENCY_LIMIT_NS: u64 = 200_000;
+
+// Correction factor applied to timer-based predictions, in 1/1024ths (1024 = timer is exact).
+// Updated as an exponential moving average of actual / predicted idle time.
+static CORRECTION: AtomicU64 = AtomicU64::new(1024);
+
+// Cache line watched by MONITOR; writing it wakes an MWAIT-ing CPU without an interrupt
+static WAKE_FLAG: AtomicU64 = AtomicU64::new(0);
+
+// Number of entries into each state of MWAIT_STATES, for statistics
+static USAGE: [AtomicU64; MWAIT_STATES.len()] = [const { AtomicU64::new(0) }; MWAIT_STATES.len()];
+
+/// Returns `true` if the CPU can enter the MWAIT state `state`.
+fn supported(state: &IdleState) -> bool {
+    let features = cpu::features();
+    if !features.mwait {
+        return false;
+    }
+
+    // Nibble n of the enumeration counts the sub-states of MWAIT C-state n
+    let cstate = (state.hint >> 4) + 1;
+    let substates = (features.mwait_substates >> (cstate * 4)) & 0xf;
+    state.hint & 0xf < substates
+}
+
+/// Picks the index into the state table for an idle period expected to last `predicted_ns`.
+fn select(predicted_ns: u64) -> Option<usize> {
+    MWAIT_STATES
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, s)| {
+            supported(s) && s.exit_latency_ns <= LATENCY_LIMIT_NS && s.target_residency_ns <= predicted_ns
+        })
+        .map(|(i, _)| i)
+        .or_else(|| MWAIT_STATES.iter().position(supported))
+}
+
+/// Idles the CPU until the next interrupt (or [`kick`]).
+///
+/// `next_event_ns` is the time until the next timer event, if the timer subsystem knows it;
+/// without one only the shallowest state is used. Interrupts are enabled atomically with
+/// entering the idle state and stay enabled on return; callers that first check for runnable
+/// work should disable them before that check so a wakeup cannot slip in between.
+pub fn enter(next_event_ns: Option<u64>) {
+    unsafe { asm!("cli", options(nomem, nostack)) };
+
+    let predicted = next_event_ns.map_or(0, |ns| ns.saturating_mul(CORRECTION.load(Ordering::Relaxed)) / 1024);
+
+    let Some(index) = select(predicted) else {
+        // No MWAIT: `sti; hlt` cannot miss a wakeup because sti takes effect after hlt starts
+        unsafe { asm!("sti; hlt", options(nomem, nostack)) };
+        return;
+    };
+
+    USAGE[index].fetch_add(1, Ordering::Relaxed);
+    let start = counter::read();
+
+    unsafe {
+        asm!("monitor", in("rax") WAKE_FLAG.as_ptr(), in("ecx") 0, in("edx") 0, options(nostack));
+
+        // ECX bit 0: wake on interrupts even though they are masked at the MWAIT itself
+        asm!("sti; mwait", in("eax") MWAIT_STATES[index].hint, in("ecx") 1, options(nostack));
+    }
+
+    if let Some(expected) = next_event_ns.filter(|ns| *ns > 0) {
+        learn(expected, elapsed_ns(start));
+    }
+}
+
+/// Wakes a CPU waiting in MWAIT without sending it an interrupt.
+pub fn kick() {
+    WAKE_FLAG.fetch_add(1, Ordering::Release);
+}
+
+fn elapsed_ns(start: u64) -> u64 {
+    match counter::ticks_per_ms() {
+        0 => 0,
+        rate => counter::read().wrapping_sub(start).saturating_mul(1_000_000) / rate,
+    }
+}
+
+// Moves the correction factor 1/8 of the way towards the observed actual/expected ratio
+fn learn(expected_ns: u64, actual_ns: u64) {
+    let observed = (actual_ns.saturating_mul(1024) / expected_ns).min(1024);
+    let old = CORRECTION.load(Ordering::Relaxed);
+    CORRECTION.store((old * 7 + observed) / 8, Ordering::Relaxed);
+}
+
+/// Calls `f` with each usable state and how often it was entered.
+pub fn for_each_state(mut f: impl FnMut(&IdleState, u64)) {
+    for (state, usage) in MWAIT_STATES.iter().zip(&USAGE) {
+        if supported(state) {
+            f(state, usage.load(Ordering::Relaxed));
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! The initial RAM disk: a tar archive the UEFI stage reads from the boot volume (see
+//! [`boot::load_initrd`]), served as the read-only root filesystem.
+//!
+//! The archive is ustar, as `tar --format=ustar` writes it, and stays where the firmware put
+//! it: files are read straight out of it, and nothing is indexed. Regular files and
+//! directories are kept; links and device nodes are skipped. A directory need not have an
+//! entry of its own, it is enough for a file to lie under it. [`init`] makes the archive the
+//! filesystem behind `rootfs` mounts, and [`spawn_init`] starts `/bin/init` from it.
+//!
+//! [`boot::load_initrd`]: crate::os::boot::load_initrd
+
+use crate::os::boot;
+use crate::os::cred::ROOT_ID;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file::{O_ACCMODE, O_RDONLY};
+use crate::os::mount::{FsType, Path};
+use crate::os::process::Process;
+use crate::os::vfs::{self, FileSystem, Inode, InodeKind};
+
+/// The program [`spawn_init`] starts.
+pub const INIT_PATH: &str = "/bin/init";
+
+// Archives are made of 512-byte records: a header, then the data padded to a whole record
+const RECORD: usize = 512;
+
+// Header fields: (offset, length)
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const UID: (usize, usize) = (108, 8);
+const GID: (usize, usize) = (116, 8);
+const SIZE: (usize, usize) = (124, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPE: usize = 156;
+const MAGIC: (usize, usize) = (257, 6);
+const PREFIX: (usize, usize) = (345, 155);
+
+const REGULAR: u8 = b'0';
+const OLD_REGULAR: u8 = 0;
+const DIRECTORY: u8 = b'5';
+
+/// A ustar archive in memory.
+#[derive(Clone, Copy)]
+pub struct Archive {
+    data: &'static [u8],
+}
+
+// A file or directory in the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    path: Path,
+    kind: InodeKind,
+
+    // Where its header starts, and its size
+    at: usize,
+    size: usize,
+
+    // Owner, group and permission bits
+    uid: u32,
+    gid: u32,
+    mode: u16,
+}
+
+static mut ARCHIVE: Option<Archive> = None;
+
+/// The initrd as the VFS opens it.
+pub static FS: Initrd = Initrd;
+
+pub struct Initrd;
+
+/// Checks the initrd the UEFI stage read, if any, and makes it the root filesystem. Called
+/// once at boot, after the VFS is set up.
+pub fn init() {
+    let (start, len) = boot::initrd_extent();
+    if len == 0 {
+        return;
+    }
+
+    let archive = Archive::new(unsafe { core::slice::from_raw_parts(start as *const u8, len as usize) });
+    let Some(files) = archive.count() else {
+        log::warn!("initrd: not a ustar archive, ignored");
+        return;
+    };
+
+    unsafe {
+        let slot = &raw mut ARCHIVE;
+        *slot = Some(archive);
+    }
+    vfs::register(FsType::RootFs, &FS);
+    log::info!("initrd: {} entries in {} KiB at {:#x}", files, len / 1024, start);
+}
+
+fn archive() -> Option<Archive> {
+    unsafe {
+        let slot = &raw const ARCHIVE;
+        *slot
+    }
+}
+
+/// Starts [`INIT_PATH`] from the initrd as the first user process, a child of the kernel.
+/// Does nothing without an initrd or with no such file in it.
+#[cfg(target_arch = "x86_64")]
+pub fn spawn_init() {
+    let Some(image) = archive().and_then(|archive| archive.file(INIT_PATH)) else {
+        return;
+    };
+
+    match crate::os::elf::spawn(image, &[INIT_PATH], 0) {
+        Ok(pid) => log::info!("initrd: started {} as pid {}", INIT_PATH, pid),
+        Err(errno) => log::error!("initrd: cannot start {}: {:?}", INIT_PATH, errno),
+    }
+}
+
+// The octal number in a header field, which ends at a NUL or space
+fn octal(field: &[u8]) -> Option<usize> {
+    let digits = field.iter().position(|&b| b == 0 || b == b' ').map_or(field, |end| &field[..end]);
+    let digits = core::str::from_utf8(digits).ok()?.trim_start();
+    if digits.is_empty() { Some(0) } else { usize::from_str_radix(digits, 8).ok() }
+}
+
+fn field(header: &[u8], (offset, len): (usize, usize)) -> &[u8] {
+    &header[offset..offset + len]
+}
+
+// A NUL-terminated header string
+fn text(field: &[u8]) -> Option<&str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).ok()
+}
+
+impl Archive {
+    /// The archive in `data`, which must stay in place for the kernel's lifetime.
+    pub fn new(data: &'static [u8]) -> Archive {
+        Archive { data }
+    }
+
+    // The entry whose header is at `at`, and where the next header is; None at the end of the
+    // archive or at a header that is not ustar or fails its checksum
+    fn entry_at(&self, at: usize) -> Option<(Option<Entry>, usize)> {
+        let header = self.data.get(at..at + RECORD)?;
+        if header.iter().all(|&b| b == 0) || !field(header, MAGIC).starts_with(b"ustar") {
+            return None;
+        }
+
+        // The checksum is taken with its own field as spaces
+        let sum: usize = header.iter().enumerate().map(|(i, &b)| if (CHECKSUM.0..CHECKSUM.0 + CHECKSUM.1).contains(&i) { b' ' as usize } else { b as usize }).sum();
+        if octal(field(header, CHECKSUM)) != Some(sum) {
+            return None;
+        }
+
+        let size = octal(field(header, SIZE))?;
+        let next = at + RECORD + size.div_ceil(RECORD) * RECORD;
+        let kind = match header[TYPE] {
+            REGULAR | OLD_REGULAR => InodeKind::File,
+            DIRECTORY => InodeKind::Directory,
+            _ => return Some((None, next)),
+        };
+
+        let (prefix, name) = (text(field(header, PREFIX))?, text(field(header, NAME))?);
+        let path = Path::new(prefix).and_then(|prefix| prefix.join(name)).ok()?;
+        let (uid, gid) = (octal(field(header, UID))? as u32, octal(field(header, GID))? as u32);
+        let mode = (octal(field(header, MODE))? & 0o7777) as u16;
+        Some((Some(Entry { path, kind, at, size, uid, gid, mode }), next))
+    }
+
+    // Calls `f` on every file and directory until it returns something
+    fn find_map<R>(&self, mut f: impl FnMut(&Entry) -> Option<R>) -> Option<R> {
+        let mut at = 0;
+        while let Some((entry, next)) = self.entry_at(at) {
+            if let Some(found) = entry.as_ref().and_then(&mut f) {
+                return Some(found);
+            }
+            at = next;
+        }
+        None
+    }
+
+    // Number of files and directories, or None if the archive does not start with a header
+    fn count(&self) -> Option<usize> {
+        self.entry_at(0)?;
+        let mut count = 0;
+        self.find_map(|_| {
+            count += 1;
+            None::<()>
+        });
+        Some(count)
+    }
+
+    // What `path` names: an entry, or a directory some entry lies under
+    fn lookup(&self, path: &Path) -> Option<Entry> {
+        // Directories without an entry of their own are root's, and open to all
+        let directory = Entry { path: *path, kind: InodeKind::Directory, at: 0, size: 0, uid: ROOT_ID, gid: ROOT_ID, mode: 0o755 };
+        if *path == Path::ROOT {
+            return Some(directory);
+        }
+
+        self.find_map(|entry| match entry.path.relative_to(path) {
+            Some(rest) if rest == Path::ROOT => Some(*entry),
+            Some(_) => Some(directory),
+            None => None,
+        })
+    }
+
+    // The contents of the entry with its header at `at`
+    fn contents(&self, at: usize) -> Option<&'static [u8]> {
+        let (entry, _) = self.entry_at(at)?;
+        self.data.get(at + RECORD..at + RECORD + entry?.size)
+    }
+
+    /// The contents of the regular file at `path`.
+    pub fn file(&self, path: &str) -> Option<&'static [u8]> {
+        let entry = self.lookup(&Path::new(path).ok()?)?;
+        if entry.kind != InodeKind::File {
+            return None;
+        }
+        self.contents(entry.at)
+    }
+}
+
+impl FileSystem for Initrd {
+    fn open(&self, _process: &Process, path: &Path, flags: u32) -> KResult<Inode> {
+        let entry = archive().ok_or(Errno::ENODEV)?.lookup(path).ok_or(Errno::ENOENT)?;
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(if entry.kind == InodeKind::Directory { Errno::EISDIR } else { Errno::EROFS });
+        }
+
+        Ok(Inode { ino: entry.at as u64, kind: entry.kind, size: entry.size as u64, uid: entry.uid, gid: entry.gid, mode: entry.mode })
+    }
+
+    fn read(&self, ino: u64, buf: &mut [u8], offset: u64) -> KResult<usize> {
+        let data = archive().and_then(|archive| archive.contents(ino as usize)).ok_or(Errno::EIO)?;
+        let rest = data.get(offset.min(data.len() as u64) as usize..).unwrap_or(&[]);
+
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Room for four entries and the zero record that ends the archive
+    static mut IMAGE: [u8; 8 * RECORD] = [0; 8 * RECORD];
+
+    fn image() -> &'static mut [u8; 8 * RECORD] {
+        unsafe {
+            let image = &raw mut IMAGE;
+            &mut *image
+        }
+    }
+
+    // Writes a header and its data at record `record`, returning the record after it
+    fn add(record: usize, name: &str, kind: u8, data: &[u8]) -> usize {
+        add_owned(record, name, kind, data, (0, 0, 0o644))
+    }
+
+    // As `add`, with the owner, group and mode given
+    fn add_owned(record: usize, name: &str, kind: u8, data: &[u8], (uid, gid, mode): (usize, usize, usize)) -> usize {
+        let at = record * RECORD;
+        let header = &mut image()[at..at + RECORD];
+        header.fill(0);
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[MODE.0..MODE.0 + 8].copy_from_slice(format_octal::<8>(mode).as_slice());
+        header[UID.0..UID.0 + 8].copy_from_slice(format_octal::<8>(uid).as_slice());
+        header[GID.0..GID.0 + 8].copy_from_slice(format_octal::<8>(gid).as_slice());
+        header[SIZE.0..SIZE.0 + 12].copy_from_slice(format_octal::<12>(data.len()).as_slice());
+        header[TYPE] = kind;
+        header[MAGIC.0..MAGIC.0 + 6].copy_from_slice(b"ustar\0");
+        header[CHECKSUM.0..CHECKSUM.0 + 8].fill(b' ');
+
+        let sum: usize = header.iter().map(|&b| b as usize).sum();
+        header[CHECKSUM.0..CHECKSUM.0 + 7].copy_from_slice(format_octal::<7>(sum).as_slice());
+
+        image()[at + RECORD..at + RECORD + data.len()].copy_from_slice(data);
+        record + 1 + data.len().div_ceil(RECORD)
+    }
+
+    // `value` in octal, zero-padded and NUL-terminated
+    fn format_octal<const N: usize>(mut value: usize) -> [u8; N] {
+        let mut out = [b'0'; N];
+        out[N - 1] = 0;
+        for digit in out[..N - 1].iter_mut().rev() {
+            *digit = b'0' + (value % 8) as u8;
+            value /= 8;
+        }
+        out
+    }
+
+    fn archive() -> Archive {
+        image().fill(0);
+        let next = add(0, "./bin/", DIRECTORY, b"");
+        let next = add(next, "./bin/init", REGULAR, b"\x7fELF");
+        let next = add(next, "./dev/console", b'3', b"");
+        add_owned(next, "./etc/motd", REGULAR, &[b'm'; 600], (1000, 100, 0o600));
+        Archive::new(image())
+    }
+
+    fn path(path: &str) -> Path {
+        Path::new(path).unwrap()
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn entries_are_found_by_path() {
+            let archive = archive();
+            assert_eq!(archive.count(), Some(3));
+
+            assert_eq!(archive.file("/bin/init"), Some(&b"\x7fELF"[..]));
+            assert_eq!(archive.file("etc/motd").map(<[u8]>::len), Some(600));
+            assert_eq!(archive.lookup(&path("/bin")).map(|entry| entry.kind), Some(InodeKind::Directory));
+            assert_eq!(archive.lookup(&path("/etc")).map(|entry| entry.kind), Some(InodeKind::Directory));
+            assert_eq!(archive.lookup(&path("/dev/console")), None);
+            assert_eq!(archive.lookup(&path("/bin/ini")), None);
+            assert_eq!(archive.file("/bin"), None);
+        }
+
+        fn bad_headers_end_the_archive() {
+            let archive = archive();
+            assert!(archive.file("/etc/motd").is_some());
+
+            // A corrupt header hides everything from it on
+            image()[3 * RECORD + 1] ^= 1;
+            assert_eq!(archive.count(), Some(2));
+            assert_eq!(archive.file("/etc/motd"), None);
+
+            image().fill(0);
+            assert_eq!(archive.count(), None);
+        }
+
+        fn owners_and_modes_come_from_the_headers() {
+            let archive = archive();
+            let motd = archive.lookup(&path("/etc/motd")).unwrap();
+            assert_eq!((motd.uid, motd.gid, motd.mode), (1000, 100, 0o600));
+            let init = archive.lookup(&path("/bin/init")).unwrap();
+            assert_eq!((init.uid, init.gid, init.mode), (0, 0, 0o644));
+
+            // A directory only implied by its files
+            let etc = archive.lookup(&path("/etc")).unwrap();
+            assert_eq!((etc.uid, etc.mode), (ROOT_ID, 0o755));
+        }
+
+        fn files_read_at_any_offset() {
+            let archive = archive();
+            let entry = archive.lookup(&path("/etc/motd")).unwrap();
+            let data = archive.contents(entry.at).unwrap();
+            assert_eq!(data.len(), 600);
+            assert!(data.iter().all(|&b| b == b'm'));
+            assert_eq!(archive.contents(entry.at + 1), None);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! IPC: kernel-managed message queues.
+//!
+//! A queue is created with a capacity of up to [`MAX_CAPACITY`] messages and named by the ID
+//! [`mq_create`] returns, which any process may send to and receive from; only its creator
+//! may destroy it, and it goes when the creator exits. Messages are up to
+//! [`MAX_MESSAGE_SIZE`] bytes and are received whole, in the order they were sent.
+//!
+//! Sending to a full queue or receiving from an empty one blocks the caller on
+//! [`WaitTarget::MessageQueue`] until a receive or send changes that, unless it asked not to
+//! ([`MQ_NONBLOCK`]). Every change to a queue wakes all its waiters, which try again; a queue
+//! destroyed under them fails their call with `EIDRM`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::percpu;
+use crate::os::process::WaitTarget;
+use crate::os::sched;
+use crate::os::sync::SpinLock;
+use crate::os::uaccess;
+
+/// Most queues that can exist at once.
+pub const MAX_QUEUES: usize = 32;
+
+/// Most messages a queue can hold.
+pub const MAX_CAPACITY: usize = 16;
+
+/// Longest message, in bytes.
+pub const MAX_MESSAGE_SIZE: usize = 256;
+
+/// `mq_send`/`mq_recv` flag: fail with `EAGAIN` rather than block.
+pub const MQ_NONBLOCK: u32 = 0x800;
+
+#[derive(Clone, Copy)]
+struct Message {
+    len: usize,
+    data: [u8; MAX_MESSAGE_SIZE],
+}
+
+struct Queue {
+    id: u32,
+    owner: u64,
+    capacity: usize,
+
+    // Ring of `len` messages from `head`
+    head: usize,
+    len: usize,
+    messages: [Message; MAX_CAPACITY],
+}
+
+static QUEUES: SpinLock<[Option<Queue>; MAX_QUEUES]> = SpinLock::new([const { None }; MAX_QUEUES]);
+
+// Next queue ID; IDs are not reused, so a stale one never names another queue
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+// Runs `f` on queue `id` with the table locked. Fails with `EINVAL` if there is no such queue.
+fn with_queue<R>(id: u32, f: impl FnOnce(&mut Queue) -> R) -> KResult<R> {
+    QUEUES.with(|queues| queues.iter_mut().flatten().find(|queue| queue.id == id).map(f).ok_or(Errno::EINVAL))
+}
+
+// Wakes every process waiting on queue `id`
+fn wake_all(id: u32) {
+    sched::wake(WaitTarget::MessageQueue(id));
+}
+
+// Runs `attempt` on queue `id` until it gets somewhere, blocking the caller between attempts
+// unless `nonblock`, then wakes the queue's other waiters. `attempt` returns None when the
+// queue is not ready for it.
+fn wait_on<R>(id: u32, nonblock: bool, mut attempt: impl FnMut(&mut Queue) -> Option<KResult<R>>) -> KResult<R> {
+    let pid = percpu::current_pid();
+    let mut waited = false;
+
+    loop {
+        let mut outcome = None;
+        let mut ready = || {
+            let result = with_queue(id, &mut attempt).and_then(Option::transpose);
+            let done = !matches!(result, Ok(None));
+            outcome = Some(result);
+            done
+        };
+
+        if !ready() {
+            if nonblock {
+                return Err(Errno::EAGAIN);
+            }
+            if sched::is_idle(pid) {
+                core::hint::spin_loop();
+                continue;
+            }
+            if !sched::block_current_unless(WaitTarget::MessageQueue(id), &mut ready) {
+                waited = true;
+                continue;
+            }
+        }
+
+        return match outcome {
+            Some(Ok(Some(result))) => {
+                wake_all(id);
+                Ok(result)
+            }
+            Some(Err(Errno::EINVAL)) if waited => Err(Errno::EIDRM),
+            Some(Err(err)) => Err(err),
+            _ => unreachable!("ipc: a queue attempt got nowhere"),
+        };
+    }
+}
+
+/// Creates a queue for up to `capacity` messages, owned by process `owner`, and returns its
+/// ID. Fails with `EINVAL` for a capacity of 0 or above [`MAX_CAPACITY`] and `ENOSPC` when
+/// [`MAX_QUEUES`] exist already.
+pub fn mq_create(owner: u64, capacity: usize) -> KResult<u32> {
+    if capacity == 0 || capacity > MAX_CAPACITY {
+        return Err(Errno::EINVAL);
+    }
+
+    QUEUES.with(|queues| {
+        let slot = queues.iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOSPC)?;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        let empty = Message { len: 0, data: [0; MAX_MESSAGE_SIZE] };
+        *slot = Some(Queue { id, owner, capacity, head: 0, len: 0, messages: [empty; MAX_CAPACITY] });
+        Ok(id)
+    })
+}
+
+/// Destroys queue `id` for process `caller`, discarding its messages; its waiters fail with
+/// `EIDRM`. Fails with `EINVAL` if there is no such queue and `EPERM` if `caller` did not
+/// create it.
+pub fn mq_destroy(caller: u64, id: u32) -> KResult<()> {
+    QUEUES.with(|queues| {
+        let slot = queues.iter_mut().find(|slot| slot.as_ref().is_some_and(|queue| queue.id == id)).ok_or(Errno::EINVAL)?;
+        if slot.as_ref().is_some_and(|queue| queue.owner != caller) {
+            return Err(Errno::EPERM);
+        }
+
+        *slot = None;
+        Ok(())
+    })?;
+
+    wake_all(id);
+    Ok(())
+}
+
+/// Appends `message` to queue `id`, blocking while it is full unless `flags` has
+/// [`MQ_NONBLOCK`]. Fails with `EMSGSIZE` for a message over [`MAX_MESSAGE_SIZE`], `EAGAIN`
+/// for a full queue not waited on, `EINVAL` for unknown flags or if there is no such queue and
+/// `EIDRM` if it was destroyed while waiting.
+pub fn mq_send(id: u32, message: &[u8], flags: u32) -> KResult<()> {
+    if flags & !MQ_NONBLOCK != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    wait_on(id, flags & MQ_NONBLOCK != 0, |queue| {
+        if queue.len == queue.capacity {
+            return None;
+        }
+
+        let slot = &mut queue.messages[(queue.head + queue.len) % queue.capacity];
+        slot.len = message.len();
+        slot.data[..message.len()].copy_from_slice(message);
+        queue.len += 1;
+        Some(Ok(()))
+    })
+}
+
+/// Takes the oldest message off queue `id` into `buf` and returns its length, blocking while
+/// the queue is empty unless `flags` has [`MQ_NONBLOCK`]. Fails with `EMSGSIZE`, leaving the
+/// message queued, if it does not fit in `buf`, and otherwise as [`mq_send`] does.
+pub fn mq_recv(id: u32, buf: &mut [u8], flags: u32) -> KResult<usize> {
+    if flags & !MQ_NONBLOCK != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    wait_on(id, flags & MQ_NONBLOCK != 0, |queue| {
+        if queue.len == 0 {
+            return None;
+        }
+
+        let message = &queue.messages[queue.head];
+        if message.len > buf.len() {
+            return Some(Err(Errno::EMSGSIZE));
+        }
+
+        buf[..message.len].copy_from_slice(&message.data[..message.len]);
+        let len = message.len;
+        queue.head = (queue.head + 1) % queue.capacity;
+        queue.len -= 1;
+        Some(Ok(len))
+    })
+}
+
+/// Teardown hook: destroys the queues process `pid` created.
+pub fn release(pid: u64) {
+    let mut owned = [0u32; MAX_QUEUES];
+    let mut count = 0;
+    QUEUES.with(|queues| {
+        for slot in queues.iter_mut().filter(|slot| slot.as_ref().is_some_and(|queue| queue.owner == pid)) {
+            owned[count] = slot.take().map_or(0, |queue| queue.id);
+            count += 1;
+        }
+    });
+
+    for &id in &owned[..count] {
+        wake_all(id);
+    }
+}
+
+/// `mq_send(id, buf, len, flags)`: sends the `len` bytes at `buf`.
+pub fn sys_mq_send(id: u32, buf: usize, len: usize, flags: u32) -> KResult<()> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let mut message = [0u8; MAX_MESSAGE_SIZE];
+    uaccess::copy_from_user(&mut message[..len], buf)?;
+    mq_send(id, &message[..len], flags)
+}
+
+/// `mq_recv(id, buf, len, flags)`: receives a message into the `len` bytes at `buf` and returns
+/// its length. Fails with `EFAULT`, before taking a message, if `buf` is not user memory.
+pub fn sys_mq_recv(id: u32, buf: usize, len: usize, flags: u32) -> KResult<usize> {
+    if !uaccess::is_user_range(buf, len) {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut message = [0u8; MAX_MESSAGE_SIZE];
+    let received = mq_recv(id, &mut message[..len.min(MAX_MESSAGE_SIZE)], flags)?;
+    uaccess::copy_to_user(buf, &message[..received])?;
+    Ok(received)
+}
+
+pub mod ktests {
+    use core::sync::atomic::AtomicI64;
+
+    use super::*;
+
+    use crate::os::kthread;
+    use crate::os::process::ProcessState;
+    use crate::os::ptable;
+
+    static QUEUE: AtomicU32 = AtomicU32::new(0);
+    static RECEIVED: AtomicI64 = AtomicI64::new(0);
+
+    // Blocks on the shared queue until the test sends it a message
+    fn receiver() {
+        let mut buf = [0u8; 8];
+        let result = mq_recv(QUEUE.load(Ordering::Relaxed), &mut buf, 0);
+        let received = match result {
+            Ok(len) => buf[..len].iter().map(|&byte| byte as i64).sum(),
+            Err(err) => err.as_syscall_return(),
+        };
+        RECEIVED.store(received, Ordering::Relaxed);
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn messages_come_out_in_order() {
+            let id = mq_create(9900, 2).unwrap();
+            assert_eq!(mq_send(id, b"one", MQ_NONBLOCK), Ok(()));
+            assert_eq!(mq_send(id, b"two!", MQ_NONBLOCK), Ok(()));
+            assert_eq!(mq_send(id, b"three", MQ_NONBLOCK), Err(Errno::EAGAIN));
+
+            let mut buf = [0u8; 8];
+            assert_eq!(mq_recv(id, &mut buf[..3], MQ_NONBLOCK), Ok(3));
+            assert_eq!(&buf[..3], b"one");
+            assert_eq!(mq_recv(id, &mut buf[..3], MQ_NONBLOCK), Err(Errno::EMSGSIZE));
+            assert_eq!(mq_recv(id, &mut buf, MQ_NONBLOCK), Ok(4));
+            assert_eq!(&buf[..4], b"two!");
+            assert_eq!(mq_recv(id, &mut buf, MQ_NONBLOCK), Err(Errno::EAGAIN));
+
+            assert_eq!(mq_send(id, &[0; MAX_MESSAGE_SIZE + 1], 0), Err(Errno::EMSGSIZE));
+            assert_eq!(mq_send(id, b"x", 1), Err(Errno::EINVAL));
+            assert_eq!(mq_destroy(9901, id), Err(Errno::EPERM));
+            assert_eq!(mq_destroy(9900, id), Ok(()));
+            assert_eq!(mq_send(id, b"x", 0), Err(Errno::EINVAL));
+        }
+
+        fn queues_are_bounded_and_released() {
+            assert_eq!(mq_create(9900, 0), Err(Errno::EINVAL));
+            assert_eq!(mq_create(9900, MAX_CAPACITY + 1), Err(Errno::EINVAL));
+
+            let ids = [mq_create(9902, 1).unwrap(), mq_create(9902, 1).unwrap()];
+            release(9902);
+            for id in ids {
+                assert_eq!(mq_send(id, b"x", MQ_NONBLOCK), Err(Errno::EINVAL));
+            }
+        }
+
+        fn receivers_block_until_a_send() {
+            let id = mq_create(9903, 1).unwrap();
+            QUEUE.store(id, Ordering::Relaxed);
+            RECEIVED.store(0, Ordering::Relaxed);
+
+            let pid = kthread::spawn_kthread(receiver, 0).unwrap();
+            let state = |pid| ptable::with_process(pid, |process| (process.state, process.waiting_on));
+            while state(pid) != Some((ProcessState::Blocked, Some(WaitTarget::MessageQueue(id)))) {
+                sched::yield_now();
+            }
+
+            assert_eq!(mq_send(id, &[1, 2, 3], 0), Ok(()));
+            while state(pid).is_some() {
+                sched::yield_now();
+            }
+            assert_eq!(RECEIVED.load(Ordering::Relaxed), 6);
+            assert_eq!(mq_destroy(9903, id), Ok(()));
+        }
+    }
+}
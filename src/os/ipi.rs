@@ -0,0 +1,192 @@
+//! Inter-processor interrupts.
+//!
+//! Two kinds are used: a reschedule IPI, and a function-call IPI, which runs a function on a
+//! set of CPUs and waits until all of them are done (the basis of TLB shootdown). The
+//! reschedule IPI carries two requests, which the sender records first: to re-run the
+//! scheduler on the way out of the interrupt, and to take a scheduler tick (see
+//! [`send_tick`]). There is a single
+//! call slot, so concurrent callers queue on a lock; CPUs spinning on it keep servicing calls
+//! aimed at them, so two CPUs calling each other with interrupts masked cannot deadlock.
+//!
+//! A panicking CPU also stops the others with the function-call IPI, through [`stop_others`]:
+//! that cannot wait for anyone, since the CPU holding the call lock may be the one stopped.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::os::arch::{self, Arch, Current};
+use crate::os::percpu::{self, PerCpu};
+use crate::os::sched;
+
+/// Vector (or SGI number) of the reschedule IPI.
+pub const RESCHEDULE_VECTOR: u32 = Current::RESCHEDULE_IPI;
+
+/// Vector (or SGI number) of the function-call IPI.
+pub const CALL_FUNCTION_VECTOR: u32 = Current::CALL_FUNCTION_IPI;
+
+// Set by a reschedule IPI, consumed by the scheduler
+static NEED_RESCHED: PerCpu<bool> = PerCpu::new(false);
+
+// CPUs another CPU asked to reschedule, and CPUs owed a scheduler tick
+static RESCHEDULE_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static TICK_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+// The call in flight: function, argument and the CPUs that have not run it yet
+static CALL_LOCK: AtomicBool = AtomicBool::new(false);
+static CALL_FUNC: AtomicUsize = AtomicUsize::new(0);
+static CALL_ARG: AtomicUsize = AtomicUsize::new(0);
+static CALL_PENDING: AtomicU64 = AtomicU64::new(0);
+
+// Set once by a panicking CPU; every other CPU halts on seeing it
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Asks CPU `cpu` to reschedule at its next opportunity.
+pub fn send_reschedule(cpu: usize) {
+    if cpu == percpu::cpu_id() {
+        NEED_RESCHED.with(|flag| *flag = true);
+        return;
+    }
+
+    RESCHEDULE_REQUESTS.fetch_or(1 << cpu, Ordering::Release);
+    Current::send_ipi(percpu::hardware_id(cpu), RESCHEDULE_VECTOR);
+}
+
+/// Passes a scheduler tick on to the online CPUs in `cpus` (bit n = CPU n) other than the
+/// caller, which charge it to whatever they are running.
+pub fn send_tick(cpus: u64) {
+    let remote = cpus & percpu::online_mask() & !(1 << percpu::cpu_id());
+    if remote == 0 {
+        return;
+    }
+
+    TICK_REQUESTS.fetch_or(remote, Ordering::Release);
+    for cpu in (0..percpu::MAX_CPUS).filter(|cpu| remote & (1 << cpu) != 0) {
+        Current::send_ipi(percpu::hardware_id(cpu), RESCHEDULE_VECTOR);
+    }
+}
+
+/// Returns whether a reschedule was requested on this CPU, clearing the request.
+pub fn take_need_resched() -> bool {
+    NEED_RESCHED.with(core::mem::take)
+}
+
+/// Handler for [`RESCHEDULE_VECTOR`]: takes the tick and the reschedule asked of this CPU.
+/// An IPI whose requests an earlier one already took does nothing.
+pub fn handle_reschedule() {
+    let bit = 1 << percpu::cpu_id();
+
+    if TICK_REQUESTS.fetch_and(!bit, Ordering::AcqRel) & bit != 0 {
+        sched::tick();
+    }
+    if RESCHEDULE_REQUESTS.fetch_and(!bit, Ordering::AcqRel) & bit != 0 {
+        NEED_RESCHED.with(|flag| *flag = true);
+    }
+    Current::end_of_interrupt(RESCHEDULE_VECTOR);
+}
+
+/// Runs `func(arg)` on every online CPU in `cpus` (bit n = CPU n), including the caller if its
+/// bit is set, and returns once all of them have finished. `func` runs with interrupts masked,
+/// so it must be short and must not block; `arg` only needs to live until the call returns.
+pub fn call_on(cpus: u64, func: fn(usize), arg: usize) {
+    let this = percpu::cpu_id();
+    let remote = cpus & percpu::online_mask() & !(1 << this);
+
+    if remote != 0 {
+        while CALL_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            poll();
+            core::hint::spin_loop();
+        }
+
+        CALL_FUNC.store(func as usize, Ordering::Relaxed);
+        CALL_ARG.store(arg, Ordering::Relaxed);
+        CALL_PENDING.store(remote, Ordering::Release);
+
+        if remote == percpu::online_mask() & !(1 << this) {
+            Current::send_ipi_all_but_self(CALL_FUNCTION_VECTOR);
+        } else {
+            for cpu in (0..percpu::MAX_CPUS).filter(|cpu| remote & (1 << cpu) != 0) {
+                Current::send_ipi(percpu::hardware_id(cpu), CALL_FUNCTION_VECTOR);
+            }
+        }
+    }
+
+    if cpus & (1 << this) != 0 {
+        arch::without_interrupts(|| func(arg));
+    }
+
+    if remote != 0 {
+        while CALL_PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+
+        CALL_LOCK.store(false, Ordering::Release);
+    }
+}
+
+/// Runs `func(arg)` on every online CPU, the caller included.
+pub fn call_on_all(func: fn(usize), arg: usize) {
+    call_on(percpu::online_mask(), func, arg);
+}
+
+/// Halts every other online CPU, without waiting for them to stop. CPUs with interrupts
+/// masked only stop once they unmask them or spin on the call lock.
+pub fn stop_others() {
+    STOP.store(true, Ordering::Release);
+
+    if percpu::online_mask() & !(1 << percpu::cpu_id()) != 0 {
+        Current::send_ipi_all_but_self(CALL_FUNCTION_VECTOR);
+    }
+}
+
+/// Handler for [`CALL_FUNCTION_VECTOR`].
+pub fn handle_call_function() {
+    poll();
+    Current::end_of_interrupt(CALL_FUNCTION_VECTOR);
+}
+
+/// Runs the call in flight if this CPU is one of its targets, or halts if told to stop. For
+/// code spinning with interrupts masked on something a caller may hold.
+pub fn poll() {
+    if STOP.load(Ordering::Acquire) {
+        Current::halt();
+    }
+
+    let bit = 1 << percpu::cpu_id();
+
+    if CALL_PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+
+    // Safety: CALL_FUNC holds a `fn(usize)` while this CPU's pending bit is set
+    let func: fn(usize) = unsafe { core::mem::transmute(CALL_FUNC.load(Ordering::Relaxed)) };
+    func(CALL_ARG.load(Ordering::Relaxed));
+
+    CALL_PENDING.fetch_and(!bit, Ordering::Release);
+}
+
+pub mod ktests {
+    use super::*;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(amount: usize) {
+        CALLS.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn call_on_self_runs_synchronously() {
+            CALLS.store(0, Ordering::Relaxed);
+
+            call_on(1 << percpu::cpu_id(), count, 3);
+
+            assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+        }
+
+        fn call_on_offline_cpus_is_a_no_op() {
+            CALLS.store(0, Ordering::Relaxed);
+
+            call_on(!percpu::online_mask(), count, 1);
+
+            assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        }
+    }
+}
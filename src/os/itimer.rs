@@ -0,0 +1,392 @@
+//! Per-process interval timers: `setitimer`/`getitimer`, `alarm` and the POSIX `timer_*` calls.
+//!
+//! Each armed timer is a high-resolution [`hrtimer`] whose callback raises a signal in the
+//! owning process: `SIGALRM` for `ITIMER_REAL` and `alarm`, whatever `timer_create` asked for
+//! otherwise. The process keeps the [`HrTimerId`]s in its PCB and cancels them through
+//! [`release`] when it exits. Only real (wall-clock) time is measured; the CPU-time itimers are not supported.
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::hrtimer::{self, HrTimerId};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::timekeeping::{self, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, NS_PER_SEC, Timespec, Timeval};
+use crate::os::uaccess;
+
+/// Timers per process that `timer_create` can make.
+pub const MAX_POSIX_TIMERS: usize = 8;
+
+/// `setitimer` timer: counts down in real time and delivers `SIGALRM`.
+pub const ITIMER_REAL: u32 = 0;
+
+/// `sigev_notify` values: raise a signal, or nothing (the timer is only polled).
+pub const SIGEV_SIGNAL: i32 = 0;
+pub const SIGEV_NONE: i32 = 1;
+
+/// `timer_settime` flag: the expiration is an absolute time on the timer's clock.
+pub const TIMER_ABSTIME: u32 = 1;
+
+/// Signal raised by `ITIMER_REAL`, `alarm` and timers created without a `sigevent`.
+const SIGALRM: u32 = 14;
+
+// Signals fit the 32 entries of the handler table; 0 is not a signal
+const MAX_SIGNAL: i32 = 31;
+
+// Timer index in callback data standing for ITIMER_REAL
+const REAL: usize = 0xff;
+
+/// `struct itimerval`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Itimerval {
+    pub interval: Timeval,
+    pub value: Timeval,
+}
+
+/// `struct itimerspec`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Itimerspec {
+    pub interval: Timespec,
+    pub value: Timespec,
+}
+
+/// `struct sigevent`, of which only the value, signal and notification type are used.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Sigevent {
+    pub value: u64,
+    pub signo: i32,
+    pub notify: i32,
+    pub reserved: [u64; 6],
+}
+
+/// A timer made with `timer_create`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PosixTimer {
+    pub clock: u32,
+
+    /// Signal raised on expiry, `None` for `SIGEV_NONE`.
+    pub signal: Option<u32>,
+
+    /// The high-resolution timer while armed.
+    pub armed: Option<HrTimerId>,
+
+    /// Expirations that found the signal still pending, as `timer_getoverrun` reports them.
+    pub overrun: u64,
+}
+
+fn encode(pid: u64, index: usize) -> usize {
+    ((pid as usize) << 8) | index
+}
+
+fn decode(data: usize) -> (u64, usize) {
+    ((data >> 8) as u64, data & 0xff)
+}
+
+// Timer callback: raises the timer's signal in its process, or counts an overrun if it is
+// still pending, and forgets one-shot timers that have now expired
+fn expire(id: HrTimerId, data: usize, expirations: u64) {
+    let (pid, index) = decode(data);
+    let done = hrtimer::remaining(id).is_none();
+
+    ptable::with_process(pid, |process| {
+        if index == REAL {
+            if process.real_timer == Some(id) {
+                process.signal_bitmap |= 1 << SIGALRM;
+                if done {
+                    process.real_timer = None;
+                }
+            }
+            return;
+        }
+
+        let Some(posix) = process.posix_timers.get_mut(index).and_then(Option::as_mut) else {
+            return;
+        };
+        if posix.armed != Some(id) {
+            return;
+        }
+
+        if let Some(signal) = posix.signal {
+            let pending = process.signal_bitmap & (1 << signal) != 0;
+            posix.overrun = if pending { posix.overrun + expirations } else { expirations - 1 };
+            process.signal_bitmap |= 1 << signal;
+        }
+        if done {
+            posix.armed = None;
+        }
+    });
+}
+
+// Time left on an armed timer and its interval, zero when disarmed
+fn current(armed: Option<HrTimerId>) -> (u64, u64) {
+    armed.and_then(hrtimer::remaining).unwrap_or((0, 0))
+}
+
+// Replaces `*slot`'s timer with one expiring in `value_ns` and then every `interval_ns`,
+// or with none for a zero `value_ns`
+fn rearm(slot: &mut Option<HrTimerId>, value_ns: u64, interval_ns: u64, data: usize) -> KResult<()> {
+    if let Some(old) = slot.take() {
+        hrtimer::cancel(old);
+    }
+    if value_ns != 0 {
+        *slot = Some(hrtimer::add(value_ns, interval_ns, expire, data)?);
+    }
+    Ok(())
+}
+
+// =========================================================================
+// setitimer / getitimer / alarm
+// =========================================================================
+
+fn itimerval(armed: Option<HrTimerId>) -> Itimerval {
+    let (value, interval) = current(armed);
+    Itimerval { interval: Timeval::from_ns(interval), value: Timeval::from_ns(value) }
+}
+
+/// `getitimer(which, curr)`: copies the time left on the caller `caller`'s `which` and its
+/// interval to `curr`. Fails with `EINVAL` for any timer but `ITIMER_REAL`.
+pub fn sys_getitimer(caller: u64, which: u32, curr: usize) -> KResult<()> {
+    if which != ITIMER_REAL {
+        return Err(Errno::EINVAL);
+    }
+    let armed = ptable::with_process(caller, |process| process.real_timer).ok_or(Errno::ESRCH)?;
+    uaccess::write_user(curr, &itimerval(armed))
+}
+
+/// Arms (or, with a zero value, disarms) `process`'s `ITIMER_REAL`, returning its old setting.
+pub fn setitimer(process: &mut Process, new: Itimerval) -> KResult<Itimerval> {
+    let (value, interval) = (new.value.to_ns()?, new.interval.to_ns()?);
+    let old = itimerval(process.real_timer);
+
+    rearm(&mut process.real_timer, value, interval, encode(process.pid, REAL))?;
+    Ok(old)
+}
+
+/// `setitimer(which, new, old)`: sets the caller `caller`'s `which` from the `struct
+/// itimerval` at `new`, copying the previous setting to `old` unless it is null. Fails with
+/// `EINVAL` for any timer but `ITIMER_REAL` or a malformed time, and `EAGAIN` when no kernel
+/// timer is free.
+pub fn sys_setitimer(caller: u64, which: u32, new: usize, old: usize) -> KResult<()> {
+    if which != ITIMER_REAL {
+        return Err(Errno::EINVAL);
+    }
+
+    let new = unsafe { uaccess::read_user::<Itimerval>(new)? };
+    let previous = ptable::with_process(caller, |process| setitimer(process, new)).unwrap_or(Err(Errno::ESRCH))?;
+    if old != 0 {
+        uaccess::write_user(old, &previous)?;
+    }
+    Ok(())
+}
+
+/// `alarm(seconds)`: raises `SIGALRM` after `seconds`, replacing any earlier alarm (0 just
+/// cancels it). Returns the seconds that were left on the earlier alarm, rounded to the
+/// nearest second but at least 1 if one was pending.
+pub fn sys_alarm(process: &mut Process, seconds: u32) -> KResult<u64> {
+    let new = Itimerval { interval: Timeval::default(), value: Timeval { sec: seconds as i64, usec: 0 } };
+    let old = setitimer(process, new)?;
+
+    let left = old.value.to_ns()?;
+    Ok(match left {
+        0 => 0,
+        _ => (left.saturating_add(NS_PER_SEC / 2) / NS_PER_SEC).max(1),
+    })
+}
+
+// =========================================================================
+// timer_create / timer_settime / timer_gettime / timer_getoverrun / timer_delete
+// =========================================================================
+
+fn posix_timer(process: &mut Process, id: usize) -> KResult<&mut PosixTimer> {
+    process.posix_timers.get_mut(id).and_then(Option::as_mut).ok_or(Errno::EINVAL)
+}
+
+/// Creates a timer on `clock` raising the signal `event` asks for (`SIGALRM` with no `event`)
+/// and returns its ID. Fails with `EINVAL` for an unknown clock, notification type or signal
+/// and `EAGAIN` when the process has [`MAX_POSIX_TIMERS`] timers.
+pub fn timer_create(process: &mut Process, clock: u32, event: Option<Sigevent>) -> KResult<usize> {
+    if !matches!(clock, CLOCK_REALTIME | CLOCK_MONOTONIC | CLOCK_BOOTTIME) {
+        return Err(Errno::EINVAL);
+    }
+
+    let signal = match event {
+        None => Some(SIGALRM),
+        Some(event) if event.notify == SIGEV_NONE => None,
+        Some(event) if event.notify == SIGEV_SIGNAL && (1..=MAX_SIGNAL).contains(&event.signo) => Some(event.signo as u32),
+        Some(_) => return Err(Errno::EINVAL),
+    };
+
+    let id = process.posix_timers.iter().position(Option::is_none).ok_or(Errno::EAGAIN)?;
+    process.posix_timers[id] = Some(PosixTimer { clock, signal, armed: None, overrun: 0 });
+    Ok(id)
+}
+
+/// `timer_create(clock, sevp, timerid)`: creates a timer for the caller `caller` as
+/// [`timer_create`] does, reading the `struct sigevent` at `sevp` unless it is null, and
+/// stores its ID at `timerid`.
+pub fn sys_timer_create(caller: u64, clock: u32, sevp: usize, timerid: usize) -> KResult<()> {
+    let event = match sevp {
+        0 => None,
+        _ => Some(unsafe { uaccess::read_user::<Sigevent>(sevp)? }),
+    };
+
+    let id = ptable::with_process(caller, |process| timer_create(process, clock, event)).unwrap_or(Err(Errno::ESRCH))?;
+    if let Err(errno) = uaccess::write_user(timerid, &(id as i32)) {
+        ptable::with_process(caller, |process| process.posix_timers[id] = None);
+        return Err(errno);
+    }
+    Ok(())
+}
+
+fn itimerspec(armed: Option<HrTimerId>) -> Itimerspec {
+    let (value, interval) = current(armed);
+    Itimerspec { interval: Timespec::from_ns(interval), value: Timespec::from_ns(value) }
+}
+
+/// Arms timer `id` to expire at `new.value` -- relative to now, or an absolute time on the
+/// timer's clock with `TIMER_ABSTIME` -- and then every `new.interval`; a zero value
+/// disarms it. Returns the previous setting. An absolute time already past expires at once.
+pub fn timer_settime(process: &mut Process, id: usize, flags: u32, new: Itimerspec) -> KResult<Itimerspec> {
+    let (value, interval) = (new.value.to_ns()?, new.interval.to_ns()?);
+    let data = encode(process.pid, id);
+    let posix = posix_timer(process, id)?;
+    let old = itimerspec(posix.armed);
+
+    // Expiring "now" still needs a non-zero delay to arm the timer
+    let delay = match flags & TIMER_ABSTIME {
+        0 => value,
+        _ if value == 0 => 0,
+        _ => value.saturating_sub(timekeeping::now(posix.clock)?).max(1),
+    };
+
+    posix.overrun = 0;
+    rearm(&mut posix.armed, delay, interval, data)?;
+    Ok(old)
+}
+
+/// `timer_settime(timerid, flags, new, old)`: sets the caller `caller`'s timer `timerid` from
+/// the `struct itimerspec` at `new` as [`timer_settime`] does, copying the previous setting to
+/// `old` unless it is null. Fails with `EINVAL` for an unknown timer or malformed time.
+pub fn sys_timer_settime(caller: u64, timerid: usize, flags: u32, new: usize, old: usize) -> KResult<()> {
+    if flags & !TIMER_ABSTIME != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let new = unsafe { uaccess::read_user::<Itimerspec>(new)? };
+    let previous =
+        ptable::with_process(caller, |process| timer_settime(process, timerid, flags, new)).unwrap_or(Err(Errno::ESRCH))?;
+    if old != 0 {
+        uaccess::write_user(old, &previous)?;
+    }
+    Ok(())
+}
+
+/// `timer_gettime(timerid, curr)`: copies the time left on the caller `caller`'s timer
+/// `timerid` and its interval to `curr`. Fails with `EINVAL` for an unknown timer.
+pub fn sys_timer_gettime(caller: u64, timerid: usize, curr: usize) -> KResult<()> {
+    let armed = ptable::with_process(caller, |process| posix_timer(process, timerid).map(|posix| posix.armed))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    uaccess::write_user(curr, &itimerspec(armed))
+}
+
+/// `timer_getoverrun(timerid)`: the expirations of timer `timerid` that happened while its
+/// signal from an earlier one was still pending. Fails with `EINVAL` for an unknown timer.
+pub fn sys_timer_getoverrun(process: &mut Process, timerid: usize) -> KResult<usize> {
+    Ok(posix_timer(process, timerid)?.overrun.min(i32::MAX as u64) as usize)
+}
+
+/// `timer_delete(timerid)`: disarms and removes timer `timerid`. A signal it already raised
+/// stays pending. Fails with `EINVAL` for an unknown timer.
+pub fn sys_timer_delete(process: &mut Process, timerid: usize) -> KResult<()> {
+    if let Some(armed) = posix_timer(process, timerid)?.armed {
+        hrtimer::cancel(armed);
+    }
+    process.posix_timers[timerid] = None;
+    Ok(())
+}
+
+/// Cancels all of `process`'s timers. Called when it exits.
+pub fn release(process: &mut Process) {
+    if let Some(real) = process.real_timer.take() {
+        hrtimer::cancel(real);
+    }
+
+    for slot in process.posix_timers.iter_mut() {
+        if let Some(armed) = slot.take().and_then(|posix| posix.armed) {
+            hrtimer::cancel(armed);
+        }
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    const SECOND: Timespec = Timespec { sec: 1, nsec: 0 };
+
+    crate::os::ktest::kernel_test! {
+        fn callback_data_round_trips() {
+            assert_eq!(decode(encode(1234, 5)), (1234, 5));
+            assert_eq!(decode(encode(1, REAL)), (1, REAL));
+        }
+
+        fn alarms_replace_each_other() {
+            let mut process = Process::new(9500, 0, "itimer");
+
+            assert_eq!(sys_alarm(&mut process, 100), Ok(0));
+            assert!(process.real_timer.is_some());
+            assert_eq!(sys_alarm(&mut process, 0), Ok(100));
+            assert!(process.real_timer.is_none());
+
+            let new = Itimerval { interval: Timeval { sec: 1, usec: 0 }, value: Timeval { sec: 5, usec: 0 } };
+            let old = setitimer(&mut process, new).unwrap();
+            assert_eq!(old, Itimerval::default());
+            assert_eq!(itimerval(process.real_timer).interval, Timeval { sec: 1, usec: 0 });
+
+            release(&mut process);
+            assert!(process.real_timer.is_none());
+        }
+
+        fn posix_timers_are_created_armed_and_deleted() {
+            let mut process = Process::new(9501, 0, "itimer");
+            let event = |signo, notify| Some(Sigevent { value: 0, signo, notify, reserved: [0; 6] });
+
+            assert_eq!(timer_create(&mut process, 3, None), Err(Errno::EINVAL));
+            assert_eq!(timer_create(&mut process, CLOCK_MONOTONIC, event(0, SIGEV_SIGNAL)), Err(Errno::EINVAL));
+            assert_eq!(timer_create(&mut process, CLOCK_MONOTONIC, event(10, 2)), Err(Errno::EINVAL));
+
+            let id = timer_create(&mut process, CLOCK_MONOTONIC, event(10, SIGEV_SIGNAL)).unwrap();
+            assert_eq!(process.posix_timers[id].map(|t| t.signal), Some(Some(10)));
+
+            let armed = Itimerspec { interval: SECOND, value: Timespec { sec: 60, nsec: 0 } };
+            assert_eq!(timer_settime(&mut process, id, 0, armed), Ok(Itimerspec::default()));
+            assert_eq!(itimerspec(process.posix_timers[id].unwrap().armed).interval, SECOND);
+
+            // Disarming reports what was set
+            let old = timer_settime(&mut process, id, 0, Itimerspec::default()).unwrap();
+            assert_eq!(old.interval, SECOND);
+            assert!(old.value.sec <= 60 && old.value != Timespec::default());
+
+            assert_eq!(sys_timer_delete(&mut process, id), Ok(()));
+            assert_eq!(sys_timer_delete(&mut process, id), Err(Errno::EINVAL));
+            assert_eq!(timer_settime(&mut process, MAX_POSIX_TIMERS, 0, armed), Err(Errno::EINVAL));
+        }
+
+        fn expiry_raises_the_signal_and_counts_overruns() {
+            let mut process = Process::new(9502, 0, "itimer");
+            let id = timer_create(&mut process, CLOCK_MONOTONIC, None).unwrap();
+            let armed = Itimerspec { interval: SECOND, value: SECOND };
+            timer_settime(&mut process, id, 0, armed).unwrap();
+            let kernel = process.posix_timers[id].unwrap().armed.unwrap();
+
+            ptable::insert(process).unwrap();
+            expire(kernel, encode(9502, id), 1);
+            expire(kernel, encode(9502, id), 2);
+
+            let mut process = ptable::remove(9502).unwrap();
+            assert_ne!(process.signal_bitmap & (1 << SIGALRM), 0);
+            assert_eq!(sys_timer_getoverrun(&mut process, id), Ok(2));
+            release(&mut process);
+        }
+    }
+}
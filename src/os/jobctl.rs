@@ -0,0 +1,513 @@
+//! Job control: process groups, sessions, and stopping and continuing processes.
+//!
+//! A session is the set of processes a login shell and its jobs run in; each job is a process
+//! group, signalled as a unit. The shell puts every job in a group of its own with
+//! [`sys_setpgid`], hands the terminal to the one in the foreground (see [`tty`](super::tty))
+//! and moves jobs between foreground and background with `SIGCONT`.
+//!
+//! The stop signals (`SIGSTOP`, and `SIGTSTP`, `SIGTTIN` and `SIGTTOU` from the terminal)
+//! suspend a process unless it handles them, and `SIGCONT` resumes it in the state it was
+//! stopped in. Both take effect as the signal is sent, and each is reported to the parent,
+//! which sees it through `wait4` with `WUNTRACED` or `WCONTINUED`. A stopped process does not
+//! run however much it would otherwise be runnable: wakeups while it is stopped only change
+//! the state `SIGCONT` returns it to (see [`Process::wake`]).
+//!
+//! Process group and session IDs are root-namespace PIDs.
+
+use crate::os::cred;
+use crate::os::errno::{Errno, KResult};
+use crate::os::exit;
+use crate::os::lsm;
+use crate::os::pidns::{self, NsId, ROOT_NS};
+use crate::os::process::{Process, ProcessState};
+use crate::os::ptable::{self, MAX_PROCESSES};
+use crate::os::tty;
+
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGKILL: u32 = 9;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+pub const SIGTTIN: u32 = 21;
+pub const SIGTTOU: u32 = 22;
+
+// Signal numbers are bits of the 64-bit pending mask
+const NSIG: u32 = 64;
+
+/// `signal_handlers` value of an ignored signal; 0 is the default action.
+pub const SIG_IGN: usize = 1;
+
+/// A change in a child's job state for its parent to collect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobEvent {
+    /// Stopped by this signal.
+    Stopped(u32),
+
+    /// Continued by `SIGCONT`.
+    Continued,
+}
+
+fn is_stop_signal(signal: u32) -> bool {
+    matches!(signal, SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU)
+}
+
+fn stop_mask() -> u64 {
+    [SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU].iter().fold(0, |mask, signal| mask | 1 << signal)
+}
+
+/// Whether `process` ignores `signal`.
+pub fn ignores(process: &Process, signal: u32) -> bool {
+    process.signal_handlers.get(signal as usize) == Some(&SIG_IGN)
+}
+
+// Whether `signal` takes its default action in `process`; SIGSTOP always does
+fn is_default(process: &Process, signal: u32) -> bool {
+    signal == SIGSTOP || process.signal_handlers.get(signal as usize).is_none_or(|handler| *handler == 0)
+}
+
+fn stop(process: &mut Process, signal: u32) -> Option<JobEvent> {
+    if matches!(process.state, ProcessState::Suspended | ProcessState::Terminated) {
+        return None;
+    }
+
+    // A process stopped mid-timeslice goes back to the run queue
+    process.stopped_from = Some(match process.state {
+        ProcessState::Running => ProcessState::Ready,
+        state => state,
+    });
+    process.state = ProcessState::Suspended;
+    process.job_event = Some(JobEvent::Stopped(signal));
+    process.job_event
+}
+
+fn resume(process: &mut Process) -> Option<JobEvent> {
+    let state = process.stopped_from.take()?;
+    process.state = state;
+    process.job_event = Some(JobEvent::Continued);
+    process.job_event
+}
+
+/// Raises `signal` in `process` and applies its job control effects at once: `SIGCONT`
+/// continues a stopped process and discards its pending stop signals, and a stop signal
+/// discards a pending `SIGCONT` and, unless handled or ignored, stops the process. `SIGKILL`
+/// continues a stopped process so it can die. Returns the stop or continue this caused, for
+/// the parent.
+pub fn send(process: &mut Process, signal: u32) -> Option<JobEvent> {
+    if signal == 0 || signal >= NSIG || process.state == ProcessState::Terminated {
+        return None;
+    }
+
+    if signal == SIGCONT || signal == SIGKILL {
+        process.signal_bitmap &= !stop_mask();
+        let event = resume(process);
+        if signal == SIGKILL || !ignores(process, SIGCONT) {
+            process.signal_bitmap |= 1 << signal;
+        }
+        return event;
+    }
+
+    if is_stop_signal(signal) {
+        process.signal_bitmap &= !(1 << SIGCONT);
+        if ignores(process, signal) && signal != SIGSTOP {
+            return None;
+        }
+        if is_default(process, signal) {
+            return stop(process, signal);
+        }
+    }
+
+    process.signal_bitmap |= 1 << signal;
+    None
+}
+
+// Sends `signal` to the process `pid` in the table and tells its parent of any stop or continue
+fn send_to(pid: u64, signal: u32) {
+    if let Some((Some(_), ppid)) = ptable::with_process(pid, |p| (send(p, signal), p.ppid)) {
+        exit::notify_parent(pid, ppid);
+    }
+}
+
+/// Whether process group `pgid` is orphaned: no member has a parent outside the group but in
+/// the same session, which could continue it if it stopped. Terminal stop signals are not
+/// sent to orphaned groups.
+pub fn is_orphaned(pgid: u64) -> bool {
+    let mut members = [(0u64, 0u64); MAX_PROCESSES];
+    let mut count = 0;
+    ptable::for_each(|p| {
+        if p.pgid == pgid && p.state != ProcessState::Terminated {
+            members[count] = (p.ppid, p.sid);
+            count += 1;
+        }
+    });
+
+    !members[..count].iter().any(|&(ppid, sid)| {
+        ptable::with_process(ppid, |parent| parent.pgid != pgid && parent.sid == sid) == Some(true)
+    })
+}
+
+// Sends `signal` to every process in group `pgid` except `skip`, returning how many there were
+fn signal_group_except(pgid: u64, signal: u32, skip: u64) -> usize {
+    let mut members = [0u64; MAX_PROCESSES];
+    let mut count = 0;
+    ptable::for_each(|p| {
+        if p.pgid == pgid && p.pid != skip && p.state != ProcessState::Terminated {
+            members[count] = p.pid;
+            count += 1;
+        }
+    });
+
+    for &pid in &members[..count] {
+        send_to(pid, signal);
+    }
+    count
+}
+
+/// Sends `signal` to every process in group `pgid`. Fails with `ESRCH` if the group is empty.
+pub fn signal_group(pgid: u64, signal: u32) -> KResult<()> {
+    match signal_group_except(pgid, signal, 0) {
+        0 => Err(Errno::ESRCH),
+        _ => Ok(()),
+    }
+}
+
+/// Sends `signal` to process `pid` and the rest of its group, for signals a process raises on
+/// its own group, such as `SIGTTOU` from a background write.
+pub fn signal_own_group(pid: u64, signal: u32) {
+    let Some(pgid) = ptable::with_process(pid, |p| p.pgid) else {
+        return;
+    };
+    send_to(pid, signal);
+    signal_group_except(pgid, signal, pid);
+}
+
+// Whether `sender` may send `signal` to `target`: the kill() credential rules, except that
+// SIGCONT may be sent anywhere in the sender's session, then the security modules
+fn may_signal(sender: &Process, target: &Process, signal: u32) -> KResult<()> {
+    if signal != SIGCONT || sender.sid != target.sid {
+        cred::check_signal(&sender.cred, &target.cred)?;
+    }
+    lsm::check_kill(sender, target, signal)
+}
+
+// The kernel's ID for the process, group or session namespace `ns` calls `id`; `ESRCH` if it
+// sees none. Groups and sessions are known by their leader's PID, except in the root
+// namespace, whose IDs are the kernel's own, so a group there stays reachable after its
+// leader exits.
+fn to_global(ns: NsId, id: u64) -> KResult<u64> {
+    match ns {
+        ROOT_NS => Ok(id),
+        ns => pidns::to_global(ns, id).ok_or(Errno::ESRCH),
+    }
+}
+
+// The ID namespace `ns` knows the kernel's process, group or session `id` by, or 0 if it
+// cannot see its leader
+fn from_global(ns: NsId, id: u64) -> u64 {
+    match ns {
+        ROOT_NS => id,
+        ns => pidns::from_global(ns, id).unwrap_or(0),
+    }
+}
+
+/// `kill(pid, signal)`: sends `signal` from the caller `caller` to process `pid`, or with `pid`
+/// 0 to the caller's process group and with `pid` below -1 to group `-pid`, both as the
+/// caller's PID namespace numbers them; signal 0 only checks the target exists. Fails with `EINVAL` for a bad signal or `pid` -1 (broadcast is
+/// not supported), `ESRCH` if there is no such process or group and `EPERM` if the caller may
+/// not signal it (or, for a group, any member).
+pub fn sys_kill(caller: u64, pid: i64, signal: u32) -> KResult<()> {
+    if signal >= NSIG {
+        return Err(Errno::EINVAL);
+    }
+
+    // The targets are checked against a copy, the table being locked for each of them
+    let sender = ptable::with_process(caller, |process| process.stand_in()).ok_or(Errno::ESRCH)?;
+    let ns = sender.pid_links.namespace();
+
+    let target = match pid {
+        pid if pid > 0 => {
+            let pid = to_global(ns, pid as u64)?;
+            if pid != caller {
+                ptable::with_process(pid, |target| may_signal(&sender, target, signal)).ok_or(Errno::ESRCH)??;
+            }
+            if signal != 0 {
+                send_to(pid, signal);
+            }
+            return Ok(());
+        }
+        0 => sender.pgid,
+        -1 => return Err(Errno::EINVAL),
+        pid => to_global(ns, pid.unsigned_abs())?,
+    };
+
+    let mut members = [0u64; MAX_PROCESSES];
+    let mut count = 0;
+    let mut allowed = false;
+    ptable::for_each(|p| {
+        if p.pgid == target && p.pid != caller && p.state != ProcessState::Terminated {
+            members[count] = p.pid;
+            count += 1;
+            allowed |= may_signal(&sender, p, signal).is_ok();
+        }
+    });
+
+    let own = sender.pgid == target;
+    if count == 0 && !own {
+        return Err(Errno::ESRCH);
+    }
+    if !allowed && !own {
+        return Err(Errno::EPERM);
+    }
+    if signal == 0 {
+        return Ok(());
+    }
+
+    for &pid in &members[..count] {
+        let permitted = ptable::with_process(pid, |p| may_signal(&sender, p, signal).is_ok());
+        if permitted == Some(true) {
+            send_to(pid, signal);
+        }
+    }
+    if own {
+        send_to(caller, signal);
+    }
+    Ok(())
+}
+
+// Whether some process other than `skip` is in group `pgid` of session `sid`
+fn group_exists(pgid: u64, sid: u64, skip: u64) -> bool {
+    let mut found = false;
+    ptable::for_each(|p| found |= p.pid != skip && p.pgid == pgid && p.sid == sid);
+    found
+}
+
+/// `setpgid(pid, pgid)`: moves the caller `caller` or one of its children (`pid` 0 for the
+/// caller) into group `pgid`, a new one led by the process if `pgid` is 0 or its own PID, or
+/// else an existing group of the caller's session; both are numbered as in the caller's PID
+/// namespace. Fails with `ESRCH` if `pid` is neither,
+/// `EINVAL` for a negative `pgid`, and `EPERM` for a session leader, a child in another
+/// session or a group not in the session.
+pub fn sys_setpgid(caller: u64, pid: i64, pgid: i64) -> KResult<()> {
+    if pid < 0 || pgid < 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let caller_ids = ptable::with_process(caller, |process| (process.pid_links.namespace(), process.sid, process.pgid));
+    let (ns, caller_sid, caller_pgid) = caller_ids.ok_or(Errno::ESRCH)?;
+
+    let pid = if pid == 0 { caller } else { to_global(ns, pid as u64)? };
+    // A group the caller cannot see is not one of its session's
+    let pgid = if pgid == 0 { pid } else { to_global(ns, pgid as u64).map_err(|_| Errno::EPERM)? };
+    let sid = if pid == caller {
+        caller_sid
+    } else {
+        ptable::with_process(pid, |target| (target.ppid == caller).then_some(target.sid))
+            .flatten()
+            .ok_or(Errno::ESRCH)?
+    };
+
+    if pid == sid || sid != caller_sid {
+        return Err(Errno::EPERM);
+    }
+    if pgid != pid && pgid != caller_pgid && !group_exists(pgid, sid, pid) {
+        return Err(Errno::EPERM);
+    }
+
+    ptable::with_process(pid, |target| target.pgid = pgid).ok_or(Errno::ESRCH)
+}
+
+// The group or session `id` picks out of process `pid`, or of the caller `caller` for 0, both
+// as the caller's namespace numbers them
+fn id_of(caller: u64, pid: i64, id: impl FnOnce(&Process) -> u64) -> KResult<u64> {
+    let ns = ptable::with_process(caller, |process| process.pid_links.namespace()).ok_or(Errno::ESRCH)?;
+    let pid = match pid {
+        0 => caller,
+        pid if pid > 0 => to_global(ns, pid as u64)?,
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let id = ptable::with_process(pid, id).ok_or(Errno::ESRCH)?;
+    Ok(from_global(ns, id))
+}
+
+/// `getpgid(pid)`: the process group of process `pid`, or of the caller `caller` for 0, as the
+/// caller's PID namespace numbers them. Fails with `ESRCH` if there is no such process.
+pub fn sys_getpgid(caller: u64, pid: i64) -> KResult<u64> {
+    id_of(caller, pid, |p| p.pgid)
+}
+
+/// `getsid(pid)`: the session of process `pid`, or of the caller `caller` for 0, as the
+/// caller's PID namespace numbers them. Fails with `ESRCH` if there is no such process.
+pub fn sys_getsid(caller: u64, pid: i64) -> KResult<u64> {
+    id_of(caller, pid, |p| p.sid)
+}
+
+/// `setsid()`: starts a new session, without a controlling terminal, led by the caller `caller`
+/// in a new process group of its own. Returns the session ID, the caller's PID in its own
+/// namespace. Fails with `EPERM` if the caller
+/// already leads a process group, whose members would be left in another session.
+pub fn sys_setsid(caller: u64) -> KResult<u64> {
+    let ids = ptable::with_process(caller, |process| (process.pgid, process.sid, process.pid_links.pid()));
+    let (pgid, sid, own_pid) = ids.ok_or(Errno::ESRCH)?;
+    if pgid == caller || group_exists(caller, sid, caller) {
+        return Err(Errno::EPERM);
+    }
+
+    ptable::with_process(caller, |process| {
+        process.sid = caller;
+        process.pgid = caller;
+    });
+    Ok(own_pid)
+}
+
+/// Ends session `sid` when its leader exits: the terminal it controlled is hung up, sending
+/// `SIGHUP` and `SIGCONT` to the foreground group, and freed for another session.
+pub fn end_session(sid: u64) {
+    if let Some(foreground) = tty::disassociate(sid) {
+        _ = signal_group(foreground, SIGHUP);
+        _ = signal_group(foreground, SIGCONT);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::exit::{ChildStatus, WCONTINUED, WNOHANG, WUNTRACED};
+
+    fn spawn(pid: u64, ppid: u64, pgid: u64, sid: u64) {
+        let mut process = Process::new(pid, ppid, "jobctl");
+        process.state = ProcessState::Ready;
+        process.pgid = pgid;
+        process.sid = sid;
+        ptable::insert(process).unwrap();
+    }
+
+    fn state(pid: u64) -> Option<ProcessState> {
+        ptable::with_process(pid, |p| p.state)
+    }
+
+    fn cleanup(pids: &[u64]) {
+        for &pid in pids {
+            ptable::remove(pid);
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn stop_and_continue_restore_the_state() {
+            let mut process = Process::new(9960, 0, "jobctl");
+            process.state = ProcessState::Blocked;
+
+            assert_eq!(send(&mut process, SIGTSTP), Some(JobEvent::Stopped(SIGTSTP)));
+            assert_eq!(process.state, ProcessState::Suspended);
+            assert_eq!(process.signal_bitmap, 0);
+
+            // Waking a stopped process leaves it stopped, but runnable once continued
+            process.wake();
+            assert_eq!(process.state, ProcessState::Suspended);
+            assert_eq!(send(&mut process, SIGSTOP), None);
+            assert_eq!(send(&mut process, SIGCONT), Some(JobEvent::Continued));
+            assert_eq!(process.state, ProcessState::Ready);
+            assert_eq!(process.signal_bitmap, 1 << SIGCONT);
+
+            // A handled stop signal is left pending; an ignored one is dropped
+            process.signal_handlers[SIGTSTP as usize] = 0x4000;
+            process.signal_handlers[SIGTTIN as usize] = SIG_IGN;
+            assert_eq!(send(&mut process, SIGTSTP), None);
+            assert_eq!(send(&mut process, SIGTTIN), None);
+            assert_eq!(process.state, ProcessState::Ready);
+            assert_eq!(process.signal_bitmap, 1 << SIGTSTP);
+
+            // SIGKILL gets a stopped process going so it can die
+            process.state = ProcessState::Running;
+            assert_eq!(send(&mut process, SIGSTOP), Some(JobEvent::Stopped(SIGSTOP)));
+            assert_eq!(send(&mut process, SIGKILL), Some(JobEvent::Continued));
+            assert_eq!(process.state, ProcessState::Ready);
+            assert_eq!(process.signal_bitmap, 1 << SIGKILL);
+        }
+
+        fn parents_see_stops_through_wait() {
+            spawn(9961, 0, 9961, 9961);
+            spawn(9962, 9961, 9962, 9961);
+
+            assert_eq!(signal_group(9962, SIGTSTP), Ok(()));
+            assert_eq!(state(9962), Some(ProcessState::Suspended));
+
+            assert_eq!(ptable::with_process(9961, |shell| shell.signal_bitmap & (1 << 17) != 0), Some(true));
+            assert_eq!(exit::wait(9961, 9962, WNOHANG), Ok(None));
+            assert_eq!(exit::wait(9961, 9962, WNOHANG | WUNTRACED), Ok(Some((9962, ChildStatus::Stopped(SIGTSTP)))));
+            assert_eq!(exit::wait(9961, 9962, WNOHANG | WUNTRACED), Ok(None));
+            assert_eq!(ChildStatus::Stopped(SIGTSTP).wstatus(), 0x147f);
+
+            // `bg`: continue the job in the background
+            assert_eq!(sys_kill(9961, -9962, SIGCONT), Ok(()));
+            assert_eq!(state(9962), Some(ProcessState::Ready));
+            assert_eq!(exit::wait(9961, -1, WNOHANG | WCONTINUED), Ok(Some((9962, ChildStatus::Continued))));
+
+            assert_eq!(sys_kill(9961, -9970, SIGCONT), Err(Errno::ESRCH));
+            assert_eq!(sys_kill(9961, -1, SIGCONT), Err(Errno::EINVAL));
+            cleanup(&[9961, 9962]);
+        }
+
+        fn process_groups_and_sessions() {
+            spawn(9963, 0, 9000, 9000);
+            assert_eq!(sys_setsid(9963), Ok(9963));
+            assert_eq!(sys_setsid(9963), Err(Errno::EPERM));
+            assert_eq!(sys_setpgid(9963, 0, 0), Err(Errno::EPERM));
+
+            spawn(9964, 9963, 9963, 9963);
+            spawn(9965, 9963, 9963, 9963);
+            spawn(9966, 0, 9966, 9966);
+
+            // A job in a group of its own, then a second process joining it
+            assert_eq!(sys_setpgid(9963, 9964, 0), Ok(()));
+            assert_eq!(sys_setpgid(9963, 9965, 9964), Ok(()));
+            assert_eq!(sys_getpgid(9963, 9965), Ok(9964));
+            assert_eq!(sys_getsid(9963, 9965), Ok(9963));
+            assert_eq!(sys_getpgid(9963, 0), Ok(9963));
+
+            // Not a child, and a group of another session
+            assert_eq!(sys_setpgid(9963, 9966, 0), Err(Errno::ESRCH));
+            assert_eq!(sys_setpgid(9963, 9965, 9966), Err(Errno::EPERM));
+            assert_eq!(sys_getpgid(9963, 9967), Err(Errno::ESRCH));
+
+            // The job's parent is in the session but outside the group
+            assert!(!is_orphaned(9964));
+            ptable::remove(9963);
+            assert!(is_orphaned(9964));
+            cleanup(&[9964, 9965, 9966]);
+        }
+
+        fn ids_are_numbered_by_the_callers_namespace() {
+            let mut parent = Process::new(9967, 0, "jobctl");
+            pidns::sys_unshare_pid(&mut parent).unwrap();
+            let mut init = Process::new(9968, 9967, "jobctl");
+            pidns::attach(&mut init, &parent).unwrap();
+            let mut job = Process::new(9969, 9968, "jobctl");
+            pidns::attach(&mut job, &init).unwrap();
+            for mut process in [init, job] {
+                (process.pgid, process.sid) = (9968, 9968);
+                ptable::insert(process).unwrap();
+            }
+
+            // The job is PID 2 inside, in the group and session of init, PID 1
+            assert_eq!(sys_getpgid(9968, 2), Ok(1));
+            assert_eq!(sys_getsid(9968, 0), Ok(1));
+            assert_eq!(sys_kill(9968, 2, 0), Ok(()));
+
+            // Global PIDs, and processes outside the namespace, are not visible from it
+            assert_eq!(sys_kill(9968, 9967, 0), Err(Errno::ESRCH));
+            assert_eq!(sys_getpgid(9968, 9969), Err(Errno::ESRCH));
+
+            assert_eq!(sys_setpgid(9968, 2, 0), Ok(()));
+            assert_eq!(ptable::with_process(9969, |p| p.pgid), Some(9969));
+            assert_eq!(sys_getpgid(9968, 2), Ok(2));
+            assert_eq!(sys_kill(9968, -2, 0), Ok(()));
+
+            for pid in [9969, 9968] {
+                pidns::detach(&mut ptable::remove(pid).unwrap());
+            }
+            pidns::detach(&mut parent);
+        }
+    }
+}
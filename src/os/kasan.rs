@@ -0,0 +1,318 @@
+//! Shadow-memory sanitizer for the kernel heap (KASAN-lite), enabled by the `kasan` feature.
+//!
+//! Every 8-byte granule of the tracked heap has one shadow byte describing how much of it may be
+//! accessed. Allocations are surrounded by poisoned red zones and freed blocks are poisoned as
+//! a whole, so [`check_read`]/[`check_write`] catch overflows and use-after-free in the paths that
+//! call them (user copies, slab and heap internals). Without the feature all hooks compile to
+//! nothing, so callers never need their own `cfg`.
+
+use core::fmt;
+
+/// Bytes of memory described by one shadow byte.
+pub const GRANULE: usize = 8;
+
+/// Size of the red zone the allocator places on each side of an allocation.
+pub const REDZONE: usize = 16;
+
+/// Largest heap the global shadow map can describe.
+#[cfg(feature = "kasan")]
+const MAX_TRACKED_BYTES: usize = 4 * 1024 * 1024;
+
+// Shadow byte values. 0 means the whole granule is accessible, 1..=7 means only that many
+// leading bytes are; everything with the top bit set is poisoned.
+const SHADOW_ACCESSIBLE: u8 = 0x00;
+const SHADOW_REDZONE: u8 = 0xfa;
+const SHADOW_FREED: u8 = 0xfb;
+const SHADOW_UNALLOCATED: u8 = 0xfc;
+
+/// Why an access was rejected, derived from the first bad shadow byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The access ran into a red zone (buffer overflow or underflow).
+    OutOfBounds,
+
+    /// The memory was freed (use-after-free).
+    UseAfterFree,
+
+    /// The memory was never handed out by the allocator.
+    Unallocated,
+}
+
+/// A rejected access, with the first offending address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub addr: usize,
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ViolationKind::OutOfBounds => f.write_str("out-of-bounds"),
+            ViolationKind::UseAfterFree => f.write_str("use-after-free"),
+            ViolationKind::Unallocated => f.write_str("access to unallocated memory"),
+        }
+    }
+}
+
+/// Shadow bytes describing the memory range `[base, base + shadow.len() * GRANULE)`.
+pub struct ShadowMap<'a> {
+    base: usize,
+    shadow: &'a mut [u8],
+}
+
+impl<'a> ShadowMap<'a> {
+    /// Creates a shadow map for memory starting at `base` (must be granule aligned).
+    /// The whole range starts out poisoned as unallocated.
+    pub fn new(base: usize, shadow: &'a mut [u8]) -> Self {
+        assert!(base.is_multiple_of(GRANULE), "kasan: shadowed range must be granule aligned");
+        shadow.fill(SHADOW_UNALLOCATED);
+        ShadowMap { base, shadow }
+    }
+
+    /// Returns `true` if `addr` lies in the memory described by this map.
+    pub fn covers(&self, addr: usize) -> bool {
+        addr >= self.base && addr - self.base < self.shadow.len() * GRANULE
+    }
+
+    /// Returns `true` if all of `[addr, addr + len)` lies in the memory described by this map.
+    pub fn covers_range(&self, addr: usize, len: usize) -> bool {
+        self.covers(addr) && self.covers(addr + len - 1)
+    }
+
+    /// Marks `[addr, addr + len)` as accessible. `addr` must be granule aligned; a partial
+    /// last granule only exposes its first `len % GRANULE` bytes.
+    pub fn unpoison(&mut self, addr: usize, len: usize) {
+        let first = self.index(addr);
+        let full = len / GRANULE;
+
+        self.shadow[first..first + full].fill(SHADOW_ACCESSIBLE);
+
+        if !len.is_multiple_of(GRANULE) {
+            self.shadow[first + full] = (len % GRANULE) as u8;
+        }
+    }
+
+    /// Marks every granule touched by `[addr, addr + len)` as poisoned with `value`.
+    fn poison(&mut self, addr: usize, len: usize, value: u8) {
+        let first = self.index(addr);
+        let last = self.index(addr + len - 1);
+
+        self.shadow[first..=last].fill(value);
+    }
+
+    /// Records an allocation. `block` is what the allocator reserved (`block_size` includes both
+    /// red zones); returns the pointer to hand out, `REDZONE` bytes into the block.
+    pub fn on_alloc(&mut self, block: usize, block_size: usize, size: usize) -> usize {
+        let user = block + REDZONE;
+
+        self.poison(block, block_size, SHADOW_REDZONE);
+        if size > 0 {
+            self.unpoison(user, size);
+        }
+
+        user
+    }
+
+    /// Records that the allocation at `user` (as returned by [`Self::on_alloc`]) was freed.
+    pub fn on_free(&mut self, user: usize, size: usize) {
+        self.poison(user, size.max(1), SHADOW_FREED);
+    }
+
+    /// Validates an access of `len` bytes at `addr`. Addresses outside the map are not checked.
+    pub fn check(&self, addr: usize, len: usize) -> Result<(), Violation> {
+        if len == 0 || !self.covers(addr) {
+            return Ok(());
+        }
+
+        // Clamp to the covered range; the rest of the access is not ours to judge
+        let end = (addr + len).min(self.base + self.shadow.len() * GRANULE);
+
+        let mut cursor = addr;
+        while cursor < end {
+            let value = self.shadow[self.index(cursor)];
+            let offset = (cursor - self.base) % GRANULE;
+
+            let accessible = match value {
+                SHADOW_ACCESSIBLE => true,
+                1..=7 => offset < value as usize,
+                _ => false,
+            };
+
+            if !accessible {
+                let kind = match value {
+                    SHADOW_FREED => ViolationKind::UseAfterFree,
+                    SHADOW_UNALLOCATED => ViolationKind::Unallocated,
+                    _ => ViolationKind::OutOfBounds,
+                };
+
+                return Err(Violation { kind, addr: cursor });
+            }
+
+            // Whole accessible granules can be skipped in one go
+            cursor = if value == SHADOW_ACCESSIBLE { cursor - offset + GRANULE } else { cursor + 1 };
+        }
+
+        Ok(())
+    }
+
+    fn index(&self, addr: usize) -> usize {
+        (addr - self.base) / GRANULE
+    }
+}
+
+/// Size the allocator must reserve for a `size`-byte allocation so both red zones fit.
+pub const fn block_size(size: usize) -> usize {
+    if cfg!(feature = "kasan") {
+        REDZONE + size.div_ceil(GRANULE) * GRANULE + REDZONE
+    } else {
+        size
+    }
+}
+
+// =========================================================================
+// Global heap shadow (only present with the `kasan` feature)
+// =========================================================================
+
+#[cfg(feature = "kasan")]
+static mut HEAP_SHADOW_BYTES: [u8; MAX_TRACKED_BYTES / GRANULE] = [SHADOW_UNALLOCATED; MAX_TRACKED_BYTES / GRANULE];
+
+#[cfg(feature = "kasan")]
+static mut HEAP_SHADOW: Option<ShadowMap<'static>> = None;
+
+/// Starts tracking the kernel heap at `[base, base + size)`. Called once by the heap allocator.
+#[cfg(feature = "kasan")]
+pub fn init_heap(base: usize, size: usize) {
+    let granules = (size / GRANULE).min(MAX_TRACKED_BYTES / GRANULE);
+
+    unsafe {
+        let shadow_bytes = &raw mut HEAP_SHADOW_BYTES;
+        HEAP_SHADOW = Some(ShadowMap::new(base, &mut (&mut *shadow_bytes)[..granules]));
+    }
+
+    log::info!("kasan: tracking {} KiB of kernel heap at {:#x}", granules * GRANULE / 1024, base);
+}
+
+#[cfg(feature = "kasan")]
+fn with_heap_shadow<R>(f: impl FnOnce(&mut ShadowMap<'static>) -> R) -> Option<R> {
+    unsafe {
+        let shadow = &raw mut HEAP_SHADOW;
+        (*shadow).as_mut().map(f)
+    }
+}
+
+/// Allocator hook: poisons the red zones around a new allocation and returns the user pointer.
+/// Blocks outside the tracked heap keep their red zones but are not shadowed.
+#[inline]
+pub fn on_alloc(block: usize, size: usize) -> usize {
+    #[cfg(feature = "kasan")]
+    {
+        let block_size = block_size(size);
+        with_heap_shadow(|s| s.covers_range(block, block_size).then(|| s.on_alloc(block, block_size, size)))
+            .flatten()
+            .unwrap_or(block + REDZONE)
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    {
+        _ = size;
+        block
+    }
+}
+
+/// Allocator hook: poisons a freed allocation and returns the start of its block.
+#[inline]
+pub fn on_free(user: usize, size: usize) -> usize {
+    #[cfg(feature = "kasan")]
+    {
+        with_heap_shadow(|s| {
+            if s.covers_range(user, size.max(1)) {
+                s.on_free(user, size);
+            }
+        });
+        user - REDZONE
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    {
+        _ = size;
+        user
+    }
+}
+
+/// Checks that the kernel may read `len` bytes at `addr`, panicking with a report otherwise.
+#[inline]
+#[track_caller]
+pub fn check_read(addr: usize, len: usize) {
+    #[cfg(feature = "kasan")]
+    check(addr, len, "read");
+
+    #[cfg(not(feature = "kasan"))]
+    {
+        _ = (addr, len);
+    }
+}
+
+/// Checks that the kernel may write `len` bytes at `addr`, panicking with a report otherwise.
+#[inline]
+#[track_caller]
+pub fn check_write(addr: usize, len: usize) {
+    #[cfg(feature = "kasan")]
+    check(addr, len, "write");
+
+    #[cfg(not(feature = "kasan"))]
+    {
+        _ = (addr, len);
+    }
+}
+
+#[cfg(feature = "kasan")]
+#[track_caller]
+fn check(addr: usize, len: usize, access: &str) {
+    if let Some(Err(violation)) = with_heap_shadow(|s| s.check(addr, len)) {
+        panic!(
+            "kasan: {} on {} of {} bytes at {:#x} (first bad byte {:#x})",
+            violation.kind, access, len, addr, violation.addr
+        );
+    }
+}
+
+pub mod ktests {
+    #[cfg(feature = "kasan")]
+    use super::*;
+
+    #[cfg(feature = "kasan")]
+    crate::os::ktest::kernel_test! {
+        fn detects_overflow_into_redzone() {
+            let mut shadow = [0u8; 32];
+            let mut map = ShadowMap::new(0x1000, &mut shadow);
+
+            let user = map.on_alloc(0x1000, block_size(13), 13);
+
+            assert_eq!(map.check(user, 13), Ok(()));
+            assert_eq!(map.check(user + 13, 1).map_err(|v| v.kind), Err(ViolationKind::OutOfBounds));
+            assert_eq!(map.check(user - 1, 1).map_err(|v| v.kind), Err(ViolationKind::OutOfBounds));
+        }
+
+        fn detects_use_after_free() {
+            let mut shadow = [0u8; 32];
+            let mut map = ShadowMap::new(0x1000, &mut shadow);
+
+            let user = map.on_alloc(0x1000, block_size(24), 24);
+            map.on_free(user, 24);
+
+            assert_eq!(map.check(user + 8, 4).map_err(|v| v.kind), Err(ViolationKind::UseAfterFree));
+        }
+
+        fn ignores_untracked_addresses() {
+            let mut shadow = [0u8; 4];
+            let map = ShadowMap::new(0x1000, &mut shadow);
+
+            assert_eq!(map.check(0x8000, 64), Ok(()));
+            assert_eq!(map.check(0x1000, 1).map_err(|v| v.kind), Err(ViolationKind::Unallocated));
+        }
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    pub(crate) const KERNEL_TESTS: &[crate::os::ktest::KernelTest] = &[];
+}
@@ -0,0 +1,338 @@
+//! PS/2 keyboard: the i8042 controller's first port, interrupting on ISA IRQ 1.
+//!
+//! The controller translates whatever the keyboard sends to scancode set 1, which [`Decoder`]
+//! turns into bytes as a terminal would send them: printable ASCII with Shift and Caps Lock
+//! applied, control characters for Ctrl with a letter, an ESC prefix for Alt, and the usual
+//! escape sequences for the cursor and editing keys. Key releases only matter to the
+//! modifiers. Characters the console terminal acts on (Ctrl+C and friends, see
+//! [`tty::receive`]) go to it; the rest wait in a ring buffer for [`read`], which blocks
+//! while the buffer is empty. The keyboard is `/dev/kbd`.
+
+use crate::os::arch::x86_64::idt;
+use crate::os::arch::x86_64::ioapic;
+use crate::os::arch::x86_64::port::{inb, outb};
+use crate::os::devfs::{self, CharDevice};
+use crate::os::errno::KResult;
+use crate::os::process::WaitTarget;
+use crate::os::sched;
+use crate::os::sync::SpinLock;
+use crate::os::tty;
+
+/// Bytes of typed input kept until read; more are dropped.
+pub const QUEUE_SIZE: usize = 256;
+
+// ISA IRQ of the controller's first port
+const IRQ: u8 = 1;
+
+// Controller ports and status bits
+const DATA: u16 = 0x60;
+const COMMAND: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands, and the configuration bit enabling the first port's interrupt
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const CONFIG_PORT1_INTERRUPT: u8 = 1 << 0;
+
+// How many status polls a controller handshake may take
+const POLLS: u32 = 100_000;
+
+// Readers wait on this
+const WAIT_TARGET: WaitTarget = WaitTarget::IODevice(IRQ as u32);
+
+// Scancode set 1 prefixes and flags
+const EXTENDED: u8 = 0xe0;
+const RELEASED: u8 = 0x80;
+
+// Modifier and lock keys
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const CTRL: u8 = 0x1d;
+const ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3a;
+
+// Modifier state bits
+const MOD_LEFT_SHIFT: u8 = 1 << 0;
+const MOD_RIGHT_SHIFT: u8 = 1 << 1;
+const MOD_CTRL: u8 = 1 << 2;
+const MOD_ALT: u8 = 1 << 3;
+const MOD_CAPS: u8 = 1 << 4;
+
+const ESC: u8 = 0x1b;
+
+// What each key below 0x3a types, unshifted and shifted; 0 for keys that type nothing
+const NORMAL: &[u8; 58] = b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 58] = b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+// The escape sequences of keys behind the 0xe0 prefix
+const SEQUENCES: &[(u8, &[u8])] = &[
+    (0x48, b"\x1b[A"),
+    (0x50, b"\x1b[B"),
+    (0x4d, b"\x1b[C"),
+    (0x4b, b"\x1b[D"),
+    (0x47, b"\x1b[H"),
+    (0x4f, b"\x1b[F"),
+    (0x53, b"\x1b[3~"),
+    (0x1c, b"\n"),
+    (0x35, b"/"),
+];
+
+/// Turns scancode set 1 into typed bytes, tracking the modifiers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Decoder {
+    modifiers: u8,
+    extended: bool,
+}
+
+// Typed bytes waiting to be read
+struct Queue {
+    buf: [u8; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+struct Input {
+    decoder: Decoder,
+    queue: Queue,
+}
+
+static INPUT: SpinLock<Input> = SpinLock::new(Input {
+    decoder: Decoder { modifiers: 0, extended: false },
+    queue: Queue { buf: [0; QUEUE_SIZE], head: 0, len: 0 },
+});
+
+/// The keyboard as `/dev/kbd` reads it.
+pub static KEYBOARD: Keyboard = Keyboard;
+
+pub struct Keyboard;
+
+impl Decoder {
+    /// Takes one scancode byte and calls `out` with each byte the key types.
+    pub fn feed(&mut self, scancode: u8, mut out: impl FnMut(u8)) {
+        if scancode == EXTENDED {
+            self.extended = true;
+            return;
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        let (code, released) = (scancode & !RELEASED, scancode & RELEASED != 0);
+
+        // Right Ctrl and Alt are the extended codes of the left ones
+        let modifier = match code {
+            LEFT_SHIFT if !extended => MOD_LEFT_SHIFT,
+            RIGHT_SHIFT if !extended => MOD_RIGHT_SHIFT,
+            CTRL => MOD_CTRL,
+            ALT => MOD_ALT,
+            _ => 0,
+        };
+        if modifier != 0 {
+            if released {
+                self.modifiers &= !modifier;
+            } else {
+                self.modifiers |= modifier;
+            }
+            return;
+        }
+
+        if released {
+            return;
+        }
+        if code == CAPS_LOCK && !extended {
+            self.modifiers ^= MOD_CAPS;
+            return;
+        }
+
+        if extended {
+            if let Some((_, sequence)) = SEQUENCES.iter().find(|(key, _)| *key == code) {
+                sequence.iter().for_each(|&byte| out(byte));
+            }
+            return;
+        }
+
+        let shift = self.modifiers & (MOD_LEFT_SHIFT | MOD_RIGHT_SHIFT) != 0;
+        let mut byte = match (if shift { SHIFTED } else { NORMAL }).get(code as usize) {
+            Some(&byte) if byte != 0 => byte,
+            _ => return,
+        };
+
+        if self.modifiers & MOD_CAPS != 0 && byte.is_ascii_alphabetic() {
+            byte ^= 0x20;
+        }
+        if self.modifiers & MOD_CTRL != 0 && (0x40..0x80).contains(&byte) {
+            byte &= 0x1f;
+        }
+        if self.modifiers & MOD_ALT != 0 {
+            out(ESC);
+        }
+        out(byte);
+    }
+}
+
+impl Queue {
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % QUEUE_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    // Moves as much as fits into `out`, oldest first
+    fn pop_into(&mut self, out: &mut [u8]) -> usize {
+        let n = self.len.min(out.len());
+        for byte in &mut out[..n] {
+            *byte = self.buf[self.head];
+            self.head = (self.head + 1) % QUEUE_SIZE;
+        }
+        self.len -= n;
+        n
+    }
+}
+
+// Waits for the controller to have a byte for us, or to take one
+fn wait_status(ready: impl Fn(u8) -> bool) -> bool {
+    (0..POLLS).any(|_| ready(unsafe { inb(COMMAND) }))
+}
+
+/// Enables the first port's interrupt in the controller, routes IRQ 1 to a device vector and
+/// registers `/dev/kbd`. Called once at boot, once the I/O APIC is set up.
+pub fn init() {
+    unsafe {
+        // Whatever the firmware left unread would never raise an interrupt. Without a
+        // controller the status reads all ones, so the draining is bounded.
+        for _ in 0..QUEUE_SIZE {
+            if inb(COMMAND) & STATUS_OUTPUT_FULL == 0 {
+                break;
+            }
+            inb(DATA);
+        }
+
+        outb(COMMAND, READ_CONFIG);
+        if !wait_status(|status| status & STATUS_OUTPUT_FULL != 0) {
+            log::warn!("keyboard: no PS/2 controller");
+            return;
+        }
+        let config = inb(DATA);
+
+        outb(COMMAND, WRITE_CONFIG);
+        if wait_status(|status| status & STATUS_INPUT_FULL == 0) {
+            outb(DATA, config | CONFIG_PORT1_INTERRUPT);
+        }
+    }
+
+    let Some(vector) = idt::allocate_vector(handle_interrupt) else {
+        log::warn!("keyboard: no interrupt vector left");
+        return;
+    };
+    if let Err(errno) = ioapic::route_isa(IRQ, vector) {
+        log::warn!("keyboard: cannot route IRQ {}: {:?}", IRQ, errno);
+        return;
+    }
+
+    if let Err(errno) = devfs::register("kbd", &KEYBOARD) {
+        log::warn!("keyboard: cannot register /dev/kbd: {:?}", errno);
+    }
+    log::info!("keyboard: IRQ {} on vector {:#x}", IRQ, vector);
+}
+
+// Decodes every scancode the controller holds, then wakes the readers
+fn handle_interrupt() {
+    while unsafe { inb(COMMAND) } & STATUS_OUTPUT_FULL != 0 {
+        let scancode = unsafe { inb(DATA) };
+
+        // At most the longest escape sequence with an Alt prefix
+        let (mut typed, mut len) = ([0u8; 8], 0);
+        INPUT.with(|input| {
+            input.decoder.feed(scancode, |byte| {
+                typed[len] = byte;
+                len += 1;
+            })
+        });
+
+        for &byte in &typed[..len] {
+            if !tty::receive(byte) {
+                INPUT.with(|input| input.queue.push(byte));
+            }
+        }
+    }
+
+    sched::wake(WAIT_TARGET);
+}
+
+/// Reads typed bytes into `buf`, blocking until there is at least one. Returns at once for an
+/// empty `buf`.
+pub fn read(buf: &mut [u8]) -> KResult<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+
+    loop {
+        let n = INPUT.with(|input| input.queue.pop_into(buf));
+        if n > 0 {
+            return Ok(n);
+        }
+
+        sched::block_on(WAIT_TARGET, || INPUT.with(|input| input.queue.len > 0));
+    }
+}
+
+impl CharDevice for Keyboard {
+    fn read(&self, buf: &mut [u8]) -> KResult<usize> {
+        read(buf)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use alloc::vec::Vec;
+
+    fn typed(scancodes: &[u8]) -> Vec<u8> {
+        let mut decoder = Decoder::default();
+        let mut out = Vec::new();
+        for &scancode in scancodes {
+            decoder.feed(scancode, |byte| out.push(byte));
+        }
+        out
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn keys_type_their_characters() {
+            // h, i, releases, Enter
+            assert_eq!(typed(&[0x23, 0xa3, 0x17, 0x97, 0x1c]), b"hi\n");
+
+            // Shift+1, then 1 once Shift is released
+            assert_eq!(typed(&[LEFT_SHIFT, 0x02, LEFT_SHIFT | RELEASED, 0x02]), b"!1");
+
+            // Caps Lock shifts letters only, and Shift undoes it
+            assert_eq!(typed(&[CAPS_LOCK, 0x1e, 0x02, RIGHT_SHIFT, 0x1e]), b"A1a");
+        }
+
+        fn modifiers_make_control_and_meta_characters() {
+            assert_eq!(typed(&[CTRL, 0x2e]), [tty::VINTR]);
+            assert_eq!(typed(&[EXTENDED, CTRL, 0x2c, EXTENDED, CTRL | RELEASED, 0x2c]), [tty::VSUSP, b'z']);
+            assert_eq!(typed(&[ALT, 0x32]), b"\x1bm");
+        }
+
+        fn extended_keys_send_escape_sequences() {
+            assert_eq!(typed(&[EXTENDED, 0x48, EXTENDED, 0xc8, EXTENDED, 0x4b]), b"\x1b[A\x1b[D");
+            assert_eq!(typed(&[EXTENDED, 0x53]), b"\x1b[3~");
+            assert_eq!(typed(&[EXTENDED, 0x5b]), b"");
+        }
+
+        fn the_queue_keeps_order_and_drops_overflow() {
+            let mut queue = Queue { buf: [0; QUEUE_SIZE], head: QUEUE_SIZE - 2, len: 0 };
+            for byte in 0..QUEUE_SIZE {
+                assert!(queue.push(byte as u8));
+            }
+            assert!(!queue.push(0xff));
+
+            let mut out = [0; 3];
+            assert_eq!(queue.pop_into(&mut out), 3);
+            assert_eq!(out, [0, 1, 2]);
+            assert_eq!(queue.len, QUEUE_SIZE - 3);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Reference-counted kernel objects.
+//!
+//! Objects shared between owners -- open files between descriptor tables and in-flight I/O,
+//! devices between the registry and their drivers -- live in a fixed [`Pool`] and are held
+//! through [`KRef`]s, which work like `Arc`s: cloning one takes another reference, dropping
+//! the last one drops the object and frees its slot. The count is intrusive, kept in the
+//! object's slot next to it, so taking a reference costs one atomic add and no allocation.
+//!
+//! With the `leakcheck` feature every object is reported to the [`leak`](super::leak)
+//! detector as it is created and destroyed, so objects nobody released show up in its
+//! reports with the call chain that made them. [`POOLS`] lists every pool, for the live
+//! counts in `/proc/kobjects`.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU32, Ordering, fence};
+
+use crate::os::device;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file;
+use crate::os::leak;
+
+// Count of a slot holding no object
+const FREE: u32 = u32::MAX;
+
+// More references than this are taken to be a leak in a loop, as Arc does
+const MAX_REFS: u32 = i32::MAX as u32;
+
+// Count 0 while the object is being created or destroyed, FREE while the slot is unused
+struct Slot<T> {
+    refs: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Slot { refs: AtomicU32::new(FREE), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+}
+
+/// A fixed set of slots objects of type `T` are created in, meant to be a `static`.
+pub struct Pool<T: 'static, const N: usize> {
+    name: &'static str,
+    slots: [Slot<T>; N],
+}
+
+// Objects are only reached through KRefs, which hand out shared references
+unsafe impl<T: Send + Sync, const N: usize> Sync for Pool<T, N> {}
+
+impl<T: 'static, const N: usize> Pool<T, N> {
+    /// An empty pool; `name` identifies it in statistics.
+    pub const fn new(name: &'static str) -> Self {
+        Pool { name, slots: [const { Slot::new() }; N] }
+    }
+
+    /// Creates an object holding `value` and returns the first reference to it. Fails with
+    /// `ENOSPC` when all `N` slots are in use.
+    #[inline(never)]
+    pub fn alloc(&'static self, value: T) -> KResult<KRef<T>> {
+        self.try_alloc(value).map_err(|_| Errno::ENOSPC)
+    }
+
+    /// As [`alloc`](Self::alloc), but hands `value` back when the pool is full, for objects
+    /// whose drop must not run unless they were created.
+    #[inline(never)]
+    pub fn try_alloc(&'static self, value: T) -> Result<KRef<T>, T> {
+        let Some(slot) = self
+            .slots
+            .iter()
+            .find(|slot| slot.refs.compare_exchange(FREE, 0, Ordering::Acquire, Ordering::Relaxed).is_ok())
+        else {
+            return Err(value);
+        };
+
+        unsafe { (*slot.value.get()).write(value) };
+        leak::record_alloc(slot.value.get() as usize, size_of::<T>(), 1);
+        slot.refs.store(1, Ordering::Release);
+        Ok(KRef { slot })
+    }
+}
+
+/// Occupancy of a pool.
+pub trait PoolStats: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Objects currently alive.
+    fn live(&self) -> usize;
+
+    fn capacity(&self) -> usize;
+}
+
+impl<T: Send + Sync + 'static, const N: usize> PoolStats for Pool<T, N> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn live(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.refs.load(Ordering::Relaxed) != FREE).count()
+    }
+
+    fn capacity(&self) -> usize {
+        N
+    }
+}
+
+/// Every kernel object pool.
+pub static POOLS: &[&dyn PoolStats] = &[&file::FILES, &device::DEVICES];
+
+/// Writes one `<name> <live> <capacity>` line per pool, the contents of `/proc/kobjects`.
+pub fn write_stats(w: &mut impl Write) -> fmt::Result {
+    for pool in POOLS {
+        writeln!(w, "{} {} {}", pool.name(), pool.live(), pool.capacity())?;
+    }
+    Ok(())
+}
+
+/// A counted reference to an object in a [`Pool`].
+pub struct KRef<T: 'static> {
+    slot: &'static Slot<T>,
+}
+
+unsafe impl<T: Send + Sync> Send for KRef<T> {}
+unsafe impl<T: Send + Sync> Sync for KRef<T> {}
+
+impl<T> KRef<T> {
+    /// Number of references to the object, this one included.
+    pub fn count(this: &Self) -> u32 {
+        this.slot.refs.load(Ordering::Relaxed)
+    }
+
+    /// Whether both refer to the same object.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        core::ptr::eq(this.slot, other.slot)
+    }
+}
+
+impl<T> Deref for KRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Initialised for as long as a reference exists
+        unsafe { (*self.slot.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Clone for KRef<T> {
+    fn clone(&self) -> Self {
+        if self.slot.refs.fetch_add(1, Ordering::Relaxed) >= MAX_REFS {
+            panic!("kobject: reference count overflow");
+        }
+        KRef { slot: self.slot }
+    }
+}
+
+impl<T> Drop for KRef<T> {
+    fn drop(&mut self) {
+        if self.slot.refs.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // Every other owner's accesses happen before the object goes
+        fence(Ordering::Acquire);
+        unsafe { (*self.slot.value.get()).assume_init_drop() };
+        leak::record_free(self.slot.value.get() as usize);
+        self.slot.refs.store(FREE, Ordering::Release);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for KRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use core::sync::atomic::AtomicUsize;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct Counted(u32);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    static TEST_POOL: Pool<Counted, 2> = Pool::new("test");
+
+    // Collects formatted output into a fixed buffer
+    struct Buffer {
+        bytes: [u8; 256],
+        len: usize,
+    }
+
+    impl Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn last_reference_drops_the_object() {
+            let dropped = DROPPED.load(Ordering::Relaxed);
+
+            let first = TEST_POOL.alloc(Counted(7)).unwrap();
+            let second = first.clone();
+            assert_eq!((KRef::count(&first), second.0), (2, 7));
+            assert!(KRef::ptr_eq(&first, &second));
+
+            drop(first);
+            assert_eq!(DROPPED.load(Ordering::Relaxed), dropped);
+            assert_eq!(TEST_POOL.live(), 1);
+
+            drop(second);
+            assert_eq!(DROPPED.load(Ordering::Relaxed), dropped + 1);
+            assert_eq!(TEST_POOL.live(), 0);
+        }
+
+        fn full_pools_refuse_and_freed_slots_are_reused() {
+            let a = TEST_POOL.alloc(Counted(1)).unwrap();
+            let b = TEST_POOL.alloc(Counted(2)).unwrap();
+            assert_eq!(TEST_POOL.alloc(Counted(3)).map(|_| ()), Err(Errno::ENOSPC));
+
+            drop(a);
+            let c = TEST_POOL.alloc(Counted(4)).unwrap();
+            assert!(!KRef::ptr_eq(&b, &c));
+            assert_eq!((b.0, c.0, TEST_POOL.live()), (2, 4, 2));
+        }
+
+        fn stats_list_every_pool() {
+            let mut out = Buffer { bytes: [0; 256], len: 0 };
+            write_stats(&mut out).unwrap();
+            let text = core::str::from_utf8(&out.bytes[..out.len]).unwrap();
+            assert_eq!(text.lines().count(), POOLS.len());
+            assert!(text.lines().any(|line| line.starts_with("file ")));
+        }
+    }
+}
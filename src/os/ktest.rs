@@ -0,0 +1,325 @@
+//! In-kernel unit test framework.
+//!
+//! `#[test]` needs `std`, so kernel modules register their tests with [`kernel_test!`] instead.
+//! Every module's test list is referenced from [`SUITES`], and [`run_all`] executes them one
+//! after another under the selftest boot mode, reporting each result over the serial console.
+//!
+//! A panicking test does not take the whole run down: the panic handler hands control back to
+//! the runner (see [`handle_panic`]), the test is reported as failed and the next one starts.
+//! The panicking test's stack is discarded without running destructors, so tests should not
+//! leave global state half-modified behind a panic.
+//!
+//! Each test also has a time budget (default [`DEFAULT_TIMEOUT_MS`]). Without a timer interrupt
+//! the runner cannot preempt a test, so the budget is checked once the test returns and an
+//! overrun is reported as a failure; a test that never returns is caught by the host-side
+//! QEMU timeout instead.
+
+use core::fmt::{self, Write};
+
+use crate::os::arch::{Arch, Current, counter};
+use crate::serial_println;
+
+/// Time budget of a test that does not specify its own.
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// A single registered kernel test.
+pub struct KernelTest {
+    /// Fully qualified test name (`module::path::test_name`).
+    pub name: &'static str,
+
+    /// The test body; it fails by panicking (e.g. through `assert!`).
+    pub func: fn(),
+
+    /// Maximum runtime in milliseconds before the test is reported as timed out.
+    pub timeout_ms: u64,
+}
+
+/// Registers kernel tests for the enclosing module.
+///
+/// Expands to the test functions plus a `KERNEL_TESTS` slice that must be listed in [`SUITES`].
+/// A test may override its time budget with `#[timeout(ms)]`:
+///
+/// ```ignore
+/// kernel_test! {
+///     fn adds_up() {
+///         assert_eq!(1 + 1, 2);
+///     }
+///
+///     #[timeout(5000)]
+///     fn slow_but_fine() { /* ... */ }
+/// }
+/// ```
+macro_rules! kernel_test {
+    ($( $(#[timeout($ms:expr)])? fn $name:ident() $body:block )*) => {
+        $( fn $name() $body )*
+
+        pub(crate) const KERNEL_TESTS: &[$crate::os::ktest::KernelTest] = &[
+            $(
+                $crate::os::ktest::KernelTest {
+                    name: concat!(module_path!(), "::", stringify!($name)),
+                    func: $name,
+                    timeout_ms: $crate::os::ktest::timeout_or_default(&[$($ms)?]),
+                },
+            )*
+        ];
+    };
+}
+
+pub(crate) use kernel_test;
+
+/// Every module's registered tests, run in order by [`run_all`].
+pub const SUITES: &[&[KernelTest]] = &[
+    crate::os::config::ktests::KERNEL_TESTS,
+    crate::os::acpi::ktests::KERNEL_TESTS,
+    crate::os::console::ktests::KERNEL_TESTS,
+    crate::os::serial::ktests::KERNEL_TESTS,
+    crate::os::panic::ktests::KERNEL_TESTS,
+    crate::os::memory::ktests::KERNEL_TESTS,
+    crate::os::frame::ktests::KERNEL_TESTS,
+    crate::os::heap::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::paging::ktests::KERNEL_TESTS,
+    crate::os::protection::ktests::KERNEL_TESTS,
+    crate::os::uaccess::ktests::KERNEL_TESTS,
+    crate::os::aslr::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::arch::x86_64::idt::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::arch::x86_64::ioapic::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::arch::x86_64::rtc::ktests::KERNEL_TESTS,
+    crate::os::kasan::ktests::KERNEL_TESTS,
+    crate::os::random::ktests::KERNEL_TESTS,
+    crate::os::deadlock::ktests::KERNEL_TESTS,
+    crate::os::sync::ktests::KERNEL_TESTS,
+    crate::os::ipc::ktests::KERNEL_TESTS,
+    crate::os::seccomp::ktests::KERNEL_TESTS,
+    crate::os::audit::ktests::KERNEL_TESTS,
+    crate::os::capability::ktests::KERNEL_TESTS,
+    crate::os::cred::ktests::KERNEL_TESTS,
+    crate::os::lsm::ktests::KERNEL_TESTS,
+    crate::os::fpu::ktests::KERNEL_TESTS,
+    crate::os::ipi::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::arch::x86_64::smp::ktests::KERNEL_TESTS,
+    crate::os::numa::ktests::KERNEL_TESTS,
+    crate::os::fdt::ktests::KERNEL_TESTS,
+    crate::os::kobject::ktests::KERNEL_TESTS,
+    crate::os::file::ktests::KERNEL_TESTS,
+    crate::os::uring::ktests::KERNEL_TESTS,
+    crate::os::pid::ktests::KERNEL_TESTS,
+    crate::os::pidns::ktests::KERNEL_TESTS,
+    crate::os::prctl::ktests::KERNEL_TESTS,
+    crate::os::rlimit::ktests::KERNEL_TESTS,
+    crate::os::sysctl::ktests::KERNEL_TESTS,
+    crate::os::mount::ktests::KERNEL_TESTS,
+    crate::os::vfs::ktests::KERNEL_TESTS,
+    crate::os::devfs::ktests::KERNEL_TESTS,
+    crate::os::fat32::ktests::KERNEL_TESTS,
+    crate::os::initrd::ktests::KERNEL_TESTS,
+    crate::os::cgroup::ktests::KERNEL_TESTS,
+    crate::os::exit::ktests::KERNEL_TESTS,
+    crate::os::ptable::ktests::KERNEL_TESTS,
+    crate::os::sched::ktests::KERNEL_TESTS,
+    crate::os::kthread::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::elf::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::fork::ktests::KERNEL_TESTS,
+    crate::os::cow::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::fault::ktests::KERNEL_TESTS,
+    crate::os::syscall::ktests::KERNEL_TESTS,
+    crate::os::jobctl::ktests::KERNEL_TESTS,
+    crate::os::signal::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::arch::x86_64::signal::ktests::KERNEL_TESTS,
+    crate::os::tty::ktests::KERNEL_TESTS,
+    crate::os::zram::ktests::KERNEL_TESTS,
+    crate::os::swap::ktests::KERNEL_TESTS,
+    crate::os::compaction::ktests::KERNEL_TESTS,
+    crate::os::thp::ktests::KERNEL_TESTS,
+    crate::os::vmalloc::ktests::KERNEL_TESTS,
+    crate::os::pagecache::ktests::KERNEL_TESTS,
+    crate::os::mmap::ktests::KERNEL_TESTS,
+    crate::os::vma::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::brk::ktests::KERNEL_TESTS,
+    crate::os::net::ktests::KERNEL_TESTS,
+    crate::os::net::arp::ktests::KERNEL_TESTS,
+    crate::os::net::ipv4::ktests::KERNEL_TESTS,
+    crate::os::net::udp::ktests::KERNEL_TESTS,
+    crate::os::net::dhcp::ktests::KERNEL_TESTS,
+    crate::os::timekeeping::ktests::KERNEL_TESTS,
+    crate::os::timer::ktests::KERNEL_TESTS,
+    crate::os::hrtimer::ktests::KERNEL_TESTS,
+    crate::os::itimer::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::pci::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::ahci::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::keyboard::ktests::KERNEL_TESTS,
+    #[cfg(target_arch = "x86_64")]
+    crate::os::shell::ktests::KERNEL_TESTS,
+    #[cfg(all(target_arch = "x86_64", feature = "virtio"))]
+    crate::os::virtio::p9::ktests::KERNEL_TESTS,
+];
+
+/// Picks the explicit `#[timeout(ms)]` value from [`kernel_test!`], or the default.
+#[doc(hidden)]
+pub const fn timeout_or_default(explicit: &[u64]) -> u64 {
+    if explicit.is_empty() {
+        DEFAULT_TIMEOUT_MS
+    } else {
+        explicit[0]
+    }
+}
+
+/// Outcome of a whole test run.
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+}
+
+/// Runs every test in [`SUITES`], reporting results over serial.
+pub fn run_all() -> Summary {
+    crate::trace_fn!();
+
+    let total: usize = SUITES.iter().map(|suite| suite.len()).sum();
+    let mut summary = Summary { passed: 0, failed: 0 };
+
+    serial_println!("ktest: running {} tests", total);
+
+    for test in SUITES.iter().flat_map(|suite| suite.iter()) {
+        let start = counter::read();
+        let panicked = run_isolated(test);
+        let elapsed_ms = counter::millis_since(start);
+
+        if panicked {
+            serial_println!("ktest: {} ... FAILED: {}", test.name, last_panic_message());
+            summary.failed += 1;
+        } else if elapsed_ms > test.timeout_ms {
+            serial_println!(
+                "ktest: {} ... TIMEOUT ({} ms, budget {} ms)",
+                test.name, elapsed_ms, test.timeout_ms
+            );
+            summary.failed += 1;
+        } else {
+            serial_println!("ktest: {} ... ok ({} ms)", test.name, elapsed_ms);
+            summary.passed += 1;
+        }
+    }
+
+    serial_println!("ktest: {} passed, {} failed", summary.passed, summary.failed);
+    summary
+}
+
+/// Called first thing by the kernel panic handler. If a test is running, records the panic
+/// message and resumes the runner, so this only returns for panics outside of tests.
+pub fn handle_panic(info: &core::panic::PanicInfo) {
+    unsafe {
+        if !TEST_RUNNING {
+            return;
+        }
+
+        TEST_RUNNING = false;
+
+        let message = &raw mut PANIC_MESSAGE;
+        (*message).len = 0;
+        _ = write!(*message, "{}", info.message());
+
+        Current::resume_context(RECOVERY_POINT);
+    }
+}
+
+// Message recorded by the most recent test panic
+fn last_panic_message() -> &'static str {
+    unsafe {
+        let message = &raw const PANIC_MESSAGE;
+        (*message).as_str()
+    }
+}
+
+// Runs a single test, returning `true` if it panicked
+fn run_isolated(test: &KernelTest) -> bool {
+    unsafe {
+        let mut point = <Current as Arch>::Context::default();
+        RECOVERY_POINT = &raw mut point;
+
+        TEST_RUNNING = true;
+        let panicked = Current::call_with_context(&raw mut point, call_test, test as *const KernelTest as *const ());
+        TEST_RUNNING = false;
+
+        panicked
+    }
+}
+
+extern "C" fn call_test(test: *const ()) {
+    let test = unsafe { &*(test as *const KernelTest) };
+    (test.func)();
+}
+
+/// Runs `f` from inside a test and returns whether it panicked, for code that is meant to. The
+/// panic is recovered as a failing test's is, so `f` should not leave global state
+/// half-modified either.
+pub fn panics(f: fn()) -> bool {
+    unsafe {
+        assert!(TEST_RUNNING, "ktest: panics() called outside a test");
+
+        let outer = RECOVERY_POINT;
+        let mut point = <Current as Arch>::Context::default();
+        RECOVERY_POINT = &raw mut point;
+
+        let panicked = Current::call_with_context(&raw mut point, call_fn, f as *const ());
+
+        // A recovered panic ended "the test"; the one calling us is still running
+        RECOVERY_POINT = outer;
+        TEST_RUNNING = true;
+
+        panicked
+    }
+}
+
+extern "C" fn call_fn(f: *const ()) {
+    let f = unsafe { core::mem::transmute::<*const (), fn()>(f) };
+    f();
+}
+
+// =========================================================================
+// Panic recovery
+// =========================================================================
+
+// Runner context captured when the current test started, resumed if the test panics
+static mut RECOVERY_POINT: *const <Current as Arch>::Context = core::ptr::null();
+
+// Set while a test body is executing
+static mut TEST_RUNNING: bool = false;
+
+// Message of the last test panic
+static mut PANIC_MESSAGE: MessageBuffer = MessageBuffer { bytes: [0; 128], len: 0 };
+
+/// Fixed-size buffer for panic messages; anything past its capacity is dropped.
+struct MessageBuffer {
+    bytes: [u8; 128],
+    len: usize,
+}
+
+impl MessageBuffer {
+    fn as_str(&self) -> &str {
+        // Truncation may split a multi-byte character; show what is valid
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(s) => s,
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&self.bytes[..e.valid_up_to()]) },
+        }
+    }
+}
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
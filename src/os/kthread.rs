@@ -0,0 +1,180 @@
+//! Kernel threads.
+//!
+//! A kernel thread is a process that runs a kernel function on a kernel stack of its own and is
+//! scheduled like any other. [`spawn_kthread`] gives it a PID, a [`KERNEL_STACK_SIZE`] stack
+//! from the frame allocator and a kernel context that starts the function, and admits it to
+//! the ready queue. Its parent is the kernel, so it is reaped as soon as it exits, which it
+//! does when the function returns or through [`exit_current`].
+//!
+//! A thread cannot free the stack it is still running on, so an exiting one leaves its stack
+//! to whatever runs next on its CPU, which frees it with [`reclaim_stack`] right after the
+//! switch. The stack of a thread that is reaped without exiting itself goes with [`release`].
+
+use crate::os::arch::{Arch, Current};
+use crate::os::compaction;
+use crate::os::errno::{Errno, KResult};
+use crate::os::exit;
+use crate::os::memory::FRAME_SIZE;
+use crate::os::percpu::{self, PerCpu};
+use crate::os::pid;
+use crate::os::process::{KERNEL_STACK_SIZE, Process};
+use crate::os::ptable;
+use crate::os::sched;
+
+// Parent of every kernel thread
+const KERNEL_PID: u64 = 0;
+
+const STACK_FRAMES: u64 = (KERNEL_STACK_SIZE as u64).div_ceil(FRAME_SIZE);
+
+// Stack of the thread that last exited on each CPU, until the next context to run there frees it
+static DEAD_STACK: PerCpu<usize> = PerCpu::new(0);
+
+/// Starts a kernel thread running `entry` at scheduling priority `priority` and returns its
+/// PID. Fails with `EAGAIN` when no PID or process table slot is free and `ENOMEM` when there
+/// is no memory for its stack.
+pub fn spawn_kthread(entry: fn(), priority: u8) -> KResult<u64> {
+    let pid = pid::alloc()?;
+
+    let Some(stack) = compaction::alloc_contiguous(STACK_FRAMES, FRAME_SIZE) else {
+        pid::free(pid);
+        return Err(Errno::ENOMEM);
+    };
+
+    let mut process = Process::new(pid, KERNEL_PID, "kthread");
+    process.priority = priority;
+    process.kernel_stack = stack as usize;
+    sched::init_context(&mut process, kthread_main, entry as usize);
+
+    if ptable::insert(process).is_err() {
+        compaction::free_contiguous(stack, STACK_FRAMES);
+        pid::free(pid);
+        return Err(Errno::EAGAIN);
+    }
+
+    sched::admit(pid)?;
+    Ok(pid)
+}
+
+// First code a kernel thread runs, on its own stack
+extern "C" fn kthread_main(entry: usize) -> ! {
+    // Switched to from inside the scheduler, which masks interrupts; a resumed context
+    // restores its own state, a new one starts with them enabled
+    reclaim_stack();
+    Current::enable_interrupts();
+
+    let entry = unsafe { core::mem::transmute::<usize, fn()>(entry) };
+    entry();
+
+    exit_current(0)
+}
+
+/// Ends the calling kernel thread, or the process whose `exit` syscall calls it, with exit
+/// code `code`.
+pub fn exit_current(code: i32) -> ! {
+    // Nothing else may run on this CPU between handing the stack over and leaving it
+    Current::disable_interrupts();
+
+    let pid = percpu::current_pid();
+    let stack = ptable::with_process(pid, |process| core::mem::take(&mut process.kernel_stack)).unwrap_or(0);
+    DEAD_STACK.with(|dead| *dead = stack);
+
+    if let Err(err) = exit::exit(pid, code) {
+        panic!("kthread: {} cannot exit: {:?}", pid, err);
+    }
+
+    sched::schedule();
+    unreachable!("kthread: {} was scheduled after exiting", pid);
+}
+
+/// Frees the stack of a thread that exited on this CPU. Called after every context switch,
+/// by the context switched to: the thread is off the stack by then.
+pub fn reclaim_stack() {
+    let stack = DEAD_STACK.with(core::mem::take);
+
+    if stack != 0 {
+        compaction::free_contiguous(stack as u64, STACK_FRAMES);
+    }
+}
+
+/// Frees the kernel stack of `process`, which is being reaped.
+pub fn release(process: &mut Process) {
+    let stack = core::mem::take(&mut process.kernel_stack);
+
+    if stack != 0 {
+        compaction::free_contiguous(stack as u64, STACK_FRAMES);
+    }
+}
+
+pub mod ktests {
+    use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+
+    use super::*;
+
+    use crate::os::memory;
+    use crate::os::timer;
+
+    static TRACE: [AtomicU8; 4] = [const { AtomicU8::new(0) }; 4];
+    static TRACED: AtomicUsize = AtomicUsize::new(0);
+
+    fn record(thread: u8) {
+        TRACE[TRACED.fetch_add(1, Ordering::Relaxed)].store(thread, Ordering::Relaxed);
+    }
+
+    fn thread_a() {
+        record(b'a');
+        sched::yield_now();
+        record(b'a');
+    }
+
+    fn thread_b() {
+        record(b'b');
+        sched::yield_now();
+        record(b'b');
+    }
+
+    static STAMP: AtomicU64 = AtomicU64::new(0);
+
+    fn stamp() {
+        let stamp = ptable::with_process(percpu::current_pid(), |p| p.last_scheduled).unwrap();
+        STAMP.store(stamp, Ordering::Relaxed);
+    }
+
+    fn alive(pid: u64) -> bool {
+        ptable::with_process(pid, |_| ()).is_some()
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn threads_take_turns_and_free_their_stacks() {
+            TRACED.store(0, Ordering::Relaxed);
+            let free = memory::stats().free;
+
+            let a = spawn_kthread(thread_a, 3).unwrap();
+            let b = spawn_kthread(thread_b, 3).unwrap();
+            assert_eq!(ptable::with_process(a, |p| p.priority), Some(3));
+            assert_eq!(memory::stats().free, free - 2 * STACK_FRAMES * FRAME_SIZE);
+
+            while alive(a) || alive(b) {
+                sched::yield_now();
+            }
+
+            let trace = TRACE.each_ref().map(|thread| thread.load(Ordering::Relaxed));
+            assert_eq!(TRACED.load(Ordering::Relaxed), 4);
+            assert_ne!(trace[0], trace[1]);
+            assert_eq!(trace.iter().filter(|&&thread| thread == b'a').count(), 2);
+
+            // Exited and reaped, the last stack freed once we ran again
+            assert_eq!(memory::stats().free, free);
+        }
+
+        fn switching_in_stamps_last_scheduled() {
+            let before = timer::current_tick();
+            let pid = spawn_kthread(stamp, 0).unwrap();
+
+            while alive(pid) {
+                sched::yield_now();
+            }
+
+            assert!((before..=timer::current_tick()).contains(&STAMP.load(Ordering::Relaxed)));
+        }
+    }
+}
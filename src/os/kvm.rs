@@ -0,0 +1,335 @@
+//! KVM guest support.
+//!
+//! When running under KVM (detected through the hypervisor CPUID leaves) the kernel uses:
+//! - kvmclock: per-vCPU time information the host keeps up to date in guest memory, which
+//!   stays correct across host frequency changes and migration, as the preferred clocksource
+//! - PV EOI: the host tells us when an interrupt needs no EOI, saving the APIC-write exit
+//! - PV unhalt: a spinning vCPU can halt and be kicked by the lock holder instead of burning
+//!   host CPU time while the holder is descheduled; skipped when the host says vCPUs are
+//!   dedicated, where plain spinning is cheaper
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::os::arch;
+use crate::os::arch::x86_64::msr::Msr;
+use crate::os::clocksource::{self, Clocksource};
+use crate::os::percpu::{self, MAX_CPUS};
+use crate::os::errno::KResult;
+use crate::os::power::{self, PowerManaged};
+
+// Hypervisor CPUID leaves
+const CPUID_SIGNATURE: u32 = 0x4000_0000;
+const CPUID_FEATURES: u32 = 0x4000_0001;
+
+// Feature bits (CPUID_FEATURES EAX)
+const FEATURE_CLOCKSOURCE: u32 = 1 << 0;
+const FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const FEATURE_PV_EOI: u32 = 1 << 6;
+const FEATURE_PV_UNHALT: u32 = 1 << 7;
+const FEATURE_CLOCKSOURCE_STABLE: u32 = 1 << 24;
+
+// Hint bits (CPUID_FEATURES EDX)
+const HINT_REALTIME: u32 = 1 << 0;
+
+// Paravirtual MSRs: the legacy pair and the CLOCKSOURCE2 ones
+const MSR_WALL_CLOCK: Msr = Msr(0x11);
+const MSR_SYSTEM_TIME: Msr = Msr(0x12);
+const MSR_WALL_CLOCK_NEW: Msr = Msr(0x4b56_4d00);
+const MSR_SYSTEM_TIME_NEW: Msr = Msr(0x4b56_4d01);
+const MSR_PV_EOI_EN: Msr = Msr(0x4b56_4d04);
+
+// Low bit of the system time and PV EOI MSRs: enable
+const MSR_ENABLE: u64 = 1;
+
+// pvclock flags
+const PVCLOCK_TSC_STABLE: u8 = 1 << 0;
+
+// Hypercall numbers
+const HC_KICK_CPU: u64 = 5;
+
+/// Per-vCPU time information, written by the host.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(64))]
+struct PvclockTimeInfo {
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+/// Host wall-clock time at the moment the guest's system time was zero.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+struct PvclockWallClock {
+    version: u32,
+    sec: u32,
+    nsec: u32,
+}
+
+static mut TIME_INFO: [PvclockTimeInfo; MAX_CPUS] = [PvclockTimeInfo {
+    version: 0,
+    pad0: 0,
+    tsc_timestamp: 0,
+    system_time: 0,
+    tsc_to_system_mul: 0,
+    tsc_shift: 0,
+    flags: 0,
+    pad: [0; 2],
+}; MAX_CPUS];
+
+static mut WALL_CLOCK: PvclockWallClock = PvclockWallClock { version: 0, sec: 0, nsec: 0 };
+
+/// One PV EOI word per CPU; the host sets bit 0 when the EOI for the current interrupt may
+/// be skipped. Each word sits in its own cache line.
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct PvEoiWord(u32);
+
+static mut PV_EOI: [PvEoiWord; MAX_CPUS] = [PvEoiWord(0); MAX_CPUS];
+
+static DETECTED: AtomicBool = AtomicBool::new(false);
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+static HINTS: AtomicU32 = AtomicU32::new(0);
+
+// Largest value kvmclock returned, to keep it monotonic across vCPUs without a stable TSC
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+fn has(feature: u32) -> bool {
+    FEATURES.load(Ordering::Relaxed) & feature != 0
+}
+
+/// Returns `true` if the kernel runs as a KVM guest.
+pub fn detected() -> bool {
+    DETECTED.load(Ordering::Relaxed)
+}
+
+/// Detects KVM and sets up the paravirtual features for the boot CPU. Called once at boot
+/// after the per-CPU area exists.
+pub fn init() {
+    // CPUID.1:ECX bit 31: running under a hypervisor
+    if __cpuid(1).ecx & (1 << 31) == 0 {
+        return;
+    }
+
+    let signature = __cpuid(CPUID_SIGNATURE);
+    let mut vendor = [0u8; 12];
+    vendor[0..4].copy_from_slice(&signature.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&signature.ecx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&signature.edx.to_le_bytes());
+
+    if &vendor != b"KVMKVMKVM\0\0\0" || signature.eax < CPUID_FEATURES {
+        return;
+    }
+
+    let leaf = __cpuid(CPUID_FEATURES);
+    FEATURES.store(leaf.eax, Ordering::Relaxed);
+    HINTS.store(leaf.edx, Ordering::Relaxed);
+    DETECTED.store(true, Ordering::Relaxed);
+
+    init_cpu();
+
+    if has(FEATURE_CLOCKSOURCE | FEATURE_CLOCKSOURCE2) {
+        clocksource::register(&KVM_CLOCK).expect("kvm: cannot register kvmclock");
+    }
+
+    if let Err(errno) = power::register(&KVM_CLOCK) {
+        log::warn!("kvm: cannot register for resume: {:?}", errno);
+    }
+
+    log::info!(
+        "kvm: features {:#x}, pv eoi {}, pv unhalt {}",
+        leaf.eax,
+        has(FEATURE_PV_EOI),
+        spin_policy() == SpinPolicy::HaltAndKick
+    );
+}
+
+/// Points the host at this CPU's kvmclock and PV EOI areas. Called by every CPU as it comes up
+/// (and again after resume, since sleeping resets the MSRs).
+pub fn init_cpu() {
+    if !detected() {
+        return;
+    }
+
+    let cpu = percpu::cpu_id();
+    let (system_time_msr, wall_clock_msr) = if has(FEATURE_CLOCKSOURCE2) {
+        (MSR_SYSTEM_TIME_NEW, MSR_WALL_CLOCK_NEW)
+    } else {
+        (MSR_SYSTEM_TIME, MSR_WALL_CLOCK)
+    };
+
+    unsafe {
+        if has(FEATURE_CLOCKSOURCE | FEATURE_CLOCKSOURCE2) {
+            let info = &raw mut TIME_INFO;
+            system_time_msr.write(&raw mut (*info)[cpu] as u64 | MSR_ENABLE);
+
+            // The wall clock is a one-shot snapshot written on each MSR write
+            if cpu == 0 {
+                wall_clock_msr.write(&raw mut WALL_CLOCK as u64);
+            }
+        }
+
+        if has(FEATURE_PV_EOI) {
+            let words = &raw mut PV_EOI;
+            (*words)[cpu].0 = 0;
+            MSR_PV_EOI_EN.write(&raw mut (*words)[cpu] as u64 | MSR_ENABLE);
+        }
+    }
+}
+
+// Reads a structure the host updates under a version counter (odd while an update runs)
+fn read_versioned<T: Copy>(data: *const T, version: *const u32) -> T {
+    loop {
+        let before = unsafe { ptr::read_volatile(version) };
+        core::sync::atomic::fence(Ordering::Acquire);
+        let value = unsafe { ptr::read_volatile(data) };
+        core::sync::atomic::fence(Ordering::Acquire);
+        let after = unsafe { ptr::read_volatile(version) };
+
+        if before & 1 == 0 && before == after {
+            return value;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// Nanoseconds of guest system time on this vCPU.
+fn kvmclock_ns() -> u64 {
+    // Stay on one vCPU while reading its time info
+    let info = arch::without_interrupts(|| unsafe {
+        let info = &raw const TIME_INFO;
+        let entry = &raw const (*info)[percpu::cpu_id()];
+        read_versioned(entry, &raw const (*entry).version)
+    });
+
+    let mut delta = crate::os::arch::counter::read().wrapping_sub(info.tsc_timestamp);
+    if info.tsc_shift < 0 {
+        delta >>= -info.tsc_shift as u32;
+    } else {
+        delta <<= info.tsc_shift as u32;
+    }
+
+    let ns = info.system_time + ((delta as u128 * info.tsc_to_system_mul as u128) >> 32) as u64;
+
+    // Each vCPU's clock is monotonic on its own; across vCPUs only when the host says so
+    if info.flags & PVCLOCK_TSC_STABLE != 0 && has(FEATURE_CLOCKSOURCE_STABLE) {
+        return ns;
+    }
+
+    LAST_NS.fetch_max(ns, Ordering::Relaxed).max(ns)
+}
+
+/// Wall-clock time at boot (guest system time zero), in nanoseconds since the Unix epoch, as
+/// reported by the host. `None` when not running on KVM.
+pub fn boot_wall_clock_ns() -> Option<u64> {
+    if !detected() || !has(FEATURE_CLOCKSOURCE | FEATURE_CLOCKSOURCE2) {
+        return None;
+    }
+
+    let wall = unsafe {
+        let wall = &raw const WALL_CLOCK;
+        read_versioned(wall, &raw const (*wall).version)
+    };
+
+    Some(wall.sec as u64 * 1_000_000_000 + wall.nsec as u64)
+}
+
+/// kvmclock as a clocksource; also re-arms the MSRs on resume.
+struct KvmClock;
+
+impl Clocksource for KvmClock {
+    fn name(&self) -> &'static str {
+        "kvm-clock"
+    }
+
+    fn rating(&self) -> u32 {
+        400
+    }
+
+    fn read_ns(&self) -> u64 {
+        kvmclock_ns()
+    }
+}
+
+impl PowerManaged for KvmClock {
+    fn name(&self) -> &'static str {
+        "kvm"
+    }
+
+    fn suspend(&self) -> KResult<()> {
+        Ok(())
+    }
+
+    fn resume(&self) {
+        init_cpu();
+    }
+}
+
+static KVM_CLOCK: KvmClock = KvmClock;
+
+/// Called by the local APIC EOI path: returns `true` if the host has flagged the current
+/// interrupt as needing no EOI (and consumes the flag), in which case the APIC write is skipped.
+pub fn pv_eoi_skip() -> bool {
+    if !has(FEATURE_PV_EOI) {
+        return false;
+    }
+
+    // The flag must be tested and cleared atomically against the host setting it
+    let cpu = percpu::cpu_id();
+    let cleared: u8;
+    unsafe {
+        let words = &raw mut PV_EOI;
+        let word = &raw mut (*words)[cpu].0;
+        asm!("btr dword ptr [{}], 0", "setc {}", in(reg) word, out(reg_byte) cleared, options(nostack));
+    }
+
+    cleared != 0
+}
+
+/// How a CPU waiting for a contended lock should wait.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpinPolicy {
+    /// Busy-wait: bare metal, or vCPUs pinned to dedicated host CPUs.
+    Spin,
+
+    /// Halt and let the lock holder kick this vCPU with [`kick_cpu`] on release.
+    HaltAndKick,
+}
+
+/// The waiting strategy spinlocks should use.
+pub fn spin_policy() -> SpinPolicy {
+    if detected() && has(FEATURE_PV_UNHALT) && HINTS.load(Ordering::Relaxed) & HINT_REALTIME == 0 {
+        SpinPolicy::HaltAndKick
+    } else {
+        SpinPolicy::Spin
+    }
+}
+
+/// Wakes the halted vCPU `cpu` (see [`SpinPolicy::HaltAndKick`]).
+pub fn kick_cpu(cpu: usize) {
+    hypercall(HC_KICK_CPU, 0, percpu::hardware_id(cpu) as u64);
+}
+
+// VMCALL on Intel, VMMCALL on AMD. The first argument goes in RBX, which LLVM reserves, so it
+// is swapped in and out around the call
+fn hypercall(nr: u64, a0: u64, a1: u64) -> u64 {
+    let result: u64;
+
+    unsafe {
+        if crate::os::cpu::features().vendor == *b"AuthenticAMD" {
+            asm!("xchg {a0}, rbx", "vmmcall", "xchg {a0}, rbx", a0 = inout(reg) a0 => _, inlateout("rax") nr => result, in("rcx") a1, options(nostack));
+        } else {
+            asm!("xchg {a0}, rbx", "vmcall", "xchg {a0}, rbx", a0 = inout(reg) a0 => _, inlateout("rax") nr => result, in("rcx") a1, options(nostack));
+        }
+    }
+
+    result
+}
@@ -0,0 +1,178 @@
+//! Kernel memory leak detector, enabled by the `leakcheck` feature.
+//!
+//! The heap and slab allocators report every allocation and free here. Each live allocation is
+//! stored with a short return-address trace of whoever allocated it and the counter time it was made,
+//! so [`report`] can list allocations that are still outstanding after a settling period,
+//! grouped by call site. Without the feature the hooks compile to nothing.
+
+#[cfg(feature = "leakcheck")]
+use crate::os::arch::{counter, frame};
+#[cfg(feature = "leakcheck")]
+use crate::serial_println;
+
+/// Number of return addresses recorded per allocation.
+pub const TRACE_DEPTH: usize = 4;
+
+/// A live allocation as seen by the leak detector.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub addr: usize,
+    pub size: usize,
+
+    /// Return addresses of the allocating call chain, innermost first (0 = not captured).
+    pub trace: [usize; TRACE_DEPTH],
+
+    /// Allocation sequence number, used to scope reports to a window of activity.
+    pub seq: u64,
+
+    /// Architecture counter value at allocation time.
+    pub timestamp: u64,
+}
+
+/// Summary of leaked allocations sharing one call site.
+#[derive(Debug, Clone, Copy)]
+pub struct LeakGroup {
+    pub trace: [usize; TRACE_DEPTH],
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[cfg(feature = "leakcheck")]
+mod table {
+    use super::Allocation;
+
+    /// Maximum number of simultaneously tracked allocations; further ones are counted as dropped.
+    pub const MAX_TRACKED: usize = 2048;
+
+    pub static mut ENTRIES: [Option<Allocation>; MAX_TRACKED] = [None; MAX_TRACKED];
+
+    // Allocations that did not fit into ENTRIES and are therefore invisible to reports
+    pub static mut DROPPED: usize = 0;
+
+    // Sequence number handed to the next allocation
+    pub static mut NEXT_SEQ: u64 = 1;
+}
+
+/// Heap hook: records a new allocation. `skip` is the number of allocator-internal frames to leave
+/// out of the recorded trace.
+#[inline]
+pub fn record_alloc(addr: usize, size: usize, skip: usize) {
+    #[cfg(feature = "leakcheck")]
+    unsafe {
+        let mut trace = [0; TRACE_DEPTH];
+        frame::return_addresses(skip + 1, &mut trace);
+
+        let seq = table::NEXT_SEQ;
+        table::NEXT_SEQ += 1;
+
+        let entries = &raw mut table::ENTRIES;
+        match (*entries).iter_mut().find(|e| e.is_none()) {
+            Some(slot) => *slot = Some(Allocation { addr, size, trace, seq, timestamp: counter::read() }),
+            None => table::DROPPED += 1,
+        }
+    }
+
+    #[cfg(not(feature = "leakcheck"))]
+    {
+        _ = (addr, size, skip);
+    }
+}
+
+/// Heap hook: forgets the allocation at `addr`.
+#[inline]
+pub fn record_free(addr: usize) {
+    #[cfg(feature = "leakcheck")]
+    unsafe {
+        let entries = &raw mut table::ENTRIES;
+        if let Some(slot) = (*entries).iter_mut().find(|e| e.is_some_and(|a| a.addr == addr)) {
+            *slot = None;
+        }
+    }
+
+    #[cfg(not(feature = "leakcheck"))]
+    {
+        _ = addr;
+    }
+}
+
+/// Returns the sequence number the next allocation will get. Pass it to [`report`] to only look
+/// at allocations made from this point on.
+pub fn mark() -> u64 {
+    #[cfg(feature = "leakcheck")]
+    return unsafe { table::NEXT_SEQ };
+
+    #[cfg(not(feature = "leakcheck"))]
+    return 0;
+}
+
+/// Groups outstanding allocations made since `since` (a [`mark`]) that are older than
+/// `settle_ms`, writing up to `out.len()` call-site groups. Returns the number of groups.
+pub fn collect(since: u64, settle_ms: u64, out: &mut [LeakGroup]) -> usize {
+    #[cfg(feature = "leakcheck")]
+    return unsafe {
+        let mut groups = 0;
+        let entries = &raw const table::ENTRIES;
+
+        let leaked = (*entries)
+            .iter()
+            .flatten()
+            .filter(|a| a.seq >= since && counter::millis_since(a.timestamp) >= settle_ms);
+
+        for allocation in leaked {
+            match out[..groups].iter().position(|g| g.trace == allocation.trace) {
+                Some(i) => {
+                    out[i].count += 1;
+                    out[i].bytes += allocation.size;
+                }
+                None if groups < out.len() => {
+                    out[groups] = LeakGroup { trace: allocation.trace, count: 1, bytes: allocation.size };
+                    groups += 1;
+                }
+                None => {}
+            }
+        }
+
+        groups
+    };
+
+    #[cfg(not(feature = "leakcheck"))]
+    {
+        _ = (since, settle_ms, out);
+        0
+    }
+}
+
+/// Prints outstanding allocations made since `since` that survived `settle_ms`, grouped by call
+/// site, to the serial port. Returns the number of leaked allocations.
+pub fn report(since: u64, settle_ms: u64) -> usize {
+    #[cfg(feature = "leakcheck")]
+    return {
+        let mut groups = [LeakGroup { trace: [0; TRACE_DEPTH], count: 0, bytes: 0 }; 32];
+        let found = collect(since, settle_ms, &mut groups);
+        let mut total = 0;
+
+        for group in &groups[..found] {
+            serial_println!("leak: {} allocations, {} bytes, allocated from:", group.count, group.bytes);
+
+            for addr in group.trace.iter().take_while(|a| **a != 0) {
+                serial_println!("leak:     {:#018x}", addr);
+            }
+
+            total += group.count;
+        }
+
+        let dropped = unsafe { table::DROPPED };
+        if dropped > 0 {
+            serial_println!("leak: {} allocations were not tracked (table full)", dropped);
+        }
+
+        serial_println!("leak: {} outstanding allocations in {} call sites", total, found);
+        total
+    };
+
+    #[cfg(not(feature = "leakcheck"))]
+    {
+        _ = (since, settle_ms);
+        0
+    }
+}
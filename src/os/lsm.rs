@@ -0,0 +1,187 @@
+//! Security module hook points.
+//!
+//! Subsystems call the `check_*` functions below at a handful of well-defined points, after
+//! their own discretionary checks (permission bits, capabilities) have passed. A compiled-in
+//! security module implementing [`SecurityModule`] can then veto the operation with an errno,
+//! which lets mandatory access control policies be tried out without touching every
+//! subsystem. With no module registered every hook allows everything.
+
+use crate::os::audit::{self, AuditKind};
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::Process;
+
+/// A security policy. Every hook defaults to allowing the operation, so a module only
+/// implements the ones it cares about.
+pub trait SecurityModule: Sync {
+    /// Short name used in log messages.
+    fn name(&self) -> &'static str;
+
+    /// `process` opens `path` with the given [`crate::os::cred::access`] bits.
+    fn file_open(&self, process: &Process, path: &str, access: u16) -> KResult<()> {
+        _ = (process, path, access);
+        Ok(())
+    }
+
+    /// `process` replaces its image with the program at `path`.
+    fn exec(&self, process: &Process, path: &str) -> KResult<()> {
+        _ = (process, path);
+        Ok(())
+    }
+
+    /// `process` creates a socket of the given domain and type.
+    fn socket_create(&self, process: &Process, domain: u32, kind: u32) -> KResult<()> {
+        _ = (process, domain, kind);
+        Ok(())
+    }
+
+    /// `sender` sends `signal` to `target`.
+    fn kill(&self, sender: &Process, target: &Process, signal: u32) -> KResult<()> {
+        _ = (sender, target, signal);
+        Ok(())
+    }
+
+    /// `process` mounts a filesystem of type `fs_type` on `target`.
+    fn mount(&self, process: &Process, fs_type: &str, target: &str) -> KResult<()> {
+        _ = (process, fs_type, target);
+        Ok(())
+    }
+}
+
+/// Maximum number of stacked security modules.
+const MAX_MODULES: usize = 4;
+
+// Registered modules, consulted in registration order; the first veto wins
+static mut MODULES: [Option<&'static dyn SecurityModule>; MAX_MODULES] = [None; MAX_MODULES];
+
+/// Registers a security module. Must be called during boot, before the first process runs,
+/// so every operation is seen by the same set of modules.
+pub fn register(module: &'static dyn SecurityModule) -> KResult<()> {
+    unsafe {
+        let modules = &raw mut MODULES;
+
+        let slot = (*modules).iter_mut().find(|m| m.is_none()).ok_or(Errno::ENOSPC)?;
+        *slot = Some(module);
+    }
+
+    log::info!("lsm: registered security module '{}'", module.name());
+    Ok(())
+}
+
+// Runs `hook` against every registered module, stopping at the first veto
+fn call(process: &Process, hook: impl Fn(&dyn SecurityModule) -> KResult<()>) -> KResult<()> {
+    let modules = unsafe {
+        let modules = &raw const MODULES;
+        *modules
+    };
+
+    for module in modules.iter().flatten() {
+        if let Err(errno) = hook(*module) {
+            log::debug!("lsm: '{}' denied operation by pid {}: {:?}", module.name(), process.pid, errno);
+            audit::record_cred(AuditKind::SecurityModuleDenied, process.pid, &process.cred, [errno as u64, 0]);
+            return Err(errno);
+        }
+    }
+
+    Ok(())
+}
+
+/// Hook: file open (VFS).
+pub fn check_file_open(process: &Process, path: &str, access: u16) -> KResult<()> {
+    call(process, |m| m.file_open(process, path, access))
+}
+
+/// Hook: exec (program loader).
+pub fn check_exec(process: &Process, path: &str) -> KResult<()> {
+    call(process, |m| m.exec(process, path))
+}
+
+/// Hook: socket creation (network stack).
+pub fn check_socket_create(process: &Process, domain: u32, kind: u32) -> KResult<()> {
+    call(process, |m| m.socket_create(process, domain, kind))
+}
+
+/// Hook: signal delivery (`kill`).
+pub fn check_kill(sender: &Process, target: &Process, signal: u32) -> KResult<()> {
+    call(sender, |m| m.kill(sender, target, signal))
+}
+
+/// Hook: mount (VFS).
+pub fn check_mount(process: &Process, fs_type: &str, target: &str) -> KResult<()> {
+    call(process, |m| m.mount(process, fs_type, target))
+}
+
+pub mod ktests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    use crate::os::cred::access;
+    use crate::os::file::O_RDONLY;
+    use crate::os::{jobctl, ptable, vfs};
+
+    // The only process the test module has an opinion on
+    const DENIED_PID: u64 = 9985;
+
+    // Denies everything `DENIED_PID` does and every signal sent to it
+    struct DenyOne;
+
+    impl SecurityModule for DenyOne {
+        fn name(&self) -> &'static str {
+            "deny-one"
+        }
+
+        fn file_open(&self, process: &Process, _path: &str, _access: u16) -> KResult<()> {
+            if process.pid == DENIED_PID { Err(Errno::EACCES) } else { Ok(()) }
+        }
+
+        fn exec(&self, process: &Process, _path: &str) -> KResult<()> {
+            if process.pid == DENIED_PID { Err(Errno::EACCES) } else { Ok(()) }
+        }
+
+        fn socket_create(&self, process: &Process, _domain: u32, _kind: u32) -> KResult<()> {
+            if process.pid == DENIED_PID { Err(Errno::EACCES) } else { Ok(()) }
+        }
+
+        fn kill(&self, _sender: &Process, target: &Process, _signal: u32) -> KResult<()> {
+            if target.pid == DENIED_PID { Err(Errno::EPERM) } else { Ok(()) }
+        }
+
+        fn mount(&self, process: &Process, _fs_type: &str, _target: &str) -> KResult<()> {
+            if process.pid == DENIED_PID { Err(Errno::EPERM) } else { Ok(()) }
+        }
+    }
+
+    static DENY_ONE: DenyOne = DenyOne;
+
+    // Modules cannot be unregistered, so the test module goes in once per boot
+    static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+    crate::os::ktest::kernel_test! {
+        fn a_registered_module_vetoes_its_process() {
+            if !REGISTERED.swap(true, Ordering::Relaxed) {
+                register(&DENY_ONE).unwrap();
+            }
+
+            let denied = Process::new(DENIED_PID, 0, "denied");
+            let allowed = Process::new(9986, 0, "allowed");
+
+            assert_eq!(check_file_open(&denied, "/proc/meminfo", access::READ), Err(Errno::EACCES));
+            assert_eq!(check_file_open(&allowed, "/proc/meminfo", access::READ), Ok(()));
+            assert_eq!(check_exec(&denied, "/bin/init"), Err(Errno::EACCES));
+            assert_eq!(check_socket_create(&denied, 2, 2), Err(Errno::EACCES));
+            assert_eq!(check_mount(&denied, "proc", "/proc"), Err(Errno::EPERM));
+            assert_eq!(check_kill(&allowed, &denied, 15), Err(Errno::EPERM));
+            assert_eq!(check_kill(&denied, &allowed, 15), Ok(()));
+
+            // The hooks are reached from the VFS and kill(), after the permission checks pass
+            assert_eq!(vfs::open(&denied, "/proc/meminfo", O_RDONLY).err(), Some(Errno::EACCES));
+            assert!(vfs::open(&allowed, "/proc/meminfo", O_RDONLY).is_ok());
+
+            ptable::insert(denied).unwrap();
+            ptable::insert(allowed).unwrap();
+            assert_eq!(jobctl::sys_kill(9986, DENIED_PID as i64, 0), Err(Errno::EPERM));
+            ptable::remove(DENIED_PID);
+            ptable::remove(9986);
+        }
+    }
+}
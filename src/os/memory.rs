@@ -1,101 +1,626 @@
-use uefi::table::{Boot, SystemTable};               // Import UEFI Boot and SystemTable types
-use uefi::table::boot::{MemoryType};                 // Import MemoryType enum to classify memory regions
+use uefi::table::boot::{MemoryDescriptor, MemoryMap, MemoryType}; // Import the UEFI memory map, its entries and the MemoryType enum classifying them
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-// Define a simple struct to hold information about a usable memory region
-#[derive(Copy, Clone)]  // <-- Add this line
+use crate::os::arch::{Arch, Current};
+use crate::os::errno::{Errno, KResult};
+use crate::os::numa::{self, Placement};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::swap;
+use crate::os::sync::SpinLock;
+use crate::os::sysctl;
+
+
+/// What a stretch of physical memory is, as far as the kernel is concerned
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// RAM free for general use
+    Usable,
+
+    /// Reserved or unusable memory, persistent memory and firmware runtime services
+    Reserved,
+
+    /// ACPI tables, which may be reused once they have been read
+    AcpiReclaimable,
+
+    /// ACPI non-volatile storage, to be left alone for good
+    AcpiNvs,
+
+    /// Memory-mapped device registers
+    Mmio,
+}
+
+impl RegionKind {
+    // Kind of the memory the firmware calls `ty`. Memory held by the loader and boot services
+    // is RAM the kernel is using itself: neither free nor the firmware's, so not tracked
+    fn of(ty: MemoryType) -> Option<RegionKind> {
+        match ty {
+            MemoryType::CONVENTIONAL => Some(RegionKind::Usable),
+            MemoryType::LOADER_CODE | MemoryType::LOADER_DATA | MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => None,
+            MemoryType::ACPI_RECLAIM => Some(RegionKind::AcpiReclaimable),
+            MemoryType::ACPI_NON_VOLATILE => Some(RegionKind::AcpiNvs),
+            MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => Some(RegionKind::Mmio),
+            _ => Some(RegionKind::Reserved),
+        }
+    }
+}
+
+/// A stretch of physical memory of one kind, on one NUMA node
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MemoryRegion {
     pub start: u64,
     pub size: u64,
+
+    /// NUMA node the region's memory belongs to
+    pub node: u8,
+
+    pub kind: RegionKind,
 }
-// Maximum number of memory regions we will store
-const MAX_REGIONS: usize = 32;
 
-// Static mutable fixed-size array to store usable memory regions
-// Unsafe because mutable globals can cause data races if misused
-static mut USABLE_REGIONS: [MemoryRegion; MAX_REGIONS] = [MemoryRegion { start: 0, size: 0 }; MAX_REGIONS];
+impl MemoryRegion {
+    const EMPTY: Self = MemoryRegion { start: 0, size: 0, node: 0, kind: RegionKind::Reserved };
 
-// Static mutable counter of how many usable regions have been stored
-static mut REGION_COUNT: usize = 0;
+    /// Address just past the region
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
 
-// Function to scan UEFI memory map and store all usable (CONVENTIONAL) memory regions
-// Takes a reference to the UEFI SystemTable (Boot phase) to access boot services
-pub fn store_usable_memory_regions(system_table: &SystemTable<Boot>) {
-    // Get a reference to UEFI Boot Services from the system table
-    let bt = system_table.boot_services();
+/// One entry of the firmware memory map: `pages` 4 KiB pages from `start`, of type `ty`
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryMapEntry {
+    pub ty: MemoryType,
+    pub start: u64,
+    pub pages: u64,
+}
 
-    // Size of buffer allocated to hold the raw UEFI memory map data (16 KiB)
-    const MAP_SIZE: usize = 4096 * 4;
+// Memory map entries and regions that fit in static storage. A larger map gets storage sized
+// for it, carved from the end of its largest CONVENTIONAL entry
+const STATIC_MAP_ENTRIES: usize = 256;
+const STATIC_REGIONS: usize = 320;
 
-    // Define a buffer type that enforces 8-byte alignment (required by UEFI memory map)
-    #[repr(C, align(8))]
-    struct AlignedBuffer([u8; MAP_SIZE]);
+// Region slots needed beyond one per map entry: every boundary of an SRAT memory range may
+// split one region in two, and cutting out the carved storage may too
+const EXTRA_REGIONS: usize = 65;
 
-    // Static mutable buffer to hold memory map data from UEFI firmware
-    // Initialized with zeros
-    static mut MEMORY_MAP_BUFFER: AlignedBuffer = AlignedBuffer([0; MAP_SIZE]);
+static mut STATIC_MAP: [MemoryMapEntry; STATIC_MAP_ENTRIES] =
+    [MemoryMapEntry { ty: MemoryType::RESERVED, start: 0, pages: 0 }; STATIC_MAP_ENTRIES];
+static mut STATIC_REGION_SLOTS: [MemoryRegion; STATIC_REGIONS] = [MemoryRegion::EMPTY; STATIC_REGIONS];
 
-    // Query UEFI Boot Services for the current memory map size and related info
-    let mem_map_size = bt.memory_map_size();
+// The stored memory map and regions, the usable regions first, and the physical ranges the
+// kernel keeps out of the frame allocator's hands because of them. Written once at boot
+#[derive(Copy, Clone)]
+struct Stored {
+    map: &'static [MemoryMapEntry],
+    regions: &'static [MemoryRegion],
+    usable_len: usize,
 
-    // Calculate how much buffer space we actually need to hold the full memory map:
-    // Add extra space for up to 8 additional MemoryDescriptors to account for updates between calls
-    let needed = mem_map_size.map_size + 8 * core::mem::size_of::<uefi::table::boot::MemoryDescriptor>();
+    // [start, end) of the buffer the firmware wrote the final memory map into
+    map_buffer: (u64, u64),
 
-    // Create a mutable byte slice over our static buffer, allowing memory_map() to write into it
-    // Unsafe because we're accessing a mutable static variable
-    let buffer: &mut [u8] = unsafe { &mut MEMORY_MAP_BUFFER.0[..] };
+    // [start, end) carved out for a memory map too large for static storage
+    carved: (u64, u64),
+}
 
-    // Ensure our buffer is large enough; if not, panic with an error
-    assert!(buffer.len() >= needed, "UEFI memory map buffer too small");
+static STORED: SpinLock<Stored> =
+    SpinLock::new(Stored { map: &[], regions: &[], usable_len: 0, map_buffer: (0, 0), carved: (0, 0) });
 
-    // Call UEFI Boot Services to fill the buffer with the current memory map entries
-    // This returns a MemoryMap object wrapping the raw entries
-    // Panic with an error message if the call fails
-    let memory_map = bt
-        .memory_map(buffer)
-        .expect("Failed to retrieve UEFI memory map");
+fn stored() -> Stored {
+    STORED.with(|stored| *stored)
+}
 
-    // Unsafe block to modify global mutable state safely
-    unsafe {
-        // Reset the counter before starting to store new regions
-        REGION_COUNT = 0;
-
-        // Iterate over each memory descriptor entry in the memory map
-        for desc in memory_map.entries() {
-            // Check if the type of the memory region is CONVENTIONAL,
-            // which means it is general-purpose usable RAM
-            if desc.ty == MemoryType::CONVENTIONAL {
-                // Extract the physical start address of this memory region
-                let start = desc.phys_start;
-
-                // Extract how many 4 KiB pages this region spans
-                let pages = desc.page_count;
-
-                // Calculate the size in bytes (pages * 4096 bytes per page)
-                let size = pages * 4096;
-
-                // Check if we still have space in our static array to store this region
-                if REGION_COUNT < MAX_REGIONS {
-                    // Store the start address and size in the global array at the current index
-                    USABLE_REGIONS[REGION_COUNT] = MemoryRegion { start, size };
-
-                    // Increment the count of stored regions
-                    REGION_COUNT += 1;
-                } else {
-                    // If we run out of space, optionally break early or handle overflow here
-                    // For now, break the loop to avoid overwriting memory
-                    break;
+// The regions being built: `slots` filled up to `len`, and whether any did not fit
+struct RegionSlots<'a> {
+    slots: &'a mut [MemoryRegion],
+    len: usize,
+    dropped: bool,
+}
+
+impl RegionSlots<'_> {
+    // Adds [start, end) as `kind`, less the range `carved`, one region per NUMA node it spans
+    fn add(&mut self, kind: RegionKind, start: u64, end: u64, carved: (u64, u64)) {
+        for (mut start, end) in [(start, end.min(carved.0)), (start.max(carved.1), end)] {
+            while start < end {
+                let (node, node_end) = numa::node_span(start);
+                let piece_end = node_end.min(end);
+
+                match self.slots.get_mut(self.len) {
+                    Some(slot) => {
+                        *slot = MemoryRegion { start, size: piece_end - start, node, kind };
+                        self.len += 1;
+                    }
+                    None => self.dropped = true,
                 }
+                start = piece_end;
+            }
+        }
+    }
+}
+
+// Fills `slots` with the regions of `map`, which is sorted by address: first the usable ones,
+// then the rest, each in address order. Entries of a kind that touch are merged into one
+// region, and the range `carved` is left out. Returns how many regions are usable, how many
+// there are in all, and whether any did not fit
+fn build_regions(map: &[MemoryMapEntry], carved: (u64, u64), slots: &mut [MemoryRegion]) -> (usize, usize, bool) {
+    let mut regions = RegionSlots { slots, len: 0, dropped: false };
+    let mut usable_len = 0;
+
+    for usable in [true, false] {
+        let mut run: Option<(RegionKind, u64, u64)> = None;
+
+        for entry in map {
+            let Some(kind) = RegionKind::of(entry.ty) else {
+                continue;
+            };
+            if (kind == RegionKind::Usable) != usable {
+                continue;
+            }
+
+            let (start, end) = (entry.start, entry.start + entry.pages * FRAME_SIZE);
+            if let Some((run_kind, _, run_end)) = &mut run
+                && *run_kind == kind
+                && *run_end == start
+            {
+                *run_end = end;
+                continue;
+            }
+            if let Some((kind, start, end)) = run.replace((kind, start, end)) {
+                regions.add(kind, start, end, carved);
+            }
+        }
+        if let Some((kind, start, end)) = run {
+            regions.add(kind, start, end, carved);
+        }
+
+        if usable {
+            usable_len = regions.len;
+        }
+    }
+
+    (usable_len, regions.len, regions.dropped)
+}
+
+// Finds `bytes` of storage at the end of the largest CONVENTIONAL entry of `memory_map` with
+// room to spare, leaving its first frames, where the frame allocator keeps its bitmap, alone
+fn carve(memory_map: &MemoryMap, bytes: u64) -> Option<(u64, u64)> {
+    let bytes = bytes.next_multiple_of(FRAME_SIZE);
+    let desc = memory_map
+        .entries()
+        .filter(|desc| desc.ty == MemoryType::CONVENTIONAL && desc.page_count * FRAME_SIZE >= 2 * bytes)
+        .max_by_key(|desc| desc.page_count)?;
+
+    let end = desc.phys_start + desc.page_count * FRAME_SIZE;
+    Some((end - bytes, end))
+}
+
+// Function to copy the final UEFI memory map into kernel-owned storage and build the memory
+// regions from it. Called right after exiting boot services, with the map
+// `exit_boot_services` handed back; nothing reads the firmware's copy afterwards.
+//
+// The map is stored sorted by address, in static storage if it fits and otherwise in storage
+// sized for it and carved out of conventional memory, which then is no longer usable. Only
+// if no conventional entry can spare that much is the map truncated to what static storage
+// holds.
+pub fn store_memory_map(memory_map: &MemoryMap) {
+    crate::trace_fn!();
+
+    let mut stored = STORED.lock();
+    if !stored.map.is_empty() {
+        log::warn!("memory: memory map already stored, keeping the first");
+        return;
+    }
+
+    let entries = memory_map.entries().count();
+    let region_slots = entries + EXTRA_REGIONS;
+    let mut carved = (0, 0);
+
+    let mut storage = None;
+    if entries > STATIC_MAP_ENTRIES || region_slots > STATIC_REGIONS {
+        let map_bytes = (entries * size_of::<MemoryMapEntry>()).next_multiple_of(align_of::<MemoryRegion>());
+        let bytes = map_bytes + region_slots * size_of::<MemoryRegion>();
+
+        match carve(memory_map, bytes as u64) {
+            Some(range) => {
+                carved = range;
+                // Conventional memory nothing else uses yet, and never will: it is kept out of
+                // the usable regions
+                storage = Some(unsafe {
+                    (
+                        core::slice::from_raw_parts_mut(range.0 as *mut MemoryMapEntry, entries),
+                        core::slice::from_raw_parts_mut((range.0 + map_bytes as u64) as *mut MemoryRegion, region_slots),
+                    )
+                });
             }
+            None => log::warn!("memory: no room for a memory map of {} entries, truncating it", entries),
         }
     }
+
+    // The static storage is handed out here only, and this runs once
+    let (map, slots): (&'static mut [MemoryMapEntry], &'static mut [MemoryRegion]) =
+        storage.unwrap_or_else(|| unsafe { (&mut *(&raw mut STATIC_MAP), &mut *(&raw mut STATIC_REGION_SLOTS)) });
+
+    let mut map_len = 0;
+    let mut buffer = (0, 0);
+    for (desc, slot) in memory_map.entries().zip(map.iter_mut()) {
+        // The descriptors live in the buffer itself, so they bound it
+        let at = desc as *const MemoryDescriptor as u64;
+        let end = at + size_of::<MemoryDescriptor>() as u64;
+        buffer = if buffer.1 == 0 { (at, end) } else { (buffer.0.min(at), buffer.1.max(end)) };
+
+        *slot = MemoryMapEntry { ty: desc.ty, start: desc.phys_start, pages: desc.page_count };
+        map_len += 1;
+    }
+    if map_len < entries {
+        log::warn!("memory: firmware memory map truncated to {} entries", map_len);
+    }
+
+    let map = &mut map[..map_len];
+    map.sort_unstable_by_key(|entry| entry.start);
+
+    let (usable_len, regions_len, dropped) = build_regions(map, carved, slots);
+    if dropped {
+        log::warn!("memory: memory regions truncated to {}", regions_len);
+    }
+
+    *stored = Stored { map, regions: &slots[..regions_len], usable_len, map_buffer: buffer, carved };
+}
+
+/// Returns the firmware memory map stored when boot services were exited, sorted by address
+pub fn memory_map() -> &'static [MemoryMapEntry] {
+    stored().map
+}
+
+/// Returns the physical range `(start, end)` of the buffer holding the firmware's copy of the
+/// memory map, which must not be handed out as free memory
+pub fn memory_map_buffer() -> (u64, u64) {
+    stored().map_buffer
+}
+
+/// Returns the physical range `(start, end)` of the conventional memory taken to store a
+/// memory map too large for static storage, `(0, 0)` if none was. It is left out of the usable
+/// regions
+pub fn carved_storage() -> (u64, u64) {
+    stored().carved
 }
 
-/// Returns a slice of all stored usable memory regions
+/// Returns every stored memory region: the usable ones, then the rest, each in address order
+pub fn memory_regions() -> &'static [MemoryRegion] {
+    stored().regions
+}
+
+/// Returns the usable memory regions, in address order
 pub fn get_usable_memory_regions() -> &'static [MemoryRegion] {
+    let stored = stored();
+    &stored.regions[..stored.usable_len]
+}
+
+/// Returns the stored memory regions of kind `kind`
+pub fn regions_of(kind: RegionKind) -> impl Iterator<Item = MemoryRegion> {
+    memory_regions().iter().copied().filter(move |r| r.kind == kind)
+}
+
+/// Returns the usable memory regions that belong to NUMA node `node`
+pub fn regions_on_node(node: u8) -> impl Iterator<Item = MemoryRegion> {
+    get_usable_memory_regions().iter().copied().filter(move |r| r.node == node)
+}
+
+/// Returns the size of all usable memory, in bytes
+pub fn total_usable_bytes() -> u64 {
+    get_usable_memory_regions().iter().map(|r| r.size).sum()
+}
+
+/// Returns the largest usable memory region, or `None` if there is none
+pub fn largest_region() -> Option<MemoryRegion> {
+    get_usable_memory_regions().iter().copied().max_by_key(|r| r.size)
+}
+
+// Whether `regions`, sorted by address, cover all of [addr, addr + len)
+fn covers(regions: &[MemoryRegion], addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len.max(1)) else {
+        return false;
+    };
+
+    let mut at = addr;
+    for r in regions {
+        if r.start <= at && at < r.end() {
+            at = r.end();
+            if at >= end {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Returns whether the `len` bytes from physical address `addr` are all usable memory, which
+/// they may be across the boundary of two regions that touch
+pub fn is_usable(addr: u64, len: u64) -> bool {
+    covers(get_usable_memory_regions(), addr, len)
+}
+
+/// Size of a physical frame in bytes
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Operations the physical frame allocator offers to the rest of the kernel
+pub trait FrameAllocator: Sync {
+    /// Allocates one frame, placed according to `placement`, and returns its physical address
+    fn alloc_frame(&self, placement: Placement) -> Option<u64>;
+
+    /// Returns the frame at physical address `addr` to the free lists
+    fn free_frame(&self, addr: u64);
+
+    /// Number of frames currently free
+    fn free_frames(&self) -> usize;
+
+    /// Whether the frame at physical address `addr` is free. Allocators that cannot tell say
+    /// no, which leaves compaction nowhere to move pages to
+    fn is_free(&self, _addr: u64) -> bool {
+        false
+    }
+
+    /// Allocates the frame at physical address `addr` if it is free, returning whether it did
+    fn alloc_frame_at(&self, _addr: u64) -> bool {
+        false
+    }
+
+    /// Allocates `frames` physically contiguous frames starting at a multiple of `align`
+    /// bytes and returns the address of the first. Allocators that cannot find runs themselves
+    /// say no, leaving [`compaction::alloc_contiguous`](super::compaction::alloc_contiguous)
+    /// to assemble one frame by frame
+    fn alloc_contiguous(&self, _frames: u64, _align: u64) -> Option<u64> {
+        None
+    }
+}
+
+// The allocator in use, installed once it has been initialised from the usable regions
+static mut FRAME_ALLOCATOR: Option<&'static dyn FrameAllocator> = None;
+
+// Frames currently handed to the hypervisor by the balloon driver
+static BALLOONED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the frame allocator used by [`frame_allocator`]
+pub fn set_frame_allocator(allocator: &'static dyn FrameAllocator) {
+    unsafe {
+        let slot = &raw mut FRAME_ALLOCATOR;
+        *slot = Some(allocator);
+    }
+}
+
+/// Returns the frame allocator, or `None` before it has been set up
+pub fn frame_allocator() -> Option<&'static dyn FrameAllocator> {
     unsafe {
-        // Return a slice from start of an array up to REGION_COUNT
-        &USABLE_REGIONS[..REGION_COUNT]
+        let slot = &raw const FRAME_ALLOCATOR;
+        *slot
+    }
+}
+
+/// Records `frames` frames going to (positive) or coming back from (negative) the balloon
+pub fn note_ballooned(frames: isize) {
+    if frames >= 0 {
+        BALLOONED_FRAMES.fetch_add(frames as usize, Ordering::Relaxed);
+    } else {
+        BALLOONED_FRAMES.fetch_sub(frames.unsigned_abs(), Ordering::Relaxed);
+    }
+}
+
+/// System-wide memory figures, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// Usable RAM reported by the firmware, minus what the balloon gave to the host
+    pub total: u64,
+
+    /// RAM not allocated to anything
+    pub free: u64,
+
+    /// RAM reclaimed by the host through the balloon
+    pub ballooned: u64,
+}
+
+/// Returns the current memory figures
+pub fn stats() -> MemoryStats {
+    let usable = total_usable_bytes();
+    let ballooned = BALLOONED_FRAMES.load(Ordering::Relaxed) as u64 * FRAME_SIZE;
+
+    // Without an allocator nothing has been handed out yet
+    let free = match frame_allocator() {
+        Some(allocator) => allocator.free_frames() as u64 * FRAME_SIZE,
+        None => usable - ballooned,
+    };
+
+    MemoryStats { total: usable - ballooned, free, ballooned }
+}
+
+/// The ranges holding a process's anonymous pages: data, heap and the stack below its base.
+pub fn anonymous_ranges(process: &Process) -> [(u64, u64); 3] {
+    let (data, heap, stack) = (process.data_base as u64, process.heap_base as u64, process.stack_base as u64);
+
+    [
+        (data, data + process.data_size as u64),
+        (heap, heap + process.heap_size as u64),
+        (stack.saturating_sub(process.stack_size as u64), stack),
+    ]
+}
+
+/// Calls `f` with the address and leaf entry of every 4 KiB page slot in `process`'s data,
+/// heap and stack that has a page table, mapped or not, until it returns false. These are the
+/// pages swap and compaction may move -- except frames `fork` left shared between address
+/// spaces (see [`cow::is_shared`](crate::os::cow::is_shared)), which they must skip.
+pub fn for_each_anonymous_entry(process: &Process, mut f: impl FnMut(u64, &mut u64) -> bool) {
+    let root = process.page_table_root as u64;
+    if root == 0 {
+        return;
+    }
+
+    for (start, end) in anonymous_ranges(process) {
+        let mut virt = start & !(FRAME_SIZE - 1);
+        while virt < end {
+            if let Some(pte) = Current::leaf_entry(root, virt)
+                && !f(virt, unsafe { &mut *pte })
+            {
+                return;
+            }
+            virt += FRAME_SIZE;
+        }
+    }
+}
+
+/// `vm.overcommit_memory` policies.
+pub const OVERCOMMIT_GUESS: u64 = 0;
+pub const OVERCOMMIT_ALWAYS: u64 = 1;
+pub const OVERCOMMIT_NEVER: u64 = 2;
+
+/// Virtual memory promised to processes, in bytes: the segments of every process in the table.
+/// Read without locking the table, since the commit check runs from inside process lookups.
+pub fn committed() -> u64 {
+    ptable::committed()
+}
+
+/// Most memory strict overcommit lets processes commit, in bytes: swap plus
+/// `vm.overcommit_ratio` percent of RAM.
+pub fn commit_limit() -> u64 {
+    let ratio = sysctl::OVERCOMMIT_RATIO.load(Ordering::Relaxed);
+    swap::stats().total + stats().total / 100 * ratio
+}
+
+/// Checks whether the system may promise `additional` more bytes of virtual memory under the
+/// `vm.overcommit_memory` policy. Pages are only backed when touched, and swap can take the
+/// ones not in use, so by default only a request larger than RAM and swap together fails.
+pub fn check_commit(additional: u64) -> KResult<()> {
+    let allowed = match sysctl::OVERCOMMIT_MEMORY.load(Ordering::Relaxed) {
+        OVERCOMMIT_ALWAYS => true,
+        OVERCOMMIT_NEVER => committed().saturating_add(additional) <= commit_limit(),
+        _ => additional <= stats().total + swap::stats().total,
+    };
+
+    if allowed { Ok(()) } else { Err(Errno::ENOMEM) }
+}
+
+pub mod ktests {
+    use super::*;
+    use crate::os::ktest::kernel_test;
+
+    kernel_test! {
+        fn regions_present() {
+            let regions = get_usable_memory_regions();
+
+            assert!(!regions.is_empty(), "no usable memory regions were stored");
+            assert!(regions.iter().all(|r| r.size != 0), "stored a zero-sized region");
+        }
+
+        fn regions_page_aligned() {
+            for r in get_usable_memory_regions() {
+                assert!(r.start % 4096 == 0 && r.size % 4096 == 0, "region {:#x} is not page aligned", r.start);
+            }
+        }
+
+        fn overcommit_follows_policy() {
+            let (mode, ratio) = (sysctl::OVERCOMMIT_MEMORY.load(Ordering::Relaxed), sysctl::OVERCOMMIT_RATIO.load(Ordering::Relaxed));
+            let huge = stats().total + swap::stats().total + FRAME_SIZE;
+
+            assert_eq!(check_commit(huge), Err(Errno::ENOMEM));
+            assert_eq!(check_commit(FRAME_SIZE), Ok(()));
+
+            sysctl::OVERCOMMIT_MEMORY.store(OVERCOMMIT_ALWAYS, Ordering::Relaxed);
+            assert_eq!(check_commit(huge), Ok(()));
+
+            sysctl::OVERCOMMIT_MEMORY.store(OVERCOMMIT_NEVER, Ordering::Relaxed);
+            sysctl::OVERCOMMIT_RATIO.store(100, Ordering::Relaxed);
+            let headroom = commit_limit().saturating_sub(committed());
+            assert_eq!(check_commit(headroom), Ok(()));
+            assert_eq!(check_commit(headroom + FRAME_SIZE), Err(Errno::ENOMEM));
+
+            sysctl::OVERCOMMIT_MEMORY.store(mode, Ordering::Relaxed);
+            sysctl::OVERCOMMIT_RATIO.store(ratio, Ordering::Relaxed);
+        }
+
+        fn regions_come_from_the_memory_map() {
+            let map = memory_map();
+            assert!(!map.is_empty(), "no memory map was stored");
+
+            // Every usable region lies inside a CONVENTIONAL entry of the map
+            for r in get_usable_memory_regions() {
+                assert!(
+                    map.iter().any(|e| e.ty == MemoryType::CONVENTIONAL && e.start <= r.start && r.start + r.size <= e.start + e.pages * FRAME_SIZE),
+                    "region {:#x} is not conventional memory", r.start
+                );
+            }
+        }
+
+        fn touching_entries_merge_into_regions_by_kind() {
+            let entry = |ty, start, pages| MemoryMapEntry { ty, start, pages };
+            let map = [
+                entry(MemoryType::CONVENTIONAL, 0x1000, 4),
+                entry(MemoryType::CONVENTIONAL, 0x5000, 4),
+                entry(MemoryType::LOADER_DATA, 0x9000, 2),
+                entry(MemoryType::CONVENTIONAL, 0xb000, 5),
+                entry(MemoryType::ACPI_RECLAIM, 0x10000, 1),
+                entry(MemoryType::ACPI_RECLAIM, 0x11000, 1),
+                entry(MemoryType::ACPI_NON_VOLATILE, 0x12000, 1),
+                entry(MemoryType::RUNTIME_SERVICES_DATA, 0x13000, 1),
+            ];
+            let region = |kind, start, end| MemoryRegion { start, size: end - start, node: 0, kind };
+
+            // Loader memory is not tracked, and the carved range is cut out of the usable memory
+            let mut slots = [MemoryRegion::EMPTY; 8];
+            let (usable, len, dropped) = build_regions(&map, (0xe000, 0x10000), &mut slots);
+            assert_eq!((usable, len, dropped), (2, 5, false));
+            assert_eq!(
+                slots[..len],
+                [
+                    region(RegionKind::Usable, 0x1000, 0x9000),
+                    region(RegionKind::Usable, 0xb000, 0xe000),
+                    region(RegionKind::AcpiReclaimable, 0x10000, 0x12000),
+                    region(RegionKind::AcpiNvs, 0x12000, 0x13000),
+                    region(RegionKind::Reserved, 0x13000, 0x14000),
+                ]
+            );
+
+            // Too few slots keep the first regions and say so
+            let mut slots = [MemoryRegion::EMPTY; 3];
+            assert_eq!(build_regions(&map, (0, 0), &mut slots), (2, 3, true));
+            assert_eq!(slots[1], region(RegionKind::Usable, 0xb000, 0x10000));
+        }
+
+        fn ranges_are_usable_across_touching_regions() {
+            let regions = [
+                MemoryRegion { start: 0x1000, size: 0x3000, node: 0, kind: RegionKind::Usable },
+                MemoryRegion { start: 0x4000, size: 0x2000, node: 1, kind: RegionKind::Usable },
+                MemoryRegion { start: 0x8000, size: 0x1000, node: 1, kind: RegionKind::Usable },
+            ];
+
+            assert!(covers(&regions, 0x1000, 0x5000));
+            assert!(covers(&regions, 0x3fff, 2));
+            assert!(covers(&regions, 0x8fff, 0));
+            assert!(!covers(&regions, 0x5000, 0x2000));
+            assert!(!covers(&regions, 0x9000, 1));
+            assert!(!covers(&regions, u64::MAX, 2));
+        }
+
+        fn usable_memory_is_summed_and_queried() {
+            let regions = get_usable_memory_regions();
+            let largest = largest_region().unwrap();
+
+            assert_eq!(total_usable_bytes(), regions.iter().map(|r| r.size).sum::<u64>());
+            assert!(regions.iter().all(|r| r.size <= largest.size && r.kind == RegionKind::Usable));
+            assert!(is_usable(largest.start, largest.size));
+
+            // Usable memory comes first, and the rest is never usable
+            assert!(memory_regions()[regions.len()..].iter().all(|r| r.kind != RegionKind::Usable));
+            assert_eq!(regions_of(RegionKind::Usable).count(), regions.len());
+        }
+
+        fn regions_disjoint() {
+            let regions = get_usable_memory_regions();
+
+            for (i, a) in regions.iter().enumerate() {
+                for b in &regions[i + 1..] {
+                    assert!(
+                        a.start >= b.start + b.size || b.start >= a.start + a.size,
+                        "regions {:#x} and {:#x} overlap", a.start, b.start
+                    );
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,547 @@
+//! Memory mappings: `mmap`, `munmap` and `msync`.
+//!
+//! A process's mappings live in its [`Process::mappings`] table; nothing is mapped when the
+//! call returns. Pages are brought in by [`handle_fault`] on first touch: anonymous mappings
+//! get zeroed frames charged to the process's group, file mappings get the file's pages from
+//! the page cache.
+//!
+//! A `MAP_SHARED` file mapping maps the cached frame itself, so every process sharing the file
+//! sees the same bytes. It is mapped read-only until written; the write fault marks the page
+//! dirty and makes it writable, and [`pagecache::writeback`] write-protects it again (through
+//! [`write_protect`]) before writing it to the file. A `MAP_PRIVATE` file mapping maps the
+//! cached frame read-only too, and a write to it copies the page to an anonymous frame of the
+//! process's own -- so an executable's segments can be mapped private and only the pages a
+//! process touches, or writes, cost it anything.
+//!
+//! Mapped pages are not seen by swap or compaction, which only walk data, heap and stack.
+
+use crate::os::arch::{Arch, Current};
+use crate::os::cgroup::{self, PageKind};
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::numa::Placement;
+use crate::os::pagecache;
+use crate::os::process::Process;
+use crate::os::protection::Protection;
+use crate::os::ptable;
+use crate::os::rlimit;
+use crate::os::thp;
+use crate::os::tlb;
+use crate::os::uaccess;
+use crate::os::vma::{self, VmaKind};
+
+/// Maximum number of mappings per process.
+pub const MAX_MAPPINGS: usize = 16;
+
+/// `mmap` protection bits.
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+/// `mmap` flags.
+pub const MAP_SHARED: u32 = 0x01;
+pub const MAP_PRIVATE: u32 = 0x02;
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+
+/// `msync` flags.
+pub const MS_ASYNC: u32 = 0x1;
+pub const MS_INVALIDATE: u32 = 0x2;
+pub const MS_SYNC: u32 = 0x4;
+
+// Valid bit of a leaf entry, bit 0 on every port
+const VALID: u64 = 1 << 0;
+
+/// One mapping of a process's address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// First address, page aligned.
+    pub start: u64,
+
+    /// Length in bytes, a whole number of pages.
+    pub len: u64,
+
+    pub prot: Protection,
+
+    /// Writes reach the file (or, for anonymous memory, would be shared with children).
+    pub shared: bool,
+
+    /// File the mapping shows, `None` for anonymous memory.
+    pub file: Option<u32>,
+
+    /// Offset into the file of `start`, page aligned.
+    pub offset: u64,
+}
+
+impl Mapping {
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    fn contains(&self, virt: u64) -> bool {
+        self.start <= virt && virt < self.end()
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end()
+    }
+
+    // Index of the file page mapped at `virt`
+    fn page_index(&self, virt: u64) -> u64 {
+        (self.offset + (virt - self.start)) / FRAME_SIZE
+    }
+
+    // Address `page_index` is mapped at, if the mapping covers it
+    fn address_of(&self, index: u64) -> Option<u64> {
+        let offset = index.checked_mul(FRAME_SIZE)?.checked_sub(self.offset)?;
+        (offset < self.len).then(|| self.start + offset)
+    }
+
+    // The part of the mapping from `start` to `end`, which must lie inside it
+    fn slice(&self, start: u64, end: u64) -> Mapping {
+        Mapping { start, len: end - start, offset: self.offset + (start - self.start), ..*self }
+    }
+}
+
+// Entry flags for a page of `mapping`, writable only if `write`
+fn page_flags(mapping: &Mapping, write: bool) -> u64 {
+    Current::page_flags(write && mapping.prot.write, mapping.prot.exec, true)
+}
+
+fn find(process: &Process, virt: u64) -> Option<Mapping> {
+    process.mappings.iter().flatten().find(|mapping| mapping.contains(virt)).copied()
+}
+
+// The highest `len` bytes below the process's mmap base no area uses
+fn pick_address(process: &Process, len: u64) -> KResult<u64> {
+    let mut end = process.mmap_base as u64 & !(FRAME_SIZE - 1);
+
+    loop {
+        let start = end.checked_sub(len).ok_or(Errno::ENOMEM)?;
+        match vma::areas(process).iter().flatten().filter(|area| area.start < end && start < area.end).map(|area| area.start).min() {
+            Some(below) => end = below,
+            None => return Ok(start),
+        }
+    }
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`: maps `len` bytes of the file open as `fd` from
+/// `offset`, or anonymous memory with `MAP_ANONYMOUS`, and returns where. `addr` is a hint
+/// unless `MAP_FIXED` demands it, replacing whatever was mapped there.
+///
+/// Fails with `EINVAL` for an empty or misaligned request, without exactly one of
+/// `MAP_SHARED` and `MAP_PRIVATE` or for a fixed mapping over the image, data, heap, stack or
+/// the stack's guard, `EBADF` for a descriptor that is not open, `EACCES` for a writable and
+/// executable mapping and `ENOMEM` when the address space, its limit or the mapping table is
+/// full.
+pub fn sys_mmap(process: &mut Process, addr: usize, len: usize, prot: u32, flags: u32, fd: usize, offset: u64) -> KResult<usize> {
+    let addr = addr as u64;
+    if len == 0 || !offset.is_multiple_of(FRAME_SIZE) || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let protection = Protection { read: prot & PROT_READ != 0, write: prot & PROT_WRITE != 0, exec: prot & PROT_EXEC != 0, user: true };
+    if protection.violates_wx() {
+        return Err(Errno::EACCES);
+    }
+
+    let file = match flags & MAP_ANONYMOUS {
+        0 => Some(process.file(fd)?.id),
+        _ => None,
+    };
+
+    let len = (len as u64).next_multiple_of(FRAME_SIZE);
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed && (!addr.is_multiple_of(FRAME_SIZE) || !uaccess::is_user_range(addr as usize, len as usize)) {
+        return Err(Errno::EINVAL);
+    }
+    if fixed && !vma::is_free(process, addr, addr + len, |area| area.kind == VmaKind::Mapping) {
+        return Err(Errno::EINVAL);
+    }
+
+    // A fixed mapping replaces what it covers, which then no longer counts against the limit
+    let replaced: u64 = match fixed {
+        true => process.mappings.iter().flatten().filter(|m| m.overlaps(addr, addr + len)).map(|m| m.end().min(addr + len) - m.start.max(addr)).sum(),
+        false => 0,
+    };
+    rlimit::check_address_space(process, (len - replaced) as usize)?;
+
+    let start = if fixed {
+        unmap(process, addr, len)?;
+        addr
+    } else if addr != 0
+        && addr.is_multiple_of(FRAME_SIZE)
+        && uaccess::is_user_range(addr as usize, len as usize)
+        && vma::is_free(process, addr, addr + len, |_| false)
+    {
+        addr
+    } else {
+        pick_address(process, len)?
+    };
+
+    let slot = process.mappings.iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOMEM)?;
+    *slot = Some(Mapping { start, len, prot: protection, shared, file, offset });
+    Ok(start as usize)
+}
+
+// Unmaps the pages of `mapping` from `start` to `end`, dropping cache holds and freeing
+// private frames once no CPU can reach them
+fn unmap_pages(process: &Process, mapping: &Mapping, start: u64, end: u64) {
+    let root = process.page_table_root as u64;
+    if root == 0 {
+        return;
+    }
+
+    let pages = (end - start) / FRAME_SIZE;
+    let entries = || (0..pages).map(move |i| start + i * FRAME_SIZE).filter_map(move |virt| Some((virt, Current::leaf_entry(root, virt)?)));
+
+    // Clearing only the valid bit keeps the frame address for after the flush
+    for (_, pte) in entries() {
+        unsafe { *pte &= !VALID };
+    }
+    tlb::shootdown(Some(root), start, pages);
+
+    for (virt, pte) in entries() {
+        let entry = unsafe { *pte };
+        if entry == 0 {
+            continue;
+        }
+
+        let frame = Current::entry_address(entry);
+        let cached = mapping.file.map(|file| (file, mapping.page_index(virt)));
+        match cached {
+            Some((file, index)) if pagecache::lookup(file, index) == Some(frame) => pagecache::put(file, index),
+            _ => {
+                if let Some(allocator) = memory::frame_allocator() {
+                    allocator.free_frame(frame);
+                }
+                cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+            }
+        }
+        unsafe { *pte = 0 };
+    }
+}
+
+// Removes `[start, start + len)` from the process's mappings, trimming or splitting the ones it
+// covers in part
+fn unmap(process: &mut Process, start: u64, len: u64) -> KResult<()> {
+    let end = start + len;
+
+    // A mapping covered in the middle splits in two, which takes a second slot
+    let splits = process.mappings.iter().flatten().filter(|m| m.start < start && end < m.end()).count();
+    if splits > process.mappings.iter().filter(|slot| slot.is_none()).count() {
+        return Err(Errno::ENOMEM);
+    }
+    if process.page_table_root != 0 {
+        thp::split_partial(process, start, end)?;
+    }
+
+    for i in 0..MAX_MAPPINGS {
+        let Some(mapping) = process.mappings[i] else {
+            continue;
+        };
+        if !mapping.overlaps(start, end) {
+            continue;
+        }
+
+        let (from, to) = (mapping.start.max(start), mapping.end().min(end));
+        unmap_pages(process, &mapping, from, to);
+
+        let below = (mapping.start < from).then(|| mapping.slice(mapping.start, from));
+        let above = (to < mapping.end()).then(|| mapping.slice(to, mapping.end()));
+        process.mappings[i] = below.or(above);
+
+        if below.is_some()
+            && above.is_some()
+            && let Some(slot) = process.mappings.iter_mut().find(|slot| slot.is_none())
+        {
+            *slot = above;
+        }
+    }
+
+    Ok(())
+}
+
+/// `munmap(addr, len)`: removes the mappings in `[addr, addr + len)`, writing nothing back;
+/// dirty shared pages stay in the page cache for writeback. Unmapping a range with nothing in
+/// it is not an error. Fails with `EINVAL` for a misaligned or empty range and `ENOMEM` if a
+/// mapping would have to be split with the mapping table full.
+pub fn sys_munmap(process: &mut Process, addr: usize, len: usize) -> KResult<()> {
+    let (addr, len) = (addr as u64, (len as u64).next_multiple_of(FRAME_SIZE));
+    if len == 0 || !addr.is_multiple_of(FRAME_SIZE) || !uaccess::is_user_range(addr as usize, len as usize) {
+        return Err(Errno::EINVAL);
+    }
+
+    unmap(process, addr, len)
+}
+
+/// `msync(addr, len, flags)`: flushes the dirty pages of the shared file mappings in
+/// `[addr, addr + len)` to their files. With `MS_SYNC` they are written before the call
+/// returns; with `MS_ASYNC` the writeback daemon is asked to write them on its next pass.
+/// `MS_INVALIDATE` has nothing to do, since every mapping of a file page maps the cached frame.
+///
+/// Fails with `EINVAL` for a misaligned address, unknown flags or both `MS_SYNC` and
+/// `MS_ASYNC`, `ENOMEM` if part of the range of the caller `caller` is not mapped, and with
+/// the first write error.
+pub fn sys_msync(caller: u64, addr: usize, len: usize, flags: u32) -> KResult<()> {
+    let (start, end) = (addr as u64, (addr as u64).saturating_add((len as u64).next_multiple_of(FRAME_SIZE)));
+    if !start.is_multiple_of(FRAME_SIZE) || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0 || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC {
+        return Err(Errno::EINVAL);
+    }
+
+    // Writeback waits on the disk, so it goes through a copy of the mappings
+    let mappings = ptable::with_process(caller, |process| process.mappings).ok_or(Errno::ESRCH)?;
+    let covered: u64 = mappings.iter().flatten().filter(|m| m.overlaps(start, end)).map(|m| m.end().min(end) - m.start.max(start)).sum();
+    if covered < end - start {
+        return Err(Errno::ENOMEM);
+    }
+
+    if flags & MS_SYNC == 0 {
+        pagecache::kick_writeback();
+        return Ok(());
+    }
+
+    for mapping in mappings.iter().flatten().filter(|m| m.shared && m.overlaps(start, end)) {
+        if let Some(file) = mapping.file {
+            let (from, to) = (mapping.start.max(start), mapping.end().min(end));
+            pagecache::writeback_range(file, mapping.page_index(from)..mapping.page_index(to - 1) + 1)?;
+        }
+    }
+    Ok(())
+}
+
+// Maps `frame` at `virt` and flushes the old translation if there was one
+fn install(root: u64, pte: &mut u64, virt: u64, entry: u64) {
+    let was_valid = *pte & VALID != 0;
+    *pte = entry;
+
+    if was_valid {
+        tlb::shootdown_page(Some(root), virt);
+    } else {
+        Current::flush_tlb_page(virt);
+    }
+}
+
+// A new anonymous frame charged to `process`'s group, filled by `fill`
+fn alloc_private(process: &Process, fill: impl FnOnce(*mut u8)) -> KResult<u64> {
+    cgroup::charge(process.cgroup, PageKind::Anon, 1)?;
+    let Some(frame) = memory::frame_allocator().and_then(|allocator| allocator.alloc_frame(Placement::Local)) else {
+        cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+        return Err(Errno::ENOMEM);
+    };
+
+    // Frames are identity-mapped
+    fill(frame as *mut u8);
+    Ok(frame)
+}
+
+// A private copy of the cached `frame`
+fn copy_of(process: &Process, frame: u64) -> KResult<u64> {
+    alloc_private(process, |page| unsafe { core::ptr::copy_nonoverlapping(frame as *const u8, page, FRAME_SIZE as usize) })
+}
+
+/// Page fault hook: resolves a fault at `virt` in `process`'s address space inside one of its
+/// mappings, returning whether it was one. `write` tells a store from a load or fetch. Fails
+/// with `EFAULT` for an access the mapping's protection forbids or a file page past the end of
+/// the file, and `ENOMEM` when no frame can be had.
+pub fn handle_fault(process: &Process, virt: u64, write: bool) -> KResult<bool> {
+    let Some(mapping) = find(process, virt) else {
+        return Ok(false);
+    };
+
+    let prot = mapping.prot;
+    if (write && !prot.write) || !(prot.read || prot.write || prot.exec) {
+        return Err(Errno::EFAULT);
+    }
+
+    let root = process.page_table_root as u64;
+    let allocator = memory::frame_allocator().ok_or(Errno::ENOMEM)?;
+    let page = virt & !(FRAME_SIZE - 1);
+    let pte = Current::leaf_entry_or_create(root, page, &mut || allocator.alloc_frame(Placement::Local)).ok_or(Errno::ENOMEM)?;
+    let pte = unsafe { &mut *pte };
+
+    let Some(file) = mapping.file else {
+        if *pte & VALID != 0 {
+            return Ok(false);
+        }
+
+        let frame = alloc_private(process, |page| unsafe { page.write_bytes(0, FRAME_SIZE as usize) })?;
+        install(root, pte, page, Current::page_entry(frame, page_flags(&mapping, true)));
+        return Ok(true);
+    };
+    let index = mapping.page_index(page);
+
+    // A mapped page only faults here when written while it still maps the cached frame
+    if *pte & VALID != 0 {
+        let frame = Current::entry_address(*pte);
+        if !write || pagecache::lookup(file, index) != Some(frame) {
+            return Ok(false);
+        }
+
+        if mapping.shared {
+            pagecache::mark_dirty(file, index);
+            install(root, pte, page, Current::page_entry(frame, page_flags(&mapping, true)));
+        } else {
+            let copy = copy_of(process, frame)?;
+            install(root, pte, page, Current::page_entry(copy, page_flags(&mapping, true)));
+            pagecache::put(file, index);
+        }
+        return Ok(true);
+    }
+
+    let frame = pagecache::get(file, index, process.cgroup)?;
+    let entry = match (mapping.shared, write) {
+        (true, true) => {
+            pagecache::mark_dirty(file, index);
+            Current::page_entry(frame, page_flags(&mapping, true))
+        }
+        (false, true) => {
+            let copy = copy_of(process, frame);
+            pagecache::put(file, index);
+            Current::page_entry(copy?, page_flags(&mapping, true))
+        }
+        (_, false) => Current::page_entry(frame, page_flags(&mapping, false)),
+    };
+
+    install(root, pte, page, entry);
+    Ok(true)
+}
+
+/// Makes page `index` of `file` read-only in every shared mapping of it, so the next write
+/// faults and dirties the page again. Called by writeback before the page is written.
+pub fn write_protect(file: u32, index: u64) {
+    let Some(frame) = pagecache::lookup(file, index) else {
+        return;
+    };
+
+    ptable::for_each(|process| {
+        let root = process.page_table_root as u64;
+        for mapping in process.mappings.iter().flatten().filter(|m| m.shared && m.file == Some(file)) {
+            let Some(virt) = mapping.address_of(index) else {
+                continue;
+            };
+
+            if let Some(pte) = Current::leaf_entry(root, virt)
+                && unsafe { *pte } & VALID != 0
+                && Current::entry_address(unsafe { *pte }) == frame
+            {
+                unsafe { *pte = Current::page_entry(frame, page_flags(mapping, false)) };
+                tlb::shootdown_page(Some(root), virt);
+            }
+        }
+    });
+}
+
+/// Bytes mapped by `process`'s mappings.
+pub fn mapped_bytes(process: &Process) -> u64 {
+    process.mappings.iter().flatten().map(|mapping| mapping.len).sum()
+}
+
+/// Removes all of `process`'s mappings. Called when its address space is torn down.
+pub fn release(process: &mut Process) {
+    for i in 0..MAX_MAPPINGS {
+        if let Some(mapping) = process.mappings[i].take() {
+            unmap_pages(process, &mapping, mapping.start, mapping.end());
+        }
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::file;
+
+    const RW: u32 = PROT_READ | PROT_WRITE;
+
+    fn anonymous(process: &mut Process, addr: usize, len: usize, flags: u32) -> KResult<usize> {
+        sys_mmap(process, addr, len, RW, flags | MAP_PRIVATE | MAP_ANONYMOUS, 0, 0)
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn requests_are_checked() {
+            let mut process = Process::new(9400, 0, "mmap");
+
+            assert_eq!(sys_mmap(&mut process, 0, 0, RW, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0), Err(Errno::EINVAL));
+            assert_eq!(sys_mmap(&mut process, 0, 4096, RW, MAP_ANONYMOUS, 0, 0), Err(Errno::EINVAL));
+            assert_eq!(sys_mmap(&mut process, 0, 4096, RW, MAP_SHARED | MAP_PRIVATE, 0, 0), Err(Errno::EINVAL));
+            assert_eq!(sys_mmap(&mut process, 0, 4096, RW, MAP_PRIVATE, 0, 100), Err(Errno::EINVAL));
+            assert_eq!(sys_mmap(&mut process, 0, 4096, RW, MAP_PRIVATE, 3, 0), Err(Errno::EBADF));
+            assert_eq!(sys_mmap(&mut process, 0, 4096, PROT_WRITE | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0), Err(Errno::EACCES));
+            assert_eq!(anonymous(&mut process, 0x1001, 4096, MAP_FIXED), Err(Errno::EINVAL));
+            assert!(process.mappings.iter().all(Option::is_none));
+        }
+
+        fn addresses_are_picked_top_down() {
+            let mut process = Process::new(9401, 0, "mmap");
+            let base = process.mmap_base;
+
+            assert_eq!(anonymous(&mut process, 0, 8192, 0), Ok(base - 8192));
+            assert_eq!(anonymous(&mut process, 0, 100, 0), Ok(base - 3 * 4096));
+
+            // A free hint is taken, a used one is not
+            assert_eq!(anonymous(&mut process, 0x1000_0000, 4096, 0), Ok(0x1000_0000));
+            assert_eq!(anonymous(&mut process, base - 8192, 4096, 0), Ok(base - 4 * 4096));
+            assert_eq!(mapped_bytes(&process), 5 * 4096);
+        }
+
+        fn unmapping_the_middle_splits_a_mapping() {
+            let mut process = Process::new(9402, 0, "mmap");
+            process.file_descriptors[3] = Some(file::open(7).unwrap());
+
+            let start = sys_mmap(&mut process, 0x2000_0000, 4 * 4096, PROT_READ, MAP_SHARED, 3, 8192).unwrap() as u64;
+            sys_munmap(&mut process, start as usize + 4096, 4096).unwrap();
+
+            let mut left: [Mapping; 2] = [process.mappings[0].unwrap(), process.mappings[1].unwrap()];
+            left.sort_unstable_by_key(|m| m.start);
+            assert_eq!((left[0].start, left[0].len, left[0].offset), (start, 4096, 8192));
+            assert_eq!((left[1].start, left[1].len, left[1].offset), (start + 8192, 8192, 16384));
+            assert_eq!(left[1].page_index(start + 3 * 4096), 7);
+            assert_eq!(left[1].address_of(6), Some(start + 8192));
+            assert_eq!(left[1].address_of(3), None);
+
+            // Unmapping across both leaves nothing
+            sys_munmap(&mut process, start as usize, 4 * 4096).unwrap();
+            assert_eq!(mapped_bytes(&process), 0);
+        }
+
+        fn fixed_mappings_replace_what_they_cover() {
+            let mut process = Process::new(9403, 0, "mmap");
+
+            assert_eq!(anonymous(&mut process, 0x3000_0000, 3 * 4096, MAP_FIXED), Ok(0x3000_0000));
+            assert_eq!(anonymous(&mut process, 0x3000_1000, 4 * 4096, MAP_FIXED), Ok(0x3000_1000));
+            assert_eq!(find(&process, 0x3000_0000).map(|m| m.len), Some(4096));
+            assert_eq!(find(&process, 0x3000_2000).map(|m| m.start), Some(0x3000_1000));
+            assert_eq!(mapped_bytes(&process), 5 * 4096);
+        }
+
+        fn msync_checks_flags_and_coverage() {
+            let mut process = Process::new(9405, 0, "mmap");
+            let start = anonymous(&mut process, 0x4000_0000, 2 * 4096, MAP_FIXED).unwrap();
+            ptable::insert(process).unwrap();
+
+            assert_eq!(sys_msync(9405, start, 8192, MS_SYNC), Ok(()));
+            assert_eq!(sys_msync(9405, start, 8192, MS_ASYNC | MS_INVALIDATE), Ok(()));
+            assert_eq!(sys_msync(9405, start + 1, 4096, MS_SYNC), Err(Errno::EINVAL));
+            assert_eq!(sys_msync(9405, start, 4096, MS_SYNC | MS_ASYNC), Err(Errno::EINVAL));
+            assert_eq!(sys_msync(9405, start, 4096, 0x8), Err(Errno::EINVAL));
+
+            // The range runs one page past the mapping
+            assert_eq!(sys_msync(9405, start, 3 * 4096, MS_SYNC), Err(Errno::ENOMEM));
+            ptable::remove(9405);
+        }
+
+        fn protection_is_enforced_on_fault() {
+            let mut process = Process::new(9404, 0, "mmap");
+            let start = sys_mmap(&mut process, 0, 4096, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0).unwrap() as u64;
+
+            assert_eq!(handle_fault(&process, start, true), Err(Errno::EFAULT));
+            assert_eq!(handle_fault(&process, start + 4096, false), Ok(false));
+        }
+    }
+}
@@ -1,2 +1,110 @@
+pub mod acpi;
+#[cfg(target_arch = "x86_64")]
+pub mod ahci;
+pub mod arch;
+pub mod aslr;
+pub mod audit;
+pub mod block;
+pub mod boot;
+#[cfg(target_arch = "x86_64")]
+pub mod brk;
+pub mod capability;
+pub mod cgroup;
+pub mod clocksource;
+pub mod compaction;
+pub mod config;
+pub mod console;
+pub mod cow;
+#[cfg(target_arch = "x86_64")]
+pub mod cpu;
+pub mod cred;
+pub mod deadlock;
+pub mod devfs;
+pub mod device;
+#[cfg(target_arch = "x86_64")]
+pub mod elf;
+pub mod errno;
+pub mod exit;
+pub mod fat32;
+#[cfg(target_arch = "x86_64")]
+pub mod fault;
+pub mod fdt;
+#[cfg(target_arch = "x86_64")]
+pub mod fork;
+pub mod file;
+#[cfg(target_arch = "x86_64")]
+pub mod fpu;
+#[cfg(target_arch = "aarch64")]
+pub use arch::aarch64::fpu;
+#[cfg(target_arch = "riscv64")]
+pub use arch::riscv64::fpu;
+pub mod frame;
+pub mod heap;
+pub mod hrtimer;
+#[cfg(target_arch = "x86_64")]
+pub mod idle;
+pub mod initrd;
+pub mod ipc;
+pub mod ipi;
+pub mod itimer;
+pub mod jobctl;
+pub mod kasan;
+#[cfg(target_arch = "x86_64")]
+pub mod keyboard;
+pub mod kobject;
+pub mod ktest;
+pub mod kthread;
+#[cfg(target_arch = "x86_64")]
+pub mod kvm;
+pub mod leak;
+pub mod lsm;
 pub mod memory;
-pub mod process;
\ No newline at end of file
+pub mod mmap;
+pub mod mount;
+pub mod net;
+pub mod numa;
+pub mod pagecache;
+#[cfg(target_arch = "x86_64")]
+pub mod paging;
+pub mod panic;
+#[cfg(target_arch = "x86_64")]
+pub mod pci;
+pub mod percpu;
+pub mod pid;
+pub mod pidns;
+#[cfg(target_arch = "x86_64")]
+pub mod power;
+pub mod prctl;
+pub mod process;
+pub mod procfs;
+pub mod protection;
+pub mod ptable;
+pub mod qemu;
+pub mod random;
+pub mod rlimit;
+pub mod sched;
+pub mod seccomp;
+pub mod selftest;
+pub mod serial;
+#[cfg(target_arch = "x86_64")]
+pub mod shell;
+pub mod signal;
+pub mod stack_protector;
+pub mod swap;
+pub mod sync;
+pub mod syscall;
+pub mod sysctl;
+pub mod thp;
+pub mod timekeeping;
+pub mod timer;
+pub mod tlb;
+pub mod trace;
+pub mod tty;
+pub mod uaccess;
+pub mod uring;
+pub mod vfs;
+#[cfg(all(target_arch = "x86_64", feature = "virtio"))]
+pub mod virtio;
+pub mod vma;
+pub mod vmalloc;
+pub mod zram;
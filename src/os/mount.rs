@@ -0,0 +1,588 @@
+//! Mount namespaces and per-process roots.
+//!
+//! Every mount belongs to one mount namespace, and a process sees only the mounts of the
+//! namespace it lives in. [`sys_unshare_mount`] gives the caller a private copy of its current
+//! mount table, after which mounts and unmounts on either side are invisible to the other.
+//!
+//! Paths are resolved in two steps: the path the process passed is normalised with `..`
+//! stopping at `/`, then appended to the process's root (set by [`sys_chroot`]), so nothing
+//! above the root can be named. The result is looked up in the namespace's mount table, the
+//! deepest mount containing it winning and a later mount shadowing an earlier one on the same
+//! target. All of this happens in [`resolve`], which filesystem syscalls go through.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::os::capability::{self, Capability};
+use crate::os::errno::{Errno, KResult};
+use crate::os::lsm;
+use crate::os::process::Process;
+use crate::os::ptable;
+
+/// Index of a namespace in the namespace table.
+pub type MntNsId = u16;
+
+/// The initial namespace, set up at boot.
+pub const ROOT_NS: MntNsId = 0;
+
+/// Maximum number of mount namespaces alive at once, the root included.
+pub const MAX_NAMESPACES: usize = 16;
+
+/// Maximum number of mounts across all namespaces.
+pub const MAX_MOUNTS: usize = 64;
+
+/// Longest path the kernel handles, in bytes.
+pub const MAX_PATH: usize = 128;
+
+/// The filesystems that can be mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    /// The empty filesystem the root namespace starts out with.
+    RootFs,
+
+    /// Process and kernel information (see `procfs`).
+    Proc,
+
+    /// The host directory shared over virtio-9p.
+    P9,
+
+    /// Control group configuration (see `cgroup`).
+    Cgroup,
+
+    /// The FAT32 volume the kernel attached (see `fat32`).
+    Fat32,
+
+    /// Character devices (see `devfs`).
+    Dev,
+}
+
+impl FsType {
+    /// The name `mount` takes and `/proc/mounts`-style listings show.
+    pub fn name(self) -> &'static str {
+        match self {
+            FsType::RootFs => "rootfs",
+            FsType::Proc => "proc",
+            FsType::P9 => "9p",
+            FsType::Cgroup => "cgroup2",
+            FsType::Fat32 => "vfat",
+            FsType::Dev => "devtmpfs",
+        }
+    }
+
+    /// Looks up a filesystem by the name user space passes to `mount`.
+    pub fn from_name(name: &str) -> Option<FsType> {
+        [FsType::RootFs, FsType::Proc, FsType::P9, FsType::Cgroup, FsType::Fat32, FsType::Dev]
+            .into_iter()
+            .find(|fs| fs.name() == name)
+    }
+}
+
+/// An absolute path without `.`, `..`, repeated or trailing slashes, stored inline.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Path {
+    buf: [u8; MAX_PATH],
+    len: u8,
+}
+
+impl Path {
+    /// `/`.
+    pub const ROOT: Path = {
+        let mut buf = [0; MAX_PATH];
+        buf[0] = b'/';
+        Path { buf, len: 1 }
+    };
+
+    /// Normalises `path`, taking relative paths from `/`. `..` never goes above `/`.
+    pub fn new(path: &str) -> KResult<Path> {
+        Path::ROOT.join(path)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from `&str` components
+        core::str::from_utf8(&self.buf[..self.len as usize]).unwrap_or("/")
+    }
+
+    /// The non-empty components, from the root down.
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.as_str().split('/').filter(|c| !c.is_empty())
+    }
+
+    /// `path` taken relative to `self` (or from `/` if absolute) and normalised.
+    pub fn join(&self, path: &str) -> KResult<Path> {
+        let mut out = if path.starts_with('/') { Path::ROOT } else { *self };
+
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => out.pop(),
+                component => out.push(component)?,
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// `other`'s components appended to `self`.
+    pub fn append(&self, other: &Path) -> KResult<Path> {
+        let mut out = *self;
+        other.components().try_for_each(|c| out.push(c))?;
+        Ok(out)
+    }
+
+    /// `self` as seen from `base`, if it is `base` or lies under it.
+    pub fn relative_to(&self, base: &Path) -> Option<Path> {
+        if *base == Path::ROOT {
+            return Some(*self);
+        }
+
+        let rest = self.as_str().strip_prefix(base.as_str())?;
+        if rest.is_empty() {
+            return Some(Path::ROOT);
+        }
+        if !rest.starts_with('/') {
+            return None;
+        }
+
+        let mut out = Path::ROOT;
+        out.buf[..rest.len()].copy_from_slice(rest.as_bytes());
+        out.len = rest.len() as u8;
+        Some(out)
+    }
+
+    fn push(&mut self, component: &str) -> KResult<()> {
+        let start = if *self == Path::ROOT { 1 } else { self.len as usize + 1 };
+        let end = start + component.len();
+        if end > MAX_PATH {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        self.buf[start - 1] = b'/';
+        self.buf[start..end].copy_from_slice(component.as_bytes());
+        self.len = end as u8;
+        Ok(())
+    }
+
+    fn pop(&mut self) {
+        let slash = self.buf[..self.len as usize].iter().rposition(|&b| b == b'/').unwrap_or(0);
+        self.len = slash.max(1) as u8;
+    }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A filesystem attached at a path in one namespace.
+#[derive(Debug, Clone, Copy)]
+pub struct Mount {
+    /// Mount ID; higher IDs were mounted later. A namespace's copy of a mount keeps the ID.
+    pub id: u32,
+
+    pub ns: MntNsId,
+    pub fs: FsType,
+
+    /// Where it is attached, as a path from the namespace's root.
+    pub target: Path,
+}
+
+/// Where a path ends up: the mount it lies in and the path within that filesystem.
+#[derive(Debug, Clone, Copy)]
+pub struct Resolved {
+    pub mount: u32,
+    pub fs: FsType,
+    pub path: Path,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Namespace {
+    // Processes living in it
+    refs: u32,
+}
+
+static mut NAMESPACES: [Option<Namespace>; MAX_NAMESPACES] = {
+    let mut table = [None; MAX_NAMESPACES];
+    table[ROOT_NS as usize] = Some(Namespace { refs: 0 });
+    table
+};
+
+static mut MOUNTS: [Option<Mount>; MAX_MOUNTS] = {
+    let mut table = [None; MAX_MOUNTS];
+    table[0] = Some(Mount { id: 0, ns: ROOT_NS, fs: FsType::RootFs, target: Path::ROOT });
+    table
+};
+
+static NEXT_MOUNT_ID: AtomicU32 = AtomicU32::new(1);
+
+// Like the PID namespace table, only changed on behalf of the running process
+fn namespaces() -> &'static mut [Option<Namespace>; MAX_NAMESPACES] {
+    unsafe {
+        let table = &raw mut NAMESPACES;
+        &mut *table
+    }
+}
+
+fn mounts() -> &'static mut [Option<Mount>; MAX_MOUNTS] {
+    unsafe {
+        let table = &raw mut MOUNTS;
+        &mut *table
+    }
+}
+
+/// Mounts procfs at `/proc`, the devices at `/dev`, the control group tree at `/sys/fs/cgroup`
+/// and, if the host shares a directory, that at `/mnt` in the root namespace. Called once at
+/// boot, after the virtio devices are probed.
+pub fn init() {
+    for (fs, target) in [(FsType::Proc, "/proc"), (FsType::Dev, "/dev"), (FsType::Cgroup, "/sys/fs/cgroup")] {
+        if let Err(errno) = Path::new(target).and_then(|target| add(ROOT_NS, fs, target)) {
+            log::warn!("mount: cannot mount {}: {:?}", fs.name(), errno);
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "virtio"))]
+    if crate::os::virtio::p9::with_client(|_| Ok(())).is_ok()
+        && let Err(errno) = Path::new("/mnt").and_then(|target| add(ROOT_NS, FsType::P9, target))
+    {
+        log::warn!("mount: cannot mount 9p share: {:?}", errno);
+    }
+}
+
+/// Mounts `fs` at `target` in the root namespace, for filesystems found after boot, on a disk
+/// a driver attached.
+pub fn mount_in_root(fs: FsType, target: &str) -> KResult<u32> {
+    add(ROOT_NS, fs, Path::new(target)?)
+}
+
+/// Number of mounts in `ns`.
+pub fn count(ns: MntNsId) -> usize {
+    mounts().iter().flatten().filter(|m| m.ns == ns).count()
+}
+
+/// Runs `f` on every mount in `ns`, oldest first.
+pub fn for_each(ns: MntNsId, mut f: impl FnMut(&Mount)) {
+    let mut last = None;
+
+    // Slots get reused, so walk in ID order rather than slot order
+    while let Some(next) = mounts()
+        .iter()
+        .flatten()
+        .filter(|m| m.ns == ns && last.is_none_or(|id| m.id > id))
+        .min_by_key(|m| m.id)
+    {
+        last = Some(next.id);
+        f(next);
+    }
+}
+
+fn add(ns: MntNsId, fs: FsType, target: Path) -> KResult<u32> {
+    let slot = mounts().iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOSPC)?;
+    let id = NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed);
+    *slot = Some(Mount { id, ns, fs, target });
+
+    log::debug!("mount: {} on {} in namespace {}", fs.name(), target, ns);
+    Ok(id)
+}
+
+fn hold(ns: MntNsId) {
+    if let Some(namespace) = namespaces()[ns as usize].as_mut() {
+        namespace.refs += 1;
+    }
+}
+
+// Drops a reference on `ns`, unmounting everything in it with the last one
+fn put(ns: MntNsId) {
+    let Some(namespace) = namespaces()[ns as usize].as_mut() else {
+        return;
+    };
+
+    namespace.refs = namespace.refs.saturating_sub(1);
+    if namespace.refs > 0 || ns == ROOT_NS {
+        return;
+    }
+
+    for slot in mounts().iter_mut() {
+        if slot.is_some_and(|m| m.ns == ns) {
+            *slot = None;
+        }
+    }
+    namespaces()[ns as usize] = None;
+    log::debug!("mount: namespace {} freed", ns);
+}
+
+/// Gives the new process `child` `parent`'s mount namespace and root. Called when the child
+/// is created.
+pub fn inherit(child: &mut Process, parent: &Process) {
+    hold(parent.mnt_ns);
+    child.mnt_ns = parent.mnt_ns;
+    child.root = parent.root;
+}
+
+/// Drops `process`'s reference on its mount namespace. Called when the process is reaped.
+pub fn release(process: &mut Process) {
+    put(process.mnt_ns);
+    process.mnt_ns = ROOT_NS;
+    process.root = Path::ROOT;
+}
+
+// The path `process` means by `path`, as seen from its namespace's root
+fn to_namespace(process: &Process, path: &str) -> KResult<Path> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+
+    process.root.append(&Path::new(path)?)
+}
+
+fn lookup(ns: MntNsId, path: &Path) -> Option<Mount> {
+    mounts()
+        .iter()
+        .flatten()
+        .filter(|m| m.ns == ns && path.relative_to(&m.target).is_some())
+        .max_by_key(|m| (m.target.len, m.id))
+        .copied()
+}
+
+/// Resolves `path` for `process`: confined to its root, in its mount namespace.
+pub fn resolve(process: &Process, path: &str) -> KResult<Resolved> {
+    let full = to_namespace(process, path)?;
+    let mount = lookup(process.mnt_ns, &full).ok_or(Errno::ENOENT)?;
+
+    Ok(Resolved { mount: mount.id, fs: mount.fs, path: full.relative_to(&mount.target).unwrap_or(Path::ROOT) })
+}
+
+/// `unshare(CLONE_NEWNS)`: moves `process` into a new namespace holding a copy of its current
+/// mount table. Requires `CAP_SYS_ADMIN`.
+pub fn sys_unshare_mount(process: &mut Process) -> KResult<()> {
+    capability::require(&process.cred, Capability::SysAdmin)?;
+
+    let old = process.mnt_ns;
+    let ns = namespaces().iter().position(Option::is_none).ok_or(Errno::ENOSPC)?;
+    let free = mounts().iter().filter(|slot| slot.is_none()).count();
+    if free < count(old) {
+        return Err(Errno::ENOSPC);
+    }
+
+    let ns = ns as MntNsId;
+    namespaces()[ns as usize] = Some(Namespace { refs: 1 });
+
+    for i in 0..MAX_MOUNTS {
+        if let Some(mount) = mounts()[i]
+            && mount.ns == old
+            && let Some(slot) = mounts().iter_mut().find(|slot| slot.is_none())
+        {
+            *slot = Some(Mount { ns, ..mount });
+        }
+    }
+
+    put(old);
+    process.mnt_ns = ns;
+
+    log::debug!("mount: pid {} unshared into namespace {}", process.pid, ns);
+    Ok(())
+}
+
+/// `mount()`: attaches a new instance of the filesystem named `fs_type` at `target`.
+/// Requires `CAP_SYS_ADMIN`; unknown filesystems fail with `ENODEV`.
+pub fn sys_mount(process: &Process, fs_type: &str, target: &str) -> KResult<()> {
+    capability::require(&process.cred, Capability::SysAdmin)?;
+
+    let fs = FsType::from_name(fs_type).ok_or(Errno::ENODEV)?;
+    let target = to_namespace(process, target)?;
+    lsm::check_mount(process, fs_type, target.as_str())?;
+
+    add(process.mnt_ns, fs, target).map(|_| ())
+}
+
+/// `umount()`: detaches the topmost mount on `target`. Fails with `EINVAL` if nothing is
+/// mounted there and `EBUSY` if it is the namespace's root, has mounts below it or holds some
+/// process's root.
+pub fn sys_umount(process: &Process, target: &str) -> KResult<()> {
+    capability::require(&process.cred, Capability::SysAdmin)?;
+
+    let ns = process.mnt_ns;
+    let target = to_namespace(process, target)?;
+
+    let top = mounts()
+        .iter()
+        .flatten()
+        .filter(|m| m.ns == ns && m.target == target)
+        .max_by_key(|m| m.id)
+        .map(|m| m.id)
+        .ok_or(Errno::EINVAL)?;
+
+    let under = |path: &Path| path.relative_to(&target).is_some();
+    let mut busy = target == Path::ROOT
+        || under(&process.root)
+        || mounts().iter().flatten().any(|m| m.ns == ns && m.target != target && under(&m.target));
+    ptable::for_each(|p| busy |= p.mnt_ns == ns && under(&p.root));
+    if busy {
+        return Err(Errno::EBUSY);
+    }
+
+    for slot in mounts().iter_mut() {
+        if slot.is_some_and(|m| m.ns == ns && m.id == top) {
+            *slot = None;
+        }
+    }
+
+    log::debug!("mount: unmounted {} in namespace {}", target, ns);
+    Ok(())
+}
+
+/// `chroot()`: makes `path` the root of `process`'s future lookups. Requires
+/// `CAP_SYS_CHROOT`.
+pub fn sys_chroot(process: &mut Process, path: &str) -> KResult<()> {
+    capability::require(&process.cred, Capability::SysChroot)?;
+
+    process.root = to_namespace(process, path)?;
+    Ok(())
+}
+
+/// `pivot_root()`: makes the mount on `new_root` the root of `process`'s namespace and moves
+/// the old root to `put_old`, which must lie under `new_root`. Every mount in the namespace
+/// and every chrooted process in it is moved along; processes whose root was the old root get
+/// the new one. Requires `CAP_SYS_ADMIN`, and unlike Linux the caller must not be chrooted.
+pub fn sys_pivot_root(process: &Process, new_root: &str, put_old: &str) -> KResult<()> {
+    capability::require(&process.cred, Capability::SysAdmin)?;
+
+    let ns = process.mnt_ns;
+    if process.root != Path::ROOT {
+        return Err(Errno::EINVAL);
+    }
+
+    let new_root = to_namespace(process, new_root)?;
+    let put_old = to_namespace(process, put_old)?;
+    let is_mount_point = mounts().iter().flatten().any(|m| m.ns == ns && m.target == new_root);
+    if new_root == Path::ROOT || !is_mount_point {
+        return Err(Errno::EINVAL);
+    }
+    let old_root = put_old.relative_to(&new_root).ok_or(Errno::EINVAL)?;
+
+    // Paths under the new root lose its prefix; everything else moves under `put_old`
+    let pivot = |path: &Path| match path.relative_to(&new_root) {
+        Some(inside) => Ok(inside),
+        None => old_root.append(path),
+    };
+    let pivot_root = |root: &Path| if *root == Path::ROOT { Ok(Path::ROOT) } else { pivot(root) };
+
+    // Check every path still fits before changing any
+    mounts().iter().flatten().filter(|m| m.ns == ns).try_for_each(|m| pivot(&m.target).map(|_| ()))?;
+    let mut fits = Ok(());
+    ptable::for_each(|p| {
+        if p.mnt_ns == ns && fits.is_ok() {
+            fits = pivot_root(&p.root).map(|_| ());
+        }
+    });
+    fits?;
+
+    for mount in mounts().iter_mut().flatten().filter(|m| m.ns == ns) {
+        mount.target = pivot(&mount.target)?;
+    }
+    ptable::for_each(|p| {
+        if p.mnt_ns == ns {
+            p.root = pivot_root(&p.root).unwrap_or(Path::ROOT);
+        }
+    });
+
+    log::debug!("mount: namespace {} pivoted to {}, old root at {}", ns, new_root, put_old);
+    Ok(())
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn privileged(pid: u64) -> Process {
+        Process::new(pid, 0, "mount")
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn paths_normalise() {
+            assert_eq!(Path::new("/a/./b/../c//").unwrap().as_str(), "/a/c");
+            assert_eq!(Path::new("../../x").unwrap().as_str(), "/x");
+            assert_eq!(Path::new("/a/b").unwrap().join("../c").unwrap().as_str(), "/a/c");
+
+            let base = Path::new("/srv").unwrap();
+            assert_eq!(Path::new("/srv/www").unwrap().relative_to(&base), Path::new("/www").ok());
+            assert_eq!(Path::new("/srv").unwrap().relative_to(&base), Some(Path::ROOT));
+            assert_eq!(Path::new("/srvx").unwrap().relative_to(&base), None);
+
+            let long = [b'a'; MAX_PATH];
+            assert_eq!(Path::new(core::str::from_utf8(&long).unwrap()), Err(Errno::ENAMETOOLONG));
+        }
+
+        fn chroot_confines_lookups() {
+            let mut process = privileged(9100);
+            sys_unshare_mount(&mut process).unwrap();
+            sys_mount(&process, "proc", "/jail/proc").unwrap();
+            sys_chroot(&mut process, "/jail").unwrap();
+
+            let escape = resolve(&process, "/../../etc/passwd").unwrap();
+            assert_eq!((escape.fs, escape.path.as_str()), (FsType::RootFs, "/jail/etc/passwd"));
+            let proc = resolve(&process, "proc/meminfo").unwrap();
+            assert_eq!((proc.fs, proc.path.as_str()), (FsType::Proc, "/meminfo"));
+
+            // `/` inside the jail is the jail itself
+            assert_eq!(sys_umount(&process, "/"), Err(Errno::EINVAL));
+            sys_umount(&process, "/proc").unwrap();
+            assert_eq!(resolve(&process, "/proc").unwrap().fs, FsType::RootFs);
+            sys_chroot(&mut process, "/").unwrap();
+            assert_eq!(process.root.as_str(), "/jail");
+
+            release(&mut process);
+        }
+
+        fn unshared_mounts_are_private() {
+            let before = count(ROOT_NS);
+            let mut private = privileged(9110);
+            let shared = privileged(9111);
+            sys_unshare_mount(&mut private).unwrap();
+            assert_eq!(count(private.mnt_ns), before);
+
+            sys_mount(&private, "proc", "/private").unwrap();
+            assert_eq!(resolve(&private, "/private/x").unwrap().fs, FsType::Proc);
+            assert_eq!(resolve(&shared, "/private/x").unwrap().fs, FsType::RootFs);
+
+            let ns = private.mnt_ns;
+            release(&mut private);
+            assert_eq!((count(ns), count(ROOT_NS)), (0, before));
+        }
+
+        fn pivot_root_swaps_roots() {
+            let mut process = privileged(9120);
+            sys_unshare_mount(&mut process).unwrap();
+            sys_mount(&process, "9p", "/new").unwrap();
+            sys_mount(&process, "proc", "/new/proc").unwrap();
+
+            assert_eq!(sys_pivot_root(&process, "/new", "/elsewhere"), Err(Errno::EINVAL));
+            sys_pivot_root(&process, "/new", "/new/old").unwrap();
+
+            assert_eq!(resolve(&process, "/").unwrap().fs, FsType::P9);
+            assert_eq!(resolve(&process, "/proc").unwrap().fs, FsType::Proc);
+            assert_eq!(resolve(&process, "/old/bin").unwrap().fs, FsType::RootFs);
+
+            assert_eq!(sys_umount(&process, "/"), Err(Errno::EBUSY));
+            sys_umount(&process, "/old").unwrap();
+            assert_eq!(resolve(&process, "/old/bin").unwrap().fs, FsType::P9);
+
+            release(&mut process);
+        }
+
+        fn mounting_needs_privilege() {
+            let mut process = privileged(9130);
+            process.cred.cap_effective.remove(Capability::SysAdmin);
+            process.cred.cap_effective.remove(Capability::SysChroot);
+
+            assert_eq!(sys_mount(&process, "proc", "/proc2"), Err(Errno::EPERM));
+            assert_eq!(sys_unshare_mount(&mut process), Err(Errno::EPERM));
+            assert_eq!(sys_chroot(&mut process, "/tmp"), Err(Errno::EPERM));
+            assert_eq!(sys_mount(&privileged(9131), "ext4", "/x"), Err(Errno::ENODEV));
+        }
+    }
+}
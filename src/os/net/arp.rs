@@ -0,0 +1,211 @@
+//! ARP: finding the MAC address behind an IPv4 address on the local network.
+//!
+//! Every ARP packet that comes in teaches the cache its sender's addresses, and a request for
+//! the interface's own address is answered. [`lookup`] only consults the cache, asking the
+//! network when it misses, so it is safe in interrupt context; [`resolve`] waits for the
+//! answer, asking again a few times before giving up with `EHOSTUNREACH`. Entries are
+//! forgotten [`ENTRY_TTL_NS`] after they were last confirmed; a full cache forgets its
+//! oldest.
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::net::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4, Ipv4Addr, MacAddr, Packet};
+use crate::os::process::WaitTarget;
+use crate::os::sched;
+use crate::os::sync::SpinLock;
+use crate::os::timekeeping;
+
+/// Addresses the cache holds.
+pub const CACHE_SIZE: usize = 16;
+
+/// How long an entry is trusted.
+pub const ENTRY_TTL_NS: u64 = 300_000_000_000;
+
+// Requests `resolve` sends, and how long it waits for an answer to each
+const ATTEMPTS: u32 = 3;
+const ATTEMPT_NS: u64 = 1_000_000_000;
+
+// Processes waiting for an answer wait on `IODevice(WAIT_TARGET)`
+const WAIT_TARGET: u32 = 0xa4e7_0000;
+
+// Bytes of an Ethernet/IPv4 ARP packet, and its operations
+const PACKET_LEN: usize = 28;
+const HARDWARE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    ip: Ipv4Addr,
+    mac: MacAddr,
+    confirmed_ns: u64,
+}
+
+static CACHE: SpinLock<[Option<Entry>; CACHE_SIZE]> = SpinLock::new([None; CACHE_SIZE]);
+
+/// An ARP packet for Ethernet and IPv4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Parses `bytes`; `None` if they are not an Ethernet/IPv4 request or reply.
+    pub fn parse(bytes: &[u8]) -> Option<ArpPacket> {
+        if bytes.len() < PACKET_LEN {
+            return None;
+        }
+
+        let field = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        if field(0) != HARDWARE_ETHERNET || field(2) != ETHERTYPE_IPV4 || bytes[4] != 6 || bytes[5] != 4 {
+            return None;
+        }
+
+        let mac = |offset: usize| MacAddr([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3], bytes[offset + 4], bytes[offset + 5]]);
+        Some(ArpPacket {
+            op: field(6),
+            sender_mac: mac(8),
+            sender_ip: Ipv4Addr::from_slice(&bytes[14..18]),
+            target_mac: mac(18),
+            target_ip: Ipv4Addr::from_slice(&bytes[24..28]),
+        })
+        .filter(|packet| matches!(packet.op, OP_REQUEST | OP_REPLY))
+    }
+
+    /// The packet as sent on the wire.
+    pub fn to_bytes(&self) -> [u8; PACKET_LEN] {
+        let mut bytes = [0; PACKET_LEN];
+        bytes[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        bytes[6..8].copy_from_slice(&self.op.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac.0);
+        bytes[14..18].copy_from_slice(&self.sender_ip.0);
+        bytes[18..24].copy_from_slice(&self.target_mac.0);
+        bytes[24..28].copy_from_slice(&self.target_ip.0);
+        bytes
+    }
+}
+
+// Records that `ip` is at `mac`, as of now
+fn learn(ip: Ipv4Addr, mac: MacAddr) {
+    let now = timekeeping::monotonic_ns();
+    CACHE.with(|cache| {
+        let slot = match cache.iter().position(|entry| entry.is_some_and(|entry| entry.ip == ip)) {
+            Some(index) => index,
+            None => cache.iter().position(Option::is_none).unwrap_or_else(|| {
+                (0..CACHE_SIZE).min_by_key(|&index| cache[index].map_or(0, |entry| entry.confirmed_ns)).unwrap_or(0)
+            }),
+        };
+        cache[slot] = Some(Entry { ip, mac, confirmed_ns: now });
+    });
+    sched::wake(WaitTarget::IODevice(WAIT_TARGET));
+}
+
+// The cached address of `ip`, if still trusted
+fn cached(ip: Ipv4Addr) -> Option<MacAddr> {
+    let now = timekeeping::monotonic_ns();
+    CACHE.with(|cache| {
+        cache.iter().flatten().find(|entry| entry.ip == ip && now.saturating_sub(entry.confirmed_ns) < ENTRY_TTL_NS).map(|entry| entry.mac)
+    })
+}
+
+// Sends `packet` to `dst`
+fn send(dst: MacAddr, packet: ArpPacket) -> KResult<()> {
+    net::send(dst, ETHERTYPE_ARP, &mut Packet::new(&packet.to_bytes())?)
+}
+
+/// Asks the network who has `ip`.
+pub fn request(ip: Ipv4Addr) -> KResult<()> {
+    let mac = net::mac().ok_or(Errno::ENETDOWN)?;
+    let packet = ArpPacket { op: OP_REQUEST, sender_mac: mac, sender_ip: net::config().addr, target_mac: MacAddr::default(), target_ip: ip };
+    send(MacAddr::BROADCAST, packet)
+}
+
+/// The MAC address of `ip` if the cache has it; otherwise asks for it and returns `None`.
+pub fn lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    let mac = cached(ip);
+    if mac.is_none() {
+        let _ = request(ip);
+    }
+    mac
+}
+
+/// The MAC address of `ip`, asking the network and waiting for the answer if need be. Fails
+/// with `EHOSTUNREACH` when nothing answers. Only for process context.
+pub fn resolve(ip: Ipv4Addr) -> KResult<MacAddr> {
+    for _ in 0..ATTEMPTS {
+        if let Some(mac) = cached(ip) {
+            return Ok(mac);
+        }
+
+        request(ip)?;
+        let deadline = timekeeping::monotonic_ns() + ATTEMPT_NS;
+        if net::wait_until(WAIT_TARGET, deadline, || cached(ip).is_some()) {
+            break;
+        }
+    }
+    cached(ip).ok_or(Errno::EHOSTUNREACH)
+}
+
+/// Takes an ARP packet the interface received.
+pub fn receive(bytes: &[u8]) {
+    let Some(packet) = ArpPacket::parse(bytes) else {
+        return;
+    };
+
+    if packet.sender_ip != Ipv4Addr::UNSPECIFIED {
+        learn(packet.sender_ip, packet.sender_mac);
+    }
+
+    let config = net::config();
+    if packet.op == OP_REQUEST
+        && config.is_configured()
+        && packet.target_ip == config.addr
+        && let Some(mac) = net::mac()
+    {
+        let reply = ArpPacket { op: OP_REPLY, sender_mac: mac, sender_ip: config.addr, target_mac: packet.sender_mac, target_ip: packet.sender_ip };
+        let _ = send(packet.sender_mac, reply);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn packets_survive_the_wire() {
+            let packet = ArpPacket {
+                op: OP_REPLY,
+                sender_mac: MacAddr([0x52, 0x55, 10, 0, 2, 2]),
+                sender_ip: Ipv4Addr([10, 0, 2, 2]),
+                target_mac: MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]),
+                target_ip: Ipv4Addr([10, 0, 2, 15]),
+            };
+            let bytes = packet.to_bytes();
+            assert_eq!(&bytes[..8], &[0, 1, 8, 0, 6, 4, 0, 2]);
+            assert_eq!(ArpPacket::parse(&bytes), Some(packet));
+
+            // Truncated, or for another protocol, is no ARP packet of ours
+            assert_eq!(ArpPacket::parse(&bytes[..27]), None);
+            let mut other = bytes;
+            other[3] = 0xdd;
+            assert_eq!(ArpPacket::parse(&other), None);
+        }
+
+        fn senders_are_learned() {
+            let ip = Ipv4Addr([192, 0, 2, 77]);
+            assert_eq!(cached(ip), None);
+
+            let announcement = ArpPacket { op: OP_REPLY, sender_mac: MacAddr([2, 0, 0, 0, 0, 77]), sender_ip: ip, target_mac: MacAddr::default(), target_ip: ip };
+            receive(&announcement.to_bytes());
+            assert_eq!(cached(ip), Some(MacAddr([2, 0, 0, 0, 0, 77])));
+            assert_eq!(lookup(ip), Some(MacAddr([2, 0, 0, 0, 0, 77])));
+
+            CACHE.with(|cache| cache.iter_mut().filter(|entry| entry.is_some_and(|entry| entry.ip == ip)).for_each(|entry| *entry = None));
+        }
+    }
+}
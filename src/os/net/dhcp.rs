@@ -0,0 +1,344 @@
+//! DHCP client: leasing the interface an address.
+//!
+//! [`start`] broadcasts a DISCOVER; the first OFFER that answers it is asked for with a
+//! REQUEST, and the ACK to that configures the interface (see [`net::configure`]) with the
+//! offered address, netmask and router. Halfway through the lease the client asks the server
+//! to extend it, and takes the address away again if the lease runs out unextended. A NAK, or
+//! a server that stops answering, sends it back to discovering.
+//!
+//! Everything is broadcast, so nothing waits for ARP: the client runs from the interface's
+//! receive path and from a kernel timer that resends what went unanswered every
+//! [`RETRY_NS`].
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::net::{self, Config, Ipv4Addr, MacAddr, udp};
+use crate::os::random;
+use crate::os::sync::SpinLock;
+use crate::os::timekeeping;
+use crate::os::timer::{self, TimerId};
+
+/// UDP ports of the client and the server.
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+/// How long an unanswered message waits before it is sent again.
+pub const RETRY_NS: u64 = 4_000_000_000;
+
+// REQUESTs sent for one offer before discovering again
+const MAX_REQUESTS: u32 = 4;
+
+// Lease assumed when the ACK names none, in seconds
+const DEFAULT_LEASE_SECS: u64 = 3600;
+
+// BOOTP message: fixed fields, the magic cookie and then options, padded to the 300 bytes
+// old relays insist on
+const MESSAGE_LEN: usize = 300;
+const OPTIONS: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+// Options
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_REQUESTED_ADDR: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+/// Message types.
+pub const DISCOVER: u8 = 1;
+pub const OFFER: u8 = 2;
+pub const REQUEST: u8 = 3;
+pub const ACK: u8 = 5;
+pub const NAK: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Selecting,
+    Requesting { offered: Ipv4Addr, server: Ipv4Addr },
+    Bound { renew_ns: u64, expires_ns: u64 },
+    Renewing { expires_ns: u64 },
+}
+
+struct Client {
+    state: State,
+
+    // Transaction id of the exchange under way, and REQUESTs sent in it
+    xid: u32,
+    requests: u32,
+}
+
+static CLIENT: SpinLock<Client> = SpinLock::new(Client { state: State::Idle, xid: 0, requests: 0 });
+static TIMER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// What a server's reply says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    pub kind: u8,
+    pub xid: u32,
+
+    /// The address offered or leased.
+    pub addr: Ipv4Addr,
+
+    pub server: Ipv4Addr,
+    pub netmask: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub lease_secs: Option<u64>,
+}
+
+/// Builds a client message of type `kind` for transaction `xid` from the card `mac`, with
+/// `ciaddr` as the client's current address and `options` after the message type.
+pub fn message(kind: u8, xid: u32, mac: MacAddr, ciaddr: Ipv4Addr, options: &[(u8, &[u8])]) -> [u8; MESSAGE_LEN] {
+    let mut bytes = [0u8; MESSAGE_LEN];
+    bytes[0] = OP_REQUEST;
+    bytes[1] = 1;
+    bytes[2] = 6;
+    bytes[4..8].copy_from_slice(&xid.to_be_bytes());
+    bytes[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    bytes[12..16].copy_from_slice(&ciaddr.0);
+    bytes[28..34].copy_from_slice(&mac.0);
+    bytes[236..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut at = OPTIONS;
+    for &(code, value) in [(OPTION_MESSAGE_TYPE, &[kind][..])].iter().chain(options) {
+        bytes[at] = code;
+        bytes[at + 1] = value.len() as u8;
+        bytes[at + 2..at + 2 + value.len()].copy_from_slice(value);
+        at += 2 + value.len();
+    }
+    bytes[at] = OPTION_END;
+    bytes
+}
+
+/// Parses a server's reply to the card `mac`; `None` if it is not one.
+pub fn parse(bytes: &[u8], mac: MacAddr) -> Option<Reply> {
+    if bytes.len() < OPTIONS || bytes[0] != OP_REPLY || bytes[28..34] != mac.0 || bytes[236..OPTIONS] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut reply = Reply {
+        kind: 0,
+        xid: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        addr: Ipv4Addr::from_slice(&bytes[16..20]),
+        server: Ipv4Addr::from_slice(&bytes[20..24]),
+        netmask: None,
+        router: None,
+        lease_secs: None,
+    };
+
+    let mut at = OPTIONS;
+    while at < bytes.len() {
+        let code = bytes[at];
+        if code == OPTION_END {
+            break;
+        }
+        if code == OPTION_PAD {
+            at += 1;
+            continue;
+        }
+
+        let len = *bytes.get(at + 1)? as usize;
+        let value = bytes.get(at + 2..at + 2 + len)?;
+        match (code, len) {
+            (OPTION_MESSAGE_TYPE, 1) => reply.kind = value[0],
+            (OPTION_SUBNET_MASK, 4) => reply.netmask = Some(Ipv4Addr::from_slice(value)),
+            (OPTION_ROUTER, 4..) => reply.router = Some(Ipv4Addr::from_slice(value)),
+            (OPTION_SERVER_ID, 4) => reply.server = Ipv4Addr::from_slice(value),
+            (OPTION_LEASE_TIME, 4) => reply.lease_secs = Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]) as u64),
+            _ => {}
+        }
+        at += 2 + len;
+    }
+
+    (reply.kind != 0).then_some(reply)
+}
+
+// Parameters asked for in every DISCOVER and REQUEST
+const PARAMETERS: [u8; 4] = [OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_LEASE_TIME, OPTION_SERVER_ID];
+
+impl Client {
+    // Begins a new exchange and returns its DISCOVER
+    fn discover(&mut self, mac: MacAddr) -> [u8; MESSAGE_LEN] {
+        self.state = State::Selecting;
+        self.xid = random::next_u64() as u32;
+        self.requests = 0;
+        message(DISCOVER, self.xid, mac, Ipv4Addr::UNSPECIFIED, &[(OPTION_PARAMETERS, &PARAMETERS)])
+    }
+
+    // The REQUEST for the state the client is in, if it sends one
+    fn request(&mut self, mac: MacAddr) -> Option<[u8; MESSAGE_LEN]> {
+        self.requests += 1;
+        match self.state {
+            State::Requesting { offered, server } => Some(message(
+                REQUEST,
+                self.xid,
+                mac,
+                Ipv4Addr::UNSPECIFIED,
+                &[(OPTION_REQUESTED_ADDR, &offered.0), (OPTION_SERVER_ID, &server.0), (OPTION_PARAMETERS, &PARAMETERS)],
+            )),
+            State::Renewing { .. } => Some(message(REQUEST, self.xid, mac, net::config().addr, &[(OPTION_PARAMETERS, &PARAMETERS)])),
+            _ => None,
+        }
+    }
+
+    // Moves on for `reply`; returns the configuration to take, if it changes, and what to send
+    fn receive(&mut self, reply: &Reply, mac: MacAddr, now: u64) -> (Option<Config>, Option<[u8; MESSAGE_LEN]>) {
+        if reply.xid != self.xid {
+            return (None, None);
+        }
+
+        match (self.state, reply.kind) {
+            (State::Selecting, OFFER) => {
+                self.state = State::Requesting { offered: reply.addr, server: reply.server };
+                self.requests = 0;
+                (None, self.request(mac))
+            }
+            (State::Requesting { .. } | State::Renewing { .. }, ACK) => {
+                let lease_ns = reply.lease_secs.unwrap_or(DEFAULT_LEASE_SECS).saturating_mul(1_000_000_000);
+                self.state = State::Bound { renew_ns: now.saturating_add(lease_ns / 2), expires_ns: now.saturating_add(lease_ns) };
+                let config = Config {
+                    addr: reply.addr,
+                    netmask: reply.netmask.unwrap_or(Ipv4Addr([255, 255, 255, 0])),
+                    gateway: reply.router.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                };
+                (Some(config), None)
+            }
+            (State::Requesting { .. } | State::Renewing { .. }, NAK) => {
+                let released = matches!(self.state, State::Renewing { .. }).then(Config::default);
+                (released, Some(self.discover(mac)))
+            }
+            _ => (None, None),
+        }
+    }
+
+    // Moves on as time passes; returns the configuration to take, if it changes, and what to
+    // send again
+    fn tick(&mut self, mac: MacAddr, now: u64) -> (Option<Config>, Option<[u8; MESSAGE_LEN]>) {
+        match self.state {
+            State::Idle => (None, None),
+            State::Selecting => (None, Some(self.discover(mac))),
+            State::Requesting { .. } if self.requests >= MAX_REQUESTS => (None, Some(self.discover(mac))),
+            State::Requesting { .. } => (None, self.request(mac)),
+            State::Bound { renew_ns, expires_ns } if now >= renew_ns => {
+                self.state = State::Renewing { expires_ns };
+                self.xid = random::next_u64() as u32;
+                self.requests = 0;
+                (None, self.request(mac))
+            }
+            State::Bound { .. } => (None, None),
+            State::Renewing { expires_ns } if now >= expires_ns => (Some(Config::default()), Some(self.discover(mac))),
+            State::Renewing { .. } => (None, self.request(mac)),
+        }
+    }
+}
+
+// Takes the configuration `step` settled on and sends what it built
+fn apply((config, message): (Option<Config>, Option<[u8; MESSAGE_LEN]>)) {
+    if let Some(config) = config {
+        net::configure(config);
+    }
+    if let Some(message) = message
+        && let Err(errno) = udp::send_from(Ipv4Addr::UNSPECIFIED, CLIENT_PORT, Ipv4Addr::BROADCAST, SERVER_PORT, &message)
+    {
+        log::warn!("dhcp: cannot send: {:?}", errno);
+    }
+}
+
+fn retry(_id: TimerId, _data: usize, _expirations: u64) {
+    if let Some(mac) = net::mac() {
+        let now = timekeeping::monotonic_ns();
+        apply(CLIENT.with(|client| client.tick(mac, now)));
+    }
+}
+
+/// Starts leasing the interface an address, from scratch.
+pub fn start() {
+    let Some(mac) = net::mac() else {
+        return;
+    };
+
+    if !TIMER_STARTED.swap(true, Ordering::AcqRel)
+        && let Err(errno) = timer::add(RETRY_NS, RETRY_NS, retry, 0)
+    {
+        TIMER_STARTED.store(false, Ordering::Release);
+        log::warn!("dhcp: no timer, nothing will be resent: {:?}", errno);
+    }
+    apply((None, Some(CLIENT.with(|client| client.discover(mac)))));
+}
+
+/// Takes a message that came in on the client's port.
+pub fn receive(bytes: &[u8]) {
+    let Some(mac) = net::mac() else {
+        return;
+    };
+    let Some(reply) = parse(bytes, mac) else {
+        return;
+    };
+
+    let now = timekeeping::monotonic_ns();
+    apply(CLIENT.with(|client| client.receive(&reply, mac, now)));
+}
+
+pub mod ktests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56]);
+
+    // A server's reply of type `kind`, leasing 10.0.2.15 for an hour
+    fn reply(kind: u8, xid: u32) -> [u8; MESSAGE_LEN] {
+        let mut bytes = message(
+            kind,
+            xid,
+            MAC,
+            Ipv4Addr::UNSPECIFIED,
+            &[(OPTION_SUBNET_MASK, &[255, 255, 255, 0]), (OPTION_ROUTER, &[10, 0, 2, 2]), (OPTION_SERVER_ID, &[10, 0, 2, 2]), (OPTION_LEASE_TIME, &3600u32.to_be_bytes())],
+        );
+        bytes[0] = OP_REPLY;
+        bytes[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        bytes
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn messages_carry_their_options() {
+            let bytes = message(DISCOVER, 0x1234_5678, MAC, Ipv4Addr::UNSPECIFIED, &[(OPTION_PARAMETERS, &PARAMETERS)]);
+            assert_eq!((bytes[0], &bytes[4..8], &bytes[28..34]), (OP_REQUEST, &[0x12, 0x34, 0x56, 0x78][..], &MAC.0[..]));
+            assert_eq!(&bytes[236..246], &[99, 130, 83, 99, OPTION_MESSAGE_TYPE, 1, DISCOVER, OPTION_PARAMETERS, 4, OPTION_SUBNET_MASK]);
+
+            let parsed = parse(&reply(OFFER, 7), MAC).unwrap();
+            assert_eq!((parsed.kind, parsed.xid, parsed.addr, parsed.server), (OFFER, 7, Ipv4Addr([10, 0, 2, 15]), Ipv4Addr([10, 0, 2, 2])));
+            assert_eq!((parsed.netmask, parsed.router, parsed.lease_secs), (Some(Ipv4Addr([255, 255, 255, 0])), Some(Ipv4Addr([10, 0, 2, 2])), Some(3600)));
+
+            // Replies to other cards, and our own requests, are not for us
+            assert_eq!(parse(&reply(OFFER, 7), MacAddr([2; 6])), None);
+            assert_eq!(parse(&bytes, MAC), None);
+        }
+
+        fn a_lease_is_offered_acked_and_renewed() {
+            let mut client = Client { state: State::Idle, xid: 0, requests: 0 };
+            client.discover(MAC);
+            let xid = client.xid;
+
+            // A stale reply changes nothing; the offer is requested, and the ACK configures
+            assert_eq!(client.receive(&parse(&reply(OFFER, xid ^ 1), MAC).unwrap(), MAC, 0), (None, None));
+            let (config, request) = client.receive(&parse(&reply(OFFER, xid), MAC).unwrap(), MAC, 0);
+            assert_eq!((config, request.map(|bytes| bytes[OPTIONS + 2])), (None, Some(REQUEST)));
+            let (config, _) = client.receive(&parse(&reply(ACK, xid), MAC).unwrap(), MAC, 0);
+            assert_eq!(config.map(|config| (config.addr, config.gateway)), Some((Ipv4Addr([10, 0, 2, 15]), Ipv4Addr([10, 0, 2, 2]))));
+
+            // Halfway through the hour the lease is renewed; past its end it is given up
+            let half = 1800 * 1_000_000_000;
+            assert_eq!(client.tick(MAC, half - 1), (None, None));
+            assert!(client.tick(MAC, half).1.is_some());
+            assert_eq!(client.state, State::Renewing { expires_ns: 2 * half });
+            assert_eq!(client.tick(MAC, 2 * half).0, Some(Config::default()));
+            assert_eq!(client.state, State::Selecting);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! IPv4: datagrams in and out of the interface.
+//!
+//! Incoming datagrams are checked (version, header checksum, length) and handed to the
+//! protocol they carry -- only UDP has a taker -- if they are for the interface's address or
+//! broadcast, or for anyone while the interface has no address yet, which is how DHCP's
+//! answers reach it. Fragments are dropped: nothing here reassembles them, and nothing sent
+//! is fragmented either, so a datagram must fit the MTU.
+//!
+//! Outgoing datagrams go to the destination's MAC address if it is on the subnet and to the
+//! gateway's otherwise; finding either may have to wait for ARP.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::net::{self, ETHERTYPE_IPV4, Ipv4Addr, MacAddr, Packet, arp, udp};
+
+/// Bytes of header, without options.
+pub const HEADER_LEN: usize = 20;
+
+/// Protocol numbers.
+pub const PROTOCOL_UDP: u8 = 17;
+
+// Hops an outgoing datagram may take
+const DEFAULT_TTL: u8 = 64;
+
+// Flags and fragment offset: don't fragment, more fragments, and the offset's bits
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+// Identification of the next datagram sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// The parts of a header the stack looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+
+    /// Where the payload starts and ends in the datagram.
+    pub payload: (usize, usize),
+}
+
+/// Parses and checks the header of `datagram`; `None` if it is malformed, corrupt or a
+/// fragment.
+pub fn parse(datagram: &[u8]) -> Option<Header> {
+    if datagram.len() < HEADER_LEN || datagram[0] >> 4 != 4 {
+        return None;
+    }
+
+    let header_len = (datagram[0] & 0xf) as usize * 4;
+    let total_len = u16::from_be_bytes([datagram[2], datagram[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > datagram.len() {
+        return None;
+    }
+    if net::checksum(&[&datagram[..header_len]]) != 0 {
+        return None;
+    }
+
+    let fragment = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+        return None;
+    }
+
+    Some(Header {
+        src: Ipv4Addr::from_slice(&datagram[12..16]),
+        dst: Ipv4Addr::from_slice(&datagram[16..20]),
+        protocol: datagram[9],
+        payload: (header_len, total_len),
+    })
+}
+
+/// Writes the header of a datagram from `src` to `dst` carrying `payload_len` bytes of
+/// `protocol` into `header`.
+pub fn write_header(header: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload_len: usize) {
+    header[0] = 0x45;
+    header[1] = 0;
+    header[2..4].copy_from_slice(&((HEADER_LEN + payload_len) as u16).to_be_bytes());
+    header[4..6].copy_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    header[8] = DEFAULT_TTL;
+    header[9] = protocol;
+    header[10..12].fill(0);
+    header[12..16].copy_from_slice(&src.0);
+    header[16..20].copy_from_slice(&dst.0);
+
+    let checksum = net::checksum(&[&header[..HEADER_LEN]]);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Takes an IPv4 datagram the interface received.
+pub fn receive(datagram: &[u8]) {
+    let Some(header) = parse(datagram) else {
+        return;
+    };
+
+    let config = net::config();
+    let for_us = !config.is_configured() || header.dst == config.addr || header.dst == Ipv4Addr::BROADCAST || header.dst == config.broadcast();
+    if !for_us {
+        return;
+    }
+
+    let payload = &datagram[header.payload.0..header.payload.1];
+    if header.protocol == PROTOCOL_UDP {
+        udp::receive(header.src, header.dst, payload);
+    }
+}
+
+/// Sends `packet`, which holds a `protocol` payload, to `dst` from `src` (the interface's
+/// address when unspecified). Fails with `ENETDOWN` while the interface has no address,
+/// unless broadcasting, and `EHOSTUNREACH` when the next hop is not to be found. Waits for ARP
+/// unless broadcasting, so only broadcasts may be sent from interrupt context.
+pub fn send(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, packet: &mut Packet) -> KResult<()> {
+    let config = net::config();
+    let broadcast = dst == Ipv4Addr::BROADCAST || (config.is_configured() && dst == config.broadcast());
+
+    let mac = if broadcast {
+        MacAddr::BROADCAST
+    } else if !config.is_configured() {
+        return Err(Errno::ENETDOWN);
+    } else if config.on_subnet(dst) {
+        arp::resolve(dst)?
+    } else if config.gateway != Ipv4Addr::UNSPECIFIED {
+        arp::resolve(config.gateway)?
+    } else {
+        return Err(Errno::EHOSTUNREACH);
+    };
+
+    let src = if src == Ipv4Addr::UNSPECIFIED { config.addr } else { src };
+    let payload_len = packet.bytes().len();
+    write_header(packet.push(HEADER_LEN), src, dst, protocol, payload_len);
+    net::send(mac, ETHERTYPE_IPV4, packet)
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn headers_are_written_and_checked() {
+            let mut datagram = [0u8; HEADER_LEN + 4];
+            write_header(&mut datagram, Ipv4Addr([10, 0, 2, 15]), Ipv4Addr([10, 0, 2, 2]), PROTOCOL_UDP, 4);
+            datagram[HEADER_LEN..].copy_from_slice(b"ping");
+
+            let header = parse(&datagram).unwrap();
+            assert_eq!((header.src, header.dst, header.protocol), (Ipv4Addr([10, 0, 2, 15]), Ipv4Addr([10, 0, 2, 2]), PROTOCOL_UDP));
+            assert_eq!(&datagram[header.payload.0..header.payload.1], b"ping");
+
+            // Padding past the total length is not payload
+            let mut padded = [0u8; HEADER_LEN + 10];
+            padded[..HEADER_LEN + 4].copy_from_slice(&datagram);
+            assert_eq!(parse(&padded).map(|header| header.payload), Some((HEADER_LEN, HEADER_LEN + 4)));
+        }
+
+        fn bad_datagrams_are_dropped() {
+            let mut datagram = [0u8; HEADER_LEN];
+            write_header(&mut datagram, Ipv4Addr([10, 0, 2, 15]), Ipv4Addr([10, 0, 2, 2]), PROTOCOL_UDP, 0);
+            assert!(parse(&datagram).is_some());
+            assert_eq!(parse(&datagram[..HEADER_LEN - 1]), None);
+
+            let mut corrupt = datagram;
+            corrupt[8] ^= 1;
+            assert_eq!(parse(&corrupt), None);
+
+            // A fragment, with its checksum fixed up, is still dropped
+            let mut fragment = datagram;
+            fragment[6] |= (FLAG_MORE_FRAGMENTS >> 8) as u8;
+            fragment[10..12].fill(0);
+            let checksum = net::checksum(&[&fragment]);
+            fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+            assert_eq!(parse(&fragment), None);
+        }
+    }
+}
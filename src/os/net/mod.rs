@@ -0,0 +1,315 @@
+//! The network stack: Ethernet, ARP, IPv4 and UDP over one interface.
+//!
+//! A network driver [`attach`]es its card as the [`Interface`], handing over its MAC address
+//! and the function that sends a frame, and passes every frame it receives to [`receive`],
+//! from its interrupt handler. Frames go to [`arp`] or [`ipv4`] by EtherType, and IPv4
+//! datagrams on to [`udp`], which queues them on the socket bound to their port. Going out, a
+//! [`Packet`] holds the payload with room in front of it, where each layer writes its header
+//! on the way down.
+//!
+//! The interface's address, netmask and gateway are the [`Config`], which the [`dhcp`] client
+//! [`attach`] starts fills in; until then only broadcasts go out. Only frames for the
+//! interface's own address, or broadcast, come in. There is no IP fragmentation and no
+//! routing beyond "on the subnet, or through the gateway".
+
+pub mod arp;
+pub mod dhcp;
+pub mod ipv4;
+pub mod udp;
+
+use core::fmt;
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::WaitTarget;
+use crate::os::sched;
+use crate::os::sync::SpinLock;
+use crate::os::timekeeping;
+use crate::os::timer::{self, TimerId};
+
+/// Largest IP datagram a frame carries.
+pub const MTU: usize = 1500;
+
+/// Bytes of Ethernet header: destination, source and EtherType.
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Largest frame, without the FCS the card adds.
+pub const MAX_FRAME: usize = ETHERNET_HEADER_LEN + MTU;
+
+// Shorter frames are padded to this, the 64-byte minimum less the FCS
+const MIN_FRAME: usize = 60;
+
+/// EtherTypes.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+// Room a packet keeps in front of its payload: Ethernet, IPv4 and UDP headers
+const HEADROOM: usize = ETHERNET_HEADER_LEN + ipv4::HEADER_LEN + udp::HEADER_LEN;
+
+/// An Ethernet MAC address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// An IPv4 address, in network byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    pub const fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub const fn from_u32(value: u32) -> Self {
+        Ipv4Addr(value.to_be_bytes())
+    }
+
+    /// The address read from the four bytes at `bytes`, which must have them.
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        Ipv4Addr([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// The network card: its address and how a frame is sent on it.
+#[derive(Clone, Copy)]
+pub struct Interface {
+    pub mac: MacAddr,
+
+    /// Queues one whole frame, headers included, for sending. May be called from interrupt
+    /// context.
+    pub transmit: fn(frame: &[u8]) -> KResult<()>,
+}
+
+/// The interface's IPv4 configuration; all unspecified until DHCP has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Config {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+impl Config {
+    /// Whether the interface has an address.
+    pub fn is_configured(&self) -> bool {
+        self.addr != Ipv4Addr::UNSPECIFIED
+    }
+
+    /// Whether `addr` is on the interface's subnet.
+    pub fn on_subnet(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        addr.to_u32() & mask == self.addr.to_u32() & mask
+    }
+
+    /// The subnet's broadcast address.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | !self.netmask.to_u32())
+    }
+}
+
+static INTERFACE: SpinLock<Option<Interface>> = SpinLock::new(None);
+static CONFIG: SpinLock<Config> = SpinLock::new(Config { addr: Ipv4Addr::UNSPECIFIED, netmask: Ipv4Addr::UNSPECIFIED, gateway: Ipv4Addr::UNSPECIFIED });
+
+/// Makes `interface` the one the stack sends on and starts configuring it over DHCP. Fails
+/// with `EBUSY` if there already is one.
+pub fn attach(interface: Interface) -> KResult<()> {
+    INTERFACE.with(|slot| match slot {
+        Some(_) => Err(Errno::EBUSY),
+        None => {
+            *slot = Some(interface);
+            Ok(())
+        }
+    })?;
+
+    log::info!("net: interface {} up", interface.mac);
+    dhcp::start();
+    Ok(())
+}
+
+/// The interface's MAC address, if there is an interface.
+pub fn mac() -> Option<MacAddr> {
+    INTERFACE.with(|slot| slot.map(|interface| interface.mac))
+}
+
+/// The interface's current configuration.
+pub fn config() -> Config {
+    CONFIG.with(|config| *config)
+}
+
+/// Gives the interface `config`, as DHCP leased it; an unspecified address takes it away.
+pub fn configure(config: Config) {
+    CONFIG.with(|slot| *slot = config);
+    if config.is_configured() {
+        log::info!("net: address {} netmask {} gateway {}", config.addr, config.netmask, config.gateway);
+    } else {
+        log::info!("net: address released");
+    }
+}
+
+/// A frame on its way out, built back to front: the payload first, then each header in
+/// front of the one before.
+pub struct Packet {
+    buf: [u8; MAX_FRAME],
+    start: usize,
+    end: usize,
+}
+
+impl Packet {
+    /// A packet holding `payload`, with room for the Ethernet, IPv4 and UDP headers in front.
+    /// Fails with `EMSGSIZE` if they would not fit in a frame together.
+    pub fn new(payload: &[u8]) -> KResult<Packet> {
+        if payload.len() > MAX_FRAME - HEADROOM {
+            return Err(Errno::EMSGSIZE);
+        }
+
+        let mut packet = Packet { buf: [0; MAX_FRAME], start: HEADROOM, end: HEADROOM + payload.len() };
+        packet.buf[HEADROOM..packet.end].copy_from_slice(payload);
+        Ok(packet)
+    }
+
+    /// Makes room for a `len`-byte header in front of what the packet holds, and returns it.
+    pub fn push(&mut self, len: usize) -> &mut [u8] {
+        self.start -= len;
+        &mut self.buf[self.start..self.start + len]
+    }
+
+    /// What the packet holds, headers included.
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.end]
+    }
+
+    // Zero-pads the packet to `len` bytes, if shorter
+    fn pad_to(&mut self, len: usize) {
+        self.end = self.end.max(self.start + len);
+    }
+}
+
+/// The Internet checksum (RFC 1071) of `parts` taken as one run of bytes. Every part but the
+/// last must be of even length.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for pair in part.chunks(2) {
+            sum += u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32;
+        }
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Sends `packet`, which holds an `ethertype` payload, to the card with MAC address `dst`.
+/// Fails with `ENETDOWN` without an interface, and as the driver does.
+pub fn send(dst: MacAddr, ethertype: u16, packet: &mut Packet) -> KResult<()> {
+    let interface = INTERFACE.with(|slot| *slot).ok_or(Errno::ENETDOWN)?;
+
+    let header = packet.push(ETHERNET_HEADER_LEN);
+    header[0..6].copy_from_slice(&dst.0);
+    header[6..12].copy_from_slice(&interface.mac.0);
+    header[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    packet.pad_to(MIN_FRAME);
+
+    (interface.transmit)(packet.bytes())
+}
+
+/// Takes a frame the interface received. Called by its driver, from its interrupt handler.
+pub fn receive(frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return;
+    }
+
+    let dst = MacAddr([frame[0], frame[1], frame[2], frame[3], frame[4], frame[5]]);
+    if dst != MacAddr::BROADCAST && Some(dst) != mac() {
+        return;
+    }
+
+    let payload = &frame[ETHERNET_HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::receive(payload),
+        ETHERTYPE_IPV4 => ipv4::receive(payload),
+        _ => {}
+    }
+}
+
+// Wakes what waits on `IODevice(data)`, its deadline having passed
+fn wake_waiter(_id: TimerId, data: usize, _expirations: u64) {
+    sched::wake(WaitTarget::IODevice(data as u32));
+}
+
+/// Blocks the running process on `IODevice(target)` until `ready` holds or the monotonic
+/// clock passes `deadline_ns`, and returns whether `ready` held. Whatever makes it hold must
+/// wake `target`.
+pub fn wait_until(target: u32, deadline_ns: u64, mut ready: impl FnMut() -> bool) -> bool {
+    let timer = timer::add(deadline_ns.saturating_sub(timekeeping::monotonic_ns()), 0, wake_waiter, target as usize).ok();
+
+    sched::block_on(WaitTarget::IODevice(target), || ready() || timekeeping::monotonic_ns() >= deadline_ns);
+
+    if let Some(timer) = timer {
+        timer::cancel(timer);
+    }
+    ready()
+}
+
+pub mod ktests {
+    use alloc::format;
+
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn checksums_fold_their_carries() {
+            // The usual worked example of an IPv4 header checksum
+            let header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+            assert_eq!(checksum(&[&header]), 0xb861);
+
+            // Split anywhere even, the sum is the same; an odd tail is padded with a zero
+            assert_eq!(checksum(&[&header[..8], &header[8..]]), 0xb861);
+            assert_eq!(checksum(&[&[0xff, 0xff], &[0x01]]), !0x0100);
+        }
+
+        fn packets_grow_headers_in_front() {
+            let mut packet = Packet::new(b"data").unwrap();
+            packet.push(2).copy_from_slice(b"hd");
+            assert_eq!(packet.bytes(), b"hddata");
+
+            packet.pad_to(8);
+            assert_eq!(packet.bytes(), b"hddata\0\0");
+            assert!(Packet::new(&[0; MAX_FRAME - HEADROOM]).is_ok());
+            assert!(Packet::new(&[0; MAX_FRAME - HEADROOM + 1]).is_err());
+        }
+
+        fn addresses_print_and_configs_route() {
+            assert_eq!(format!("{} {}", Ipv4Addr([10, 0, 2, 15]), MacAddr([0x52, 0x54, 0, 0x12, 0x34, 0x56])), "10.0.2.15 52:54:00:12:34:56");
+
+            let config = Config { addr: Ipv4Addr([10, 0, 2, 15]), netmask: Ipv4Addr([255, 255, 255, 0]), gateway: Ipv4Addr([10, 0, 2, 2]) };
+            assert!(config.on_subnet(Ipv4Addr([10, 0, 2, 200])));
+            assert!(!config.on_subnet(Ipv4Addr([10, 0, 3, 1])));
+            assert_eq!(config.broadcast(), Ipv4Addr([10, 0, 2, 255]));
+            assert!(!Config::default().is_configured());
+        }
+    }
+}
@@ -0,0 +1,480 @@
+//! UDP, and the datagram sockets user space reaches it through.
+//!
+//! `socket(AF_INET, SOCK_DGRAM, 0)` takes a slot in the socket table and opens a [`File`] on
+//! it, its id [`SOCKET_FILE_BASE`] plus the slot, so the socket lives as long as some
+//! descriptor refers to it. A socket is bound to a local port by `bind`, or to a free one
+//! picked from the ephemeral range by its first `sendto`; ports below 1024 take
+//! `CAP_NET_BIND_SERVICE`. Datagrams for a bound port wait on its socket, up to
+//! [`QUEUE_LEN`] of them -- more are dropped, as UDP allows -- until `recvfrom` takes them,
+//! blocking on the socket while there are none. Datagrams for the DHCP client's port go to
+//! [`dhcp`] instead.
+//!
+//! [`File`]: crate::os::file::File
+
+use crate::os::capability::{self, Capability};
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file;
+use crate::os::lsm;
+use crate::os::net::{self, Ipv4Addr, Packet, dhcp, ipv4};
+use crate::os::process::{Process, WaitTarget};
+use crate::os::ptable;
+use crate::os::random;
+use crate::os::sched;
+use crate::os::sync::SpinLock;
+use crate::os::uaccess;
+
+/// Bytes of header: source port, destination port, length and checksum.
+pub const HEADER_LEN: usize = 8;
+
+/// Largest payload a datagram can carry without fragmenting.
+pub const MAX_PAYLOAD: usize = net::MTU - ipv4::HEADER_LEN - HEADER_LEN;
+
+/// Most sockets open at once, system-wide.
+pub const MAX_SOCKETS: usize = 16;
+
+/// Datagrams a socket holds until they are received.
+pub const QUEUE_LEN: usize = 4;
+
+/// File ids from here up stand for sockets in a process's descriptor table.
+pub const SOCKET_FILE_BASE: u32 = 0xffff_fe00;
+
+/// `socket` domain, type and protocol.
+pub const AF_INET: u32 = 2;
+pub const SOCK_DGRAM: u32 = 2;
+pub const IPPROTO_UDP: u32 = 17;
+
+/// `recvfrom` flag: fail with `EAGAIN` rather than block.
+pub const MSG_DONTWAIT: u32 = 0x40;
+
+// Ports `bind` and `sendto` pick from when not given one
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+// Ports below this take CAP_NET_BIND_SERVICE
+const PRIVILEGED_PORTS: u16 = 1024;
+
+// Sockets wait on `IODevice(WAIT_BASE + slot)`
+const WAIT_BASE: u32 = 0xa4e7_1000;
+
+/// `struct sockaddr_in`, as user space passes it: family, port and address in network byte
+/// order, and padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct SockAddrIn {
+    pub family: u16,
+    pub port: [u8; 2],
+    pub addr: [u8; 4],
+    pub zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub fn new(addr: Ipv4Addr, port: u16) -> Self {
+        SockAddrIn { family: AF_INET as u16, port: port.to_be_bytes(), addr: addr.0, zero: [0; 8] }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Datagram {
+    src: Ipv4Addr,
+    port: u16,
+    len: usize,
+    data: [u8; MAX_PAYLOAD],
+}
+
+struct Socket {
+    // Local port, 0 until bound
+    port: u16,
+
+    // Received datagrams, oldest at `head`
+    queue: [Datagram; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+const EMPTY: Datagram = Datagram { src: Ipv4Addr::UNSPECIFIED, port: 0, len: 0, data: [0; MAX_PAYLOAD] };
+
+static SOCKETS: SpinLock<[Option<Socket>; MAX_SOCKETS]> = SpinLock::new([const { None }; MAX_SOCKETS]);
+
+fn wait_target(socket: usize) -> WaitTarget {
+    WaitTarget::IODevice(WAIT_BASE + socket as u32)
+}
+
+/// Creates a socket and returns its slot. Fails with `ENOSPC` when [`MAX_SOCKETS`] are open.
+pub fn socket() -> KResult<usize> {
+    SOCKETS.with(|sockets| {
+        let slot = sockets.iter().position(Option::is_none).ok_or(Errno::ENOSPC)?;
+        sockets[slot] = Some(Socket { port: 0, queue: [EMPTY; QUEUE_LEN], head: 0, len: 0 });
+        Ok(slot)
+    })
+}
+
+/// Closes socket `socket`, dropping what it had queued and failing the receives waiting on it.
+pub fn close(socket: usize) {
+    SOCKETS.with(|sockets| sockets[socket] = None);
+    sched::wake(wait_target(socket));
+}
+
+/// The socket behind file id `file`, if it is a socket's.
+pub fn socket_index(file: u32) -> Option<usize> {
+    file.checked_sub(SOCKET_FILE_BASE).map(|socket| socket as usize).filter(|&socket| socket < MAX_SOCKETS)
+}
+
+/// Binds socket `socket` to local `port`, or to a free ephemeral port for 0, and returns the
+/// port. Fails with `EINVAL` if it is already bound and `EADDRINUSE` if the port is taken.
+pub fn bind(socket: usize, port: u16) -> KResult<u16> {
+    SOCKETS.with(|sockets| {
+        if sockets[socket].as_ref().ok_or(Errno::EBADF)?.port != 0 {
+            return Err(Errno::EINVAL);
+        }
+
+        let taken = |port: u16| sockets.iter().flatten().any(|socket| socket.port == port);
+        let port = match port {
+            0 => {
+                // Start somewhere random, so ports are hard to guess, and take the next free one
+                let span = (EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start()) as u64 + 1;
+                let first = random::below(span) as u16;
+                (0..span as u16)
+                    .map(|i| EPHEMERAL_PORTS.start() + (first + i) % span as u16)
+                    .find(|&port| !taken(port))
+                    .ok_or(Errno::EADDRINUSE)?
+            }
+            port if taken(port) => return Err(Errno::EADDRINUSE),
+            port => port,
+        };
+
+        if let Some(socket) = &mut sockets[socket] {
+            socket.port = port;
+        }
+        Ok(port)
+    })
+}
+
+// The local port of socket `socket`, binding it to an ephemeral one if it has none
+fn local_port(socket: usize) -> KResult<u16> {
+    match SOCKETS.with(|sockets| sockets[socket].as_ref().map(|socket| socket.port)) {
+        None => Err(Errno::EBADF),
+        Some(0) => bind(socket, 0),
+        Some(port) => Ok(port),
+    }
+}
+
+/// Sends `data` from local port `src_port` and address `src` (the interface's when
+/// unspecified) to port `port` of `dst`. Fails with `EMSGSIZE` for more than
+/// [`MAX_PAYLOAD`] bytes, and as [`ipv4::send`] does.
+pub fn send_from(src: Ipv4Addr, src_port: u16, dst: Ipv4Addr, port: u16, data: &[u8]) -> KResult<()> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let mut packet = Packet::new(data)?;
+    let len = (HEADER_LEN + data.len()) as u16;
+    let header = packet.push(HEADER_LEN);
+    header[0..2].copy_from_slice(&src_port.to_be_bytes());
+    header[2..4].copy_from_slice(&port.to_be_bytes());
+    header[4..6].copy_from_slice(&len.to_be_bytes());
+    header[6..8].fill(0);
+
+    let from = if src == Ipv4Addr::UNSPECIFIED { net::config().addr } else { src };
+    let checksum = match net::checksum(&[&pseudo_header(from, dst, len), packet.bytes()]) {
+        // 0 means "no checksum", so a sum of 0 is sent as its other form
+        0 => 0xffff,
+        sum => sum,
+    };
+    packet.bytes_mut()[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv4::send(src, dst, ipv4::PROTOCOL_UDP, &mut packet)
+}
+
+/// Sends `data` on socket `socket` to port `port` of `dst`, binding the socket first if it is
+/// not. Fails as [`send_from`] does.
+pub fn send(socket: usize, dst: Ipv4Addr, port: u16, data: &[u8]) -> KResult<()> {
+    let src_port = local_port(socket)?;
+    send_from(Ipv4Addr::UNSPECIFIED, src_port, dst, port, data)
+}
+
+// The pseudo header the checksum also covers
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, len: u16) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&src.0);
+    header[4..8].copy_from_slice(&dst.0);
+    header[9] = ipv4::PROTOCOL_UDP;
+    header[10..12].copy_from_slice(&len.to_be_bytes());
+    header
+}
+
+/// Takes a UDP datagram the interface received from `src` for `dst`, and queues it on the
+/// socket bound to its port.
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < HEADER_LEN {
+        return;
+    }
+
+    let field = |offset: usize| u16::from_be_bytes([datagram[offset], datagram[offset + 1]]);
+    let (src_port, port, len) = (field(0), field(2), field(4) as usize);
+    if len < HEADER_LEN || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if field(6) != 0 && net::checksum(&[&pseudo_header(src, dst, len as u16), datagram]) != 0 {
+        return;
+    }
+
+    let payload = &datagram[HEADER_LEN..];
+    if port == dhcp::CLIENT_PORT {
+        dhcp::receive(payload);
+        return;
+    }
+
+    let queued = SOCKETS.with(|sockets| {
+        let (slot, socket) = sockets.iter_mut().enumerate().find_map(|(slot, socket)| Some((slot, socket.as_mut().filter(|socket| socket.port == port)?)))?;
+        if socket.len == QUEUE_LEN {
+            return None;
+        }
+
+        let entry = &mut socket.queue[(socket.head + socket.len) % QUEUE_LEN];
+        entry.src = src;
+        entry.port = src_port;
+        entry.len = payload.len();
+        entry.data[..payload.len()].copy_from_slice(payload);
+        socket.len += 1;
+        Some(slot)
+    });
+
+    if let Some(slot) = queued {
+        sched::wake(wait_target(slot));
+    }
+}
+
+/// Receives the oldest datagram queued on socket `socket` into `buf`, returning its length
+/// (what did not fit in `buf` is lost), sender and sender's port. Blocks while there is none,
+/// unless `nonblock`, when it fails with `EAGAIN`; fails with `EBADF` if the socket is closed
+/// meanwhile.
+pub fn recv(socket: usize, buf: &mut [u8], nonblock: bool) -> KResult<(usize, Ipv4Addr, u16)> {
+    let mut take = || {
+        SOCKETS.with(|sockets| {
+            let socket = sockets[socket].as_mut().ok_or(Errno::EBADF)?;
+            if socket.len == 0 {
+                return Ok(None);
+            }
+
+            let datagram = &socket.queue[socket.head];
+            let len = datagram.len.min(buf.len());
+            buf[..len].copy_from_slice(&datagram.data[..len]);
+            let from = (len, datagram.src, datagram.port);
+            socket.head = (socket.head + 1) % QUEUE_LEN;
+            socket.len -= 1;
+            Ok(Some(from))
+        })
+    };
+
+    loop {
+        if let Some(result) = take().transpose() {
+            return result;
+        }
+        if nonblock {
+            return Err(Errno::EAGAIN);
+        }
+
+        sched::block_on(wait_target(socket), || SOCKETS.with(|sockets| sockets[socket].as_ref().is_none_or(|socket| socket.len > 0)));
+    }
+}
+
+/// The socket behind descriptor `fd` of `process`. Fails with `EBADF` if `fd` is not open and
+/// `ENOTSOCK` if it is no socket.
+pub fn socket_of(process: &Process, fd: usize) -> KResult<usize> {
+    socket_index(process.file(fd)?.id).ok_or(Errno::ENOTSOCK)
+}
+
+/// `socket(domain, type, protocol)`: creates a UDP socket and returns a descriptor for it in
+/// the caller `caller`. Fails with `EAFNOSUPPORT` for a domain other than `AF_INET` and
+/// `EPROTONOSUPPORT` for anything but UDP.
+pub fn sys_socket(caller: u64, domain: u32, kind: u32, protocol: u32) -> KResult<usize> {
+    if domain != AF_INET {
+        return Err(Errno::EAFNOSUPPORT);
+    }
+    if kind != SOCK_DGRAM || !matches!(protocol, 0 | IPPROTO_UDP) {
+        return Err(Errno::EPROTONOSUPPORT);
+    }
+    ptable::with_process(caller, |process| lsm::check_socket_create(process, domain, kind)).unwrap_or(Err(Errno::ESRCH))?;
+
+    let socket = socket()?;
+    // Once the file exists, dropping it closes the socket
+    let file = match file::open(SOCKET_FILE_BASE + socket as u32) {
+        Ok(file) => file,
+        Err(errno) => {
+            close(socket);
+            return Err(errno);
+        }
+    };
+
+    // Without a descriptor the socket is closed as `file` goes, which wakes its waiters and so
+    // must wait for the table to be unlocked
+    ptable::with_process(caller, |process| process.alloc_fd(file.clone())).unwrap_or(Err(Errno::ESRCH))
+}
+
+// Reads the `struct sockaddr_in` of `len` bytes at `addr`
+fn read_addr(addr: usize, len: usize) -> KResult<SockAddrIn> {
+    if len < size_of::<SockAddrIn>() {
+        return Err(Errno::EINVAL);
+    }
+
+    // Any bit pattern is a `SockAddrIn`
+    let addr: SockAddrIn = unsafe { uaccess::read_user(addr)? };
+    if addr.family != AF_INET as u16 {
+        return Err(Errno::EAFNOSUPPORT);
+    }
+    Ok(addr)
+}
+
+/// `bind(fd, addr, addrlen)` on socket `socket`, for a caller with credentials `cred`: binds
+/// it to the port in the `struct sockaddr_in` at `addr`, which must name the interface's
+/// address or none. Fails with `EPERM` for a privileged port without `CAP_NET_BIND_SERVICE`,
+/// and as [`bind`] does.
+pub fn sys_bind(cred: &Credentials, socket: usize, addr: usize, len: usize) -> KResult<()> {
+    let addr = read_addr(addr, len)?;
+
+    let ip = Ipv4Addr(addr.addr);
+    if ip != Ipv4Addr::UNSPECIFIED && ip != net::config().addr {
+        return Err(Errno::EINVAL);
+    }
+
+    let port = u16::from_be_bytes(addr.port);
+    if port != 0 && port < PRIVILEGED_PORTS {
+        capability::require(cred, Capability::NetBindService)?;
+    }
+    bind(socket, port).map(|_| ())
+}
+
+/// `sendto(fd, buf, len, flags, addr, addrlen)` on socket `socket`: sends the `len` bytes at
+/// `buf` as one datagram to the `struct sockaddr_in` at `addr`, and returns `len`. Fails with
+/// `EDESTADDRREQ` without an address, there being no connected sockets, and as [`send`] does.
+/// May wait for ARP.
+pub fn sys_sendto(socket: usize, buf: usize, len: usize, flags: u32, addr: usize, addr_len: usize) -> KResult<usize> {
+    if flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if addr == 0 {
+        return Err(Errno::EDESTADDRREQ);
+    }
+    if len > MAX_PAYLOAD {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    let addr = read_addr(addr, addr_len)?;
+    let mut data = [0u8; MAX_PAYLOAD];
+    uaccess::copy_from_user(&mut data[..len], buf)?;
+
+    send(socket, Ipv4Addr(addr.addr), u16::from_be_bytes(addr.port), &data[..len])?;
+    Ok(len)
+}
+
+/// `recvfrom(fd, buf, len, flags, addr, addrlen)` on socket `socket`: receives a datagram
+/// into the `len` bytes at `buf` and returns how many bytes were received, storing the sender
+/// at `addr` and its size at `addrlen` unless `addr` is null. Only `MSG_DONTWAIT` is
+/// accepted in `flags`. Fails with `EFAULT`, before taking a datagram, if `buf` is not user
+/// memory, and as [`recv`] does.
+pub fn sys_recvfrom(socket: usize, buf: usize, len: usize, flags: u32, addr: usize, addr_len: usize) -> KResult<usize> {
+    if flags & !MSG_DONTWAIT != 0 {
+        return Err(Errno::EINVAL);
+    }
+    if !uaccess::is_user_range(buf, len) {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut data = [0u8; MAX_PAYLOAD];
+    let (received, src, port) = recv(socket, &mut data[..len.min(MAX_PAYLOAD)], flags & MSG_DONTWAIT != 0)?;
+    uaccess::copy_to_user(buf, &data[..received])?;
+
+    if addr != 0 {
+        let room: u32 = unsafe { uaccess::read_user(addr_len)? };
+        let from = SockAddrIn::new(src, port);
+        let bytes = unsafe { core::slice::from_raw_parts(&from as *const SockAddrIn as *const u8, size_of::<SockAddrIn>()) };
+        uaccess::copy_to_user(addr, &bytes[..(room as usize).min(bytes.len())])?;
+        uaccess::write_user(addr_len, &(size_of::<SockAddrIn>() as u32))?;
+    }
+    Ok(received)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::kobject::KRef;
+
+    // A datagram for `port` from 10.0.2.2:5353, with a valid checksum
+    fn datagram(port: u16, payload: &[u8]) -> ([u8; 64], usize) {
+        let mut bytes = [0u8; 64];
+        let len = HEADER_LEN + payload.len();
+        bytes[0..2].copy_from_slice(&5353u16.to_be_bytes());
+        bytes[2..4].copy_from_slice(&port.to_be_bytes());
+        bytes[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        bytes[HEADER_LEN..len].copy_from_slice(payload);
+
+        let checksum = net::checksum(&[&pseudo_header(Ipv4Addr([10, 0, 2, 2]), Ipv4Addr([10, 0, 2, 15]), len as u16), &bytes[..len]]);
+        bytes[6..8].copy_from_slice(&checksum.to_be_bytes());
+        (bytes, len)
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn datagrams_queue_on_their_port() {
+            let socket = socket().unwrap();
+            assert_eq!(bind(socket, 9450), Ok(9450));
+            assert_eq!(bind(socket, 9451), Err(Errno::EINVAL));
+
+            let (bytes, len) = datagram(9450, b"hello");
+            receive(Ipv4Addr([10, 0, 2, 2]), Ipv4Addr([10, 0, 2, 15]), &bytes[..len]);
+
+            // Short buffers truncate; an empty queue does not block when asked not to
+            let mut buf = [0u8; 4];
+            assert_eq!(recv(socket, &mut buf, true), Ok((4, Ipv4Addr([10, 0, 2, 2]), 5353)));
+            assert_eq!(&buf, b"hell");
+            assert_eq!(recv(socket, &mut buf, true), Err(Errno::EAGAIN));
+
+            // A corrupt datagram, or one for another port, goes nowhere
+            let (mut bytes, len) = datagram(9450, b"hello");
+            bytes[HEADER_LEN] ^= 1;
+            receive(Ipv4Addr([10, 0, 2, 2]), Ipv4Addr([10, 0, 2, 15]), &bytes[..len]);
+            let (bytes, len) = datagram(9452, b"hello");
+            receive(Ipv4Addr([10, 0, 2, 2]), Ipv4Addr([10, 0, 2, 15]), &bytes[..len]);
+            assert_eq!(recv(socket, &mut buf, true), Err(Errno::EAGAIN));
+
+            close(socket);
+        }
+
+        fn full_queues_drop_and_ports_are_unique() {
+            let (first, second) = (socket().unwrap(), socket().unwrap());
+            assert_eq!(bind(first, 9453), Ok(9453));
+            assert_eq!(bind(second, 9453), Err(Errno::EADDRINUSE));
+            assert!(EPHEMERAL_PORTS.contains(&bind(second, 0).unwrap()));
+
+            for i in 0..QUEUE_LEN + 2 {
+                let (bytes, len) = datagram(9453, &[i as u8]);
+                receive(Ipv4Addr([10, 0, 2, 2]), Ipv4Addr([10, 0, 2, 15]), &bytes[..len]);
+            }
+            let mut buf = [0u8; 1];
+            for i in 0..QUEUE_LEN {
+                assert_eq!(recv(first, &mut buf, true).map(|(len, ..)| (len, buf[0])), Ok((1, i as u8)));
+            }
+            assert_eq!(recv(first, &mut buf, true), Err(Errno::EAGAIN));
+
+            close(first);
+            close(second);
+            assert_eq!(recv(first, &mut buf, true), Err(Errno::EBADF));
+        }
+
+        fn sockets_are_files() {
+            ptable::insert(Process::new(9450, 0, "udp")).unwrap();
+            assert_eq!(sys_socket(9450, 1, SOCK_DGRAM, 0), Err(Errno::EAFNOSUPPORT));
+            assert_eq!(sys_socket(9450, AF_INET, 1, 0), Err(Errno::EPROTONOSUPPORT));
+
+            let fd = sys_socket(9450, AF_INET, SOCK_DGRAM, IPPROTO_UDP).unwrap();
+            let mut process = ptable::remove(9450).unwrap();
+            let socket = socket_of(&process, fd).unwrap();
+            assert_eq!(KRef::count(process.file(fd).unwrap()), 1);
+            assert_eq!(bind(socket, 9454), Ok(9454));
+
+            // The last descriptor takes the socket with it
+            assert!(file::sys_close(&mut process, fd).is_ok());
+            assert_eq!(bind(socket, 9454), Err(Errno::EBADF));
+        }
+    }
+}
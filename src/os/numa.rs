@@ -0,0 +1,367 @@
+//! NUMA topology.
+//!
+//! The SRAT assigns memory ranges and CPUs (by APIC ID) to proximity domains, and the optional
+//! SLIT gives the relative access cost between them. Domains are renumbered into dense node IDs
+//! `0..node_count()`; without an SRAT the whole machine is node 0.
+//!
+//! The frame allocator asks [`fallback_order`] which nodes to try for an allocation: the
+//! requested node (by default the node of the calling CPU) first, then the others from nearest
+//! to farthest.
+
+use crate::os::acpi::{self, HEADER_SIZE, read_u8, read_u32, read_u64};
+use crate::os::percpu;
+
+/// Maximum number of nodes tracked.
+pub const MAX_NODES: usize = 8;
+
+/// Maximum number of SRAT memory ranges tracked.
+const MAX_RANGES: usize = 32;
+
+// CPUs tracked from the SRAT: all a machine may list, even in a build that only brings up the
+// boot CPU, which need not come first
+const MAX_SRAT_CPUS: usize = 64;
+
+/// SLIT distance of a node to itself, and the default to any other node.
+pub const LOCAL_DISTANCE: u8 = 10;
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// A physical memory range belonging to one node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAffinity {
+    pub start: u64,
+    pub end: u64,
+    pub node: u8,
+}
+
+/// The parsed SRAT/SLIT contents.
+#[derive(Debug, Clone, Copy)]
+pub struct Topology {
+    /// Proximity domain of each node.
+    domains: [u32; MAX_NODES],
+    nodes: usize,
+
+    ranges: [Option<MemoryAffinity>; MAX_RANGES],
+
+    /// APIC ID and node of each enabled CPU.
+    cpus: [Option<(u32, u8)>; MAX_SRAT_CPUS],
+
+    distances: [[u8; MAX_NODES]; MAX_NODES],
+}
+
+impl Topology {
+    /// A machine with a single node holding all CPUs and memory.
+    pub const fn uniform() -> Self {
+        let mut distances = [[REMOTE_DISTANCE; MAX_NODES]; MAX_NODES];
+        let mut i = 0;
+        while i < MAX_NODES {
+            distances[i][i] = LOCAL_DISTANCE;
+            i += 1;
+        }
+
+        Topology { domains: [0; MAX_NODES], nodes: 1, ranges: [None; MAX_RANGES], cpus: [None; MAX_SRAT_CPUS], distances }
+    }
+
+    /// Number of nodes.
+    pub fn node_count(&self) -> usize {
+        self.nodes
+    }
+
+    // Node ID for a proximity domain, assigning the next free one on first sight
+    fn node_for_domain(&mut self, domain: u32) -> Option<u8> {
+        if let Some(node) = self.domains[..self.nodes].iter().position(|d| *d == domain) {
+            return Some(node as u8);
+        }
+
+        if self.nodes == MAX_NODES {
+            log::warn!("numa: more than {} proximity domains, folding domain {} into node 0", MAX_NODES, domain);
+            return None;
+        }
+
+        self.domains[self.nodes] = domain;
+        self.nodes += 1;
+        Some((self.nodes - 1) as u8)
+    }
+
+    /// Parses a System Resource Affinity Table.
+    pub fn parse_srat(srat: &[u8]) -> Self {
+        let mut topology = Topology::uniform();
+        topology.nodes = 0;
+
+        // 12 reserved bytes follow the header
+        let mut offset = HEADER_SIZE + 12;
+
+        while offset + 2 <= srat.len() {
+            let (kind, len) = (read_u8(srat, offset), read_u8(srat, offset + 1) as usize);
+            if len == 0 {
+                break;
+            }
+
+            let entry = &srat[offset..(offset + len).min(srat.len())];
+            topology.add_srat_entry(kind, entry);
+            offset += len;
+        }
+
+        // Firmware that lists nothing usable still describes one node
+        topology.nodes = topology.nodes.max(1);
+        topology
+    }
+
+    fn add_srat_entry(&mut self, kind: u8, entry: &[u8]) {
+        const ENABLED: u32 = 1;
+
+        match kind {
+            // Processor local APIC affinity: domain bits 0-7 at 2, 8-31 at 9..12
+            0 if read_u32(entry, 4) & ENABLED != 0 => {
+                let domain = read_u8(entry, 2) as u32 | (read_u32(entry, 8) & 0xffff_ff00);
+                let apic_id = read_u8(entry, 3) as u32;
+                self.add_cpu(apic_id, domain);
+            }
+
+            // Memory affinity
+            1 if read_u32(entry, 28) & ENABLED != 0 => {
+                let domain = read_u32(entry, 2);
+                let start = read_u64(entry, 8);
+                let end = start + read_u64(entry, 16);
+                let node = self.node_for_domain(domain).unwrap_or(0);
+
+                match self.ranges.iter_mut().find(|r| r.is_none()) {
+                    Some(slot) => *slot = Some(MemoryAffinity { start, end, node }),
+                    None => log::warn!("numa: too many memory ranges, {:#x}..{:#x} left on node 0", start, end),
+                }
+            }
+
+            // Processor local x2APIC affinity
+            2 if read_u32(entry, 12) & ENABLED != 0 => {
+                let domain = read_u32(entry, 4);
+                let apic_id = read_u32(entry, 8);
+                self.add_cpu(apic_id, domain);
+            }
+
+            _ => {}
+        }
+    }
+
+    fn add_cpu(&mut self, apic_id: u32, domain: u32) {
+        let node = self.node_for_domain(domain).unwrap_or(0);
+
+        if let Some(slot) = self.cpus.iter_mut().find(|c| c.is_none()) {
+            *slot = Some((apic_id, node));
+        }
+    }
+
+    /// Fills in distances from a System Locality Information Table. Localities are proximity
+    /// domains; pairs involving unknown domains keep their defaults.
+    pub fn apply_slit(&mut self, slit: &[u8]) {
+        let localities = read_u64(slit, HEADER_SIZE) as usize;
+        let matrix = HEADER_SIZE + 8;
+
+        for (a, &from) in self.domains[..self.nodes].iter().enumerate() {
+            for (b, &to) in self.domains[..self.nodes].iter().enumerate() {
+                let (from, to) = (from as usize, to as usize);
+
+                if from < localities && to < localities {
+                    match read_u8(slit, matrix + from * localities + to) {
+                        // 0 never appears in a valid table; 255 means unreachable, keep it
+                        0 => {}
+                        distance => self.distances[a][b] = distance,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Node of the memory at `addr`, and the address where the next range starts (beyond which
+    /// the node may differ). Memory the SRAT does not cover is node 0.
+    pub fn node_span(&self, addr: u64) -> (u8, u64) {
+        let ranges = self.ranges.iter().flatten();
+
+        if let Some(range) = ranges.clone().find(|r| r.start <= addr && addr < r.end) {
+            return (range.node, range.end);
+        }
+
+        let next = ranges.map(|r| r.start).filter(|start| *start > addr).min().unwrap_or(u64::MAX);
+        (0, next)
+    }
+
+    /// Node of the CPU with APIC ID `apic_id` (node 0 if the SRAT does not list it).
+    pub fn node_of_apic(&self, apic_id: u32) -> u8 {
+        self.cpus.iter().flatten().find(|(id, _)| *id == apic_id).map_or(0, |(_, node)| *node)
+    }
+
+    /// Relative cost of node `from` accessing memory on node `to`.
+    pub fn distance(&self, from: u8, to: u8) -> u8 {
+        self.distances[from as usize][to as usize]
+    }
+}
+
+static mut TOPOLOGY: Topology = Topology::uniform();
+
+fn topology() -> &'static Topology {
+    // Written once by init() before any other CPU runs, read-only afterwards
+    unsafe {
+        let topology = &raw const TOPOLOGY;
+        &*topology
+    }
+}
+
+/// Reads the SRAT and SLIT. Called once at boot after ACPI discovery, before the per-CPU areas
+/// and the memory map are set up so both get tagged with node IDs.
+pub fn init() {
+    let Some(srat) = acpi::find_table(b"SRAT") else {
+        log::info!("numa: no SRAT, assuming a single node");
+        return;
+    };
+
+    let mut parsed = Topology::parse_srat(srat);
+    if let Some(slit) = acpi::find_table(b"SLIT") {
+        parsed.apply_slit(slit);
+    }
+
+    unsafe {
+        let topology = &raw mut TOPOLOGY;
+        *topology = parsed;
+    }
+
+    log::info!("numa: {} node(s)", parsed.node_count());
+}
+
+/// Number of NUMA nodes.
+pub fn node_count() -> usize {
+    topology().node_count()
+}
+
+/// Node of the memory at `addr` and the end of the span it belongs to; see [`Topology::node_span`].
+pub fn node_span(addr: u64) -> (u8, u64) {
+    topology().node_span(addr)
+}
+
+/// Node of the CPU with APIC ID `apic_id`.
+pub fn node_of_apic(apic_id: u32) -> u8 {
+    topology().node_of_apic(apic_id)
+}
+
+/// Node of the CPU this code runs on.
+pub fn current_node() -> u8 {
+    percpu::node(percpu::cpu_id())
+}
+
+/// Relative cost of node `from` accessing memory on node `to` (10 = local).
+pub fn distance(from: u8, to: u8) -> u8 {
+    topology().distance(from, to)
+}
+
+/// Where an allocation should be placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// On the node of the allocating CPU, falling back to the nearest others.
+    Local,
+
+    /// On the given node, falling back to the nearest others.
+    Preferred(u8),
+
+    /// On the given node only.
+    Bind(u8),
+}
+
+/// Nodes to try for an allocation, in order.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeOrder {
+    nodes: [u8; MAX_NODES],
+    len: usize,
+}
+
+impl NodeOrder {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.nodes[..self.len]
+    }
+}
+
+/// Returns the nodes to allocate from for `placement`: the preferred node first, then (unless
+/// bound) every other node by increasing distance from it.
+pub fn fallback_order(placement: Placement) -> NodeOrder {
+    let count = node_count();
+
+    let (first, bound) = match placement {
+        Placement::Local => (current_node(), false),
+        Placement::Preferred(node) => (node, false),
+        Placement::Bind(node) => (node, true),
+    };
+    let first = if (first as usize) < count { first } else { 0 };
+
+    let mut order = NodeOrder { nodes: [0; MAX_NODES], len: 0 };
+    order.nodes[0] = first;
+    order.len = 1;
+
+    if !bound {
+        for node in (0..count as u8).filter(|n| *n != first) {
+            order.nodes[order.len] = node;
+            order.len += 1;
+        }
+
+        order.nodes[1..order.len].sort_unstable_by_key(|n| distance(first, *n));
+    }
+
+    order
+}
+
+pub mod ktests {
+    use super::*;
+
+    // An SRAT with two domains (7 and 3), one CPU each, and two memory ranges
+    fn sample_srat() -> [u8; 48 + 16 * 2 + 40 * 2] {
+        let mut srat = [0u8; 48 + 16 * 2 + 40 * 2];
+        let mut at = 48;
+
+        for (apic_id, domain) in [(0u8, 7u8), (1, 3)] {
+            srat[at..at + 4].copy_from_slice(&[0, 16, domain, apic_id]);
+            srat[at + 4] = 1;
+            at += 16;
+        }
+
+        for (domain, start, len) in [(7u32, 0u64, 0x8000_0000u64), (3, 0x1_0000_0000, 0x8000_0000)] {
+            srat[at] = 1;
+            srat[at + 1] = 40;
+            srat[at + 2..at + 6].copy_from_slice(&domain.to_le_bytes());
+            srat[at + 8..at + 16].copy_from_slice(&start.to_le_bytes());
+            srat[at + 16..at + 24].copy_from_slice(&len.to_le_bytes());
+            srat[at + 28] = 1;
+            at += 40;
+        }
+
+        srat
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn srat_assigns_dense_node_ids() {
+            let topology = Topology::parse_srat(&sample_srat());
+
+            assert_eq!(topology.node_count(), 2);
+            assert_eq!(topology.node_of_apic(0), 0);
+            assert_eq!(topology.node_of_apic(1), 1);
+            assert_eq!(topology.node_of_apic(42), 0);
+        }
+
+        fn memory_spans_follow_ranges() {
+            let topology = Topology::parse_srat(&sample_srat());
+
+            assert_eq!(topology.node_span(0x1000), (0, 0x8000_0000));
+            assert_eq!(topology.node_span(0x9000_0000), (0, 0x1_0000_0000));
+            assert_eq!(topology.node_span(0x1_2000_0000), (1, 0x1_8000_0000));
+        }
+
+        fn slit_overrides_distances() {
+            let mut topology = Topology::parse_srat(&sample_srat());
+
+            // 8 localities; domain 7 -> 3 costs 31, 3 -> 7 costs 32
+            let mut slit = [0u8; HEADER_SIZE + 8 + 64];
+            slit[HEADER_SIZE] = 8;
+            slit[HEADER_SIZE + 8 + 7 * 8 + 3] = 31;
+            slit[HEADER_SIZE + 8 + 3 * 8 + 7] = 32;
+            topology.apply_slit(&slit);
+
+            assert_eq!(topology.distance(0, 1), 31);
+            assert_eq!(topology.distance(1, 0), 32);
+            assert_eq!(topology.distance(0, 0), LOCAL_DISTANCE);
+        }
+    }
+}
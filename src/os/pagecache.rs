@@ -0,0 +1,473 @@
+//! The page cache: file contents held in frames, one page per file offset.
+//!
+//! File-backed mappings fault pages in through [`get`], which reads a page from the installed
+//! [`FileStore`] on first use and charges it to the faulting process's group as cache. A page
+//! stays cached while any mapping uses it and after, until the memory controller reclaims it.
+//! Shared writable mappings mark pages dirty with [`mark_dirty`]; [`writeback`] writes dirty
+//! pages back to their files, and [`run_writeback`] does so every `vm.dirty_writeback_ms`, or
+//! sooner once more than `vm.dirty_background_ratio` percent of the cache is dirty.
+//!
+//! Pages are keyed by the file ids held in process descriptor tables; the cache never opens or
+//! closes files itself.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::os::arch;
+use crate::os::cgroup::{self, GroupId, PageKind};
+use crate::os::clocksource;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::mmap;
+use crate::os::numa::Placement;
+use crate::os::sysctl;
+
+/// Maximum number of pages cached at once.
+pub const MAX_PAGES: usize = 256;
+
+/// File contents the cache reads pages from and writes them back to, provided by whatever
+/// backs process file descriptors.
+pub trait FileStore: Sync {
+    /// Current size of `file` in bytes.
+    fn size(&self, file: u32) -> KResult<u64>;
+
+    /// Reads from `file` at `offset` into `buf`, returning the number of bytes read.
+    fn read(&self, file: u32, offset: u64, buf: &mut [u8]) -> KResult<usize>;
+
+    /// Writes `data` to `file` at `offset`, returning the number of bytes written.
+    fn write(&self, file: u32, offset: u64, data: &[u8]) -> KResult<usize>;
+}
+
+static mut FILE_STORE: Option<&'static dyn FileStore> = None;
+
+/// Installs the store pages are read from and written back to.
+pub fn set_file_store(store: &'static dyn FileStore) {
+    unsafe {
+        let slot = &raw mut FILE_STORE;
+        *slot = Some(store);
+    }
+}
+
+fn file_store() -> Option<&'static dyn FileStore> {
+    unsafe {
+        let slot = &raw const FILE_STORE;
+        *slot
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedPage {
+    file: u32,
+    index: u64,
+    frame: u64,
+
+    // Group the page is charged to
+    group: GroupId,
+
+    // Mappings currently using the frame
+    mapped: u32,
+
+    dirty: bool,
+
+    // Being written back: the frame must stay even if the page looks clean and unused
+    writeback: bool,
+}
+
+static mut PAGES: [Option<CachedPage>; MAX_PAGES] = [None; MAX_PAGES];
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the page table holding its lock, with interrupts masked so a holder cannot be
+// interrupted by something spinning on it
+fn locked<R>(f: impl FnOnce(&mut [Option<CachedPage>; MAX_PAGES]) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let pages = &raw mut PAGES;
+            f(&mut *pages)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+fn find(pages: &mut [Option<CachedPage>; MAX_PAGES], file: u32, index: u64) -> Option<&mut CachedPage> {
+    pages.iter_mut().flatten().find(|page| page.file == file && page.index == index)
+}
+
+// Frees the frame of a page dropped from the table and its charge
+fn free_page(page: CachedPage) {
+    if let Some(allocator) = memory::frame_allocator() {
+        allocator.free_frame(page.frame);
+    }
+    cgroup::uncharge(page.group, PageKind::Cache, 1);
+}
+
+// Drops clean, unused pages charged to groups in `group`'s subtree until `pages` are gone,
+// returning how many were
+fn drop_clean(group: GroupId, pages: u64) -> u64 {
+    let mut dropped = 0;
+
+    while dropped < pages {
+        let victim = locked(|table| {
+            table
+                .iter_mut()
+                .find(|slot| slot.is_some_and(|page| page.mapped == 0 && !page.dirty && !page.writeback && cgroup::is_within(page.group, group)))
+                .and_then(Option::take)
+        });
+        let Some(page) = victim else {
+            break;
+        };
+
+        free_page(page);
+        dropped += 1;
+    }
+
+    dropped
+}
+
+// Fills `frame` with page `index` of `file`, zeroing whatever lies past the end of the file
+fn read_page(store: &dyn FileStore, file: u32, index: u64, frame: u64) -> KResult<()> {
+    // Frames are identity-mapped
+    let buf = unsafe { core::slice::from_raw_parts_mut(frame as *mut u8, FRAME_SIZE as usize) };
+    let mut done = 0;
+
+    while done < buf.len() {
+        let read = store.read(file, index * FRAME_SIZE + done as u64, &mut buf[done..])?;
+        if read == 0 {
+            break;
+        }
+        done += read;
+    }
+
+    buf[done..].fill(0);
+    Ok(())
+}
+
+/// Returns the frame holding page `index` of `file` for a new mapping of it, reading it in and
+/// charging it to `group` if it is not cached yet. Every call must be paired with a [`put`]
+/// once the mapping is gone. Fails with `EFAULT` for a page wholly past the end of the file,
+/// `ENOMEM` when no frame or cache slot can be had, and with whatever the read fails with.
+pub fn get(file: u32, index: u64, group: GroupId) -> KResult<u64> {
+    let cached = locked(|pages| {
+        find(pages, file, index).map(|page| {
+            page.mapped += 1;
+            page.frame
+        })
+    });
+    if let Some(frame) = cached {
+        return Ok(frame);
+    }
+
+    let store = file_store().ok_or(Errno::ENODEV)?;
+    if index * FRAME_SIZE >= store.size(file)? {
+        return Err(Errno::EFAULT);
+    }
+
+    cgroup::charge(group, PageKind::Cache, 1)?;
+    let Some(frame) = memory::frame_allocator().and_then(|allocator| allocator.alloc_frame(Placement::Local)) else {
+        cgroup::uncharge(group, PageKind::Cache, 1);
+        return Err(Errno::ENOMEM);
+    };
+    let ours = CachedPage { file, index, frame, group, mapped: 1, dirty: false, writeback: false };
+
+    if let Err(errno) = read_page(store, file, index, frame) {
+        free_page(ours);
+        return Err(errno);
+    }
+
+    // Another fault may have read the same page in the meantime; its copy wins
+    let insert = || {
+        locked(|pages| {
+            if let Some(page) = find(pages, file, index) {
+                page.mapped += 1;
+                return Ok(Some(page.frame));
+            }
+            let slot = pages.iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOMEM)?;
+            *slot = Some(ours);
+            Ok(None)
+        })
+    };
+
+    // A full table makes room by dropping some clean, unused page
+    let mut inserted = insert();
+    if inserted.is_err() && drop_clean(cgroup::ROOT_GROUP, 1) == 1 {
+        inserted = insert();
+    }
+
+    match inserted {
+        Ok(None) => Ok(frame),
+        Ok(Some(theirs)) => {
+            free_page(ours);
+            Ok(theirs)
+        }
+        Err(errno) => {
+            free_page(ours);
+            Err(errno)
+        }
+    }
+}
+
+/// Releases a mapping's hold on page `index` of `file` taken by [`get`]. The page stays cached.
+pub fn put(file: u32, index: u64) {
+    locked(|pages| {
+        if let Some(page) = find(pages, file, index) {
+            page.mapped = page.mapped.saturating_sub(1);
+        }
+    });
+}
+
+/// The frame caching page `index` of `file`, if it is cached.
+pub fn lookup(file: u32, index: u64) -> Option<u64> {
+    locked(|pages| find(pages, file, index).map(|page| page.frame))
+}
+
+// Whether `dirty` pages are more than `vm.dirty_background_ratio` percent of the cache
+fn over_background_ratio(dirty: usize) -> bool {
+    let ratio = sysctl::DIRTY_BACKGROUND_RATIO.load(Ordering::Relaxed) as usize;
+    dirty * 100 > MAX_PAGES * ratio
+}
+
+/// Marks page `index` of `file` as modified, so the next writeback writes it to the file, and
+/// asks for that writeback to come early if too much of the cache is dirty.
+pub fn mark_dirty(file: u32, index: u64) {
+    let dirty = locked(|pages| {
+        if let Some(page) = find(pages, file, index) {
+            page.dirty = true;
+        }
+        pages.iter().flatten().filter(|page| page.dirty).count()
+    });
+
+    if over_background_ratio(dirty) {
+        kick_writeback();
+    }
+}
+
+// Writes one dirty page back, up to the end of the file
+fn write_page(store: &dyn FileStore, page: &CachedPage) -> KResult<()> {
+    let offset = page.index * FRAME_SIZE;
+    let len = store.size(page.file)?.saturating_sub(offset).min(FRAME_SIZE) as usize;
+
+    // Frames are identity-mapped
+    let data = unsafe { core::slice::from_raw_parts(page.frame as *const u8, len) };
+    let mut done = 0;
+
+    while done < data.len() {
+        let written = store.write(page.file, offset + done as u64, &data[done..])?;
+        if written == 0 {
+            return Err(Errno::EIO);
+        }
+        done += written;
+    }
+
+    Ok(())
+}
+
+// Writes back the dirty pages `select` picks, one at a time. Each page is write-protected in
+// the mappings sharing it before it is written, so a store made during the write dirties it
+// again rather than being lost.
+fn write_dirty(select: impl Fn(&CachedPage) -> bool) -> KResult<usize> {
+    let Some(store) = file_store() else {
+        return Ok(0);
+    };
+    let mut written = 0;
+
+    loop {
+        let next = locked(|pages| {
+            let page = pages.iter_mut().flatten().find(|page| page.dirty && select(page))?;
+            page.dirty = false;
+            page.writeback = true;
+            Some(*page)
+        });
+        let Some(page) = next else {
+            break;
+        };
+
+        mmap::write_protect(page.file, page.index);
+        let outcome = write_page(store, &page);
+
+        locked(|pages| {
+            if let Some(cached) = find(pages, page.file, page.index) {
+                cached.writeback = false;
+                cached.dirty |= outcome.is_err();
+            }
+        });
+
+        if let Err(errno) = outcome {
+            log::warn!("pagecache: writeback of file {} page {} failed: {:?}", page.file, page.index, errno);
+            return Err(errno);
+        }
+        written += 1;
+    }
+
+    Ok(written)
+}
+
+/// Writes the dirty pages of `file`, or of every file with `None`, back to their files and
+/// returns how many were written. Stops at the first page that fails to write, which stays
+/// dirty for the next pass.
+pub fn writeback(file: Option<u32>) -> KResult<usize> {
+    write_dirty(|page| file.is_none_or(|file| page.file == file))
+}
+
+/// Like [`writeback`], for the pages of `file` with indices in `pages` only.
+pub fn writeback_range(file: u32, pages: Range<u64>) -> KResult<usize> {
+    write_dirty(|page| page.file == file && pages.contains(&page.index))
+}
+
+// Time of the last periodic writeback pass, in nanoseconds
+static LAST_WRITEBACK: AtomicU64 = AtomicU64::new(0);
+
+// Set when the next pass should not wait for the interval
+static KICKED: AtomicBool = AtomicBool::new(false);
+
+/// Makes the next [`run_writeback`] write dirty pages back whether or not the interval has
+/// passed.
+pub fn kick_writeback() {
+    KICKED.store(true, Ordering::Relaxed);
+}
+
+/// Writes dirty pages back if `vm.dirty_writeback_ms` has passed since the last pass or a pass
+/// was asked for with [`kick_writeback`]. Called from the idle loop.
+pub fn run_writeback() {
+    let now = clocksource::now_ns();
+    let interval = sysctl::DIRTY_WRITEBACK_MS.load(Ordering::Relaxed) * 1_000_000;
+
+    let last = LAST_WRITEBACK.load(Ordering::Relaxed);
+    let due = KICKED.swap(false, Ordering::Relaxed) || now.saturating_sub(last) >= interval;
+    if !due || LAST_WRITEBACK.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+        return;
+    }
+
+    let _ = writeback(None);
+}
+
+/// Page cache figures, in pages.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub cached: u64,
+    pub dirty: u64,
+}
+
+/// Returns how many pages are cached and how many of them are dirty.
+pub fn stats() -> CacheStats {
+    locked(|pages| {
+        pages.iter().flatten().fold(CacheStats::default(), |stats, page| CacheStats {
+            cached: stats.cached + 1,
+            dirty: stats.dirty + (page.dirty || page.writeback) as u64,
+        })
+    })
+}
+
+// The memory controller's reclaim hook
+struct Cache;
+
+impl cgroup::PageCache for Cache {
+    fn reclaim_clean(&self, group: GroupId, pages: u64) -> u64 {
+        drop_clean(group, pages)
+    }
+}
+
+static CACHE: Cache = Cache;
+
+/// Hands the cache to the memory controller, which drops clean pages before swapping.
+pub fn init() {
+    cgroup::set_page_cache(&CACHE);
+}
+
+pub mod ktests {
+    use super::*;
+
+    const FILE: u32 = 0x7c00;
+
+    // Two and a half pages of a repeating pattern; writes land in `written`
+    struct TestStore {
+        written: AtomicU64,
+    }
+
+    const SIZE: u64 = 2 * FRAME_SIZE + FRAME_SIZE / 2;
+
+    fn pattern(offset: u64) -> u8 {
+        (offset % 251) as u8
+    }
+
+    impl FileStore for TestStore {
+        fn size(&self, _file: u32) -> KResult<u64> {
+            Ok(SIZE)
+        }
+
+        fn read(&self, _file: u32, offset: u64, buf: &mut [u8]) -> KResult<usize> {
+            // Short reads, to exercise the loop
+            let len = buf.len().min(1000).min(SIZE.saturating_sub(offset) as usize);
+            for (i, byte) in buf[..len].iter_mut().enumerate() {
+                *byte = pattern(offset + i as u64);
+            }
+            Ok(len)
+        }
+
+        fn write(&self, _file: u32, _offset: u64, data: &[u8]) -> KResult<usize> {
+            self.written.fetch_add(data.len() as u64, Ordering::Relaxed);
+            Ok(data.len())
+        }
+    }
+
+    #[repr(C, align(4096))]
+    struct Frame([u8; FRAME_SIZE as usize]);
+
+    static mut FRAME: Frame = Frame([0xff; FRAME_SIZE as usize]);
+
+    fn frame() -> u64 {
+        unsafe {
+            let frame = &raw mut FRAME;
+            (*frame).0.as_ptr() as u64
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn pages_are_read_and_padded_with_zeroes() {
+            let store = TestStore { written: AtomicU64::new(0) };
+            let bytes = unsafe { &*(frame() as *const [u8; FRAME_SIZE as usize]) };
+
+            read_page(&store, FILE, 1, frame()).unwrap();
+            assert!(bytes.iter().enumerate().all(|(i, &b)| b == pattern(FRAME_SIZE + i as u64)));
+
+            // Only half of the last page is in the file
+            read_page(&store, FILE, 2, frame()).unwrap();
+            let half = FRAME_SIZE as usize / 2;
+            assert!(bytes[..half].iter().enumerate().all(|(i, &b)| b == pattern(2 * FRAME_SIZE + i as u64)));
+            assert!(bytes[half..].iter().all(|&b| b == 0));
+        }
+
+        fn writeback_stops_at_the_end_of_the_file() {
+            let store = TestStore { written: AtomicU64::new(0) };
+            let page = |index| CachedPage { file: FILE, index, frame: frame(), group: cgroup::ROOT_GROUP, mapped: 0, dirty: true, writeback: false };
+
+            write_page(&store, &page(0)).unwrap();
+            assert_eq!(store.written.load(Ordering::Relaxed), FRAME_SIZE);
+
+            write_page(&store, &page(2)).unwrap();
+            assert_eq!(store.written.load(Ordering::Relaxed), FRAME_SIZE + FRAME_SIZE / 2);
+
+            write_page(&store, &page(3)).unwrap();
+            assert_eq!(store.written.load(Ordering::Relaxed), FRAME_SIZE + FRAME_SIZE / 2);
+        }
+
+        fn dirty_ratio_kicks_writeback() {
+            let ratio = sysctl::DIRTY_BACKGROUND_RATIO.load(Ordering::Relaxed);
+            sysctl::DIRTY_BACKGROUND_RATIO.store(25, Ordering::Relaxed);
+
+            assert!(!over_background_ratio(MAX_PAGES / 4));
+            assert!(over_background_ratio(MAX_PAGES / 4 + 1));
+
+            // At 0 any dirty page is too many
+            sysctl::DIRTY_BACKGROUND_RATIO.store(0, Ordering::Relaxed);
+            assert!(!over_background_ratio(0));
+            assert!(over_background_ratio(1));
+
+            sysctl::DIRTY_BACKGROUND_RATIO.store(ratio, Ordering::Relaxed);
+        }
+    }
+}
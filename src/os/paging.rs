@@ -0,0 +1,445 @@
+//! x86_64 page tables: the kernel's own PML4 and per-process address spaces.
+//!
+//! The firmware leaves the kernel on an identity map, which is where it still runs. [`init`]
+//! replaces that root with one the kernel owns: the lower half keeps the firmware's identity
+//! entries, and the upper half gets a direct map of all RAM at [`PHYS_OFFSET`] and an alias of
+//! the kernel image at [`KERNEL_BASE`], mapped section by section with the rights the image
+//! headers give (code read-only, data non-executable). Every upper-half slot is given a table
+//! up front, so the kernel half is the same set of tables in every address space and kernel
+//! mappings made later -- vmalloc's, say -- show up in all of them.
+//!
+//! A process's address space shares the kernel half and the identity slots below
+//! [`USER_START`] with the kernel root, and owns the user range above them. Only user
+//! mappings carry the user bit, and the identity slots have it cleared at the root, so ring 3
+//! reaches nothing of the kernel's. [`map_page`],
+//! [`unmap_page`] and [`translate`] work on any root; tables are identity-mapped, like all
+//! frames.
+
+use uefi::table::boot::MemoryType;
+
+use crate::os::arch::x86_64::pte::{self, ENTRIES, GLOBAL, HUGE_PAGE, PRESENT, USER};
+use crate::os::arch::{Arch, Current};
+use crate::os::boot;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::numa::Placement;
+use crate::os::process::Process;
+use crate::os::protection::Protection;
+use crate::os::tlb;
+
+/// Where the direct map of physical memory starts: physical address `p` is also at
+/// `PHYS_OFFSET + p`.
+pub const PHYS_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// Where the alias of the kernel image starts.
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// Lowest user address. The slots below it hold the firmware's identity map, which the kernel
+/// runs on.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+
+/// End of the user range (the lower half).
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// First upper-half PML4 slot
+const KERNEL_SLOT: usize = ENTRIES / 2;
+
+// First PML4 slot of the user range
+const USER_SLOT: usize = (USER_START >> 39) as usize;
+
+const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+// The table at physical address `phys`
+fn table(phys: u64) -> &'static mut [u64; ENTRIES] {
+    unsafe { &mut *(phys as *mut [u64; ENTRIES]) }
+}
+
+fn alloc_table() -> Option<u64> {
+    let frame = memory::frame_allocator()?.alloc_frame(Placement::Local)?;
+    table(frame).fill(0);
+    Some(frame)
+}
+
+fn free_table(phys: u64) {
+    if let Some(allocator) = memory::frame_allocator() {
+        allocator.free_frame(phys);
+    }
+}
+
+// Entry flags for a mapping with `protection`; kernel mappings are global
+fn entry_flags(protection: Protection) -> KResult<u64> {
+    let flags = protection.pte_flags()?;
+    Ok(if protection.user { flags } else { flags | GLOBAL })
+}
+
+/// Returns the direct-map address of physical address `phys`.
+pub fn phys_to_virt(phys: u64) -> u64 {
+    PHYS_OFFSET + phys
+}
+
+// Maps `[virt, virt + len)` to `[phys, phys + len)` with `flags`, in 2 MiB pages where both
+// sides are aligned and the rest is covered, otherwise in 4 KiB pages
+fn map_range(root: u64, virt: u64, phys: u64, len: u64, flags: u64) -> Option<()> {
+    let mut offset = 0;
+
+    while offset < len {
+        let (v, p) = (virt + offset, phys + offset);
+
+        if (v | p).is_multiple_of(HUGE_PAGE_SIZE) && len - offset >= HUGE_PAGE_SIZE {
+            let entry = pte::huge_or_create(root, v, &mut alloc_table)?;
+            if unsafe { *entry } == 0 {
+                unsafe { *entry = Current::page_entry(p, Current::huge_flags(flags)) };
+                offset += HUGE_PAGE_SIZE;
+                continue;
+            }
+        }
+
+        let entry = pte::leaf_or_create(root, v, &mut alloc_table)?;
+        unsafe { *entry = Current::page_entry(p, flags) };
+        offset += FRAME_SIZE;
+    }
+
+    Some(())
+}
+
+// Memory the direct map covers: RAM of any use, but no MMIO or holes
+fn is_ram(ty: MemoryType) -> bool {
+    !matches!(ty, MemoryType::RESERVED | MemoryType::UNUSABLE | MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE | MemoryType::PAL_CODE)
+}
+
+/// Rights of a section of a PE image, in bytes from the image base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Section {
+    start: u64,
+    end: u64,
+    write: bool,
+    exec: bool,
+}
+
+const MAX_SECTIONS: usize = 16;
+
+// PE section characteristics
+const SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+fn read_u16(image: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(image.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(image: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(image.get(at..at + 4)?.try_into().ok()?))
+}
+
+// The sections listed in the PE headers at the start of `image`, or none if they do not parse
+fn sections(image: &[u8]) -> ([Option<Section>; MAX_SECTIONS], usize) {
+    let mut found = [None; MAX_SECTIONS];
+    let parse = |found: &mut [Option<Section>; MAX_SECTIONS]| -> Option<usize> {
+        if image.get(..2)? != b"MZ" {
+            return None;
+        }
+        let pe = read_u32(image, 0x3c)? as usize;
+        if image.get(pe..pe + 4)? != b"PE\0\0" {
+            return None;
+        }
+
+        let count = read_u16(image, pe + 6)? as usize;
+        let table = pe + 24 + read_u16(image, pe + 20)? as usize;
+        for (i, slot) in found.iter_mut().enumerate().take(count) {
+            let header = table + i * 40;
+            let (size, start, flags) = (read_u32(image, header + 8)?, read_u32(image, header + 12)?, read_u32(image, header + 36)?);
+            *slot = Some(Section {
+                start: start as u64,
+                end: start as u64 + size as u64,
+                write: flags & SCN_MEM_WRITE != 0,
+                exec: flags & SCN_MEM_EXECUTE != 0,
+            });
+        }
+        Some(count.min(MAX_SECTIONS))
+    };
+
+    let count = parse(&mut found).unwrap_or(0);
+    (found, count)
+}
+
+// Rights of the image page `offset` bytes from its base: those of the section it starts in,
+// read-only for the headers and anything between sections
+fn image_protection(sections: &[Option<Section>], offset: u64) -> Protection {
+    match sections.iter().flatten().find(|s| s.start <= offset && offset < s.end) {
+        Some(section) if section.exec => Protection::code(false),
+        Some(section) if section.write => Protection::data(false),
+        _ => Protection::read_only(false),
+    }
+}
+
+// Maps the kernel image at KERNEL_BASE
+fn map_image(root: u64) -> Option<()> {
+    let (start, end) = boot::image_extent();
+    if start == end {
+        return Some(());
+    }
+
+    // The image is identity-mapped; its headers sit at its base
+    let image = unsafe { core::slice::from_raw_parts(start as *const u8, (end - start) as usize) };
+    let (sections, count) = sections(image);
+
+    let mut offset = 0;
+    while start + offset < end {
+        let flags = entry_flags(image_protection(&sections[..count], offset)).ok()?;
+        map_range(root, KERNEL_BASE + offset, start + offset, FRAME_SIZE, flags)?;
+        offset += FRAME_SIZE;
+    }
+    Some(())
+}
+
+// Builds the kernel root on top of the firmware's, whose lower half it keeps
+fn build_kernel_root(firmware: u64) -> Option<u64> {
+    let root = alloc_table()?;
+    let (old, new) = (table(firmware), table(root));
+    new[..KERNEL_SLOT].copy_from_slice(&old[..KERNEL_SLOT]);
+
+    // The identity map is the kernel's alone, whatever the firmware's entries below allow
+    new[..USER_SLOT].iter_mut().for_each(|entry| *entry &= !USER);
+
+    // Whatever the firmware had in the upper half stays; every other slot gets its table now
+    for slot in KERNEL_SLOT..ENTRIES {
+        new[slot] = if old[slot] & PRESENT != 0 { old[slot] } else { pte::table_entry(alloc_table()?) };
+    }
+
+    let flags = entry_flags(Protection::data(false)).ok()?;
+    for entry in memory::memory_map().iter().filter(|e| is_ram(e.ty)) {
+        map_range(root, phys_to_virt(entry.start), entry.start, entry.pages * FRAME_SIZE, flags)?;
+    }
+    map_image(root)?;
+
+    Some(root)
+}
+
+/// Builds the kernel's own root and switches to it. Called once the frame allocator is up;
+/// without frames the kernel stays on the firmware's tables.
+pub fn init() {
+    crate::trace_fn!();
+
+    let firmware = tlb::kernel_root();
+    if firmware == 0 {
+        return;
+    }
+
+    let Some(root) = build_kernel_root(firmware) else {
+        log::error!("paging: out of frames building the kernel page tables, staying on the firmware's");
+        return;
+    };
+
+    // The lower half is the firmware's, so the running code and stack stay mapped
+    unsafe { tlb::set_kernel_root(root) };
+    log::info!("paging: kernel root at {:#x}, RAM mapped at {:#x}", root, PHYS_OFFSET);
+}
+
+/// Gives `process` a fresh address space: an empty user range, with the kernel half shared.
+/// Fails with `EEXIST` if it already has one and `ENOMEM` when no frame is free or the kernel
+/// runs without translation.
+pub fn create_address_space(process: &mut Process) -> KResult<()> {
+    if process.page_table_root != 0 {
+        return Err(Errno::EEXIST);
+    }
+
+    let kernel = tlb::kernel_root();
+    if kernel == 0 {
+        return Err(Errno::ENOMEM);
+    }
+
+    let root = alloc_table().ok_or(Errno::ENOMEM)?;
+    let (old, new) = (table(kernel), table(root));
+    new[..USER_SLOT].copy_from_slice(&old[..USER_SLOT]);
+    new[KERNEL_SLOT..].copy_from_slice(&old[KERNEL_SLOT..]);
+
+    process.page_table_root = root as usize;
+    Ok(())
+}
+
+// Frees the tables below the table entry `entry` at `level` (3 = PDPT, ..., 1 = PT), then the
+// table itself; leaf pages belong to whoever mapped them
+fn free_tables(entry: u64, level: u32) {
+    if entry & PRESENT == 0 || entry & HUGE_PAGE != 0 {
+        return;
+    }
+
+    let phys = Current::entry_address(entry);
+    if level > 1 {
+        table(phys).iter().for_each(|&entry| free_tables(entry, level - 1));
+    }
+    free_table(phys);
+}
+
+/// Frees `process`'s address space: its user-range tables and its root. Whoever mapped pages
+/// into it frees those first. Called when it exits.
+pub fn release(process: &mut Process) {
+    let root = process.page_table_root as u64;
+    if root == 0 {
+        return;
+    }
+
+    // No CPU may still walk the tables once they are gone
+    tlb::release_address_space(root);
+    table(root)[USER_SLOT..KERNEL_SLOT].iter().for_each(|&entry| free_tables(entry, 3));
+    free_table(root);
+    process.page_table_root = 0;
+}
+
+/// Maps the 4 KiB page at `virt` to the frame at `phys` with `protection` in the tables at
+/// `root`, creating missing tables. User mappings go in the user range, kernel ones in the
+/// upper half. Fails with `EINVAL` for a misaligned address or one outside its range,
+/// `EACCES` for a writable and executable user mapping, `EEXIST` if the page is mapped and
+/// `ENOMEM` when no frame is free for a table.
+pub fn map_page(root: u64, virt: u64, phys: u64, protection: Protection) -> KResult<()> {
+    if !virt.is_multiple_of(FRAME_SIZE) || !phys.is_multiple_of(FRAME_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+
+    let in_range = if protection.user { (USER_START..USER_END).contains(&virt) } else { virt >= PHYS_OFFSET };
+    if !in_range {
+        return Err(Errno::EINVAL);
+    }
+
+    let flags = entry_flags(protection)?;
+    let entry = pte::leaf_or_create(root, virt, &mut alloc_table).ok_or(Errno::ENOMEM)?;
+    let entry = unsafe { &mut *entry };
+    if *entry & PRESENT != 0 {
+        return Err(Errno::EEXIST);
+    }
+
+    // Nothing was mapped here, so no CPU can have cached a translation
+    *entry = Current::page_entry(phys, flags);
+    Ok(())
+}
+
+/// Unmaps the 4 KiB page at `virt` from the tables at `root`, flushing it from every CPU
+/// that may have cached it, and returns the frame it mapped. Fails with `EINVAL` if no 4 KiB
+/// page is mapped there.
+pub fn unmap_page(root: u64, virt: u64) -> KResult<u64> {
+    let entry = pte::leaf(root, virt & !(FRAME_SIZE - 1)).ok_or(Errno::EINVAL)?;
+    let entry = unsafe { &mut *entry };
+    if *entry & PRESENT == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let frame = Current::entry_address(*entry);
+    *entry = 0;
+
+    let space = (virt < PHYS_OFFSET).then_some(root);
+    tlb::shootdown_page(space, virt);
+    Ok(frame)
+}
+
+/// The physical address `virt` translates to in the tables at `root`, through pages of any
+/// size, or `None` if it is not mapped.
+pub fn translate(root: u64, virt: u64) -> Option<u64> {
+    let mut phys = root;
+
+    for level in (0..4).rev() {
+        let entry = table(phys)[(virt >> (12 + 9 * level)) as usize % ENTRIES];
+        if entry & PRESENT == 0 {
+            return None;
+        }
+
+        let size = 1u64 << (12 + 9 * level);
+        if level == 0 || entry & HUGE_PAGE != 0 {
+            return Some((Current::entry_address(entry) & !(size - 1)) + (virt & (size - 1)));
+        }
+        phys = Current::entry_address(entry);
+    }
+
+    None
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::memory::FrameAllocator;
+
+    fn allocator() -> &'static dyn FrameAllocator {
+        memory::frame_allocator().expect("paging tests need the frame allocator")
+    }
+
+    // PE headers with a code section at 0x1000 and a data section at 0x3000
+    fn pe_headers() -> [u8; 0x200] {
+        let mut image = [0u8; 0x200];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        image[0x86..0x88].copy_from_slice(&2u16.to_le_bytes());
+        image[0x94..0x96].copy_from_slice(&0xf0u16.to_le_bytes());
+
+        let table = 0x80 + 24 + 0xf0;
+        for (i, (start, size, flags)) in [(0x1000u32, 0x1800u32, SCN_MEM_EXECUTE), (0x3000, 0x1000, SCN_MEM_WRITE)].into_iter().enumerate() {
+            let header = table + i * 40;
+            image[header + 8..header + 12].copy_from_slice(&size.to_le_bytes());
+            image[header + 12..header + 16].copy_from_slice(&start.to_le_bytes());
+            image[header + 36..header + 40].copy_from_slice(&flags.to_le_bytes());
+        }
+        image
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn image_sections_get_their_rights() {
+            let headers = pe_headers();
+            let (sections, count) = sections(&headers);
+            assert_eq!(count, 2);
+
+            let sections = &sections[..count];
+            assert_eq!(image_protection(sections, 0), Protection::read_only(false));
+            assert_eq!(image_protection(sections, 0x2000), Protection::code(false));
+            assert_eq!(image_protection(sections, 0x3000), Protection::data(false));
+            assert_eq!(image_protection(sections, 0x4000), Protection::read_only(false));
+
+            assert_eq!(super::sections(b"not an image").1, 0);
+        }
+
+        fn kernel_half_maps_memory_and_image() {
+            let root = tlb::kernel_root();
+            assert!(table(root)[..USER_SLOT].iter().all(|&entry| entry & USER == 0));
+
+            let region = memory::get_usable_memory_regions()[0];
+            assert_eq!(translate(root, phys_to_virt(region.start) + 0x123), Some(region.start + 0x123));
+
+            let (start, end) = boot::image_extent();
+            let code = init as *const () as u64;
+            if (start..end).contains(&code) {
+                assert_eq!(translate(root, KERNEL_BASE + (code - start)), Some(code));
+            }
+        }
+
+        fn process_address_spaces_map_user_pages() {
+            let free = allocator().free_frames();
+            let mut process = Process::new(9970, 0, "paging");
+            assert_eq!(create_address_space(&mut process), Ok(()));
+            assert_eq!(create_address_space(&mut process), Err(Errno::EEXIST));
+
+            // The kernel half and the identity slots are the kernel root's
+            let (root, kernel) = (process.page_table_root as u64, tlb::kernel_root());
+            assert_eq!(table(root)[KERNEL_SLOT..], table(kernel)[KERNEL_SLOT..]);
+            assert_eq!(table(root)[..USER_SLOT], table(kernel)[..USER_SLOT]);
+            assert!(table(root)[USER_SLOT..KERNEL_SLOT].iter().all(|&entry| entry == 0));
+
+            let frame = allocator().alloc_frame(Placement::Local).unwrap();
+            let virt = USER_START + 0x5000;
+            assert_eq!(map_page(root, virt, frame, Protection::data(true)), Ok(()));
+            assert_eq!(map_page(root, virt, frame, Protection::data(true)), Err(Errno::EEXIST));
+            assert_eq!(map_page(root, USER_START - FRAME_SIZE, frame, Protection::data(true)), Err(Errno::EINVAL));
+            assert_eq!(map_page(root, virt + 1, frame, Protection::data(true)), Err(Errno::EINVAL));
+            assert_eq!(translate(root, virt + 0x42), Some(frame + 0x42));
+
+            let entry = unsafe { *pte::leaf(root, virt).unwrap() };
+            assert_eq!(entry & (pte::USER | pte::WRITABLE), pte::USER | pte::WRITABLE);
+
+            assert_eq!(unmap_page(root, virt), Ok(frame));
+            assert_eq!(unmap_page(root, virt), Err(Errno::EINVAL));
+            assert_eq!(translate(root, virt), None);
+            allocator().free_frame(frame);
+
+            // The tables created for the page go with the address space
+            release(&mut process);
+            assert_eq!(process.page_table_root, 0);
+            assert_eq!(allocator().free_frames(), free);
+        }
+    }
+}
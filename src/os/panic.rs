@@ -0,0 +1,136 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch::{frame, Arch, Current};
+use crate::os::boot::{self, BootMode};
+use crate::os::console;
+use crate::os::ipi;
+use crate::os::ktest;
+use crate::os::percpu::{self, PerCpu};
+use crate::os::qemu::{exit_qemu, QemuExitCode};
+use crate::os::serial;
+
+// Return addresses shown in a panic report
+const BACKTRACE_DEPTH: usize = 16;
+
+/// Registers of an exception, recorded by its handler before it panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fault {
+    pc: usize,
+    sp: usize,
+    fp: usize,
+}
+
+static FAULT: PerCpu<Option<Fault>> = PerCpu::new(None);
+
+// Set by the first CPU to panic outside of a test; any later panic just halts
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Records the program counter, stack pointer and frame pointer of an exception its handler
+/// is about to panic over, so the report shows where the fault happened and the backtrace
+/// starts from the faulting code rather than from the handler.
+pub fn record_fault(pc: usize, sp: usize, fp: usize) {
+    FAULT.with(|fault| *fault = Some(Fault { pc, sp, fp }));
+}
+
+/// Kernel panic handler.
+///
+/// Panics inside a kernel test are handed back to the test runner. Anything else stops the
+/// other CPUs and is reported on the serial port and the console: the message, the CPU and
+/// PID it happened on, the registers of the fault behind it (if any) and a backtrace. The
+/// CPU is then halted (or QEMU is told the self-test run failed).
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Taken before a test can recover, so it never outlives the panic it was recorded for
+    let fault = FAULT.with(core::mem::take);
+
+    // Only returns if no test is currently running
+    ktest::handle_panic(info);
+
+    Current::disable_interrupts();
+
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        // Another CPU is reporting, or the report itself panicked
+        Current::halt();
+    }
+
+    ipi::stop_others();
+
+    _ = writeln!(Report, "\n[PANIC] on CPU {}, PID {}: {}", percpu::cpu_id(), percpu::current_pid(), info.message());
+    if let Some(location) = info.location() {
+        _ = writeln!(Report, "  at {}", location);
+    }
+    _ = write_state(&mut Report, fault);
+
+    if boot::boot_mode() == BootMode::SelfTest {
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    Current::halt()
+}
+
+// Writes where the CPU was and how it got there: the fault registers and the frames above the
+// faulting code, or the current stack pointer and the frames above the panic
+fn write_state(out: &mut impl Write, fault: Option<Fault>) -> fmt::Result {
+    let mut backtrace = [0; BACKTRACE_DEPTH];
+
+    let depth = match fault {
+        Some(fault) => {
+            writeln!(out, "  fault: pc {:#018x}  sp {:#018x}", fault.pc, fault.sp)?;
+            frame::return_addresses_from(fault.fp, 0, &mut backtrace)
+        }
+        None => {
+            writeln!(out, "  sp {:#018x}", Current::stack_pointer())?;
+            frame::return_addresses(0, &mut backtrace)
+        }
+    };
+
+    writeln!(out, "  backtrace:")?;
+    for (i, address) in backtrace[..depth].iter().enumerate() {
+        writeln!(out, "    #{:<2} {:#018x}", i, address)?;
+    }
+
+    Ok(())
+}
+
+// Sends the report to the serial port and, if there is one, the framebuffer
+struct Report;
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial::_print(format_args!("{}", s));
+        console::panic_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+pub mod ktests {
+    use alloc::string::String;
+
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn recorded_faults_stay_on_their_cpu_until_taken() {
+            record_fault(0x1234, 0x5678, 0);
+
+            assert_eq!(FAULT.read(percpu::cpu_id()), Some(Fault { pc: 0x1234, sp: 0x5678, fp: 0 }));
+            assert_eq!(FAULT.with(core::mem::take).map(|fault| fault.pc), Some(0x1234));
+            assert_eq!(FAULT.read(percpu::cpu_id()), None);
+        }
+
+        fn state_shows_fault_registers_and_a_backtrace() {
+            let mut out = String::new();
+
+            // No frame record to walk from, so no frames
+            write_state(&mut out, Some(Fault { pc: 0xdead, sp: 0xbeef, fp: 0 })).unwrap();
+            assert!(out.contains("pc 0x000000000000dead"));
+            assert!(!out.contains("#0"));
+
+            out.clear();
+            write_state(&mut out, None).unwrap();
+            assert!(out.contains("  sp 0x"));
+            assert!(out.contains("#0"));
+        }
+    }
+}
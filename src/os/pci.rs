@@ -0,0 +1,485 @@
+//! PCI configuration space access and driver binding.
+//!
+//! Configuration space is reached through ECAM, the memory-mapped window the ACPI MCFG table
+//! describes, when [`init`] finds one for segment 0; otherwise, and for buses outside the
+//! window, through configuration mechanism #1 (the `0xcf8` address / `0xcfc` data port pair),
+//! which every PC chipset and QEMU machine type supports. [`register_devices`] records what
+//! the bus holds in the device registry the device tree also feeds.
+//!
+//! Drivers describe the functions they handle with a [`Driver`] and [`register_driver`] it;
+//! [`probe_drivers`] then offers every unbound function to the first driver that matches it,
+//! after enabling the function's decoding and bus mastering.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::acpi;
+use crate::os::arch::x86_64::port::{inl, outl};
+use crate::os::device::{self, Device, DeviceClass, Location, MAX_DEVICES};
+use crate::os::errno::{Errno, KResult};
+use crate::os::sync::SpinLock;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// Configuration header offsets
+pub const VENDOR_ID: u8 = 0x00;
+pub const DEVICE_ID: u8 = 0x02;
+pub const COMMAND: u8 = 0x04;
+pub const STATUS: u8 = 0x06;
+pub const PROG_IF: u8 = 0x09;
+pub const SUBCLASS: u8 = 0x0a;
+pub const CLASS: u8 = 0x0b;
+pub const HEADER_TYPE: u8 = 0x0e;
+pub const BAR0: u8 = 0x10;
+pub const SUBSYSTEM_ID: u8 = 0x2e;
+pub const CAPABILITIES: u8 = 0x34;
+pub const INTERRUPT_LINE: u8 = 0x3c;
+
+// Command register bits
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+// Status register bits
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+// Capability IDs
+pub const CAP_POWER: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCIE: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+// MSI capability: message control bits, and where the message goes (fixed delivery to one
+// local APIC, named in bits 12-19 of the address)
+const MSI_CONTROL: u8 = 2;
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_ENABLE: u16 = 0x7 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+/// Maximum number of registered drivers.
+pub const MAX_DRIVERS: usize = 16;
+
+// The ECAM window of segment 0 (0 = none), and the buses it covers (first << 8 | last)
+static ECAM_BASE: AtomicU64 = AtomicU64::new(0);
+static ECAM_BUSES: AtomicU64 = AtomicU64::new(0);
+
+static DRIVERS: SpinLock<[Option<&'static Driver>; MAX_DRIVERS]> = SpinLock::new([None; MAX_DRIVERS]);
+
+// Functions a driver has taken, and the driver's name
+static BOUND: SpinLock<[Option<(PciAddress, &'static str)>; MAX_DEVICES]> = SpinLock::new([None; MAX_DEVICES]);
+
+/// Finds the ECAM window for segment 0 in the MCFG table. Called once at boot, before the bus
+/// is enumerated; without it configuration space goes through the legacy ports.
+pub fn init() {
+    let Some(window) = acpi::ecam_window(0) else {
+        log::info!("pci: no MCFG window for segment 0, using port I/O");
+        return;
+    };
+
+    let (base, first, last) = (window.base, window.start_bus, window.end_bus);
+    ECAM_BUSES.store((first as u64) << 8 | last as u64, Ordering::Relaxed);
+    ECAM_BASE.store(base, Ordering::Release);
+    log::info!("pci: ECAM at {:#x} for buses {:02x}-{:02x}", base, first, last);
+}
+
+/// Location of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// Where the doubleword at `offset` of `address` lies in an ECAM window at `base` starting at
+// bus `first`: each bus takes 1 MiB, each device 32 KiB and each function 4 KiB
+fn ecam_address(base: u64, first: u8, address: &PciAddress, offset: u8) -> u64 {
+    base + (((address.bus - first) as u64) << 20
+        | (address.device as u64) << 15
+        | (address.function as u64) << 12
+        | (offset & 0xfc) as u64)
+}
+
+impl PciAddress {
+    // The ECAM address of the doubleword at `offset`, if the bus is in the window
+    fn ecam(&self, offset: u8) -> Option<*mut u32> {
+        let base = ECAM_BASE.load(Ordering::Acquire);
+        let buses = ECAM_BUSES.load(Ordering::Relaxed);
+        let (first, last) = ((buses >> 8) as u8, buses as u8);
+
+        (base != 0 && (first..=last).contains(&self.bus)).then(|| ecam_address(base, first, self, offset) as *mut u32)
+    }
+
+    fn select(&self, offset: u8) {
+        let address = 1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32;
+
+        unsafe { outl(CONFIG_ADDRESS, address) };
+    }
+
+    /// Reads the aligned doubleword at `offset`.
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        if let Some(register) = self.ecam(offset) {
+            return unsafe { register.read_volatile() };
+        }
+
+        self.select(offset);
+        unsafe { inl(CONFIG_DATA) }
+    }
+
+    /// Writes the aligned doubleword at `offset`.
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        if let Some(register) = self.ecam(offset) {
+            unsafe { register.write_volatile(value) };
+            return;
+        }
+
+        self.select(offset);
+        unsafe { outl(CONFIG_DATA, value) };
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xffff << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    /// Base address register `index`.
+    pub fn bar(&self, index: u8) -> Bar {
+        let offset = BAR0 + index * 4;
+        let low = self.read_u32(offset);
+
+        if low & 1 != 0 {
+            return Bar::Io((low & !0x3) as u16);
+        }
+
+        // Type 2 in bits 1-2: a 64-bit BAR that also takes the next slot
+        let high = if (low >> 1) & 0x3 == 2 { self.read_u32(offset + 4) as u64 } else { 0 };
+        Bar::Memory(high << 32 | (low & !0xf) as u64)
+    }
+
+    /// Enables I/O, memory and DMA (bus master) decoding.
+    pub fn enable(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    /// Delivers the function's interrupts as MSI messages for `vector` to this CPU, and stops
+    /// its legacy INTx line. Fails with `ENODEV` if the function cannot send MSI.
+    pub fn enable_msi(&self, vector: u8) -> KResult<()> {
+        let cap = self.find_capability(CAP_MSI).ok_or(Errno::ENODEV)?;
+        let control = self.read_u16(cap + MSI_CONTROL);
+
+        let apic_id = crate::os::arch::x86_64::apic::id() & 0xff;
+        self.write_u32(cap + 4, MSI_ADDRESS | apic_id << 12);
+        let data = if control & MSI_64BIT != 0 {
+            self.write_u32(cap + 8, 0);
+            cap + 12
+        } else {
+            cap + 8
+        };
+        self.write_u16(data, vector as u16);
+
+        // One vector, whatever the function asks for
+        self.write_u16(cap + MSI_CONTROL, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE);
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | COMMAND_INTX_DISABLE);
+        Ok(())
+    }
+
+    /// What the function is: its IDs and class code.
+    pub fn identity(&self) -> Identity {
+        Identity {
+            vendor: self.read_u16(VENDOR_ID),
+            device: self.read_u16(DEVICE_ID),
+            class: self.read_u8(CLASS),
+            subclass: self.read_u8(SUBCLASS),
+            prog_if: self.read_u8(PROG_IF),
+        }
+    }
+
+    /// Calls `f` with the ID and offset of every capability in the function's list.
+    pub fn for_each_capability(&self, mut f: impl FnMut(u8, u8)) {
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+
+        // Offsets are doubleword-aligned and past the standard header; a list can hold at most
+        // 48 entries, which also stops a corrupt one that loops
+        let mut offset = self.read_u8(CAPABILITIES) & 0xfc;
+        for _ in 0..48 {
+            if offset < 0x40 {
+                break;
+            }
+
+            let header = self.read_u16(offset);
+            f(header as u8, offset);
+            offset = (header >> 8) as u8 & 0xfc;
+        }
+    }
+
+    /// Offset of the first capability with ID `id`.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        let mut found = None;
+        self.for_each_capability(|cap, offset| {
+            if cap == id && found.is_none() {
+                found = Some(offset);
+            }
+        });
+        found
+    }
+}
+
+/// A function's identity, as drivers match on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+/// A set of functions a driver handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// One device of one vendor.
+    Id { vendor: u16, device: u16 },
+
+    /// A class and subclass, e.g. 0x01/0x06 for SATA controllers, and optionally a programming
+    /// interface within them.
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl Match {
+    pub fn matches(&self, identity: &Identity) -> bool {
+        match *self {
+            Match::Id { vendor, device } => identity.vendor == vendor && identity.device == device,
+            Match::Class { class, subclass, prog_if } => {
+                identity.class == class
+                    && identity.subclass == subclass
+                    && prog_if.is_none_or(|prog_if| identity.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+/// A PCI driver.
+pub struct Driver {
+    pub name: &'static str,
+
+    /// The functions it handles.
+    pub matches: &'static [Match],
+
+    /// Brings up the function at the address, which has decoding and bus mastering enabled. On
+    /// error the function is left unbound, for another driver to try.
+    pub probe: fn(PciAddress) -> KResult<()>,
+}
+
+impl Driver {
+    pub fn handles(&self, identity: &Identity) -> bool {
+        self.matches.iter().any(|m| m.matches(identity))
+    }
+}
+
+/// Decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+/// Calls `f` for every function present on the bus.
+pub fn for_each_function(mut f: impl FnMut(PciAddress)) {
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let base = PciAddress { bus, device, function: 0 };
+            if base.read_u16(VENDOR_ID) == 0xffff {
+                continue;
+            }
+
+            // Bit 7 of the header type: the device implements functions 1-7
+            let functions = if base.read_u8(HEADER_TYPE) & 0x80 != 0 { 8 } else { 1 };
+
+            for function in 0..functions {
+                let address = PciAddress { bus, device, function };
+                if address.read_u16(VENDOR_ID) != 0xffff {
+                    f(address);
+                }
+            }
+        }
+    }
+}
+
+/// Adds a driver for [`probe_drivers`] to offer functions to. Fails with `EEXIST` if a driver
+/// of the same name is registered and `ENOSPC` once [`MAX_DRIVERS`] are.
+pub fn register_driver(driver: &'static Driver) -> KResult<()> {
+    DRIVERS.with(|drivers| {
+        if drivers.iter().flatten().any(|d| d.name == driver.name) {
+            return Err(Errno::EEXIST);
+        }
+
+        let slot = drivers.iter_mut().find(|slot| slot.is_none()).ok_or(Errno::ENOSPC)?;
+        *slot = Some(driver);
+        Ok(())
+    })
+}
+
+/// The name of the driver bound to the function at `address`.
+pub fn driver_of(address: PciAddress) -> Option<&'static str> {
+    BOUND.with(|bound| bound.iter().flatten().find(|(a, _)| *a == address).map(|(_, name)| *name))
+}
+
+/// Offers every function no driver has taken to the registered drivers, in registration
+/// order, and returns how many were bound. Called at boot once the drivers are registered, and
+/// again to place drivers registered later.
+pub fn probe_drivers() -> usize {
+    // Probes may sleep, so they run on a copy of the table
+    let drivers = DRIVERS.with(|drivers| *drivers);
+    let mut bound = 0;
+
+    for_each_function(|address| {
+        if driver_of(address).is_some() {
+            return;
+        }
+
+        let identity = address.identity();
+        for driver in drivers.iter().flatten().filter(|driver| driver.handles(&identity)) {
+            address.enable();
+
+            match (driver.probe)(address) {
+                Ok(()) => {
+                    let slot = BOUND.with(|table| {
+                        let slot = table.iter_mut().find(|slot| slot.is_none())?;
+                        *slot = Some((address, driver.name));
+                        Some(())
+                    });
+                    if slot.is_none() {
+                        log::warn!("pci: binding table full, {} bound to {} untracked", driver.name, address);
+                    }
+
+                    log::info!("pci: {} bound to {} ({:04x}:{:04x})", driver.name, address, identity.vendor, identity.device);
+                    bound += 1;
+                    return;
+                }
+                Err(errno) => log::warn!("pci: {} failed to probe {}: {:?}", driver.name, address, errno),
+            }
+        }
+    });
+
+    bound
+}
+
+/// Finds the first function with vendor `vendor` and one of the device IDs in `devices`.
+pub fn find(vendor: u16, devices: &[u16]) -> Option<PciAddress> {
+    let mut found = None;
+
+    for_each_function(|address| {
+        if found.is_none() && address.read_u16(VENDOR_ID) == vendor && devices.contains(&address.read_u16(DEVICE_ID)) {
+            found = Some(address);
+        }
+    });
+
+    found
+}
+
+// Vendor ID of virtio devices (Red Hat)
+const VIRTIO_VENDOR: u16 = 0x1af4;
+
+/// Records every function on the bus in the device registry. Called once at boot.
+pub fn register_devices() {
+    let mut registered = 0;
+
+    for_each_function(|address| {
+        let vendor_id = address.read_u16(VENDOR_ID);
+        let line = address.read_u8(INTERRUPT_LINE);
+
+        let device = Device {
+            class: if vendor_id == VIRTIO_VENDOR { DeviceClass::Virtio } else { DeviceClass::Other },
+            name: "pci",
+            location: Location::Pci {
+                bus: address.bus,
+                device: address.device,
+                function: address.function,
+                vendor_id,
+                device_id: address.read_u16(DEVICE_ID),
+            },
+            // 0xff: not connected to the legacy interrupt controller
+            irq: (line != 0xff).then_some(line as u32),
+        };
+
+        match device::register(device) {
+            Ok(_) => registered += 1,
+            Err(_) => log::warn!("pci: device registry full, dropping {}", address),
+        }
+    });
+
+    log::info!("pci: {} functions registered", registered);
+}
+
+pub mod ktests {
+    use super::*;
+
+    const AHCI: Identity = Identity { vendor: 0x8086, device: 0x2922, class: 0x01, subclass: 0x06, prog_if: 0x01 };
+
+    crate::os::ktest::kernel_test! {
+        fn matches_by_id_or_class() {
+            assert!(Match::Id { vendor: 0x8086, device: 0x2922 }.matches(&AHCI));
+            assert!(!Match::Id { vendor: 0x8086, device: 0x2923 }.matches(&AHCI));
+            assert!(Match::Class { class: 0x01, subclass: 0x06, prog_if: None }.matches(&AHCI));
+            assert!(Match::Class { class: 0x01, subclass: 0x06, prog_if: Some(0x01) }.matches(&AHCI));
+            assert!(!Match::Class { class: 0x01, subclass: 0x06, prog_if: Some(0x00) }.matches(&AHCI));
+            assert!(!Match::Class { class: 0x01, subclass: 0x01, prog_if: None }.matches(&AHCI));
+        }
+
+        fn drivers_handle_any_of_their_matches() {
+            fn probe(_: PciAddress) -> KResult<()> {
+                Ok(())
+            }
+
+            let driver = Driver {
+                name: "test",
+                matches: &[Match::Id { vendor: 0x1af4, device: 0x1000 }, Match::Class { class: 0x01, subclass: 0x06, prog_if: None }],
+                probe,
+            };
+            assert!(driver.handles(&AHCI));
+            assert!(!driver.handles(&Identity { class: 0x02, ..AHCI }));
+            assert!(!Driver { matches: &[], ..driver }.handles(&AHCI));
+        }
+
+        fn ecam_windows_are_laid_out_by_bus_device_and_function() {
+            let address = PciAddress { bus: 3, device: 2, function: 1 };
+            assert_eq!(ecam_address(0xb000_0000, 0, &address, 0x12), 0xb031_1010);
+            assert_eq!(ecam_address(0xb000_0000, 3, &address, 0), 0xb001_1000);
+        }
+
+        fn the_host_bridge_is_enumerated() {
+            let mut bridge = None;
+            for_each_function(|address| {
+                if address == (PciAddress { bus: 0, device: 0, function: 0 }) {
+                    bridge = Some(address.identity());
+                }
+            });
+            assert_eq!(bridge.map(|identity| identity.class), Some(0x06));
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Per-CPU data.
+//!
+//! Every CPU has a [`CpuArea`] and points its per-CPU base register at it (GS base on x86, so
+//! `gs:[..]` reaches the running CPU's own copy in a single instruction and the syscall entry
+//! path can use it after `swapgs`; TPIDR_EL1 on AArch64, tp on RISC-V). [`PerCpu<T>`] holds one `T` per CPU for subsystem state such as run queues,
+//! softirq bookkeeping and statistics; each CPU only touches its own slot, so no locks are
+//! needed as long as the access cannot be preempted, which [`PerCpu::with`] ensures.
+
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::arch::{self, Arch, Current};
+use crate::os::numa;
+
+/// Maximum number of CPUs the kernel supports: just the boot CPU without the `smp` feature.
+pub const MAX_CPUS: usize = if cfg!(feature = "smp") { 64 } else { 1 };
+
+/// Fixed per-CPU state reachable through the per-CPU base register.
+#[derive(Debug)]
+#[repr(C, align(64))]
+pub struct CpuArea {
+    /// Address of this area, so the base register's target yields a normal pointer to it.
+    pub self_ptr: u64,
+
+    /// Index of this CPU (0 = bootstrap processor).
+    pub cpu_id: u64,
+
+    /// PID of the process running on this CPU.
+    pub current_pid: u64,
+
+    /// Top of the kernel stack to switch to on syscall entry.
+    pub kernel_stack_top: u64,
+
+    /// Scratch slot for the user stack pointer during syscall entry.
+    pub user_rsp: u64,
+
+    /// Interrupt controller ID, the destination for IPIs to this CPU.
+    pub hardware_id: u64,
+
+    /// NUMA node this CPU belongs to.
+    pub node: u64,
+}
+
+static mut AREAS: [CpuArea; MAX_CPUS] = [const {
+    CpuArea { self_ptr: 0, cpu_id: 0, current_pid: 0, kernel_stack_top: 0, user_rsp: 0, hardware_id: 0, node: 0 }
+}; MAX_CPUS];
+
+// Bit n set: CPU n has run init_cpu and can take IPIs
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Byte offsets of [`CpuArea`] fields, for assembly that addresses them through the base register.
+pub const CPU_ID_OFFSET: usize = offset_of!(CpuArea, cpu_id);
+pub const CURRENT_PID_OFFSET: usize = offset_of!(CpuArea, current_pid);
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(CpuArea, kernel_stack_top);
+pub const USER_RSP_OFFSET: usize = offset_of!(CpuArea, user_rsp);
+
+/// Sets up CPU `id`'s area and points this CPU's base register at it. Called by the bootstrap
+/// processor early in boot (with `id` 0) and by each application processor as it comes up.
+pub fn init_cpu(id: usize) {
+    assert!(id < MAX_CPUS, "percpu: CPU {} exceeds MAX_CPUS", id);
+
+    unsafe {
+        let areas = &raw mut AREAS;
+        let area = &mut (*areas)[id];
+
+        area.self_ptr = area as *mut CpuArea as u64;
+        area.cpu_id = id as u64;
+        area.hardware_id = Current::hardware_cpu_id() as u64;
+        area.node = numa::node_of_apic(area.hardware_id as u32) as u64;
+
+        Current::set_percpu_base(area.self_ptr);
+    }
+
+    ONLINE.fetch_or(1 << id, Ordering::Release);
+}
+
+/// Bitmask of the CPUs that are up (bit n = CPU n).
+pub fn online_mask() -> u64 {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Interrupt controller ID of CPU `cpu` (its local APIC ID on x86).
+pub fn hardware_id(cpu: usize) -> u32 {
+    unsafe {
+        let areas = &raw const AREAS;
+        (*areas)[cpu].hardware_id as u32
+    }
+}
+
+/// NUMA node of CPU `cpu`.
+pub fn node(cpu: usize) -> u8 {
+    unsafe {
+        let areas = &raw const AREAS;
+        (*areas)[cpu].node as u8
+    }
+}
+
+// This CPU's area. Fields written through it are only ever read back by the same CPU (or
+// copied by others without synchronisation, like the statistics).
+#[inline]
+fn this_cpu() -> *mut CpuArea {
+    Current::percpu_base() as *mut CpuArea
+}
+
+/// Index of the CPU this code runs on.
+#[inline]
+pub fn cpu_id() -> usize {
+    unsafe { (*this_cpu()).cpu_id as usize }
+}
+
+/// PID of the process running on this CPU.
+#[inline]
+pub fn current_pid() -> u64 {
+    unsafe { (*this_cpu()).current_pid }
+}
+
+/// Records the process now running on this CPU (called by the scheduler on a switch).
+#[inline]
+pub fn set_current_pid(pid: u64) {
+    unsafe { (*this_cpu()).current_pid = pid };
+}
+
+/// Records the kernel stack the next syscall or interrupt from user mode should use (see
+/// [`Process::kernel_stack_top`](crate::os::process::Process::kernel_stack_top)).
+#[inline]
+pub fn set_kernel_stack_top(top: u64) {
+    unsafe { (*this_cpu()).kernel_stack_top = top };
+
+    // Interrupts find it in the TSS rather than here
+    #[cfg(target_arch = "x86_64")]
+    crate::os::arch::x86_64::gdt::set_kernel_stack(top);
+}
+
+/// One `T` per CPU.
+pub struct PerCpu<T> {
+    slots: UnsafeCell<[T; MAX_CPUS]>,
+}
+
+// Each CPU only accesses its own slot (with interrupts off), or reads copies of others
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T: Copy> PerCpu<T> {
+    /// Creates the variable with every CPU's slot set to `value`.
+    pub const fn new(value: T) -> Self {
+        PerCpu { slots: UnsafeCell::new([value; MAX_CPUS]) }
+    }
+
+    /// Returns a copy of CPU `cpu`'s value, e.g. to sum statistics. The value may be stale by
+    /// the time it is used.
+    pub fn read(&self, cpu: usize) -> T {
+        unsafe { core::ptr::read_volatile(&(*self.slots.get())[cpu]) }
+    }
+}
+
+impl<T> PerCpu<T> {
+    /// Runs `f` on this CPU's value with interrupts masked, so it cannot be preempted or
+    /// migrated to another CPU in the middle.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        arch::without_interrupts(|| {
+            let slot = unsafe { &mut (*self.slots.get())[cpu_id()] };
+            f(slot)
+        })
+    }
+}
+
+/// Event counters kept per CPU.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    pub interrupts: u64,
+    pub context_switches: u64,
+    pub syscalls: u64,
+    pub page_faults: u64,
+}
+
+/// Per-CPU event counters, bumped without locks by the subsystems that own each event.
+pub static STATS: PerCpu<CpuStats> = PerCpu::new(CpuStats { interrupts: 0, context_switches: 0, syscalls: 0, page_faults: 0 });
+
+/// Sum of the counters over all CPUs.
+pub fn total_stats() -> CpuStats {
+    (0..MAX_CPUS).map(|cpu| STATS.read(cpu)).fold(CpuStats::default(), |sum, s| CpuStats {
+        interrupts: sum.interrupts + s.interrupts,
+        context_switches: sum.context_switches + s.context_switches,
+        syscalls: sum.syscalls + s.syscalls,
+        page_faults: sum.page_faults + s.page_faults,
+    })
+}
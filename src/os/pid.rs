@@ -0,0 +1,216 @@
+//! PID allocation.
+//!
+//! Root PIDs are handed out cyclically: each allocation takes the next free number after the
+//! last one handed out, and past `kernel.pid_max` the search wraps round to [`RESERVED_PIDS`],
+//! leaving the low numbers to the processes started at boot as Linux does. A freed PID thus
+//! comes back only once the counter has gone all the way round, and even then it is skipped
+//! while
+//! - it is in quarantine, having been freed less than [`QUARANTINE_NS`] ago, or
+//! - a process still refers to it: as its parent (`ppid`), through a [`WaitTarget::PID`], or
+//!   by having it, for processes created with a PID of their own choosing,
+//!
+//! so a stale reference never starts matching an unrelated new process.
+//!
+//! PIDs inside nested namespaces are numbered by [`pidns`](super::pidns) on top of these.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch;
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::WaitTarget;
+use crate::os::ptable;
+use crate::os::sysctl;
+use crate::os::timekeeping::{self, NS_PER_SEC};
+
+/// Largest value `kernel.pid_max` can take; PIDs stay below it.
+pub const PID_LIMIT: u64 = 32768;
+
+/// PIDs below this are only handed out before the first wraparound.
+pub const RESERVED_PIDS: u64 = 300;
+
+/// How long a freed PID stays out of circulation, even after a wraparound.
+pub const QUARANTINE_NS: u64 = NS_PER_SEC;
+
+// Recently freed PIDs remembered for the quarantine; when more are freed within the period
+// the oldest leave it early
+const QUARANTINE_SLOTS: usize = 64;
+
+const WORDS: usize = PID_LIMIT as usize / 64;
+
+struct Allocator {
+    // Bit n set: PID n is in use
+    used: [u64; WORDS],
+
+    // The PID handed out last, where the next search starts
+    last: u64,
+
+    // Freed PIDs and when they were freed, oldest overwritten first
+    quarantine: [Option<(u64, u64)>; QUARANTINE_SLOTS],
+    next_slot: usize,
+}
+
+impl Allocator {
+    // PID 0 is the kernel's own
+    const fn new() -> Self {
+        let mut used = [0; WORDS];
+        used[0] = 1;
+        Allocator { used, last: 0, quarantine: [None; QUARANTINE_SLOTS], next_slot: 0 }
+    }
+
+    fn is_used(&self, pid: u64) -> bool {
+        self.used[pid as usize / 64] & (1 << (pid % 64)) != 0
+    }
+
+    fn in_quarantine(&self, pid: u64, now: u64) -> bool {
+        self.quarantine.iter().flatten().any(|&(freed, at)| freed == pid && now.saturating_sub(at) < QUARANTINE_NS)
+    }
+
+    // Takes the next PID below `pid_max` that is free, out of quarantine and not `referenced`
+    fn alloc(&mut self, pid_max: u64, now: u64, referenced: impl Fn(u64) -> bool) -> KResult<u64> {
+        let pid_max = pid_max.min(PID_LIMIT);
+        let mut pid = self.last;
+
+        for _ in 0..pid_max {
+            pid += 1;
+            if pid >= pid_max {
+                pid = RESERVED_PIDS.min(pid_max - 1);
+            }
+
+            if !self.is_used(pid) && !self.in_quarantine(pid, now) && !referenced(pid) {
+                self.used[pid as usize / 64] |= 1 << (pid % 64);
+                self.last = pid;
+                return Ok(pid);
+            }
+        }
+
+        Err(Errno::EAGAIN)
+    }
+
+    fn free(&mut self, pid: u64, now: u64) {
+        if pid == 0 || pid >= PID_LIMIT || !self.is_used(pid) {
+            return;
+        }
+
+        self.used[pid as usize / 64] &= !(1 << (pid % 64));
+        self.quarantine[self.next_slot] = Some((pid, now));
+        self.next_slot = (self.next_slot + 1) % QUARANTINE_SLOTS;
+    }
+}
+
+static mut ALLOCATOR: Allocator = Allocator::new();
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+fn locked<R>(f: impl FnOnce(&mut Allocator) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let allocator = &raw mut ALLOCATOR;
+            f(&mut *allocator)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+// Whether some process has `pid`, has it as its parent or waits on it
+fn referenced(pid: u64) -> bool {
+    let mut found = false;
+    ptable::for_each(|p| found |= p.pid == pid || p.ppid == pid || p.waiting_on == Some(WaitTarget::PID(pid)));
+    found
+}
+
+/// Allocates a PID for a new process. Fails with `EAGAIN` when every PID below
+/// `kernel.pid_max` is taken, quarantined or still referred to.
+pub fn alloc() -> KResult<u64> {
+    let pid_max = sysctl::PID_MAX.load(Ordering::Relaxed);
+    let now = timekeeping::monotonic_ns();
+    locked(|allocator| allocator.alloc(pid_max, now, referenced))
+}
+
+/// Returns `pid` once its process has been reaped. It is quarantined for [`QUARANTINE_NS`].
+pub fn free(pid: u64) {
+    let now = timekeeping::monotonic_ns();
+    locked(|allocator| allocator.free(pid, now));
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::process::Process;
+
+    static mut TEST_ALLOCATOR: Allocator = Allocator::new();
+
+    fn test_allocator() -> &'static mut Allocator {
+        unsafe {
+            let allocator = &raw mut TEST_ALLOCATOR;
+            *allocator = Allocator::new();
+            &mut *allocator
+        }
+    }
+
+    fn unreferenced(_pid: u64) -> bool {
+        false
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn pids_are_handed_out_cyclically() {
+            let allocator = test_allocator();
+            assert_eq!(allocator.alloc(PID_LIMIT, 0, unreferenced), Ok(1));
+            assert_eq!(allocator.alloc(PID_LIMIT, 0, unreferenced), Ok(2));
+            assert_eq!(allocator.alloc(PID_LIMIT, 0, unreferenced), Ok(3));
+
+            // A freed PID is not the next one handed out, even once out of quarantine
+            allocator.free(2, 0);
+            assert_eq!(allocator.alloc(PID_LIMIT, QUARANTINE_NS, unreferenced), Ok(4));
+
+            // Nor is the kernel's
+            allocator.free(0, 0);
+            assert!(allocator.is_used(0));
+        }
+
+        fn wraparound_skips_reserved_and_used_pids() {
+            let allocator = test_allocator();
+            let pid_max = RESERVED_PIDS + 3;
+            for expected in 1..pid_max {
+                assert_eq!(allocator.alloc(pid_max, 0, unreferenced), Ok(expected));
+            }
+            assert_eq!(allocator.alloc(pid_max, 0, unreferenced), Err(Errno::EAGAIN));
+
+            // Freed low PIDs stay unused; the search wraps to the first PID past the reserved ones
+            allocator.free(5, 0);
+            allocator.free(RESERVED_PIDS + 1, 0);
+            assert_eq!(allocator.alloc(pid_max, QUARANTINE_NS, unreferenced), Ok(RESERVED_PIDS + 1));
+            assert_eq!(allocator.alloc(pid_max, QUARANTINE_NS, unreferenced), Err(Errno::EAGAIN));
+        }
+
+        fn freed_pids_are_quarantined() {
+            let allocator = test_allocator();
+            let pid_max = RESERVED_PIDS + 2;
+            allocator.last = RESERVED_PIDS;
+
+            assert_eq!(allocator.alloc(pid_max, 0, unreferenced), Ok(RESERVED_PIDS + 1));
+            allocator.free(RESERVED_PIDS + 1, 1_000);
+
+            // Wrapped all the way round within the quarantine: nothing left to hand out
+            assert_eq!(allocator.alloc(pid_max, 1_000 + QUARANTINE_NS - 1, |pid| pid == RESERVED_PIDS), Err(Errno::EAGAIN));
+            assert_eq!(allocator.alloc(pid_max, 1_000 + QUARANTINE_NS, |pid| pid == RESERVED_PIDS), Ok(RESERVED_PIDS + 1));
+        }
+
+        fn referenced_pids_are_skipped() {
+            let allocator = test_allocator();
+            assert_eq!(allocator.alloc(PID_LIMIT, 0, |pid| pid < 4), Ok(4));
+
+            let mut child = Process::new(9800, 9801, "pid");
+            child.waiting_on = Some(WaitTarget::PID(9802));
+            ptable::insert(child).unwrap();
+
+            let stale = [referenced(9800), referenced(9801), referenced(9802), referenced(9803)];
+            ptable::remove(9800);
+            assert_eq!(stale, [true, true, true, false]);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! PID namespaces.
+//!
+//! A namespace gives a process subtree its own PID numbering: the first process in it is its
+//! init and sees itself as PID 1. Namespaces nest, and a process is visible in its own
+//! namespace and every ancestor with a separate PID in each, recorded in its [`PidLinks`];
+//! processes outside its namespace's subtree are invisible to it.
+//!
+//! `Process::pid` stays the PID in the root namespace, which the kernel uses internally
+//! (process table, wait targets, audit records). Translation happens where PIDs cross into or
+//! out of user space: [`sys_getpid`] and [`sys_getppid`], [`to_global`] for PIDs passed in, and
+//! procfs, which renders the tree as seen from a given namespace.
+//!
+//! As with Linux's `unshare(CLONE_NEWPID)`, [`sys_unshare_pid`] does not move the caller: its
+//! next child becomes the new namespace's init and later children join it. A namespace lives as
+//! long as a process in it, a child namespace or a pending `unshare` refers to it; once its
+//! init is gone no process can join it any more.
+
+use crate::os::capability::{self, Capability};
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::sync::SpinLock;
+
+/// Index of a namespace in the namespace table.
+pub type NsId = u16;
+
+/// The initial namespace, whose PIDs are the kernel's own.
+pub const ROOT_NS: NsId = 0;
+
+/// Maximum number of namespaces alive at once, the root included.
+pub const MAX_NAMESPACES: usize = 32;
+
+/// Deepest nesting level; the root namespace is level 0.
+pub const MAX_LEVEL: usize = 7;
+
+/// PID of a namespace's init as seen from inside.
+pub const INIT_PID: u64 = 1;
+
+/// A process's place in the namespace hierarchy: the namespaces from the root down to its own,
+/// and its PID in each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PidLinks {
+    /// Level of the process's own namespace.
+    pub level: u8,
+
+    /// `ns[l]` is the ancestor at level `l`; `ns[level]` is the process's own namespace.
+    pub ns: [NsId; MAX_LEVEL + 1],
+
+    /// `pids[l]` is the PID the process has in `ns[l]`; `pids[0]` is `Process::pid`.
+    pub pids: [u64; MAX_LEVEL + 1],
+}
+
+impl PidLinks {
+    /// Links of a process living in the root namespace with PID `pid`.
+    pub const fn root(pid: u64) -> Self {
+        let mut pids = [0; MAX_LEVEL + 1];
+        pids[0] = pid;
+
+        PidLinks { level: 0, ns: [ROOT_NS; MAX_LEVEL + 1], pids }
+    }
+
+    /// The namespace the process lives in.
+    pub fn namespace(&self) -> NsId {
+        self.ns[self.level as usize]
+    }
+
+    /// The PID the process has in its own namespace.
+    pub fn pid(&self) -> u64 {
+        self.pids[self.level as usize]
+    }
+
+    /// The process's PID as seen from `ns`, or `None` if `ns` cannot see it.
+    pub fn pid_in(&self, ns: NsId) -> Option<u64> {
+        let level = level_of(ns)? as usize;
+        (level <= self.level as usize && self.ns[level] == ns).then(|| self.pids[level])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Namespace {
+    parent: NsId,
+    level: u8,
+
+    // Next PID handed out inside the namespace
+    next_pid: u64,
+
+    // Processes living in it, child namespaces and pending unshares
+    refs: u32,
+
+    // Set once its init has been reaped
+    dead: bool,
+}
+
+const ROOT: Namespace = Namespace { parent: ROOT_NS, level: 0, next_pid: 0, refs: 0, dead: false };
+
+type Table = [Option<Namespace>; MAX_NAMESPACES];
+
+// Taken inside process table lookups (`attach`, `detach`, `pid_in`), never the other way round
+static NAMESPACES: SpinLock<Table> = SpinLock::new({
+    let mut table = [None; MAX_NAMESPACES];
+    table[ROOT_NS as usize] = Some(ROOT);
+    table
+});
+
+fn get(table: &mut Table, ns: NsId) -> Option<&mut Namespace> {
+    table.get_mut(ns as usize)?.as_mut()
+}
+
+/// Nesting level of `ns`, or `None` if it does not exist.
+pub fn level_of(ns: NsId) -> Option<u8> {
+    NAMESPACES.with(|table| get(table, ns).map(|namespace| namespace.level))
+}
+
+/// Number of namespaces alive, the root included.
+pub fn count() -> usize {
+    NAMESPACES.with(|table| table.iter().flatten().count())
+}
+
+// Takes a reference on `ns`; the root is never freed and needs none
+fn hold(table: &mut Table, ns: NsId) {
+    if ns != ROOT_NS
+        && let Some(namespace) = get(table, ns)
+    {
+        namespace.refs += 1;
+    }
+}
+
+// Drops a reference on `ns`, freeing it (and releasing its parent) with the last one
+fn put(table: &mut Table, ns: NsId) {
+    let mut at = ns;
+
+    while at != ROOT_NS {
+        let Some(namespace) = get(table, at) else {
+            return;
+        };
+
+        namespace.refs -= 1;
+        if namespace.refs != 0 {
+            return;
+        }
+
+        let parent = namespace.parent;
+        table[at as usize] = None;
+        log::debug!("pidns: namespace {} freed", at);
+        at = parent;
+    }
+}
+
+/// Creates a namespace nested in `parent` and returns it with one reference held.
+fn create(table: &mut Table, parent: NsId) -> KResult<NsId> {
+    let level = get(table, parent).ok_or(Errno::EINVAL)?.level;
+    if level as usize >= MAX_LEVEL {
+        return Err(Errno::ENOSPC);
+    }
+
+    let ns = table.iter().position(Option::is_none).ok_or(Errno::ENOSPC)?;
+    table[ns] = Some(Namespace { parent, level: level + 1, next_pid: INIT_PID, refs: 1, dead: false });
+    hold(table, parent);
+
+    Ok(ns as NsId)
+}
+
+/// `unshare(CLONE_NEWPID)`: makes `process`'s future children start a new namespace nested in
+/// its own. Requires `CAP_SYS_ADMIN`; fails with `EINVAL` if an earlier unshare is still
+/// pending or its children already live elsewhere.
+pub fn sys_unshare_pid(process: &mut Process) -> KResult<()> {
+    capability::require(&process.cred, Capability::SysAdmin)?;
+
+    if process.pid_ns_for_children != process.pid_links.namespace() {
+        return Err(Errno::EINVAL);
+    }
+
+    let ns = NAMESPACES.with(|table| {
+        let ns = create(table, process.pid_links.namespace())?;
+        put(table, process.pid_ns_for_children);
+        Ok(ns)
+    })?;
+    process.pid_ns_for_children = ns;
+
+    log::debug!("pidns: pid {} unshared into namespace {}", process.pid, ns);
+    Ok(())
+}
+
+/// Places the new process `child` in `parent`'s namespace for children, giving it a PID in
+/// each level below the root. `child.pid` must already hold its root PID, from
+/// [`pid::alloc`](crate::os::pid::alloc). Called when the child is created, before it is
+/// admitted to the scheduler.
+pub fn attach(child: &mut Process, parent: &Process) -> KResult<()> {
+    let ns = parent.pid_ns_for_children;
+
+    let links = NAMESPACES.with(|table| {
+        let namespace = get(table, ns).ok_or(Errno::EINVAL)?;
+
+        // A namespace whose init is gone takes no new members
+        if namespace.dead {
+            return Err(Errno::ENOMEM);
+        }
+
+        let mut links = PidLinks::root(child.pid);
+        links.level = namespace.level;
+
+        let mut at = ns;
+        for level in (1..=links.level as usize).rev() {
+            let namespace = get(table, at).ok_or(Errno::EINVAL)?;
+
+            links.ns[level] = at;
+            links.pids[level] = namespace.next_pid;
+            namespace.next_pid += 1;
+
+            at = namespace.parent;
+        }
+
+        hold(table, ns);
+        hold(table, ns);
+        Ok(links)
+    })?;
+
+    child.pid_links = links;
+    child.pid_ns_for_children = ns;
+    Ok(())
+}
+
+/// Drops the references `process` holds. Called when the process is reaped; reaping a
+/// namespace's init closes the namespace to new members.
+pub fn detach(process: &mut Process) {
+    let ns = process.pid_links.namespace();
+
+    NAMESPACES.with(|table| {
+        if process.pid_links.pid() == INIT_PID
+            && ns != ROOT_NS
+            && let Some(namespace) = get(table, ns)
+        {
+            namespace.dead = true;
+        }
+
+        put(table, ns);
+        put(table, process.pid_ns_for_children);
+    });
+
+    process.pid_links = PidLinks::root(process.pid);
+    process.pid_ns_for_children = ROOT_NS;
+}
+
+/// Root PID of the process `ns` calls `pid`, if `ns` can see such a process.
+pub fn to_global(ns: NsId, pid: u64) -> Option<u64> {
+    let mut found = None;
+
+    ptable::for_each(|p| {
+        if found.is_none() && p.pid_links.pid_in(ns) == Some(pid) {
+            found = Some(p.pid);
+        }
+    });
+
+    found
+}
+
+/// The PID `ns` knows the process with root PID `global` by, or `None` if it is invisible
+/// from there (or does not exist).
+pub fn from_global(ns: NsId, global: u64) -> Option<u64> {
+    ptable::with_process(global, |p| p.pid_links.pid_in(ns)).flatten()
+}
+
+/// `getpid()`: the caller's PID in its own namespace.
+pub fn sys_getpid(process: &Process) -> u64 {
+    process.pid_links.pid()
+}
+
+/// `getppid()`: the parent's PID in the namespace of the caller `caller`, or 0 if the parent
+/// lives outside it (as for every namespace's init).
+pub fn sys_getppid(caller: u64) -> KResult<u64> {
+    let (ns, ppid) = ptable::with_process(caller, |p| (p.pid_links.namespace(), p.ppid)).ok_or(Errno::ESRCH)?;
+    Ok(from_global(ns, ppid).unwrap_or(0))
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn privileged(pid: u64, ppid: u64) -> Process {
+        Process::new(pid, ppid, "pidns")
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn children_number_from_one() {
+            let namespaces = count();
+            let mut parent = privileged(9000, 0);
+            sys_unshare_pid(&mut parent).unwrap();
+
+            // The unsharer itself stays where it was
+            assert_eq!(sys_getpid(&parent), 9000);
+            assert_eq!(sys_unshare_pid(&mut parent), Err(Errno::EINVAL));
+
+            let mut init = privileged(9001, 9000);
+            attach(&mut init, &parent).unwrap();
+            let mut second = privileged(9002, 9001);
+            attach(&mut second, &init).unwrap();
+
+            let ns = init.pid_links.namespace();
+            assert_eq!(level_of(ns), Some(1));
+            assert_eq!((sys_getpid(&init), sys_getpid(&second)), (1, 2));
+            assert_eq!(second.pid_links.pid_in(ROOT_NS), Some(9002));
+            assert_eq!(parent.pid_links.pid_in(ns), None);
+
+            detach(&mut second);
+            detach(&mut init);
+            detach(&mut parent);
+            assert_eq!(count(), namespaces);
+        }
+
+        fn nested_namespaces_see_down_only() {
+            let namespaces = count();
+            let mut outer = privileged(9010, 0);
+            sys_unshare_pid(&mut outer).unwrap();
+
+            let mut outer_init = privileged(9011, 9010);
+            attach(&mut outer_init, &outer).unwrap();
+            sys_unshare_pid(&mut outer_init).unwrap();
+
+            let mut inner_init = privileged(9012, 9011);
+            attach(&mut inner_init, &outer_init).unwrap();
+
+            let (outer_ns, inner_ns) = (outer_init.pid_links.namespace(), inner_init.pid_links.namespace());
+            assert_eq!(level_of(inner_ns), Some(2));
+            assert_eq!(inner_init.pid_links.pid_in(inner_ns), Some(1));
+            assert_eq!(inner_init.pid_links.pid_in(outer_ns), Some(2));
+            assert_eq!(inner_init.pid_links.pid_in(ROOT_NS), Some(9012));
+            assert_eq!(outer_init.pid_links.pid_in(inner_ns), None);
+
+            detach(&mut inner_init);
+            detach(&mut outer_init);
+            detach(&mut outer);
+            assert_eq!(count(), namespaces);
+        }
+
+        fn reaped_init_closes_namespace() {
+            let mut parent = privileged(9020, 0);
+            sys_unshare_pid(&mut parent).unwrap();
+
+            let mut init = privileged(9021, 9020);
+            attach(&mut init, &parent).unwrap();
+            let mut orphan = privileged(9022, 9021);
+            orphan.pid_ns_for_children = init.pid_ns_for_children;
+            NAMESPACES.with(|table| hold(table, orphan.pid_ns_for_children));
+
+            detach(&mut init);
+            let mut late = privileged(9023, 9022);
+            assert_eq!(attach(&mut late, &orphan), Err(Errno::ENOMEM));
+
+            NAMESPACES.with(|table| put(table, orphan.pid_ns_for_children));
+            detach(&mut parent);
+        }
+
+        fn unshare_needs_sys_admin() {
+            let mut process = privileged(9030, 0);
+            process.cred.cap_effective.remove(Capability::SysAdmin);
+
+            assert_eq!(sys_unshare_pid(&mut process), Err(Errno::EPERM));
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! System sleep states.
+//!
+//! Drivers that hold hardware state register a [`PowerManaged`] implementation. Suspend-to-RAM
+//! (ACPI S3) quiesces them in reverse registration order, saves the CPU state the firmware
+//! does not preserve, points the FACS waking vector at the resume trampoline and writes the
+//! sleep type to the PM1 control registers. On wake the trampoline restores paging and
+//! returns into [`suspend_to_ram`], which restores the CPU state and resumes the devices in
+//! registration order.
+
+use crate::os::acpi::{self, Fadt};
+use crate::os::arch::x86_64::control::{Xcr0, Xcr0Flags, Cr4, Cr4Flags};
+use crate::os::arch::x86_64::msr::Msr;
+use crate::os::arch::x86_64::{port, wakeup};
+use crate::os::capability::{self, Capability};
+use crate::os::cpu;
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::fpu::FpuState;
+
+/// A driver whose device must be quiesced before the system sleeps and reinitialised after.
+pub trait PowerManaged: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Stops the device and saves whatever state it loses when powered off. An error aborts
+    /// the suspend; devices suspended before this one are resumed again.
+    fn suspend(&self) -> KResult<()>;
+
+    /// Restores the device after wake-up.
+    fn resume(&self);
+}
+
+/// Maximum number of power-managed devices.
+const MAX_DEVICES: usize = 32;
+
+// Registered devices, in registration (and therefore resume) order
+static mut DEVICES: [Option<&'static dyn PowerManaged>; MAX_DEVICES] = [None; MAX_DEVICES];
+
+/// Registers a device for suspend/resume callbacks. Devices should register after the devices
+/// they depend on (a disk after its controller), so they are suspended first and resumed last.
+pub fn register(device: &'static dyn PowerManaged) -> KResult<()> {
+    unsafe {
+        let devices = &raw mut DEVICES;
+
+        let slot = (*devices).iter_mut().find(|d| d.is_none()).ok_or(Errno::ENOSPC)?;
+        *slot = Some(device);
+    }
+
+    Ok(())
+}
+
+fn devices() -> [Option<&'static dyn PowerManaged>; MAX_DEVICES] {
+    unsafe {
+        let devices = &raw const DEVICES;
+        *devices
+    }
+}
+
+// Suspends every device, newest first. On failure the ones already suspended are resumed.
+// Devices are never unregistered, so the registered ones are a prefix of the table.
+fn suspend_devices() -> KResult<()> {
+    let devices = devices();
+    let registered = devices.iter().flatten().count();
+
+    for i in (0..registered).rev() {
+        let Some(device) = devices[i] else { continue };
+
+        if let Err(errno) = device.suspend() {
+            log::error!("power: {} refused to suspend: {:?}", device.name(), errno);
+
+            for device in devices[i + 1..registered].iter().flatten() {
+                device.resume();
+            }
+
+            return Err(errno);
+        }
+    }
+
+    Ok(())
+}
+
+fn resume_devices() {
+    for device in devices().iter().flatten() {
+        device.resume();
+    }
+}
+
+// =========================================================================
+// CPU state the firmware does not preserve
+// =========================================================================
+
+/// MSRs restored after wake-up (the trampoline only restores EFER).
+const SAVED_MSRS: [Msr; 8] = [
+    Msr::IA32_PAT,
+    Msr::IA32_APIC_BASE,
+    Msr::IA32_FS_BASE,
+    Msr::IA32_GS_BASE,
+    Msr::IA32_KERNEL_GS_BASE,
+    Msr::IA32_STAR,
+    Msr::IA32_LSTAR,
+    Msr::IA32_FMASK,
+];
+
+struct CpuState {
+    msrs: [u64; SAVED_MSRS.len()],
+    xcr0: Option<Xcr0Flags>,
+    fpu: FpuState,
+}
+
+impl CpuState {
+    fn save() -> Self {
+        let features = cpu::features();
+        let mut msrs = [0; SAVED_MSRS.len()];
+
+        for (value, msr) in msrs.iter_mut().zip(SAVED_MSRS) {
+            if Self::present(msr, &features) {
+                *value = unsafe { msr.read() };
+            }
+        }
+
+        let xcr0 = Cr4::read().contains(Cr4Flags::OSXSAVE).then(Xcr0::read);
+
+        let mut fpu = FpuState::new();
+        fpu.save();
+
+        CpuState { msrs, xcr0, fpu }
+    }
+
+    fn restore(&self) {
+        let features = cpu::features();
+
+        for (value, msr) in self.msrs.iter().zip(SAVED_MSRS) {
+            if Self::present(msr, &features) {
+                unsafe { msr.write(*value) };
+            }
+        }
+
+        if let Some(xcr0) = self.xcr0 {
+            unsafe { Xcr0::write(xcr0) }.expect("power: cannot restore XCR0");
+        }
+
+        self.fpu.restore();
+    }
+
+    fn present(msr: Msr, features: &cpu::Features) -> bool {
+        match msr {
+            Msr::IA32_APIC_BASE => features.apic,
+            Msr::IA32_STAR | Msr::IA32_LSTAR | Msr::IA32_FMASK => features.syscall,
+            _ => true,
+        }
+    }
+}
+
+// =========================================================================
+// S3
+// =========================================================================
+
+// PM1 control register fields
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+
+// PM1 status register: set by hardware on wake
+const WAK_STS: u16 = 1 << 15;
+
+// FACS field offsets
+const FACS_WAKING_VECTOR: u64 = 12;
+const FACS_X_WAKING_VECTOR: u64 = 24;
+
+// Values for sleep(), which runs without arguments inside the saved context
+static mut SLEEP_FADT: Option<Fadt> = None;
+static mut SLEEP_TYPES: (u8, u8) = (0, 0);
+
+/// Suspends the system to RAM and returns after it woke up again. Requires `CAP_SYS_BOOT`.
+///
+/// AML methods (`_PTS`, `_WAK`) are not run, since there is no AML interpreter; this works on
+/// QEMU and most firmware that does not depend on them.
+pub fn suspend_to_ram(cred: &Credentials) -> KResult<()> {
+    capability::require(cred, Capability::SysBoot)?;
+
+    let fadt = acpi::fadt().ok_or(Errno::ENOSYS)?;
+    let types = acpi::sleep_type(3).ok_or(Errno::ENOSYS)?;
+    let facs = fadt.firmware_ctrl;
+
+    if facs == 0 || unsafe { core::ptr::read_unaligned(facs as *const [u8; 4]) } != *b"FACS" {
+        return Err(Errno::ENOSYS);
+    }
+
+    enable_acpi_mode(&fadt);
+    suspend_devices()?;
+
+    let state = CpuState::save();
+
+    let vector = match unsafe { wakeup::install_trampoline() } {
+        Ok(vector) => vector,
+        Err(err) => {
+            log::error!("power: cannot install wake-up trampoline: {:?}", err);
+            resume_devices();
+            return Err(Errno::ENOSYS);
+        }
+    };
+
+    let resumed = unsafe {
+        ((facs + FACS_WAKING_VECTOR) as *mut u32).write_volatile(vector);
+        ((facs + FACS_X_WAKING_VECTOR) as *mut u64).write_unaligned(0);
+
+        let sleep_fadt = &raw mut SLEEP_FADT;
+        let sleep_types = &raw mut SLEEP_TYPES;
+        *sleep_fadt = Some(fadt);
+        *sleep_types = types;
+
+        log::info!("power: entering S3");
+        wakeup::suspend(sleep)
+    };
+
+    state.restore();
+    resume_devices();
+
+    if resumed {
+        log::info!("power: resumed from S3");
+        Ok(())
+    } else {
+        log::error!("power: platform did not enter S3");
+        Err(Errno::EIO)
+    }
+}
+
+// Firmware may still own the PM registers (SMM mode) until asked to hand them over
+fn enable_acpi_mode(fadt: &Fadt) {
+    unsafe {
+        if port::inw(fadt.pm1a_cnt as u16) & SCI_EN != 0 || fadt.smi_cmd == 0 {
+            return;
+        }
+
+        port::outb(fadt.smi_cmd as u16, fadt.acpi_enable);
+
+        for _ in 0..1_000_000 {
+            if port::inw(fadt.pm1a_cnt as u16) & SCI_EN != 0 {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    log::warn!("power: firmware did not switch to ACPI mode");
+}
+
+// Runs with all kernel registers saved; powering down the CPU here is the expected outcome
+extern "sysv64" fn sleep() {
+    let (fadt, (typ_a, typ_b)) = unsafe {
+        let sleep_fadt = &raw const SLEEP_FADT;
+        let sleep_types = &raw const SLEEP_TYPES;
+        match *sleep_fadt {
+            Some(fadt) => (fadt, *sleep_types),
+            None => return,
+        }
+    };
+
+    unsafe {
+        // Clear the wake status so the transition is not aborted right away
+        port::outw(fadt.pm1a_evt as u16, WAK_STS);
+        if fadt.pm1b_evt != 0 {
+            port::outw(fadt.pm1b_evt as u16, WAK_STS);
+        }
+
+        // Caches are lost in S3; everything must be in RAM first
+        core::arch::asm!("wbinvd", options(nostack));
+
+        let write = |register: u32, typ: u8| {
+            let value = port::inw(register as u16) & !(0x7 << SLP_TYP_SHIFT);
+            port::outw(register as u16, value | ((typ as u16) << SLP_TYP_SHIFT) | SLP_EN);
+        };
+
+        if fadt.pm1b_cnt != 0 {
+            write(fadt.pm1b_cnt, typ_b);
+        }
+        write(fadt.pm1a_cnt, typ_a);
+
+        // Give the chipset time to power down; falling through means the sleep failed
+        for _ in 0..10_000_000 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! `prctl`: operations a process performs on itself.
+//!
+//! Only the name operations are supported so far: `PR_SET_NAME` renames the calling process
+//! (its `comm`, as `ps` and `/proc/<pid>/comm` show it) and `PR_GET_NAME` reads the name back.
+//! Unknown options fail with `EINVAL`, so callers can probe for support.
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::{COMM_LEN, Process};
+use crate::os::ptable;
+use crate::os::uaccess;
+
+/// Sets the process name from the NUL-terminated string at `arg2`.
+pub const PR_SET_NAME: u32 = 15;
+
+/// Copies the process name, NUL-terminated, to the [`COMM_LEN`]-byte buffer at `arg2`.
+pub const PR_GET_NAME: u32 = 16;
+
+// Reads a NUL-terminated name of up to COMM_LEN - 1 bytes, byte by byte so a short string at
+// the end of a mapping does not fault; longer strings are cut off like Linux does
+fn read_name(src: usize, out: &mut [u8; COMM_LEN]) -> KResult<usize> {
+    for (len, slot) in out.iter_mut().take(COMM_LEN - 1).enumerate() {
+        let mut byte = [0u8];
+        uaccess::copy_from_user(&mut byte, src + len)?;
+        if byte[0] == 0 {
+            return Ok(len);
+        }
+        *slot = byte[0];
+    }
+    Ok(COMM_LEN - 1)
+}
+
+/// Sets `process`'s name from `name`, which may be cut off mid-character by the length limit
+/// and is then shortened to whole characters. Fails with `EINVAL` if it is not UTF-8.
+pub fn set_name(process: &mut Process, name: &[u8]) -> KResult<()> {
+    let name = match core::str::from_utf8(name) {
+        Ok(name) => name,
+        Err(error) if error.error_len().is_none() => {
+            // Only the truncated last character is incomplete
+            core::str::from_utf8(&name[..error.valid_up_to()]).map_err(|_| Errno::EINVAL)?
+        }
+        Err(_) => return Err(Errno::EINVAL),
+    };
+
+    process.set_comm(name);
+    Ok(())
+}
+
+/// `prctl(option, arg2, ...)`: performs `option` on the calling process `caller`, returning 0.
+/// Fails with `EINVAL` for an unsupported option or a name that is not UTF-8.
+pub fn sys_prctl(caller: u64, option: u32, arg2: usize) -> KResult<usize> {
+    match option {
+        PR_SET_NAME => {
+            let mut name = [0u8; COMM_LEN];
+            let len = read_name(arg2, &mut name)?;
+            ptable::with_process(caller, |process| set_name(process, &name[..len])).unwrap_or(Err(Errno::ESRCH))?;
+        }
+        PR_GET_NAME => {
+            let name = ptable::with_process(caller, |process| process.name).ok_or(Errno::ESRCH)?;
+            uaccess::copy_to_user(arg2, &name)?;
+        }
+        _ => return Err(Errno::EINVAL),
+    }
+    Ok(0)
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn names_are_truncated_to_whole_characters() {
+            let mut process = Process::new(9700, 0, "a-rather-long-process-name-that-goes-on");
+            assert_eq!(process.comm().len(), COMM_LEN - 1);
+            assert_eq!(process.name[COMM_LEN - 1], 0);
+
+            // 'é' is two bytes; the one that would straddle the limit is dropped whole
+            let mut accented = [b'x'; COMM_LEN];
+            accented[COMM_LEN - 2..].copy_from_slice("é".as_bytes());
+            process.set_comm(core::str::from_utf8(&accented).unwrap());
+            assert_eq!(process.comm().len(), COMM_LEN - 2);
+
+            // As read from user space, cut off after its first byte
+            assert_eq!(set_name(&mut process, &accented[..COMM_LEN - 1]), Ok(()));
+            assert_eq!(process.comm().len(), COMM_LEN - 2);
+            assert_eq!(set_name(&mut process, b"bad\xffname"), Err(Errno::EINVAL));
+        }
+
+        fn exec_renames_after_the_program() {
+            let mut process = Process::new(9701, 0, "sh");
+            process.on_exec("/usr/bin/top");
+            assert_eq!(process.comm(), "top");
+
+            process.on_exec("relative/dir/");
+            assert_eq!(process.comm(), "dir");
+            process.on_exec("plain");
+            assert_eq!(process.comm(), "plain");
+        }
+
+        fn unknown_options_are_einval() {
+            ptable::insert(Process::new(9702, 0, "prctl")).unwrap();
+            assert_eq!(sys_prctl(9702, 0, 0), Err(Errno::EINVAL));
+            assert_eq!(ptable::remove(9702).unwrap().comm(), "prctl");
+        }
+    }
+}
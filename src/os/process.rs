@@ -1,3 +1,24 @@
+use core::sync::atomic::Ordering;
+
+use crate::os::aslr;
+use crate::os::capability;
+use crate::os::cgroup::{self, GroupId};
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::file::File;
+use crate::os::fpu::FpuState;
+use crate::os::hrtimer::{HrTimerId, Sleep};
+use crate::os::itimer::{self, PosixTimer};
+use crate::os::jobctl::JobEvent;
+use crate::os::kobject::KRef;
+use crate::os::mmap::{self, Mapping};
+use crate::os::mount::{self, MntNsId};
+use crate::os::pidns::{self, NsId, PidLinks};
+use crate::os::rlimit::{self, Rlimits};
+use crate::os::seccomp::SyscallFilter;
+use crate::os::sysctl;
+use crate::os::timer;
+
 /// Represents the current execution state of a process.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
@@ -20,6 +41,19 @@ pub enum ProcessState {
     Terminated,
 }
 
+impl ProcessState {
+    /// Short lowercase label used by `ps`-style listings and procfs.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessState::New => "new",
+            ProcessState::Ready => "ready",
+            ProcessState::Running => "running",
+            ProcessState::Blocked => "blocked",
+            ProcessState::Suspended => "suspended",
+            ProcessState::Terminated => "terminated",
+        }
+    }
+}
 
 /// A Process Control Block (PCB) that tracks all kernel-managed state for a user or kernel process.
 /// Each `Process` is a complete, schedulable execution unit tracked by the kernel scheduler.
@@ -37,13 +71,26 @@ pub struct Process {
     /// Used for signaling, hierarchy tracking, and reparenting on exit.
     pub ppid: u64,
 
+    /// Set once the process has been reparented to a reaper because its parent exited. The
+    /// kernel reaps adopted processes itself when they exit, on the reaper's behalf.
+    pub adopted: bool,
+
+    /// The process group, named after its leader's PID, which job control signals and hands
+    /// the terminal to as a unit. Inherited by children; changed through setpgid().
+    pub pgid: u64,
+
+    /// The session, named after its leader's PID: the process groups sharing a controlling
+    /// terminal. Inherited by children; setsid() starts a new one.
+    pub sid: u64,
+
     // =========================================================================
     // Metadata
     // =========================================================================
 
-    /// Fixed-length ASCII name for the process (e.g., "init", "shell", "myprog").
-    /// Null-padded if shorter than 32 bytes. Not necessarily null-terminated.
-    pub name: [u8; 32],
+    /// Fixed-length name for the process (e.g., "init", "shell", "myprog"), its `comm`.
+    /// UTF-8, null-padded and always null-terminated; read and set through
+    /// [`Process::comm`] and [`Process::set_comm`].
+    pub name: [u8; COMM_LEN],
 
     // =========================================================================
     // State and Scheduling
@@ -52,7 +99,8 @@ pub struct Process {
     /// Current scheduling state of the process (Ready, Running, Blocked, etc.).
     pub state: ProcessState,
 
-    /// Scheduling priority (0 = highest priority). Used by priority schedulers.
+    /// Scheduling priority (0 = highest priority, down to
+    /// [`LOWEST_PRIORITY`](crate::os::sched::LOWEST_PRIORITY)). Set through setpriority().
     pub priority: u8,
 
     /// Time slice allocated to the process by the scheduler (in ticks or ms).
@@ -85,8 +133,8 @@ pub struct Process {
     /// Grows upward as memory is allocated.
     pub heap_base: usize,
 
-    /// Maximum size of the heap segment in bytes.
-    /// May grow at runtime via sbrk/heap allocator logic.
+    /// Size of the heap segment in bytes: the program break lies at `heap_base + heap_size`.
+    /// Moved at runtime by `brk` and `sbrk`.
     pub heap_size: usize,
 
     /// Virtual base address of the stack segment.
@@ -96,6 +144,14 @@ pub struct Process {
     /// Maximum stack size in bytes. Enforced by guard pages or memory maps.
     pub stack_size: usize,
 
+    /// Top of the region mmap() picks addresses from, growing down.
+    /// Set from the ASLR layout at exec.
+    pub mmap_base: usize,
+
+    /// Regions mapped with mmap(): anonymous memory or views of files.
+    /// Their pages are faulted in on first touch.
+    pub mappings: [Option<Mapping>; mmap::MAX_MAPPINGS],
+
     // =========================================================================
     // Memory Management (Paging)
     // =========================================================================
@@ -122,6 +178,9 @@ pub struct Process {
     /// Saved flags register (EFLAGS/RFLAGS). Captures CPU status (interrupts, zero/carry, etc.).
     pub flags: u64,
 
+    /// Saved x87/SSE/AVX register state, switched by the FPU code on context switches.
+    pub fpu: FpuState,
+
     // =========================================================================
     // Scheduling and Blocking
     // =========================================================================
@@ -134,13 +193,29 @@ pub struct Process {
     /// Used by timer-based wait mechanisms (e.g., `sleep()`).
     pub wakeup_time: Option<u64>,
 
+    /// The nanosleep() in progress: the timer that wakes the process at `wakeup_time`.
+    pub sleep: Option<Sleep>,
+
+    /// While the process is `Suspended` by a stop signal, the state SIGCONT returns it to.
+    pub stopped_from: Option<ProcessState>,
+
+    /// A stop or continue the parent has not yet collected through wait().
+    pub job_event: Option<JobEvent>,
+
+    /// High-resolution timer behind setitimer(ITIMER_REAL) and alarm() while one is armed.
+    /// Raises SIGALRM on expiry; cancelled when the process exits.
+    pub real_timer: Option<HrTimerId>,
+
+    /// Timers made with timer_create(), indexed by the IDs handed to the process.
+    pub posix_timers: [Option<PosixTimer>; itimer::MAX_POSIX_TIMERS],
+
     // =========================================================================
     // Interprocess Communication / File System
     // =========================================================================
 
-    /// Simulated file descriptor table: up to 64 open files/pipes/devices per process.
-    /// Each entry contains a file descriptor index or ID. `None` means unused slot.
-    pub file_descriptors: [Option<u32>; 64],
+    /// File descriptor table: up to 64 open files/pipes/devices per process.
+    /// Each entry holds a reference to the open file. `None` means unused slot.
+    pub file_descriptors: [Option<KRef<File>>; 64],
 
     // =========================================================================
     // Signals (UNIX-like)
@@ -154,20 +229,40 @@ pub struct Process {
     /// If `signal_handlers[n]` is non-zero, it's the handler for signal `n`.
     pub signal_handlers: [usize; 32],
 
+    /// Address of the trampoline each handler returns into, which makes the `rt_sigreturn`
+    /// call; registered with the handler as `rt_sigaction`'s `sa_restorer`.
+    pub signal_restorers: [usize; 32],
+
+    /// Bitmap of blocked signals, which stay pending until unblocked.
+    /// A handler runs with its own signal added to the mask.
+    pub signal_mask: u64,
+
+    // =========================================================================
+    // Resource Limits
+    // =========================================================================
+
+    /// Soft and hard limits on open files, address space, stack size and CPU time.
+    /// Inherited by children across fork; changed through setrlimit().
+    pub rlimits: Rlimits,
+
+    /// Control group whose CPU weight and memory ceiling apply to this process.
+    /// Inherited by children; changed by writing the PID to a group's `cgroup.procs`.
+    pub cgroup: GroupId,
+
     // =========================================================================
     // Time Accounting
     // =========================================================================
 
-    /// Kernel tick count when the process was created.
-    /// Used for diagnostics, aging, and lifetime metrics.
+    /// Timer tick ([`timer::current_tick`], milliseconds of monotonic time) when the process
+    /// was created. Used for diagnostics, aging, and lifetime metrics.
     pub created_at: u64,
 
     /// Total CPU time consumed by this process (in ticks).
-    /// Updated on every deschedule or preemption.
+    /// Charged by every timer tick it runs through, and for the rest on every context switch.
     pub cpu_time: u64,
 
-    /// Timestamp of the last time this process was scheduled to run.
-    /// Useful for scheduling policies and profiling.
+    /// Tick up to which the running process's CPU time has been charged.
+    /// Set when it is switched to and advanced by every timer tick charged to it.
     pub last_scheduled: u64,
 
     // =========================================================================
@@ -177,15 +272,214 @@ pub struct Process {
     /// Virtual base address of this process’s kernel stack (for syscall, interrupts).
     /// Used during privilege transitions and stored in TSS or equivalent structure.
     pub kernel_stack: usize,
+
+    // =========================================================================
+    // Security
+    // =========================================================================
+
+    /// User/group identity used for permission checks (file access, signalling, ...).
+    /// Inherited by children and changed through the setuid/setgid family.
+    pub cred: Credentials,
+
+    /// Syscalls this process may make, checked by the dispatcher before every call.
+    /// Inherited by children across fork and kept across exec; can only be narrowed.
+    pub syscall_filter: SyscallFilter,
+
+    // =========================================================================
+    // Namespaces
+    // =========================================================================
+
+    /// The PID namespace the process lives in and its PID in each namespace from the root
+    /// down to that one. `pid` is the PID in the root namespace.
+    pub pid_links: PidLinks,
+
+    /// The PID namespace new children are placed in; differs from the process's own after
+    /// an `unshare` of the PID namespace.
+    pub pid_ns_for_children: NsId,
+
+    /// The mount namespace whose mount table the process's path lookups use.
+    pub mnt_ns: MntNsId,
+
+    /// The process's root directory as a path in its mount namespace; lookups cannot leave
+    /// it. Changed through chroot() and inherited by children.
+    pub root: mount::Path,
+}
+
+/// Size of a process name, the null terminator included (Linux's `TASK_COMM_LEN`).
+pub const COMM_LEN: usize = 32;
+
+/// The process name stored in `name`, up to the first NUL byte (non-UTF-8 names are shown
+/// as `?`).
+pub fn comm_str(name: &[u8; COMM_LEN]) -> &str {
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    core::str::from_utf8(&name[..len]).unwrap_or("?")
+}
+
+/// Priority given to new processes unless the creator asks for something else.
+pub const DEFAULT_PRIORITY: u8 = 16;
+
+/// Timeslice (in ticks) given to new processes until `kernel.sched_timeslice` is changed.
+pub const DEFAULT_TIMESLICE: u32 = 10;
+
+/// Size of a process's kernel stack.
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+impl Process {
+    /// Creates a PCB in the `New` state with an empty address space, no open files and no
+    /// pending signals. `name` is truncated as [`Process::set_comm`] does.
+    pub fn new(pid: u64, ppid: u64, name: &str) -> Self {
+        let mut process = Process {
+            pid,
+            ppid,
+            adopted: false,
+            pgid: pid,
+            sid: pid,
+            name: [0; COMM_LEN],
+            state: ProcessState::New,
+            priority: DEFAULT_PRIORITY,
+            timeslice: sysctl::SCHED_TIMESLICE.load(Ordering::Relaxed) as u32,
+            exit_code: None,
+            code_base: 0,
+            code_size: 0,
+            data_base: 0,
+            data_size: 0,
+            heap_base: 0,
+            heap_size: 0,
+            stack_base: 0,
+            stack_size: 0,
+            mmap_base: aslr::MMAP_BASE as usize,
+            mappings: [None; mmap::MAX_MAPPINGS],
+            page_table_root: 0,
+            regs: [0; 32],
+            pc: 0,
+            sp: 0,
+            flags: 0,
+            fpu: FpuState::new(),
+            waiting_on: None,
+            wakeup_time: None,
+            sleep: None,
+            stopped_from: None,
+            job_event: None,
+            real_timer: None,
+            posix_timers: [None; itimer::MAX_POSIX_TIMERS],
+            file_descriptors: [const { None }; 64],
+            signal_bitmap: 0,
+            signal_handlers: [0; 32],
+            signal_restorers: [0; 32],
+            signal_mask: 0,
+            rlimits: Rlimits::DEFAULT,
+            cgroup: cgroup::ROOT_GROUP,
+            created_at: timer::current_tick(),
+            cpu_time: 0,
+            last_scheduled: 0,
+            kernel_stack: 0,
+            cred: Credentials::ROOT,
+            syscall_filter: SyscallFilter::ALLOW_ALL,
+            pid_links: PidLinks::root(pid),
+            pid_ns_for_children: pidns::ROOT_NS,
+            mnt_ns: mount::ROOT_NS,
+            root: mount::Path::ROOT,
+        };
+        process.set_comm(name);
+        process
+    }
+
+    /// A PCB standing in for this one in checks made on its behalf once the process table is
+    /// unlocked, such as path lookups and the security modules' hooks. It has the process's
+    /// IDs, name, credentials, limits, cgroup, namespaces and root, and is otherwise as
+    /// [`Process::new`] leaves it.
+    pub fn stand_in(&self) -> Process {
+        let mut stand_in = Process::new(self.pid, self.ppid, "");
+        stand_in.pgid = self.pgid;
+        stand_in.sid = self.sid;
+        stand_in.name = self.name;
+        stand_in.rlimits = self.rlimits;
+        stand_in.cgroup = self.cgroup;
+        stand_in.cred = self.cred;
+        stand_in.pid_links = self.pid_links;
+        stand_in.pid_ns_for_children = self.pid_ns_for_children;
+        stand_in.mnt_ns = self.mnt_ns;
+        stand_in.root = self.root;
+        stand_in
+    }
+
+    /// Makes the process runnable again once what it was blocked on has happened. A stopped
+    /// process stays stopped, and is runnable when continued.
+    pub fn wake(&mut self) {
+        self.waiting_on = None;
+        match self.state {
+            ProcessState::Blocked => self.state = ProcessState::Ready,
+            ProcessState::Suspended if self.stopped_from == Some(ProcessState::Blocked) => {
+                self.stopped_from = Some(ProcessState::Ready);
+            }
+            _ => {}
+        }
+    }
+
+    /// Top of the kernel stack, where interrupts and syscalls from user mode start out, or 0
+    /// if the process has none.
+    pub fn kernel_stack_top(&self) -> u64 {
+        if self.kernel_stack == 0 { 0 } else { (self.kernel_stack + KERNEL_STACK_SIZE) as u64 }
+    }
+
+    /// The process name, as `ps`, `prctl(PR_GET_NAME)` and `/proc/<pid>/comm` report it.
+    pub fn comm(&self) -> &str {
+        comm_str(&self.name)
+    }
+
+    /// Renames the process. Names longer than [`COMM_LEN`] - 1 bytes are cut after the last
+    /// whole character that fits.
+    pub fn set_comm(&mut self, name: &str) {
+        let mut len = name.len().min(COMM_LEN - 1);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        self.name = [0; COMM_LEN];
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+
+    /// Updates what follows the image rather than the process once an exec of the program at
+    /// `path` is committed: the process is renamed after the last component of `path` and its
+    /// capability sets are recomputed.
+    pub fn on_exec(&mut self, path: &str) {
+        let trimmed = path.trim_end_matches('/');
+        self.set_comm(trimmed.rsplit('/').next().unwrap_or(trimmed));
+        capability::on_exec(&mut self.cred);
+    }
+
+    /// Total virtual memory reserved by the code, data, heap and stack segments and by
+    /// mappings, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.code_size + self.data_size + self.heap_size + self.stack_size + mmap::mapped_bytes(self) as usize
+    }
+
+    /// Stores `file` in the lowest free descriptor slot allowed by `RLIMIT_NOFILE` and returns
+    /// the descriptor number.
+    pub fn alloc_fd(&mut self, file: KRef<File>) -> KResult<usize> {
+        let fd = self.file_descriptors.iter().position(Option::is_none).ok_or(Errno::EMFILE)?;
+        rlimit::check_fd(self, fd)?;
+
+        self.file_descriptors[fd] = Some(file);
+        Ok(fd)
+    }
+
+    /// The file open as descriptor `fd`. Fails with `EBADF` if there is none.
+    pub fn file(&self, fd: usize) -> KResult<&KRef<File>> {
+        self.file_descriptors.get(fd).and_then(Option::as_ref).ok_or(Errno::EBADF)
+    }
 }
 
 /// Enum representing entities that a process may be blocked waiting for.
 /// Used by the scheduler and blocking primitives to resume the process.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WaitTarget {
     /// Waiting for a specific process to terminate or change state (e.g., waitpid).
     PID(u64),
 
+    /// Waiting for any child to terminate (e.g., wait(), waitpid(-1, ...)).
+    AnyChild,
+
     /// Waiting on an I/O device (e.g., disk, terminal, network card).
     IODevice(u32),
 
@@ -197,4 +491,7 @@ pub enum WaitTarget {
 
     /// Waiting on a message to arrive in a queue or IPC channel.
     MessageQueue(u32),
+
+    /// Waiting to acquire a kernel mutex currently held by another process.
+    Mutex(u32),
 }
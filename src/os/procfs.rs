@@ -0,0 +1,267 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+
+use crate::os::config;
+use crate::os::cred::{Credentials, ROOT_ID};
+use crate::os::errno::{Errno, KResult};
+use crate::os::file::{MAX_FILES, O_ACCMODE, O_RDONLY};
+use crate::os::kobject;
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::mount::Path;
+use crate::os::pagecache;
+use crate::os::pidns::{self, NsId};
+use crate::os::process::Process;
+use crate::os::ptable::{self, ProcessInfo, MAX_PROCESSES};
+use crate::os::swap;
+use crate::os::sync::SpinLock;
+use crate::os::sysctl::{self, Tunable};
+use crate::os::thp;
+use crate::os::vfs::{FileSystem, Inode, InodeKind};
+use crate::os::vmalloc;
+
+/// Renders the procfs file at `path` (relative to the procfs mount point) into `w`, as seen
+/// from PID namespace `ns`: PIDs in paths and contents are those of `ns`, and processes it
+/// cannot see do not appear.
+///
+/// Supported files:
+/// - `processes`: one line per process, the data source for `ps`/`top`
+/// - `meminfo`: system-wide memory figures
+/// - `config`: the build options the kernel was compiled with
+/// - `kobjects`: live and total objects of each kernel object pool
+/// - `<pid>/status`: `key: value` lines describing a single process
+/// - `<pid>/comm`: the process name
+/// - `sys/<section>/<name>`: the value of the kernel tunable `<section>.<name>`
+///
+/// Returns `None` if no such file exists.
+pub fn render(path: &str, ns: NsId, w: &mut impl Write) -> Option<fmt::Result> {
+    let path = path.trim_matches('/');
+
+    if path == "processes" {
+        return Some(write_process_list(ns, w));
+    }
+
+    if path == "meminfo" {
+        return Some(write_meminfo(w));
+    }
+
+    if path == "config" {
+        return Some(config::write_config(w));
+    }
+
+    if path == "kobjects" {
+        return Some(kobject::write_stats(w));
+    }
+
+    if let Some(tunable) = path.strip_prefix("sys/").and_then(find_tunable) {
+        return Some(writeln!(w, "{}", tunable.value.load(Ordering::Relaxed)));
+    }
+
+    let (pid, file) = path.split_once('/')?;
+    let pid = pidns::to_global(ns, pid.parse().ok()?)?;
+
+    match file {
+        "status" => ptable::info(pid).map(|info| write_status(&info, ns, w)),
+        "comm" => ptable::info(pid).map(|info| writeln!(w, "{}", info.name())),
+        _ => None,
+    }
+}
+
+/// Writes `data` (a decimal number, optionally followed by a newline) to the procfs file at
+/// `path` on behalf of `cred`. Only the `sys/` tunables are writable.
+///
+/// Returns `None` if no such writable file exists.
+pub fn write(path: &str, data: &str, cred: &Credentials) -> Option<KResult<()>> {
+    let tunable = path.trim_matches('/').strip_prefix("sys/").and_then(find_tunable)?;
+
+    Some(match data.trim().parse() {
+        Ok(value) => sysctl::set(cred, tunable.name, value),
+        Err(_) => Err(Errno::EINVAL),
+    })
+}
+
+/// procfs as the VFS opens it. A file is rendered afresh for every read, as seen from the PID
+/// namespace of the process that opened it, and written on behalf of that process's
+/// credentials; only the tunables can be opened for writing.
+pub static FS: ProcFs = ProcFs;
+
+pub struct ProcFs;
+
+// What each open file was opened as, by inode number
+#[derive(Clone, Copy)]
+struct OpenFile {
+    path: Path,
+    ns: NsId,
+    cred: Credentials,
+}
+
+static OPEN: SpinLock<[Option<OpenFile>; MAX_FILES]> = SpinLock::new([None; MAX_FILES]);
+
+// Counts what is written to it
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len() as u64;
+        Ok(())
+    }
+}
+
+// Keeps the part of what is written to it that falls in `buf`, `skip` bytes in
+struct Window<'a> {
+    skip: u64,
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        let skipped = self.skip.min(bytes.len() as u64);
+        self.skip -= skipped;
+        bytes = &bytes[skipped as usize..];
+
+        let n = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn open(&self, process: &Process, path: &Path, flags: u32) -> KResult<Inode> {
+        let ns = process.pid_links.namespace();
+        let kind = if *path == Path::ROOT { InodeKind::Directory } else { InodeKind::File };
+
+        let mut size = Counter(0);
+        if kind == InodeKind::File {
+            render(path.as_str(), ns, &mut size).ok_or(Errno::ENOENT)?.map_err(|_| Errno::EIO)?;
+        }
+        let tunable = path.as_str().trim_matches('/').strip_prefix("sys/").and_then(find_tunable);
+        if flags & O_ACCMODE != O_RDONLY && tunable.is_none() {
+            return Err(Errno::EACCES);
+        }
+
+        let file = OpenFile { path: *path, ns, cred: process.cred };
+        let ino = OPEN.with(|open| {
+            let ino = open.iter().position(Option::is_none)?;
+            open[ino] = Some(file);
+            Some(ino)
+        });
+        // Root's, and only the tunables writable, by root alone
+        let mode = match (kind, tunable) {
+            (InodeKind::Directory, _) => 0o555,
+            (_, Some(_)) => 0o644,
+            _ => 0o444,
+        };
+        Ok(Inode { ino: ino.ok_or(Errno::ENOSPC)? as u64, kind, size: size.0, uid: ROOT_ID, gid: ROOT_ID, mode })
+    }
+
+    fn read(&self, ino: u64, buf: &mut [u8], offset: u64) -> KResult<usize> {
+        let file = OPEN.with(|open| open.get(ino as usize).copied().flatten()).ok_or(Errno::EBADF)?;
+
+        let mut window = Window { skip: offset, buf, len: 0 };
+        render(file.path.as_str(), file.ns, &mut window).ok_or(Errno::ENOENT)?.map_err(|_| Errno::EIO)?;
+        Ok(window.len)
+    }
+
+    fn write(&self, ino: u64, data: &[u8], _offset: u64) -> KResult<usize> {
+        let file = OPEN.with(|open| open.get(ino as usize).copied().flatten()).ok_or(Errno::EBADF)?;
+
+        let data = core::str::from_utf8(data).map_err(|_| Errno::EINVAL)?;
+        write(file.path.as_str(), data, &file.cred).ok_or(Errno::EACCES)??;
+        Ok(data.len())
+    }
+
+    fn close(&self, ino: u64) {
+        OPEN.with(|open| open[ino as usize] = None);
+    }
+}
+
+// Maps `kernel/log_level` to the `kernel.log_level` tunable
+fn find_tunable(path: &str) -> Option<&'static Tunable> {
+    sysctl::TUNABLES.iter().find(|t| t.name.len() == path.len() && t.name.split('.').eq(path.split('/')))
+}
+
+// PID of `info`'s parent as seen from `ns`, or 0 if it is not visible there
+fn ppid_in(info: &ProcessInfo, ns: NsId) -> u64 {
+    pidns::from_global(ns, info.ppid).unwrap_or(0)
+}
+
+/// Writes a `ps`-style table of every process visible from `ns`, sorted by PID.
+pub fn write_process_list(ns: NsId, w: &mut impl Write) -> fmt::Result {
+    let mut infos = [ProcessInfo::EMPTY; MAX_PROCESSES];
+    let count = ptable::snapshot(&mut infos);
+
+    // Keep the visible processes, renumbered as `ns` sees them
+    let mut visible = [ProcessInfo::EMPTY; MAX_PROCESSES];
+    let mut shown = 0;
+    for info in &infos[..count] {
+        if let Some(pid) = info.pid_links.pid_in(ns) {
+            visible[shown] = ProcessInfo { pid, ppid: ppid_in(info, ns), ..*info };
+            shown += 1;
+        }
+    }
+    visible[..shown].sort_unstable_by_key(|info| info.pid);
+
+    writeln!(w, "{:>6} {:>6} {:<10} {:>4} {:>10} {:>10} NAME", "PID", "PPID", "STATE", "PRI", "CPU", "MEM(KiB)")?;
+
+    for info in &visible[..shown] {
+        writeln!(
+            w,
+            "{:>6} {:>6} {:<10} {:>4} {:>10} {:>10} {}",
+            info.pid,
+            info.ppid,
+            info.state.as_str(),
+            info.priority,
+            info.cpu_time,
+            info.memory / 1024,
+            info.name()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the `meminfo` file: total, free and ballooned memory, the page cache, huge pages, swap
+/// space, the vmalloc window and commitments.
+pub fn write_meminfo(w: &mut impl Write) -> fmt::Result {
+    let stats = memory::stats();
+    let swap = swap::stats();
+    let cache = pagecache::stats();
+
+    writeln!(w, "MemTotal:\t{} kB", stats.total / 1024)?;
+    writeln!(w, "MemFree:\t{} kB", stats.free / 1024)?;
+    writeln!(w, "Ballooned:\t{} kB", stats.ballooned / 1024)?;
+    writeln!(w, "Cached:\t{} kB", cache.cached * FRAME_SIZE / 1024)?;
+    writeln!(w, "Dirty:\t{} kB", cache.dirty * FRAME_SIZE / 1024)?;
+    writeln!(w, "AnonHugePages:\t{} kB", thp::huge_pages() * thp::HUGE_PAGE_SIZE / 1024)?;
+    writeln!(w, "SwapTotal:\t{} kB", swap.total / 1024)?;
+    writeln!(w, "SwapFree:\t{} kB", swap.free / 1024)?;
+    writeln!(w, "VmallocTotal:\t{} kB", vmalloc::WINDOW_SIZE / 1024)?;
+    writeln!(w, "VmallocUsed:\t{} kB", vmalloc::used() / 1024)?;
+    writeln!(w, "CommitLimit:\t{} kB", memory::commit_limit() / 1024)?;
+    writeln!(w, "Committed_AS:\t{} kB", memory::committed() / 1024)
+}
+
+/// Writes the `status` file of a single process as seen from `ns`, which must be able to see it.
+pub fn write_status(info: &ProcessInfo, ns: NsId, w: &mut impl Write) -> fmt::Result {
+    let links = &info.pid_links;
+    let from = pidns::level_of(ns).unwrap_or(0) as usize;
+
+    writeln!(w, "Name:\t{}", info.name())?;
+    writeln!(w, "Pid:\t{}", links.pid_in(ns).unwrap_or(0))?;
+    writeln!(w, "PPid:\t{}", ppid_in(info, ns))?;
+
+    // The process's PID in `ns` and each namespace below it, down to its own
+    write!(w, "NSpid:")?;
+    for pid in &links.pids[from..=links.level as usize] {
+        write!(w, "\t{}", pid)?;
+    }
+    writeln!(w)?;
+
+    writeln!(w, "State:\t{}", info.state.as_str())?;
+    writeln!(w, "Priority:\t{}", info.priority)?;
+    writeln!(w, "CpuTime:\t{}", info.cpu_time)?;
+    writeln!(w, "VmSize:\t{} kB", info.memory / 1024)
+}
+
@@ -0,0 +1,89 @@
+use crate::os::arch::{Arch, Current};
+use crate::os::errno::{Errno, KResult};
+
+/// Enables the hardware pieces W^X relies on (on x86, EFER.NXE for non-executable pages and CR0.WP
+/// so read-only pages also bind the kernel). Called once at boot before any page tables are built.
+pub fn init() {
+    Current::init_memory_protection();
+}
+
+/// Access rights requested for a mapping, in `mmap(PROT_*)` terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+
+    /// Accessible from ring 3.
+    pub user: bool,
+}
+
+impl Protection {
+    /// Kernel or user code: readable and executable, never writable.
+    pub const fn code(user: bool) -> Self {
+        Protection { read: true, write: false, exec: true, user }
+    }
+
+    /// Read-only data (constants, `.rodata`).
+    pub const fn read_only(user: bool) -> Self {
+        Protection { read: true, write: false, exec: false, user }
+    }
+
+    /// Ordinary data, heap and stack pages: writable, never executable.
+    pub const fn data(user: bool) -> Self {
+        Protection { read: true, write: true, exec: false, user }
+    }
+
+    /// Returns `true` if the mapping would be writable and executable at the same time.
+    pub fn violates_wx(&self) -> bool {
+        self.write && self.exec
+    }
+
+    /// Translates the protection into page table entry flags, enforcing W^X.
+    ///
+    /// Every page that is not code is non-executable (when the CPU supports it) and code is never
+    /// writable.
+    /// A user request for a writable and executable mapping is rejected with `EACCES`; kernel
+    /// requests of that kind are a kernel bug and panic.
+    pub fn pte_flags(&self) -> KResult<u64> {
+        if self.violates_wx() {
+            assert!(self.user, "protection: kernel asked for a writable and executable mapping");
+            return Err(Errno::EACCES);
+        }
+
+        Ok(Current::page_flags(self.write, self.exec, self.user))
+    }
+}
+
+pub mod ktests {
+    use super::*;
+    use crate::os::ktest;
+
+    crate::os::ktest::kernel_test! {
+        fn only_writable_code_violates_wx() {
+            for user in [false, true] {
+                assert!(!Protection::code(user).violates_wx());
+                assert!(!Protection::read_only(user).violates_wx());
+                assert!(!Protection::data(user).violates_wx());
+                assert!(Protection { read: false, write: true, exec: true, user }.violates_wx());
+            }
+        }
+
+        fn user_wx_requests_get_eacces() {
+            let wx = Protection { read: true, write: true, exec: true, user: true };
+            assert_eq!(wx.pte_flags(), Err(Errno::EACCES));
+
+            assert_eq!(Protection::data(true).pte_flags(), Ok(Current::page_flags(true, false, true)));
+            assert_eq!(Protection::code(false).pte_flags(), Ok(Current::page_flags(false, true, false)));
+        }
+
+        fn kernel_wx_requests_panic() {
+            assert!(ktest::panics(|| {
+                _ = Protection { read: true, write: true, exec: true, user: false }.pte_flags();
+            }));
+            assert!(!ktest::panics(|| {
+                _ = Protection::code(false).pte_flags();
+            }));
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::os::ipi;
+use crate::os::pidns::PidLinks;
+use crate::os::process::{self, COMM_LEN, Process, ProcessState, WaitTarget};
+use crate::os::sync::SpinLock;
+
+/// Maximum number of processes (including zombies) the kernel can track at once.
+pub const MAX_PROCESSES: usize = 64;
+
+type Table = [Option<Process>; MAX_PROCESSES];
+
+// Static process table; a slot is `None` when unused
+static PROCESS_TABLE: SpinLock<Table> = SpinLock::new([const { None }; MAX_PROCESSES]);
+
+// Memory reserved by each slot's process as of the last time the slot was touched, so the
+// commit check can add it up without the lock, which its callers may already hold
+static USAGE: [AtomicU64; MAX_PROCESSES] = [const { AtomicU64::new(0) }; MAX_PROCESSES];
+
+fn note_usage(index: usize, slot: &Option<Process>) {
+    USAGE[index].store(slot.as_ref().map_or(0, |p| p.memory_usage() as u64), Ordering::Relaxed);
+}
+
+// Wakeups asked for while the table was locked, each with how many processes it may wake,
+// left for whoever holds the lock to carry out before unlocking
+static DEFERRED_WAKES: SpinLock<[Option<(WaitTarget, usize)>; MAX_PROCESSES]> = SpinLock::new([None; MAX_PROCESSES]);
+static WAKES_DEFERRED: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the table with its lock held. The lock is not reentrant: a lookup made while
+// this CPU holds it spins forever.
+fn with_table<R>(f: impl FnOnce(&mut Table) -> R) -> R {
+    let mut table = loop {
+        if let Some(table) = PROCESS_TABLE.try_lock() {
+            break table;
+        }
+        // The holder may be waiting for this CPU to take part in a TLB shootdown
+        ipi::poll();
+        core::hint::spin_loop();
+    };
+    let result = f(&mut table);
+    run_deferred_wakes(&mut table);
+    drop(table);
+
+    // A wakeup may have been deferred after the run above, before the unlock
+    try_run_deferred_wakes();
+    result
+}
+
+fn run_deferred_wakes(table: &mut Table) {
+    if WAKES_DEFERRED.swap(false, Ordering::SeqCst) {
+        let wakes = DEFERRED_WAKES.with(|wakes| core::mem::replace(wakes, [None; MAX_PROCESSES]));
+        for (target, limit) in wakes.into_iter().flatten() {
+            wake_in(table, target, limit, |_, _| ());
+        }
+    }
+}
+
+// Carries out the deferred wakeups if the table can be locked without waiting; otherwise
+// its holder will
+fn try_run_deferred_wakes() {
+    while WAKES_DEFERRED.load(Ordering::SeqCst)
+        && let Some(mut table) = PROCESS_TABLE.try_lock()
+    {
+        run_deferred_wakes(&mut table);
+    }
+}
+
+fn wake_in(table: &mut Table, target: WaitTarget, limit: usize, mut woken: impl FnMut(usize, &Process)) -> usize {
+    let mut count = 0;
+    for process in table.iter_mut().flatten() {
+        if count < limit && process.waiting_on == Some(target) && process.state != ProcessState::Terminated {
+            process.wake();
+            woken(count, process);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Reasons a process cannot be added to the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    /// Every slot is in use.
+    TableFull,
+
+    /// Another process already has this PID.
+    PidInUse,
+}
+
+/// Adds a process to the table.
+pub fn insert(process: Process) -> Result<(), InsertError> {
+    with_table(|table| {
+        if table.iter().flatten().any(|p| p.pid == process.pid) {
+            return Err(InsertError::PidInUse);
+        }
+
+        match table.iter().position(Option::is_none) {
+            Some(index) => {
+                table[index] = Some(process);
+                note_usage(index, &table[index]);
+                Ok(())
+            }
+            None => Err(InsertError::TableFull),
+        }
+    })
+}
+
+/// Removes a process from the table, returning its PCB.
+pub fn remove(pid: u64) -> Option<Process> {
+    with_table(|table| {
+        let index = find(table, pid)?;
+        USAGE[index].store(0, Ordering::Relaxed);
+        table[index].take()
+    })
+}
+
+fn find(table: &Table, pid: u64) -> Option<usize> {
+    table.iter().position(|slot| slot.as_ref().is_some_and(|p| p.pid == pid))
+}
+
+/// Runs `f` on the process with the given PID, if it exists. The table stays locked while `f`
+/// runs, so `f` must not give up the CPU, call back into the table or touch user memory, whose
+/// page faults look the process up: callers copy out what they need and act on it afterwards.
+pub fn with_process<R>(pid: u64, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    with_table(|table| {
+        let index = find(table, pid)?;
+        let result = table[index].as_mut().map(f);
+        note_usage(index, &table[index]);
+        result
+    })
+}
+
+/// Runs `f` on the two different processes `first` and `second` at once, with the table locked
+/// as for [`with_process`]. Returns `None` if either is missing or they are the same process.
+pub fn with_pair<R>(first: u64, second: u64, f: impl FnOnce(&mut Process, &mut Process) -> R) -> Option<R> {
+    with_table(|table| {
+        let (i, j) = (find(table, first)?, find(table, second)?);
+        if i == j {
+            return None;
+        }
+
+        let (low, high) = table.split_at_mut(i.max(j));
+        let (lower, upper) = (low[i.min(j)].as_mut()?, high[0].as_mut()?);
+        let result = if i < j { f(lower, upper) } else { f(upper, lower) };
+
+        note_usage(i, &table[i]);
+        note_usage(j, &table[j]);
+        Some(result)
+    })
+}
+
+/// Wakes up to `limit` processes waiting on `target`, in slot order, calls `woken` with the
+/// index and PCB of each, and returns how many there were. If the table is locked, as it is
+/// for code running under a lookup on this CPU, the wakeup is left to the holder to carry out
+/// before it unlocks, and 0 is returned.
+pub fn wake(target: WaitTarget, limit: usize, woken: impl FnMut(usize, &Process)) -> usize {
+    if let Some(mut table) = PROCESS_TABLE.try_lock() {
+        let count = wake_in(&mut table, target, limit, woken);
+        run_deferred_wakes(&mut table);
+        drop(table);
+        try_run_deferred_wakes();
+        return count;
+    }
+
+    DEFERRED_WAKES.with(|wakes| match wakes.iter_mut().find(|wake| wake.is_none_or(|(t, _)| t == target)) {
+        Some(Some((_, pending))) => *pending = pending.saturating_add(limit),
+        Some(wake) => *wake = Some((target, limit)),
+        None => log::warn!("ptable: too many deferred wakeups, {:?} dropped", target),
+    });
+    WAKES_DEFERRED.store(true, Ordering::SeqCst);
+    try_run_deferred_wakes();
+    0
+}
+
+/// Runs `f` on every process in the table, in slot order, with the table locked as for
+/// [`with_process`].
+pub fn for_each(mut f: impl FnMut(&mut Process)) {
+    with_table(|table| {
+        for (index, slot) in table.iter_mut().enumerate() {
+            if let Some(process) = slot {
+                f(process);
+                note_usage(index, slot);
+            }
+        }
+    });
+}
+
+/// Virtual memory reserved by the processes in the table, in bytes, as of when each was last
+/// looked up. Taken without the lock, so it can be read from under a lookup.
+pub fn committed() -> u64 {
+    USAGE.iter().map(|usage| usage.load(Ordering::Relaxed)).sum()
+}
+
+/// Number of processes currently in the table.
+pub fn count() -> usize {
+    let mut count = 0;
+    for_each(|_| count += 1);
+    count
+}
+
+/// A point-in-time copy of the fields a `ps`/`top` style tool needs about one process.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: u64,
+    pub ppid: u64,
+    pub name: [u8; COMM_LEN],
+    pub state: ProcessState,
+    pub priority: u8,
+
+    /// CPU time consumed so far, in ticks.
+    pub cpu_time: u64,
+
+    /// Virtual memory reserved by the process's segments, in bytes.
+    pub memory: usize,
+
+    /// The process's PIDs in its namespace and each ancestor, for translating `pid`.
+    pub pid_links: PidLinks,
+}
+
+impl ProcessInfo {
+    /// Placeholder for initialising snapshot buffers.
+    pub const EMPTY: ProcessInfo = ProcessInfo {
+        pid: 0,
+        ppid: 0,
+        name: [0; COMM_LEN],
+        state: ProcessState::New,
+        priority: 0,
+        cpu_time: 0,
+        memory: 0,
+        pid_links: PidLinks::root(0),
+    };
+
+    fn from_process(p: &Process) -> Self {
+        ProcessInfo {
+            pid: p.pid,
+            ppid: p.ppid,
+            name: p.name,
+            state: p.state,
+            priority: p.priority,
+            cpu_time: p.cpu_time,
+            memory: p.memory_usage(),
+            pid_links: p.pid_links,
+        }
+    }
+
+    /// The process name up to the first NUL byte (non-UTF-8 names are shown as `?`).
+    pub fn name(&self) -> &str {
+        process::comm_str(&self.name)
+    }
+}
+
+/// Copies up to `out.len()` process entries into `out`, sorted by PID. Returns how many were
+/// written; the snapshot is consistent because the table is locked while it is taken.
+pub fn snapshot(out: &mut [ProcessInfo]) -> usize {
+    let mut written = 0;
+
+    for_each(|p| {
+        if written < out.len() {
+            out[written] = ProcessInfo::from_process(p);
+            written += 1;
+        }
+    });
+
+    out[..written].sort_unstable_by_key(|info| info.pid);
+    written
+}
+
+/// Returns a snapshot of a single process.
+pub fn info(pid: u64) -> Option<ProcessInfo> {
+    with_process(pid, |p| ProcessInfo::from_process(p))
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::arch::{Arch, Current};
+
+    crate::os::ktest::kernel_test! {
+        fn lookups_run_with_the_table_locked() {
+            insert(Process::new(9990, 0, "outer")).unwrap();
+            insert(Process::new(9991, 9990, "inner")).unwrap();
+            assert_eq!(insert(Process::new(9991, 0, "again")).err(), Some(InsertError::PidInUse));
+
+            // The table is locked, with interrupts masked; the parent is looked up after
+            let ppid = with_process(9991, |child| {
+                assert!(!Current::interrupts_enabled());
+                child.ppid
+            });
+            assert_eq!(ppid.and_then(|ppid| with_process(ppid, |parent| parent.pid)), Some(9990));
+
+            let mut seen = [0; 2];
+            let mut count = 0;
+            for_each(|p| {
+                if [9990, 9991].contains(&p.pid) {
+                    seen[count] = p.pid;
+                    count += 1;
+                }
+            });
+            assert!(seen[..count].iter().all(|&pid| info(pid).is_some()));
+            assert_eq!(count, 2);
+
+            // The commit total follows each lookup, and can be read from inside one
+            let before = committed();
+            let inside = with_process(9990, |p| {
+                p.heap_size += 8192;
+                committed()
+            });
+            assert_eq!(inside, Some(before));
+            assert_eq!(committed(), before + 8192);
+
+            // A wakeup asked for under a lookup is carried out once the table is unlocked
+            let target = WaitTarget::Semaphore(9991);
+            with_process(9991, |p| {
+                p.state = ProcessState::Blocked;
+                p.waiting_on = Some(target);
+            });
+            assert_eq!(with_process(9990, |_| wake(target, 1, |_, _| ())), Some(0));
+            assert_eq!(with_process(9991, |p| (p.state, p.waiting_on)), Some((ProcessState::Ready, None)));
+            assert_eq!(wake(target, 1, |_, _| ()), 0);
+
+            // Both processes of a pair can be changed together, in the order asked for
+            assert_eq!(with_pair(9991, 9990, |child, parent| (child.pid, parent.pid)), Some((9991, 9990)));
+            assert_eq!(with_pair(9990, 9991, |parent, child| (parent.pid, child.pid)), Some((9990, 9991)));
+            assert!(with_pair(9990, 9990, |_, _| ()).is_none());
+
+            assert!(remove(9990).is_some() && remove(9991).is_some());
+            assert!(committed() <= before);
+            assert!(with_process(9990, |_| ()).is_none());
+        }
+    }
+}
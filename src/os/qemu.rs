@@ -0,0 +1,56 @@
+use crate::os::arch::{Arch, Current};
+
+/// Exit codes reported to QEMU.
+///
+/// QEMU terminates with status `(code << 1) | 1`, so `Success` becomes 33 and `Failed` 35.
+/// Both are chosen to stay clear of QEMU's own exit statuses (0, 1 and 2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Terminates QEMU with the given exit code: through the `isa-debug-exit` device on x86
+/// (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`), through semihosting on AArch64
+/// (`-semihosting`), through the `virt` machine's test finisher on RISC-V.
+///
+/// If the mechanism is not available (real hardware, or QEMU started without it) the request is
+/// ignored, so this falls back to halting the CPU forever.
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    request_exit(code as u32);
+    Current::halt()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn request_exit(code: u32) {
+    /// I/O port of QEMU's `isa-debug-exit` device.
+    const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+    unsafe { crate::os::arch::x86_64::port::outl(ISA_DEBUG_EXIT_PORT, code) };
+}
+
+#[cfg(target_arch = "aarch64")]
+fn request_exit(code: u32) {
+    // SYS_EXIT with an ADP_Stopped_ApplicationExit block; QEMU exits with the subcode, which is
+    // encoded like isa-debug-exit does it so the host sees the same statuses on every architecture
+    const SYS_EXIT: u64 = 0x18;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+    let block = [ADP_STOPPED_APPLICATION_EXIT, ((code as u64) << 1) | 1];
+
+    unsafe {
+        core::arch::asm!("hlt #0xf000", in("x0") SYS_EXIT, in("x1") block.as_ptr(), options(nostack, readonly));
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+fn request_exit(code: u32) {
+    // QEMU `virt`'s SiFive test finisher: FAIL exits QEMU with the status in the upper half,
+    // which again carries the isa-debug-exit encoding
+    const TEST_FINISHER: u64 = 0x10_0000;
+    const FINISHER_FAIL: u32 = 0x3333;
+
+    let value = ((code << 1) | 1) << 16 | FINISHER_FAIL;
+    unsafe { core::ptr::write_volatile(TEST_FINISHER as *mut u32, value) };
+}
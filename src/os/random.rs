@@ -0,0 +1,350 @@
+//! Kernel CSPRNG.
+//!
+//! A ChaCha20 keystream generator with fast key erasure: after every request the generator
+//! rekeys itself from its own output, so a later compromise of its state cannot reveal earlier
+//! output. Entropy from the firmware RNG, the CPU's random number instructions (RDSEED/RDRAND,
+//! RNDR) and counter jitter is folded into the key at boot; [`add_entropy`] lets drivers mix in more later. Consumers that must not run on a weak
+//! state check [`is_seeded`] first.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use uefi::prelude::*;
+use uefi::proto::rng::Rng;
+
+use crate::os::arch::{Arch, Current, counter};
+use crate::os::errno::{Errno, KResult};
+use crate::os::uaccess;
+
+/// Bits of credited entropy required before the generator counts as seeded.
+const SEED_THRESHOLD_BITS: u32 = 256;
+
+// Set once SEED_THRESHOLD_BITS of credited entropy have been mixed in
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+// Total credited entropy so far
+static CREDITED_BITS: AtomicU32 = AtomicU32::new(0);
+
+// Generator state; the initial key is a constant and only becomes secret once seeded
+static mut STATE: ChaCha20 = ChaCha20 { key: [0; 8], counter: 0 };
+
+/// Gathers boot-time entropy from every available source. Must run while boot services are up.
+pub fn init(system_table: &SystemTable<Boot>) {
+    let mut buffer = [0u8; 32];
+
+    // Firmware RNG protocol (backed by the platform's hardware RNG on most machines)
+    if firmware_random(system_table, &mut buffer) {
+        add_entropy(&buffer, 256);
+    }
+
+    // CPU random number instructions; only credited if every word came from the hardware
+    let mut filled = 0;
+    for chunk in buffer.chunks_mut(8) {
+        if let Some(word) = Current::hardware_random() {
+            chunk.copy_from_slice(&word.to_le_bytes());
+            filled += 1;
+        }
+    }
+
+    if filled > 0 {
+        add_entropy(&buffer, if filled == buffer.len() / 8 { 256 } else { 0 });
+    }
+
+    // Counter jitter across firmware stalls: weak, so it is mixed in but never credited
+    for chunk in buffer.chunks_mut(8) {
+        system_table.boot_services().stall(1);
+        chunk.copy_from_slice(&counter::read().to_le_bytes());
+    }
+    add_entropy(&buffer, 0);
+
+    if !is_seeded() {
+        log::warn!("random: no trusted entropy source found, CSPRNG is not seeded");
+    }
+}
+
+fn firmware_random(system_table: &SystemTable<Boot>, buffer: &mut [u8]) -> bool {
+    let bt = system_table.boot_services();
+
+    let Ok(handle) = bt.get_handle_for_protocol::<Rng>() else {
+        return false;
+    };
+
+    let Ok(mut rng) = bt.open_protocol_exclusive::<Rng>(handle) else {
+        return false;
+    };
+
+    rng.get_rng(None, buffer).is_ok()
+}
+
+/// Mixes `data` into the generator key, crediting `bits` of entropy towards seeding.
+pub fn add_entropy(data: &[u8], bits: u32) {
+    unsafe {
+        let state = &raw mut STATE;
+        (*state).mix(data);
+    }
+
+    let total = CREDITED_BITS.fetch_add(bits, Ordering::Relaxed) + bits;
+    if total >= SEED_THRESHOLD_BITS {
+        SEEDED.store(true, Ordering::Release);
+    }
+}
+
+/// Returns `true` once enough trusted entropy has been collected.
+pub fn is_seeded() -> bool {
+    SEEDED.load(Ordering::Acquire)
+}
+
+/// Fills `out` with random bytes. Callers needing unpredictability must check [`is_seeded`].
+pub fn fill_bytes(out: &mut [u8]) {
+    unsafe {
+        let state = &raw mut STATE;
+        (*state).fill(out);
+    }
+}
+
+/// Returns a random `u64`.
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Returns a uniformly distributed value in `0..bound` (`bound` must be non-zero).
+pub fn below(bound: u64) -> u64 {
+    // Reject the top partial range so every residue is equally likely
+    let zone = u64::MAX - (u64::MAX % bound);
+
+    loop {
+        let value = next_u64();
+        if value < zone {
+            return value % bound;
+        }
+    }
+}
+
+// =========================================================================
+// getrandom syscall
+// =========================================================================
+
+/// `getrandom` flag: fail with `EAGAIN` instead of waiting for the generator to be seeded.
+pub const GRND_NONBLOCK: u32 = 0x1;
+
+/// `getrandom` flag: accepted for compatibility; there is no separate blocking pool.
+pub const GRND_RANDOM: u32 = 0x2;
+
+/// `getrandom` flag: return output even if the generator is not seeded yet.
+pub const GRND_INSECURE: u32 = 0x4;
+
+/// Largest request served by one call; bigger requests return a short count, as on Linux.
+const GETRANDOM_MAX: usize = 32 * 1024 * 1024;
+
+/// `getrandom(buf, len, flags)`: fills `len` bytes of user memory at `buf` with CSPRNG output
+/// and returns the number of bytes written.
+///
+/// Until the generator is seeded the call waits (collecting timing jitter until enough entropy
+/// is credited), unless `GRND_NONBLOCK` asks for `EAGAIN` or `GRND_INSECURE` accepts output from
+/// an unseeded generator.
+pub fn sys_getrandom(buf: usize, len: usize, flags: u32) -> KResult<usize> {
+    getrandom(len, flags, |offset, bytes| uaccess::copy_to_user(buf + offset, bytes))
+}
+
+// The body of sys_getrandom, handing each chunk of output to `copy` with its offset into the
+// buffer
+fn getrandom(len: usize, flags: u32, mut copy: impl FnMut(usize, &[u8]) -> KResult<()>) -> KResult<usize> {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    if flags & GRND_INSECURE != 0 && flags & GRND_RANDOM != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    if !is_seeded() && flags & GRND_INSECURE == 0 {
+        if flags & GRND_NONBLOCK != 0 {
+            return Err(Errno::EAGAIN);
+        }
+
+        wait_until_seeded();
+    }
+
+    let len = len.min(GETRANDOM_MAX);
+    let mut chunk = [0u8; 256];
+    let mut written = 0;
+
+    while written < len {
+        let n = (len - written).min(chunk.len());
+        fill_bytes(&mut chunk[..n]);
+
+        let result = copy(written, &chunk[..n]);
+        chunk.fill(0);
+
+        match result {
+            Ok(()) => written += n,
+            // Report what was already delivered; fault only if nothing was
+            Err(err) if written == 0 => return Err(err),
+            Err(_) => break,
+        }
+    }
+
+    Ok(written)
+}
+
+/// Jitter samples folded together per credited bit while waiting for seeding.
+const JITTER_SAMPLES_PER_BIT: usize = 64;
+
+// Blocks until the generator is seeded by harvesting execution-time jitter, crediting it very
+// conservatively. Once the scheduler exists this runs in the context of the waiting process.
+fn wait_until_seeded() {
+    let mut samples = [0u8; JITTER_SAMPLES_PER_BIT * 8];
+
+    while !is_seeded() {
+        for sample in samples.chunks_mut(8) {
+            let start = counter::read();
+
+            // Memory-bound busy work whose duration varies with cache and pipeline state
+            let mut x = start;
+            for _ in 0..(start & 0xff) {
+                x = core::hint::black_box(x.rotate_left(7) ^ 0x9e37_79b9_7f4a_7c15);
+            }
+
+            sample.copy_from_slice(&(counter::read() ^ x).to_le_bytes());
+        }
+
+        add_entropy(&samples, 1);
+    }
+}
+
+// =========================================================================
+// ChaCha20 keystream
+// =========================================================================
+
+struct ChaCha20 {
+    key: [u32; 8],
+    counter: u64,
+}
+
+impl ChaCha20 {
+    fn fill(&mut self, out: &mut [u8]) {
+        for chunk in out.chunks_mut(64) {
+            let block = self.block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+
+        self.rekey();
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, bytes) in chunk.chunks(4).enumerate() {
+                let mut word = [0u8; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                self.key[i] ^= u32::from_le_bytes(word);
+            }
+
+            self.rekey();
+        }
+    }
+
+    // Fast key erasure: replace the key with fresh keystream so old output cannot be recomputed
+    fn rekey(&mut self) {
+        let block = self.block();
+
+        for (i, word) in block[..32].chunks(4).enumerate() {
+            self.key[i] = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        }
+    }
+
+    fn block(&mut self) -> [u8; 64] {
+        const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+        let mut input = [0u32; 16];
+        input[..4].copy_from_slice(&SIGMA);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut x = input;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+
+        let mut out = [0u8; 64];
+        for i in 0..16 {
+            out[i * 4..i * 4 + 4].copy_from_slice(&x[i].wrapping_add(input[i]).to_le_bytes());
+        }
+
+        out
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Runs `f` with the generator taken for unseeded
+    fn unseeded<R>(f: impl FnOnce() -> R) -> R {
+        let seeded = SEEDED.swap(false, Ordering::AcqRel);
+        let result = f();
+        SEEDED.store(seeded, Ordering::Release);
+        result
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn getrandom_checks_its_flags() {
+            let mut buf = [0u8; 16];
+            let addr = buf.as_mut_ptr() as usize;
+
+            assert_eq!(sys_getrandom(addr, buf.len(), 0x8), Err(Errno::EINVAL));
+            assert_eq!(sys_getrandom(addr, buf.len(), GRND_INSECURE | GRND_RANDOM), Err(Errno::EINVAL));
+            assert_eq!(sys_getrandom(addr, buf.len(), GRND_RANDOM), Ok(buf.len()));
+            assert_ne!(buf, [0; 16]);
+        }
+
+        fn unseeded_generator_fails_nonblocking_calls() {
+            let mut buf = [0u8; 16];
+            let addr = buf.as_mut_ptr() as usize;
+
+            assert_eq!(unseeded(|| sys_getrandom(addr, buf.len(), GRND_NONBLOCK)), Err(Errno::EAGAIN));
+            assert_eq!(unseeded(|| sys_getrandom(addr, buf.len(), GRND_NONBLOCK | GRND_INSECURE)), Ok(buf.len()));
+        }
+
+        fn faults_after_the_first_chunk_give_a_short_count() {
+            let fault_at = |limit: usize| move |offset: usize, _: &[u8]| if offset < limit { Ok(()) } else { Err(Errno::EFAULT) };
+
+            assert_eq!(getrandom(1000, 0, fault_at(512)), Ok(512));
+            assert_eq!(getrandom(1000, 0, fault_at(0)), Err(Errno::EFAULT));
+            assert_eq!(sys_getrandom(0xffff_8000_0000_0000, 16, 0), Err(Errno::EFAULT));
+        }
+
+        // Generates the whole GETRANDOM_MAX bytes
+        #[timeout(5000)]
+        fn requests_are_capped() {
+            let mut delivered = 0;
+            let count = getrandom(usize::MAX, 0, |_, bytes| {
+                delivered += bytes.len();
+                Ok(())
+            });
+
+            assert_eq!(count, Ok(GETRANDOM_MAX));
+            assert_eq!(delivered, GETRANDOM_MAX);
+        }
+    }
+}
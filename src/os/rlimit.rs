@@ -0,0 +1,244 @@
+use crate::os::capability::{self, Capability};
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory;
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::uaccess;
+
+/// Value of an unlimited limit.
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Timer ticks per second, used to convert `RLIMIT_CPU` (seconds) to the PCB's tick counts.
+const TICKS_PER_SECOND: u64 = 1000;
+
+/// Signal sent when the soft CPU limit is exceeded (`SIGXCPU`).
+const SIGXCPU: u32 = 24;
+
+/// Signal sent when the hard CPU limit is exceeded (`SIGKILL`).
+const SIGKILL: u32 = 9;
+
+/// Limited resources, numbered as on Linux so `getrlimit`/`setrlimit` callers can use the usual
+/// constants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Resource {
+    /// `RLIMIT_CPU`: CPU time in seconds.
+    Cpu = 0,
+
+    /// `RLIMIT_STACK`: stack size in bytes.
+    Stack = 3,
+
+    /// `RLIMIT_NOFILE`: one more than the highest file descriptor number.
+    NoFile = 7,
+
+    /// `RLIMIT_AS`: total virtual address space in bytes.
+    AddressSpace = 9,
+}
+
+impl Resource {
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(Resource::Cpu),
+            3 => Some(Resource::Stack),
+            7 => Some(Resource::NoFile),
+            9 => Some(Resource::AddressSpace),
+            _ => None,
+        }
+    }
+}
+
+/// Number of slots in [`Rlimits`] (highest resource number plus one).
+const RLIM_NLIMITS: usize = 10;
+
+/// A soft (`cur`) and hard (`max`) limit, laid out like `struct rlimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Rlimit {
+    /// Limit actually enforced. May be raised up to `max` by anyone.
+    pub cur: u64,
+
+    /// Ceiling for `cur`. Only raised with `CAP_SYS_RESOURCE`.
+    pub max: u64,
+}
+
+impl Rlimit {
+    pub const UNLIMITED: Rlimit = Rlimit { cur: RLIM_INFINITY, max: RLIM_INFINITY };
+}
+
+/// The resource limits of one process. Inherited across fork and kept across exec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimits([Rlimit; RLIM_NLIMITS]);
+
+impl Rlimits {
+    /// Limits given to the first process: 8 MiB of stack and 64 file descriptors (the size of the
+    /// descriptor table), everything else unlimited.
+    pub const DEFAULT: Rlimits = {
+        let mut limits = [Rlimit::UNLIMITED; RLIM_NLIMITS];
+        limits[Resource::Stack as usize] = Rlimit { cur: 8 * 1024 * 1024, max: RLIM_INFINITY };
+        limits[Resource::NoFile as usize] = Rlimit { cur: 64, max: 64 };
+        Rlimits(limits)
+    };
+
+    pub fn get(&self, resource: Resource) -> Rlimit {
+        self.0[resource as usize]
+    }
+
+    /// Soft limit of `resource`.
+    pub fn cur(&self, resource: Resource) -> u64 {
+        self.get(resource).cur
+    }
+}
+
+// =========================================================================
+// Enforcement
+// =========================================================================
+
+/// Checks whether `process` may grow its address space by `additional` bytes (mmap, brk),
+/// against `RLIMIT_AS` and the system's overcommit policy.
+pub fn check_address_space(process: &Process, additional: usize) -> KResult<()> {
+    let total = process.memory_usage() as u64 + additional as u64;
+
+    if total > process.rlimits.cur(Resource::AddressSpace) {
+        return Err(Errno::ENOMEM);
+    }
+
+    memory::check_commit(additional as u64)
+}
+
+/// Checks whether the stack of `process` may grow to `new_size` bytes.
+pub fn check_stack(process: &Process, new_size: usize) -> KResult<()> {
+    if new_size as u64 > process.rlimits.cur(Resource::Stack) {
+        return Err(Errno::ENOMEM);
+    }
+
+    check_address_space(process, new_size.saturating_sub(process.stack_size))
+}
+
+/// Checks whether descriptor number `fd` is allowed for `process`.
+pub fn check_fd(process: &Process, fd: usize) -> KResult<()> {
+    if fd as u64 >= process.rlimits.cur(Resource::NoFile) {
+        Err(Errno::EMFILE)
+    } else {
+        Ok(())
+    }
+}
+
+/// Scheduler tick hook, called after `cpu_time` was charged. Raises `SIGXCPU` once per second
+/// past the soft limit and `SIGKILL` at the hard limit.
+pub fn on_cpu_tick(process: &mut Process) {
+    let limit = process.rlimits.get(Resource::Cpu);
+    let seconds = process.cpu_time / TICKS_PER_SECOND;
+
+    if limit.max != RLIM_INFINITY && seconds >= limit.max {
+        process.signal_bitmap |= 1 << SIGKILL;
+    } else if limit.cur != RLIM_INFINITY && seconds >= limit.cur && process.cpu_time.is_multiple_of(TICKS_PER_SECOND) {
+        process.signal_bitmap |= 1 << SIGXCPU;
+    }
+}
+
+// =========================================================================
+// getrlimit / setrlimit
+// =========================================================================
+
+/// `getrlimit(resource, rlim)`: copies the caller `caller`'s limit to the `struct rlimit` at
+/// `rlim`.
+pub fn sys_getrlimit(caller: u64, resource: u32, rlim: usize) -> KResult<()> {
+    let resource = Resource::from_raw(resource).ok_or(Errno::EINVAL)?;
+    let limit = ptable::with_process(caller, |process| process.rlimits.get(resource)).ok_or(Errno::ESRCH)?;
+    uaccess::write_user(rlim, &limit)
+}
+
+/// `setrlimit(resource, rlim)`: sets the caller `caller`'s limit from the `struct rlimit` at
+/// `rlim`.
+pub fn sys_setrlimit(caller: u64, resource: u32, rlim: usize) -> KResult<()> {
+    let resource = Resource::from_raw(resource).ok_or(Errno::EINVAL)?;
+    let new: Rlimit = unsafe { uaccess::read_user(rlim)? };
+
+    ptable::with_process(caller, |process| setrlimit(process, resource, new)).unwrap_or(Err(Errno::ESRCH))
+}
+
+/// Replaces a limit. The soft limit may not exceed the hard one, and raising the hard limit
+/// requires `CAP_SYS_RESOURCE`.
+pub fn setrlimit(process: &mut Process, resource: Resource, new: Rlimit) -> KResult<()> {
+    if new.cur > new.max {
+        return Err(Errno::EINVAL);
+    }
+
+    // The descriptor table has a fixed size, so no limit can go past it
+    if resource == Resource::NoFile && new.max > process.file_descriptors.len() as u64 {
+        return Err(Errno::EPERM);
+    }
+
+    if new.max > process.rlimits.get(resource).max {
+        capability::require(&process.cred, Capability::SysResource)?;
+    }
+
+    process.rlimits.0[resource as usize] = new;
+    Ok(())
+}
+
+pub mod ktests {
+    use super::*;
+    use crate::os::cred::Credentials;
+
+    fn process(uid: u32) -> Process {
+        let mut process = Process::new(9730, 0, "rlimit");
+        process.cred = Credentials::new(uid, uid);
+        process
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn soft_limits_stay_under_hard_ones() {
+            let mut process = process(1000);
+
+            assert_eq!(setrlimit(&mut process, Resource::Stack, Rlimit { cur: 2, max: 1 }), Err(Errno::EINVAL));
+            assert_eq!(setrlimit(&mut process, Resource::Stack, Rlimit { cur: 1 << 20, max: 1 << 20 }), Ok(()));
+            assert_eq!(process.rlimits.get(Resource::Stack), Rlimit { cur: 1 << 20, max: 1 << 20 });
+
+            // The soft limit moves freely below the hard one
+            assert_eq!(setrlimit(&mut process, Resource::Stack, Rlimit { cur: 4096, max: 1 << 20 }), Ok(()));
+            assert_eq!(setrlimit(&mut process, Resource::Stack, Rlimit { cur: 1 << 20, max: 1 << 20 }), Ok(()));
+        }
+
+        fn raising_a_hard_limit_needs_sys_resource() {
+            let mut user = process(1000);
+            setrlimit(&mut user, Resource::Cpu, Rlimit { cur: 10, max: 10 }).unwrap();
+            assert_eq!(setrlimit(&mut user, Resource::Cpu, Rlimit { cur: 10, max: 20 }), Err(Errno::EPERM));
+            assert_eq!(user.rlimits.get(Resource::Cpu).max, 10);
+
+            let mut root = process(0);
+            setrlimit(&mut root, Resource::Cpu, Rlimit { cur: 10, max: 10 }).unwrap();
+            assert_eq!(setrlimit(&mut root, Resource::Cpu, Rlimit { cur: 10, max: 20 }), Ok(()));
+
+            // Not even root gets more descriptors than the table holds
+            let slots = root.file_descriptors.len() as u64;
+            assert_eq!(setrlimit(&mut root, Resource::NoFile, Rlimit { cur: slots, max: slots + 1 }), Err(Errno::EPERM));
+        }
+
+        fn address_space_grows_up_to_the_soft_limit() {
+            let mut process = process(1000);
+            let usage = process.memory_usage() as u64;
+            setrlimit(&mut process, Resource::AddressSpace, Rlimit { cur: usage + 0x2000, max: RLIM_INFINITY }).unwrap();
+
+            assert_eq!(check_address_space(&process, 0x2000), Ok(()));
+            assert_eq!(check_address_space(&process, 0x3000), Err(Errno::ENOMEM));
+            assert_eq!(check_stack(&process, process.stack_size + 0x3000), Err(Errno::ENOMEM));
+        }
+
+        fn syscalls_check_the_resource() {
+            ptable::insert(process(1000)).unwrap();
+            let mut limit = Rlimit { cur: 0, max: 0 };
+            let addr = &raw mut limit as usize;
+
+            assert_eq!(sys_getrlimit(9730, 1, addr), Err(Errno::EINVAL));
+            sys_getrlimit(9730, Resource::NoFile as u32, addr).unwrap();
+            assert_eq!(limit, Rlimits::DEFAULT.get(Resource::NoFile));
+
+            limit.cur = 16;
+            sys_setrlimit(9730, Resource::NoFile as u32, addr).unwrap();
+            let process = ptable::remove(9730).unwrap();
+            assert_eq!(check_fd(&process, 15), Ok(()));
+            assert_eq!(check_fd(&process, 16), Err(Errno::EMFILE));
+        }
+    }
+}
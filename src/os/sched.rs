@@ -0,0 +1,844 @@
+//! The scheduler: priority levels, each served round robin, over the processes admitted to
+//! each CPU's ready queue.
+//!
+//! A process joins a queue with [`admit`], which moves it from `New` to `Ready`, and stays
+//! on it until it terminates or the load balancer moves it. [`schedule`] runs the `Ready`
+//! process with the best priority (0 is the best, [`LOWEST_PRIORITY`] the worst), the one
+//! nearest the front of the running CPU's queue among equals, and moves it to the back, so
+//! every runnable process on a level gets a turn before any gets a second one. The running
+//! process keeps the CPU while nothing waiting beats it. So that a stream of urgent work
+//! cannot starve the rest, waiting ages a process: its priority counts one level better for
+//! every [`AGING_TICKS`] ticks it has been `Ready` without running, until it runs again.
+//!
+//! Processes that block are skipped until something wakes them (sets them `Ready` again),
+//! wherever that happens; terminated ones are dropped. Kernel code waits with [`block_on`]
+//! and wakes the waiters on a [`WaitTarget`] with [`wake`] or [`wake_one`], which also
+//! reschedule where a woken process beats the one running. When nothing else is runnable the
+//! running process carries on, or the CPU falls back to its idle process, which is never
+//! queued: [`IDLE_PID`], the boot context, on the boot CPU, and on the others the context
+//! they started in.
+//!
+//! New processes go to the CPU with the fewest runnable ones. Every few ticks each CPU
+//! compares its queue with the least loaded one and, if it has at least [`IMBALANCE`] more,
+//! hands one of its `Ready` processes over and sends that CPU a reschedule IPI. Only a
+//! queue's own CPU takes processes off it: every `Ready` process on it but the running one
+//! has had its context saved by an earlier switch there, so none is moved half switched.
+//!
+//! The state transitions follow the PCB: `Ready -> Running` when picked, `Running -> Ready`
+//! when preempted or yielding, `Running -> Blocked` through [`block_current`]. Every timer tick
+//! charges the running process with [`tick`]; once its timeslice (from
+//! [`cgroup::timeslice`]) is used up a reschedule is requested, and carried out on the way out
+//! of the interrupt. The tick timer fires on one CPU, which passes the tick on to the others
+//! ([`ipi::send_tick`]). Kernel code can also give up the CPU itself with [`yield_now`], or
+//! for a while with [`sleep_ticks`].
+//!
+//! Switching runs on kernel contexts: a process that is not running has the callee-saved
+//! registers, stack pointer and resume address of its kernel context saved in its `regs`, and
+//! [`context_switch`] saves the outgoing one there and resumes the incoming one. A process
+//! only gets a context to resume when something builds one with [`init_context`], as
+//! [`kthread::spawn_kthread`] does; the boot context gets its own the first time it is
+//! switched out. The incoming process's address space is loaded with its context, the
+//! kernel's for processes that have none.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::os::arch::{self, Arch, ContextEntry, Current};
+use crate::os::capability::{self, Capability};
+use crate::os::cgroup;
+use crate::os::deadlock;
+use crate::os::errno::{Errno, KResult};
+use crate::os::hrtimer;
+use crate::os::ipi;
+use crate::os::kthread;
+use crate::os::percpu::{self, MAX_CPUS, PerCpu};
+use crate::os::pidns;
+use crate::os::process::{Process, ProcessState, WaitTarget};
+use crate::os::ptable::{self, MAX_PROCESSES};
+use crate::os::rlimit;
+use crate::os::timekeeping;
+use crate::os::timer::{self, TICK_NS};
+use crate::os::tlb;
+
+/// The process the boot CPU runs when nothing else is runnable: the boot context.
+pub const IDLE_PID: u64 = 0;
+
+/// The worst scheduling priority; priorities above it schedule as it.
+pub const LOWEST_PRIORITY: u8 = 31;
+
+/// Ticks a `Ready` process waits for each level its priority improves by.
+pub const AGING_TICKS: u32 = 20;
+
+/// How many more runnable processes a CPU must have than the least loaded one before the
+/// balancer moves one.
+pub const IMBALANCE: usize = 2;
+
+/// `which` for [`sys_setpriority`]: `who` names a process.
+pub const PRIO_PROCESS: u32 = 0;
+
+// Ticks between a CPU's load balancing passes
+const BALANCE_TICKS: u32 = 4;
+
+type Context = <Current as Arch>::Context;
+
+// The saved kernel context lives in the PCB's register save area
+const _: () = assert!(size_of::<Context>() <= size_of::<[u64; 32]>() && align_of::<Context>() <= align_of::<u64>());
+
+fn saved_context(process: &mut Process) -> *mut Context {
+    process.regs.as_mut_ptr().cast()
+}
+
+// Admitted processes in round-robin order, the next to consider first, and the ticks each
+// has waited `Ready` since it last ran
+struct Queue {
+    pids: [u64; MAX_PROCESSES],
+    waited: [u32; MAX_PROCESSES],
+    len: usize,
+}
+
+impl Queue {
+    fn remove(&mut self, index: usize) -> u64 {
+        let pid = self.pids[index];
+        self.pids.copy_within(index + 1..self.len, index);
+        self.waited.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        pid
+    }
+
+    fn push(&mut self, pid: u64) {
+        self.pids[self.len] = pid;
+        self.waited[self.len] = 0;
+        self.len += 1;
+    }
+}
+
+/// The priority `priority` counts as after waiting `waited` ticks.
+pub fn effective_priority(priority: u8, waited: u32) -> u8 {
+    let boost = (waited / AGING_TICKS).min(LOWEST_PRIORITY as u32) as u8;
+    priority.min(LOWEST_PRIORITY).saturating_sub(boost)
+}
+
+/// A ready queue and the priority policy over it.
+pub struct RunQueue {
+    lock: AtomicBool,
+    queue: UnsafeCell<Queue>,
+}
+
+// The queue is only touched under the lock
+unsafe impl Sync for RunQueue {}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        RunQueue {
+            lock: AtomicBool::new(false),
+            queue: UnsafeCell::new(Queue { pids: [0; MAX_PROCESSES], waited: [0; MAX_PROCESSES], len: 0 }),
+        }
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut Queue) -> R) -> R {
+        arch::without_interrupts(|| {
+            while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                core::hint::spin_loop();
+            }
+
+            let result = unsafe { f(&mut *self.queue.get()) };
+            self.lock.store(false, Ordering::Release);
+            result
+        })
+    }
+
+    /// Makes the `New` process `pid` `Ready` and puts it at the back of the queue. Fails with
+    /// `ESRCH` if there is no such process and `EINVAL` if it is not `New`.
+    pub fn admit(&self, pid: u64) -> KResult<()> {
+        self.locked(|queue| {
+            ptable::with_process(pid, |process| {
+                if process.state != ProcessState::New {
+                    return Err(Errno::EINVAL);
+                }
+
+                process.state = ProcessState::Ready;
+                Ok(())
+            })
+            .ok_or(Errno::ESRCH)??;
+
+            queue.push(pid);
+            Ok(())
+        })
+    }
+
+    /// Picks the process to run after `current`, the waiting one with the best aged priority
+    /// unless `current` is better still, and makes the state transitions for the switch,
+    /// returning its PID (`current` itself if it keeps the CPU, this CPU's idle process if
+    /// nothing can run).
+    pub fn pick_next(&self, current: u64) -> u64 {
+        self.locked(|queue| {
+            // Forget processes that terminated or were reaped
+            let mut index = 0;
+            while index < queue.len {
+                if state(queue.pids[index]).is_none_or(|state| state == ProcessState::Terminated) {
+                    queue.remove(index);
+                } else {
+                    index += 1;
+                }
+            }
+
+            // The best aged priority waiting, the front-most on a tie
+            let next = (0..queue.len)
+                .filter_map(|index| {
+                    let pid = queue.pids[index];
+                    let (state, priority) = ptable::with_process(pid, |process| (process.state, process.priority))?;
+                    (pid != current && state == ProcessState::Ready)
+                        .then(|| (effective_priority(priority, queue.waited[index]), index))
+                })
+                .min();
+
+            // The running process carries on unless something waiting is at least as good
+            let keeps = match ptable::with_process(current, |process| (process.state, process.priority)) {
+                Some((ProcessState::Running, priority)) if !is_idle(current) => {
+                    next.is_none_or(|(best, _)| effective_priority(priority, 0) < best)
+                }
+                _ => false,
+            };
+            if keeps {
+                ptable::with_process(current, |process| process.timeslice = cgroup::timeslice(process));
+                return current;
+            }
+
+            let Some((_, index)) = next else {
+                return idle_pid();
+            };
+
+            let pid = queue.remove(index);
+            queue.push(pid);
+
+            if !is_idle(current) {
+                ptable::with_process(current, |process| {
+                    if process.state == ProcessState::Running {
+                        process.state = ProcessState::Ready;
+                    }
+                });
+            }
+            ptable::with_process(pid, |process| {
+                process.state = ProcessState::Running;
+                process.timeslice = cgroup::timeslice(process);
+            });
+
+            pid
+        })
+    }
+
+    /// Number of processes on the queue, whatever their state.
+    pub fn len(&self) -> usize {
+        self.locked(|queue| queue.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of processes on the queue that are running or ready to.
+    pub fn load(&self) -> usize {
+        self.locked(|queue| {
+            queue.pids[..queue.len]
+                .iter()
+                .filter(|&&pid| matches!(state(pid), Some(ProcessState::Ready | ProcessState::Running)))
+                .count()
+        })
+    }
+
+    // Takes the `Ready` process nearest the back of the queue other than `current` off it
+    fn take_ready(&self, current: u64) -> Option<u64> {
+        self.locked(|queue| {
+            let index = (0..queue.len).rev().find(|&index| {
+                let pid = queue.pids[index];
+                pid != current && state(pid) == Some(ProcessState::Ready)
+            })?;
+            Some(queue.remove(index))
+        })
+    }
+
+    // Puts `pid`, `Ready` and taken off another queue, at the back
+    fn adopt(&self, pid: u64) {
+        self.locked(|queue| queue.push(pid));
+    }
+
+    /// Whether `pid` is on the queue.
+    pub fn contains(&self, pid: u64) -> bool {
+        self.locked(|queue| queue.pids[..queue.len].contains(&pid))
+    }
+
+    /// Charges a tick of waiting to every `Ready` process on the queue, aging its priority.
+    pub fn age(&self) {
+        self.locked(|queue| {
+            for index in 0..queue.len {
+                if state(queue.pids[index]) == Some(ProcessState::Ready) {
+                    queue.waited[index] = queue.waited[index].saturating_add(1);
+                }
+            }
+        })
+    }
+}
+
+fn state(pid: u64) -> Option<ProcessState> {
+    ptable::with_process(pid, |process| process.state)
+}
+
+// Each CPU's ready queue
+static RUN_QUEUES: [RunQueue; MAX_CPUS] = [const { RunQueue::new() }; MAX_CPUS];
+
+// Each CPU's idle process
+static IDLE: PerCpu<u64> = PerCpu::new(IDLE_PID);
+
+// Ticks since each CPU last balanced
+static SINCE_BALANCE: PerCpu<u32> = PerCpu::new(0);
+
+// Ticks the boot CPU has taken
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The ready queue of CPU `cpu`.
+pub fn run_queue(cpu: usize) -> &'static RunQueue {
+    &RUN_QUEUES[cpu]
+}
+
+/// PID of this CPU's idle process.
+pub fn idle_pid() -> u64 {
+    IDLE.with(|pid| *pid)
+}
+
+/// Whether `pid` is this CPU's idle process, which runs when nothing else can and so must
+/// never block.
+pub fn is_idle(pid: u64) -> bool {
+    pid == idle_pid()
+}
+
+/// Makes `pid`, the process running the calling code, this CPU's idle process. Called once by
+/// each application processor as it comes up.
+pub fn init_cpu(pid: u64) {
+    IDLE.with(|idle| *idle = pid);
+    percpu::set_current_pid(pid);
+}
+
+// The online CPU with the fewest runnable processes, this one on a tie
+fn least_loaded() -> usize {
+    let this = percpu::cpu_id();
+    let online = percpu::online_mask();
+
+    (0..MAX_CPUS)
+        .filter(|&cpu| online & (1 << cpu) != 0)
+        .map(|cpu| (RUN_QUEUES[cpu].load(), cpu != this, cpu))
+        .min()
+        .map_or(this, |(_, _, cpu)| cpu)
+}
+
+/// Admits the `New` process `pid` to the ready queue of the least loaded CPU, as
+/// [`RunQueue::admit`] does, and has that CPU reschedule if it is another. Only processes
+/// with a kernel context to resume are admitted.
+pub fn admit(pid: u64) -> KResult<()> {
+    let cpu = least_loaded();
+    RUN_QUEUES[cpu].admit(pid)?;
+
+    if cpu != percpu::cpu_id() {
+        ipi::send_reschedule(cpu);
+    }
+    Ok(())
+}
+
+// Moves a `Ready` process from this CPU's queue to the least loaded CPU's, if this one has
+// IMBALANCE more runnable processes
+fn balance() {
+    let this = percpu::cpu_id();
+    let target = least_loaded();
+    if target == this || RUN_QUEUES[this].load() < RUN_QUEUES[target].load() + IMBALANCE {
+        return;
+    }
+
+    if let Some(pid) = RUN_QUEUES[this].take_ready(percpu::current_pid()) {
+        RUN_QUEUES[target].adopt(pid);
+        ipi::send_reschedule(target);
+    }
+}
+
+/// Runs the next process on the ready queue, or carries on with the current one if nothing
+/// else is runnable. Returns once the calling process is picked again.
+pub fn schedule() {
+    // A resumed context restores its own interrupt state on the way out
+    arch::without_interrupts(|| {
+        let prev = percpu::current_pid();
+        let next = RUN_QUEUES[percpu::cpu_id()].pick_next(prev);
+
+        if next != prev {
+            switch_to(prev, next);
+        }
+    });
+}
+
+/// Gives `process` a kernel context that runs `entry(arg)` on its kernel stack the first time
+/// it is switched to.
+pub fn init_context(process: &mut Process, entry: ContextEntry, arg: usize) {
+    let context = Current::new_context(process.kernel_stack_top(), entry, arg);
+    unsafe { saved_context(process).write(context) };
+}
+
+/// Switches this CPU from the kernel context saved at `prev` to the one at `next`, both in the
+/// `regs` of their PCBs. Returns once something switches back to `prev`.
+///
+/// # Safety
+/// Interrupts must be masked. `next` must hold a context saved by an earlier switch or built
+/// by [`init_context`], and both PCBs must stay where they are until `prev` is resumed.
+pub unsafe fn context_switch(prev: *mut Context, next: *const Context) {
+    unsafe { Current::switch_context(prev, next) };
+}
+
+// Makes `next` the process running on this CPU and switches to it: charges `prev` the ticks
+// it ran since its `last_scheduled` and not charged yet, and stamps `next`'s. Called with
+// interrupts masked; returns once `prev` runs again.
+fn switch_to(prev: u64, next: u64) {
+    let now = timer::current_tick();
+    let next_pcb = ptable::with_process(next, |process| {
+        process.last_scheduled = now;
+        (process.kernel_stack_top(), process.page_table_root, saved_context(process))
+    });
+    let Some((stack_top, root, next_context)) = next_pcb else {
+        return;
+    };
+
+    percpu::set_current_pid(next);
+    percpu::set_kernel_stack_top(stack_top);
+
+    // Processes without an address space of their own run on the kernel's
+    let root = match root {
+        0 => tlb::kernel_root(),
+        root => root as u64,
+    };
+    if root != 0 {
+        unsafe { tlb::switch_to(root) };
+    }
+    percpu::STATS.with(|stats| stats.context_switches += 1);
+
+    let prev_context = ptable::with_process(prev, |process| {
+        process.cpu_time += now.saturating_sub(process.last_scheduled);
+        saved_context(process)
+    });
+    unsafe {
+        match prev_context {
+            Some(prev_context) => context_switch(prev_context, next_context),
+
+            // `prev` exited and was reaped: nothing will resume it
+            None => context_switch(&mut Context::default(), next_context),
+        }
+    }
+
+    // `prev` runs again, and whatever ran last on this CPU is off its stack
+    kthread::reclaim_stack();
+}
+
+/// Gives up the CPU to the next runnable process, if there is one. Called by kernel threads.
+pub fn yield_now() {
+    schedule();
+}
+
+/// Blocks the running process on `target` and runs something else until it is woken.
+pub fn block_current(target: WaitTarget) {
+    ptable::with_process(percpu::current_pid(), |process| {
+        process.waiting_on = Some(target);
+        process.state = ProcessState::Blocked;
+    });
+    schedule();
+}
+
+/// Blocks the running process on `target` as [`block_current`] does, unless `ready` holds once
+/// it is marked blocked, which catches a wakeup that came just before. Returns whether `ready`
+/// held, in which case the process never gave up the CPU.
+pub fn block_current_unless(target: WaitTarget, ready: &mut dyn FnMut() -> bool) -> bool {
+    let pid = percpu::current_pid();
+    ptable::with_process(pid, |process| {
+        process.waiting_on = Some(target);
+        process.state = ProcessState::Blocked;
+    });
+
+    if ready() {
+        ptable::with_process(pid, |process| process.wake());
+        return true;
+    }
+
+    schedule();
+    false
+}
+
+/// Waits until `ready` holds, blocking the running process on `target` between checks; the
+/// waker is expected to [`wake`] `target` once `ready` may hold. An idle process, which
+/// cannot block, spins instead, as does one running with interrupts masked.
+pub fn block_on(target: WaitTarget, mut ready: impl FnMut() -> bool) {
+    let pid = percpu::current_pid();
+    while !ready() {
+        if is_idle(pid) || !Current::interrupts_enabled() {
+            core::hint::spin_loop();
+        } else if block_current_unless(target, &mut ready) {
+            return;
+        }
+    }
+}
+
+/// Wakes every process waiting on `target` and returns how many there were. Safe to call with
+/// the process table locked, as [`ptable::wake`] explains.
+pub fn wake(target: WaitTarget) -> usize {
+    wake_where(target, usize::MAX)
+}
+
+/// Wakes the first process (in process table order) waiting on `target`, returning whether
+/// there was one.
+pub fn wake_one(target: WaitTarget) -> bool {
+    wake_where(target, 1) == 1
+}
+
+// Wakes up to `limit` processes waiting on `target` and has their CPUs reschedule if they
+// may beat what runs there
+fn wake_where(target: WaitTarget, limit: usize) -> usize {
+    let mut woken = [(0, 0); MAX_PROCESSES];
+    let count = ptable::wake(target, limit, |index, process| woken[index] = (process.pid, process.priority));
+
+    woken[..count].iter().for_each(|&(pid, priority)| preempt_for(pid, priority));
+    count
+}
+
+// Asks the CPU whose queue has `pid`, just woken, to reschedule if the process may run
+// before the one running there. Only this CPU's running process is known here, so another
+// CPU always reschedules and leaves the decision to its scheduler.
+fn preempt_for(pid: u64, priority: u8) {
+    let this = percpu::cpu_id();
+    let online = percpu::online_mask();
+    let owner = (0..MAX_CPUS).filter(|&cpu| online & (1 << cpu) != 0).find(|&cpu| RUN_QUEUES[cpu].contains(pid));
+    let Some(cpu) = owner else {
+        return;
+    };
+
+    if cpu != this {
+        ipi::send_reschedule(cpu);
+        return;
+    }
+
+    let current = percpu::current_pid();
+    let running = ptable::with_process(current, |process| process.priority).unwrap_or(LOWEST_PRIORITY);
+    if is_idle(current) || effective_priority(priority, 0) <= effective_priority(running, 0) {
+        ipi::send_reschedule(cpu);
+    }
+}
+
+/// Sleeps for `ticks` scheduler ticks, for kernel code, running other processes meanwhile. With
+/// nothing else to run, or in the boot context, the CPU waits for interrupts until the sleep's
+/// timer ends it. Called with interrupts enabled.
+pub fn sleep_ticks(ticks: u64) {
+    let pid = percpu::current_pid();
+    let deadline = timekeeping::monotonic_ns().saturating_add(ticks.saturating_mul(TICK_NS));
+
+    let slept = ptable::with_process(pid, |process| hrtimer::sleep_until(process, deadline, 0));
+    if slept != Some(Ok(())) {
+        // No timer free: still give the CPU away until the deadline
+        while timekeeping::monotonic_ns() < deadline {
+            schedule();
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    wait_out_sleep(pid);
+}
+
+/// Runs other processes until the sleep [`hrtimer::sleep_until`] put `pid`, the calling
+/// process, to is over, waiting for interrupts when there is nothing else to run. Returns at
+/// once if it is not asleep. Called with interrupts enabled.
+pub fn wait_out_sleep(pid: u64) {
+    let sleeping = || ptable::with_process(pid, |process| process.sleep.is_some()) == Some(true);
+    while sleeping() {
+        schedule();
+
+        // Back without the timer having fired: nothing else could run
+        Current::disable_interrupts();
+        if sleeping() {
+            Current::wait_for_interrupt();
+        } else {
+            Current::enable_interrupts();
+        }
+    }
+
+    // The scheduler never picks an idle process, so it is not made running again otherwise
+    if is_idle(pid) {
+        ptable::with_process(pid, |process| process.state = ProcessState::Running);
+    }
+}
+
+/// Charges one tick to `process`, which is running, and returns whether its timeslice is used
+/// up. The tick is not charged again when the process is switched out.
+pub fn charge_tick(process: &mut Process) -> bool {
+    process.cpu_time += 1;
+    process.last_scheduled += 1;
+    cgroup::on_cpu_tick(process);
+    rlimit::on_cpu_tick(process);
+
+    process.timeslice = process.timeslice.saturating_sub(1);
+    process.timeslice == 0
+}
+
+/// Timer tick: charges the running process, ages the waiting ones and asks for a reschedule
+/// once the timeslice is used up. Every few ticks it also balances the load.
+pub fn tick() {
+    // The boot CPU takes every tick, so its count paces the periodic deadlock scan
+    if percpu::cpu_id() == 0 {
+        deadlock::on_tick(TICKS.fetch_add(1, Ordering::Relaxed) + 1);
+    }
+
+    if ptable::with_process(percpu::current_pid(), charge_tick) == Some(true) {
+        ipi::send_reschedule(percpu::cpu_id());
+    }
+    RUN_QUEUES[percpu::cpu_id()].age();
+
+    let balance_due = SINCE_BALANCE.with(|ticks| {
+        *ticks = (*ticks + 1) % BALANCE_TICKS;
+        *ticks == 0
+    });
+    if balance_due {
+        balance();
+    }
+}
+
+/// Preemption point, on the way out of an interrupt: runs the scheduler if a reschedule was
+/// requested.
+pub fn preempt() {
+    if ipi::take_need_resched() {
+        schedule();
+    }
+}
+
+// Periodic timer driving `tick`, here and on the other CPUs
+fn tick_timer(_id: hrtimer::HrTimerId, _data: usize, expirations: u64) {
+    (0..expirations).for_each(|_| tick());
+    ipi::send_tick(percpu::online_mask() & !(1 << percpu::cpu_id()));
+}
+
+/// `setpriority(which, who, priority)` with `which` [`PRIO_PROCESS`]: sets the scheduling
+/// priority of process `who`, a PID in the caller's namespace (the caller `caller` if 0), on
+/// the kernel's scale, 0 to [`LOWEST_PRIORITY`], rather than as a nice value. Changing another
+/// user's process and raising a priority both take `CAP_SYS_NICE`. Fails with `EINVAL` for
+/// another `which` or a priority out of range, `ESRCH` if there is no such process the caller
+/// can see and `EPERM` without the privilege.
+pub fn sys_setpriority(caller: u64, which: u32, who: u64, priority: u32) -> KResult<()> {
+    if which != PRIO_PROCESS || priority > LOWEST_PRIORITY as u32 {
+        return Err(Errno::EINVAL);
+    }
+    let priority = priority as u8;
+
+    let (cred, ns) = ptable::with_process(caller, |process| (process.cred, process.pid_links.namespace())).ok_or(Errno::ESRCH)?;
+    let who = match who {
+        0 => caller,
+        who => pidns::to_global(ns, who).ok_or(Errno::ESRCH)?,
+    };
+
+    ptable::with_process(who, |target| {
+        let owner = who == caller || [target.cred.uid, target.cred.euid].contains(&cred.euid);
+        if !owner || priority < target.priority {
+            capability::require(&cred, Capability::SysNice)?;
+        }
+        target.priority = priority;
+        Ok(())
+    })
+    .ok_or(Errno::ESRCH)?
+}
+
+/// Starts the scheduler tick. Called once at boot.
+pub fn init() {
+    crate::trace_fn!();
+
+    if let Err(err) = hrtimer::add(TICK_NS, TICK_NS, tick_timer, 0) {
+        log::warn!("sched: no timer for the scheduler tick ({:?}), no preemption", err);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::cred::Credentials;
+    use crate::os::process::DEFAULT_PRIORITY;
+
+    fn spawn(pid: u64) {
+        let mut process = Process::new(pid, 0, "sched");
+        process.timeslice = 0;
+        ptable::insert(process).unwrap();
+    }
+
+    fn set_state(pid: u64, state: ProcessState) {
+        ptable::with_process(pid, |process| process.state = state);
+    }
+
+    fn cleanup(pids: &[u64]) {
+        pids.iter().for_each(|&pid| _ = ptable::remove(pid));
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn runs_ready_processes_in_turn() {
+            let queue = RunQueue::new();
+            let pids = [9950, 9951, 9952];
+            pids.iter().for_each(|&pid| spawn(pid));
+            pids.iter().for_each(|&pid| queue.admit(pid).unwrap());
+            assert_eq!(queue.len(), 3);
+
+            assert_eq!(queue.pick_next(IDLE_PID), 9950);
+            assert_eq!(state(9950), Some(ProcessState::Running));
+            assert!(ptable::with_process(9950, |p| p.timeslice).unwrap() > 0);
+
+            assert_eq!(queue.pick_next(9950), 9951);
+            assert_eq!(state(9950), Some(ProcessState::Ready));
+            assert_eq!(queue.pick_next(9951), 9952);
+            assert_eq!(queue.pick_next(9952), 9950);
+
+            cleanup(&pids);
+        }
+
+        fn blocked_processes_are_skipped_until_woken() {
+            let queue = RunQueue::new();
+            let pids = [9953, 9954];
+            pids.iter().for_each(|&pid| spawn(pid));
+            pids.iter().for_each(|&pid| queue.admit(pid).unwrap());
+
+            assert_eq!(queue.pick_next(IDLE_PID), 9953);
+            set_state(9954, ProcessState::Blocked);
+
+            // Nothing else to run: the running process keeps the CPU
+            assert_eq!(queue.pick_next(9953), 9953);
+            assert_eq!(state(9953), Some(ProcessState::Running));
+
+            // Blocking the last runnable process idles the CPU
+            set_state(9953, ProcessState::Blocked);
+            assert_eq!(queue.pick_next(9953), IDLE_PID);
+
+            ptable::with_process(9954, Process::wake);
+            assert_eq!(queue.pick_next(IDLE_PID), 9954);
+
+            // Terminated processes leave the queue
+            set_state(9953, ProcessState::Terminated);
+            queue.pick_next(9954);
+            assert_eq!(queue.len(), 1);
+
+            cleanup(&pids);
+        }
+
+        fn only_new_processes_are_admitted() {
+            let queue = RunQueue::new();
+            assert_eq!(queue.admit(9955), Err(Errno::ESRCH));
+
+            spawn(9955);
+            queue.admit(9955).unwrap();
+            assert_eq!(queue.admit(9955), Err(Errno::EINVAL));
+            assert_eq!(queue.len(), 1);
+
+            cleanup(&[9955]);
+        }
+
+        fn ready_processes_can_move_to_another_queue() {
+            let (queue, other) = (RunQueue::new(), RunQueue::new());
+            let pids = [9957, 9958, 9959];
+            pids.iter().for_each(|&pid| spawn(pid));
+            pids.iter().for_each(|&pid| queue.admit(pid).unwrap());
+            set_state(9959, ProcessState::Blocked);
+            assert_eq!(queue.pick_next(IDLE_PID), 9957);
+            assert_eq!(queue.load(), 2);
+
+            // Neither the running process nor a blocked one is taken
+            assert_eq!(queue.take_ready(9957), Some(9958));
+            assert_eq!(queue.take_ready(9957), None);
+            other.adopt(9958);
+            assert_eq!((queue.len(), other.len(), other.load()), (2, 1, 1));
+            assert_eq!(other.pick_next(IDLE_PID), 9958);
+
+            cleanup(&pids);
+        }
+
+        fn better_priorities_run_first_until_waiting_ages_the_rest() {
+            let queue = RunQueue::new();
+            let pids = [9961, 9962, 9963];
+            pids.iter().for_each(|&pid| spawn(pid));
+            ptable::with_process(9961, |process| process.priority = 20);
+            ptable::with_process(9963, |process| process.priority = 10);
+            pids.iter().for_each(|&pid| queue.admit(pid).unwrap());
+
+            assert_eq!(queue.pick_next(IDLE_PID), 9963);
+            assert_eq!(queue.pick_next(9963), 9963);
+
+            // Aged to 10, the default priority ties with the running process and takes a turn
+            (0..AGING_TICKS * (DEFAULT_PRIORITY as u32 - 10)).for_each(|_| queue.age());
+            assert_eq!(queue.pick_next(9963), 9962);
+            assert_eq!(queue.pick_next(9962), 9963);
+
+            assert_eq!(effective_priority(20, 0), 20);
+            assert_eq!(effective_priority(u8::MAX, 0), LOWEST_PRIORITY);
+            assert_eq!(effective_priority(20, AGING_TICKS * 100), 0);
+
+            cleanup(&pids);
+        }
+
+        fn wake_readies_the_waiters_on_a_target() {
+            let pids = [9964, 9965];
+            pids.iter().for_each(|&pid| spawn(pid));
+            pids.iter().for_each(|&pid| {
+                set_state(pid, ProcessState::Blocked);
+                ptable::with_process(pid, |process| process.waiting_on = Some(WaitTarget::Semaphore(9964)));
+            });
+
+            assert!(wake_one(WaitTarget::Semaphore(9964)));
+            assert_eq!(wake(WaitTarget::Semaphore(9964)), 1);
+            assert_eq!(wake(WaitTarget::Semaphore(9964)), 0);
+            assert!(pids.iter().all(|&pid| state(pid) == Some(ProcessState::Ready)));
+
+            // Ready already: returns without blocking
+            block_on(WaitTarget::Semaphore(9964), || true);
+
+            cleanup(&pids);
+        }
+
+        fn raising_priorities_takes_cap_sys_nice() {
+            spawn(9966);
+            ptable::with_process(9966, |process| process.cred = Credentials::new(1000, 1000));
+
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 0, 20), Ok(()));
+            assert_eq!(ptable::with_process(9966, |p| p.priority), Some(20));
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 0, 19), Err(Errno::EPERM));
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 9966, 20), Ok(()));
+
+            spawn(9967);
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 9967, 30), Err(Errno::EPERM));
+            ptable::with_process(9966, |process| process.cred = Credentials::ROOT);
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 9967, 0), Ok(()));
+            assert_eq!(ptable::with_process(9967, |p| p.priority), Some(0));
+
+            // From a PID namespace of its own, the caller is PID 1 and 9967 is out of sight
+            let mut parent = Process::new(9965, 0, "sched");
+            pidns::sys_unshare_pid(&mut parent).unwrap();
+            assert_eq!(ptable::with_process(9966, |process| pidns::attach(process, &parent)), Some(Ok(())));
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 9967, 0), Err(Errno::ESRCH));
+            assert_eq!(sys_setpriority(9966, PRIO_PROCESS, 1, 10), Ok(()));
+            assert_eq!(ptable::with_process(9966, |p| p.priority), Some(10));
+            ptable::with_process(9966, pidns::detach);
+            pidns::detach(&mut parent);
+
+            cleanup(&[9966, 9967]);
+        }
+
+        fn ticks_use_up_the_timeslice() {
+            let mut process = Process::new(9956, 0, "sched");
+            process.timeslice = 2;
+
+            assert!(!charge_tick(&mut process));
+            assert!(charge_tick(&mut process));
+            assert_eq!((process.cpu_time, process.timeslice), (2, 0));
+            assert_eq!(process.last_scheduled, 2);
+        }
+
+        fn sleeps_last_at_least_the_ticks_asked_for() {
+            let start = timekeeping::monotonic_ns();
+            sleep_ticks(2);
+
+            assert!(timekeeping::monotonic_ns() - start >= 2 * TICK_NS);
+            assert_eq!(state(percpu::current_pid()), Some(ProcessState::Running));
+            assert_eq!(ptable::with_process(percpu::current_pid(), |p| p.wakeup_time), Some(None));
+        }
+    }
+}
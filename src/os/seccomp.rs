@@ -0,0 +1,237 @@
+//! Per-process system call filtering.
+//!
+//! Every PCB carries a [`SyscallFilter`]: a bitmap of permitted syscall numbers plus the action
+//! to take when a process makes any other call. The dispatcher asks [`check`] before running a
+//! handler. Filters are inherited across fork and survive exec, and installing a new one can
+//! only narrow what the process may already do, so a sandboxed service cannot lift its own
+//! restrictions (or those its parent placed on it).
+
+use crate::os::audit::{self, AuditKind};
+use crate::os::errno::{Errno, KResult};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::uaccess;
+
+/// Number of syscall numbers a filter can describe; larger numbers are always denied by an
+/// active filter.
+pub const MAX_SYSCALLS: usize = 512;
+
+const WORDS: usize = MAX_SYSCALLS / 64;
+
+/// What happens when a filtered process makes a syscall its filter does not permit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// The call fails with the given error without reaching its handler.
+    Errno(Errno),
+
+    /// The process is terminated on the spot.
+    Kill,
+}
+
+impl FilterAction {
+    // Kill is stricter than any errno
+    fn strictest(self, other: FilterAction) -> FilterAction {
+        match (self, other) {
+            (FilterAction::Kill, _) | (_, FilterAction::Kill) => FilterAction::Kill,
+            (action, _) => action,
+        }
+    }
+}
+
+/// The outcome of checking one syscall against a process's filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny(Errno),
+    Kill,
+}
+
+/// Set of syscalls a process may make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallFilter {
+    allowed: [u64; WORDS],
+    action: FilterAction,
+    active: bool,
+}
+
+impl SyscallFilter {
+    /// The filter of an unrestricted process.
+    pub const ALLOW_ALL: SyscallFilter = SyscallFilter {
+        allowed: [u64::MAX; WORDS],
+        action: FilterAction::Errno(Errno::EPERM),
+        active: false,
+    };
+
+    /// An allowlist: only the syscalls in `numbers` are permitted.
+    pub fn allowlist(numbers: &[u32], action: FilterAction) -> Self {
+        let mut filter = SyscallFilter { allowed: [0; WORDS], action, active: true };
+
+        for &nr in numbers {
+            filter.set(nr, true);
+        }
+
+        filter
+    }
+
+    /// A denylist: everything except the syscalls in `numbers` is permitted.
+    pub fn denylist(numbers: &[u32], action: FilterAction) -> Self {
+        let mut filter = SyscallFilter { allowed: [u64::MAX; WORDS], action, active: true };
+
+        for &nr in numbers {
+            filter.set(nr, false);
+        }
+
+        filter
+    }
+
+    fn set(&mut self, nr: u32, allowed: bool) {
+        let nr = nr as usize;
+        if nr >= MAX_SYSCALLS {
+            return;
+        }
+
+        if allowed {
+            self.allowed[nr / 64] |= 1 << (nr % 64);
+        } else {
+            self.allowed[nr / 64] &= !(1 << (nr % 64));
+        }
+    }
+
+    /// Returns `true` if the filter restricts anything at all.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns `true` if syscall `nr` is permitted.
+    pub fn permits(&self, nr: u64) -> bool {
+        if !self.active {
+            return true;
+        }
+
+        let nr = nr as usize;
+        nr < MAX_SYSCALLS && self.allowed[nr / 64] & (1 << (nr % 64)) != 0
+    }
+
+    /// Narrows this filter by `other`: a syscall stays permitted only if both allow it, and
+    /// the stricter of the two actions wins.
+    pub fn restrict(&mut self, other: &SyscallFilter) {
+        if !other.active {
+            return;
+        }
+
+        for (mine, theirs) in self.allowed.iter_mut().zip(other.allowed) {
+            *mine &= theirs;
+        }
+
+        self.action = if self.active { self.action.strictest(other.action) } else { other.action };
+        self.active = true;
+    }
+}
+
+/// Dispatcher hook: decides whether `process` may make syscall `nr`.
+pub fn check(process: &Process, nr: u64) -> Verdict {
+    let filter = &process.syscall_filter;
+
+    if filter.permits(nr) {
+        return Verdict::Allow;
+    }
+
+    log::debug!("seccomp: pid {} denied syscall {}", process.pid, nr);
+
+    let killed = filter.action == FilterAction::Kill;
+    audit::record_cred(AuditKind::SyscallDenied, process.pid, &process.cred, [nr, killed as u64]);
+
+    match filter.action {
+        FilterAction::Errno(errno) => Verdict::Deny(errno),
+        FilterAction::Kill => Verdict::Kill,
+    }
+}
+
+/// Adds `filter` to the restrictions already in place on `process`.
+pub fn install(process: &mut Process, filter: &SyscallFilter) {
+    process.syscall_filter.restrict(filter);
+}
+
+/// `mode` argument of the filter syscall: the list names the permitted syscalls.
+pub const MODE_ALLOWLIST: u64 = 0;
+
+/// `mode` argument of the filter syscall: the list names the forbidden syscalls.
+pub const MODE_DENYLIST: u64 = 1;
+
+/// `action` argument of the filter syscall: kill the process instead of failing the call.
+pub const ACTION_KILL: u64 = 0;
+
+/// Handler for the filter syscall, narrowing the filter of the caller `caller`: `list` points
+/// to `count` `u32` syscall numbers in user memory; `action` is [`ACTION_KILL`] or a positive
+/// errno to fail denied calls with.
+pub fn sys_set_syscall_filter(caller: u64, mode: u64, list: usize, count: usize, action: u64) -> KResult<()> {
+    if count > MAX_SYSCALLS {
+        return Err(Errno::EINVAL);
+    }
+
+    let action = match action {
+        ACTION_KILL => FilterAction::Kill,
+        errno => FilterAction::Errno(errno_from_raw(errno).ok_or(Errno::EINVAL)?),
+    };
+
+    let mut bytes = [0u8; MAX_SYSCALLS * 4];
+    let bytes = &mut bytes[..count * 4];
+    uaccess::copy_from_user(bytes, list)?;
+
+    let mut numbers = [0u32; MAX_SYSCALLS];
+    for (nr, raw) in numbers.iter_mut().zip(bytes.chunks(4)) {
+        *nr = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+    }
+
+    let filter = match mode {
+        MODE_ALLOWLIST => SyscallFilter::allowlist(&numbers[..count], action),
+        MODE_DENYLIST => SyscallFilter::denylist(&numbers[..count], action),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    ptable::with_process(caller, |process| install(process, &filter)).ok_or(Errno::ESRCH)
+}
+
+// Only the errors that make sense as a filter verdict are accepted
+fn errno_from_raw(raw: u64) -> Option<Errno> {
+    match raw {
+        1 => Some(Errno::EPERM),
+        13 => Some(Errno::EACCES),
+        22 => Some(Errno::EINVAL),
+        38 => Some(Errno::ENOSYS),
+        _ => None,
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn allowlist_permits_only_listed() {
+            let filter = SyscallFilter::allowlist(&[0, 1, 60], FilterAction::Kill);
+
+            assert!(filter.permits(1));
+            assert!(!filter.permits(2));
+            assert!(!filter.permits(MAX_SYSCALLS as u64 + 1));
+        }
+
+        fn restrictions_only_narrow() {
+            let mut filter = SyscallFilter::ALLOW_ALL;
+            filter.restrict(&SyscallFilter::allowlist(&[0, 1, 2], FilterAction::Errno(Errno::EPERM)));
+            filter.restrict(&SyscallFilter::denylist(&[1], FilterAction::Kill));
+
+            assert!(filter.permits(0));
+            assert!(!filter.permits(1));
+            assert!(!filter.permits(3));
+
+            // A later, looser filter cannot re-enable anything
+            filter.restrict(&SyscallFilter::denylist(&[], FilterAction::Errno(Errno::EPERM)));
+            assert!(!filter.permits(3));
+
+            let mut process = Process::new(1, 0, "sandboxed");
+            install(&mut process, &filter);
+            assert_eq!(check(&process, 1), Verdict::Kill);
+            assert_eq!(check(&process, 0), Verdict::Allow);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use log::{error, info};
+
+use crate::os::arch::counter;
+use crate::os::ktest;
+use crate::os::leak;
+use crate::os::qemu::{exit_qemu, QemuExitCode};
+
+/// How long allocations made by the tests may stay alive before `leakcheck` builds call them leaked.
+const LEAK_SETTLE_MS: u64 = 100;
+
+/// Runs the kernel test suites (see `os::ktest::SUITES`) and exits QEMU with a pass/fail code.
+///
+/// Never returns: the whole point of self-test mode is to hand a verdict back to the host.
+pub fn run() -> ! {
+    info!("selftest: running kernel test suites");
+
+    let since = leak::mark();
+    let summary = ktest::run_all();
+
+    // Give deferred frees a chance to happen, then flag anything the tests left behind
+    let mut leaked = 0;
+    if cfg!(feature = "leakcheck") {
+        let start = counter::read();
+        while counter::millis_since(start) < LEAK_SETTLE_MS {
+            core::hint::spin_loop();
+        }
+
+        leaked = leak::report(since, LEAK_SETTLE_MS);
+    }
+
+    if summary.failed == 0 && leaked == 0 {
+        info!("selftest: all {} tests passed", summary.passed);
+        exit_qemu(QemuExitCode::Success)
+    } else {
+        if summary.failed != 0 {
+            error!("selftest: {} of {} tests failed", summary.failed, summary.passed + summary.failed);
+        }
+        if leaked != 0 {
+            error!("selftest: {} allocations leaked", leaked);
+        }
+        exit_qemu(QemuExitCode::Failed)
+    }
+}
@@ -0,0 +1,239 @@
+//! Serial console and kernel logger.
+//!
+//! The platform UART (a 16550 on COM1 on x86) is polled for output, and is where the `log`
+//! backend sends every record, along with the firmware console until boot services go. Which
+//! records are kept depends on the module they come from: the `log=` command line option sets
+//! a default level and levels for modules of their own, e.g. `log=info,os::paging=debug`, and
+//! `kernel.log_level` changes the default later.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::LevelFilter;
+use uefi::table::{Boot, SystemTable};
+
+use crate::os::arch::uart::{self, write_byte};
+use crate::os::boot;
+
+/// Programs the platform UART (COM1 on x86, the PL011 on AArch64, a 16550 on
+/// RISC-V) for polled output.
+pub fn init() {
+    uart::init();
+}
+
+/// Zero-sized handle implementing `fmt::Write` on top of the serial console.
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Terminals attached to the serial port expect CRLF line endings
+            if byte == b'\n' {
+                write_byte(b'\r');
+            }
+
+            write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// Most modules the command line can give a level of their own.
+pub const MAX_MODULE_LEVELS: usize = 16;
+
+/// A module given a level of its own, such as `os::paging=debug` on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModuleLevel {
+    /// Module path within the kernel crate, e.g. `os::paging`; covers its submodules too.
+    pub module: &'static str,
+    pub level: LevelFilter,
+}
+
+// Level of records from modules without one of their own
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(log::STATIC_MAX_LEVEL as usize);
+
+// Set once at boot by `configure_levels`
+static mut MODULE_LEVELS: [Option<ModuleLevel>; MAX_MODULE_LEVELS] = [None; MAX_MODULE_LEVELS];
+
+fn module_levels() -> &'static [Option<ModuleLevel>] {
+    let modules = &raw const MODULE_LEVELS;
+    unsafe { &*modules }
+}
+
+fn level_filter(value: usize) -> LevelFilter {
+    LevelFilter::iter().nth(value).unwrap_or(LevelFilter::Trace)
+}
+
+/// Parses a `log=` option: comma-separated entries, each a level (the default for every
+/// module) or `module=level`. Returns the default level, if given, and the module levels;
+/// entries that do not parse, or do not fit, are dropped.
+pub fn parse_levels(spec: &'static str) -> (Option<LevelFilter>, [Option<ModuleLevel>; MAX_MODULE_LEVELS]) {
+    let mut default = None;
+    let mut modules = [None; MAX_MODULE_LEVELS];
+    let mut count = 0;
+
+    for entry in spec.split(',') {
+        match entry.split_once('=') {
+            None => default = entry.parse().ok().or(default),
+            Some((module, level)) => {
+                if let (Ok(level), Some(slot)) = (level.parse(), modules.get_mut(count)) {
+                    *slot = Some(ModuleLevel { module, level });
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    (default, modules)
+}
+
+/// Whether `module` is the module a record's `target` names (its module path, crate first)
+/// or one of its parents.
+pub fn covers(module: &str, target: &str) -> bool {
+    let path = target.split_once("::").map_or("", |(_, path)| path);
+    path.strip_prefix(module).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+// The level for records from `target`: that of the most specific module covering it
+fn level_for(target: &str, modules: &[Option<ModuleLevel>]) -> LevelFilter {
+    modules
+        .iter()
+        .flatten()
+        .filter(|entry| covers(entry.module, target))
+        .max_by_key(|entry| entry.module.len())
+        .map_or_else(|| level_filter(DEFAULT_LEVEL.load(Ordering::Relaxed)), |entry| entry.level)
+}
+
+// `log` drops records above its maximum before the logger sees them, so it must let through
+// the most verbose level any module has
+fn update_max_level() {
+    let most = module_levels().iter().flatten().map(|entry| entry.level).max().unwrap_or(LevelFilter::Off);
+    log::set_max_level(most.max(level_filter(DEFAULT_LEVEL.load(Ordering::Relaxed))));
+}
+
+/// Applies the `log=` command line option, e.g. `log=warn,os::paging=debug,os::sched=trace`.
+/// Called once at boot, once the command line is stored.
+pub fn configure_levels() {
+    let Some(spec) = boot::option("log") else {
+        return;
+    };
+
+    let (default, modules) = parse_levels(spec);
+    if let Some(default) = default {
+        DEFAULT_LEVEL.store(default as usize, Ordering::Relaxed);
+    }
+
+    unsafe { MODULE_LEVELS = modules };
+    update_max_level();
+}
+
+/// Sets the level of modules without one of their own, as `kernel.log_level` does.
+pub fn set_default_level(level: LevelFilter) {
+    DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// The kernel's `log` backend: records go to the serial console, and also to the firmware
+/// console while boot services are up, if the level of the module they come from allows.
+struct Logger;
+
+// Logs to the firmware console until boot services are exited
+static mut FIRMWARE_CONSOLE: Option<uefi::logger::Logger> = None;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target(), module_levels())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        _print(format_args!("[{:>5}]: {}\n", record.level(), record.args()));
+
+        unsafe {
+            let console = &raw const FIRMWARE_CONSOLE;
+            if let Some(console) = &*console {
+                console.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Installs the kernel logger, writing to the serial console and to the firmware's text
+/// output until [`detach_firmware_console`] is called.
+pub fn init_logger(system_table: &mut SystemTable<Boot>) {
+    unsafe {
+        let console = &raw mut FIRMWARE_CONSOLE;
+        *console = Some(uefi::logger::Logger::new(system_table.stdout()));
+    }
+
+    // Can only fail if a logger is already installed
+    log::set_logger(&LOGGER).unwrap();
+    update_max_level();
+}
+
+/// Stops logging to the firmware console, which goes away with boot services. Must be called
+/// before exiting them; the serial console keeps logging.
+pub fn detach_firmware_console() {
+    unsafe {
+        let console = &raw mut FIRMWARE_CONSOLE;
+        *console = None;
+    }
+}
+
+// Internal function for the serial print macros.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // Writing to the UART cannot fail
+    _ = SerialWriter.write_fmt(args);
+}
+
+/// Prints to the serial console.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::os::serial::_print(core::format_args!($($arg)*)));
+}
+
+/// Prints to the serial console, with a newline.
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::os::serial::_print(core::format_args!("{}\n", core::format_args!($($arg)*))));
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn log_option_sets_default_and_module_levels() {
+            let (default, modules) = parse_levels("warn,os::paging=debug,os::sched=trace,bogus,os::tty=loud");
+            assert_eq!(default, Some(LevelFilter::Warn));
+            assert_eq!(modules[0], Some(ModuleLevel { module: "os::paging", level: LevelFilter::Debug }));
+            assert_eq!(modules[1], Some(ModuleLevel { module: "os::sched", level: LevelFilter::Trace }));
+            assert_eq!(modules[2], None);
+
+            assert_eq!(parse_levels("os::heap=off").0, None);
+        }
+
+        fn module_levels_cover_submodules() {
+            assert!(covers("os::paging", "osproj::os::paging"));
+            assert!(covers("os::virtio", "osproj::os::virtio::balloon"));
+            assert!(!covers("os::pid", "osproj::os::pidns"));
+            assert!(!covers("os::pid", "osproj"));
+
+            // The most specific module decides
+            let (_, modules) = parse_levels("os::virtio=error,os::virtio::p9=trace");
+            assert_eq!(level_for("osproj::os::virtio::p9", &modules), LevelFilter::Trace);
+            assert_eq!(level_for("osproj::os::virtio::balloon", &modules), LevelFilter::Error);
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! The kernel shell: a debug monitor on the console.
+//!
+//! [`spawn`] starts it as a kernel thread that reads lines from the keyboard, echoes them to
+//! the framebuffer console and runs them. Lines are edited with backspace only. The commands
+//! look at the kernel from the inside, with no permission checks: the shell is for whoever
+//! sits at the machine.
+//!
+//! | Command      | Effect                                                       |
+//! |--------------|--------------------------------------------------------------|
+//! | `help`       | lists the commands                                           |
+//! | `ps`         | the process table                                            |
+//! | `mem`        | usable memory regions and frame allocator usage              |
+//! | `kill <pid>` | sends `SIGKILL` to a process                                 |
+//! | `run <path>` | starts the ELF executable at `path` as a child of the kernel |
+//! | `uptime`     | time since boot                                              |
+//! | `deadlock`   | scans for processes waiting on each other and logs any cycle |
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::os::deadlock;
+use crate::os::elf;
+use crate::os::errno::{Errno, KResult};
+use crate::os::exit;
+use crate::os::file::O_RDONLY;
+use crate::os::jobctl::{self, SIGKILL};
+use crate::os::keyboard;
+use crate::os::kthread;
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::percpu;
+use crate::os::process::{Process, ProcessState};
+use crate::os::ptable::{self, MAX_PROCESSES, ProcessInfo};
+use crate::os::timekeeping;
+use crate::os::vfs;
+use crate::print;
+
+/// Longest line the shell takes; further input is dropped until the line ends.
+pub const LINE_MAX: usize = 128;
+
+// Below user processes, which start at the default of 0, so typing stays responsive
+const PRIORITY: u8 = 1;
+
+const PROMPT: &str = "> ";
+
+// Parent of what `run` starts, and the one process `kill` refuses
+const KERNEL_PID: u64 = 0;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// A parsed command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    /// A blank line
+    Empty,
+    Help,
+    Ps,
+    Mem,
+    Kill(u64),
+    Run(&'a str),
+    Uptime,
+    Deadlock,
+}
+
+/// Why a line is not a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError<'a> {
+    /// No command has this name
+    Unknown(&'a str),
+
+    /// The arguments do not fit the command; holds its usage line
+    Usage(&'a str),
+}
+
+/// The line being typed.
+pub struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+}
+
+// What a typed byte did to the line, for the echo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Insert(u8),
+    Erase,
+    Done,
+    None,
+}
+
+/// Starts the shell. Does nothing but log on failure: the kernel runs fine without it.
+pub fn spawn() {
+    match kthread::spawn_kthread(shell_main, PRIORITY) {
+        Ok(pid) => log::info!("shell: started as pid {}", pid),
+        Err(errno) => log::warn!("shell: cannot start: {:?}", errno),
+    }
+}
+
+fn shell_main() {
+    let mut console = Console;
+    let mut line = Line::new();
+    _ = write!(console, "\nKernel shell; type `help` for the commands.\n{}", PROMPT);
+
+    loop {
+        let mut typed = [0u8; 16];
+        let Ok(n) = keyboard::read(&mut typed) else {
+            continue;
+        };
+
+        for &byte in &typed[..n] {
+            match line.feed(byte) {
+                Edit::Insert(byte) => print!("{}", byte as char),
+                Edit::Erase => print!("\x08 \x08"),
+                Edit::Done => {
+                    print!("\n");
+                    _ = run(line.as_str(), &mut console);
+                    line.clear();
+                    print!("{}", PROMPT);
+                }
+                Edit::None => {}
+            }
+        }
+    }
+}
+
+// Writes through `print!`
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+impl Line {
+    pub const fn new() -> Line {
+        Line { buf: [0; LINE_MAX], len: 0 }
+    }
+
+    // Applies a typed byte; only printable ASCII goes into the line
+    fn feed(&mut self, byte: u8) -> Edit {
+        match byte {
+            b'\n' | b'\r' => Edit::Done,
+            BACKSPACE | DELETE if self.len > 0 => {
+                self.len -= 1;
+                Edit::Erase
+            }
+            b' '..=b'~' if self.len < LINE_MAX => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                Edit::Insert(byte)
+            }
+            _ => Edit::None,
+        }
+    }
+
+    /// The line so far.
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is ever stored
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl Default for Line {
+    fn default() -> Line {
+        Line::new()
+    }
+}
+
+/// Parses a command line: a command name and its arguments, separated by whitespace.
+pub fn parse(line: &str) -> Result<Command<'_>, ParseError<'_>> {
+    let mut words = line.split_ascii_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(Command::Empty);
+    };
+    let (argument, extra) = (words.next(), words.next());
+
+    let command = match (name, argument) {
+        ("help", None) => Command::Help,
+        ("ps", None) => Command::Ps,
+        ("mem", None) => Command::Mem,
+        ("uptime", None) => Command::Uptime,
+        ("deadlock", None) => Command::Deadlock,
+        ("kill", Some(pid)) if extra.is_none() => Command::Kill(pid.parse().map_err(|_| ParseError::Usage("kill <pid>"))?),
+        ("kill", _) => return Err(ParseError::Usage("kill <pid>")),
+        ("run", Some(path)) if extra.is_none() => Command::Run(path),
+        ("run", _) => return Err(ParseError::Usage("run <path>")),
+        ("help" | "ps" | "mem" | "uptime" | "deadlock", Some(_)) => return Err(ParseError::Usage(name)),
+        _ => return Err(ParseError::Unknown(name)),
+    };
+    Ok(command)
+}
+
+/// Parses and runs `line`, writing its output and any error to `out`.
+pub fn run(line: &str, out: &mut impl Write) -> fmt::Result {
+    let command = match parse(line) {
+        Ok(command) => command,
+        Err(ParseError::Unknown(name)) => return writeln!(out, "{}: unknown command", name),
+        Err(ParseError::Usage(usage)) => return writeln!(out, "usage: {}", usage),
+    };
+
+    match command {
+        Command::Empty => Ok(()),
+        Command::Help => writeln!(out, "commands: help, ps, mem, kill <pid>, run <path>, uptime, deadlock"),
+        Command::Ps => ps(out),
+        Command::Mem => mem(out),
+        Command::Kill(pid) => match kill(pid) {
+            Ok(()) => Ok(()),
+            Err(errno) => writeln!(out, "kill: {}: {:?}", pid, errno),
+        },
+        Command::Run(path) => match spawn_program(path) {
+            Ok(pid) => writeln!(out, "started {} as pid {}", path, pid),
+            Err(errno) => writeln!(out, "run: {}: {:?}", path, errno),
+        },
+        Command::Uptime => write_uptime(timekeeping::monotonic_ns(), out),
+        Command::Deadlock => match deadlock::report() {
+            true => writeln!(out, "deadlock found; the cycle is in the log"),
+            false => writeln!(out, "no deadlock"),
+        },
+    }
+}
+
+fn ps(out: &mut impl Write) -> fmt::Result {
+    let mut processes = [ProcessInfo::EMPTY; MAX_PROCESSES];
+    let count = ptable::snapshot(&mut processes);
+
+    writeln!(out, "{:>5} {:>5} {:<10} {:>4} {:>10} {:>8}  NAME", "PID", "PPID", "STATE", "PRIO", "CPU", "VM(KiB)")?;
+    for info in &processes[..count] {
+        writeln!(out, "{:>5} {:>5} {:<10} {:>4} {:>10} {:>8}  {}", info.pid, info.ppid, info.state.as_str(), info.priority, info.cpu_time, info.memory / 1024, info.name())?;
+    }
+    Ok(())
+}
+
+fn mem(out: &mut impl Write) -> fmt::Result {
+    let regions = memory::get_usable_memory_regions();
+    let largest = memory::largest_region().map_or(0, |region| region.size);
+    writeln!(out, "{} usable regions, {} KiB in the largest", regions.len(), largest / 1024)?;
+    for region in regions.iter() {
+        writeln!(out, "  {:#014x}-{:#014x} {:>8} KiB  node {}", region.start, region.start + region.size, region.size / 1024, region.node)?;
+    }
+
+    let stats = memory::stats();
+    let (total, free) = (stats.total / FRAME_SIZE, stats.free / FRAME_SIZE);
+    writeln!(out, "frames: {} of {} in use, {} free ({} MiB)", total - free.min(total), total, free, stats.free >> 20)?;
+    if stats.ballooned != 0 {
+        writeln!(out, "ballooned: {} MiB", stats.ballooned >> 20)?;
+    }
+    Ok(())
+}
+
+// Kills `pid` as `kill -9` would, telling its parent if that continued it
+fn kill(pid: u64) -> KResult<()> {
+    if pid == KERNEL_PID || pid == percpu::current_pid() {
+        return Err(Errno::EPERM);
+    }
+
+    let (event, ppid) = ptable::with_process(pid, |p| {
+        if p.state == ProcessState::Terminated {
+            return Err(Errno::ESRCH);
+        }
+        Ok((jobctl::send(p, SIGKILL), p.ppid))
+    })
+    .ok_or(Errno::ESRCH)??;
+
+    if event.is_some() {
+        exit::notify_parent(pid, ppid);
+    }
+    Ok(())
+}
+
+// Reads the executable at `path` and starts it
+fn spawn_program(path: &str) -> KResult<u64> {
+    // Opened with the kernel's credentials
+    let opener = Process::new(percpu::current_pid(), KERNEL_PID, "shell");
+    let file = vfs::open(&opener, path, O_RDONLY)?;
+
+    let mut image = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let n = vfs::read(&file, &mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        image.try_reserve(n).map_err(|_| Errno::ENOMEM)?;
+        image.extend_from_slice(&chunk[..n]);
+    }
+
+    elf::spawn(&image, &[path], KERNEL_PID)
+}
+
+fn write_uptime(ns: u64, out: &mut impl Write) -> fmt::Result {
+    let seconds = ns / 1_000_000_000;
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        write!(out, "up {} day{}, ", days, if days == 1 { "" } else { "s" })?;
+    } else {
+        write!(out, "up ")?;
+    }
+    writeln!(out, "{:02}:{:02}:{:02}.{:03}", hours, minutes, seconds % 60, ns / 1_000_000 % 1000)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use alloc::string::String;
+
+    fn typed(line: &mut Line, input: &[u8]) -> Vec<Edit> {
+        input.iter().map(|&byte| line.feed(byte)).collect()
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn lines_parse_into_commands() {
+            assert_eq!(parse(""), Ok(Command::Empty));
+            assert_eq!(parse("   "), Ok(Command::Empty));
+            assert_eq!(parse("ps"), Ok(Command::Ps));
+            assert_eq!(parse("  mem  "), Ok(Command::Mem));
+            assert_eq!(parse("kill 42"), Ok(Command::Kill(42)));
+            assert_eq!(parse("run /bin/init"), Ok(Command::Run("/bin/init")));
+            assert_eq!(parse("uptime"), Ok(Command::Uptime));
+            assert_eq!(parse("deadlock"), Ok(Command::Deadlock));
+
+            assert_eq!(parse("kill"), Err(ParseError::Usage("kill <pid>")));
+            assert_eq!(parse("kill -1"), Err(ParseError::Usage("kill <pid>")));
+            assert_eq!(parse("kill 1 2"), Err(ParseError::Usage("kill <pid>")));
+            assert_eq!(parse("run"), Err(ParseError::Usage("run <path>")));
+            assert_eq!(parse("ps aux"), Err(ParseError::Usage("ps")));
+            assert_eq!(parse("reboot now"), Err(ParseError::Unknown("reboot")));
+        }
+
+        fn lines_are_edited_with_backspace() {
+            let mut line = Line::new();
+            assert_eq!(typed(&mut line, b"pz\x08s\n"), [Edit::Insert(b'p'), Edit::Insert(b'z'), Edit::Erase, Edit::Insert(b's'), Edit::Done]);
+            assert_eq!(line.as_str(), "ps");
+
+            // Nothing to erase, control characters and overflow are ignored
+            line.clear();
+            assert_eq!(typed(&mut line, b"\x7f\x1b"), [Edit::None, Edit::None]);
+            for _ in 0..LINE_MAX + 5 {
+                line.feed(b'a');
+            }
+            assert_eq!(line.as_str().len(), LINE_MAX);
+        }
+
+        fn uptime_is_formatted() {
+            let mut out = String::new();
+            write_uptime(3_723_004_000_000, &mut out).unwrap();
+            assert_eq!(out, "up 01:02:03.004\n");
+
+            out.clear();
+            write_uptime(2 * 86_400_000_000_000 + 5_000_000_000, &mut out).unwrap();
+            assert_eq!(out, "up 2 days, 00:00:05.000\n");
+        }
+
+        fn commands_report_errors() {
+            let mut out = String::new();
+            run("frobnicate", &mut out).unwrap();
+            run("kill 0", &mut out).unwrap();
+            run("kill 999999", &mut out).unwrap();
+            run("uptime 1", &mut out).unwrap();
+            assert_eq!(out, "frobnicate: unknown command\nkill: 0: EPERM\nkill: 999999: ESRCH\nusage: uptime\n");
+
+            out.clear();
+            run("run /no/such/file", &mut out).unwrap();
+            assert!(out.starts_with("run: /no/such/file: E"));
+
+            out.clear();
+            run("deadlock", &mut out).unwrap();
+            assert_eq!(out, "no deadlock\n");
+
+            out.clear();
+            run("ps", &mut out).unwrap();
+            assert!(out.lines().any(|line| line.split_whitespace().next() == Some("0")));
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Signal delivery: what happens to a pending signal once its process heads back to user mode.
+//!
+//! Signals are raised by [`jobctl::send`], which applies the job control ones (stopping and
+//! continuing) at once and leaves the rest pending in `signal_bitmap`. Each time a process
+//! returns to user mode, from a syscall or an interrupt, the architecture's entry path takes
+//! the next signal it does not block ([`next`]) and carries out its action: the default one
+//! terminates the process, with status 128 plus the signal, or does nothing for the signals
+//! that default to being ignored; a handler is run in user mode (see
+//! [`arch::x86_64::signal`](crate::os::arch::x86_64::signal)). `SIGKILL` is taken first and,
+//! like `SIGSTOP`, can be neither handled nor blocked.
+//!
+//! Handlers are installed with `rt_sigaction`, which needs `SA_RESTORER`: the restorer is the
+//! trampoline a handler returns into, and it makes the `rt_sigreturn` call that restores the
+//! interrupted context, as on Linux. `sa_mask` is not supported; a handler runs with only its
+//! own signal blocked.
+
+use crate::os::errno::{Errno, KResult};
+use crate::os::jobctl::{SIG_IGN, SIGCONT, SIGKILL, SIGSTOP, SIGTSTP, SIGTTIN, SIGTTOU};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::uaccess;
+
+pub const SIGSEGV: u32 = 11;
+pub const SIGCHLD: u32 = 17;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+
+/// `sa_flags` bit: `sa_restorer` holds the trampoline handlers return into.
+pub const SA_RESTORER: u64 = 0x0400_0000;
+
+// Signals that can be given a handler: the ones `signal_handlers` has room for
+const HANDLED_SIGNALS: u32 = 32;
+
+// Signals that cannot be blocked
+const UNBLOCKABLE: u64 = (1 << SIGKILL) | (1 << SIGSTOP);
+
+/// `struct sigaction` as `rt_sigaction` takes it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+/// What to do with a signal taken for delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// End the process, with exit status 128 plus the signal.
+    Terminate,
+
+    /// Run `handler` in user mode, returning through `restorer`.
+    Handle { handler: usize, restorer: usize },
+}
+
+// Whether `signal`'s default action is to do nothing. The stop signals are here too: a
+// default stop takes effect as the signal is sent, so one only gets this far if the handler
+// it was sent to was reset since
+fn ignored_by_default(signal: u32) -> bool {
+    matches!(signal, SIGCHLD | SIGCONT | SIGURG | SIGWINCH | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU)
+}
+
+/// Exit status of a process `signal` terminates.
+pub fn exit_status(signal: u32) -> i32 {
+    128 + signal as i32
+}
+
+/// Takes the next signal pending in `process` that it does not block, `SIGKILL` first and then
+/// the lowest, and returns it with the action to carry out. Signals whose action is to do
+/// nothing are discarded along the way.
+pub fn next(process: &mut Process) -> Option<(u32, Action)> {
+    loop {
+        let deliverable = process.signal_bitmap & !(process.signal_mask & !UNBLOCKABLE);
+        if deliverable == 0 {
+            return None;
+        }
+
+        let signal = if deliverable & (1 << SIGKILL) != 0 { SIGKILL } else { deliverable.trailing_zeros() };
+        process.signal_bitmap &= !(1 << signal);
+
+        let handler = match signal {
+            SIGKILL | SIGSTOP => 0,
+            signal => process.signal_handlers.get(signal as usize).copied().unwrap_or(0),
+        };
+        match handler {
+            SIG_IGN => continue,
+            0 if ignored_by_default(signal) => continue,
+            0 => return Some((signal, Action::Terminate)),
+            handler => {
+                let restorer = process.signal_restorers[signal as usize];
+                return Some((signal, Action::Handle { handler, restorer }));
+            }
+        }
+    }
+}
+
+/// Raises `signal` for a fault `process` caused in user mode. Returns whether a handler will
+/// deal with it; if not, returning would only repeat the fault, so the caller terminates the
+/// process.
+pub fn force(process: &mut Process, signal: u32) -> bool {
+    let handled = process.signal_handlers.get(signal as usize).is_some_and(|handler| *handler > SIG_IGN);
+    if !handled || process.signal_mask & (1 << signal) != 0 {
+        return false;
+    }
+
+    process.signal_bitmap |= 1 << signal;
+    true
+}
+
+/// `rt_sigaction(signal, act, oldact, sigsetsize)`: installs the action at `act` for `signal`
+/// in the caller `caller` unless `act` is null, and writes the one it replaces to `oldact`
+/// unless that is null. Ignoring a signal discards it if pending. Fails with `EINVAL` for a
+/// bad signal, for `SIGKILL` or `SIGSTOP` with an action, for a handler without
+/// `SA_RESTORER` and for a `sigsetsize` other than 8, and with `EFAULT` for pointers outside
+/// user memory.
+pub fn sys_rt_sigaction(caller: u64, signal: u32, act: usize, oldact: usize, sigsetsize: usize) -> KResult<()> {
+    if sigsetsize != size_of::<u64>() || signal == 0 || signal >= HANDLED_SIGNALS {
+        return Err(Errno::EINVAL);
+    }
+
+    let new = match act {
+        0 => None,
+        _ if signal == SIGKILL || signal == SIGSTOP => return Err(Errno::EINVAL),
+        act => {
+            let new: SigAction = unsafe { uaccess::read_user(act)? };
+            if new.handler > SIG_IGN as u64 && new.flags & SA_RESTORER == 0 {
+                return Err(Errno::EINVAL);
+            }
+            Some(new)
+        }
+    };
+
+    let index = signal as usize;
+    let old = ptable::with_process(caller, |process| {
+        let restorer = process.signal_restorers[index] as u64;
+        let old = SigAction {
+            handler: process.signal_handlers[index] as u64,
+            flags: if restorer != 0 { SA_RESTORER } else { 0 },
+            restorer,
+            mask: 0,
+        };
+
+        if let Some(new) = new {
+            process.signal_handlers[index] = new.handler as usize;
+            process.signal_restorers[index] = if new.flags & SA_RESTORER != 0 { new.restorer as usize } else { 0 };
+            if new.handler == SIG_IGN as u64 {
+                process.signal_bitmap &= !(1 << signal);
+            }
+        }
+        old
+    })
+    .ok_or(Errno::ESRCH)?;
+
+    if oldact != 0 {
+        uaccess::write_user(oldact, &old)?;
+    }
+    Ok(())
+}
+
+pub mod ktests {
+    use super::*;
+
+    const SIGUSR1: u32 = 10;
+    const SIGTERM: u32 = 15;
+
+    crate::os::ktest::kernel_test! {
+        fn pending_signals_are_taken_in_order() {
+            let mut process = Process::new(9970, 0, "signal");
+            process.signal_handlers[SIGUSR1 as usize] = 0x40_1000;
+            process.signal_restorers[SIGUSR1 as usize] = 0x40_2000;
+            process.signal_handlers[SIGTERM as usize] = SIG_IGN;
+            process.signal_bitmap = (1 << SIGCHLD) | (1 << SIGTERM) | (1 << SIGUSR1) | (1 << SIGKILL) | (1 << SIGURG);
+
+            // SIGKILL first, then the handled one; the ignored ones are dropped
+            assert_eq!(next(&mut process), Some((SIGKILL, Action::Terminate)));
+            assert_eq!(next(&mut process), Some((SIGUSR1, Action::Handle { handler: 0x40_1000, restorer: 0x40_2000 })));
+            assert_eq!(next(&mut process), None);
+            assert_eq!(process.signal_bitmap, 0);
+
+            // Blocked signals wait; SIGKILL cannot be blocked
+            process.signal_mask = u64::MAX;
+            process.signal_bitmap = (1 << 2) | (1 << SIGKILL);
+            assert_eq!(next(&mut process), Some((SIGKILL, Action::Terminate)));
+            assert_eq!(next(&mut process), None);
+            process.signal_mask = 0;
+            assert_eq!(next(&mut process), Some((2, Action::Terminate)));
+            assert_eq!(exit_status(2), 130);
+        }
+
+        fn faults_are_forced_on_handlers_only() {
+            let mut process = Process::new(9971, 0, "signal");
+            assert!(!force(&mut process, SIGSEGV));
+
+            process.signal_handlers[SIGSEGV as usize] = 0x40_1000;
+            assert!(force(&mut process, SIGSEGV));
+            assert_eq!(process.signal_bitmap, 1 << SIGSEGV);
+
+            process.signal_mask = 1 << SIGSEGV;
+            assert!(!force(&mut process, SIGSEGV));
+        }
+
+        fn sigaction_installs_and_reports_handlers() {
+            ptable::insert(Process::new(9972, 0, "signal")).unwrap();
+            let action = SigAction { handler: 0x40_1000, flags: SA_RESTORER, restorer: 0x40_2000, mask: 0 };
+            let mut old = SigAction::default();
+            let (act, oldact) = (&action as *const SigAction as usize, &mut old as *mut SigAction as usize);
+
+            assert_eq!(sys_rt_sigaction(9972, SIGUSR1, act, 0, 8), Ok(()));
+            assert_eq!(sys_rt_sigaction(9972, SIGUSR1, 0, oldact, 8), Ok(()));
+            assert_eq!(old, action);
+            assert_eq!(ptable::with_process(9972, |process| process.signal_restorers[SIGUSR1 as usize]), Some(0x40_2000));
+
+            assert_eq!(sys_rt_sigaction(9972, SIGKILL, act, 0, 8), Err(Errno::EINVAL));
+            assert_eq!(sys_rt_sigaction(9972, SIGUSR1, act, 0, 16), Err(Errno::EINVAL));
+            assert_eq!(sys_rt_sigaction(9972, 40, act, 0, 8), Err(Errno::EINVAL));
+
+            let bare = SigAction { flags: 0, ..action };
+            assert_eq!(sys_rt_sigaction(9972, SIGUSR1, &bare as *const SigAction as usize, 0, 8), Err(Errno::EINVAL));
+
+            // Ignoring a pending signal discards it
+            let ignore = SigAction { handler: SIG_IGN as u64, ..SigAction::default() };
+            ptable::with_process(9972, |process| process.signal_bitmap = 1 << SIGUSR1);
+            assert_eq!(sys_rt_sigaction(9972, SIGUSR1, &ignore as *const SigAction as usize, 0, 8), Ok(()));
+            assert_eq!(ptable::remove(9972).unwrap().signal_bitmap, 0);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Runtime support for stack-smashing protection.
+//!
+//! When the kernel is built with `-Z stack-protector` (see `scripts/run.sh`), the compiler
+//! places a copy of [`__stack_chk_guard`] between the locals and the return address of every
+//! protected function and calls [`__stack_chk_fail`] if it changed by the time the function
+//! returns. The guard starts out as a fixed constant and is replaced by a per-boot random
+//! value in [`init`].
+//!
+//! The UEFI target follows the MSVC conventions, where the same check is done through
+//! `__security_cookie`/`__security_check_cookie`; both pairs are provided and share the guard.
+
+use crate::os::random;
+
+// Value used until init() runs; the low byte is zero so string overflows cannot reproduce it
+const INITIAL_GUARD: usize = 0x2f8a_61c4_9e3d_b700;
+
+/// The canary compared on function exit by the GNU-style stack protector.
+#[unsafe(no_mangle)]
+pub static mut __stack_chk_guard: usize = INITIAL_GUARD;
+
+/// The canary used by the MSVC-style stack protector.
+#[unsafe(no_mangle)]
+pub static mut __security_cookie: usize = INITIAL_GUARD;
+
+/// Replaces the boot-time guard with a random one. Must run after [`random::init`].
+///
+/// Only functions entered after this point are checked against the new value, so it has to be
+/// called from a frame that never returns (`os_main`). This function itself has no arrays or
+/// address-taken locals and is therefore not instrumented.
+pub fn init() {
+    // Zero the low byte, as glibc does, so an unterminated string copy cannot forge the canary
+    set_guard(random::next_u64() as usize & !0xff);
+
+    if !random::is_seeded() {
+        log::warn!("stack protector: CSPRNG is not seeded, canary is guessable");
+    }
+}
+
+#[inline(always)]
+fn set_guard(value: usize) {
+    unsafe {
+        let guard = &raw mut __stack_chk_guard;
+        let cookie = &raw mut __security_cookie;
+        guard.write_volatile(value);
+        cookie.write_volatile(value);
+    }
+}
+
+/// Called by instrumented functions whose canary was overwritten.
+#[unsafe(no_mangle)]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    // The corrupted frame cannot be trusted, so go straight to the panic path rather than
+    // unwinding back through it
+    panic!("stack smashing detected");
+}
+
+/// MSVC-style check: `cookie` is the value read back from the frame.
+#[unsafe(no_mangle)]
+pub extern "C" fn __security_check_cookie(cookie: usize) {
+    let expected = unsafe {
+        let guard = &raw const __security_cookie;
+        guard.read_volatile()
+    };
+
+    if cookie != expected {
+        __stack_chk_fail();
+    }
+}
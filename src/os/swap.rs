@@ -0,0 +1,605 @@
+//! Swap: anonymous pages paged out to block devices.
+//!
+//! A swap area is a block device formatted by [`mkswap`] with the Linux `SWAPSPACE2` header
+//! in block 0 and enabled with [`swapon`]; every other block is a slot holding one page.
+//! Areas are used in the order they were enabled.
+//!
+//! Paging out replaces a page's leaf entry with a [`SwapEntry`]: an invalid entry (bit 0, the
+//! valid bit on every port, clear) that records the area and slot, which the hardware ignores
+//! and the page fault handler recognises. [`handle_fault`] reads the page back into a fresh
+//! frame, charged to the process's control group, and maps it again.
+//!
+//! Victims are picked by second chance over a process's data, heap and stack: a page whose
+//! accessed bit is set has the bit cleared and is skipped, one whose bit is still clear on the
+//! next pass is paged out; frames `fork` left shared (see [`cow`]) are never taken. Installed
+//! as the memory controller's `AnonSwap` hook, this is what lets the kernel promise more
+//! memory than it has (see `vm.overcommit_memory` in [`memory::check_commit`]).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch::{self, Arch, Current};
+use crate::os::block::{BLOCK_SIZE, BlockDevice};
+use crate::os::capability::{self, Capability};
+use crate::os::cgroup::{self, AnonSwap, GroupId, PageKind};
+use crate::os::cow;
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::numa::Placement;
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::random;
+use crate::os::tlb;
+use crate::os::zram;
+
+/// Maximum number of areas enabled at once.
+pub const MAX_AREAS: usize = 4;
+
+/// Most slots used on one area (128 MiB of pages); larger devices are only used up to here.
+pub const MAX_SLOTS: usize = 32768;
+
+/// Most bad blocks the header can list.
+pub const MAX_BAD_PAGES: usize = (BLOCK_SIZE - 10 - BAD_PAGES_OFFSET) / 4;
+
+// SWAPSPACE2 header layout in block 0: the magic ends the block, the fields start at 1 KiB
+// (leaving room for a boot block) and the bad block list at 1.5 KiB
+const MAGIC: &[u8; 10] = b"SWAPSPACE2";
+const VERSION: u32 = 1;
+const VERSION_OFFSET: usize = 1024;
+const LAST_PAGE_OFFSET: usize = 1028;
+const BAD_COUNT_OFFSET: usize = 1032;
+const UUID_OFFSET: usize = 1036;
+const BAD_PAGES_OFFSET: usize = 1536;
+
+// Swap entries in page tables: valid bit clear, bit 1 set, area in bits 2-5, slot from bit 12
+const VALID: u64 = 1 << 0;
+const SWAP_MARKER: u64 = 1 << 1;
+const AREA_SHIFT: u64 = 2;
+const AREA_MASK: u64 = 0xf;
+const SLOT_SHIFT: u64 = 12;
+
+// =========================================================================
+// Format
+// =========================================================================
+
+fn u32_at(block: &[u8; BLOCK_SIZE], offset: usize) -> u32 {
+    u32::from_le_bytes([block[offset], block[offset + 1], block[offset + 2], block[offset + 3]])
+}
+
+fn put_u32(block: &mut [u8; BLOCK_SIZE], offset: usize, value: u32) {
+    block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Writes a swap header to block 0 of `device`, making every other block (up to
+/// [`MAX_SLOTS`]) a slot, and returns the number of slots. Fails with `EINVAL` if the device
+/// has no room for a single slot.
+pub fn mkswap(device: &dyn BlockDevice) -> KResult<u32> {
+    let pages = device.blocks().min(MAX_SLOTS as u64) as u32;
+    if pages < 2 {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut header = [0u8; BLOCK_SIZE];
+    put_u32(&mut header, VERSION_OFFSET, VERSION);
+    put_u32(&mut header, LAST_PAGE_OFFSET, pages - 1);
+    put_u32(&mut header, BAD_COUNT_OFFSET, 0);
+    random::fill_bytes(&mut header[UUID_OFFSET..UUID_OFFSET + 16]);
+    header[BLOCK_SIZE - MAGIC.len()..].copy_from_slice(MAGIC);
+
+    device.write_block(0, &header)?;
+    Ok(pages - 1)
+}
+
+// =========================================================================
+// Areas
+// =========================================================================
+
+/// Where a paged-out page lives: a slot of an enabled area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapEntry {
+    pub area: u8,
+    pub slot: u32,
+}
+
+impl SwapEntry {
+    /// The page table entry standing in for the page while it is swapped out.
+    pub fn to_pte(self) -> u64 {
+        SWAP_MARKER | (self.area as u64) << AREA_SHIFT | (self.slot as u64) << SLOT_SHIFT
+    }
+
+    /// The swap entry `pte` holds, if it is one.
+    pub fn from_pte(pte: u64) -> Option<Self> {
+        if pte & (VALID | SWAP_MARKER) != SWAP_MARKER {
+            return None;
+        }
+
+        Some(SwapEntry { area: ((pte >> AREA_SHIFT) & AREA_MASK) as u8, slot: (pte >> SLOT_SHIFT) as u32 })
+    }
+}
+
+struct Area {
+    device: &'static dyn BlockDevice,
+
+    // Blocks in use on the device, the header included
+    pages: u32,
+
+    // Slots taken by the header, bad blocks and swapped pages
+    used: [u64; MAX_SLOTS / 64],
+
+    // Of those, slots holding pages
+    in_use: u32,
+}
+
+impl Area {
+    fn is_used(&self, slot: u32) -> bool {
+        self.used[slot as usize / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn set_used(&mut self, slot: u32, used: bool) {
+        if used {
+            self.used[slot as usize / 64] |= 1 << (slot % 64);
+        } else {
+            self.used[slot as usize / 64] &= !(1 << (slot % 64));
+        }
+    }
+
+    fn free_slots(&self) -> u32 {
+        (0..self.pages).filter(|&slot| !self.is_used(slot)).count() as u32
+    }
+}
+
+static mut AREAS: [Option<Area>; MAX_AREAS] = [const { None }; MAX_AREAS];
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the area table holding its lock, with interrupts masked so a holder cannot be
+// interrupted by something spinning on it. Device I/O happens outside it.
+fn locked<R>(f: impl FnOnce(&mut [Option<Area>; MAX_AREAS]) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let areas = &raw mut AREAS;
+            f(&mut *areas)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+static SWAPPER: Swapper = Swapper;
+
+// Checks the header on `device` and adds it as an area
+fn enable(device: &'static dyn BlockDevice) -> KResult<u8> {
+    let mut header = [0u8; BLOCK_SIZE];
+    device.read_block(0, &mut header)?;
+
+    if header[BLOCK_SIZE - MAGIC.len()..] != *MAGIC || u32_at(&header, VERSION_OFFSET) != VERSION {
+        return Err(Errno::EINVAL);
+    }
+
+    let last_page = u32_at(&header, LAST_PAGE_OFFSET) as u64;
+    let pages = (last_page + 1).min(device.blocks()).min(MAX_SLOTS as u64) as u32;
+    let bad_count = u32_at(&header, BAD_COUNT_OFFSET) as usize;
+    if pages < 2 || bad_count > MAX_BAD_PAGES {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut area = Area { device, pages, used: [0; MAX_SLOTS / 64], in_use: 0 };
+    area.set_used(0, true);
+    for bad in 0..bad_count {
+        let page = u32_at(&header, BAD_PAGES_OFFSET + 4 * bad);
+        if page < pages {
+            area.set_used(page, true);
+        }
+    }
+
+    let index = locked(|areas| {
+        let device = device as *const dyn BlockDevice;
+        if areas.iter().flatten().any(|a| core::ptr::addr_eq(a.device as *const dyn BlockDevice, device)) {
+            return Err(Errno::EBUSY);
+        }
+
+        let index = areas.iter().position(Option::is_none).ok_or(Errno::ENOSPC)?;
+        areas[index] = Some(area);
+        Ok(index as u8)
+    })?;
+
+    cgroup::set_anon_swap(&SWAPPER);
+    log::info!("swap: {} enabled as area {}, {} KiB", device.name(), index, (pages as u64 - 1) * FRAME_SIZE / 1024);
+    Ok(index)
+}
+
+/// `swapon()`: starts paging out to `device`, which must hold a swap header, and returns the
+/// new area's index. Requires `CAP_SYS_ADMIN`; fails with `EINVAL` on a bad header, `EBUSY` if
+/// the device is already enabled and `ENOSPC` if [`MAX_AREAS`] are.
+pub fn swapon(device: &'static dyn BlockDevice, cred: &Credentials) -> KResult<u8> {
+    capability::require(cred, Capability::SysAdmin)?;
+    enable(device)
+}
+
+/// `swapoff()`: stops using area `area`. Requires `CAP_SYS_ADMIN`; fails with `EINVAL` if
+/// there is no such area and `EBUSY` while pages are still swapped out to it.
+pub fn swapoff(area: u8, cred: &Credentials) -> KResult<()> {
+    capability::require(cred, Capability::SysAdmin)?;
+
+    locked(|areas| {
+        let slot = areas.get_mut(area as usize).ok_or(Errno::EINVAL)?;
+        match slot {
+            None => Err(Errno::EINVAL),
+            Some(a) if a.in_use > 0 => Err(Errno::EBUSY),
+            Some(_) => {
+                *slot = None;
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Formats zram and enables it as the first swap area. Called once at boot.
+pub fn init() {
+    let result = mkswap(&zram::ZRAM).and_then(|_| enable(&zram::ZRAM));
+    if let Err(errno) = result {
+        log::warn!("swap: cannot enable zram0: {:?}", errno);
+    }
+}
+
+/// Swap space figures, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapStats {
+    /// Slots on every enabled area.
+    pub total: u64,
+
+    /// Slots free to page out to.
+    pub free: u64,
+}
+
+/// Current swap space figures.
+pub fn stats() -> SwapStats {
+    locked(|areas| {
+        let mut stats = SwapStats { total: 0, free: 0 };
+        for area in areas.iter().flatten() {
+            stats.total += (area.pages as u64 - 1) * FRAME_SIZE;
+            stats.free += area.free_slots() as u64 * FRAME_SIZE;
+        }
+        stats
+    })
+}
+
+// Takes the lowest free slot of the first area that has one
+fn alloc_slot() -> KResult<(SwapEntry, &'static dyn BlockDevice)> {
+    locked(|areas| {
+        for (index, area) in areas.iter_mut().enumerate() {
+            let Some(area) = area else {
+                continue;
+            };
+
+            if let Some(slot) = (1..area.pages).find(|&slot| !area.is_used(slot)) {
+                area.set_used(slot, true);
+                area.in_use += 1;
+                return Ok((SwapEntry { area: index as u8, slot }, area.device));
+            }
+        }
+
+        Err(Errno::ENOSPC)
+    })
+}
+
+fn device_of(entry: SwapEntry) -> KResult<&'static dyn BlockDevice> {
+    locked(|areas| match areas.get(entry.area as usize) {
+        Some(Some(area)) if entry.slot < area.pages && area.is_used(entry.slot) => Ok(area.device),
+        _ => Err(Errno::EINVAL),
+    })
+}
+
+/// Frees the slot behind `entry`, discarding its contents.
+pub fn free_slot(entry: SwapEntry) {
+    let device = locked(|areas| {
+        let area = areas.get_mut(entry.area as usize)?.as_mut()?;
+        if entry.slot == 0 || entry.slot >= area.pages || !area.is_used(entry.slot) {
+            return None;
+        }
+
+        area.set_used(entry.slot, false);
+        area.in_use -= 1;
+        Some(area.device)
+    });
+
+    if let Some(device) = device {
+        let _ = device.discard_block(entry.slot as u64);
+    }
+}
+
+// =========================================================================
+// Paging out and in
+// =========================================================================
+
+// Frames are identity-mapped, so the kernel reaches a page's contents at its physical address
+fn frame_bytes(frame: u64) -> *mut [u8; BLOCK_SIZE] {
+    frame as *mut [u8; BLOCK_SIZE]
+}
+
+// Moves the page `pte` maps to a free slot, leaving the slot's swap entry in `pte`, and returns
+// the frame that held it. `flush` runs between the unmap and the copy, so no CPU can still
+// write through a stale translation once the copy is taken.
+fn evict(pte: &mut u64, flush: impl FnOnce()) -> KResult<u64> {
+    let old = *pte;
+    if old & VALID == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let (entry, device) = alloc_slot()?;
+    *pte = entry.to_pte();
+    flush();
+
+    let frame = Current::entry_address(old);
+    if let Err(errno) = device.write_block(entry.slot as u64, unsafe { &*frame_bytes(frame) }) {
+        *pte = old;
+        free_slot(entry);
+        return Err(errno);
+    }
+
+    Ok(frame)
+}
+
+// Reads the page behind the swap entry in `pte` into `frame`, maps it there as user data and
+// frees the slot
+fn restore(pte: &mut u64, frame: u64) -> KResult<()> {
+    let entry = SwapEntry::from_pte(*pte).ok_or(Errno::EINVAL)?;
+    device_of(entry)?.read_block(entry.slot as u64, unsafe { &mut *frame_bytes(frame) })?;
+
+    // Only data, heap and stack pages are swapped, all of them writable and not executable
+    *pte = Current::page_entry(frame, Current::page_flags(true, false, true));
+    free_slot(entry);
+    Ok(())
+}
+
+/// Pages out the page mapped at `virt` in `process`'s address space, frees its frame and
+/// uncharges it from the process's group. Fails with `EFAULT` if no page table covers `virt`,
+/// `EINVAL` if nothing is mapped there and `ENOSPC` if every swap slot is taken.
+pub fn page_out(process: &Process, virt: u64) -> KResult<()> {
+    let root = process.page_table_root as u64;
+    let pte = Current::leaf_entry(root, virt).ok_or(Errno::EFAULT)?;
+
+    let frame = evict(unsafe { &mut *pte }, || tlb::shootdown_page(Some(root), virt))?;
+    if let Some(allocator) = memory::frame_allocator() {
+        allocator.free_frame(frame);
+    }
+    cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+    Ok(())
+}
+
+/// Page fault hook: resolves a fault at `virt` in `process`'s address space that swap is
+/// responsible for, returning whether it was one. A swapped-out page is read back into a new
+/// frame charged to the process's group (failing with `ENOMEM` if none can be had); a page
+/// whose accessed bit was cleared by reclaim, on CPUs that fault rather than set it, gets the
+/// bit back.
+pub fn handle_fault(process: &Process, virt: u64) -> KResult<bool> {
+    let Some(pte) = Current::leaf_entry(process.page_table_root as u64, virt) else {
+        return Ok(false);
+    };
+    let pte = unsafe { &mut *pte };
+
+    if *pte & VALID != 0 {
+        if *pte & Current::accessed_flag() != 0 {
+            return Ok(false);
+        }
+
+        *pte |= Current::accessed_flag();
+        Current::flush_tlb_page(virt);
+        return Ok(true);
+    }
+
+    if SwapEntry::from_pte(*pte).is_none() {
+        return Ok(false);
+    }
+
+    cgroup::charge(process.cgroup, PageKind::Anon, 1)?;
+    let Some(frame) = memory::frame_allocator().and_then(|allocator| allocator.alloc_frame(Placement::Local)) else {
+        cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+        return Err(Errno::ENOMEM);
+    };
+
+    if let Err(errno) = restore(pte, frame) {
+        if let Some(allocator) = memory::frame_allocator() {
+            allocator.free_frame(frame);
+        }
+        cgroup::uncharge(process.cgroup, PageKind::Anon, 1);
+        return Err(errno);
+    }
+
+    Ok(true)
+}
+
+// =========================================================================
+// Reclaim
+// =========================================================================
+
+/// Pages out up to `pages` of `process`'s anonymous pages not accessed since the last pass,
+/// clearing the accessed bit of the others so a later pass can take them. Returns how many
+/// were paged out.
+pub fn reclaim_process(process: &Process, pages: u64) -> u64 {
+    let accessed = Current::accessed_flag();
+    let mut done = 0;
+
+    memory::for_each_anonymous_entry(process, |virt, pte| {
+        // Paging out one mapping of a shared frame would leave the others behind
+        if *pte & VALID == 0 || cow::is_shared(Current::entry_address(*pte)) {
+            return true;
+        }
+
+        // Without a flush a CPU may keep using its cached translation and not set the bit
+        // again; the page then only looks colder than it is
+        if *pte & accessed != 0 {
+            *pte &= !accessed;
+        } else if page_out(process, virt).is_ok() {
+            done += 1;
+        }
+        done < pages
+    });
+
+    done
+}
+
+/// Frees the swap slots of `process`'s paged-out pages. Called when its address space is torn
+/// down.
+pub fn release(process: &Process) {
+    memory::for_each_anonymous_entry(process, |_, pte| {
+        if let Some(entry) = SwapEntry::from_pte(*pte) {
+            free_slot(entry);
+            *pte = 0;
+        }
+        true
+    });
+}
+
+// The memory controller's swap hook
+struct Swapper;
+
+impl AnonSwap for Swapper {
+    fn swap_out(&self, group: GroupId, pages: u64) -> u64 {
+        let mut done = 0;
+
+        // The first round may only clear accessed bits; the second then finds victims even
+        // when every page was touched since the last reclaim
+        for _ in 0..2 {
+            ptable::for_each(|process| {
+                if done < pages && cgroup::is_within(process.cgroup, group) {
+                    done += reclaim_process(process, pages - done);
+                }
+            });
+
+            if done >= pages {
+                break;
+            }
+        }
+
+        done
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    const DISK_BLOCKS: usize = 16;
+
+    // A small RAM disk, so the tests never touch the areas in real use
+    struct TestDisk;
+
+    static mut TEST_BLOCKS: [[u8; BLOCK_SIZE]; DISK_BLOCKS] = [[0; BLOCK_SIZE]; DISK_BLOCKS];
+
+    static TEST_DISK: TestDisk = TestDisk;
+
+    fn blocks() -> &'static mut [[u8; BLOCK_SIZE]; DISK_BLOCKS] {
+        unsafe {
+            let blocks = &raw mut TEST_BLOCKS;
+            &mut *blocks
+        }
+    }
+
+    impl BlockDevice for TestDisk {
+        fn name(&self) -> &str {
+            "ktest"
+        }
+
+        fn blocks(&self) -> u64 {
+            DISK_BLOCKS as u64
+        }
+
+        fn read_block(&self, index: u64, buf: &mut [u8; BLOCK_SIZE]) -> KResult<()> {
+            buf.copy_from_slice(blocks().get(index as usize).ok_or(Errno::EIO)?);
+            Ok(())
+        }
+
+        fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> KResult<()> {
+            blocks().get_mut(index as usize).ok_or(Errno::EIO)?.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[repr(C, align(4096))]
+    struct Page([u8; BLOCK_SIZE]);
+
+    static mut PAGE: Page = Page([0; BLOCK_SIZE]);
+
+    fn page() -> &'static mut [u8; BLOCK_SIZE] {
+        unsafe {
+            let page = &raw mut PAGE;
+            &mut (*page).0
+        }
+    }
+
+    fn admin() -> Credentials {
+        Process::new(0, 0, "swap").cred
+    }
+
+    // Enables the test disk as the only area with free slots, returning it
+    fn enable_test_disk() -> u8 {
+        assert_eq!(mkswap(&TEST_DISK), Ok(DISK_BLOCKS as u32 - 1));
+        swapon(&TEST_DISK, &admin()).unwrap()
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn header_is_checked() {
+            let cred = admin();
+            blocks()[0].fill(0);
+            assert_eq!(swapon(&TEST_DISK, &cred), Err(Errno::EINVAL));
+
+            let before = stats();
+            let area = enable_test_disk();
+            assert_eq!(&blocks()[0][BLOCK_SIZE - 10..], b"SWAPSPACE2");
+            assert_eq!(swapon(&TEST_DISK, &cred), Err(Errno::EBUSY));
+            assert_eq!(stats().total, before.total + (DISK_BLOCKS as u64 - 1) * FRAME_SIZE);
+
+            let mut user = admin();
+            user.cap_effective.remove(Capability::SysAdmin);
+            assert_eq!(swapoff(area, &user), Err(Errno::EPERM));
+            swapoff(area, &cred).unwrap();
+            assert_eq!(stats(), before);
+        }
+
+        fn entries_round_trip_through_ptes() {
+            let entry = SwapEntry { area: 3, slot: 0x12345 };
+            let pte = entry.to_pte();
+
+            assert_eq!(pte & VALID, 0);
+            assert_eq!(SwapEntry::from_pte(pte), Some(entry));
+            assert_eq!(SwapEntry::from_pte(0), None);
+            assert_eq!(SwapEntry::from_pte(Current::page_entry(0x5000, Current::page_flags(true, false, true))), None);
+        }
+
+        fn pages_come_back_from_swap() {
+            let area = enable_test_disk();
+            let frame = page().as_ptr() as u64;
+            let flags = Current::page_flags(true, false, true);
+            let mut pte = Current::page_entry(frame, flags);
+
+            for (i, byte) in page().iter_mut().enumerate() {
+                *byte = i as u8 ^ 0x5a;
+            }
+            let mut flushed = false;
+            assert_eq!(evict(&mut pte, || flushed = true), Ok(frame));
+            assert!(flushed);
+
+            // The slot may be on any area, zram included; it is busy until the page is back
+            let entry = SwapEntry::from_pte(pte).unwrap();
+            assert_eq!(swapoff(entry.area, &admin()), Err(Errno::EBUSY));
+
+            page().fill(0);
+            restore(&mut pte, frame).unwrap();
+            assert_eq!(pte, Current::page_entry(frame, flags));
+            assert!(page().iter().enumerate().all(|(i, &byte)| byte == i as u8 ^ 0x5a));
+            assert_eq!(device_of(entry).err(), Some(Errno::EINVAL));
+
+            swapoff(area, &admin()).unwrap();
+        }
+
+        fn unmapped_pages_are_not_evicted() {
+            let mut pte = 0;
+            assert_eq!(evict(&mut pte, || ()), Err(Errno::EINVAL));
+            assert_eq!(restore(&mut pte, page().as_ptr() as u64), Err(Errno::EINVAL));
+        }
+    }
+}
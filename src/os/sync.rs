@@ -0,0 +1,312 @@
+//! Kernel synchronization primitives.
+//!
+//! [`SpinLock`] guards data that interrupt handlers touch too, or that is only held for a few
+//! instructions: the CPU spins until the lock is free, with interrupts masked for as long as
+//! it is held so a holder is never interrupted by something spinning on the same lock.
+//!
+//! [`Mutex`] and [`Semaphore`] are for longer waits in process context. A process that cannot
+//! have one is parked with [`sched::block_on`]: blocked on a [`WaitTarget`] naming the
+//! primitive, and woken when it is released. Each primitive takes an ID the first time it is
+//! used. Holders are reported to the [`deadlock`] detector, so cycles of processes waiting on
+//! each other can be traced. Before the scheduler runs, and in a CPU's idle process, waiting
+//! spins instead.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use crate::os::arch::{Arch, Current};
+use crate::os::deadlock;
+use crate::os::percpu;
+use crate::os::process::WaitTarget;
+use crate::os::sched;
+
+// Next ID to give a mutex or semaphore; 0 means none yet
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+// The ID in `slot`, given out now if it has none
+fn id_of(slot: &AtomicU32) -> u32 {
+    match slot.load(Ordering::Relaxed) {
+        0 => {
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            match slot.compare_exchange(0, id, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => id,
+                Err(other) => other,
+            }
+        }
+        id => id,
+    }
+}
+
+/// A spinning lock around a `T`, held with interrupts masked.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// The value is only reached through the lock
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
+    }
+
+    /// Masks interrupts and spins until the lock is free, returning a guard that releases it,
+    /// and restores the interrupt state, when dropped.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts = Current::interrupts_enabled();
+        Current::disable_interrupts();
+
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard { lock: self, interrupts }
+    }
+
+    /// Takes the lock if it is free, masking interrupts as [`lock`](Self::lock) does.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts = Current::interrupts_enabled();
+        Current::disable_interrupts();
+
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            return Some(SpinLockGuard { lock: self, interrupts });
+        }
+        if interrupts {
+            Current::enable_interrupts();
+        }
+        None
+    }
+
+    /// Runs `f` on the value with the lock held.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+}
+
+/// Access to a [`SpinLock`]'s value, for as long as it is held.
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    interrupts: bool,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts {
+            Current::enable_interrupts();
+        }
+    }
+}
+
+/// A lock around a `T` that parks the processes waiting for it.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    id: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+// The value is only reached through the lock
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex { locked: AtomicBool::new(false), id: AtomicU32::new(0), value: UnsafeCell::new(value) }
+    }
+
+    fn target(&self) -> WaitTarget {
+        WaitTarget::Mutex(id_of(&self.id))
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        deadlock::note_acquired(self.target(), percpu::current_pid());
+        MutexGuard { lock: self }
+    }
+
+    /// Takes the lock, parking the calling process until it is free, and returns a guard that
+    /// releases it when dropped.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        sched::block_on(self.target(), || self.acquire());
+        self.guard()
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire().then(|| self.guard())
+    }
+}
+
+/// Access to a [`Mutex`]'s value, for as long as it is held.
+pub struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let target = self.lock.target();
+        deadlock::note_released(target, percpu::current_pid());
+
+        self.lock.locked.store(false, Ordering::Release);
+        sched::wake_one(target);
+    }
+}
+
+/// A counting semaphore: [`down`](Self::down) takes a unit, parking the calling process while
+/// there is none, and [`up`](Self::up) gives one back.
+pub struct Semaphore {
+    count: AtomicUsize,
+    id: AtomicU32,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore { count: AtomicUsize::new(count), id: AtomicU32::new(0) }
+    }
+
+    fn target(&self) -> WaitTarget {
+        WaitTarget::Semaphore(id_of(&self.id))
+    }
+
+    /// Takes a unit if one is left, returning whether it did.
+    pub fn try_down(&self) -> bool {
+        let taken = self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1)).is_ok();
+        if taken {
+            deadlock::note_acquired(self.target(), percpu::current_pid());
+        }
+        taken
+    }
+
+    /// Takes a unit, parking the calling process until one is given back.
+    pub fn down(&self) {
+        sched::block_on(self.target(), || self.try_down());
+    }
+
+    /// Gives a unit back, waking a process waiting for one.
+    pub fn up(&self) {
+        let target = self.target();
+        deadlock::note_released(target, percpu::current_pid());
+
+        self.count.fetch_add(1, Ordering::Release);
+        sched::wake_one(target);
+    }
+
+    /// Units left.
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+pub mod ktests {
+    use core::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    use crate::os::kthread;
+    use crate::os::ptable;
+
+    static SHARED: Mutex<u64> = Mutex::new(0);
+    static READY: Semaphore = Semaphore::new(0);
+    static PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+    // Waits for the test to hand over a unit, then adds to the counter under the mutex
+    fn worker() {
+        READY.down();
+        *SHARED.lock() += 1;
+        PROGRESS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn spinlocks_restore_the_interrupt_state() {
+            let lock = SpinLock::new(1u32);
+            let enabled = Current::interrupts_enabled();
+
+            {
+                let mut value = lock.lock();
+                assert!(!Current::interrupts_enabled());
+                *value += 1;
+            }
+            assert_eq!(Current::interrupts_enabled(), enabled);
+            assert_eq!(lock.with(|value| *value), 2);
+
+            let held = lock.try_lock();
+            assert!(held.is_some() && lock.try_lock().is_none());
+            drop(held);
+            assert_eq!(Current::interrupts_enabled(), enabled);
+        }
+
+        fn mutexes_exclude_and_semaphores_count() {
+            let semaphore = Semaphore::new(2);
+            assert!(semaphore.try_down() && semaphore.try_down());
+            assert!(!semaphore.try_down());
+            semaphore.up();
+            assert_eq!(semaphore.count(), 1);
+
+            let mutex = Mutex::new(());
+            let guard = mutex.try_lock();
+            assert!(guard.is_some());
+            assert!(mutex.try_lock().is_none());
+            drop(guard);
+            assert!(mutex.try_lock().is_some());
+        }
+
+        fn waiters_are_parked_and_woken() {
+            PROGRESS.store(0, Ordering::Relaxed);
+            let pids = [kthread::spawn_kthread(worker, 0).unwrap(), kthread::spawn_kthread(worker, 0).unwrap()];
+            let parked = |pid| ptable::with_process(pid, |p| p.waiting_on == Some(READY.target())) == Some(true);
+            while !pids.iter().all(|&pid| parked(pid)) {
+                sched::yield_now();
+            }
+
+            // The mutex is held while both workers are let go, so they queue up on it too
+            let before = *SHARED.lock();
+            {
+                let _held = SHARED.lock();
+                READY.up();
+                READY.up();
+                for _ in 0..8 {
+                    sched::yield_now();
+                }
+                assert_eq!(PROGRESS.load(Ordering::Relaxed), 0);
+            }
+
+            while PROGRESS.load(Ordering::Relaxed) < 2 {
+                sched::yield_now();
+            }
+            assert_eq!(*SHARED.lock(), before + 2);
+        }
+    }
+}
@@ -0,0 +1,1290 @@
+//! System calls: the table user space calls into.
+//!
+//! The architecture's entry path (`syscall` on x86_64, see
+//! [`arch::x86_64::syscall`](crate::os::arch::x86_64::syscall)) saves the user state on the
+//! calling process's kernel stack and hands [`dispatch`] the syscall number and six arguments.
+//! The dispatcher counts the call, lets the caller's seccomp filter veto it and runs the
+//! handler [`TABLE`] has for the number, turning an error into the `-errno` user space gets
+//! back. Numbers follow the x86_64 Linux ABI.
+//!
+//! Handlers are given the caller's PID rather than its PCB: the ones that give up the CPU
+//! (`nanosleep`, `clock_nanosleep`, `sched_yield`, `wait4`, `mq_send`, `mq_recv`, `sendto`,
+//! `recvfrom`, `getrandom`, `io_uring_enter`) or never return (`exit`) cannot hold on to it,
+//! and the rest look it up for just as long as they need it. A lookup holds the process
+//! table's lock with interrupts masked, and the lock is not reentrant, so user memory is
+//! copied before or after it, and whatever reaches other processes -- signals, sessions,
+//! path lookups -- works from fields copied out of it.
+
+#[cfg(target_arch = "x86_64")]
+use crate::os::arch::x86_64::signal as arch_signal;
+use crate::os::audit;
+#[cfg(target_arch = "x86_64")]
+use crate::os::brk;
+use crate::os::capability;
+use crate::os::console;
+use crate::os::cred;
+#[cfg(target_arch = "x86_64")]
+use crate::os::elf;
+use crate::os::errno::{Errno, KResult};
+use crate::os::exit;
+use crate::os::file;
+#[cfg(target_arch = "x86_64")]
+use crate::os::fork;
+use crate::os::hrtimer;
+use crate::os::ipc;
+use crate::os::itimer;
+use crate::os::jobctl;
+use crate::os::kthread;
+use crate::os::mmap;
+use crate::os::mount::{self, MAX_PATH};
+use crate::os::net::udp;
+use crate::os::percpu;
+use crate::os::pidns;
+use crate::os::prctl;
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::random;
+use crate::os::rlimit;
+use crate::os::sched;
+use crate::os::seccomp::{self, Verdict};
+use crate::os::signal;
+use crate::os::sysctl;
+use crate::os::timekeeping;
+use crate::os::tty;
+use crate::os::uaccess;
+use crate::os::uring;
+use crate::os::vfs;
+
+/// `read(fd, buf, len)`.
+pub const SYS_READ: u64 = 0;
+
+/// `write(fd, buf, len)`.
+pub const SYS_WRITE: u64 = 1;
+
+/// `open(path, flags, mode)`.
+pub const SYS_OPEN: u64 = 2;
+
+/// `close(fd)`.
+pub const SYS_CLOSE: u64 = 3;
+
+/// `lseek(fd, offset, whence)`.
+pub const SYS_LSEEK: u64 = 8;
+
+/// `mmap(addr, len, prot, flags, fd, offset)`.
+pub const SYS_MMAP: u64 = 9;
+
+/// `munmap(addr, len)`.
+pub const SYS_MUNMAP: u64 = 11;
+
+/// `brk(addr)`.
+pub const SYS_BRK: u64 = 12;
+
+/// `rt_sigaction(signal, act, oldact, sigsetsize)`.
+pub const SYS_RT_SIGACTION: u64 = 13;
+
+/// `rt_sigreturn()`.
+pub const SYS_RT_SIGRETURN: u64 = 15;
+
+/// `ioctl(fd, request, arg)`.
+pub const SYS_IOCTL: u64 = 16;
+
+/// `sched_yield()`.
+pub const SYS_SCHED_YIELD: u64 = 24;
+
+/// `msync(addr, len, flags)`.
+pub const SYS_MSYNC: u64 = 26;
+
+/// `dup(fd)`.
+pub const SYS_DUP: u64 = 32;
+
+/// `dup2(old, new)`.
+pub const SYS_DUP2: u64 = 33;
+
+/// `nanosleep(req, rem)`.
+pub const SYS_NANOSLEEP: u64 = 35;
+
+/// `getitimer(which, curr)`.
+pub const SYS_GETITIMER: u64 = 36;
+
+/// `alarm(seconds)`.
+pub const SYS_ALARM: u64 = 37;
+
+/// `setitimer(which, new, old)`.
+pub const SYS_SETITIMER: u64 = 38;
+
+/// `getpid()`.
+pub const SYS_GETPID: u64 = 39;
+
+/// `socket(domain, type, protocol)`.
+pub const SYS_SOCKET: u64 = 41;
+
+/// `sendto(fd, buf, len, flags, addr, addrlen)`.
+pub const SYS_SENDTO: u64 = 44;
+
+/// `recvfrom(fd, buf, len, flags, addr, addrlen)`.
+pub const SYS_RECVFROM: u64 = 45;
+
+/// `bind(fd, addr, addrlen)`.
+pub const SYS_BIND: u64 = 49;
+
+/// `fork()`.
+pub const SYS_FORK: u64 = 57;
+
+/// `exit(code)`.
+pub const SYS_EXIT: u64 = 60;
+
+/// `wait4(pid, status, options, rusage)`.
+pub const SYS_WAIT4: u64 = 61;
+
+/// `kill(pid, signal)`.
+pub const SYS_KILL: u64 = 62;
+
+/// `getrlimit(resource, rlim)`.
+pub const SYS_GETRLIMIT: u64 = 97;
+
+/// `setuid(uid)`.
+pub const SYS_SETUID: u64 = 105;
+
+/// `setgid(gid)`.
+pub const SYS_SETGID: u64 = 106;
+
+/// `setpgid(pid, pgid)`.
+pub const SYS_SETPGID: u64 = 109;
+
+/// `getppid()`.
+pub const SYS_GETPPID: u64 = 110;
+
+/// `setsid()`.
+pub const SYS_SETSID: u64 = 112;
+
+/// `setgroups(size, list)`.
+pub const SYS_SETGROUPS: u64 = 116;
+
+/// `getpgid(pid)`.
+pub const SYS_GETPGID: u64 = 121;
+
+/// `getsid(pid)`.
+pub const SYS_GETSID: u64 = 124;
+
+/// `capget(header, data)`.
+pub const SYS_CAPGET: u64 = 125;
+
+/// `capset(header, data)`.
+pub const SYS_CAPSET: u64 = 126;
+
+/// `setpriority(which, who, priority)`.
+pub const SYS_SETPRIORITY: u64 = 141;
+
+/// `pivot_root(new_root, put_old)`.
+pub const SYS_PIVOT_ROOT: u64 = 155;
+
+/// `sysctl(name, name_len, old, new)`. Takes the number of Linux's `_sysctl`, but names
+/// tunables by their dotted string (see [`sysctl`]).
+pub const SYS_SYSCTL: u64 = 156;
+
+/// `prctl(option, arg2)`.
+pub const SYS_PRCTL: u64 = 157;
+
+/// `setrlimit(resource, rlim)`.
+pub const SYS_SETRLIMIT: u64 = 160;
+
+/// `chroot(path)`.
+pub const SYS_CHROOT: u64 = 161;
+
+/// `mount(source, target, fs_type, flags, data)`.
+pub const SYS_MOUNT: u64 = 165;
+
+/// `umount2(target, flags)`.
+pub const SYS_UMOUNT2: u64 = 166;
+
+/// `timer_create(clock, sevp, timerid)`.
+pub const SYS_TIMER_CREATE: u64 = 222;
+
+/// `timer_settime(timerid, flags, new, old)`.
+pub const SYS_TIMER_SETTIME: u64 = 223;
+
+/// `timer_gettime(timerid, curr)`.
+pub const SYS_TIMER_GETTIME: u64 = 224;
+
+/// `timer_getoverrun(timerid)`.
+pub const SYS_TIMER_GETOVERRUN: u64 = 225;
+
+/// `timer_delete(timerid)`.
+pub const SYS_TIMER_DELETE: u64 = 226;
+
+/// `clock_settime(clock, tp)`.
+pub const SYS_CLOCK_SETTIME: u64 = 227;
+
+/// `clock_gettime(clock, tp)`.
+pub const SYS_CLOCK_GETTIME: u64 = 228;
+
+/// `clock_getres(clock, res)`.
+pub const SYS_CLOCK_GETRES: u64 = 229;
+
+/// `clock_nanosleep(clock, flags, req, rem)`.
+pub const SYS_CLOCK_NANOSLEEP: u64 = 230;
+
+/// `mq_create(capacity)`. The message queue calls take the numbers of Linux's POSIX queue
+/// calls, but name queues by kernel-assigned IDs (see [`ipc`]).
+pub const SYS_MQ_CREATE: u64 = 240;
+
+/// `mq_destroy(id)`.
+pub const SYS_MQ_DESTROY: u64 = 241;
+
+/// `mq_send(id, buf, len, flags)`.
+pub const SYS_MQ_SEND: u64 = 242;
+
+/// `mq_recv(id, buf, len, flags)`.
+pub const SYS_MQ_RECV: u64 = 243;
+
+/// `unshare(flags)`.
+pub const SYS_UNSHARE: u64 = 272;
+
+/// `set_syscall_filter(mode, list, count, action)`. Takes the number of Linux's `seccomp`, but
+/// installs a plain allow- or denylist (see [`seccomp`]).
+pub const SYS_SET_SYSCALL_FILTER: u64 = 317;
+
+/// `getrandom(buf, len, flags)`.
+pub const SYS_GETRANDOM: u64 = 318;
+
+/// `execveat(fd, path, argv, envp, flags)`.
+pub const SYS_EXECVEAT: u64 = 322;
+
+/// `io_uring_setup(entries, params)`.
+pub const SYS_IO_URING_SETUP: u64 = 425;
+
+/// `io_uring_enter(fd, to_submit, min_complete)`.
+pub const SYS_IO_URING_ENTER: u64 = 426;
+
+/// `audit_read(from, buf, count)`. Linux has no such call; numbers from here up are this
+/// kernel's own.
+pub const SYS_AUDIT_READ: u64 = 500;
+
+/// `adjtime(delta, olddelta)`, the BSD call; Linux reaches it through `adjtimex`.
+pub const SYS_ADJTIME: u64 = 501;
+
+/// `unshare` flags: a new mount namespace, and a new PID namespace for the caller's children.
+pub const CLONE_NEWNS: u64 = 0x0002_0000;
+pub const CLONE_NEWPID: u64 = 0x2000_0000;
+
+/// One past the highest syscall number the table has room for.
+pub const TABLE_SIZE: usize = 512;
+
+/// A syscall handler: the caller's PID and the six argument registers in, the value returned
+/// to user space out.
+pub type Handler = fn(caller: u64, args: [u64; 6]) -> KResult<u64>;
+
+/// Handlers by syscall number; numbers without one fail with `ENOSYS`.
+pub static TABLE: [Option<Handler>; TABLE_SIZE] = {
+    let mut table: [Option<Handler>; TABLE_SIZE] = [None; TABLE_SIZE];
+    table[SYS_READ as usize] = Some(sys_read);
+    table[SYS_WRITE as usize] = Some(sys_write);
+    table[SYS_OPEN as usize] = Some(sys_open);
+    table[SYS_CLOSE as usize] = Some(sys_close);
+    table[SYS_LSEEK as usize] = Some(sys_lseek);
+    table[SYS_MMAP as usize] = Some(sys_mmap);
+    table[SYS_MUNMAP as usize] = Some(sys_munmap);
+    table[SYS_RT_SIGACTION as usize] = Some(sys_rt_sigaction);
+    table[SYS_IOCTL as usize] = Some(sys_ioctl);
+    table[SYS_SCHED_YIELD as usize] = Some(sys_sched_yield);
+    table[SYS_MSYNC as usize] = Some(sys_msync);
+    table[SYS_DUP as usize] = Some(sys_dup);
+    table[SYS_DUP2 as usize] = Some(sys_dup2);
+    table[SYS_NANOSLEEP as usize] = Some(sys_nanosleep);
+    table[SYS_GETITIMER as usize] = Some(sys_getitimer);
+    table[SYS_ALARM as usize] = Some(sys_alarm);
+    table[SYS_SETITIMER as usize] = Some(sys_setitimer);
+    table[SYS_GETPID as usize] = Some(sys_getpid);
+    table[SYS_SOCKET as usize] = Some(sys_socket);
+    table[SYS_SENDTO as usize] = Some(sys_sendto);
+    table[SYS_RECVFROM as usize] = Some(sys_recvfrom);
+    table[SYS_BIND as usize] = Some(sys_bind);
+    table[SYS_EXIT as usize] = Some(sys_exit);
+    table[SYS_WAIT4 as usize] = Some(sys_wait4);
+    table[SYS_KILL as usize] = Some(sys_kill);
+    table[SYS_GETRLIMIT as usize] = Some(sys_getrlimit);
+    table[SYS_SETUID as usize] = Some(sys_setuid);
+    table[SYS_SETGID as usize] = Some(sys_setgid);
+    table[SYS_SETPGID as usize] = Some(sys_setpgid);
+    table[SYS_GETPPID as usize] = Some(sys_getppid);
+    table[SYS_SETSID as usize] = Some(sys_setsid);
+    table[SYS_SETGROUPS as usize] = Some(sys_setgroups);
+    table[SYS_GETPGID as usize] = Some(sys_getpgid);
+    table[SYS_GETSID as usize] = Some(sys_getsid);
+    table[SYS_CAPGET as usize] = Some(sys_capget);
+    table[SYS_CAPSET as usize] = Some(sys_capset);
+    table[SYS_SETPRIORITY as usize] = Some(sys_setpriority);
+    table[SYS_PIVOT_ROOT as usize] = Some(sys_pivot_root);
+    table[SYS_SYSCTL as usize] = Some(sys_sysctl);
+    table[SYS_PRCTL as usize] = Some(sys_prctl);
+    table[SYS_SETRLIMIT as usize] = Some(sys_setrlimit);
+    table[SYS_CHROOT as usize] = Some(sys_chroot);
+    table[SYS_MOUNT as usize] = Some(sys_mount);
+    table[SYS_UMOUNT2 as usize] = Some(sys_umount2);
+    table[SYS_TIMER_CREATE as usize] = Some(sys_timer_create);
+    table[SYS_TIMER_SETTIME as usize] = Some(sys_timer_settime);
+    table[SYS_TIMER_GETTIME as usize] = Some(sys_timer_gettime);
+    table[SYS_TIMER_GETOVERRUN as usize] = Some(sys_timer_getoverrun);
+    table[SYS_TIMER_DELETE as usize] = Some(sys_timer_delete);
+    table[SYS_CLOCK_SETTIME as usize] = Some(sys_clock_settime);
+    table[SYS_CLOCK_GETTIME as usize] = Some(sys_clock_gettime);
+    table[SYS_CLOCK_GETRES as usize] = Some(sys_clock_getres);
+    table[SYS_CLOCK_NANOSLEEP as usize] = Some(sys_clock_nanosleep);
+    table[SYS_MQ_CREATE as usize] = Some(sys_mq_create);
+    table[SYS_MQ_DESTROY as usize] = Some(sys_mq_destroy);
+    table[SYS_MQ_SEND as usize] = Some(sys_mq_send);
+    table[SYS_MQ_RECV as usize] = Some(sys_mq_recv);
+    table[SYS_UNSHARE as usize] = Some(sys_unshare);
+    table[SYS_SET_SYSCALL_FILTER as usize] = Some(sys_set_syscall_filter);
+    table[SYS_GETRANDOM as usize] = Some(sys_getrandom);
+    table[SYS_IO_URING_SETUP as usize] = Some(sys_io_uring_setup);
+    table[SYS_IO_URING_ENTER as usize] = Some(sys_io_uring_enter);
+    table[SYS_AUDIT_READ as usize] = Some(sys_audit_read);
+    table[SYS_ADJTIME as usize] = Some(sys_adjtime);
+    #[cfg(target_arch = "x86_64")]
+    {
+        table[SYS_BRK as usize] = Some(sys_brk);
+        table[SYS_RT_SIGRETURN as usize] = Some(sys_rt_sigreturn);
+        table[SYS_FORK as usize] = Some(sys_fork);
+        table[SYS_EXECVEAT as usize] = Some(sys_execveat);
+    }
+    table
+};
+
+// Exit status of a process its filter kills, there being no signals to kill it with yet
+// (128 + SIGSYS, as a shell reports it)
+const FILTER_KILL_STATUS: i32 = 128 + 31;
+
+// Bytes of user memory `read` and `write` copy at a time
+const IO_CHUNK: usize = 256;
+
+/// Runs syscall `nr` with arguments `args` for the process running on this CPU and returns
+/// what goes back to user space: the handler's result, or `-errno`. Called by the entry path
+/// with interrupts enabled.
+pub fn dispatch(nr: u64, args: [u64; 6]) -> i64 {
+    percpu::STATS.with(|stats| stats.syscalls += 1);
+    let caller = percpu::current_pid();
+
+    match ptable::with_process(caller, |process| seccomp::check(process, nr)) {
+        Some(Verdict::Allow) => {}
+        Some(Verdict::Deny(errno)) => return errno.as_syscall_return(),
+        Some(Verdict::Kill) => kthread::exit_current(FILTER_KILL_STATUS),
+        None => return Errno::ESRCH.as_syscall_return(),
+    }
+
+    let handler = usize::try_from(nr).ok().and_then(|nr| TABLE.get(nr).copied().flatten());
+    let result = match handler {
+        Some(handler) => handler(caller, args),
+        None => Err(Errno::ENOSYS),
+    };
+
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => errno.as_syscall_return(),
+    }
+}
+
+// Runs `f` on the caller's PCB
+fn with_caller<R>(caller: u64, f: impl FnOnce(&mut Process) -> KResult<R>) -> KResult<R> {
+    ptable::with_process(caller, f).unwrap_or(Err(Errno::ESRCH))
+}
+
+/// `write(fd, buf, len)`: writes `len` bytes from `buf` to descriptor `fd` at its file's
+/// offset and returns how many were written. Standard output and standard error go to the
+/// console while nothing is open on them. Fails with `EBADF` if `fd` is not open for writing
+/// and `EFAULT` if `buf` is not user memory.
+fn sys_write(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let [fd, buf, len, ..] = args.map(|arg| arg as usize);
+
+    let file = with_caller(caller, |process| match process.file(fd) {
+        Ok(file) => Ok(Some(file.clone())),
+        Err(_) if fd == 1 || fd == 2 => Ok(None),
+        Err(err) => Err(err),
+    })?;
+    if file.is_none() {
+        tty::check_write(caller)?;
+    }
+
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut written = 0;
+
+    while written < len {
+        let n = (len - written).min(IO_CHUNK);
+        let chunk = &mut chunk[..n];
+
+        if let Err(err) = uaccess::copy_from_user(chunk, buf + written) {
+            // Report what was already written; fault only if nothing was
+            return if written == 0 { Err(err) } else { Ok(written as u64) };
+        }
+
+        let Some(file) = &file else {
+            chunk.utf8_chunks().for_each(|text| {
+                console::_print(format_args!("{}", text.valid()));
+                if !text.invalid().is_empty() {
+                    console::_print(format_args!("{}", char::REPLACEMENT_CHARACTER));
+                }
+            });
+            written += n;
+            continue;
+        };
+
+        let done = match vfs::write(file, chunk) {
+            Ok(done) => done,
+            Err(err) if written == 0 => return Err(err),
+            Err(_) => break,
+        };
+        written += done;
+
+        if done < n {
+            break;
+        }
+    }
+
+    Ok(written as u64)
+}
+
+/// `read(fd, buf, len)`: reads up to `len` bytes from descriptor `fd` at its file's offset into
+/// `buf` and returns how many were read, 0 at the end of the file. Fails with `EBADF` if `fd`
+/// is not open for reading and `EFAULT` if `buf` is not user memory.
+fn sys_read(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let [fd, buf, len, ..] = args.map(|arg| arg as usize);
+
+    let file = with_caller(caller, |process| process.file(fd).cloned())?;
+    if !uaccess::is_user_range(buf, len) {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut read = 0;
+
+    while read < len {
+        let n = (len - read).min(IO_CHUNK);
+        let done = match vfs::read(&file, &mut chunk[..n]) {
+            Ok(done) => done,
+            Err(err) if read == 0 => return Err(err),
+            Err(_) => break,
+        };
+
+        uaccess::copy_to_user(buf + read, &chunk[..done])?;
+        read += done;
+
+        if done < n {
+            break;
+        }
+    }
+
+    Ok(read as u64)
+}
+
+/// `open(path, flags, mode)`: opens `path` and returns a new descriptor for it. `mode` is
+/// ignored, since nothing can be created through the VFS yet.
+fn sys_open(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    vfs::sys_open(caller, args[0] as usize, args[1] as u32).map(|fd| fd as u64)
+}
+
+/// `close(fd)`: drops descriptor `fd`.
+fn sys_close(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    // Closing a file may write it back or wake a socket's readers, so it happens unlocked
+    let file = with_caller(caller, |process| file::sys_close(process, args[0] as usize))?;
+    drop(file);
+    Ok(0)
+}
+
+/// `lseek(fd, offset, whence)`: moves the offset of descriptor `fd`'s file and returns it.
+fn sys_lseek(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| vfs::sys_lseek(process, args[0] as usize, args[1] as i64, args[2] as u32))
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`: maps a file or anonymous memory into the
+/// caller's address space and returns where.
+fn sys_mmap(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| {
+        mmap::sys_mmap(process, args[0] as usize, args[1] as usize, args[2] as u32, args[3] as u32, args[4] as usize, args[5])
+    })
+    .map(|addr| addr as u64)
+}
+
+/// `munmap(addr, len)`: removes the caller's mappings in `[addr, addr + len)`.
+fn sys_munmap(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| mmap::sys_munmap(process, args[0] as usize, args[1] as usize))?;
+    Ok(0)
+}
+
+/// `brk(addr)`: moves the caller's program break to `addr` and returns the break, which is
+/// the old one if it could not be moved.
+#[cfg(target_arch = "x86_64")]
+fn sys_brk(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| Ok(brk::sys_brk(process, args[0] as usize)))
+}
+
+/// `rt_sigaction(signal, act, oldact, sigsetsize)`: installs and reports signal handlers.
+fn sys_rt_sigaction(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    signal::sys_rt_sigaction(caller, args[0] as u32, args[1] as usize, args[2] as usize, args[3] as usize)?;
+    Ok(0)
+}
+
+/// `rt_sigreturn()`: resumes the code a signal handler interrupted; does not return.
+#[cfg(target_arch = "x86_64")]
+fn sys_rt_sigreturn(caller: u64, _args: [u64; 6]) -> KResult<u64> {
+    arch_signal::sigreturn(caller)
+}
+
+/// `ioctl(fd, request, arg)`: the console's job control requests. `fd` is not looked at, the
+/// console being the only terminal.
+fn sys_ioctl(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    tty::sys_ioctl(caller, args[1] as u32, args[2] as usize).map(|value| value as u64)
+}
+
+/// `sched_yield()`: lets the other runnable processes run first.
+fn sys_sched_yield(_caller: u64, _args: [u64; 6]) -> KResult<u64> {
+    sched::yield_now();
+    Ok(0)
+}
+
+/// `msync(addr, len, flags)`: writes back the caller's shared file mappings in
+/// `[addr, addr + len)`.
+fn sys_msync(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    mmap::sys_msync(caller, args[0] as usize, args[1] as usize, args[2] as u32)?;
+    Ok(0)
+}
+
+/// `dup(fd)`: a new descriptor for the file open as `fd`.
+fn sys_dup(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| file::sys_dup(process, args[0] as usize)).map(|fd| fd as u64)
+}
+
+/// `dup2(old, new)`: makes descriptor `new` refer to the file open as `old`.
+fn sys_dup2(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let [old, new, ..] = args.map(|arg| arg as usize);
+
+    // As for close, whatever was open as `new` is dropped unlocked
+    let replaced = with_caller(caller, |process| file::sys_dup2(process, old, new))?;
+    drop(replaced);
+    Ok(new as u64)
+}
+
+/// `nanosleep(req, rem)`: sleeps for the `struct timespec` at `req`.
+fn sys_nanosleep(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    hrtimer::sys_nanosleep(caller, args[0] as usize, args[1] as usize)?;
+    sched::wait_out_sleep(caller);
+    Ok(0)
+}
+
+/// `getitimer(which, curr)`: reports the caller's interval timer.
+fn sys_getitimer(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    itimer::sys_getitimer(caller, args[0] as u32, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `alarm(seconds)`: raises `SIGALRM` after `seconds`; returns the seconds left on the last
+/// alarm.
+fn sys_alarm(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| itimer::sys_alarm(process, args[0] as u32))
+}
+
+/// `setitimer(which, new, old)`: sets the caller's interval timer.
+fn sys_setitimer(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    itimer::sys_setitimer(caller, args[0] as u32, args[1] as usize, args[2] as usize)?;
+    Ok(0)
+}
+
+/// `getpid()`: the caller's PID in its own namespace.
+fn sys_getpid(caller: u64, _args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| Ok(pidns::sys_getpid(process)))
+}
+
+/// `socket(domain, type, protocol)`: creates a UDP socket and returns a descriptor for it.
+fn sys_socket(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    udp::sys_socket(caller, args[0] as u32, args[1] as u32, args[2] as u32).map(|fd| fd as u64)
+}
+
+/// `sendto(fd, buf, len, flags, addr, addrlen)`: sends the `len` bytes at `buf` as one
+/// datagram to the address at `addr`; may wait for the destination's MAC address.
+fn sys_sendto(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let socket = with_caller(caller, |process| udp::socket_of(process, args[0] as usize))?;
+    Ok(udp::sys_sendto(socket, args[1] as usize, args[2] as usize, args[3] as u32, args[4] as usize, args[5] as usize)? as u64)
+}
+
+/// `recvfrom(fd, buf, len, flags, addr, addrlen)`: receives a datagram into the `len` bytes at
+/// `buf`, waiting for one unless `MSG_DONTWAIT` is given.
+fn sys_recvfrom(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let socket = with_caller(caller, |process| udp::socket_of(process, args[0] as usize))?;
+    Ok(udp::sys_recvfrom(socket, args[1] as usize, args[2] as usize, args[3] as u32, args[4] as usize, args[5] as usize)? as u64)
+}
+
+/// `bind(fd, addr, addrlen)`: binds socket `fd` to the local port at `addr`.
+fn sys_bind(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let (socket, cred) = with_caller(caller, |process| Ok((udp::socket_of(process, args[0] as usize)?, process.cred)))?;
+    udp::sys_bind(&cred, socket, args[1] as usize, args[2] as usize)?;
+    Ok(0)
+}
+
+/// `exit(code)`: ends the caller with exit code `code`.
+fn sys_exit(_caller: u64, args: [u64; 6]) -> KResult<u64> {
+    kthread::exit_current(args[0] as i32)
+}
+
+/// `wait4(pid, status, options, rusage)`: collects an exited child, blocking until one exits
+/// unless `options` has `WNOHANG`; `rusage` is not filled in.
+fn sys_wait4(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    // pid_t is an int, whatever the upper half of the register holds
+    let pid = args[0] as i32 as i64;
+
+    let options = args[2] as u32;
+
+    loop {
+        let child = exit::sys_wait4(caller, pid, args[1] as usize, options)?;
+        if child != 0 || options & exit::WNOHANG != 0 {
+            return Ok(child);
+        }
+
+        // Woken by the exit of a child it waits for; the call runs again to collect it
+        sched::schedule();
+    }
+}
+
+/// `kill(pid, signal)`: sends `signal` to a process or process group.
+fn sys_kill(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    jobctl::sys_kill(caller, args[0] as i32 as i64, args[1] as u32)?;
+    Ok(0)
+}
+
+/// `getrlimit(resource, rlim)`: reports one of the caller's resource limits.
+fn sys_getrlimit(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    rlimit::sys_getrlimit(caller, args[0] as u32, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `setuid(uid)`: sets the caller's user IDs.
+fn sys_setuid(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| cred::setuid(process, args[0] as u32))?;
+    Ok(0)
+}
+
+/// `setgid(gid)`: sets the caller's group IDs.
+fn sys_setgid(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| cred::setgid(process, args[0] as u32))?;
+    Ok(0)
+}
+
+/// `setpgid(pid, pgid)`: moves the caller or a child into a process group.
+fn sys_setpgid(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    jobctl::sys_setpgid(caller, args[0] as i32 as i64, args[1] as i32 as i64)?;
+    Ok(0)
+}
+
+/// `getppid()`: the caller's parent's PID in the caller's namespace.
+fn sys_getppid(caller: u64, _args: [u64; 6]) -> KResult<u64> {
+    pidns::sys_getppid(caller)
+}
+
+/// `setsid()`: starts a new session led by the caller and returns its ID.
+fn sys_setsid(caller: u64, _args: [u64; 6]) -> KResult<u64> {
+    jobctl::sys_setsid(caller)
+}
+
+/// `setgroups(size, list)`: replaces the caller's supplementary groups.
+fn sys_setgroups(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    cred::sys_setgroups(caller, args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `getpgid(pid)`: the process group of process `pid`, or of the caller for 0.
+fn sys_getpgid(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    jobctl::sys_getpgid(caller, args[0] as i32 as i64)
+}
+
+/// `getsid(pid)`: the session of process `pid`, or of the caller for 0.
+fn sys_getsid(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    jobctl::sys_getsid(caller, args[0] as i32 as i64)
+}
+
+/// `capget(header, data)`: copies a process's capability sets to `data`.
+fn sys_capget(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    capability::sys_capget(caller, args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `capset(header, data)`: replaces the caller's capability sets.
+fn sys_capset(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    capability::sys_capset(caller, args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `setpriority(which, who, priority)`: sets a process's scheduling priority.
+fn sys_setpriority(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    sched::sys_setpriority(caller, args[0] as u32, args[1], args[2] as u32)?;
+    Ok(0)
+}
+
+/// `pivot_root(new_root, put_old)`: swaps the root of the caller's mount namespace.
+fn sys_pivot_root(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let (mut new_root, mut put_old) = ([0u8; MAX_PATH], [0u8; MAX_PATH]);
+    let new_root = user_path(&mut new_root, args[0] as usize)?;
+    let put_old = user_path(&mut put_old, args[1] as usize)?;
+
+    // Every process in the namespace moves with the root, so the check runs on a copy
+    let process = with_caller(caller, |process| Ok(process.stand_in()))?;
+    mount::sys_pivot_root(&process, new_root, put_old)?;
+    Ok(0)
+}
+
+/// `sysctl(name, name_len, old, new)`: reads and sets a tunable.
+fn sys_sysctl(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let [name, name_len, old, new, ..] = args.map(|arg| arg as usize);
+
+    // Setting a tunable may apply it at length; only the credentials are needed for that
+    let cred = with_caller(caller, |process| Ok(process.cred))?;
+    sysctl::sys_sysctl(&cred, name, name_len, old, new)?;
+    Ok(0)
+}
+
+/// `prctl(option, arg2)`: gets or sets the caller's name.
+fn sys_prctl(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    prctl::sys_prctl(caller, args[0] as u32, args[1] as usize).map(|value| value as u64)
+}
+
+/// `setrlimit(resource, rlim)`: sets one of the caller's resource limits.
+fn sys_setrlimit(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    rlimit::sys_setrlimit(caller, args[0] as u32, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `chroot(path)`: makes `path` the root of the caller's lookups.
+fn sys_chroot(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let mut path = [0u8; MAX_PATH];
+    let path = user_path(&mut path, args[0] as usize)?;
+
+    with_caller(caller, |process| mount::sys_chroot(process, path))?;
+    Ok(0)
+}
+
+/// `mount(source, target, fs_type, flags, data)`: mounts a new instance of `fs_type` on
+/// `target`. Filesystems here are not backed by devices, so `source`, `flags` and `data` are
+/// not looked at.
+fn sys_mount(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let (mut target, mut fs_type) = ([0u8; MAX_PATH], [0u8; MAX_PATH]);
+    let target = user_path(&mut target, args[1] as usize)?;
+    let fs_type = user_path(&mut fs_type, args[2] as usize)?;
+
+    with_caller(caller, |process| mount::sys_mount(process, fs_type, target))?;
+    Ok(0)
+}
+
+/// `umount2(target, flags)`: detaches the topmost mount on `target`; no `flags` are supported.
+fn sys_umount2(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    if args[1] != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut target = [0u8; MAX_PATH];
+    let target = user_path(&mut target, args[0] as usize)?;
+
+    // A mount is busy while any process has its root under it, which takes a look at them all
+    let process = with_caller(caller, |process| Ok(process.stand_in()))?;
+    mount::sys_umount(&process, target)?;
+    Ok(0)
+}
+
+/// `timer_create(clock, sevp, timerid)`: creates a POSIX timer for the caller.
+fn sys_timer_create(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    itimer::sys_timer_create(caller, args[0] as u32, args[1] as usize, args[2] as usize)?;
+    Ok(0)
+}
+
+/// `timer_settime(timerid, flags, new, old)`: arms or disarms one of the caller's timers.
+fn sys_timer_settime(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    itimer::sys_timer_settime(caller, args[0] as usize, args[1] as u32, args[2] as usize, args[3] as usize)?;
+    Ok(0)
+}
+
+/// `timer_gettime(timerid, curr)`: reports one of the caller's timers.
+fn sys_timer_gettime(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    itimer::sys_timer_gettime(caller, args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `timer_getoverrun(timerid)`: the overrun count of one of the caller's timers.
+fn sys_timer_getoverrun(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| itimer::sys_timer_getoverrun(process, args[0] as usize)).map(|count| count as u64)
+}
+
+/// `timer_delete(timerid)`: removes one of the caller's timers.
+fn sys_timer_delete(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    with_caller(caller, |process| itimer::sys_timer_delete(process, args[0] as usize))?;
+    Ok(0)
+}
+
+/// `clock_settime(clock, tp)`: sets `clock` to the `struct timespec` at `tp`.
+fn sys_clock_settime(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let cred = with_caller(caller, |process| Ok(process.cred))?;
+    timekeeping::sys_clock_settime(&cred, args[0] as u32, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `clock_gettime(clock, tp)`: copies the time on `clock` to `tp`.
+fn sys_clock_gettime(_caller: u64, args: [u64; 6]) -> KResult<u64> {
+    timekeeping::sys_clock_gettime(args[0] as u32, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `clock_getres(clock, res)`: copies the resolution of `clock` to `res`.
+fn sys_clock_getres(_caller: u64, args: [u64; 6]) -> KResult<u64> {
+    timekeeping::sys_clock_getres(args[0] as u32, args[1] as usize)?;
+    Ok(0)
+}
+
+/// `clock_nanosleep(clock, flags, req, rem)`: sleeps until the `struct timespec` at `req` has
+/// passed on `clock`.
+fn sys_clock_nanosleep(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let [clock, flags, req, rem, ..] = args;
+    hrtimer::sys_clock_nanosleep(caller, clock as u32, flags as u32, req as usize, rem as usize)?;
+    sched::wait_out_sleep(caller);
+    Ok(0)
+}
+
+/// `mq_create(capacity)`: creates a message queue owned by the caller and returns its ID.
+fn sys_mq_create(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    Ok(ipc::mq_create(caller, args[0] as usize)? as u64)
+}
+
+/// `mq_destroy(id)`: destroys a message queue the caller created.
+fn sys_mq_destroy(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    ipc::mq_destroy(caller, args[0] as u32)?;
+    Ok(0)
+}
+
+/// `mq_send(id, buf, len, flags)`: sends a message, waiting for room unless told not to.
+fn sys_mq_send(_caller: u64, args: [u64; 6]) -> KResult<u64> {
+    ipc::sys_mq_send(args[0] as u32, args[1] as usize, args[2] as usize, args[3] as u32)?;
+    Ok(0)
+}
+
+/// `mq_recv(id, buf, len, flags)`: receives a message, waiting for one unless told not to;
+/// returns its length.
+fn sys_mq_recv(_caller: u64, args: [u64; 6]) -> KResult<u64> {
+    Ok(ipc::sys_mq_recv(args[0] as u32, args[1] as usize, args[2] as usize, args[3] as u32)? as u64)
+}
+
+/// `unshare(flags)`: moves the caller into a new mount namespace (`CLONE_NEWNS`) and its
+/// future children into a new PID namespace (`CLONE_NEWPID`). Fails with `EINVAL` for any
+/// other flag.
+fn sys_unshare(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let flags = args[0];
+    if flags & !(CLONE_NEWNS | CLONE_NEWPID) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    with_caller(caller, |process| {
+        if flags & CLONE_NEWPID != 0 {
+            pidns::sys_unshare_pid(process)?;
+        }
+        if flags & CLONE_NEWNS != 0 {
+            mount::sys_unshare_mount(process)?;
+        }
+        Ok(0)
+    })
+}
+
+/// `set_syscall_filter(mode, list, count, action)`: narrows the caller's syscall filter.
+fn sys_set_syscall_filter(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    seccomp::sys_set_syscall_filter(caller, args[0], args[1] as usize, args[2] as usize, args[3])?;
+    Ok(0)
+}
+
+/// `getrandom(buf, len, flags)`: fills `buf` with random bytes, waiting for the generator to
+/// be seeded unless `flags` say otherwise.
+fn sys_getrandom(_caller: u64, args: [u64; 6]) -> KResult<u64> {
+    Ok(random::sys_getrandom(args[0] as usize, args[1] as usize, args[2] as u32)? as u64)
+}
+
+/// `fork()`: creates a copy of the caller; the child's PID in the caller's namespace in the
+/// parent, 0 in the child.
+#[cfg(target_arch = "x86_64")]
+fn sys_fork(caller: u64, _args: [u64; 6]) -> KResult<u64> {
+    let ns = with_caller(caller, |process| Ok(process.pid_links.namespace()))?;
+    let child = fork::fork(caller)?;
+    Ok(pidns::from_global(ns, child).unwrap_or(0))
+}
+
+/// `execveat(fd, path, argv, envp, flags)`: runs the executable open as `fd` in place of the
+/// caller's image; returns into the new image rather than to the call.
+#[cfg(target_arch = "x86_64")]
+fn sys_execveat(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    elf::sys_execveat(caller, args[0] as usize, args[1] as usize, args[2] as usize, args[4] as u32)?;
+    Ok(0)
+}
+
+/// `io_uring_setup(entries, params)`: gives the caller a ring and returns its descriptor.
+fn sys_io_uring_setup(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    uring::sys_uring_setup(caller, args[0] as u32, args[1] as usize).map(|fd| fd as u64)
+}
+
+/// `io_uring_enter(fd, to_submit, min_complete)`: submits queued operations and waits for
+/// completions; returns how many were submitted.
+fn sys_io_uring_enter(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let [fd, to_submit, min_complete, ..] = args;
+    uring::sys_uring_enter(caller, fd as usize, to_submit as u32, min_complete as u32).map(|submitted| submitted as u64)
+}
+
+/// `audit_read(from, buf, count)`: copies audit records to `buf` and returns how many.
+fn sys_audit_read(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let cred = with_caller(caller, |process| Ok(process.cred))?;
+    Ok(audit::sys_audit_read(&cred, args[0], args[1] as usize, args[2] as usize)? as u64)
+}
+
+/// `adjtime(delta, olddelta)`: slews the clocks by the `struct timeval` at `delta` and reports
+/// the slew left outstanding.
+fn sys_adjtime(caller: u64, args: [u64; 6]) -> KResult<u64> {
+    let cred = with_caller(caller, |process| Ok(process.cred))?;
+    timekeeping::sys_adjtime(&cred, args[0] as usize, args[1] as usize)?;
+    Ok(0)
+}
+
+// Copies the NUL-terminated path at user address `addr` into `buf`
+fn user_path(buf: &mut [u8; MAX_PATH], addr: usize) -> KResult<&str> {
+    let len = vfs::read_user_path(buf, addr)?;
+    core::str::from_utf8(&buf[..len]).map_err(|_| Errno::ENOENT)
+}
+
+pub mod ktests {
+    use core::sync::atomic::{AtomicI64, Ordering};
+
+    use super::*;
+
+    use crate::os::audit::AuditRecord;
+    use crate::os::capability::{CAPABILITY_VERSION, CapUserData, CapUserHeader};
+    use crate::os::process::{COMM_LEN, ProcessState};
+    use crate::os::seccomp::{FilterAction, SyscallFilter};
+    use crate::os::timekeeping::{Timespec, Timeval};
+    use crate::os::uring::UringParams;
+
+    static RESULT: AtomicI64 = AtomicI64::new(0);
+
+    fn filtered_getpid() {
+        let filter = SyscallFilter::denylist(&[SYS_GETPID as u32], FilterAction::Errno(Errno::EPERM));
+        ptable::with_process(percpu::current_pid(), |process| seccomp::install(process, &filter));
+
+        RESULT.store(dispatch(SYS_GETPID, [0; 6]), Ordering::Relaxed);
+    }
+
+    // Waits for a child it creates, which the test then ends
+    fn waiting() {
+        let mut child = Process::new(9960, percpu::current_pid(), "child");
+        child.state = ProcessState::Ready;
+        ptable::insert(child).unwrap();
+
+        let mut status = 0i32;
+        let result = dispatch(SYS_WAIT4, [9960, &mut status as *mut i32 as u64, 0, 0, 0, 0]);
+        RESULT.store(if result == 9960 { status as i64 } else { result }, Ordering::Relaxed);
+    }
+
+    fn exiting() {
+        dispatch(SYS_EXIT, [7, 0, 0, 0, 0, 0]);
+        RESULT.store(-1, Ordering::Relaxed);
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn unknown_numbers_fail_with_enosys() {
+            assert_eq!(dispatch(TABLE_SIZE as u64, [0; 6]), Errno::ENOSYS.as_syscall_return());
+            assert_eq!(dispatch(u64::MAX, [0; 6]), Errno::ENOSYS.as_syscall_return());
+            assert_eq!(dispatch(TABLE_SIZE as u64 - 1, [0; 6]), Errno::ENOSYS.as_syscall_return());
+        }
+
+        fn getpid_and_yield_answer_for_the_caller() {
+            let pid = ptable::with_process(percpu::current_pid(), |process| pidns::sys_getpid(process)).unwrap();
+
+            assert_eq!(dispatch(SYS_GETPID, [0; 6]), pid as i64);
+            assert_eq!(dispatch(SYS_SCHED_YIELD, [0; 6]), 0);
+        }
+
+        fn write_goes_to_the_console_and_checks_its_descriptor() {
+            let text = b"syscall: write\n";
+            let args = |fd: u64| [fd, text.as_ptr() as u64, text.len() as u64, 0, 0, 0];
+
+            assert_eq!(dispatch(SYS_WRITE, args(1)), text.len() as i64);
+            assert_eq!(dispatch(SYS_WRITE, args(9)), Errno::EBADF.as_syscall_return());
+            assert_eq!(dispatch(SYS_WRITE, [1, u64::MAX, 2, 0, 0, 0]), Errno::EFAULT.as_syscall_return());
+        }
+
+        fn files_are_opened_read_and_closed() {
+            let path = b"/proc/meminfo\0";
+            let fd = dispatch(SYS_OPEN, [path.as_ptr() as u64, 0, 0, 0, 0, 0]) as u64;
+            let mut buf = [0u8; 8];
+            let read = |buf: &mut [u8; 8]| dispatch(SYS_READ, [fd, buf.as_mut_ptr() as u64, 8, 0, 0, 0]);
+
+            assert_eq!(read(&mut buf), 8);
+            assert_eq!(&buf, b"MemTotal");
+            assert_eq!(dispatch(SYS_LSEEK, [fd, 3, vfs::SEEK_SET as u64, 0, 0, 0]), 3);
+            assert_eq!(read(&mut buf), 8);
+            assert_eq!(&buf[..7], b"Total:\t");
+            assert!(buf[7].is_ascii_digit());
+
+            assert_eq!(dispatch(SYS_CLOSE, [fd, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(read(&mut buf), Errno::EBADF.as_syscall_return());
+            assert_eq!(dispatch(SYS_OPEN, [b"/nowhere\0".as_ptr() as u64, 0, 0, 0, 0, 0]), Errno::ENOENT.as_syscall_return());
+        }
+
+        fn descriptors_are_duplicated() {
+            let fd = dispatch(SYS_OPEN, [b"/proc/meminfo\0".as_ptr() as u64, 0, 0, 0, 0, 0]) as u64;
+            let copy = dispatch(SYS_DUP, [fd, 0, 0, 0, 0, 0]);
+            assert!(copy >= 0 && copy as u64 != fd);
+            assert_eq!(dispatch(SYS_DUP2, [fd, copy as u64, 0, 0, 0, 0]), copy);
+            assert_eq!(dispatch(SYS_DUP, [99, 0, 0, 0, 0, 0]), Errno::EBADF.as_syscall_return());
+
+            assert_eq!(dispatch(SYS_CLOSE, [copy as u64, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_CLOSE, [fd, 0, 0, 0, 0, 0]), 0);
+        }
+
+        fn ioctl_reaches_the_console() {
+            assert_eq!(dispatch(SYS_IOCTL, [0, 0x1234, 0, 0, 0, 0]), Errno::ENOTTY.as_syscall_return());
+        }
+
+        fn job_control_ids_are_reported() {
+            let (pgid, sid) = ptable::with_process(percpu::current_pid(), |process| (process.pgid, process.sid)).unwrap();
+
+            assert_eq!(dispatch(SYS_GETPGID, [0; 6]), pgid as i64);
+            assert_eq!(dispatch(SYS_GETSID, [0; 6]), sid as i64);
+            assert_eq!(dispatch(SYS_GETPGID, [9969, 0, 0, 0, 0, 0]), Errno::ESRCH.as_syscall_return());
+            assert_eq!(dispatch(SYS_SETPGID, [9969, 0, 0, 0, 0, 0]), Errno::ESRCH.as_syscall_return());
+        }
+
+        fn namespace_calls_answer_for_the_caller() {
+            let ppid = pidns::sys_getppid(percpu::current_pid()).unwrap();
+
+            assert_eq!(dispatch(SYS_GETPPID, [0; 6]), ppid as i64);
+            assert_eq!(dispatch(SYS_UNSHARE, [1, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+        }
+
+        fn mount_calls_read_their_paths() {
+            let (root, old, unknown) = (b"/\0", b"/old\0", b"nosuchfs\0");
+
+            assert_eq!(dispatch(SYS_MOUNT, [0, root.as_ptr() as u64, unknown.as_ptr() as u64, 0, 0, 0]), Errno::ENODEV.as_syscall_return());
+            assert_eq!(dispatch(SYS_UMOUNT2, [root.as_ptr() as u64, 0, 0, 0, 0, 0]), Errno::EBUSY.as_syscall_return());
+            assert_eq!(dispatch(SYS_UMOUNT2, [root.as_ptr() as u64, 1, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_PIVOT_ROOT, [root.as_ptr() as u64, old.as_ptr() as u64, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_CHROOT, [root.as_ptr() as u64, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_CHROOT, [u64::MAX, 0, 0, 0, 0, 0]), Errno::EFAULT.as_syscall_return());
+        }
+
+        fn timers_are_reached_through_their_calls() {
+            let mut value = itimer::Itimerval::default();
+            let mut spec = itimer::Itimerspec::default();
+            let mut id = 0i32;
+            let (value_addr, spec_addr) = (&raw mut value as u64, &raw mut spec as u64);
+
+            assert_eq!(dispatch(SYS_GETITIMER, [itimer::ITIMER_REAL as u64, value_addr, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_GETITIMER, [1, value_addr, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_SETITIMER, [1, value_addr, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_ALARM, [0; 6]), 0);
+
+            assert_eq!(dispatch(SYS_TIMER_CREATE, [99, 0, &raw mut id as u64, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_TIMER_GETTIME, [99, spec_addr, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_TIMER_SETTIME, [99, 0, spec_addr, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_TIMER_GETOVERRUN, [99, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_TIMER_DELETE, [99, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+        }
+
+        fn resource_limits_round_trip() {
+            let mut limit = [0u64; 2];
+            let nofile = rlimit::Resource::NoFile as u64;
+
+            assert_eq!(dispatch(SYS_GETRLIMIT, [nofile, limit.as_mut_ptr() as u64, 0, 0, 0, 0]), 0);
+            assert!(limit[0] <= limit[1]);
+            assert_eq!(dispatch(SYS_SETRLIMIT, [nofile, limit.as_ptr() as u64, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_GETRLIMIT, [1, limit.as_mut_ptr() as u64, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+        }
+
+        fn sysctl_reads_tunables_by_name() {
+            let name = "kernel.log_level";
+            let mut old = u64::MAX;
+            let args = |name: &str, old: &mut u64| [name.as_ptr() as u64, name.len() as u64, old as *mut u64 as u64, 0, 0, 0];
+
+            assert_eq!(dispatch(SYS_SYSCTL, args(name, &mut old)), 0);
+            assert_eq!(Ok(old), sysctl::get(name));
+            assert_eq!(dispatch(SYS_SYSCTL, args("kernel.nothing", &mut old)), Errno::ENOENT.as_syscall_return());
+        }
+
+        fn prctl_reports_the_callers_name() {
+            let name = ptable::with_process(percpu::current_pid(), |process| process.name).unwrap();
+            let mut buf = [0xffu8; COMM_LEN];
+
+            assert_eq!(dispatch(SYS_PRCTL, [prctl::PR_GET_NAME as u64, buf.as_mut_ptr() as u64, 0, 0, 0, 0]), 0);
+            assert_eq!(buf, name);
+            assert_eq!(dispatch(SYS_PRCTL, [0, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+        }
+
+        fn syscall_filters_are_installed_through_their_call() {
+            let oversized = seccomp::MAX_SYSCALLS as u64 + 1;
+            assert_eq!(
+                dispatch(SYS_SET_SYSCALL_FILTER, [seccomp::MODE_DENYLIST, 0, oversized, 0, 0, 0]),
+                Errno::EINVAL.as_syscall_return()
+            );
+        }
+
+        fn getrandom_fills_the_buffer() {
+            let mut buf = [0u8; 16];
+
+            assert_eq!(dispatch(SYS_GETRANDOM, [buf.as_mut_ptr() as u64, 16, random::GRND_INSECURE as u64, 0, 0, 0]), 16);
+            assert_eq!(dispatch(SYS_GETRANDOM, [buf.as_mut_ptr() as u64, 16, 0x80, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+        }
+
+        fn audit_records_are_read_through_their_call() {
+            let mut records = [0u8; size_of::<AuditRecord>()];
+
+            assert_eq!(dispatch(SYS_AUDIT_READ, [0, records.as_mut_ptr() as u64, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_AUDIT_READ, [u64::MAX, records.as_mut_ptr() as u64, 1, 0, 0, 0]), 0);
+        }
+
+        fn rings_are_set_up_and_entered() {
+            let mut params = UringParams::default();
+            let params_addr = &raw mut params as u64;
+
+            assert_eq!(dispatch(SYS_IO_URING_SETUP, [0, params_addr, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            let fd = dispatch(SYS_IO_URING_SETUP, [4, params_addr, 0, 0, 0, 0]);
+            assert!(fd >= 0);
+            assert_eq!(params.sq_entries, 4);
+
+            assert_eq!(dispatch(SYS_IO_URING_ENTER, [fd as u64, 0, 1, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_IO_URING_ENTER, [99, 0, 0, 0, 0, 0]), Errno::EBADF.as_syscall_return());
+            assert_eq!(dispatch(SYS_CLOSE, [fd as u64, 0, 0, 0, 0, 0]), 0);
+        }
+
+        fn nanosleep_sleeps_for_the_time_asked_for() {
+            let req = Timespec { sec: 0, nsec: 2_000_000 };
+            let start = timekeeping::monotonic_ns();
+
+            assert_eq!(dispatch(SYS_NANOSLEEP, [&req as *const Timespec as u64, 0, 0, 0, 0, 0]), 0);
+            assert!(timekeeping::monotonic_ns() - start >= 2_000_000);
+        }
+
+        fn clocks_are_read_and_slept_on() {
+            let mut time = Timespec::default();
+            let tp = &mut time as *mut Timespec as u64;
+
+            assert_eq!(dispatch(SYS_CLOCK_GETTIME, [timekeeping::CLOCK_MONOTONIC as u64, tp, 0, 0, 0, 0]), 0);
+            let start = time.to_ns().unwrap();
+            assert_eq!(dispatch(SYS_CLOCK_GETTIME, [99, tp, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_CLOCK_GETRES, [timekeeping::CLOCK_REALTIME as u64, tp, 0, 0, 0, 0]), 0);
+            assert_eq!(time, Timespec { sec: 0, nsec: 1 });
+
+            // A null delta only reports the outstanding slew
+            let mut left = Timeval { sec: -1, usec: -1 };
+            assert_eq!(dispatch(SYS_ADJTIME, [0, &raw mut left as u64, 0, 0, 0, 0]), 0);
+            assert_ne!(left, Timeval { sec: -1, usec: -1 });
+            assert_eq!(dispatch(SYS_ADJTIME, [0xffff_8000_0000_0000, 0, 0, 0, 0, 0]), Errno::EFAULT.as_syscall_return());
+
+            // An absolute deadline on the monotonic clock
+            let req = Timespec::from_ns(start + 2_000_000);
+            let args = [timekeeping::CLOCK_MONOTONIC as u64, itimer::TIMER_ABSTIME as u64, &req as *const Timespec as u64, 0, 0, 0];
+            assert_eq!(dispatch(SYS_CLOCK_NANOSLEEP, args), 0);
+            assert!(timekeeping::monotonic_ns() >= start + 2_000_000);
+        }
+
+        fn memory_calls_check_their_arguments() {
+            let anonymous = (mmap::MAP_PRIVATE | mmap::MAP_ANONYMOUS) as u64;
+
+            assert_eq!(dispatch(SYS_MMAP, [0, 0, mmap::PROT_READ as u64, anonymous, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_MUNMAP, [1, 0x1000, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_MSYNC, [1, 0x1000, mmap::MS_SYNC as u64, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            let both = (mmap::MS_SYNC | mmap::MS_ASYNC) as u64;
+            assert_eq!(dispatch(SYS_MSYNC, [0x1000, 0x1000, both, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+
+            // A break that cannot move is reported unmoved
+            let brk = dispatch(SYS_BRK, [0; 6]);
+            assert_eq!(dispatch(SYS_BRK, [1, 0, 0, 0, 0, 0]), brk);
+        }
+
+        fn sockets_send_only_with_an_address() {
+            let fd = dispatch(SYS_SOCKET, [udp::AF_INET as u64, udp::SOCK_DGRAM as u64, 0, 0, 0, 0]);
+            assert!(fd >= 0);
+            let fd = fd as u64;
+
+            let data = b"datagram";
+            assert_eq!(dispatch(SYS_SENDTO, [fd, data.as_ptr() as u64, 8, 0, 0, 0]), Errno::EDESTADDRREQ.as_syscall_return());
+            let mut buf = [0u8; 8];
+            let args = [fd, buf.as_mut_ptr() as u64, 8, udp::MSG_DONTWAIT as u64, 0, 0];
+            assert_eq!(dispatch(SYS_RECVFROM, args), Errno::EAGAIN.as_syscall_return());
+
+            // A closed descriptor names no socket
+            assert_eq!(dispatch(SYS_CLOSE, [fd, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_RECVFROM, args), Errno::EBADF.as_syscall_return());
+        }
+
+        fn filters_veto_calls_and_exit_ends_the_caller() {
+            let alive = |pid| ptable::with_process(pid, |_| ()).is_some();
+
+            let pid = kthread::spawn_kthread(filtered_getpid, 0).unwrap();
+            while alive(pid) {
+                sched::yield_now();
+            }
+            assert_eq!(RESULT.load(Ordering::Relaxed), Errno::EPERM.as_syscall_return());
+
+            RESULT.store(0, Ordering::Relaxed);
+            let pid = kthread::spawn_kthread(exiting, 0).unwrap();
+            while alive(pid) {
+                sched::yield_now();
+            }
+            assert_eq!(RESULT.load(Ordering::Relaxed), 0);
+        }
+
+        fn setpriority_checks_its_range() {
+            let priority = ptable::with_process(percpu::current_pid(), |process| process.priority).unwrap();
+
+            assert_eq!(dispatch(SYS_SETPRIORITY, [0, 0, priority as u64, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_SETPRIORITY, [1, 0, priority as u64, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_SETPRIORITY, [0, 0, sched::LOWEST_PRIORITY as u64 + 1, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_SETPRIORITY, [0, 9969, priority as u64, 0, 0, 0]), Errno::ESRCH.as_syscall_return());
+        }
+
+        fn kill_checks_its_target() {
+            let pid = ptable::with_process(percpu::current_pid(), |process| pidns::sys_getpid(process)).unwrap();
+
+            assert_eq!(dispatch(SYS_KILL, [pid, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_KILL, [9969, 0, 0, 0, 0, 0]), Errno::ESRCH.as_syscall_return());
+            assert_eq!(dispatch(SYS_KILL, [pid, 64, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+        }
+
+        fn credential_calls_reach_cred() {
+            // Root setting its own IDs keeps everything as it was
+            assert_eq!(dispatch(SYS_SETUID, [0, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_SETGID, [0, 0, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_SETGROUPS, [cred::NGROUPS_MAX as u64 + 1, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_SETGROUPS, [1, 0xffff_8000_0000_0000, 0, 0, 0, 0]), Errno::EFAULT.as_syscall_return());
+        }
+
+        fn capability_calls_reach_capability() {
+            let mut header = CapUserHeader { version: CAPABILITY_VERSION, pid: 0 };
+            let mut data = [CapUserData::default(); 2];
+            let header_addr = &raw mut header as u64;
+            let data_addr = &raw mut data as u64;
+
+            // Setting back exactly what was read changes nothing
+            assert_eq!(dispatch(SYS_CAPGET, [header_addr, data_addr, 0, 0, 0, 0]), 0);
+            assert_eq!(dispatch(SYS_CAPSET, [header_addr, data_addr, 0, 0, 0, 0]), 0);
+
+            header.version = 0;
+            assert_eq!(dispatch(SYS_CAPGET, [header_addr, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+            assert_eq!(dispatch(SYS_CAPGET, [header_addr, 0, 0, 0, 0, 0]), 0);
+        }
+
+        fn wait4_blocks_until_the_child_exits() {
+            assert_eq!(dispatch(SYS_WAIT4, [0, 0, 0, 0, 0, 0]), Errno::EINVAL.as_syscall_return());
+
+            let pid = kthread::spawn_kthread(waiting, 0).unwrap();
+            let state = |pid| ptable::with_process(pid, |process| process.state);
+            while state(pid) != Some(ProcessState::Blocked) {
+                sched::yield_now();
+            }
+            assert_eq!(state(9960), Some(ProcessState::Ready));
+
+            RESULT.store(0, Ordering::Relaxed);
+            assert_eq!(exit::exit(9960, 5), Ok(()));
+            while state(pid).is_some() {
+                sched::yield_now();
+            }
+            assert_eq!(RESULT.load(Ordering::Relaxed), 5 << 8);
+            assert_eq!(state(9960), None);
+        }
+    }
+}
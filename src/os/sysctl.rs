@@ -0,0 +1,229 @@
+//! Runtime kernel tunables.
+//!
+//! Every tunable is a named integer with a valid range, stored in an atomic so readers never
+//! need a lock. The owning subsystem reads it when it needs the value; [`set`] validates a new
+//! value, stores it and runs the tunable's apply hook (if any) so changes take effect without a
+//! reboot. Tunables are reachable through [`sys_sysctl`] and procfs under `sys/`, where the
+//! dots in a name become path separators (`kernel.log_level` is `sys/kernel/log_level`).
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::capability::{self, Capability};
+use crate::os::compaction;
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::pid;
+use crate::os::process;
+use crate::os::serial;
+use crate::os::uaccess;
+
+/// Timeslice (in ticks) given to processes when they are scheduled.
+pub static SCHED_TIMESLICE: AtomicU64 = AtomicU64::new(process::DEFAULT_TIMESLICE as u64);
+
+/// PIDs are allocated below this; the search wraps round past it.
+pub static PID_MAX: AtomicU64 = AtomicU64::new(pid::PID_LIMIT);
+
+/// Interval between dirty page writeback passes, in milliseconds.
+pub static DIRTY_WRITEBACK_MS: AtomicU64 = AtomicU64::new(5000);
+
+/// Percentage of the page cache that may be dirty before writeback starts without waiting for
+/// the next pass.
+pub static DIRTY_BACKGROUND_RATIO: AtomicU64 = AtomicU64::new(10);
+
+/// Kernel log verbosity: 0 = off, 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace.
+pub static LOG_LEVEL: AtomicU64 = AtomicU64::new(3);
+
+/// Overcommit policy: 0 = refuse only requests larger than RAM plus swap, 1 = never refuse,
+/// 2 = keep total commitments under the limit set by `vm.overcommit_ratio`.
+pub static OVERCOMMIT_MEMORY: AtomicU64 = AtomicU64::new(0);
+
+/// Percentage of RAM that, with swap, may be committed under strict overcommit.
+pub static OVERCOMMIT_RATIO: AtomicU64 = AtomicU64::new(50);
+
+/// Transparent huge pages: 1 = back aligned 2 MiB anonymous regions with huge pages on first
+/// touch, 0 = never.
+pub static TRANSPARENT_HUGEPAGE: AtomicU64 = AtomicU64::new(1);
+
+/// Writing 1 compacts physical memory; reads back the last value written.
+pub static COMPACT_MEMORY: AtomicU64 = AtomicU64::new(0);
+
+/// One registered tunable.
+pub struct Tunable {
+    /// Dotted name, e.g. `kernel.sched_timeslice`.
+    pub name: &'static str,
+    pub value: &'static AtomicU64,
+    pub min: u64,
+    pub max: u64,
+
+    /// Called with the new value after it was stored.
+    pub apply: Option<fn(u64)>,
+}
+
+/// Every tunable the kernel knows about.
+pub static TUNABLES: &[Tunable] = &[
+    Tunable { name: "kernel.sched_timeslice", value: &SCHED_TIMESLICE, min: 1, max: 1000, apply: None },
+    Tunable { name: "kernel.log_level", value: &LOG_LEVEL, min: 0, max: 5, apply: Some(apply_log_level) },
+    Tunable { name: "kernel.pid_max", value: &PID_MAX, min: pid::RESERVED_PIDS + 1, max: pid::PID_LIMIT, apply: None },
+    Tunable { name: "vm.dirty_writeback_ms", value: &DIRTY_WRITEBACK_MS, min: 100, max: 600_000, apply: None },
+    Tunable { name: "vm.dirty_background_ratio", value: &DIRTY_BACKGROUND_RATIO, min: 0, max: 100, apply: None },
+    Tunable { name: "vm.overcommit_memory", value: &OVERCOMMIT_MEMORY, min: 0, max: 2, apply: None },
+    Tunable { name: "vm.overcommit_ratio", value: &OVERCOMMIT_RATIO, min: 0, max: 100, apply: None },
+    Tunable { name: "vm.transparent_hugepage", value: &TRANSPARENT_HUGEPAGE, min: 0, max: 1, apply: None },
+    Tunable { name: "vm.compact_memory", value: &COMPACT_MEMORY, min: 1, max: 1, apply: Some(apply_compact_memory) },
+];
+
+fn apply_log_level(level: u64) {
+    let filter = match level {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+
+    serial::set_default_level(filter);
+}
+
+fn apply_compact_memory(_: u64) {
+    compaction::compact();
+}
+
+/// Looks up a tunable by its dotted name.
+pub fn find(name: &str) -> Option<&'static Tunable> {
+    TUNABLES.iter().find(|t| t.name == name)
+}
+
+/// Current value of the tunable `name`.
+pub fn get(name: &str) -> KResult<u64> {
+    find(name).map(|t| t.value.load(Ordering::Relaxed)).ok_or(Errno::ENOENT)
+}
+
+/// Sets the tunable `name`. Requires `CAP_SYS_ADMIN`; out-of-range values fail with `EINVAL`.
+pub fn set(cred: &Credentials, name: &str, value: u64) -> KResult<()> {
+    let tunable = find(name).ok_or(Errno::ENOENT)?;
+    capability::require(cred, Capability::SysAdmin)?;
+
+    if value < tunable.min || value > tunable.max {
+        return Err(Errno::EINVAL);
+    }
+
+    tunable.value.store(value, Ordering::Relaxed);
+
+    if let Some(apply) = tunable.apply {
+        apply(value);
+    }
+
+    log::info!("sysctl: {} = {}", name, value);
+    Ok(())
+}
+
+/// Longest tunable name accepted from user space.
+const NAME_MAX: usize = 64;
+
+/// `sysctl(name, name_len, old, new)`: if `old` is non-zero the current value is written there
+/// as a `u64`; if `new` is non-zero the tunable is then set to the `u64` stored there.
+pub fn sys_sysctl(cred: &Credentials, name: usize, name_len: usize, old: usize, new: usize) -> KResult<()> {
+    if name_len > NAME_MAX {
+        return Err(Errno::ENOENT);
+    }
+
+    let mut buffer = [0u8; NAME_MAX];
+    uaccess::copy_from_user(&mut buffer[..name_len], name)?;
+    let name = core::str::from_utf8(&buffer[..name_len]).map_err(|_| Errno::ENOENT)?;
+
+    if old != 0 {
+        uaccess::write_user(old, &get(name)?)?;
+    }
+
+    if new != 0 {
+        let value: u64 = unsafe { uaccess::read_user(new)? };
+        set(cred, name, value)?;
+    }
+
+    Ok(())
+}
+
+pub mod ktests {
+    use alloc::string::String;
+
+    use super::*;
+    use crate::os::pidns::ROOT_NS;
+    use crate::os::procfs;
+
+    const NAME: &str = "vm.dirty_background_ratio";
+
+    // Runs `f`, then puts the tunable the tests change back as it was
+    fn restoring(f: impl FnOnce()) {
+        let saved = DIRTY_BACKGROUND_RATIO.load(Ordering::Relaxed);
+        f();
+        DIRTY_BACKGROUND_RATIO.store(saved, Ordering::Relaxed);
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn values_must_lie_within_the_range() {
+            restoring(|| {
+                let root = Credentials::ROOT;
+                let tunable = find(NAME).unwrap();
+
+                assert_eq!(set(&root, NAME, tunable.max + 1), Err(Errno::EINVAL));
+                assert_eq!(set(&root, NAME, u64::MAX), Err(Errno::EINVAL));
+                assert_eq!(set(&root, NAME, tunable.min), Ok(()));
+                assert_eq!(get(NAME), Ok(tunable.min));
+                assert_eq!(set(&root, NAME, tunable.max), Ok(()));
+                assert_eq!(get(NAME), Ok(tunable.max));
+
+                assert_eq!(get("vm.no_such_thing"), Err(Errno::ENOENT));
+                assert_eq!(set(&root, "vm.no_such_thing", 1), Err(Errno::ENOENT));
+            });
+        }
+
+        fn writes_need_sys_admin() {
+            restoring(|| {
+                let before = get(NAME).unwrap();
+                assert_eq!(set(&Credentials::new(1000, 1000), NAME, 42), Err(Errno::EPERM));
+                assert_eq!(get(NAME), Ok(before));
+
+                let mut admin = Credentials::new(1000, 1000);
+                admin.cap_permitted.insert(Capability::SysAdmin);
+                admin.cap_effective.insert(Capability::SysAdmin);
+                assert_eq!(set(&admin, NAME, 42), Ok(()));
+                assert_eq!(get(NAME), Ok(42));
+            });
+        }
+
+        fn procfs_shows_tunables_under_sys() {
+            restoring(|| {
+                let root = Credentials::ROOT;
+                set(&root, NAME, 35).unwrap();
+
+                let mut out = String::new();
+                procfs::render("sys/vm/dirty_background_ratio", ROOT_NS, &mut out).unwrap().unwrap();
+                assert_eq!(out, "35\n");
+                assert!(procfs::render("sys/vm.dirty_background_ratio", ROOT_NS, &mut String::new()).is_none());
+
+                let path = "sys/vm/dirty_background_ratio";
+                assert_eq!(procfs::write("/sys/vm/dirty_background_ratio", "60\n", &root), Some(Ok(())));
+                assert_eq!(get(NAME), Ok(60));
+                assert_eq!(procfs::write(path, "lots", &root), Some(Err(Errno::EINVAL)));
+                assert_eq!(procfs::write(path, "20", &Credentials::new(1000, 1000)), Some(Err(Errno::EPERM)));
+                assert_eq!(procfs::write("meminfo", "1", &root), None);
+            });
+        }
+
+        fn sysctl_reads_and_writes_through_user_memory() {
+            restoring(|| {
+                let name = NAME.as_bytes();
+                let before = get(NAME).unwrap();
+                let mut old = 0u64;
+                let new = 25u64;
+                let root = Credentials::ROOT;
+
+                sys_sysctl(&root, name.as_ptr() as usize, name.len(), &raw mut old as usize, &raw const new as usize).unwrap();
+                assert_eq!(old, before);
+                assert_eq!(get(NAME), Ok(new));
+                assert_eq!(sys_sysctl(&root, name.as_ptr() as usize, NAME_MAX + 1, 0, 0), Err(Errno::ENOENT));
+            });
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Transparent huge pages for anonymous memory.
+//!
+//! A process touching a 2 MiB-aligned region that lies wholly inside its data, heap or stack,
+//! with nothing mapped there yet, gets the whole region backed by one 2 MiB page from
+//! [`handle_fault`]: a single entry one level up from the leaves, so one TLB entry covers what
+//! would otherwise take 512. The page is allocated with [`compaction::try_alloc_contiguous`] and
+//! charged to the process's group as 512 anonymous pages; when either fails the fault falls
+//! back to a 4 KiB page.
+//!
+//! Code that changes part of a region -- unmapping it, changing its protection -- calls
+//! [`split_partial`] first, which replaces each huge page the change only partly covers with a
+//! table of 512 small pages mapping the same frames. Swap and compaction only see small pages,
+//! so a huge page stays resident and in place until it is split or freed.
+//!
+//! Controlled by `vm.transparent_hugepage`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::arch::{Arch, Current};
+use crate::os::cgroup::{self, PageKind};
+use crate::os::compaction;
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::numa::Placement;
+use crate::os::process::Process;
+use crate::os::sysctl;
+use crate::os::tlb;
+
+/// Size of a huge page.
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Frames backing one huge page.
+pub const HUGE_PAGE_FRAMES: u64 = HUGE_PAGE_SIZE / FRAME_SIZE;
+
+// Huge pages mapped across all processes
+static HUGE_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of huge pages currently mapped.
+pub fn huge_pages() -> u64 {
+    HUGE_PAGES.load(Ordering::Relaxed)
+}
+
+// Whether the huge page at `base` would lie entirely inside one of `process`'s anonymous ranges
+fn fits(process: &Process, base: u64) -> bool {
+    memory::anonymous_ranges(process).iter().any(|&(start, end)| start <= base && base + HUGE_PAGE_SIZE <= end)
+}
+
+/// Page fault hook: backs the 2 MiB region around `virt` with a huge page if transparent huge
+/// pages are on, the region is anonymous memory of `process` and nothing in it is mapped yet.
+/// Returns whether it did; if not, the caller maps a 4 KiB page as usual.
+pub fn handle_fault(process: &Process, virt: u64) -> bool {
+    if sysctl::TRANSPARENT_HUGEPAGE.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+
+    let base = virt & !(HUGE_PAGE_SIZE - 1);
+    if !fits(process, base) {
+        return false;
+    }
+
+    // Without the tables above, or with a table of small pages already there, the region is
+    // left to the small-page path
+    let Some(slot) = Current::huge_entry(process.page_table_root as u64, base) else {
+        return false;
+    };
+    let slot = unsafe { &mut *slot };
+    if *slot != 0 {
+        return false;
+    }
+
+    if cgroup::charge(process.cgroup, PageKind::Anon, HUGE_PAGE_FRAMES).is_err() {
+        return false;
+    }
+    let Some(frame) = compaction::try_alloc_contiguous(HUGE_PAGE_FRAMES, HUGE_PAGE_SIZE) else {
+        cgroup::uncharge(process.cgroup, PageKind::Anon, HUGE_PAGE_FRAMES);
+        return false;
+    };
+
+    // Frames are identity-mapped
+    unsafe { core::ptr::write_bytes(frame as *mut u8, 0, HUGE_PAGE_SIZE as usize) };
+    *slot = Current::page_entry(frame, Current::huge_flags(Current::page_flags(true, false, true)));
+    HUGE_PAGES.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+// Fills the table at `table` with the entries of the 512 small pages the huge page `huge` maps
+fn fill_table(table: u64, huge: u64) {
+    let frame = Current::entry_address(huge);
+    let flags = Current::split_flags(huge & !Current::page_entry(frame, 0));
+    let entries = table as *mut u64;
+
+    for i in 0..HUGE_PAGE_FRAMES {
+        unsafe { entries.add(i as usize).write(Current::page_entry(frame + i * FRAME_SIZE, flags)) };
+    }
+}
+
+/// Splits the huge page covering `virt` in `process`'s address space into 512 small pages
+/// mapping the same frames with the same rights. Does nothing if `virt` is not in a huge page;
+/// fails with `ENOMEM` if no frame is free for the new page table.
+pub fn split(process: &Process, virt: u64) -> KResult<()> {
+    let root = process.page_table_root as u64;
+    let Some(slot) = Current::huge_entry(root, virt) else {
+        return Ok(());
+    };
+    let slot = unsafe { &mut *slot };
+    if !Current::is_huge(*slot) {
+        return Ok(());
+    }
+
+    let table = memory::frame_allocator().and_then(|allocator| allocator.alloc_frame(Placement::Local)).ok_or(Errno::ENOMEM)?;
+    fill_table(table, *slot);
+    *slot = Current::table_entry(table);
+
+    tlb::shootdown(Some(root), virt & !(HUGE_PAGE_SIZE - 1), HUGE_PAGE_FRAMES);
+    HUGE_PAGES.fetch_sub(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Prepares `[start, end)` of `process`'s address space for an unmap or protection change by
+/// splitting the huge pages it covers only in part. Huge pages it covers whole stay as they are.
+pub fn split_partial(process: &Process, start: u64, end: u64) -> KResult<()> {
+    for boundary in [start, end] {
+        if !boundary.is_multiple_of(HUGE_PAGE_SIZE) {
+            split(process, boundary)?;
+        }
+    }
+    Ok(())
+}
+
+/// Frees `process`'s huge pages and their charges. Called when its address space is torn down.
+pub fn release(process: &Process) {
+    let root = process.page_table_root as u64;
+    if root == 0 {
+        return;
+    }
+
+    for (start, end) in memory::anonymous_ranges(process) {
+        let mut base = start.next_multiple_of(HUGE_PAGE_SIZE);
+        while base + HUGE_PAGE_SIZE <= end {
+            if let Some(slot) = Current::huge_entry(root, base)
+                && Current::is_huge(unsafe { *slot })
+            {
+                let frame = Current::entry_address(unsafe { *slot });
+                unsafe { *slot = 0 };
+
+                compaction::free_contiguous(frame, HUGE_PAGE_FRAMES);
+                cgroup::uncharge(process.cgroup, PageKind::Anon, HUGE_PAGE_FRAMES);
+                HUGE_PAGES.fetch_sub(1, Ordering::Relaxed);
+            }
+            base += HUGE_PAGE_SIZE;
+        }
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    #[repr(C, align(4096))]
+    struct Table([u64; 512]);
+
+    static mut TABLE: Table = Table([0; 512]);
+
+    crate::os::ktest::kernel_test! {
+        fn huge_entries_are_told_apart() {
+            let flags = Current::page_flags(true, false, true);
+            let huge = Current::page_entry(0x4000_0000, Current::huge_flags(flags));
+
+            assert!(Current::is_huge(huge));
+            assert!(!Current::is_huge(Current::table_entry(0x4000_0000)));
+            assert!(!Current::is_huge(0));
+            assert_eq!(Current::split_flags(Current::huge_flags(flags)), flags);
+            assert_eq!(Current::entry_address(huge), 0x4000_0000);
+        }
+
+        fn split_maps_the_same_frames() {
+            let flags = Current::page_flags(true, false, true);
+            let huge = Current::page_entry(0x4000_0000, Current::huge_flags(flags));
+            let table = unsafe {
+                let table = &raw mut TABLE;
+                &mut (*table).0
+            };
+
+            fill_table(table.as_ptr() as u64, huge);
+            for (i, &entry) in table.iter().enumerate() {
+                assert_eq!(entry, Current::page_entry(0x4000_0000 + i as u64 * FRAME_SIZE, flags));
+            }
+        }
+
+        fn only_whole_anonymous_regions_qualify() {
+            let mut process = Process::new(9300, 0, "thp");
+            process.heap_base = 0x4010_0000;
+            process.heap_size = 0x0050_0000;
+
+            // The heap covers 0x4010_0000-0x4060_0000: only 0x4020_0000 and 0x4040_0000 fit
+            assert!(!fits(&process, 0x4000_0000));
+            assert!(fits(&process, 0x4020_0000));
+            assert!(fits(&process, 0x4040_0000));
+            assert!(!fits(&process, 0x4060_0000));
+        }
+    }
+}
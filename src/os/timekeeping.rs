@@ -0,0 +1,414 @@
+//! Timekeeping: the system clocks, built on the clocksource.
+//!
+//! - `CLOCK_MONOTONIC_RAW` is the clocksource as it is.
+//! - `CLOCK_MONOTONIC` (and `CLOCK_BOOTTIME`, as the system never suspends) is the raw clock
+//!   steered by NTP-style adjustments: a frequency correction of up to
+//!   [`MAX_FREQUENCY_PPB`], and offsets from [`adjtime`] slewed in at [`SLEW_PPB`]. It never
+//!   jumps and never runs backwards, so timers and timeouts can rely on it.
+//! - `CLOCK_REALTIME` is monotonic time plus an offset, seeded at boot from the host's wall
+//!   clock, the firmware's RTC or, failing both, the CMOS RTC read directly. [`settime`] only moves the offset, so setting the date does
+//!   not disturb monotonic time or anything measured with it.
+//!
+//! All clocks count nanoseconds.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use uefi::prelude::*;
+use uefi::table::runtime::Time;
+
+use crate::os::arch;
+#[cfg(target_arch = "x86_64")]
+use crate::os::arch::x86_64::rtc;
+use crate::os::capability::Capability;
+use crate::os::clocksource;
+use crate::os::cred::Credentials;
+use crate::os::errno::{Errno, KResult};
+use crate::os::uaccess;
+
+/// Clock IDs.
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+pub const CLOCK_MONOTONIC_RAW: u32 = 4;
+pub const CLOCK_BOOTTIME: u32 = 7;
+
+/// Largest frequency correction, in parts per billion (500 ppm, as NTP allows).
+pub const MAX_FREQUENCY_PPB: i64 = 500_000;
+
+/// Rate at which [`adjtime`] offsets are slewed in, in parts per billion: 0.5 ms per second.
+pub const SLEW_PPB: i64 = 500_000;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// `struct timeval`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeval {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+/// `struct timespec`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+impl Timeval {
+    /// The time in nanoseconds. Fails with `EINVAL` for a negative time or microseconds out
+    /// of range.
+    pub fn to_ns(self) -> KResult<u64> {
+        if self.sec < 0 {
+            return Err(Errno::EINVAL);
+        }
+        self.to_signed_ns().map(|ns| ns as u64)
+    }
+
+    /// The time in nanoseconds, which may be negative. Fails with `EINVAL` for microseconds out
+    /// of range.
+    pub fn to_signed_ns(self) -> KResult<i64> {
+        if !(0..1_000_000).contains(&self.usec) {
+            return Err(Errno::EINVAL);
+        }
+        Ok(self.sec.saturating_mul(NS_PER_SEC as i64).saturating_add(self.usec * 1000))
+    }
+
+    /// `ns` rounded up to whole microseconds.
+    pub fn from_ns(ns: u64) -> Self {
+        let usec = ns.div_ceil(1000);
+        Timeval { sec: (usec / 1_000_000) as i64, usec: (usec % 1_000_000) as i64 }
+    }
+
+    /// `ns`, which may be negative, rounded towards zero to whole microseconds.
+    pub fn from_signed_ns(ns: i64) -> Self {
+        let usec = ns / 1000;
+        Timeval { sec: usec.div_euclid(1_000_000), usec: usec.rem_euclid(1_000_000) }
+    }
+}
+
+impl Timespec {
+    /// The time in nanoseconds. Fails with `EINVAL` for a negative time or nanoseconds out of
+    /// range.
+    pub fn to_ns(self) -> KResult<u64> {
+        if self.sec < 0 || !(0..NS_PER_SEC as i64).contains(&self.nsec) {
+            return Err(Errno::EINVAL);
+        }
+        Ok((self.sec as u64).saturating_mul(NS_PER_SEC).saturating_add(self.nsec as u64))
+    }
+
+    pub fn from_ns(ns: u64) -> Self {
+        Timespec { sec: (ns / NS_PER_SEC) as i64, nsec: (ns % NS_PER_SEC) as i64 }
+    }
+}
+
+// Monotonic time at `base_raw` on the raw clock was `base_mono`; from there it advances at the
+// raw rate corrected by `frequency` parts per billion, plus or minus `SLEW_PPB` while `slew`
+// nanoseconds are left to slew in
+#[derive(Debug, Clone, Copy)]
+struct State {
+    base_raw: u64,
+    base_mono: u64,
+    frequency: i64,
+    slew: i64,
+
+    // Realtime minus monotonic time
+    wall_offset: i64,
+}
+
+impl State {
+    const fn new() -> Self {
+        State { base_raw: 0, base_mono: 0, frequency: 0, slew: 0, wall_offset: 0 }
+    }
+
+    // Monotonic time at `raw`, and how much of the slew it includes
+    fn monotonic(&self, raw: u64) -> (u64, i64) {
+        let elapsed = raw.saturating_sub(self.base_raw) as i128;
+        let budget = (elapsed * SLEW_PPB as i128 / NS_PER_SEC as i128) as i64;
+        let slewed = self.slew.clamp(-budget, budget);
+
+        let corrected = elapsed + elapsed * self.frequency as i128 / NS_PER_SEC as i128 + slewed as i128;
+        ((self.base_mono as i128 + corrected).max(self.base_mono as i128) as u64, slewed)
+    }
+
+    // Moves the base to `raw`, so a new frequency or slew only applies from now on
+    fn fold(&mut self, raw: u64) {
+        let (mono, slewed) = self.monotonic(raw);
+        self.base_raw = raw;
+        self.base_mono = mono;
+        self.slew -= slewed;
+    }
+}
+
+static mut STATE: State = State::new();
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the clock state holding its lock, with interrupts masked so a holder cannot be
+// interrupted by something spinning on it
+fn locked<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let state = &raw mut STATE;
+            f(&mut *state)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+// Days from 1970-01-01 to the given civil date (proleptic Gregorian calendar)
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Nanoseconds since the Unix epoch of a firmware time. UEFI gives local time with its offset
+// from UTC in minutes (local = UTC - offset); an unspecified offset is taken as UTC.
+fn unix_ns(time: &Time) -> Option<u64> {
+    let days = days_from_civil(time.year() as i64, time.month(), time.day());
+    let seconds = days * 86_400 + time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    let seconds = seconds + time.time_zone().unwrap_or(0) as i64 * 60;
+
+    u64::try_from(seconds).ok().map(|s| s * NS_PER_SEC + time.nanosecond() as u64)
+}
+
+// Nanoseconds since the Unix epoch of a CMOS RTC time, taken as UTC
+#[cfg(target_arch = "x86_64")]
+fn rtc_unix_ns(time: &rtc::RtcTime) -> Option<u64> {
+    let days = days_from_civil(time.year as i64, time.month, time.day);
+    let seconds = days * 86_400 + time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64;
+    u64::try_from(seconds).ok().map(|s| s * NS_PER_SEC)
+}
+
+// Wall-clock time now from the host (on KVM), the firmware's RTC or the CMOS RTC
+fn boot_wall_clock(system_table: &SystemTable<Boot>) -> Option<u64> {
+    #[cfg(target_arch = "x86_64")]
+    if let Some(boot) = crate::os::kvm::boot_wall_clock_ns() {
+        return Some(boot.saturating_add(clocksource::now_ns()));
+    }
+
+    if let Ok(time) = system_table.runtime_services().get_time() {
+        return unix_ns(&time);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if let Some(time) = rtc::read() {
+        return rtc_unix_ns(&time);
+    }
+    None
+}
+
+/// Seeds the realtime clock. Called once the clocksource is up.
+pub fn init(system_table: &SystemTable<Boot>) {
+    let Some(wall) = boot_wall_clock(system_table) else {
+        log::warn!("timekeeping: no wall clock, realtime starts at the epoch");
+        return;
+    };
+
+    let mono = monotonic_ns();
+    locked(|state| state.wall_offset = wall as i64 - mono as i64);
+    log::info!("timekeeping: realtime seeded at {} s since the epoch", wall / NS_PER_SEC);
+}
+
+/// `CLOCK_MONOTONIC` now.
+pub fn monotonic_ns() -> u64 {
+    let raw = clocksource::now_ns();
+    locked(|state| state.monotonic(raw).0)
+}
+
+/// `CLOCK_REALTIME` now, in nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    let raw = clocksource::now_ns();
+    locked(|state| (state.monotonic(raw).0 as i64).saturating_add(state.wall_offset).max(0) as u64)
+}
+
+/// The time on `clock` now. Fails with `EINVAL` for an unknown clock.
+pub fn now(clock: u32) -> KResult<u64> {
+    match clock {
+        CLOCK_REALTIME => Ok(realtime_ns()),
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => Ok(monotonic_ns()),
+        CLOCK_MONOTONIC_RAW => Ok(clocksource::now_ns()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Sets `clock` to `ns`. Only the realtime clock can be set, and only with `CAP_SYS_TIME`;
+/// monotonic time goes on undisturbed. Fails with `EINVAL` for any other clock and `EPERM`
+/// without the capability.
+pub fn settime(clock: u32, ns: u64, cred: &Credentials) -> KResult<()> {
+    now(clock)?;
+    if clock != CLOCK_REALTIME {
+        return Err(Errno::EINVAL);
+    }
+    if !cred.capable(Capability::SysTime) {
+        return Err(Errno::EPERM);
+    }
+
+    let raw = clocksource::now_ns();
+    locked(|state| state.wall_offset = ns.min(i64::MAX as u64) as i64 - state.monotonic(raw).0 as i64);
+    Ok(())
+}
+
+/// Starts slewing the clocks by `delta_ns` (added if positive, taken off if negative) at
+/// [`SLEW_PPB`], replacing any slew still in progress, and returns what was left of that one.
+/// Fails with `EPERM` without `CAP_SYS_TIME`.
+pub fn adjtime(delta_ns: i64, cred: &Credentials) -> KResult<i64> {
+    if !cred.capable(Capability::SysTime) {
+        return Err(Errno::EPERM);
+    }
+
+    let raw = clocksource::now_ns();
+    Ok(locked(|state| {
+        state.fold(raw);
+        core::mem::replace(&mut state.slew, delta_ns)
+    }))
+}
+
+/// Sets the frequency correction applied to the raw clock, in parts per billion. Fails with
+/// `EINVAL` beyond [`MAX_FREQUENCY_PPB`] either way and `EPERM` without `CAP_SYS_TIME`.
+pub fn set_frequency(ppb: i64, cred: &Credentials) -> KResult<()> {
+    if !(-MAX_FREQUENCY_PPB..=MAX_FREQUENCY_PPB).contains(&ppb) {
+        return Err(Errno::EINVAL);
+    }
+    if !cred.capable(Capability::SysTime) {
+        return Err(Errno::EPERM);
+    }
+
+    let raw = clocksource::now_ns();
+    locked(|state| {
+        state.fold(raw);
+        state.frequency = ppb;
+    });
+    Ok(())
+}
+
+// =========================================================================
+// clock_gettime / clock_settime / clock_getres / adjtime
+// =========================================================================
+
+/// `clock_gettime(clock, tp)`: copies the time on `clock` to `tp`.
+pub fn sys_clock_gettime(clock: u32, tp: usize) -> KResult<()> {
+    uaccess::write_user(tp, &Timespec::from_ns(now(clock)?))
+}
+
+/// `clock_settime(clock, tp)`: sets `clock` to the `struct timespec` at `tp` as [`settime`]
+/// does.
+pub fn sys_clock_settime(cred: &Credentials, clock: u32, tp: usize) -> KResult<()> {
+    let time = unsafe { uaccess::read_user::<Timespec>(tp)? };
+    settime(clock, time.to_ns()?, cred)
+}
+
+/// `clock_getres(clock, res)`: copies the resolution of `clock`, one nanosecond for all of
+/// them, to `res` unless it is null.
+pub fn sys_clock_getres(clock: u32, res: usize) -> KResult<()> {
+    now(clock)?;
+    if res != 0 {
+        uaccess::write_user(res, &Timespec { sec: 0, nsec: 1 })?;
+    }
+    Ok(())
+}
+
+/// `adjtime(delta, olddelta)`: slews the clocks by the `struct timeval` at `delta` as
+/// [`adjtime`] does, or with a null `delta` only reports; the slew still outstanding before
+/// the call is copied to `olddelta` unless it is null.
+pub fn sys_adjtime(cred: &Credentials, delta: usize, olddelta: usize) -> KResult<()> {
+    let left = match delta {
+        0 => locked(|state| {
+            let (_, slewed) = state.monotonic(clocksource::now_ns());
+            state.slew - slewed
+        }),
+        _ => {
+            let delta = unsafe { uaccess::read_user::<Timeval>(delta)? };
+            adjtime(delta.to_signed_ns()?, cred)?
+        }
+    };
+
+    if olddelta != 0 {
+        uaccess::write_user(olddelta, &Timeval::from_signed_ns(left))?;
+    }
+    Ok(())
+}
+
+pub mod ktests {
+    use super::*;
+
+    const SECOND: u64 = NS_PER_SEC;
+
+    crate::os::ktest::kernel_test! {
+        fn times_are_validated_and_converted() {
+            assert_eq!(Timeval { sec: 2, usec: 500 }.to_ns(), Ok(2_000_500_000));
+            assert_eq!(Timeval { sec: 0, usec: 1_000_000 }.to_ns(), Err(Errno::EINVAL));
+            assert_eq!(Timeval { sec: -1, usec: 0 }.to_ns(), Err(Errno::EINVAL));
+            assert_eq!(Timeval { sec: -1, usec: 500_000 }.to_signed_ns(), Ok(-500_000_000));
+            assert_eq!(Timespec { sec: 0, nsec: -1 }.to_ns(), Err(Errno::EINVAL));
+
+            // Leftover time rounds up, so a pending timer never reads as zero
+            assert_eq!(Timeval::from_ns(1), Timeval { sec: 0, usec: 1 });
+            assert_eq!(Timeval::from_ns(SECOND - 1), Timeval { sec: 1, usec: 0 });
+            assert_eq!(Timeval::from_signed_ns(-1_500_000_000), Timeval { sec: -2, usec: 500_000 });
+            assert_eq!(Timespec::from_ns(3 * SECOND + 7), Timespec { sec: 3, nsec: 7 });
+        }
+
+        fn civil_dates_convert_to_epoch_days() {
+            assert_eq!(days_from_civil(1970, 1, 1), 0);
+            assert_eq!(days_from_civil(2000, 3, 1) * 86_400, 951_868_800);
+            assert_eq!(days_from_civil(2024, 2, 29) * 86_400 + 12 * 3600 + 34 * 60 + 56, 1_709_210_096);
+            assert_eq!(days_from_civil(1969, 12, 31), -1);
+        }
+
+        fn frequency_correction_scales_elapsed_time() {
+            let mut state = State::new();
+            state.frequency = 100_000;
+
+            // 100 ppm fast: 100 us gained per second
+            assert_eq!(state.monotonic(SECOND).0, SECOND + 100_000);
+            state.fold(SECOND);
+            state.frequency = -MAX_FREQUENCY_PPB;
+            assert_eq!(state.monotonic(2 * SECOND).0, 2 * SECOND + 100_000 - 500_000);
+        }
+
+        fn slews_are_spread_out_and_used_up() {
+            let mut state = State::new();
+            state.slew = 1_000_000;
+
+            // 0.5 ms per second: the first second takes half, the next the rest, then no more
+            assert_eq!(state.monotonic(SECOND), (SECOND + 500_000, 500_000));
+            assert_eq!(state.monotonic(3 * SECOND), (3 * SECOND + 1_000_000, 1_000_000));
+            state.fold(SECOND);
+            assert_eq!(state.slew, 500_000);
+            assert_eq!(state.monotonic(3 * SECOND).0, 3 * SECOND + 1_000_000);
+
+            // Slewing backwards slows the clock but never turns it round
+            let mut state = State::new();
+            state.slew = -10 * SECOND as i64;
+            let (a, b) = (state.monotonic(SECOND).0, state.monotonic(2 * SECOND).0);
+            assert!(a < b);
+            assert_eq!(a, SECOND - 500_000);
+        }
+
+        fn setting_realtime_leaves_monotonic_alone() {
+            let (mono, real) = (monotonic_ns(), realtime_ns());
+
+            assert_eq!(settime(CLOCK_MONOTONIC, 0, &Credentials::ROOT), Err(Errno::EINVAL));
+            assert_eq!(settime(99, 0, &Credentials::ROOT), Err(Errno::EINVAL));
+            assert_eq!(settime(CLOCK_REALTIME, 0, &Credentials::new(1000, 1000)), Err(Errno::EPERM));
+            assert_eq!(set_frequency(MAX_FREQUENCY_PPB + 1, &Credentials::ROOT), Err(Errno::EINVAL));
+
+            settime(CLOCK_REALTIME, real + 3600 * SECOND, &Credentials::ROOT).unwrap();
+            assert!(realtime_ns() >= real + 3600 * SECOND);
+            assert!(monotonic_ns() >= mono && monotonic_ns() < mono + 3600 * SECOND);
+
+            settime(CLOCK_REALTIME, real, &Credentials::ROOT).unwrap();
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Kernel timers: callbacks run once a deadline passes, optionally again at a fixed interval.
+//!
+//! Timers sit on a hashed timing wheel of [`WHEEL_SLOTS`] buckets, one per millisecond tick: a
+//! timer expiring at tick `t` is linked into bucket `t % WHEEL_SLOTS`, so each tick only looks
+//! at the timers hashed to it. Timers further out than one turn of the wheel share buckets
+//! with nearer ones and are skipped until their tick comes round.
+//!
+//! Deadlines are in `CLOCK_MONOTONIC` time, which setting the date does not move.
+//!
+//! [`run`] advances the wheel to the current time and runs the callbacks of expired timers,
+//! outside the wheel's lock so they may add and cancel timers themselves. It is called from the
+//! idle loop, which sleeps no longer than [`next_event_ns`] says.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch;
+use crate::os::timekeeping;
+use crate::os::errno::{Errno, KResult};
+
+/// Length of a tick, the wheel's resolution.
+pub const TICK_NS: u64 = 1_000_000;
+
+/// Number of buckets on the wheel.
+pub const WHEEL_SLOTS: usize = 256;
+
+/// Maximum number of timers pending at once.
+pub const MAX_TIMERS: usize = 128;
+
+/// Called when a timer expires, with its ID, the data it was added with and the number of
+/// expirations since the last call (more than 1 when a periodic timer's callback ran late).
+pub type Callback = fn(id: TimerId, data: usize, expirations: u64);
+
+/// A pending timer. IDs are not reused while the timer is pending, and stale IDs of expired
+/// or cancelled timers refer to nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    slot: u16,
+    generation: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    // Tick the timer expires at and, for periodic timers, ticks between expirations
+    expires: u64,
+    interval: u64,
+
+    callback: Callback,
+    data: usize,
+
+    // Next timer in the same bucket
+    next: Option<u16>,
+}
+
+// An expiration handed to `run`
+#[derive(Debug, Clone, Copy)]
+struct Expired {
+    id: TimerId,
+    callback: Callback,
+    data: usize,
+    expirations: u64,
+}
+
+struct Wheel {
+    timers: [Option<Timer>; MAX_TIMERS],
+    generations: [u16; MAX_TIMERS],
+    buckets: [Option<u16>; WHEEL_SLOTS],
+
+    // First tick not fully processed yet
+    current: u64,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Wheel { timers: [None; MAX_TIMERS], generations: [0; MAX_TIMERS], buckets: [None; WHEEL_SLOTS], current: 0 }
+    }
+
+    fn get(&self, id: TimerId) -> Option<&Timer> {
+        let slot = id.slot as usize;
+        if self.generations[slot] != id.generation {
+            return None;
+        }
+        self.timers[slot].as_ref()
+    }
+
+    fn link(&mut self, slot: u16) {
+        let Some(timer) = self.timers[slot as usize].as_mut() else {
+            return;
+        };
+
+        // A deadline already behind the wheel would otherwise wait for a full turn
+        timer.expires = timer.expires.max(self.current);
+        let bucket = (timer.expires % WHEEL_SLOTS as u64) as usize;
+        timer.next = self.buckets[bucket];
+        self.buckets[bucket] = Some(slot);
+    }
+
+    fn unlink(&mut self, slot: u16) {
+        let Some(timer) = self.timers[slot as usize] else {
+            return;
+        };
+        let bucket = (timer.expires % WHEEL_SLOTS as u64) as usize;
+
+        let mut link = &mut self.buckets[bucket];
+        while let Some(at) = *link {
+            if at == slot {
+                *link = timer.next;
+                return;
+            }
+            link = match self.timers[at as usize].as_mut() {
+                Some(other) => &mut other.next,
+                None => return,
+            };
+        }
+    }
+
+    fn insert(&mut self, expires: u64, interval: u64, callback: Callback, data: usize) -> KResult<TimerId> {
+        let slot = self.timers.iter().position(Option::is_none).ok_or(Errno::EAGAIN)?;
+
+        self.timers[slot] = Some(Timer { expires, interval, callback, data, next: None });
+        self.link(slot as u16);
+        Ok(TimerId { slot: slot as u16, generation: self.generations[slot] })
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        self.get(id)?;
+
+        self.unlink(id.slot);
+        self.generations[id.slot as usize] = self.generations[id.slot as usize].wrapping_add(1);
+        self.timers[id.slot as usize].take()
+    }
+
+    // Takes the next timer expired by tick `now`, advancing the wheel as buckets run dry.
+    // Periodic timers are put back for their next expiration; one-shot timers are removed.
+    fn pop_expired(&mut self, now: u64) -> Option<Expired> {
+        // After a long gap one turn of the wheel visits every bucket
+        if now >= self.current + WHEEL_SLOTS as u64 {
+            self.current = now + 1 - WHEEL_SLOTS as u64;
+        }
+
+        while self.current <= now {
+            let mut next = self.buckets[(self.current % WHEEL_SLOTS as u64) as usize];
+            while let Some(slot) = next {
+                let timer = self.timers[slot as usize]?;
+                next = timer.next;
+                if timer.expires > now {
+                    continue;
+                }
+
+                let id = TimerId { slot, generation: self.generations[slot as usize] };
+                let mut expired = Expired { id, callback: timer.callback, data: timer.data, expirations: 1 };
+
+                // One-shot timers have no interval to divide by
+                if let Some(missed) = (now - timer.expires).checked_div(timer.interval) {
+                    expired.expirations += missed;
+                    self.unlink(slot);
+                    if let Some(timer) = self.timers[slot as usize].as_mut() {
+                        timer.expires += expired.expirations * timer.interval;
+                    }
+                    self.link(slot);
+                } else {
+                    self.remove(id);
+                }
+                return Some(expired);
+            }
+
+            if self.current == now {
+                break;
+            }
+            self.current += 1;
+        }
+
+        None
+    }
+
+    fn next_expiry(&self) -> Option<u64> {
+        self.timers.iter().flatten().map(|timer| timer.expires).min()
+    }
+}
+
+static mut WHEEL: Wheel = Wheel::new();
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the wheel holding its lock, with interrupts masked so a holder cannot be
+// interrupted by something spinning on it
+fn locked<R>(f: impl FnOnce(&mut Wheel) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let wheel = &raw mut WHEEL;
+            f(&mut *wheel)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+/// The monotonic tick count: whole ticks of `CLOCK_MONOTONIC` since boot. The scheduler
+/// accounts CPU time in these.
+pub fn current_tick() -> u64 {
+    timekeeping::monotonic_ns() / TICK_NS
+}
+
+// Ticks covering `ns`, rounded up so a timer never fires early
+fn ticks(ns: u64) -> u64 {
+    ns.div_ceil(TICK_NS)
+}
+
+/// Adds a timer expiring `delay_ns` from now and then, if `interval_ns` is not 0, every
+/// `interval_ns`. Both are rounded up to whole ticks. Fails with `EAGAIN` when
+/// [`MAX_TIMERS`] timers are pending.
+pub fn add(delay_ns: u64, interval_ns: u64, callback: Callback, data: usize) -> KResult<TimerId> {
+    let expires = ticks(timekeeping::monotonic_ns().saturating_add(delay_ns));
+    locked(|wheel| wheel.insert(expires, ticks(interval_ns), callback, data))
+}
+
+/// Cancels a pending timer, returning the time that was left until it expired, or `None` if
+/// it had already expired or been cancelled.
+pub fn cancel(id: TimerId) -> Option<u64> {
+    let timer = locked(|wheel| wheel.remove(id))?;
+    Some((timer.expires * TICK_NS).saturating_sub(timekeeping::monotonic_ns()))
+}
+
+/// The time left until a pending timer expires and its interval, both in nanoseconds, or
+/// `None` if the timer is no longer pending.
+pub fn remaining(id: TimerId) -> Option<(u64, u64)> {
+    let timer = locked(|wheel| wheel.get(id).copied())?;
+    Some(((timer.expires * TICK_NS).saturating_sub(timekeeping::monotonic_ns()), timer.interval * TICK_NS))
+}
+
+/// Time until the next timer expires, for the idle governor. `None` with no timer pending.
+pub fn next_event_ns() -> Option<u64> {
+    let expires = locked(|wheel| wheel.next_expiry())?;
+    Some((expires * TICK_NS).saturating_sub(timekeeping::monotonic_ns()))
+}
+
+/// Runs the callbacks of all timers that have expired. Called from the idle loop.
+pub fn run() {
+    let now = current_tick();
+
+    while let Some(expired) = locked(|wheel| wheel.pop_expired(now)) {
+        (expired.callback)(expired.id, expired.data, expired.expirations);
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    fn ignore(_id: TimerId, _data: usize, _expirations: u64) {}
+
+    static mut TEST_WHEEL: Wheel = Wheel::new();
+
+    fn test_wheel() -> &'static mut Wheel {
+        unsafe {
+            let wheel = &raw mut TEST_WHEEL;
+            *wheel = Wheel::new();
+            &mut *wheel
+        }
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn timers_expire_in_order() {
+            let wheel = test_wheel();
+            let late = wheel.insert(30, 0, ignore, 2).unwrap();
+            let early = wheel.insert(10, 0, ignore, 1).unwrap();
+
+            assert!(wheel.pop_expired(9).is_none());
+            assert_eq!(wheel.pop_expired(10).map(|e| (e.id, e.data)), Some((early, 1)));
+            assert!(wheel.pop_expired(29).is_none());
+            assert_eq!(wheel.pop_expired(40).map(|e| (e.id, e.expirations)), Some((late, 1)));
+            assert!(wheel.pop_expired(40).is_none());
+
+            // Expired one-shot timers are gone, and their IDs with them
+            assert!(wheel.get(early).is_none());
+            assert!(wheel.remove(late).is_none());
+            assert_eq!(wheel.next_expiry(), None);
+        }
+
+        fn timers_a_turn_apart_share_a_bucket() {
+            let wheel = test_wheel();
+            let far = wheel.insert(5 + WHEEL_SLOTS as u64, 0, ignore, 0).unwrap();
+            let near = wheel.insert(5, 0, ignore, 0).unwrap();
+
+            assert_eq!(wheel.pop_expired(5).map(|e| e.id), Some(near));
+            assert!(wheel.pop_expired(5 + WHEEL_SLOTS as u64 - 1).is_none());
+            assert_eq!(wheel.pop_expired(5 + WHEEL_SLOTS as u64).map(|e| e.id), Some(far));
+
+            // Deadlines the wheel has passed fire on the next run
+            wheel.insert(0, 0, ignore, 0).unwrap();
+            assert!(wheel.pop_expired(wheel.current).is_some());
+        }
+
+        fn periodic_timers_count_missed_expirations() {
+            let wheel = test_wheel();
+            let id = wheel.insert(10, 5, ignore, 0).unwrap();
+
+            assert_eq!(wheel.pop_expired(10).map(|e| e.expirations), Some(1));
+            assert_eq!(wheel.get(id).map(|t| t.expires), Some(15));
+
+            // Running 12 ticks late covers three expirations: 15, 20 and 25
+            assert_eq!(wheel.pop_expired(27).map(|e| e.expirations), Some(3));
+            assert_eq!(wheel.get(id).map(|t| t.expires), Some(30));
+
+            assert!(wheel.remove(id).is_some());
+            assert!(wheel.pop_expired(1000).is_none());
+        }
+
+        fn cancelled_timers_leave_their_bucket() {
+            let wheel = test_wheel();
+            let ids = [wheel.insert(7, 0, ignore, 0).unwrap(), wheel.insert(7, 0, ignore, 1).unwrap(), wheel.insert(7, 0, ignore, 2).unwrap()];
+
+            assert!(wheel.remove(ids[1]).is_some());
+            assert!(wheel.remove(ids[1]).is_none());
+
+            let mut fired = [wheel.pop_expired(7).unwrap().data, wheel.pop_expired(7).unwrap().data];
+            fired.sort_unstable();
+            assert_eq!(fired, [0, 2]);
+            assert!(wheel.pop_expired(7).is_none());
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! TLB maintenance across CPUs.
+//!
+//! A CPU caches translations for the address space it has loaded (and for global kernel
+//! pages), so changing or removing a mapping is only complete once every CPU that could hold
+//! the old translation has flushed it. Each CPU records the page table root it runs on; a
+//! shootdown sends a function-call IPI to exactly the CPUs using the affected root (all of them
+//! for kernel mappings) and waits for them to invalidate.
+//!
+//! Callers update the page tables first and shoot down afterwards: a CPU that switches to the
+//! root in between loads it after the change and so cannot cache the stale entry.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::os::arch::{Arch, Current};
+use crate::os::ipi;
+use crate::os::percpu::{self, MAX_CPUS, PerCpu};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Ranges longer than this many pages are flushed wholesale instead of page by page.
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// Page table root each CPU currently has loaded
+static ACTIVE_ROOT: PerCpu<u64> = PerCpu::new(0);
+
+// The kernel-only root CPUs fall back to when their address space is destroyed
+static KERNEL_ROOT: AtomicU64 = AtomicU64::new(0);
+
+/// Records the root the boot CPU is running on as the kernel root. Called once at boot.
+pub fn init() {
+    let root = Current::page_table_root();
+    KERNEL_ROOT.store(root, Ordering::Relaxed);
+    note_active(root);
+}
+
+/// Makes `root` the kernel root and switches this CPU to it, for when the kernel replaces the
+/// tables it booted on with its own.
+///
+/// # Safety
+/// `root` must map the kernel, including the running code and stack.
+pub unsafe fn set_kernel_root(root: u64) {
+    KERNEL_ROOT.store(root, Ordering::Relaxed);
+    unsafe { switch_to(root) };
+}
+
+/// The root the kernel booted on, or the one it built to replace it, which holds the kernel's
+/// own mappings (0 on a CPU with translation off).
+pub fn kernel_root() -> u64 {
+    KERNEL_ROOT.load(Ordering::Relaxed)
+}
+
+/// Records the root this CPU is running on without switching (AP bring-up).
+pub fn note_active(root: u64) {
+    ACTIVE_ROOT.with(|active| *active = root);
+}
+
+/// Switches this CPU to the address space rooted at `root`.
+///
+/// # Safety
+/// `root` must map the kernel, including the running code and stack.
+pub unsafe fn switch_to(root: u64) {
+    ACTIVE_ROOT.with(|active| {
+        if *active != root {
+            unsafe { Current::set_page_table_root(root) };
+            *active = root;
+        }
+    });
+}
+
+/// Invalidates this CPU's translation for the page containing `addr`, global or not.
+#[inline]
+pub fn flush_page(addr: u64) {
+    Current::flush_tlb_page(addr);
+}
+
+/// Invalidates all of this CPU's non-global translations.
+pub fn flush_local() {
+    Current::flush_tlb_local();
+}
+
+/// Invalidates all of this CPU's translations, global pages included.
+pub fn flush_local_global() {
+    Current::flush_tlb_all_local();
+}
+
+/// What a shootdown invalidates; lives on the initiator's stack for the duration of the call.
+struct Request {
+    /// Address space the range belongs to, or `None` for kernel (global) mappings.
+    root: Option<u64>,
+    start: u64,
+    pages: u64,
+}
+
+fn flush_request(arg: usize) {
+    let request = unsafe { &*(arg as *const Request) };
+
+    // The CPU may have switched away since the initiator looked; its TLB is then clean already
+    if let Some(root) = request.root
+        && ACTIVE_ROOT.with(|active| *active) != root
+    {
+        return;
+    }
+
+    if request.pages > FLUSH_ALL_THRESHOLD {
+        if request.root.is_some() { flush_local() } else { flush_local_global() }
+        return;
+    }
+
+    for page in 0..request.pages {
+        flush_page(request.start + page * PAGE_SIZE);
+    }
+}
+
+// CPUs that have `root` loaded, or all online CPUs for kernel mappings
+fn cpus_using(root: Option<u64>) -> u64 {
+    let Some(root) = root else {
+        return percpu::online_mask();
+    };
+
+    (0..MAX_CPUS)
+        .filter(|&cpu| percpu::online_mask() & (1 << cpu) != 0 && ACTIVE_ROOT.read(cpu) == root)
+        .fold(0, |mask, cpu| mask | 1 << cpu)
+}
+
+/// Invalidates `pages` pages starting at `start` on every CPU that may have cached them, after
+/// the mappings were changed or removed. `root` is the address space the pages belong to, or
+/// `None` for kernel mappings shared by all address spaces.
+pub fn shootdown(root: Option<u64>, start: u64, pages: u64) {
+    let request = Request { root, start: start & !(PAGE_SIZE - 1), pages };
+    ipi::call_on(cpus_using(root), flush_request, &request as *const Request as usize);
+}
+
+/// Shoots down a single page.
+pub fn shootdown_page(root: Option<u64>, addr: u64) {
+    shootdown(root, addr, 1);
+}
+
+fn leave_root(arg: usize) {
+    let root = arg as u64;
+
+    if ACTIVE_ROOT.with(|active| *active) == root {
+        unsafe { switch_to(KERNEL_ROOT.load(Ordering::Relaxed)) };
+    }
+}
+
+/// Moves every CPU still running on `root` (idle CPUs keep the last address space loaded)
+/// to the kernel root. Must be called before the page tables of a dying address space are
+/// freed, so no CPU walks or caches freed tables.
+pub fn release_address_space(root: u64) {
+    assert_ne!(root, KERNEL_ROOT.load(Ordering::Relaxed), "tlb: cannot release the kernel root");
+    ipi::call_on(cpus_using(Some(root)), leave_root, root as usize);
+}
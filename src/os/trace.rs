@@ -0,0 +1,161 @@
+//! Function entry/exit tracer (ftrace-lite), enabled by the `ftrace` feature.
+//!
+//! Hot paths mark themselves with [`trace_fn!`]; while tracing is switched on at runtime every
+//! entry and exit is appended to a fixed ring buffer with its counter timestamp, and exits carry the
+//! time spent in the function. [`dump`] prints the buffer over serial. Without the feature the
+//! macro expands to nothing, so instrumented functions cost nothing in normal builds.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch::counter;
+use crate::serial_println;
+
+/// Number of events kept; older events are overwritten.
+const TRACE_CAPACITY: usize = 4096;
+
+/// What a trace event records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Entry,
+
+    /// Function exit, with the counter ticks spent since the matching entry.
+    Exit { ticks: u64 },
+}
+
+/// One recorded event.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub timestamp: u64,
+    pub function: &'static str,
+    pub kind: EventKind,
+
+    /// Nesting depth at the time of the event (0 = outermost traced function).
+    pub depth: u16,
+}
+
+// Runtime on/off switch, so the tracer can be armed around just the interesting window
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Ring buffer of events plus the index of the next slot and the total number ever written
+static mut EVENTS: [Option<TraceEvent>; TRACE_CAPACITY] = [None; TRACE_CAPACITY];
+static mut NEXT: usize = 0;
+static mut WRITTEN: u64 = 0;
+
+// Current nesting depth of traced functions
+static mut DEPTH: u16 = 0;
+
+/// Starts recording events.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stops recording events; the buffer is kept for [`dump`].
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Returns `true` while events are being recorded.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Discards every recorded event.
+pub fn clear() {
+    unsafe {
+        let events = &raw mut EVENTS;
+        (*events).fill(None);
+        NEXT = 0;
+        WRITTEN = 0;
+    }
+}
+
+fn record(function: &'static str, kind: EventKind, depth: u16) {
+    unsafe {
+        let events = &raw mut EVENTS;
+        (*events)[NEXT] = Some(TraceEvent { timestamp: counter::read(), function, kind, depth });
+
+        NEXT = (NEXT + 1) % TRACE_CAPACITY;
+        WRITTEN += 1;
+    }
+}
+
+/// Guard created by [`trace_fn!`]: records the entry on creation and the exit when dropped.
+pub struct FunctionTrace {
+    function: &'static str,
+    start: u64,
+    active: bool,
+}
+
+impl FunctionTrace {
+    #[inline]
+    pub fn enter(function: &'static str) -> Self {
+        if !is_enabled() {
+            return FunctionTrace { function, start: 0, active: false };
+        }
+
+        unsafe {
+            record(function, EventKind::Entry, DEPTH);
+            DEPTH += 1;
+        }
+
+        FunctionTrace { function, start: counter::read(), active: true }
+    }
+}
+
+impl Drop for FunctionTrace {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+
+        unsafe {
+            DEPTH = DEPTH.saturating_sub(1);
+            record(self.function, EventKind::Exit { ticks: counter::read() - self.start }, DEPTH);
+        }
+    }
+}
+
+/// Traces entry to and exit from the enclosing function. Place it at the top of the body.
+#[macro_export]
+macro_rules! trace_fn {
+    () => {
+        #[cfg(feature = "ftrace")]
+        let _function_trace = $crate::os::trace::FunctionTrace::enter({
+            // The type name of a nested fn is "path::to::enclosing::__trace_marker"
+            fn __trace_marker() {}
+            let name = core::any::type_name_of_val(&__trace_marker);
+            &name[..name.len() - "::__trace_marker".len()]
+        });
+    };
+}
+
+/// Prints the recorded events, oldest first, with exit durations in microseconds.
+pub fn dump() {
+    let ticks_per_us = (counter::ticks_per_ms() / 1000).max(1);
+
+    unsafe {
+        let events = &raw const EVENTS;
+        let kept = WRITTEN.min(TRACE_CAPACITY as u64) as usize;
+        let first = (NEXT + TRACE_CAPACITY - kept) % TRACE_CAPACITY;
+
+        serial_println!("trace: {} events ({} overwritten)", kept, WRITTEN - kept as u64);
+
+        for i in 0..kept {
+            let Some(event) = (*events)[(first + i) % TRACE_CAPACITY] else {
+                continue;
+            };
+
+            let indent = event.depth as usize * 2;
+            match event.kind {
+                EventKind::Entry => {
+                    serial_println!("trace: {:>20} {:indent$}-> {}", event.timestamp, "", event.function)
+                }
+                EventKind::Exit { ticks } => serial_println!(
+                    "trace: {:>20} {:indent$}<- {} ({} us)",
+                    event.timestamp, "", event.function, ticks / ticks_per_us
+                ),
+            }
+        }
+    }
+}
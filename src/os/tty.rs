@@ -0,0 +1,309 @@
+//! The console terminal's job control side: its session and foreground process group.
+//!
+//! A session leader makes the console its controlling terminal with `TIOCSCTTY`, and the shell
+//! then picks the foreground process group with `tcsetpgrp`. The console driver hands typed
+//! characters to [`receive`], which turns the interrupt, quit and suspend characters (Ctrl+C,
+//! Ctrl+\ and Ctrl+Z) into `SIGINT`, `SIGQUIT` and `SIGTSTP` for the foreground group.
+//! Background groups that read from the terminal are sent `SIGTTIN`, and with `TOSTOP` set
+//! those that write to it `SIGTTOU`, as are those that try to change the foreground group;
+//! see [`check_read`] and [`check_write`].
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch;
+use crate::os::errno::{Errno, KResult};
+use crate::os::jobctl::{self, SIGINT, SIGQUIT, SIGTSTP, SIGTTIN, SIGTTOU};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::uaccess;
+
+/// Interrupt character (Ctrl+C): sends `SIGINT`.
+pub const VINTR: u8 = 0x03;
+
+/// Quit character (Ctrl+\): sends `SIGQUIT`.
+pub const VQUIT: u8 = 0x1c;
+
+/// Suspend character (Ctrl+Z): sends `SIGTSTP`.
+pub const VSUSP: u8 = 0x1a;
+
+/// `ioctl`: make the terminal the caller's controlling terminal.
+pub const TIOCSCTTY: u32 = 0x540e;
+
+/// `ioctl`: read the foreground process group (`tcgetpgrp`).
+pub const TIOCGPGRP: u32 = 0x540f;
+
+/// `ioctl`: set the foreground process group (`tcsetpgrp`).
+pub const TIOCSPGRP: u32 = 0x5410;
+
+/// `ioctl`: give up the controlling terminal.
+pub const TIOCNOTTY: u32 = 0x5422;
+
+struct Terminal {
+    // The session the terminal controls, if any
+    session: Option<u64>,
+
+    // Process group in the foreground
+    foreground: u64,
+
+    // TOSTOP: stop background writers with SIGTTOU
+    tostop: bool,
+}
+
+static mut CONSOLE: Terminal = Terminal { session: None, foreground: 0, tostop: false };
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+fn locked<R>(f: impl FnOnce(&mut Terminal) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let console = &raw mut CONSOLE;
+            f(&mut *console)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+// The foreground group, if the console is session `sid`'s controlling terminal
+fn foreground_of(sid: u64) -> Option<u64> {
+    locked(|tty| (tty.session == Some(sid)).then_some(tty.foreground))
+}
+
+/// Makes the console the controlling terminal of `process`'s session, with the caller's group
+/// in the foreground. Fails with `EPERM` unless the caller leads its session and the console
+/// is free or already its own.
+pub fn set_controlling(process: &Process) -> KResult<()> {
+    if process.pid != process.sid {
+        return Err(Errno::EPERM);
+    }
+
+    locked(|tty| match tty.session {
+        Some(session) if session != process.sid => Err(Errno::EPERM),
+        Some(_) => Ok(()),
+        None => {
+            tty.session = Some(process.sid);
+            tty.foreground = process.pgid;
+            Ok(())
+        }
+    })
+}
+
+/// Frees the console from session `sid`, returning the group that was in the foreground, or
+/// `None` if the console was not `sid`'s.
+pub fn disassociate(sid: u64) -> Option<u64> {
+    locked(|tty| {
+        if tty.session != Some(sid) {
+            return None;
+        }
+        tty.session = None;
+        Some(tty.foreground)
+    })
+}
+
+/// Sets `TOSTOP`, under which background writes stop the writer with `SIGTTOU`.
+pub fn set_tostop(enabled: bool) {
+    locked(|tty| tty.tostop = enabled);
+}
+
+// Whether process `pid` may go ahead with an operation reserved to the foreground; otherwise
+// `signal` is sent to its group, and the call is restarted once it is continued. Fails with
+// `EIO` when it would never be continued: SIGTTIN ignored or the group orphaned.
+fn check_foreground(pid: u64, signal: u32) -> KResult<()> {
+    let (pgid, sid, ignored) =
+        ptable::with_process(pid, |p| (p.pgid, p.sid, jobctl::ignores(p, signal))).ok_or(Errno::ESRCH)?;
+    if foreground_of(sid).is_none_or(|foreground| foreground == pgid) {
+        return Ok(());
+    }
+
+    if ignored {
+        return if signal == SIGTTIN { Err(Errno::EIO) } else { Ok(()) };
+    }
+    if jobctl::is_orphaned(pgid) {
+        return Err(Errno::EIO);
+    }
+
+    jobctl::signal_own_group(pid, signal);
+    Err(Errno::EINTR)
+}
+
+/// Checks whether process `pid` may read from the console: a background group is sent
+/// `SIGTTIN` and the read fails with `EINTR`, to be restarted once the group is continued, or
+/// with `EIO` if it is orphaned or ignores `SIGTTIN`.
+pub fn check_read(pid: u64) -> KResult<()> {
+    check_foreground(pid, SIGTTIN)
+}
+
+/// Checks whether process `pid` may write to the console: with `TOSTOP` set, a background
+/// group that does not ignore `SIGTTOU` is sent it and the write fails with `EINTR`, or `EIO`
+/// if the group is orphaned.
+pub fn check_write(pid: u64) -> KResult<()> {
+    if !locked(|tty| tty.tostop) {
+        return Ok(());
+    }
+    check_foreground(pid, SIGTTOU)
+}
+
+// The session of process `pid`
+fn session_of(pid: u64) -> KResult<u64> {
+    ptable::with_process(pid, |p| p.sid).ok_or(Errno::ESRCH)
+}
+
+/// `tcgetpgrp()`: the console's foreground process group. Fails with `ENOTTY` unless the
+/// console is the controlling terminal of the caller `caller`.
+pub fn tcgetpgrp(caller: u64) -> KResult<u64> {
+    foreground_of(session_of(caller)?).ok_or(Errno::ENOTTY)
+}
+
+/// `tcsetpgrp(pgid)`: puts group `pgid`, which must be in the session of the caller `caller`,
+/// in the foreground. A background caller is sent `SIGTTOU` as for [`check_write`], `TOSTOP`
+/// or not. Fails with `ENOTTY` unless the console is the caller's controlling terminal,
+/// `EINVAL` for a negative `pgid` and `EPERM` for one not in the session.
+pub fn tcsetpgrp(caller: u64, pgid: i64) -> KResult<()> {
+    let sid = session_of(caller)?;
+    foreground_of(sid).ok_or(Errno::ENOTTY)?;
+    if pgid < 0 {
+        return Err(Errno::EINVAL);
+    }
+    check_foreground(caller, SIGTTOU)?;
+
+    let pgid = pgid as u64;
+    let mut in_session = false;
+    ptable::for_each(|p| in_session |= p.pgid == pgid && p.sid == sid);
+    if !in_session {
+        return Err(Errno::EPERM);
+    }
+
+    locked(|tty| tty.foreground = pgid);
+    Ok(())
+}
+
+/// Handles a character typed on the console: the interrupt, quit and suspend characters
+/// signal the foreground group, if the console has a session, and are consumed. Returns
+/// whether `byte` was; other characters are input.
+pub fn receive(byte: u8) -> bool {
+    let signal = match byte {
+        VINTR => SIGINT,
+        VQUIT => SIGQUIT,
+        VSUSP => SIGTSTP,
+        _ => return false,
+    };
+
+    let Some(foreground) = locked(|tty| tty.session.map(|_| tty.foreground)) else {
+        return false;
+    };
+    _ = jobctl::signal_group(foreground, signal);
+    true
+}
+
+/// `ioctl(fd, request, arg)` on the console for the job control requests of the caller
+/// `caller`: `TIOCGPGRP` and `TIOCSPGRP` with a process group ID at `arg`, `TIOCSCTTY` and
+/// `TIOCNOTTY`. Fails with `ENOTTY` for other requests and as the calls above do.
+pub fn sys_ioctl(caller: u64, request: u32, arg: usize) -> KResult<usize> {
+    match request {
+        TIOCGPGRP => {
+            let pgid = tcgetpgrp(caller)? as i32;
+            uaccess::copy_to_user(arg, &pgid.to_ne_bytes())?;
+        }
+        TIOCSPGRP => {
+            let mut pgid = [0u8; 4];
+            uaccess::copy_from_user(&mut pgid, arg)?;
+            tcsetpgrp(caller, i32::from_ne_bytes(pgid) as i64)?;
+        }
+        TIOCSCTTY => ptable::with_process(caller, |process| set_controlling(process)).unwrap_or(Err(Errno::ESRCH))?,
+        TIOCNOTTY => {
+            let sid = session_of(caller)?;
+            foreground_of(sid).ok_or(Errno::ENOTTY)?;
+            if caller == sid {
+                jobctl::end_session(sid);
+            }
+        }
+        _ => return Err(Errno::ENOTTY),
+    }
+    Ok(0)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::process::ProcessState;
+
+    fn spawn(pid: u64, ppid: u64, pgid: u64) {
+        let mut process = Process::new(pid, ppid, "tty");
+        process.state = ProcessState::Ready;
+        process.pgid = pgid;
+        process.sid = 9980;
+        ptable::insert(process).unwrap();
+    }
+
+    fn state(pid: u64) -> Option<ProcessState> {
+        ptable::with_process(pid, |p| p.state)
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn ctrl_z_stops_the_foreground_job() {
+            // The shell leads session 9980; job 9981 runs in the foreground, 9983 behind it.
+            // Like any job control shell, it ignores SIGTTOU to take the terminal back.
+            let mut shell = Process::new(9980, 0, "sh");
+            shell.signal_handlers[SIGTTOU as usize] = jobctl::SIG_IGN;
+            ptable::insert(shell).unwrap();
+            assert_eq!(tcgetpgrp(9980), Err(Errno::ENOTTY));
+            assert_eq!(sys_ioctl(9980, TIOCSCTTY, 0), Ok(0));
+            spawn(9981, 9980, 9981);
+            spawn(9982, 9980, 9981);
+            spawn(9983, 9980, 9983);
+
+            assert_eq!(tcsetpgrp(9980, 9981), Ok(()));
+            assert_eq!(tcsetpgrp(9980, 9999), Err(Errno::EPERM));
+            assert_eq!(tcgetpgrp(9980), Ok(9981));
+
+            assert!(!receive(b'z'));
+            assert!(receive(VSUSP));
+            assert_eq!([state(9981), state(9982), state(9983)], [Some(ProcessState::Suspended), Some(ProcessState::Suspended), Some(ProcessState::Ready)]);
+
+            // `fg`: the shell hands the job the terminal and continues it
+            assert_eq!(tcsetpgrp(9980, 9981), Ok(()));
+            assert_eq!(jobctl::sys_kill(9980, -9981, jobctl::SIGCONT), Ok(()));
+            assert_eq!(state(9981), Some(ProcessState::Ready));
+
+            assert_eq!(disassociate(9980), Some(9981));
+            for pid in [9980, 9981, 9982, 9983] {
+                ptable::remove(pid);
+            }
+        }
+
+        fn background_jobs_stop_on_terminal_access() {
+            let shell = Process::new(9980, 0, "sh");
+            assert_eq!(set_controlling(&shell), Ok(()));
+            ptable::insert(shell).unwrap();
+            spawn(9984, 9980, 9984);
+            let cont = || ptable::with_process(9984, |job| jobctl::send(job, jobctl::SIGCONT));
+
+            // With the shell in the foreground, 9984 is a background job
+            assert_eq!(check_write(9984), Ok(()));
+            assert_eq!(check_read(9984), Err(Errno::EINTR));
+            assert_eq!(state(9984), Some(ProcessState::Suspended));
+
+            cont();
+            set_tostop(true);
+            assert_eq!(check_write(9984), Err(Errno::EINTR));
+            cont();
+            set_tostop(false);
+
+            // Taking the terminal from the background stops the job, TOSTOP or not
+            assert_eq!(tcsetpgrp(9984, 9984), Err(Errno::EINTR));
+            assert_eq!(state(9984), Some(ProcessState::Suspended));
+
+            // Once orphaned, nobody could continue it: reads fail instead
+            cont();
+            ptable::remove(9980);
+            assert_eq!(check_read(9984), Err(Errno::EIO));
+            assert_eq!(state(9984), Some(ProcessState::Ready));
+            assert_eq!(disassociate(9980), Some(9980));
+            ptable::remove(9984);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch::{Arch, Current};
+use crate::os::errno::{Errno, KResult};
+use crate::os::kasan;
+
+/// First address above the canonical lower half; user pointers must stay below it.
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+// Whether user access protection (SMAP, PAN) is on, i.e. whether user copies need a window
+static ACCESS_PROTECTION: AtomicBool = AtomicBool::new(false);
+
+/// Turns on user execution and access protection (SMEP/SMAP on x86, PXN/PAN on AArch64) when the
+/// CPU has them, so stray kernel execution of or access to user memory faults instead of
+/// silently succeeding. Only the helpers below open access windows.
+pub fn init() {
+    let (exec, access) = Current::init_user_access_protection();
+    ACCESS_PROTECTION.store(access, Ordering::Relaxed);
+
+    log::info!("uaccess: execution protection {}, access protection {}", on_off(exec), on_off(access));
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled { "on" } else { "off" }
+}
+
+/// Returns `true` if `[addr, addr + len)` lies entirely in user space.
+pub fn is_user_range(addr: usize, len: usize) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+/// Opens a user access window for its lifetime.
+struct UserAccessWindow;
+
+impl UserAccessWindow {
+    fn open() -> Self {
+        if ACCESS_PROTECTION.load(Ordering::Relaxed) {
+            Current::user_access_begin();
+        }
+
+        UserAccessWindow
+    }
+}
+
+impl Drop for UserAccessWindow {
+    fn drop(&mut self) {
+        if ACCESS_PROTECTION.load(Ordering::Relaxed) {
+            Current::user_access_end();
+        }
+    }
+}
+
+/// Copies `dst.len()` bytes from the user address `src` into the kernel buffer `dst`.
+///
+/// Fails with `EFAULT` if the source range is not entirely in user space. The caller must be
+/// running in the address space the pointer belongs to.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> KResult<()> {
+    if !is_user_range(src, dst.len()) {
+        return Err(Errno::EFAULT);
+    }
+
+    kasan::check_write(dst.as_ptr() as usize, dst.len());
+
+    let _window = UserAccessWindow::open();
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+
+    Ok(())
+}
+
+/// Copies the kernel buffer `src` to the user address `dst`.
+///
+/// Fails with `EFAULT` if the destination range is not entirely in user space.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> KResult<()> {
+    if !is_user_range(dst, src.len()) {
+        return Err(Errno::EFAULT);
+    }
+
+    kasan::check_read(src.as_ptr() as usize, src.len());
+
+    let _window = UserAccessWindow::open();
+    unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+
+    Ok(())
+}
+
+/// Reads a plain value of type `T` from user memory.
+///
+/// # Safety
+/// `T` must be plain old data: every bit pattern has to be a valid `T`.
+pub unsafe fn read_user<T: Copy>(src: usize) -> KResult<T> {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+
+    // Safety: the byte view covers exactly the MaybeUninit storage, which we fully overwrite
+    let bytes = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(bytes, src)?;
+
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Writes a plain value of type `T` to user memory. `T` should have no padding bytes.
+pub fn write_user<T: Copy>(dst: usize, value: &T) -> KResult<()> {
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(dst, bytes)
+}
+
+pub mod ktests {
+    use super::*;
+
+    // The first page of the kernel half
+    const KERNEL_ADDR: usize = 0xffff_8000_0000_0000;
+
+    crate::os::ktest::kernel_test! {
+        fn user_ranges_end_at_the_lower_half() {
+            assert!(is_user_range(0, 0));
+            assert!(is_user_range(0x1000, 0x1000));
+            assert!(is_user_range(USER_SPACE_END - 1, 1));
+            assert!(is_user_range(USER_SPACE_END, 0));
+
+            assert!(!is_user_range(USER_SPACE_END - 1, 2));
+            assert!(!is_user_range(USER_SPACE_END, 1));
+            assert!(!is_user_range(KERNEL_ADDR, 8));
+        }
+
+        fn wrapping_ranges_are_rejected() {
+            assert!(!is_user_range(usize::MAX, 1));
+            assert!(!is_user_range(0x1000, usize::MAX));
+            assert!(!is_user_range(usize::MAX - 0xfff, 0x2000));
+        }
+
+        fn kernel_addresses_fault() {
+            let mut buf = [0u8; 8];
+
+            assert_eq!(copy_from_user(&mut buf, KERNEL_ADDR), Err(Errno::EFAULT));
+            assert_eq!(copy_to_user(KERNEL_ADDR, &buf), Err(Errno::EFAULT));
+            assert_eq!(copy_to_user(USER_SPACE_END - 4, &buf), Err(Errno::EFAULT));
+            assert_eq!(unsafe { read_user::<u64>(KERNEL_ADDR) }, Err(Errno::EFAULT));
+            assert_eq!(write_user(usize::MAX - 3, &0u64), Err(Errno::EFAULT));
+        }
+
+        fn user_copies_round_trip() {
+            let source = *b"uaccess!";
+            let mut target = [0u8; 8];
+
+            copy_to_user(target.as_mut_ptr() as usize, &source).unwrap();
+            assert_eq!(target, source);
+
+            let mut back = [0u8; 8];
+            copy_from_user(&mut back, target.as_ptr() as usize).unwrap();
+            assert_eq!(back, source);
+            assert_eq!(unsafe { read_user::<u64>(target.as_ptr() as usize) }, Ok(u64::from_ne_bytes(source)));
+        }
+    }
+}
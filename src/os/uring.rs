@@ -0,0 +1,717 @@
+//! Asynchronous syscall rings, in the style of Linux's io_uring.
+//!
+//! [`sys_uring_setup`] gives a process a ring: memory it shares with the kernel, holding a
+//! submission queue (SQ) of [`Sqe`]s the process fills and a completion queue (CQ) of [`Cqe`]s
+//! the kernel fills. Once it has queued entries the process calls [`sys_uring_enter`], which
+//! takes everything between the SQ head and tail in one go and hands the operations to kernel
+//! worker threads. Each finished operation posts a CQE carrying the submitter's `user_data`, so
+//! a batch of reads, writes and accepts costs one syscall and completes in any order.
+//!
+//! Head and tail indices run freely and are masked into the arrays. Each side only writes the
+//! indices it owns -- the process the SQ tail and CQ head, the kernel the SQ head and CQ tail --
+//! so the queues need no lock shared with user space. The kernel never trusts the shared
+//! memory for anything but those indices and the entries themselves: sizes and masks come
+//! from its own copies, and every SQE is copied out once before it is looked at.
+//!
+//! Workers are kernel threads, started by [`init`] and run by the scheduler like any other
+//! process. They block until operations are queued, and [`sys_uring_enter`] blocks until
+//! they have posted the completions it waits for.
+
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::os::arch::{self, Arch, Current};
+use crate::os::errno::{Errno, KResult};
+use crate::os::file::{self, FileOps};
+use crate::os::kthread;
+use crate::os::percpu;
+use crate::os::process::{DEFAULT_PRIORITY, Process, WaitTarget};
+use crate::os::ptable;
+use crate::os::sched;
+use crate::os::tlb;
+use crate::os::uaccess;
+
+/// Maximum number of rings in the system.
+pub const MAX_RINGS: usize = 16;
+
+/// Largest submission queue a ring can have.
+pub const MAX_ENTRIES: u32 = 64;
+
+/// The completion queue is this many times the size of the submission queue, so a full SQ of
+/// operations can complete before the process has reaped the previous batch.
+pub const CQ_FACTOR: u32 = 2;
+
+/// File IDs from here up stand for rings in a process's descriptor table.
+pub const RING_FILE_BASE: u32 = 0xffff_ff00;
+
+// Opcodes
+
+/// Does nothing; completes with 0.
+pub const OP_NOP: u8 = 0;
+
+/// Reads `len` bytes at `offset` of file `fd` into `addr`; completes with the bytes read.
+pub const OP_READ: u8 = 1;
+
+/// Writes `len` bytes from `addr` at `offset` of file `fd`; completes with the bytes written.
+pub const OP_WRITE: u8 = 2;
+
+/// Accepts a connection on listening socket `fd`; completes with the new descriptor.
+pub const OP_ACCEPT: u8 = 3;
+
+// Operations queued for the workers and not yet picked up
+const MAX_PENDING: usize = 128;
+
+// Kernel worker threads
+const WORKERS: usize = 2;
+
+// Workers wait on WORK_WAIT for operations, waiters on COMPLETION_WAIT_BASE + ring for CQEs
+const WORK_WAIT: u32 = 0xa4e8_0000;
+const COMPLETION_WAIT_BASE: u32 = 0xa4e8_1000;
+
+// Reads and writes go through a kernel buffer of this size
+const CHUNK_SIZE: usize = 512;
+
+// Owner value of a free ring
+const FREE: u64 = u64::MAX;
+
+/// A submission queue entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Sqe {
+    pub opcode: u8,
+
+    /// No flags are defined yet; must be 0.
+    pub flags: u8,
+    pub reserved: u16,
+    pub fd: i32,
+
+    /// Absolute file offset for reads and writes.
+    pub offset: u64,
+
+    /// User buffer of reads and writes.
+    pub addr: u64,
+    pub len: u32,
+    pub reserved2: u32,
+
+    /// Returned untouched in the operation's CQE.
+    pub user_data: u64,
+}
+
+/// A completion queue entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Cqe {
+    pub user_data: u64,
+
+    /// The operation's result, or a negated errno.
+    pub res: i32,
+    pub flags: u32,
+}
+
+/// Indices and sizes at the start of the ring memory.
+#[derive(Debug)]
+#[repr(C)]
+pub struct RingHeader {
+    /// Next SQE the kernel takes (written by the kernel).
+    pub sq_head: AtomicU32,
+
+    /// One past the last SQE the process queued (written by the process).
+    pub sq_tail: AtomicU32,
+    pub sq_mask: u32,
+    pub sq_entries: u32,
+
+    /// Next CQE the process reaps (written by the process).
+    pub cq_head: AtomicU32,
+
+    /// One past the last CQE the kernel posted (written by the kernel).
+    pub cq_tail: AtomicU32,
+    pub cq_mask: u32,
+    pub cq_entries: u32,
+
+    /// Completions dropped because the CQ was full.
+    pub cq_overflow: AtomicU32,
+}
+
+/// The memory shared between a ring's owner and the kernel.
+#[repr(C, align(4096))]
+pub struct RingMemory {
+    pub header: RingHeader,
+    pub sqes: [Sqe; MAX_ENTRIES as usize],
+    pub cqes: [Cqe; (MAX_ENTRIES * CQ_FACTOR) as usize],
+}
+
+/// What [`sys_uring_setup`] tells the process about its ring.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct UringParams {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+
+    /// Where the ring memory is mapped in the process, and its size.
+    pub ring_addr: u64,
+    pub ring_size: u64,
+
+    /// Offsets of the SQE and CQE arrays from `ring_addr`.
+    pub sqes_offset: u32,
+    pub cqes_offset: u32,
+}
+
+// One operation handed to the workers
+#[derive(Debug, Clone, Copy)]
+struct Work {
+    ring: usize,
+    generation: u32,
+    pid: u64,
+    page_table_root: u64,
+
+    // File the SQE's descriptor named at submission
+    file: u32,
+    sqe: Sqe,
+}
+
+// FIFO of submitted operations
+struct Queue {
+    items: [Option<Work>; MAX_PENDING],
+    head: usize,
+    len: usize,
+}
+
+const EMPTY_SQE: Sqe = Sqe { opcode: 0, flags: 0, reserved: 0, fd: 0, offset: 0, addr: 0, len: 0, reserved2: 0, user_data: 0 };
+const EMPTY_CQE: Cqe = Cqe { user_data: 0, res: 0, flags: 0 };
+
+static mut RINGS: [RingMemory; MAX_RINGS] = [const {
+    RingMemory {
+        header: RingHeader {
+            sq_head: AtomicU32::new(0),
+            sq_tail: AtomicU32::new(0),
+            sq_mask: 0,
+            sq_entries: 0,
+            cq_head: AtomicU32::new(0),
+            cq_tail: AtomicU32::new(0),
+            cq_mask: 0,
+            cq_entries: 0,
+            cq_overflow: AtomicU32::new(0),
+        },
+        sqes: [EMPTY_SQE; MAX_ENTRIES as usize],
+        cqes: [EMPTY_CQE; (MAX_ENTRIES * CQ_FACTOR) as usize],
+    }
+}; MAX_RINGS];
+
+// Kernel-side ring state: owner PID, SQ size, a generation bumped on release so operations
+// still in flight for an old owner are dropped, operations not yet completed, and a lock
+// serialising CQ posting between workers
+static OWNERS: [AtomicU64; MAX_RINGS] = [const { AtomicU64::new(FREE) }; MAX_RINGS];
+static SQ_ENTRIES: [AtomicU32; MAX_RINGS] = [const { AtomicU32::new(0) }; MAX_RINGS];
+static GENERATIONS: [AtomicU32; MAX_RINGS] = [const { AtomicU32::new(0) }; MAX_RINGS];
+static IN_FLIGHT: [AtomicU32; MAX_RINGS] = [const { AtomicU32::new(0) }; MAX_RINGS];
+static CQ_LOCKS: [AtomicBool; MAX_RINGS] = [const { AtomicBool::new(false) }; MAX_RINGS];
+
+static mut PENDING: Queue = Queue { items: [None; MAX_PENDING], head: 0, len: 0 };
+static PENDING_LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` holding `lock`, with interrupts masked so a holder cannot be interrupted by
+// something spinning on the same lock
+fn locked<R>(lock: &AtomicBool, f: impl FnOnce() -> R) -> R {
+    arch::without_interrupts(|| {
+        while lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = f();
+        lock.store(false, Ordering::Release);
+        result
+    })
+}
+
+fn memory(ring: usize) -> *mut RingMemory {
+    unsafe {
+        let rings = &raw mut RINGS;
+        &raw mut (*rings)[ring]
+    }
+}
+
+fn header(ring: usize) -> &'static RingHeader {
+    unsafe { &(*memory(ring)).header }
+}
+
+/// `uring_setup(entries, params)`: creates a ring with room for `entries` submissions
+/// (rounded up to a power of two), writes its [`UringParams`] to `params` and returns the
+/// ring's descriptor.
+///
+/// The ring memory stays where the kernel allocated it: processes run on the kernel's
+/// identity-mapped tables, so the kernel's address for it is also the process's.
+pub fn sys_uring_setup(caller: u64, entries: u32, params: usize) -> KResult<usize> {
+    if entries == 0 || entries > MAX_ENTRIES {
+        return Err(Errno::EINVAL);
+    }
+
+    let ring = OWNERS
+        .iter()
+        .position(|owner| owner.compare_exchange(FREE, caller, Ordering::AcqRel, Ordering::Relaxed).is_ok())
+        .ok_or(Errno::ENOMEM)?;
+
+    let sq_entries = entries.next_power_of_two();
+    let cq_entries = sq_entries * CQ_FACTOR;
+    SQ_ENTRIES[ring].store(sq_entries, Ordering::Relaxed);
+    IN_FLIGHT[ring].store(0, Ordering::Relaxed);
+
+    unsafe {
+        ptr::write(
+            &raw mut (*memory(ring)).header,
+            RingHeader {
+                sq_head: AtomicU32::new(0),
+                sq_tail: AtomicU32::new(0),
+                sq_mask: sq_entries - 1,
+                sq_entries,
+                cq_head: AtomicU32::new(0),
+                cq_tail: AtomicU32::new(0),
+                cq_mask: cq_entries - 1,
+                cq_entries,
+                cq_overflow: AtomicU32::new(0),
+            },
+        );
+    }
+
+    // Once the file exists, dropping it closes the ring
+    let file = match file::open(RING_FILE_BASE + ring as u32) {
+        Ok(file) => file,
+        Err(errno) => {
+            OWNERS[ring].store(FREE, Ordering::Release);
+            return Err(errno);
+        }
+    };
+    let fd = ptable::with_process(caller, |process| process.alloc_fd(file)).unwrap_or(Err(Errno::ESRCH))?;
+
+    let out = UringParams {
+        sq_entries,
+        cq_entries,
+        ring_addr: memory(ring) as u64,
+        ring_size: size_of::<RingMemory>() as u64,
+        sqes_offset: offset_of!(RingMemory, sqes) as u32,
+        cqes_offset: offset_of!(RingMemory, cqes) as u32,
+    };
+
+    if let Err(errno) = uaccess::write_user(params, &out) {
+        ptable::with_process(caller, |process| release(process, fd)).unwrap_or(Err(Errno::ESRCH))?;
+        return Err(errno);
+    }
+
+    log::debug!("uring: pid {} set up ring {} with {} entries", caller, ring, sq_entries);
+    Ok(fd)
+}
+
+/// The ring behind descriptor `fd` of `process`. Fails with `EBADF` if `fd` is not a ring
+/// `process` owns.
+pub fn ring_of(process: &Process, fd: usize) -> KResult<usize> {
+    match ring_index(process.file(fd)?.id) {
+        Some(ring) if OWNERS[ring].load(Ordering::Acquire) == process.pid => Ok(ring),
+        _ => Err(Errno::EBADF),
+    }
+}
+
+/// The ring behind file id `file`, if it is a ring's.
+pub fn ring_index(file: u32) -> Option<usize> {
+    file.checked_sub(RING_FILE_BASE).map(|ring| ring as usize).filter(|&ring| ring < MAX_RINGS)
+}
+
+/// Frees `ring` once the last reference to its file is gone. Operations still queued or
+/// running complete into nothing.
+pub fn close_ring(ring: usize) {
+    locked(&CQ_LOCKS[ring], || GENERATIONS[ring].fetch_add(1, Ordering::AcqRel));
+    OWNERS[ring].store(FREE, Ordering::Release);
+}
+
+/// Closes ring descriptor `fd`; the ring goes with the last descriptor referring to it.
+pub fn release(process: &mut Process, fd: usize) -> KResult<()> {
+    ring_of(process, fd)?;
+    process.file_descriptors[fd] = None;
+    Ok(())
+}
+
+/// `uring_enter(fd, to_submit, min_complete)`: submits up to `to_submit` queued SQEs, then
+/// waits until at least `min_complete` CQEs are ready to reap (or nothing is left running).
+/// Returns the number of SQEs consumed.
+///
+/// An SQE that cannot be started (unknown opcode, bad descriptor or buffer) is still consumed
+/// and completes at once with the error.
+pub fn sys_uring_enter(caller: u64, fd: usize, to_submit: u32, min_complete: u32) -> KResult<usize> {
+    let (ring, page_table_root) = ptable::with_process(caller, |process| {
+        Ok((ring_of(process, fd)?, process.page_table_root as u64))
+    })
+    .unwrap_or(Err(Errno::ESRCH))?;
+    let header = header(ring);
+    let generation = GENERATIONS[ring].load(Ordering::Acquire);
+    let sq_entries = SQ_ENTRIES[ring].load(Ordering::Relaxed);
+
+    let mut head = header.sq_head.load(Ordering::Relaxed);
+    let available = header.sq_tail.load(Ordering::Acquire).wrapping_sub(head).min(sq_entries);
+    let mut submitted = 0;
+
+    while submitted < available.min(to_submit) {
+        // The process may rewrite the slot at any time; look only at this one copy
+        let sqe = unsafe { ptr::read_volatile(&raw const (*memory(ring)).sqes[(head & (sq_entries - 1)) as usize]) };
+
+        // Queueing wakes a worker, so the descriptor is resolved first and the table unlocked
+        match ptable::with_process(caller, |process| prepare(process, &sqe)).unwrap_or(Err(Errno::ESRCH)) {
+            Ok(file) => {
+                let work = Work { ring, generation, pid: caller, page_table_root, file, sqe };
+                if !queue(work) {
+                    break;
+                }
+            }
+            Err(errno) => post(ring, generation, sqe.user_data, Err(errno)),
+        }
+
+        head = head.wrapping_add(1);
+        header.sq_head.store(head, Ordering::Release);
+        submitted += 1;
+    }
+
+    if submitted == 0 && available != 0 && to_submit != 0 {
+        return Err(Errno::EBUSY);
+    }
+
+    wait_completions(ring, min_complete);
+    Ok(submitted as usize)
+}
+
+/// Waits until `ring` holds `min_complete` CQEs to reap (capped at what its CQ holds) or
+/// nothing it submitted is still running. Needs no PCB, so callers can wait without one.
+pub fn wait_completions(ring: usize, min_complete: u32) {
+    if min_complete > 0 {
+        wait(ring, min_complete.min(SQ_ENTRIES[ring].load(Ordering::Relaxed) * CQ_FACTOR));
+    }
+}
+
+// Checks an SQE and resolves its descriptor, returning the file it names
+fn prepare(process: &Process, sqe: &Sqe) -> KResult<u32> {
+    if sqe.flags != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    match sqe.opcode {
+        OP_NOP => return Ok(0),
+        OP_READ | OP_WRITE => {
+            if !uaccess::is_user_range(sqe.addr as usize, sqe.len as usize) {
+                return Err(Errno::EFAULT);
+            }
+        }
+        OP_ACCEPT => {}
+        _ => return Err(Errno::EINVAL),
+    }
+
+    let fd = usize::try_from(sqe.fd).map_err(|_| Errno::EBADF)?;
+    let file = process.file(fd)?.id;
+
+    // Rings cannot be read from or waited on through other rings
+    if file >= RING_FILE_BASE {
+        return Err(Errno::EINVAL);
+    }
+
+    Ok(file)
+}
+
+// The pending queue; only touched with PENDING_LOCK held
+fn pending() -> *mut Queue {
+    &raw mut PENDING
+}
+
+// Hands `work` to the workers, or returns `false` if too much is already queued
+fn queue(work: Work) -> bool {
+    let queued = locked(&PENDING_LOCK, || {
+        let queue = unsafe { &mut *pending() };
+        if queue.len == MAX_PENDING {
+            return false;
+        }
+
+        queue.items[(queue.head + queue.len) % MAX_PENDING] = Some(work);
+        queue.len += 1;
+        true
+    });
+
+    if queued {
+        IN_FLIGHT[work.ring].fetch_add(1, Ordering::Relaxed);
+        sched::wake_one(WaitTarget::IODevice(WORK_WAIT));
+    }
+
+    queued
+}
+
+fn take_work() -> Option<Work> {
+    locked(&PENDING_LOCK, || {
+        let queue = unsafe { &mut *pending() };
+        if queue.len == 0 {
+            return None;
+        }
+
+        let work = queue.items[queue.head].take();
+        queue.head = (queue.head + 1) % MAX_PENDING;
+        queue.len -= 1;
+        work
+    })
+}
+
+fn has_work() -> bool {
+    locked(&PENDING_LOCK, || unsafe { (*pending()).len != 0 })
+}
+
+// Waits until `count` CQEs are ready or the ring has nothing left in flight
+fn wait(ring: usize, count: u32) {
+    let header = header(ring);
+    let ready = || {
+        let ready = header.cq_tail.load(Ordering::Acquire).wrapping_sub(header.cq_head.load(Ordering::Acquire));
+        ready >= count || IN_FLIGHT[ring].load(Ordering::Acquire) == 0
+    };
+
+    // The idle process cannot block; it lets the workers run instead
+    if sched::is_idle(percpu::current_pid()) {
+        while !ready() {
+            sched::yield_now();
+        }
+    } else {
+        sched::block_on(WaitTarget::IODevice(COMPLETION_WAIT_BASE + ring as u32), ready);
+    }
+}
+
+/// Starts the worker threads. Called once at boot, after the scheduler is up; the kernel runs
+/// without rings if they cannot be started.
+pub fn init() {
+    for _ in 0..WORKERS {
+        if let Err(errno) = kthread::spawn_kthread(worker_main, DEFAULT_PRIORITY) {
+            log::warn!("uring: cannot start a worker: {:?}", errno);
+        }
+    }
+}
+
+// Body of a worker: drains the queue, then sleeps until more is queued
+fn worker_main() {
+    loop {
+        sched::block_on(WaitTarget::IODevice(WORK_WAIT), has_work);
+
+        while let Some(work) = take_work() {
+            execute(&work);
+        }
+    }
+}
+
+fn execute(work: &Work) {
+    // Buffers are user addresses of the submitter. The worker borrows its address space in its
+    // own PCB, so the scheduler restores it if the operation blocks or is preempted.
+    let worker = percpu::current_pid();
+    let previous = Current::page_table_root();
+    if work.page_table_root != 0 {
+        ptable::with_process(worker, |process| process.page_table_root = work.page_table_root as usize);
+        unsafe { tlb::switch_to(work.page_table_root) };
+    }
+
+    let result = perform(work);
+
+    if work.page_table_root != 0 {
+        ptable::with_process(worker, |process| process.page_table_root = 0);
+        unsafe { tlb::switch_to(previous) };
+    }
+
+    post(work.ring, work.generation, work.sqe.user_data, result);
+
+    if GENERATIONS[work.ring].load(Ordering::Acquire) == work.generation {
+        IN_FLIGHT[work.ring].fetch_sub(1, Ordering::Release);
+    }
+    sched::wake(WaitTarget::IODevice(COMPLETION_WAIT_BASE + work.ring as u32));
+}
+
+fn perform(work: &Work) -> KResult<usize> {
+    let sqe = &work.sqe;
+
+    match sqe.opcode {
+        OP_NOP => Ok(0),
+        OP_READ => read(file::ops().ok_or(Errno::EBADF)?, work.file, sqe.addr as usize, sqe.len as usize, sqe.offset),
+        OP_WRITE => write(file::ops().ok_or(Errno::EBADF)?, work.file, sqe.addr as usize, sqe.len as usize, sqe.offset),
+        OP_ACCEPT => {
+            let ops = file::ops().ok_or(Errno::EBADF)?;
+            let accepted = (ops.accept)(work.file)?;
+            let file = file::open(accepted).inspect_err(|_| (ops.close)(accepted))?;
+
+            // A file the process never got is closed as this reference drops, the table unlocked
+            ptable::with_process(work.pid, |process| process.alloc_fd(file.clone())).unwrap_or(Err(Errno::ESRCH))
+        }
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+// Short transfers stop the loop; an error after some bytes moved reports the bytes instead
+fn read(ops: &FileOps, file: u32, buf: usize, len: usize, offset: u64) -> KResult<usize> {
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+
+    while done < len {
+        let want = (len - done).min(CHUNK_SIZE);
+        let result = (ops.read)(file, &mut chunk[..want], offset + done as u64)
+            .and_then(|n| uaccess::copy_to_user(buf + done, &chunk[..n]).map(|()| n));
+
+        match result {
+            Ok(n) if n < want => return Ok(done + n),
+            Ok(n) => done += n,
+            Err(errno) if done == 0 => return Err(errno),
+            Err(_) => break,
+        }
+    }
+
+    Ok(done)
+}
+
+fn write(ops: &FileOps, file: u32, buf: usize, len: usize, offset: u64) -> KResult<usize> {
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+
+    while done < len {
+        let want = (len - done).min(CHUNK_SIZE);
+        let result = uaccess::copy_from_user(&mut chunk[..want], buf + done)
+            .and_then(|()| (ops.write)(file, &chunk[..want], offset + done as u64));
+
+        match result {
+            Ok(n) if n < want => return Ok(done + n),
+            Ok(n) => done += n,
+            Err(errno) if done == 0 => return Err(errno),
+            Err(_) => break,
+        }
+    }
+
+    Ok(done)
+}
+
+// Posts a CQE, unless the ring was released since the operation was submitted
+fn post(ring: usize, generation: u32, user_data: u64, result: KResult<usize>) {
+    let res = match result {
+        Ok(n) => n.min(i32::MAX as usize) as i32,
+        Err(errno) => -(errno as i32),
+    };
+
+    locked(&CQ_LOCKS[ring], || {
+        if GENERATIONS[ring].load(Ordering::Acquire) != generation {
+            return;
+        }
+
+        let header = header(ring);
+        let cq_entries = SQ_ENTRIES[ring].load(Ordering::Relaxed) * CQ_FACTOR;
+        let tail = header.cq_tail.load(Ordering::Relaxed);
+
+        if tail.wrapping_sub(header.cq_head.load(Ordering::Acquire)) >= cq_entries {
+            header.cq_overflow.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let slot = (tail & (cq_entries - 1)) as usize;
+        unsafe { ptr::write_volatile(&raw mut (*memory(ring)).cqes[slot], Cqe { user_data, res, flags: 0 }) };
+        header.cq_tail.store(tail.wrapping_add(1), Ordering::Release);
+    });
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Sets up a ring for process `pid`, returning its descriptor and parameters
+    fn setup(pid: u64, entries: u32) -> (usize, UringParams) {
+        let mut params = UringParams::default();
+        let fd = sys_uring_setup(pid, entries, &raw mut params as usize).expect("ring setup");
+        (fd, params)
+    }
+
+    // Closes ring descriptor `fd` of process `pid`
+    fn close(pid: u64, fd: usize) -> KResult<()> {
+        ptable::with_process(pid, |process| release(process, fd)).unwrap_or(Err(Errno::ESRCH))
+    }
+
+    // Queues `sqe` the way a process would: fill the slot, then publish the new tail
+    fn submit(params: &UringParams, sqe: Sqe) {
+        let header = unsafe { &*(params.ring_addr as *const RingHeader) };
+        let tail = header.sq_tail.load(Ordering::Relaxed);
+        let sqes = (params.ring_addr + params.sqes_offset as u64) as *mut Sqe;
+
+        unsafe { sqes.add((tail & (params.sq_entries - 1)) as usize).write_volatile(sqe) };
+        header.sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    // Reaps the next CQE, if any
+    fn reap(params: &UringParams) -> Option<Cqe> {
+        let header = unsafe { &*(params.ring_addr as *const RingHeader) };
+        let head = header.cq_head.load(Ordering::Relaxed);
+
+        if header.cq_tail.load(Ordering::Acquire) == head {
+            return None;
+        }
+
+        let cqes = (params.ring_addr + params.cqes_offset as u64) as *const Cqe;
+        let cqe = unsafe { cqes.add((head & (params.cq_entries - 1)) as usize).read_volatile() };
+        header.cq_head.store(head.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn setup_validates_entries() {
+            ptable::insert(Process::new(4242, 0, "uring")).unwrap();
+            let mut params = UringParams::default();
+
+            assert_eq!(sys_uring_setup(4242, 0, &raw mut params as usize), Err(Errno::EINVAL));
+            assert_eq!(sys_uring_setup(4242, MAX_ENTRIES + 1, &raw mut params as usize), Err(Errno::EINVAL));
+
+            let (fd, params) = setup(4242, 5);
+            assert_eq!((params.sq_entries, params.cq_entries), (8, 16));
+
+            let mut process = ptable::remove(4242).unwrap();
+            assert_eq!(process.file(fd).map(|file| file.id), Ok(RING_FILE_BASE + ring_of(&process, fd).unwrap() as u32));
+
+            release(&mut process, fd).unwrap();
+            assert!(process.file_descriptors[fd].is_none());
+        }
+
+        fn nop_completes_through_worker() {
+            ptable::insert(Process::new(4242, 0, "uring")).unwrap();
+            let (fd, params) = setup(4242, 4);
+
+            submit(&params, Sqe { opcode: OP_NOP, user_data: 0xfeed, ..Sqe::default() });
+            submit(&params, Sqe { opcode: OP_NOP, user_data: 0xbeef, ..Sqe::default() });
+            assert_eq!(sys_uring_enter(4242, fd, 8, 2), Ok(2));
+
+            assert_eq!(reap(&params), Some(Cqe { user_data: 0xfeed, res: 0, flags: 0 }));
+            assert_eq!(reap(&params), Some(Cqe { user_data: 0xbeef, res: 0, flags: 0 }));
+            assert_eq!(reap(&params), None);
+
+            close(4242, fd).unwrap();
+            ptable::remove(4242);
+        }
+
+        fn bad_entries_fail_at_once() {
+            ptable::insert(Process::new(4242, 0, "uring")).unwrap();
+            let (fd, params) = setup(4242, 4);
+
+            submit(&params, Sqe { opcode: 0xff, user_data: 1, ..Sqe::default() });
+            submit(&params, Sqe { opcode: OP_READ, fd: 63, user_data: 2, ..Sqe::default() });
+            assert_eq!(sys_uring_enter(4242, fd, 2, 0), Ok(2));
+
+            assert_eq!(reap(&params).map(|c| (c.user_data, c.res)), Some((1, -(Errno::EINVAL as i32))));
+            assert_eq!(reap(&params).map(|c| (c.user_data, c.res)), Some((2, -(Errno::EBADF as i32))));
+
+            close(4242, fd).unwrap();
+            ptable::remove(4242);
+        }
+
+        fn full_completion_queue_counts_overflow() {
+            ptable::insert(Process::new(4242, 0, "uring")).unwrap();
+            let (fd, params) = setup(4242, 1);
+
+            for user_data in 0..3 {
+                submit(&params, Sqe { opcode: 0xff, user_data, ..Sqe::default() });
+                assert_eq!(sys_uring_enter(4242, fd, 1, 0), Ok(1));
+            }
+
+            let header = unsafe { &*(params.ring_addr as *const RingHeader) };
+            assert_eq!(header.cq_overflow.load(Ordering::Relaxed), 1);
+            assert_eq!(reap(&params).map(|c| c.user_data), Some(0));
+
+            close(4242, fd).unwrap();
+            assert_eq!(sys_uring_enter(4242, fd, 1, 0), Err(Errno::EBADF));
+            ptable::remove(4242);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+//! The virtual filesystem: opening paths, and reading and writing what they name.
+//!
+//! A path is resolved through the caller's mount namespace (see [`mount::resolve`]) to a
+//! filesystem type and a path within it, and handed to the [`FileSystem`] registered for that
+//! type, which opens it as an [`Inode`]. The VFS keeps each opened inode in a node table and
+//! gives the [`File`] it opens for it the node's id, so the installed [`FileOps`] send reads,
+//! writes and the final close back to the filesystem that opened it. Each open makes a new
+//! node; two opens of one file share nothing but what the filesystem behind them shares.
+//!
+//! Every inode carries an owner, a group and permission bits. Opening checks the access the
+//! `O_*` flags ask for against them ([`cred::check_file_access`]), then lets the security
+//! modules have their say ([`lsm::check_file_open`]).
+//!
+//! Descriptors reference their [`File`], which carries the access mode and the offset `read`,
+//! `write` and `lseek` work on. Filesystem types nothing is registered for cannot be opened.
+
+use core::sync::atomic::Ordering;
+
+use crate::os::cred::{self, ROOT_ID, access};
+use crate::os::errno::{Errno, KResult};
+use crate::os::file::{self, File, FileOps, MAX_FILES, O_ACCMODE, O_APPEND, O_RDONLY, O_WRONLY};
+use crate::os::kobject::KRef;
+use crate::os::lsm;
+use crate::os::mount::{self, FsType, MAX_PATH, Path};
+use crate::os::process::Process;
+use crate::os::ptable;
+use crate::os::sync::SpinLock;
+use crate::os::uaccess;
+
+/// `lseek` whence values.
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+/// Maximum number of filesystem types with VFS support.
+pub const MAX_FILESYSTEMS: usize = 8;
+
+// Id of the first node's file; kept clear of the small ids other backing stores use
+const NODE_BASE: u32 = 0x10_0000;
+
+/// What an inode is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    File,
+    Directory,
+}
+
+/// An opened file, as its filesystem knows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Inode {
+    /// The filesystem's handle for it, passed back on every operation.
+    pub ino: u64,
+
+    pub kind: InodeKind,
+
+    /// Size in bytes when opened.
+    pub size: u64,
+
+    /// Owner, group and permission bits (e.g. `0o644`).
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+}
+
+/// A filesystem the VFS can open files on.
+pub trait FileSystem: Sync {
+    /// Opens `path`, taken from the filesystem's root, for `process` with the `O_*` flags
+    /// `flags`. Fails with `ENOENT` if there is nothing there.
+    fn open(&self, process: &Process, path: &Path, flags: u32) -> KResult<Inode>;
+
+    /// Reads from the file at `offset`, returning how much was read; 0 at the end.
+    fn read(&self, ino: u64, buf: &mut [u8], offset: u64) -> KResult<usize>;
+
+    /// Writes to the file at `offset`, returning how much was written.
+    fn write(&self, _ino: u64, _data: &[u8], _offset: u64) -> KResult<usize> {
+        Err(Errno::EROFS)
+    }
+
+    /// Releases the inode once its last file is closed.
+    fn close(&self, _ino: u64) {}
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    fs: &'static dyn FileSystem,
+    ino: u64,
+    kind: InodeKind,
+    size: u64,
+}
+
+static FILESYSTEMS: SpinLock<[Option<(FsType, &'static dyn FileSystem)>; MAX_FILESYSTEMS]> =
+    SpinLock::new([None; MAX_FILESYSTEMS]);
+
+static NODES: SpinLock<[Option<Node>; MAX_FILES]> = SpinLock::new([None; MAX_FILES]);
+
+static OPS: FileOps = FileOps { read: node_read, write: node_write, accept: node_accept, close: node_close };
+
+/// Registers the filesystems with VFS support and installs the file operations. Called once
+/// at boot, after the mounts are set up.
+pub fn init() {
+    register(FsType::RootFs, &ROOT_FS);
+    register(FsType::Proc, &crate::os::procfs::FS);
+    register(FsType::Fat32, &crate::os::fat32::FS);
+    register(FsType::Dev, &crate::os::devfs::FS);
+    #[cfg(all(target_arch = "x86_64", feature = "virtio"))]
+    register(FsType::P9, &crate::os::virtio::p9::FS);
+
+    file::set_file_ops(&OPS);
+}
+
+/// Makes `ops` the filesystem files on mounts of type `fs` are opened with, in place of any
+/// registered before.
+pub fn register(fs: FsType, ops: &'static dyn FileSystem) {
+    FILESYSTEMS.with(|table| {
+        let slot = match table.iter().position(|slot| slot.is_some_and(|(kind, _)| kind == fs)) {
+            Some(slot) => slot,
+            None => table.iter().position(Option::is_none).expect("vfs: too many filesystems"),
+        };
+        table[slot] = Some((fs, ops));
+    });
+}
+
+fn filesystem(fs: FsType) -> Option<&'static dyn FileSystem> {
+    FILESYSTEMS.with(|table| table.iter().flatten().find(|(kind, _)| *kind == fs).map(|(_, ops)| *ops))
+}
+
+fn node(id: u32) -> Option<Node> {
+    let index = id.checked_sub(NODE_BASE)? as usize;
+    NODES.with(|nodes| nodes.get(index).copied().flatten())
+}
+
+/// The [`access`] bits opening with the `O_*` flags `flags` asks for.
+pub fn access_of(flags: u32) -> u16 {
+    match flags & O_ACCMODE {
+        O_RDONLY => access::READ,
+        O_WRONLY => access::WRITE,
+        _ => access::READ | access::WRITE,
+    }
+}
+
+/// Checks that `process` may open `inode` with the `O_*` flags `flags`. Fails with `EACCES`
+/// if its mode bits do not allow it. Filesystems that act on the flags while opening, as
+/// `O_TRUNC` does, call this before they do.
+pub fn check_access(process: &Process, inode: &Inode, flags: u32) -> KResult<()> {
+    cred::check_file_access(&process.cred, inode.uid, inode.gid, inode.mode, access_of(flags))
+}
+
+/// Opens `path` for `process` with the `O_*` flags `flags`. Fails with `ENOENT` if there is
+/// nothing there, `ENODEV` if its filesystem cannot be opened through the VFS, `EISDIR` for a
+/// directory opened for writing, `EACCES` if the inode's mode bits do not allow the access,
+/// whatever a security module vetoes it with, and `ENOSPC` when [`MAX_FILES`] files are open.
+pub fn open(process: &Process, path: &str, flags: u32) -> KResult<KRef<File>> {
+    let resolved = mount::resolve(process, path)?;
+    let fs = filesystem(resolved.fs).ok_or(Errno::ENODEV)?;
+    let inode = fs.open(process, &resolved.path, flags)?;
+
+    let allowed = if inode.kind == InodeKind::Directory && flags & O_ACCMODE != O_RDONLY {
+        Err(Errno::EISDIR)
+    } else {
+        check_access(process, &inode, flags).and_then(|()| lsm::check_file_open(process, path, access_of(flags)))
+    };
+    if let Err(errno) = allowed {
+        fs.close(inode.ino);
+        return Err(errno);
+    }
+
+    let node = Node { fs, ino: inode.ino, kind: inode.kind, size: inode.size };
+    let index = NODES.with(|nodes| {
+        let index = nodes.iter().position(Option::is_none)?;
+        nodes[index] = Some(node);
+        Some(index)
+    });
+    let Some(index) = index else {
+        fs.close(inode.ino);
+        return Err(Errno::ENOSPC);
+    };
+
+    // Closing the file releases the node
+    file::open_with(NODE_BASE + index as u32, flags)
+}
+
+/// Reads from `file` at its offset, moving the offset past what was read. Fails with `EBADF`
+/// if it was not opened for reading.
+pub fn read(file: &File, buf: &mut [u8]) -> KResult<usize> {
+    if !file.readable() {
+        return Err(Errno::EBADF);
+    }
+
+    let ops = file::ops().ok_or(Errno::EBADF)?;
+    let n = (ops.read)(file.id, buf, file.offset.load(Ordering::Relaxed))?;
+    file.offset.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+}
+
+/// Writes to `file` at its offset, or its end if it was opened with `O_APPEND`, moving the
+/// offset past what was written. Fails with `EBADF` if it was not opened for writing.
+pub fn write(file: &File, data: &[u8]) -> KResult<usize> {
+    if !file.writable() {
+        return Err(Errno::EBADF);
+    }
+
+    let ops = file::ops().ok_or(Errno::EBADF)?;
+    let offset = match node(file.id) {
+        Some(node) if file.flags & O_APPEND != 0 => node.size,
+        _ => file.offset.load(Ordering::Relaxed),
+    };
+    let n = (ops.write)(file.id, data, offset)?;
+    file.offset.store(offset + n as u64, Ordering::Relaxed);
+    Ok(n)
+}
+
+/// `open(path, flags)`: opens the path at user address `path` for the caller `caller` and
+/// returns the lowest free descriptor for it. Fails with `ENAMETOOLONG` for a path over
+/// [`MAX_PATH`] bytes.
+pub fn sys_open(caller: u64, path: usize, flags: u32) -> KResult<usize> {
+    let mut buf = [0u8; MAX_PATH];
+    let len = read_user_path(&mut buf, path)?;
+    let path = core::str::from_utf8(&buf[..len]).map_err(|_| Errno::ENOENT)?;
+
+    // Filesystems may wait on their devices or look processes up, so the lookup runs on a copy
+    let opener = ptable::with_process(caller, |process| process.stand_in()).ok_or(Errno::ESRCH)?;
+    let file = open(&opener, path, flags)?;
+    ptable::with_process(caller, |process| process.alloc_fd(file)).unwrap_or(Err(Errno::ESRCH))
+}
+
+/// `lseek(fd, offset, whence)`: moves the offset of the file open as `fd` and returns the new
+/// one. Fails with `ESPIPE` for files not opened through the VFS and `EINVAL` for a bad
+/// `whence` or an offset that would end up negative.
+pub fn sys_lseek(process: &Process, fd: usize, offset: i64, whence: u32) -> KResult<u64> {
+    let file = process.file(fd)?;
+    let node = node(file.id).ok_or(Errno::ESPIPE)?;
+
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset.load(Ordering::Relaxed),
+        SEEK_END => node.size,
+        _ => return Err(Errno::EINVAL),
+    };
+    let new = base.checked_add_signed(offset).ok_or(Errno::EINVAL)?;
+
+    file.offset.store(new, Ordering::Relaxed);
+    Ok(new)
+}
+
+/// Copies the NUL-terminated path at user address `addr` into `buf`, returning its length.
+/// Fails with `ENAMETOOLONG` if it does not fit and `EFAULT` if it is not user memory.
+pub fn read_user_path(buf: &mut [u8; MAX_PATH], addr: usize) -> KResult<usize> {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let mut one = [0u8];
+        uaccess::copy_from_user(&mut one, addr + i)?;
+        if one[0] == 0 {
+            return Ok(i);
+        }
+        *byte = one[0];
+    }
+    Err(Errno::ENAMETOOLONG)
+}
+
+fn node_read(file: u32, buf: &mut [u8], offset: u64) -> KResult<usize> {
+    let node = node(file).ok_or(Errno::EBADF)?;
+    if node.kind == InodeKind::Directory {
+        return Err(Errno::EISDIR);
+    }
+    node.fs.read(node.ino, buf, offset)
+}
+
+fn node_write(file: u32, data: &[u8], offset: u64) -> KResult<usize> {
+    let node = node(file).ok_or(Errno::EBADF)?;
+    let n = node.fs.write(node.ino, data, offset)?;
+
+    let index = (file - NODE_BASE) as usize;
+    NODES.with(|nodes| {
+        if let Some(node) = nodes[index].as_mut() {
+            node.size = node.size.max(offset + n as u64);
+        }
+    });
+    Ok(n)
+}
+
+fn node_accept(_file: u32) -> KResult<u32> {
+    Err(Errno::EINVAL)
+}
+
+fn node_close(file: u32) {
+    let Some(index) = file.checked_sub(NODE_BASE) else {
+        return;
+    };
+    let node = NODES.with(|nodes| nodes.get_mut(index as usize).and_then(Option::take));
+    if let Some(node) = node {
+        node.fs.close(node.ino);
+    }
+}
+
+// The root filesystem the root namespace starts with: nothing but its root directory
+struct RootFs;
+
+static ROOT_FS: RootFs = RootFs;
+
+impl FileSystem for RootFs {
+    fn open(&self, _process: &Process, path: &Path, _flags: u32) -> KResult<Inode> {
+        if *path != Path::ROOT {
+            return Err(Errno::ENOENT);
+        }
+        Ok(Inode { ino: 0, kind: InodeKind::Directory, size: 0, uid: ROOT_ID, gid: ROOT_ID, mode: 0o755 })
+    }
+
+    fn read(&self, _ino: u64, _buf: &mut [u8], _offset: u64) -> KResult<usize> {
+        Err(Errno::EISDIR)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::cred::Credentials;
+    use crate::os::file::O_RDWR;
+    use crate::os::kobject::PoolStats;
+
+    crate::os::ktest::kernel_test! {
+        fn files_are_opened_through_their_mount() {
+            let process = Process::new(9950, 0, "vfs");
+            let live = file::FILES.live();
+
+            let root = open(&process, "/", O_RDONLY).unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(read(&root, &mut buf), Err(Errno::EISDIR));
+            assert_eq!(open(&process, "/", O_RDWR).map(|_| ()), Err(Errno::EISDIR));
+            assert_eq!(open(&process, "/missing", O_RDONLY).map(|_| ()), Err(Errno::ENOENT));
+            assert_eq!(open(&process, "/sys/fs/cgroup", O_RDONLY).map(|_| ()), Err(Errno::ENODEV));
+
+            drop(root);
+            assert_eq!(file::FILES.live(), live);
+        }
+
+        fn reads_follow_the_offset() {
+            let mut process = Process::new(9951, 0, "vfs");
+            let fd = process.alloc_fd(open(&process, "/proc/meminfo", O_RDONLY).unwrap()).unwrap();
+            let file = process.file(fd).unwrap().clone();
+
+            let mut head = [0u8; 8];
+            assert_eq!(read(&file, &mut head), Ok(8));
+            assert_eq!(&head, b"MemTotal");
+            assert_eq!(sys_lseek(&process, fd, 0, SEEK_CUR), Ok(8));
+            assert_eq!(write(&file, b"1"), Err(Errno::EBADF));
+
+            // Back to the start, and past the end
+            assert_eq!(sys_lseek(&process, fd, 3, SEEK_SET), Ok(3));
+            assert_eq!(read(&file, &mut head[..5]), Ok(5));
+            assert_eq!(&head[..5], b"Total");
+            let end = sys_lseek(&process, fd, 0, SEEK_END).unwrap();
+            assert_eq!(read(&file, &mut head), Ok(0));
+            assert_eq!(sys_lseek(&process, fd, -(end as i64) - 1, SEEK_CUR), Err(Errno::EINVAL));
+            assert_eq!(sys_lseek(&process, fd, 0, 7), Err(Errno::EINVAL));
+
+            // Files that are not the VFS's cannot seek
+            let other = process.alloc_fd(file::open(5).unwrap()).unwrap();
+            assert_eq!(sys_lseek(&process, other, 0, SEEK_SET), Err(Errno::ESPIPE));
+            assert_eq!(open(&process, "/proc/meminfo", O_WRONLY).map(|_| ()), Err(Errno::EACCES));
+
+            file::release(&mut process);
+        }
+
+        fn mode_bits_decide_who_opens_what() {
+            let mut process = Process::new(9952, 0, "vfs");
+            process.cred = Credentials::new(1000, 1000);
+            let secret = Inode { ino: 0, kind: InodeKind::File, size: 0, uid: ROOT_ID, gid: ROOT_ID, mode: 0o600 };
+
+            assert_eq!(access_of(O_RDONLY), access::READ);
+            assert_eq!(access_of(O_RDWR), access::READ | access::WRITE);
+
+            // Not the owner, and nothing granted to anyone else
+            assert_eq!(check_access(&process, &secret, O_RDONLY), Err(Errno::EACCES));
+            assert_eq!(check_access(&process, &secret, O_WRONLY), Err(Errno::EACCES));
+            assert_eq!(check_access(&process, &Inode { uid: 1000, ..secret }, O_RDWR), Ok(()));
+            assert_eq!(check_access(&process, &Inode { mode: 0o604, ..secret }, O_RDONLY), Ok(()));
+            assert_eq!(check_access(&Process::new(9953, 0, "vfs"), &secret, O_RDWR), Ok(()));
+
+            // Through open: /proc files are root's and read-only for everyone else
+            assert!(open(&process, "/proc/meminfo", O_RDONLY).is_ok());
+            assert_eq!(open(&process, "/proc/sys/kernel/sched_timeslice", O_WRONLY).map(|_| ()), Err(Errno::EACCES));
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! virtio-balloon driver.
+//!
+//! The host sets a target balloon size in its config space; the driver inflates by taking
+//! frames from the frame allocator and reporting their PFNs on the inflate queue (the host may
+//! then reclaim them), and deflates by reporting PFNs on the deflate queue and freeing the
+//! frames again. Memory statistics are offered to the host through the stats queue.
+//!
+//! There is no interrupt wiring: [`poll`] is called periodically and moves the balloon towards
+//! the target one batch at a time.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::LegacyPci;
+use super::queue::{Buffer, QueueMemory, VirtQueue};
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE};
+use crate::os::numa::Placement;
+use crate::os::pci::{self, Match, PciAddress};
+
+/// Transitional PCI device ID of the balloon.
+const DEVICE_ID: u16 = 0x1002;
+
+// Feature bits
+const F_MUST_TELL_HOST: u32 = 1 << 0;
+const F_STATS_VQ: u32 = 1 << 1;
+const F_DEFLATE_ON_OOM: u32 = 1 << 2;
+
+// Queue indices
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+const STATS_QUEUE: u16 = 2;
+
+// Config space: target size (num_pages) and current size (actual), both in 4 KiB pages
+const CONFIG_NUM_PAGES: u16 = 0;
+const CONFIG_ACTUAL: u16 = 4;
+
+/// Most PFNs sent in one inflate or deflate request.
+const BATCH: usize = 256;
+
+/// Most frames the balloon can hold (256 MiB), bounded by the PFN bookkeeping below.
+const MAX_BALLOON_FRAMES: usize = 65536;
+
+// Statistics tags
+const STAT_MEMFREE: u16 = 4;
+const STAT_MEMTOT: u16 = 5;
+const STAT_AVAILABLE: u16 = 6;
+
+/// One `virtio_balloon_stat`: a tag and a 64-bit value, packed.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Stat {
+    tag: u16,
+    value: u64,
+}
+
+struct Balloon {
+    device: LegacyPci,
+    inflate: VirtQueue,
+    deflate: VirtQueue,
+    stats: Option<VirtQueue>,
+    features: u32,
+
+    /// Number of frames in the balloon (recorded in `FRAMES`).
+    len: usize,
+}
+
+static mut BALLOON: Option<Balloon> = None;
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+// Device-visible memory: queues, the PFN batch and the stats buffer
+static mut INFLATE_MEMORY: QueueMemory = QueueMemory::new();
+static mut DEFLATE_MEMORY: QueueMemory = QueueMemory::new();
+static mut STATS_MEMORY: QueueMemory = QueueMemory::new();
+static mut PFNS: [u32; BATCH] = [0; BATCH];
+
+// Frame numbers of the frames in the balloon, newest last
+static mut FRAMES: [u32; MAX_BALLOON_FRAMES] = [0; MAX_BALLOON_FRAMES];
+static mut STATS: [Stat; 3] = [Stat { tag: 0, value: 0 }; 3];
+
+/// The balloon's PCI driver.
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-balloon",
+    matches: &[Match::Id { vendor: super::VENDOR, device: DEVICE_ID }],
+    probe,
+};
+
+// Brings up the balloon device at `address`
+fn probe(address: PciAddress) -> KResult<()> {
+    if PRESENT.load(Ordering::Acquire) {
+        return Err(Errno::EBUSY);
+    }
+
+    let device = LegacyPci::new(address)?;
+
+    let features = device.negotiate(F_MUST_TELL_HOST | F_STATS_VQ | F_DEFLATE_ON_OOM);
+
+    let (inflate, deflate, stats) = unsafe {
+        let (inflate, deflate, stats) = (&raw mut INFLATE_MEMORY, &raw mut DEFLATE_MEMORY, &raw mut STATS_MEMORY);
+
+        let inflate = VirtQueue::new(&mut *inflate, device.queue_size(INFLATE_QUEUE));
+        let deflate = VirtQueue::new(&mut *deflate, device.queue_size(DEFLATE_QUEUE));
+        let stats = match features & F_STATS_VQ {
+            0 => None,
+            _ => VirtQueue::new(&mut *stats, device.queue_size(STATS_QUEUE)),
+        };
+        (inflate, deflate, stats)
+    };
+
+    let (Some(inflate), Some(deflate)) = (inflate, deflate) else {
+        log::warn!("virtio-balloon: unsupported queue size");
+        device.set_status(super::STATUS_FAILED);
+        return Err(Errno::EINVAL);
+    };
+
+    device.set_queue(INFLATE_QUEUE, &inflate);
+    device.set_queue(DEFLATE_QUEUE, &deflate);
+    if let Some(stats) = &stats {
+        device.set_queue(STATS_QUEUE, stats);
+    }
+
+    device.driver_ok();
+
+    let mut balloon = Balloon { device, inflate, deflate, stats, features, len: 0 };
+
+    // The device asks for statistics by returning this buffer; give it one to start with
+    balloon.offer_stats();
+
+    log::info!("virtio-balloon: at {}, features {:#x}", balloon.device.address, features);
+
+    unsafe {
+        let slot = &raw mut BALLOON;
+        *slot = Some(balloon);
+    }
+    PRESENT.store(true, Ordering::Release);
+    Ok(())
+}
+
+fn with_balloon<R>(f: impl FnOnce(&mut Balloon) -> R) -> Option<R> {
+    if !PRESENT.load(Ordering::Acquire) {
+        return None;
+    }
+
+    unsafe {
+        let balloon = &raw mut BALLOON;
+        (*balloon).as_mut().map(f)
+    }
+}
+
+/// Moves the balloon one batch towards the host's target and answers statistics requests.
+/// Called periodically (from the timer tick once interrupts are wired up).
+pub fn poll() {
+    with_balloon(|balloon| {
+        // Reading the ISR also acknowledges a pending config-change interrupt
+        balloon.device.isr();
+
+        if let Some(stats) = &mut balloon.stats
+            && stats.pop_used().is_some()
+        {
+            balloon.offer_stats();
+        }
+
+        let target = (balloon.device.config_read_u32(CONFIG_NUM_PAGES) as usize).min(MAX_BALLOON_FRAMES);
+
+        if target > balloon.len {
+            balloon.inflate(target - balloon.len);
+        } else if target < balloon.len {
+            balloon.deflate(balloon.len - target);
+        }
+    });
+}
+
+/// Gives up to `frames` ballooned frames back to the allocator when it runs out of memory, if
+/// the host allows it. Returns how many were released.
+pub fn deflate_on_oom(frames: usize) -> usize {
+    with_balloon(|balloon| {
+        if balloon.features & F_DEFLATE_ON_OOM == 0 {
+            return 0;
+        }
+
+        balloon.deflate(frames)
+    })
+    .unwrap_or(0)
+}
+
+/// Number of frames currently in the balloon.
+pub fn size() -> usize {
+    with_balloon(|balloon| balloon.len).unwrap_or(0)
+}
+
+impl Balloon {
+    // Sends the PFN batch in `PFNS[..count]` on `queue` and waits for the device to take it
+    fn transfer(&mut self, queue_index: u16, count: usize) {
+        let buffer = Buffer { addr: (&raw const PFNS) as u64, len: (count * size_of::<u32>()) as u32, device_writable: false };
+
+        let queue = if queue_index == INFLATE_QUEUE { &mut self.inflate } else { &mut self.deflate };
+        if queue.add(&[buffer]).is_none() {
+            return;
+        }
+
+        self.device.notify(queue_index);
+
+        while queue.pop_used().is_none() {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn inflate(&mut self, wanted: usize) -> usize {
+        let Some(allocator) = memory::frame_allocator() else {
+            return 0;
+        };
+
+        let (pfns, frames) = unsafe {
+            let (pfns, frames) = (&raw mut PFNS, &raw mut FRAMES);
+            (&mut *pfns, &mut *frames)
+        };
+        let mut count = 0;
+
+        while count < wanted.min(BATCH) {
+            let Some(frame) = allocator.alloc_frame(Placement::Local) else {
+                break;
+            };
+
+            pfns[count] = (frame / FRAME_SIZE) as u32;
+            count += 1;
+        }
+
+        if count == 0 {
+            return 0;
+        }
+
+        self.transfer(INFLATE_QUEUE, count);
+
+        frames[self.len..self.len + count].copy_from_slice(&pfns[..count]);
+        self.len += count;
+        memory::note_ballooned(count as isize);
+        self.device.config_write_u32(CONFIG_ACTUAL, self.len as u32);
+
+        count
+    }
+
+    fn deflate(&mut self, wanted: usize) -> usize {
+        let Some(allocator) = memory::frame_allocator() else {
+            return 0;
+        };
+
+        let count = wanted.min(BATCH).min(self.len);
+        if count == 0 {
+            return 0;
+        }
+
+        let (pfns, frames) = unsafe {
+            let (pfns, frames) = (&raw mut PFNS, &raw const FRAMES);
+            (&mut *pfns, &*frames)
+        };
+        pfns[..count].copy_from_slice(&frames[self.len - count..self.len]);
+
+        // Without MUST_TELL_HOST the frames could be reused right away, but waiting for the
+        // host is needed anyway before the PFN buffer is reused
+        self.transfer(DEFLATE_QUEUE, count);
+
+        for &pfn in &pfns[..count] {
+            allocator.free_frame(pfn as u64 * FRAME_SIZE);
+        }
+
+        self.len -= count;
+        memory::note_ballooned(-(count as isize));
+        self.device.config_write_u32(CONFIG_ACTUAL, self.len as u32);
+
+        count
+    }
+
+    // Fills the stats buffer and hands it to the device
+    fn offer_stats(&mut self) {
+        let Some(queue) = &mut self.stats else {
+            return;
+        };
+
+        let figures = memory::stats();
+        let stats = unsafe {
+            let stats = &raw mut STATS;
+            &mut *stats
+        };
+        stats[0] = Stat { tag: STAT_MEMFREE, value: figures.free };
+        stats[1] = Stat { tag: STAT_MEMTOT, value: figures.total };
+        stats[2] = Stat { tag: STAT_AVAILABLE, value: figures.free };
+
+        let buffer = Buffer { addr: stats.as_ptr() as u64, len: size_of::<[Stat; 3]>() as u32, device_writable: false };
+        if queue.add(&[buffer]).is_some() {
+            self.device.notify(STATS_QUEUE);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! virtio devices over the legacy PCI transport.
+//!
+//! QEMU's virtio PCI devices are transitional by default, so the legacy interface (registers in
+//! I/O BAR 0, queues set up by page frame number) is enough and avoids capability parsing.
+//! The drivers register with the PCI layer, which probes them when it finds their device.
+
+pub mod balloon;
+pub mod net;
+pub mod p9;
+pub mod queue;
+
+use crate::os::arch::x86_64::port::{inb, inl, inw, outb, outl, outw};
+use crate::os::errno::{Errno, KResult};
+use crate::os::pci::{self, Bar, PciAddress};
+
+use queue::VirtQueue;
+
+/// PCI vendor ID of virtio devices.
+pub const VENDOR: u16 = 0x1af4;
+
+/// Registers the virtio drivers with the PCI layer. Called once at boot, before the bus is
+/// probed.
+pub fn register_drivers() {
+    for driver in [&balloon::DRIVER, &net::DRIVER, &p9::DRIVER] {
+        if let Err(errno) = pci::register_driver(driver) {
+            log::error!("virtio: cannot register {}: {:?}", driver.name, errno);
+        }
+    }
+}
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+// Legacy register offsets in BAR 0
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+const REG_ISR: u16 = 0x13;
+
+// Device-specific configuration (MSI-X is never enabled, so it starts right here)
+const REG_CONFIG: u16 = 0x14;
+
+/// A virtio device found on the PCI bus, driven through its legacy I/O registers.
+pub struct LegacyPci {
+    pub address: PciAddress,
+    io: u16,
+}
+
+impl LegacyPci {
+    /// Takes over the virtio device at `address`, which the PCI layer has enabled, and resets
+    /// it. Fails with `ENODEV` if it has no legacy I/O BAR.
+    pub fn new(address: PciAddress) -> KResult<Self> {
+        let Bar::Io(io) = address.bar(0) else {
+            log::warn!("virtio: {} has no legacy I/O BAR", address);
+            return Err(Errno::ENODEV);
+        };
+
+        let device = LegacyPci { address, io };
+        device.set_status(0);
+        device.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        Ok(device)
+    }
+
+    pub fn status(&self) -> u8 {
+        unsafe { inb(self.io + REG_STATUS) }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        unsafe { outb(self.io + REG_STATUS, status) };
+    }
+
+    /// Accepts the subset of `supported` the device offers and returns it.
+    pub fn negotiate(&self, supported: u32) -> u32 {
+        let features = unsafe { inl(self.io + REG_DEVICE_FEATURES) } & supported;
+        unsafe { outl(self.io + REG_GUEST_FEATURES, features) };
+        features
+    }
+
+    /// Size of queue `index` as fixed by the device (0 if the queue does not exist).
+    pub fn queue_size(&self, index: u16) -> u16 {
+        unsafe {
+            outw(self.io + REG_QUEUE_SELECT, index);
+            inw(self.io + REG_QUEUE_SIZE)
+        }
+    }
+
+    /// Hands queue `index` to the device.
+    pub fn set_queue(&self, index: u16, queue: &VirtQueue) {
+        unsafe {
+            outw(self.io + REG_QUEUE_SELECT, index);
+            outl(self.io + REG_QUEUE_PFN, queue.pfn());
+        }
+    }
+
+    /// Tells the device that queue `index` has new buffers.
+    pub fn notify(&self, index: u16) {
+        unsafe { outw(self.io + REG_QUEUE_NOTIFY, index) };
+    }
+
+    /// Reads and acknowledges the interrupt status (bit 0: queue used, bit 1: config changed).
+    pub fn isr(&self) -> u8 {
+        unsafe { inb(self.io + REG_ISR) }
+    }
+
+    pub fn config_read_u8(&self, offset: u16) -> u8 {
+        unsafe { inb(self.io + REG_CONFIG + offset) }
+    }
+
+    pub fn config_read_u32(&self, offset: u16) -> u32 {
+        unsafe { inl(self.io + REG_CONFIG + offset) }
+    }
+
+    pub fn config_write_u32(&self, offset: u16, value: u32) {
+        unsafe { outl(self.io + REG_CONFIG + offset, value) };
+    }
+
+    /// Finishes initialisation; the device may use its queues from now on.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+}
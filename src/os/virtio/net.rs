@@ -0,0 +1,226 @@
+//! virtio-net driver.
+//!
+//! The card has a receive queue, kept stocked with [`RX_BUFFERS`] empty buffers, and a
+//! transmit queue, through which frames go out of [`TX_BUFFERS`] buffers reused once the
+//! device is done with them. Each buffer is a legacy `virtio_net_hdr` followed by the frame;
+//! no offloads are negotiated, so the header going out is all zeroes and the one coming in is
+//! ignored.
+//!
+//! The device interrupts on its legacy INTx line, routed through the I/O APIC (QEMU's virtio
+//! functions offer MSI-X but not plain MSI). The handler passes every frame received to the
+//! network stack ([`net::receive`]), outside the driver's lock so the stack can answer
+//! straight away, and puts each buffer back on the queue.
+
+use super::LegacyPci;
+use super::queue::{Buffer, MAX_QUEUE_SIZE, QueueMemory, VirtQueue};
+use crate::os::arch::x86_64::idt;
+use crate::os::arch::x86_64::ioapic;
+use crate::os::errno::{Errno, KResult};
+use crate::os::net::{self, Interface, MAX_FRAME, MacAddr};
+use crate::os::pci::{self, Match, PciAddress};
+use crate::os::random;
+use crate::os::sync::SpinLock;
+
+/// Transitional PCI device ID of the network card.
+const DEVICE_ID: u16 = 0x1000;
+
+// Feature bits: the device has a MAC address in its config space
+const F_MAC: u32 = 1 << 5;
+
+// Queue indices
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+// Config space: the MAC address, six bytes
+const CONFIG_MAC: u16 = 0;
+
+// Legacy header without mergeable buffers: flags, GSO type, header length, GSO size, checksum
+// start and offset
+const HEADER_LEN: usize = 10;
+
+// Bytes per buffer: a header and a whole frame
+const BUFFER_SIZE: usize = 2048;
+
+/// Buffers kept on the receive queue.
+pub const RX_BUFFERS: usize = 16;
+
+/// Buffers frames are sent from.
+pub const TX_BUFFERS: usize = 16;
+
+struct Card {
+    device: LegacyPci,
+    rx: VirtQueue,
+    tx: VirtQueue,
+
+    // Buffer each chain the device holds was made of, by head descriptor
+    rx_buffer_of: [u8; MAX_QUEUE_SIZE as usize],
+    tx_buffer_of: [u8; MAX_QUEUE_SIZE as usize],
+
+    // Transmit buffers free to fill, one bit each
+    tx_free: u32,
+}
+
+static CARD: SpinLock<Option<Card>> = SpinLock::new(None);
+
+// Device-visible memory: queues and buffers
+static mut RX_MEMORY: QueueMemory = QueueMemory::new();
+static mut TX_MEMORY: QueueMemory = QueueMemory::new();
+static mut RX_DATA: [[u8; BUFFER_SIZE]; RX_BUFFERS] = [[0; BUFFER_SIZE]; RX_BUFFERS];
+static mut TX_DATA: [[u8; BUFFER_SIZE]; TX_BUFFERS] = [[0; BUFFER_SIZE]; TX_BUFFERS];
+
+/// The network card's PCI driver.
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-net",
+    matches: &[Match::Id { vendor: super::VENDOR, device: DEVICE_ID }],
+    probe,
+};
+
+// Address of receive or transmit buffer `index`
+fn rx_buffer(index: usize) -> *mut u8 {
+    unsafe { (&raw mut RX_DATA[index]) as *mut u8 }
+}
+
+fn tx_buffer(index: usize) -> *mut u8 {
+    unsafe { (&raw mut TX_DATA[index]) as *mut u8 }
+}
+
+// Brings up the card at `address` and attaches it to the network stack
+fn probe(address: PciAddress) -> KResult<()> {
+    if CARD.with(|card| card.is_some()) {
+        return Err(Errno::EBUSY);
+    }
+
+    let device = LegacyPci::new(address)?;
+    let features = device.negotiate(F_MAC);
+
+    // Without an address of its own the card gets a random locally administered one
+    let mac = if features & F_MAC != 0 {
+        MacAddr(core::array::from_fn(|i| device.config_read_u8(CONFIG_MAC + i as u16)))
+    } else {
+        let mut mac = [0u8; 6];
+        random::fill_bytes(&mut mac);
+        MacAddr([(mac[0] & !1) | 2, mac[1], mac[2], mac[3], mac[4], mac[5]])
+    };
+
+    let (rx, tx) = unsafe {
+        let (rx, tx) = (&raw mut RX_MEMORY, &raw mut TX_MEMORY);
+        (VirtQueue::new(&mut *rx, device.queue_size(RX_QUEUE)), VirtQueue::new(&mut *tx, device.queue_size(TX_QUEUE)))
+    };
+    let (Some(rx), Some(tx)) = (rx, tx) else {
+        log::warn!("virtio-net: unsupported queue size");
+        device.set_status(super::STATUS_FAILED);
+        return Err(Errno::EINVAL);
+    };
+
+    device.set_queue(RX_QUEUE, &rx);
+    device.set_queue(TX_QUEUE, &tx);
+
+    let mut card = Card {
+        device,
+        rx,
+        tx,
+        rx_buffer_of: [0; MAX_QUEUE_SIZE as usize],
+        tx_buffer_of: [0; MAX_QUEUE_SIZE as usize],
+        tx_free: (1 << TX_BUFFERS) - 1,
+    };
+    for index in 0..RX_BUFFERS {
+        card.post_rx(index);
+    }
+    card.device.driver_ok();
+    card.device.notify(RX_QUEUE);
+    CARD.with(|slot| *slot = Some(card));
+
+    // 0xff: not connected to the legacy interrupt controller
+    let line = address.read_u8(pci::INTERRUPT_LINE);
+    if line == 0xff {
+        log::warn!("virtio-net: {} has no interrupt line, nothing will be received", address);
+    } else if let Some(vector) = idt::allocate_vector(handle_interrupt) {
+        if let Err(errno) = ioapic::route_isa(line, vector) {
+            log::warn!("virtio-net: cannot route IRQ {}: {:?}", line, errno);
+        }
+    } else {
+        log::warn!("virtio-net: no interrupt vector left, nothing will be received");
+    }
+
+    log::info!("virtio-net: at {}, MAC {}, IRQ {}", address, mac, line);
+    net::attach(Interface { mac, transmit })
+}
+
+impl Card {
+    // Hands receive buffer `index` to the device
+    fn post_rx(&mut self, index: usize) {
+        let buffer = Buffer { addr: rx_buffer(index) as u64, len: BUFFER_SIZE as u32, device_writable: true };
+        if let Some(head) = self.rx.add(&[buffer]) {
+            self.rx_buffer_of[head as usize] = index as u8;
+        }
+    }
+
+    // Copies the next frame the device received into `frame` and gives its buffer back to
+    // the device; returns the frame's length, or `None` once there are no more
+    fn take_frame(&mut self, frame: &mut [u8; MAX_FRAME]) -> Option<usize> {
+        let (head, written) = self.rx.pop_used()?;
+        let index = self.rx_buffer_of[head as usize] as usize;
+
+        let len = (written as usize).saturating_sub(HEADER_LEN).min(MAX_FRAME);
+        let data = unsafe { core::slice::from_raw_parts(rx_buffer(index).add(HEADER_LEN), len) };
+        frame[..len].copy_from_slice(data);
+
+        self.post_rx(index);
+        self.device.notify(RX_QUEUE);
+        Some(len)
+    }
+
+    // Takes back the transmit buffers the device is done with
+    fn reclaim(&mut self) {
+        while let Some((head, _)) = self.tx.pop_used() {
+            self.tx_free |= 1 << self.tx_buffer_of[head as usize];
+        }
+    }
+}
+
+// Sends `frame`; the stack's `Interface::transmit`. Fails with `EMSGSIZE` for a frame over
+// the MTU and `EAGAIN` while every transmit buffer is in flight.
+fn transmit(frame: &[u8]) -> KResult<()> {
+    if frame.len() > MAX_FRAME {
+        return Err(Errno::EMSGSIZE);
+    }
+
+    CARD.with(|card| {
+        let card = card.as_mut().ok_or(Errno::ENODEV)?;
+        card.reclaim();
+        if card.tx_free == 0 {
+            return Err(Errno::EAGAIN);
+        }
+
+        let index = card.tx_free.trailing_zeros() as usize;
+        let buffer = unsafe { core::slice::from_raw_parts_mut(tx_buffer(index), BUFFER_SIZE) };
+        buffer[..HEADER_LEN].fill(0);
+        buffer[HEADER_LEN..HEADER_LEN + frame.len()].copy_from_slice(frame);
+
+        let chain = Buffer { addr: buffer.as_ptr() as u64, len: (HEADER_LEN + frame.len()) as u32, device_writable: false };
+        let head = card.tx.add(&[chain]).ok_or(Errno::EAGAIN)?;
+        card.tx_buffer_of[head as usize] = index as u8;
+        card.tx_free &= !(1 << index);
+
+        card.device.notify(TX_QUEUE);
+        Ok(())
+    })
+}
+
+// Acknowledges the interrupt, hands what came in to the stack and reclaims what went out
+fn handle_interrupt() {
+    if CARD.with(|card| card.as_ref().map(|card| card.device.isr())).is_none() {
+        return;
+    }
+
+    let mut frame = [0u8; MAX_FRAME];
+    while let Some(len) = CARD.with(|card| card.as_mut().and_then(|card| card.take_frame(&mut frame))) {
+        net::receive(&frame[..len]);
+    }
+
+    CARD.with(|card| {
+        if let Some(card) = card {
+            card.reclaim();
+        }
+    });
+}
@@ -0,0 +1,664 @@
+//! virtio-9p: a 9P2000.L client for directories the host exports to the guest.
+//!
+//! QEMU shares a host directory with `-virtfs local,path=DIR,mount_tag=TAG,security_model=none`.
+//! Each request is one T-message out and one R-message back on the device's single queue,
+//! issued synchronously. Files are addressed by fids, which the client allocates from a small
+//! bitmap; fid 0 is the attached root. The share is opened through the VFS as [`FS`], each
+//! open file being an open fid.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::LegacyPci;
+use super::queue::{Buffer, QueueMemory, VirtQueue};
+use crate::os::errno::{Errno, KResult};
+use crate::os::mount::Path;
+use crate::os::pci::{self, Match, PciAddress};
+use crate::os::process::Process;
+use crate::os::vfs::{self, FileSystem, Inode, InodeKind};
+
+/// Transitional PCI device ID of virtio-9p.
+const DEVICE_ID: u16 = 0x1009;
+
+// Feature bit: the config space holds the mount tag
+const F_MOUNT_TAG: u32 = 1 << 0;
+
+/// Largest message exchanged with the server, and so the largest read or write per request.
+pub const MSIZE: usize = 8192;
+
+/// Bytes of a read or write reply that are not data (size, type, tag, count).
+const IO_HEADER: usize = 4 + 1 + 2 + 4;
+
+/// Most fids in use at once, root included.
+const MAX_FIDS: usize = 64;
+
+/// Longest mount tag kept.
+const MAX_TAG: usize = 32;
+
+/// The fid of the attached root directory.
+pub const ROOT_FID: u32 = 0;
+
+// Message types
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// The tag for Tversion, and the one used for everything else (requests are synchronous)
+const NOTAG: u16 = 0xffff;
+const TAG: u16 = 1;
+
+// Tattach: no authentication fid; numeric uname of root
+const NOFID: u32 = u32::MAX;
+
+// Tunlinkat flag for directories
+const AT_REMOVEDIR: u32 = 0x200;
+
+// Tgetattr request mask: everything in the basic stat
+const GETATTR_BASIC: u64 = 0x7ff;
+
+/// Open flags for [`Client::open`] and [`Client::create`] (Linux `O_*` values, as 9P2000.L
+/// uses them).
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+/// A server's unique file identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    /// Returns `true` if the qid names a directory.
+    pub fn is_dir(&self) -> bool {
+        self.kind & 0x80 != 0
+    }
+}
+
+/// The subset of `Rgetattr` the kernel uses.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Attr {
+    pub qid: Qid,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub size: u64,
+    pub mtime_sec: u64,
+}
+
+/// One directory entry from [`Client::readdir`].
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntry<'a> {
+    pub qid: Qid,
+
+    /// Offset to pass to the next `readdir` to continue after this entry.
+    pub offset: u64,
+    pub name: &'a str,
+}
+
+// =========================================================================
+// Message encoding
+// =========================================================================
+
+/// Builds a T-message in place.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], kind: u8, tag: u16) -> Self {
+        let mut writer = Writer { buf, len: 4 };
+        writer.u8(kind);
+        writer.u16(tag);
+        writer
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> KResult<()> {
+        let end = self.len + data.len();
+        self.buf.get_mut(self.len..end).ok_or(Errno::E2BIG)?.copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) {
+        _ = self.bytes(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        _ = self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        _ = self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        _ = self.bytes(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) -> KResult<()> {
+        let len = u16::try_from(value.len()).map_err(|_| Errno::E2BIG)?;
+        self.u16(len);
+        self.bytes(value.as_bytes())
+    }
+
+    /// Writes the size prefix and returns the message length.
+    fn finish(self) -> usize {
+        self.buf[..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        self.len
+    }
+}
+
+/// Parses an R-message body.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> KResult<&'a [u8]> {
+        let data = self.buf.get(self.pos..self.pos + len).ok_or(Errno::EIO)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> KResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> KResult<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().map_err(|_| Errno::EIO)?))
+    }
+
+    fn u32(&mut self) -> KResult<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().map_err(|_| Errno::EIO)?))
+    }
+
+    fn u64(&mut self) -> KResult<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().map_err(|_| Errno::EIO)?))
+    }
+
+    fn str(&mut self) -> KResult<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| Errno::EIO)
+    }
+
+    fn qid(&mut self) -> KResult<Qid> {
+        Ok(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+}
+
+// Checks an R-message's header and returns a reader over its body. Rlerror becomes its errno.
+fn parse_reply(reply: &[u8], request: u8) -> KResult<Reader<'_>> {
+    let mut reader = Reader { buf: reply, pos: 0 };
+
+    let size = reader.u32()? as usize;
+    let kind = reader.u8()?;
+    let _tag = reader.u16()?;
+
+    reader.buf = reply.get(..size).ok_or(Errno::EIO)?;
+
+    match kind {
+        RLERROR => Err(Errno::from_raw(reader.u32()?)),
+        _ if kind == request + 1 => Ok(reader),
+        _ => Err(Errno::EIO),
+    }
+}
+
+// =========================================================================
+// Client
+// =========================================================================
+
+/// A 9P2000.L session with one exported directory.
+pub struct Client {
+    device: LegacyPci,
+    queue: VirtQueue,
+    tag: [u8; MAX_TAG],
+    tag_len: usize,
+
+    /// Bit n set: fid n is in use.
+    fids: u64,
+}
+
+// Device-visible message buffers
+static mut REQUEST: [u8; MSIZE] = [0; MSIZE];
+static mut REPLY: [u8; MSIZE] = [0; MSIZE];
+static mut QUEUE_MEMORY: QueueMemory = QueueMemory::new();
+
+static mut CLIENT: Option<Client> = None;
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// The virtio-9p PCI driver.
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-9p",
+    matches: &[Match::Id { vendor: super::VENDOR, device: DEVICE_ID }],
+    probe,
+};
+
+// Brings up the virtio-9p device at `address`, negotiates the protocol and attaches to the
+// export's root. Only the first share is used.
+fn probe(address: PciAddress) -> KResult<()> {
+    if PRESENT.load(Ordering::Acquire) {
+        return Err(Errno::EBUSY);
+    }
+
+    let device = LegacyPci::new(address)?;
+
+    let features = device.negotiate(F_MOUNT_TAG);
+
+    let queue = unsafe {
+        let memory = &raw mut QUEUE_MEMORY;
+        VirtQueue::new(&mut *memory, device.queue_size(0))
+    };
+    let Some(queue) = queue else {
+        log::warn!("virtio-9p: unsupported queue size");
+        device.set_status(super::STATUS_FAILED);
+        return Err(Errno::EINVAL);
+    };
+
+    device.set_queue(0, &queue);
+    device.driver_ok();
+
+    let mut client = Client { device, queue, tag: [0; MAX_TAG], tag_len: 0, fids: 1 << ROOT_FID };
+
+    // Config space: tag length (16 bits) followed by the tag bytes
+    if features & F_MOUNT_TAG != 0 {
+        let len = (client.device.config_read_u32(0) & 0xffff) as usize;
+        client.tag_len = len.min(MAX_TAG);
+
+        for i in 0..client.tag_len {
+            let word = client.device.config_read_u32((2 + i as u16) & !3);
+            client.tag[i] = (word >> (((2 + i) & 3) * 8)) as u8;
+        }
+    }
+
+    if let Err(errno) = client.handshake() {
+        log::error!("virtio-9p: cannot attach to {}: {:?}", client.device.address, errno);
+        client.device.set_status(super::STATUS_FAILED);
+        return Err(errno);
+    }
+
+    log::info!("virtio-9p: attached to '{}' at {}", client.tag(), client.device.address);
+
+    unsafe {
+        let slot = &raw mut CLIENT;
+        *slot = Some(client);
+    }
+    PRESENT.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Runs `f` on the attached share's client, or fails with `ENOENT` if there is none.
+pub fn with_client<R>(f: impl FnOnce(&mut Client) -> KResult<R>) -> KResult<R> {
+    if !PRESENT.load(Ordering::Acquire) {
+        return Err(Errno::ENOENT);
+    }
+
+    unsafe {
+        let client = &raw mut CLIENT;
+        (*client).as_mut().map_or(Err(Errno::ENOENT), f)
+    }
+}
+
+impl Client {
+    /// The mount tag the host gave the share.
+    pub fn tag(&self) -> &str {
+        core::str::from_utf8(&self.tag[..self.tag_len]).unwrap_or("?")
+    }
+
+    // Sends the request built by `build` and waits for the reply, which lands in REPLY
+    fn transact(&mut self, kind: u8, build: impl FnOnce(&mut Writer) -> KResult<()>) -> KResult<Reader<'static>> {
+        let (request, reply) = unsafe {
+            let (request, reply) = (&raw mut REQUEST, &raw mut REPLY);
+            (&mut *request, &mut *reply)
+        };
+
+        let tag = if kind == TVERSION { NOTAG } else { TAG };
+        let mut writer = Writer::new(request, kind, tag);
+        build(&mut writer)?;
+        let len = writer.finish();
+
+        let buffers = [
+            Buffer { addr: request.as_ptr() as u64, len: len as u32, device_writable: false },
+            Buffer { addr: reply.as_mut_ptr() as u64, len: MSIZE as u32, device_writable: true },
+        ];
+        self.queue.add(&buffers).ok_or(Errno::EBUSY)?;
+        self.device.notify(0);
+
+        while self.queue.pop_used().is_none() {
+            core::hint::spin_loop();
+        }
+
+        parse_reply(reply, kind)
+    }
+
+    fn handshake(&mut self) -> KResult<()> {
+        let mut reply = self.transact(TVERSION, |w| {
+            w.u32(MSIZE as u32);
+            w.str("9P2000.L")
+        })?;
+
+        let msize = reply.u32()? as usize;
+        if msize < MSIZE || reply.str()? != "9P2000.L" {
+            return Err(Errno::EIO);
+        }
+
+        self.transact(TATTACH, |w| {
+            w.u32(ROOT_FID);
+            w.u32(NOFID);
+            w.str("root")?;
+            w.str("")?;
+            w.u32(0);
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn alloc_fid(&mut self) -> KResult<u32> {
+        let fid = (!self.fids).trailing_zeros();
+        if fid as usize >= MAX_FIDS {
+            return Err(Errno::EMFILE);
+        }
+
+        self.fids |= 1 << fid;
+        Ok(fid)
+    }
+
+    fn free_fid(&mut self, fid: u32) {
+        self.fids &= !(1 << fid);
+    }
+
+    /// Walks from the root to `path` (components separated by `/`) and returns a new fid for it.
+    /// The fid is not open yet; pass it to [`Client::open`] or use it for metadata.
+    pub fn walk(&mut self, path: &str) -> KResult<u32> {
+        let fid = self.alloc_fid()?;
+
+        let count = path.split('/').filter(|c| !c.is_empty()).count();
+        let result = self.transact(TWALK, |w| {
+            w.u32(ROOT_FID);
+            w.u32(fid);
+            w.u16(u16::try_from(count).map_err(|_| Errno::E2BIG)?);
+            path.split('/').filter(|c| !c.is_empty()).try_for_each(|c| w.str(c))
+        });
+
+        // A partial walk (fewer qids than names) means a component is missing
+        match result.and_then(|mut reply| reply.u16()) {
+            Ok(walked) if walked as usize == count => Ok(fid),
+            Ok(_) => {
+                self.free_fid(fid);
+                Err(Errno::ENOENT)
+            }
+            Err(errno) => {
+                self.free_fid(fid);
+                Err(errno)
+            }
+        }
+    }
+
+    /// Opens the file at `path` with `flags` (`O_*`) and returns its fid.
+    pub fn open(&mut self, path: &str, flags: u32) -> KResult<u32> {
+        let fid = self.walk(path)?;
+
+        let result = self.transact(TLOPEN, |w| {
+            w.u32(fid);
+            w.u32(flags);
+            Ok(())
+        });
+
+        if let Err(errno) = result {
+            self.clunk(fid)?;
+            return Err(errno);
+        }
+
+        Ok(fid)
+    }
+
+    /// Creates and opens the regular file `name` in directory `dir` and returns its fid.
+    pub fn create(&mut self, dir: &str, name: &str, flags: u32, mode: u32) -> KResult<u32> {
+        // Tlcreate turns the directory fid into the new file's
+        let fid = self.walk(dir)?;
+
+        let result = self.transact(TLCREATE, |w| {
+            w.u32(fid);
+            w.str(name)?;
+            w.u32(flags);
+            w.u32(mode);
+            w.u32(0);
+            Ok(())
+        });
+
+        if let Err(errno) = result {
+            self.clunk(fid)?;
+            return Err(errno);
+        }
+
+        Ok(fid)
+    }
+
+    /// Creates directory `name` in directory `dir`.
+    pub fn mkdir(&mut self, dir: &str, name: &str, mode: u32) -> KResult<()> {
+        let fid = self.walk(dir)?;
+
+        let result = self.transact(TMKDIR, |w| {
+            w.u32(fid);
+            w.str(name)?;
+            w.u32(mode);
+            w.u32(0);
+            Ok(())
+        });
+
+        self.clunk(fid)?;
+        result.map(|_| ())
+    }
+
+    /// Removes `name` from directory `dir` (an empty directory if `is_dir`).
+    pub fn unlink(&mut self, dir: &str, name: &str, is_dir: bool) -> KResult<()> {
+        let fid = self.walk(dir)?;
+
+        let result = self.transact(TUNLINKAT, |w| {
+            w.u32(fid);
+            w.str(name)?;
+            w.u32(if is_dir { AT_REMOVEDIR } else { 0 });
+            Ok(())
+        });
+
+        self.clunk(fid)?;
+        result.map(|_| ())
+    }
+
+    /// Reads up to `buf.len()` bytes at `offset` from the open `fid`. Returns 0 at end of file.
+    pub fn read(&mut self, fid: u32, offset: u64, buf: &mut [u8]) -> KResult<usize> {
+        let count = buf.len().min(MSIZE - IO_HEADER);
+
+        let mut reply = self.transact(TREAD, |w| {
+            w.u32(fid);
+            w.u64(offset);
+            w.u32(count as u32);
+            Ok(())
+        })?;
+
+        let got = (reply.u32()? as usize).min(count);
+        buf[..got].copy_from_slice(reply.bytes(got)?);
+        Ok(got)
+    }
+
+    /// Writes up to `data.len()` bytes at `offset` to the open `fid` and returns how many the
+    /// server accepted.
+    pub fn write(&mut self, fid: u32, offset: u64, data: &[u8]) -> KResult<usize> {
+        let count = data.len().min(MSIZE - IO_HEADER - 12);
+
+        let mut reply = self.transact(TWRITE, |w| {
+            w.u32(fid);
+            w.u64(offset);
+            w.u32(count as u32);
+            w.bytes(&data[..count])
+        })?;
+
+        Ok(reply.u32()? as usize)
+    }
+
+    /// Returns the attributes of `fid`.
+    pub fn getattr(&mut self, fid: u32) -> KResult<Attr> {
+        let mut reply = self.transact(TGETATTR, |w| {
+            w.u32(fid);
+            w.u64(GETATTR_BASIC);
+            Ok(())
+        })?;
+
+        let _valid = reply.u64()?;
+        let qid = reply.qid()?;
+        let mode = reply.u32()?;
+        let uid = reply.u32()?;
+        let gid = reply.u32()?;
+        let nlink = reply.u64()?;
+        let _rdev = reply.u64()?;
+        let size = reply.u64()?;
+        let _blksize = reply.u64()?;
+        let _blocks = reply.u64()?;
+        let _atime = (reply.u64()?, reply.u64()?);
+        let mtime_sec = reply.u64()?;
+
+        Ok(Attr { qid, mode, uid, gid, nlink, size, mtime_sec })
+    }
+
+    /// Reads directory entries of the open directory `fid` starting at `offset` (0 for the
+    /// first) and calls `f` for each. Returns the number of entries; 0 means the end was reached.
+    pub fn readdir(&mut self, fid: u32, offset: u64, mut f: impl FnMut(DirEntry)) -> KResult<usize> {
+        let mut reply = self.transact(TREADDIR, |w| {
+            w.u32(fid);
+            w.u64(offset);
+            w.u32((MSIZE - IO_HEADER) as u32);
+            Ok(())
+        })?;
+
+        let len = reply.u32()? as usize;
+        let mut entries = Reader { buf: reply.bytes(len)?, pos: 0 };
+        let mut count = 0;
+
+        while entries.pos < entries.buf.len() {
+            let qid = entries.qid()?;
+            let offset = entries.u64()?;
+            let _kind = entries.u8()?;
+            let name = entries.str()?;
+
+            f(DirEntry { qid, offset, name });
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Releases `fid` (closing it if open).
+    pub fn clunk(&mut self, fid: u32) -> KResult<()> {
+        let result = self.transact(TCLUNK, |w| {
+            w.u32(fid);
+            Ok(())
+        });
+
+        // The fid is gone on the server even if the clunk failed
+        self.free_fid(fid);
+        result.map(|_| ())
+    }
+}
+
+/// The share as the VFS opens it.
+pub static FS: P9Fs = P9Fs;
+
+pub struct P9Fs;
+
+impl FileSystem for P9Fs {
+    fn open(&self, process: &Process, path: &Path, flags: u32) -> KResult<Inode> {
+        with_client(|client| {
+            // The server's owner and mode are checked before opening, which may truncate
+            let probe = client.walk(path.as_str())?;
+            let attr = client.getattr(probe);
+            client.clunk(probe)?;
+            let attr = attr?;
+
+            let kind = if attr.qid.is_dir() { InodeKind::Directory } else { InodeKind::File };
+            let mode = (attr.mode & 0o7777) as u16;
+            let mut inode = Inode { ino: 0, kind, size: attr.size, uid: attr.uid, gid: attr.gid, mode };
+            vfs::check_access(process, &inode, flags)?;
+
+            let flags = flags & (O_RDWR | O_WRONLY | O_TRUNC | O_APPEND);
+            inode.ino = client.open(path.as_str(), flags)? as u64;
+            if flags & O_TRUNC != 0 && flags & (O_RDWR | O_WRONLY) != 0 {
+                inode.size = 0;
+            }
+            Ok(inode)
+        })
+    }
+
+    fn read(&self, ino: u64, buf: &mut [u8], offset: u64) -> KResult<usize> {
+        with_client(|client| client.read(ino as u32, offset, buf))
+    }
+
+    fn write(&self, ino: u64, data: &[u8], offset: u64) -> KResult<usize> {
+        with_client(|client| client.write(ino as u32, offset, data))
+    }
+
+    fn close(&self, ino: u64) {
+        if let Err(errno) = with_client(|client| client.clunk(ino as u32)) {
+            log::warn!("virtio-9p: cannot clunk fid {}: {:?}", ino, errno);
+        }
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    crate::os::ktest::kernel_test! {
+        fn messages_roundtrip() {
+            let mut buf = [0u8; 64];
+            let mut writer = Writer::new(&mut buf, TWALK, TAG);
+            writer.u32(7);
+            writer.str("etc").unwrap();
+            let len = writer.finish();
+
+            assert_eq!(len, 4 + 1 + 2 + 4 + 2 + 3);
+            assert_eq!(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize, len);
+
+            // Answer it as the server would, with the reply type
+            buf[4] = TWALK + 1;
+            let mut reader = parse_reply(&buf[..len], TWALK).unwrap();
+            assert_eq!(reader.u32().unwrap(), 7);
+            assert_eq!(reader.str().unwrap(), "etc");
+        }
+
+        fn rlerror_becomes_errno() {
+            let mut buf = [0u8; 16];
+            let mut writer = Writer::new(&mut buf, RLERROR, TAG);
+            writer.u32(Errno::ENOENT as u32);
+            let len = writer.finish();
+
+            assert_eq!(parse_reply(&buf[..len], TLOPEN).err(), Some(Errno::ENOENT));
+        }
+
+        fn truncated_reply_is_an_io_error() {
+            let mut buf = [0u8; 16];
+            let mut writer = Writer::new(&mut buf, TREAD + 1, TAG);
+            writer.u32(100);
+            let len = writer.finish();
+
+            let mut reader = parse_reply(&buf[..len], TREAD).unwrap();
+            let count = reader.u32().unwrap() as usize;
+            assert_eq!(reader.bytes(count).err(), Some(Errno::EIO));
+        }
+    }
+}
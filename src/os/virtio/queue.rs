@@ -0,0 +1,187 @@
+//! Split virtqueues in the legacy memory layout: descriptor table, available ring and (on the
+//! next page boundary) used ring, one physically contiguous block per queue.
+
+use core::ptr;
+use core::sync::atomic::{Ordering, fence};
+
+/// Largest queue size the static queue memory has room for.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+const PAGE_SIZE: usize = 4096;
+
+// Descriptor flags
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+
+const DESC_SIZE: usize = 16;
+
+/// Backing memory for one queue of up to [`MAX_QUEUE_SIZE`] entries. Must live at an
+/// identity-mapped address, since its address is handed to the device.
+#[repr(C, align(4096))]
+pub struct QueueMemory([u8; 3 * PAGE_SIZE]);
+
+impl QueueMemory {
+    pub const fn new() -> Self {
+        QueueMemory([0; 3 * PAGE_SIZE])
+    }
+}
+
+impl Default for QueueMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A buffer handed to the device: physical address, length and whether the device writes it.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    pub device_writable: bool,
+}
+
+/// One split virtqueue.
+pub struct VirtQueue {
+    base: *mut u8,
+    size: u16,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+// The queue is owned by one driver, which serialises access to it
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Lays out a queue of `size` entries (a power of two, as dictated by the device) in
+    /// `memory`. Returns `None` if it does not fit.
+    pub fn new(memory: &'static mut QueueMemory, size: u16) -> Option<Self> {
+        if size == 0 || size > MAX_QUEUE_SIZE || !size.is_power_of_two() {
+            return None;
+        }
+
+        memory.0.fill(0);
+
+        let mut queue = VirtQueue { base: memory.0.as_mut_ptr(), size, free_head: 0, num_free: size, avail_idx: 0, last_used: 0 };
+
+        // Chain every descriptor into the free list
+        for i in 0..size {
+            queue.write_desc(i, 0, 0, 0, (i + 1) % size);
+        }
+
+        Some(queue)
+    }
+
+    /// Physical page frame number of the queue, for the legacy `QUEUE_PFN` register.
+    pub fn pfn(&self) -> u32 {
+        (self.base as u64 / PAGE_SIZE as u64) as u32
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn avail_offset(&self) -> usize {
+        self.size as usize * DESC_SIZE
+    }
+
+    fn used_offset(&self) -> usize {
+        (self.avail_offset() + 6 + 2 * self.size as usize).next_multiple_of(PAGE_SIZE)
+    }
+
+    fn write_desc(&mut self, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = unsafe { self.base.add(index as usize * DESC_SIZE) };
+
+        unsafe {
+            ptr::write_volatile(desc as *mut u64, addr);
+            ptr::write_volatile(desc.add(8) as *mut u32, len);
+            ptr::write_volatile(desc.add(12) as *mut u16, flags);
+            ptr::write_volatile(desc.add(14) as *mut u16, next);
+        }
+    }
+
+    fn desc_flags_next(&self, index: u16) -> (u16, u16) {
+        let desc = unsafe { self.base.add(index as usize * DESC_SIZE) };
+        unsafe { (ptr::read_volatile(desc.add(12) as *const u16), ptr::read_volatile(desc.add(14) as *const u16)) }
+    }
+
+    /// Queues a chain of buffers for the device and returns the head descriptor, or `None` if
+    /// there are not enough free descriptors. The device is not notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            let (_, next) = self.desc_flags_next(index);
+
+            let mut flags = if buffer.device_writable { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+            }
+
+            self.write_desc(index, buffer.addr, buffer.len, flags, next);
+            self.num_free -= 1;
+
+            if i + 1 < buffers.len() {
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+
+        // Publish the chain in the next available ring slot, then the new index
+        unsafe {
+            let avail = self.base.add(self.avail_offset());
+            let slot = (self.avail_idx % self.size) as usize;
+            ptr::write_volatile(avail.add(4 + 2 * slot) as *mut u16, head);
+
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            ptr::write_volatile(avail.add(2) as *mut u16, self.avail_idx);
+        }
+
+        Some(head)
+    }
+
+    /// Takes the next chain the device has finished with, returning its head descriptor and the
+    /// number of bytes the device wrote, and returns its descriptors to the free list.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe { self.base.add(self.used_offset()) };
+        let device_idx = unsafe { ptr::read_volatile(used.add(2) as *const u16) };
+
+        if device_idx == self.last_used {
+            return None;
+        }
+
+        fence(Ordering::SeqCst);
+
+        let slot = (self.last_used % self.size) as usize;
+        let (head, len) = unsafe {
+            let elem = used.add(4 + 8 * slot);
+            (ptr::read_volatile(elem as *const u32) as u16, ptr::read_volatile(elem.add(4) as *const u32))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Walk to the end of the chain and splice it onto the free list
+        let mut tail = head;
+        self.num_free += 1;
+        loop {
+            let (flags, next) = self.desc_flags_next(tail);
+            if flags & DESC_NEXT == 0 {
+                break;
+            }
+            tail = next;
+            self.num_free += 1;
+        }
+
+        self.write_desc(tail, 0, 0, 0, self.free_head);
+        self.free_head = head;
+
+        Some((head, len))
+    }
+}
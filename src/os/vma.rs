@@ -0,0 +1,170 @@
+//! Virtual memory areas: a process's address space as one list.
+//!
+//! What a process may touch is recorded in two places: its image, data, heap and stack by
+//! base and size in its PCB, set at exec and, for the heap, moved by `brk`; and what `mmap`
+//! added in its mapping table. [`areas`] lists both as virtual memory areas, in address
+//! order, each with the rights it grants and what it holds, and [`find`] looks one up. The
+//! page fault handler asks it whether an access is legal at all before any hook looks at the
+//! page, and `mmap` and `brk` ask it whether a range is free.
+//!
+//! Below the stack's reach lie [`STACK_GUARD_PAGES`] that no area may take, so a stack
+//! overflow always faults there rather than in whatever lies below.
+
+use crate::os::memory::FRAME_SIZE;
+use crate::os::mmap::MAX_MAPPINGS;
+use crate::os::process::Process;
+use crate::os::protection::Protection;
+
+/// Most areas a process can have: image, data, heap, stack and its mappings.
+pub const MAX_VMAS: usize = 4 + MAX_MAPPINGS;
+
+/// Pages below the reach of a process's stack kept unmapped to catch overflows.
+pub const STACK_GUARD_PAGES: u64 = 16;
+
+/// What an area holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// The executable's code and read-only segments.
+    Image,
+    /// Its writable segments.
+    Data,
+    /// The heap, from its base up to the program break.
+    Heap,
+    /// The stack, as far down as it may grow.
+    Stack,
+    /// A region made by `mmap`.
+    Mapping,
+}
+
+/// One area of a process's address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    /// First address, page aligned.
+    pub start: u64,
+
+    /// First address past the area, page aligned.
+    pub end: u64,
+
+    pub prot: Protection,
+    pub kind: VmaKind,
+}
+
+/// A kind of memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Vma {
+    pub fn contains(&self, virt: u64) -> bool {
+        self.start <= virt && virt < self.end
+    }
+
+    /// Whether the area's rights allow `access`.
+    pub fn permits(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.prot.read || self.prot.write || self.prot.exec,
+            Access::Write => self.prot.write,
+            Access::Execute => self.prot.exec,
+        }
+    }
+}
+
+// The area `[start, start + len)` with `prot`, or `None` if empty; both ends are rounded out to
+// whole pages
+fn area(start: usize, len: usize, prot: Protection, kind: VmaKind) -> Option<Vma> {
+    let (start, end) = (start as u64, (start + len) as u64);
+    (len != 0).then(|| Vma { start: start & !(FRAME_SIZE - 1), end: end.next_multiple_of(FRAME_SIZE), prot, kind })
+}
+
+/// `process`'s areas in address order, the unused slots last.
+pub fn areas(process: &Process) -> [Option<Vma>; MAX_VMAS] {
+    let mut areas = [None; MAX_VMAS];
+    let stack_bottom = process.stack_base.saturating_sub(process.stack_size);
+
+    areas[0] = area(process.code_base, process.code_size, Protection::code(true), VmaKind::Image);
+    areas[1] = area(process.data_base, process.data_size, Protection::data(true), VmaKind::Data);
+    areas[2] = area(process.heap_base, process.heap_size, Protection::data(true), VmaKind::Heap);
+    areas[3] = area(stack_bottom, process.stack_base - stack_bottom, Protection::data(true), VmaKind::Stack);
+    for (slot, mapping) in areas[4..].iter_mut().zip(&process.mappings) {
+        *slot = mapping.map(|m| Vma { start: m.start, end: m.start + m.len, prot: m.prot, kind: VmaKind::Mapping });
+    }
+
+    areas.sort_unstable_by_key(|area| area.map_or(u64::MAX, |area| area.start));
+    areas
+}
+
+/// The area holding `virt` in `process`'s address space, if any.
+pub fn find(process: &Process, virt: u64) -> Option<Vma> {
+    areas(process).into_iter().flatten().find(|area| area.contains(virt))
+}
+
+/// Whether `virt` lies in the guard pages below `process`'s stack.
+pub fn in_stack_guard(process: &Process, virt: u64) -> bool {
+    let bottom = process.stack_base.saturating_sub(process.stack_size) as u64;
+    process.stack_base != 0 && virt < bottom && virt >= bottom.saturating_sub(STACK_GUARD_PAGES * FRAME_SIZE)
+}
+
+/// Whether `[start, end)` is clear of every area of `process` but those `skip` accepts, and
+/// of the stack guard.
+pub fn is_free(process: &Process, start: u64, end: u64, skip: impl Fn(&Vma) -> bool) -> bool {
+    let guard_end = process.stack_base.saturating_sub(process.stack_size) as u64;
+    let guard = guard_end.saturating_sub(STACK_GUARD_PAGES * FRAME_SIZE)..guard_end;
+    let clear_of_guard = process.stack_base == 0 || end <= guard.start || guard.end <= start;
+
+    clear_of_guard && !areas(process).iter().flatten().any(|area| !skip(area) && area.start < end && start < area.end)
+}
+
+pub mod ktests {
+    use super::*;
+
+    use crate::os::mmap::Mapping;
+
+    crate::os::ktest::kernel_test! {
+        fn areas_come_in_address_order() {
+            let mut process = Process::new(9370, 0, "vma");
+            process.code_base = 0x40_0000;
+            process.code_size = 0x1800;
+            process.data_base = 0x40_2000;
+            process.data_size = 0x100;
+            process.stack_base = 0x7000_0000;
+            process.stack_size = 0x4000;
+            process.mappings[3] = Some(Mapping { start: 0x5000_0000, len: 0x2000, prot: Protection::read_only(true), shared: false, file: None, offset: 0 });
+
+            let areas = areas(&process);
+            let kinds: [Option<VmaKind>; 5] = core::array::from_fn(|i| areas[i].map(|area| area.kind));
+            assert_eq!(kinds, [Some(VmaKind::Image), Some(VmaKind::Data), Some(VmaKind::Mapping), Some(VmaKind::Stack), None]);
+
+            // Rounded out to whole pages; an empty heap is no area at all
+            assert_eq!((areas[0].unwrap().start, areas[0].unwrap().end), (0x40_0000, 0x40_2000));
+            assert_eq!(areas[1].unwrap().end, 0x40_3000);
+            assert_eq!(find(&process, 0x6fff_c000).map(|area| area.kind), Some(VmaKind::Stack));
+            assert_eq!(find(&process, 0x6fff_bfff), None);
+        }
+
+        fn rights_and_free_ranges() {
+            let mut process = Process::new(9371, 0, "vma");
+            process.code_base = 0x40_0000;
+            process.code_size = 0x1000;
+            process.stack_base = 0x7000_0000;
+            process.stack_size = 0x4000;
+
+            let code = find(&process, 0x40_0000).unwrap();
+            assert!(code.permits(Access::Read) && code.permits(Access::Execute) && !code.permits(Access::Write));
+            let stack = find(&process, 0x6fff_f000).unwrap();
+            assert!(stack.permits(Access::Write) && !stack.permits(Access::Execute));
+
+            assert!(is_free(&process, 0x40_1000, 0x40_2000, |_| false));
+            assert!(!is_free(&process, 0x40_0000, 0x40_2000, |_| false));
+            assert!(is_free(&process, 0x40_0000, 0x40_2000, |area| area.kind == VmaKind::Image));
+
+            // The guard below the stack is never free
+            let guard = 0x7000_0000 - 0x4000 - FRAME_SIZE;
+            assert!(in_stack_guard(&process, guard));
+            assert!(!is_free(&process, guard, guard + FRAME_SIZE, |_| false));
+            assert!(is_free(&process, guard - STACK_GUARD_PAGES * FRAME_SIZE, guard - (STACK_GUARD_PAGES - 1) * FRAME_SIZE, |_| false));
+        }
+    }
+}
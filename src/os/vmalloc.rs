@@ -0,0 +1,289 @@
+//! vmalloc: virtually contiguous kernel allocations.
+//!
+//! Large kernel buffers -- module images, big ring buffers -- need contiguous addresses but not
+//! contiguous memory, which after a while is hard to come by. [`vmalloc`] takes single frames
+//! wherever the frame allocator has them and maps them back to back into a window of kernel
+//! virtual addresses (`Arch::VMALLOC_START`, [`WINDOW_SIZE`] long) in the kernel's root. An
+//! unmapped guard page follows every allocation, so running off the end of a buffer faults
+//! instead of corrupting the next one.
+//!
+//! Page tables created for the window are kept when allocations are freed. Address spaces
+//! built later must share the kernel root's top-level entries for the window to see it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch::{self, Arch, Current};
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::{self, FRAME_SIZE, FrameAllocator};
+use crate::os::numa::Placement;
+use crate::os::tlb;
+
+/// Size of the kernel virtual window allocations are mapped into.
+pub const WINDOW_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Maximum number of allocations alive at once.
+pub const MAX_AREAS: usize = 64;
+
+// Valid bit of a leaf entry, bit 0 on every port
+const VALID: u64 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    start: u64,
+    pages: u64,
+}
+
+impl Area {
+    // End of the area including its guard page
+    fn end(&self) -> u64 {
+        self.start + (self.pages + 1) * FRAME_SIZE
+    }
+}
+
+static mut AREAS: [Option<Area>; MAX_AREAS] = [None; MAX_AREAS];
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the area table holding its lock, with interrupts masked so a holder cannot be
+// interrupted by something spinning on it
+fn locked<R>(f: impl FnOnce(&mut [Option<Area>; MAX_AREAS]) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let areas = &raw mut AREAS;
+            f(&mut *areas)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+// First fit: the lowest address in the window with room for `pages` and a guard page
+fn reserve(areas: &mut [Option<Area>; MAX_AREAS], pages: u64) -> KResult<u64> {
+    let slot = areas.iter().position(Option::is_none).ok_or(Errno::ENOMEM)?;
+    let mut area = Area { start: Current::VMALLOC_START, pages };
+
+    while let Some(other) = areas.iter().flatten().find(|other| other.start < area.end() && area.start < other.end()) {
+        area.start = other.end();
+    }
+
+    if area.end() > Current::VMALLOC_START + WINDOW_SIZE {
+        return Err(Errno::ENOMEM);
+    }
+
+    areas[slot] = Some(area);
+    Ok(area.start)
+}
+
+// Removes the area starting at `start`, returning its size in pages
+fn unreserve(areas: &mut [Option<Area>; MAX_AREAS], start: u64) -> Option<u64> {
+    let slot = areas.iter_mut().find(|slot| slot.is_some_and(|area| area.start == start))?;
+    slot.take().map(|area| area.pages)
+}
+
+// Unmaps `pages` pages from `start` and frees their frames. `flush` runs between the two, so
+// no CPU still reaches a frame once it is handed out again.
+fn unmap_pages(allocator: &dyn FrameAllocator, root: u64, start: u64, pages: u64, flush: impl FnOnce()) {
+    let entries = || (0..pages).filter_map(move |i| Current::leaf_entry(root, start + i * FRAME_SIZE));
+
+    // Clearing only the valid bit keeps the frame address for after the flush
+    for pte in entries() {
+        unsafe { *pte &= !VALID };
+    }
+    flush();
+
+    for pte in entries() {
+        unsafe {
+            if *pte != 0 {
+                allocator.free_frame(Current::entry_address(*pte));
+                *pte = 0;
+            }
+        }
+    }
+}
+
+// Maps `pages` fresh zeroed frames, readable and writable by the kernel only, from `start`
+fn map_pages(allocator: &dyn FrameAllocator, root: u64, start: u64, pages: u64) -> KResult<()> {
+    let flags = Current::page_flags(true, false, false);
+
+    for i in 0..pages {
+        let virt = start + i * FRAME_SIZE;
+        let mapped = allocator.alloc_frame(Placement::Local).and_then(|frame| {
+            let Some(pte) = Current::leaf_entry_or_create(root, virt, &mut || allocator.alloc_frame(Placement::Local)) else {
+                allocator.free_frame(frame);
+                return None;
+            };
+
+            // Frames are identity-mapped
+            unsafe {
+                (frame as *mut u8).write_bytes(0, FRAME_SIZE as usize);
+                *pte = Current::page_entry(frame, flags);
+            }
+            Some(())
+        });
+
+        if mapped.is_none() {
+            // Nothing was ever mapped at these addresses, so no CPU can have cached them
+            unmap_pages(allocator, root, start, i, || ());
+            return Err(Errno::ENOMEM);
+        }
+
+        Current::flush_tlb_page(virt);
+    }
+
+    Ok(())
+}
+
+/// Allocates `size` bytes of zeroed, virtually contiguous kernel memory, rounded up to whole
+/// pages. Fails with `EINVAL` for a zero size and `ENOMEM` when frames or window space run
+/// out, or the kernel runs without translation.
+pub fn vmalloc(size: usize) -> KResult<*mut u8> {
+    if size == 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let allocator = memory::frame_allocator().ok_or(Errno::ENOMEM)?;
+    let root = tlb::kernel_root();
+    if root == 0 {
+        return Err(Errno::ENOMEM);
+    }
+
+    let pages = (size as u64).div_ceil(FRAME_SIZE);
+    let start = locked(|areas| reserve(areas, pages))?;
+
+    if let Err(errno) = map_pages(allocator, root, start, pages) {
+        locked(|areas| unreserve(areas, start));
+        return Err(errno);
+    }
+
+    Ok(start as *mut u8)
+}
+
+/// Frees an allocation made by [`vmalloc`]. Fails with `EINVAL` if `ptr` is not the start of
+/// one.
+pub fn vfree(ptr: *mut u8) -> KResult<()> {
+    let start = ptr as u64;
+    let pages = locked(|areas| areas.iter().flatten().find(|area| area.start == start).map(|area| area.pages)).ok_or(Errno::EINVAL)?;
+
+    if let Some(allocator) = memory::frame_allocator() {
+        let root = tlb::kernel_root();
+        unmap_pages(allocator, root, start, pages, || tlb::shootdown(None, start, pages));
+    }
+
+    // Only now may the addresses be handed out again
+    locked(|areas| unreserve(areas, start));
+    Ok(())
+}
+
+/// Bytes currently mapped by vmalloc, guard pages excluded.
+pub fn used() -> u64 {
+    locked(|areas| areas.iter().flatten().map(|area| area.pages * FRAME_SIZE).sum())
+}
+
+pub mod ktests {
+    use core::sync::atomic::AtomicU8;
+
+    use super::*;
+
+    const FRAMES: usize = 8;
+
+    #[repr(C, align(4096))]
+    struct Frames([[u8; FRAME_SIZE as usize]; FRAMES]);
+
+    // Frame 0 is the root, the rest go to the allocator
+    static mut TEST_FRAMES: Frames = Frames([[0; FRAME_SIZE as usize]; FRAMES]);
+
+    fn frame(index: usize) -> u64 {
+        unsafe {
+            let frames = &raw mut TEST_FRAMES;
+            (*frames).0[index].as_ptr() as u64
+        }
+    }
+
+    // Hands out frames 1-7, bit i of `free` set while frame i is free
+    struct TestAllocator {
+        free: AtomicU8,
+    }
+
+    impl FrameAllocator for TestAllocator {
+        fn alloc_frame(&self, _placement: Placement) -> Option<u64> {
+            let free = self.free.load(Ordering::Relaxed);
+            if free == 0 {
+                return None;
+            }
+
+            let index = free.trailing_zeros();
+            self.free.fetch_and(!(1 << index), Ordering::Relaxed);
+            Some(frame(index as usize))
+        }
+
+        fn free_frame(&self, addr: u64) {
+            let index = (addr - frame(0)) / FRAME_SIZE;
+            self.free.fetch_or(1 << index, Ordering::Relaxed);
+        }
+
+        fn free_frames(&self) -> usize {
+            self.free.load(Ordering::Relaxed).count_ones() as usize
+        }
+    }
+
+    fn empty_root() -> u64 {
+        let root = frame(0);
+        unsafe { (root as *mut u8).write_bytes(0, FRAME_SIZE as usize) };
+        root
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn allocations_are_separated_by_guard_pages() {
+            let mut areas = [None; MAX_AREAS];
+            let base = Current::VMALLOC_START;
+
+            assert_eq!(reserve(&mut areas, 2), Ok(base));
+            assert_eq!(reserve(&mut areas, 1), Ok(base + 3 * FRAME_SIZE));
+            assert_eq!(reserve(&mut areas, 4), Ok(base + 5 * FRAME_SIZE));
+
+            // The first hole fits two pages again, but not three
+            assert_eq!(unreserve(&mut areas, base), Some(2));
+            assert_eq!(reserve(&mut areas, 3), Ok(base + 10 * FRAME_SIZE));
+            assert_eq!(reserve(&mut areas, 2), Ok(base));
+            assert_eq!(unreserve(&mut areas, base + FRAME_SIZE), None);
+
+            assert_eq!(reserve(&mut areas, WINDOW_SIZE / FRAME_SIZE), Err(Errno::ENOMEM));
+        }
+
+        fn pages_are_mapped_and_freed() {
+            let root = empty_root();
+            let allocator = TestAllocator { free: AtomicU8::new(0b1111_1110) };
+            let start = Current::VMALLOC_START + 16 * FRAME_SIZE;
+
+            map_pages(&allocator, root, start, 2).unwrap();
+            let first = Current::leaf_entry(root, start).map(|pte| unsafe { *pte }).unwrap();
+            let second = Current::leaf_entry(root, start + FRAME_SIZE).map(|pte| unsafe { *pte }).unwrap();
+            assert_eq!(first & VALID, VALID);
+            assert_ne!(Current::entry_address(first), Current::entry_address(second));
+            assert_eq!(Current::leaf_entry(root, start + 2 * FRAME_SIZE).map(|pte| unsafe { *pte }), Some(0));
+
+            // The tables stay, the two data frames come back
+            let tables = 7 - 2 - allocator.free_frames();
+            let mut flushed = false;
+            unmap_pages(&allocator, root, start, 2, || flushed = true);
+            assert!(flushed);
+            assert_eq!(allocator.free_frames(), 7 - tables);
+            assert_eq!(Current::leaf_entry(root, start).map(|pte| unsafe { *pte }), Some(0));
+        }
+
+        fn running_out_of_frames_undoes_the_mapping() {
+            let root = empty_root();
+            let allocator = TestAllocator { free: AtomicU8::new(0b1111_1110) };
+
+            // Seven frames cannot hold the tables and eight pages
+            assert_eq!(map_pages(&allocator, root, Current::VMALLOC_START, 8), Err(Errno::ENOMEM));
+            let tables = 7 - allocator.free_frames();
+            assert!(tables < 7);
+            assert_eq!(Current::leaf_entry(root, Current::VMALLOC_START).map(|pte| unsafe { *pte }), Some(0));
+        }
+    }
+}
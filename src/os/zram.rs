@@ -0,0 +1,474 @@
+//! zram: a compressed block device backed by RAM, typically used for swap.
+//!
+//! The device is [`DISK_PAGES`] page-sized blocks. Each written page is compressed with a
+//! small LZ77 coder (an LZ4-style token stream, see [`compress`]) and stored in a static pool
+//! carved into 64-byte chunks; pages whose words are all equal, most often zero pages, only
+//! record the value, and pages that do not compress well are stored as they are. Blocks never
+//! written read back as zeros.
+//!
+//! The device is [`ZRAM`], a [`BlockDevice`]: formatted with [`swap::mkswap`] and enabled with
+//! [`swap::swapon`], anonymous pages are swapped to it before the OOM killer has to act,
+//! trading CPU time for RAM on machines that have little of it.
+//!
+//! [`swap::mkswap`]: crate::os::swap::mkswap
+//! [`swap::swapon`]: crate::os::swap::swapon
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::os::arch;
+use crate::os::block::{BLOCK_SIZE, BlockDevice};
+use crate::os::errno::{Errno, KResult};
+use crate::os::memory::FRAME_SIZE;
+
+/// Size of a block, the unit of reads and writes.
+pub const PAGE_SIZE: usize = FRAME_SIZE as usize;
+
+/// Number of blocks (4 MiB of uncompressed data).
+pub const DISK_PAGES: usize = 1024;
+
+/// RAM set aside for compressed data.
+pub const POOL_SIZE: usize = 1024 * 1024;
+
+// Pool allocation granularity
+const CHUNK: usize = 64;
+const CHUNKS: usize = POOL_SIZE / CHUNK;
+
+// Pages compressing to more than this are stored uncompressed, which is cheaper to read back
+const MAX_COMPRESSED: usize = PAGE_SIZE * 3 / 4;
+
+// Shortest match the coder emits; shorter ones cost more than the literals they replace
+const MIN_MATCH: usize = 4;
+
+// Size of the coder's match-finder hash table, in bits
+const HASH_BITS: u32 = 12;
+
+// =========================================================================
+// Compression
+// =========================================================================
+
+// Writes a length's continuation bytes after its 4-bit token field saturated at 15
+fn put_length(out: &mut [u8], at: &mut usize, len: usize) -> Option<()> {
+    if len < 15 {
+        return Some(());
+    }
+
+    let mut rest = len - 15;
+    while rest >= 255 {
+        *out.get_mut(*at)? = 255;
+        *at += 1;
+        rest -= 255;
+    }
+
+    *out.get_mut(*at)? = rest as u8;
+    *at += 1;
+    Some(())
+}
+
+// Appends one sequence: literals, then the back-reference `(offset, length)` if any
+fn put_sequence(out: &mut [u8], mut at: usize, literals: &[u8], found: Option<(usize, usize)>) -> Option<usize> {
+    let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+
+    *out.get_mut(at)? = (literals.len().min(15) as u8) << 4 | match_len.min(15) as u8;
+    at += 1;
+    put_length(out, &mut at, literals.len())?;
+
+    out.get_mut(at..at + literals.len())?.copy_from_slice(literals);
+    at += literals.len();
+
+    if let Some((offset, _)) = found {
+        out.get_mut(at..at + 2)?.copy_from_slice(&(offset as u16).to_le_bytes());
+        at += 2;
+        put_length(out, &mut at, match_len)?;
+    }
+
+    Some(at)
+}
+
+/// Compresses `input` (at most 64 KiB) into `output` and returns the compressed length, or
+/// `None` if it does not fit.
+///
+/// The output is a series of sequences, each a token byte (literal count in the high nibble,
+/// match length minus 4 in the low one, 15 meaning more length bytes follow), the literals,
+/// and a 16-bit little-endian offset back into the output; the last sequence has literals
+/// only.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    if input.len() > u16::MAX as usize {
+        return None;
+    }
+
+    let mut table = [0u16; 1 << HASH_BITS];
+    let (mut pos, mut anchor, mut out) = (0, 0, 0);
+
+    while pos + MIN_MATCH <= input.len() {
+        let word = u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]]);
+        let hash = (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash] as usize;
+        table[hash] = pos as u16;
+
+        if candidate >= pos || input[candidate..candidate + MIN_MATCH] != input[pos..pos + MIN_MATCH] {
+            pos += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while pos + len < input.len() && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+
+        out = put_sequence(output, out, &input[anchor..pos], Some((pos - candidate, len)))?;
+        pos += len;
+        anchor = pos;
+    }
+
+    put_sequence(output, out, &input[anchor..], None)
+}
+
+// Reads a length whose token field was `field`, with its continuation bytes
+fn get_length(input: &[u8], at: &mut usize, field: usize) -> KResult<usize> {
+    let mut len = field;
+
+    if field == 15 {
+        loop {
+            let byte = *input.get(*at).ok_or(Errno::EIO)?;
+            *at += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+
+    Ok(len)
+}
+
+/// Decompresses `input` (produced by [`compress`]) into `output` and returns the length
+/// written. Malformed input, or output that would not fit, fails with `EIO`.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> KResult<usize> {
+    let (mut at, mut out) = (0, 0);
+
+    loop {
+        let token = *input.get(at).ok_or(Errno::EIO)? as usize;
+        at += 1;
+
+        let literals = get_length(input, &mut at, token >> 4)?;
+        let src = input.get(at..at + literals).ok_or(Errno::EIO)?;
+        output.get_mut(out..out + literals).ok_or(Errno::EIO)?.copy_from_slice(src);
+        at += literals;
+        out += literals;
+
+        if at == input.len() {
+            return Ok(out);
+        }
+
+        let offset = u16::from_le_bytes([*input.get(at).ok_or(Errno::EIO)?, *input.get(at + 1).ok_or(Errno::EIO)?]) as usize;
+        at += 2;
+        let len = get_length(input, &mut at, token & 15)? + MIN_MATCH;
+        if offset == 0 || offset > out || out + len > output.len() {
+            return Err(Errno::EIO);
+        }
+
+        // Byte by byte: a match may overlap the bytes it produces
+        for i in out..out + len {
+            output[i] = output[i - offset];
+        }
+        out += len;
+    }
+}
+
+// =========================================================================
+// Device
+// =========================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Empty,
+
+    // Every 64-bit word of the page holds this value
+    Same(u64),
+
+    // `len` bytes at chunk `chunk` of the pool, compressed unless `len` is a whole page
+    Stored { chunk: u16, len: u16 },
+}
+
+struct Device {
+    slots: [Slot; DISK_PAGES],
+    used: [u64; CHUNKS / 64],
+    pool: [u8; POOL_SIZE],
+}
+
+static mut DEVICE: Device = Device { slots: [Slot::Empty; DISK_PAGES], used: [0; CHUNKS / 64], pool: [0; POOL_SIZE] };
+
+static LOCK: AtomicBool = AtomicBool::new(false);
+
+// Runs `f` on the device holding its lock, with interrupts masked so a holder cannot be
+// interrupted by something spinning on it
+fn locked<R>(f: impl FnOnce(&mut Device) -> R) -> R {
+    arch::without_interrupts(|| {
+        while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+
+        let result = unsafe {
+            let device = &raw mut DEVICE;
+            f(&mut *device)
+        };
+        LOCK.store(false, Ordering::Release);
+        result
+    })
+}
+
+impl Device {
+    fn is_used(&self, chunk: usize) -> bool {
+        self.used[chunk / 64] & (1 << (chunk % 64)) != 0
+    }
+
+    fn mark(&mut self, first: usize, count: usize, used: bool) {
+        for chunk in first..first + count {
+            if used {
+                self.used[chunk / 64] |= 1 << (chunk % 64);
+            } else {
+                self.used[chunk / 64] &= !(1 << (chunk % 64));
+            }
+        }
+    }
+
+    // First fit: the lowest run of `count` free chunks
+    fn alloc(&mut self, count: usize) -> Option<usize> {
+        let mut run = 0;
+
+        for chunk in 0..CHUNKS {
+            run = if self.is_used(chunk) { 0 } else { run + 1 };
+            if run == count {
+                let first = chunk + 1 - count;
+                self.mark(first, count, true);
+                return Some(first);
+            }
+        }
+
+        None
+    }
+
+    fn free(&mut self, index: usize) {
+        if let Slot::Stored { chunk, len } = self.slots[index] {
+            self.mark(chunk as usize, (len as usize).div_ceil(CHUNK), false);
+        }
+        self.slots[index] = Slot::Empty;
+    }
+
+    fn write(&mut self, index: usize, data: &[u8; PAGE_SIZE]) -> KResult<()> {
+        self.free(index);
+
+        let (words, _) = data.as_chunks::<8>();
+        if words.iter().all(|word| *word == words[0]) {
+            self.slots[index] = Slot::Same(u64::from_ne_bytes(words[0]));
+            return Ok(());
+        }
+
+        let mut compressed = [0u8; MAX_COMPRESSED];
+        let stored: &[u8] = match compress(data, &mut compressed) {
+            Some(len) => &compressed[..len],
+            None => data,
+        };
+
+        let chunk = self.alloc(stored.len().div_ceil(CHUNK)).ok_or(Errno::ENOSPC)?;
+        let start = chunk * CHUNK;
+        self.pool[start..start + stored.len()].copy_from_slice(stored);
+        self.slots[index] = Slot::Stored { chunk: chunk as u16, len: stored.len() as u16 };
+        Ok(())
+    }
+
+    fn read(&self, index: usize, out: &mut [u8; PAGE_SIZE]) -> KResult<()> {
+        match self.slots[index] {
+            Slot::Empty => out.fill(0),
+            Slot::Same(value) => out.as_chunks_mut::<8>().0.iter_mut().for_each(|word| *word = value.to_ne_bytes()),
+            Slot::Stored { chunk, len } => {
+                let stored = &self.pool[chunk as usize * CHUNK..][..len as usize];
+                if stored.len() == PAGE_SIZE {
+                    out.copy_from_slice(stored);
+                } else if decompress(stored, out)? != PAGE_SIZE {
+                    return Err(Errno::EIO);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Device usage figures, in the spirit of Linux's `mm_stat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZramStats {
+    /// Blocks holding data.
+    pub pages_stored: usize,
+
+    /// Of those, blocks stored as a single repeated word.
+    pub same_pages: usize,
+
+    /// Uncompressed size of the stored data, in bytes.
+    pub orig_data_size: u64,
+
+    /// Compressed size of the data in the pool, in bytes.
+    pub compr_data_size: u64,
+
+    /// Pool memory in use, whole chunks, in bytes.
+    pub mem_used: u64,
+}
+
+fn check(index: usize) -> KResult<()> {
+    if index < DISK_PAGES { Ok(()) } else { Err(Errno::EINVAL) }
+}
+
+/// Stores `data` in block `index`. Fails with `ENOSPC` if the pool has no room for it.
+pub fn write_page(index: usize, data: &[u8; PAGE_SIZE]) -> KResult<()> {
+    check(index)?;
+    locked(|device| device.write(index, data))
+}
+
+/// Reads block `index` into `out`.
+pub fn read_page(index: usize, out: &mut [u8; PAGE_SIZE]) -> KResult<()> {
+    check(index)?;
+    locked(|device| device.read(index, out))
+}
+
+/// Drops the contents of block `index`, returning its pool space.
+pub fn discard(index: usize) -> KResult<()> {
+    check(index)?;
+    locked(|device| device.free(index));
+    Ok(())
+}
+
+/// Current usage figures.
+pub fn stats() -> ZramStats {
+    locked(|device| {
+        let mut stats = ZramStats { pages_stored: 0, same_pages: 0, orig_data_size: 0, compr_data_size: 0, mem_used: 0 };
+
+        for slot in &device.slots {
+            match *slot {
+                Slot::Empty => continue,
+                Slot::Same(_) => stats.same_pages += 1,
+                Slot::Stored { len, .. } => {
+                    stats.compr_data_size += len as u64;
+                    stats.mem_used += (len as usize).div_ceil(CHUNK) as u64 * CHUNK as u64;
+                }
+            }
+            stats.pages_stored += 1;
+            stats.orig_data_size += PAGE_SIZE as u64;
+        }
+
+        stats
+    })
+}
+
+/// The device as a [`BlockDevice`].
+pub struct Zram;
+
+/// The one zram device.
+pub static ZRAM: Zram = Zram;
+
+impl BlockDevice for Zram {
+    fn name(&self) -> &str {
+        "zram0"
+    }
+
+    fn blocks(&self) -> u64 {
+        DISK_PAGES as u64
+    }
+
+    fn read_block(&self, index: u64, buf: &mut [u8; BLOCK_SIZE]) -> KResult<()> {
+        read_page(usize::try_from(index).map_err(|_| Errno::EINVAL)?, buf)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> KResult<()> {
+        write_page(usize::try_from(index).map_err(|_| Errno::EINVAL)?, data)
+    }
+
+    fn discard_block(&self, index: u64) -> KResult<()> {
+        discard(usize::try_from(index).map_err(|_| Errno::EINVAL)?)
+    }
+}
+
+pub mod ktests {
+    use super::*;
+
+    // Deterministic noise for incompressible pages
+    fn noise(seed: u64, out: &mut [u8]) {
+        let mut state = seed | 1;
+        for byte in out {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+    }
+
+    fn text_page() -> [u8; PAGE_SIZE] {
+        let mut page = [0u8; PAGE_SIZE];
+        let line = b"the quick brown fox jumps over the lazy dog; ";
+        for (i, byte) in page.iter_mut().enumerate() {
+            *byte = line[i % line.len()].wrapping_add((i / 512) as u8);
+        }
+        page
+    }
+
+    crate::os::ktest::kernel_test! {
+        fn coder_roundtrips() {
+            let mut packed = [0u8; PAGE_SIZE + PAGE_SIZE / 8];
+            let mut unpacked = [0u8; PAGE_SIZE];
+
+            let text = text_page();
+            let len = compress(&text, &mut packed).unwrap();
+            assert!(len < PAGE_SIZE / 4, "text compressed to {} bytes", len);
+            assert_eq!(decompress(&packed[..len], &mut unpacked), Ok(PAGE_SIZE));
+            assert!(unpacked == text);
+
+            let mut random = [0u8; PAGE_SIZE];
+            noise(42, &mut random);
+            let len = compress(&random, &mut packed).unwrap();
+            assert_eq!(decompress(&packed[..len], &mut unpacked), Ok(PAGE_SIZE));
+            assert!(unpacked == random);
+
+            assert_eq!(compress(&text, &mut [0u8; 16]), None);
+            assert_eq!(compress(b"", &mut packed), Some(1));
+        }
+
+        fn malformed_input_is_rejected() {
+            let mut out = [0u8; 64];
+
+            assert_eq!(decompress(&[], &mut out), Err(Errno::EIO));
+            assert_eq!(decompress(&[0x30, b'a'], &mut out), Err(Errno::EIO));
+            // A back-reference before the start of the output
+            assert_eq!(decompress(&[0x10, b'a', 0x05, 0x00], &mut out), Err(Errno::EIO));
+            // Output that would not fit
+            assert_eq!(decompress(&[0x1f, b'a', 0x01, 0x00, 0x80], &mut out), Err(Errno::EIO));
+            assert_eq!(decompress(&[0x10, b'a', 0x01, 0x00], &mut out), Ok(5));
+            assert_eq!(&out[..5], b"aaaaa");
+        }
+
+        fn pages_are_stored_by_kind() {
+            let before = stats();
+            let mut page = [0u8; PAGE_SIZE];
+
+            write_page(DISK_PAGES - 1, &[0xab; PAGE_SIZE]).unwrap();
+            write_page(DISK_PAGES - 2, &text_page()).unwrap();
+            noise(7, &mut page);
+            write_page(DISK_PAGES - 3, &page).unwrap();
+
+            let after = stats();
+            assert_eq!(after.pages_stored, before.pages_stored + 3);
+            assert_eq!(after.same_pages, before.same_pages + 1);
+            assert!(after.compr_data_size - before.compr_data_size < 2 * PAGE_SIZE as u64);
+
+            let mut out = [0u8; PAGE_SIZE];
+            read_page(DISK_PAGES - 3, &mut out).unwrap();
+            assert!(out == page);
+            read_page(DISK_PAGES - 1, &mut out).unwrap();
+            assert!(out.iter().all(|&b| b == 0xab));
+
+            for index in DISK_PAGES - 3..DISK_PAGES {
+                discard(index).unwrap();
+            }
+            assert_eq!(stats(), before);
+            assert_eq!(write_page(DISK_PAGES, &page), Err(Errno::EINVAL));
+        }
+    }
+}